@@ -1,11 +1,13 @@
 use std::collections::{HashSet};
 
+use serde::{Deserialize, Serialize};
+
 use crate::expression::Expression;
 use crate::identifier::Identifier;
 use crate::pattern::Pattern;
 use crate::topology::{TopologyError, sort_topological, Node};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Assignment<'a, 'b> {
     pub pattern: Pattern<'a>,
     pub expression: Expression<'b>,
@@ -17,7 +19,7 @@ impl std::fmt::Display for Assignment<'_,'_> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AssignmentSet<'a, 'b> {
     pub assignments: Vec<Assignment<'a, 'b>>,
 }
@@ -32,6 +34,22 @@ impl<'a, 'b> AssignmentSet<'a, 'b> {
             assignments: sorted,
         })
     }
+
+    // The identifiers referred to but not bound by any of the assignments.
+    pub fn free_identifiers<'x>(&self) -> Vec<Identifier<'x>> {
+        let bound = self
+            .assignments
+            .iter()
+            .flat_map(|a| a.output_identifiers())
+            .collect::<HashSet<_>>();
+
+        self.assignments
+            .iter()
+            .flat_map(|a| a.input_identifiers())
+            .filter(|id| !bound.contains(id))
+            .map(|id| id.deep_clone())
+            .collect()
+    }
 }
 
 impl<'a,'b> Node for Assignment<'a, 'b> {
@@ -43,6 +61,9 @@ impl<'a,'b> Node for Assignment<'a, 'b> {
     }
 
     fn input_identifiers<'x>(&'x self) -> Self::InputIter<'x> {
-        self.pattern.get_expressions().chain(Some(&self.expression).into_iter()).flat_map(|e| e.get_identifiers())
+        // Guards may refer to identifiers bound by the pattern itself.
+        let own = self.pattern.get_identifiers().collect::<Vec<_>>();
+        self.pattern.get_expressions().flat_map(|e| e.get_identifiers()).filter(move |id| !own.contains(id))
+            .chain(self.expression.get_identifiers())
     }
 }
@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::trash;
+
+// How many entries are kept in memory for the admin page.
+pub const AUDIT_HISTORY: usize = 200;
+// The ids of sessions authenticate them, only their start is logged. It is
+// enough to tell the sessions apart.
+const SESSION_PREFIX: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    // Seconds since the unix epoch.
+    pub time: i64,
+    pub session: String,
+    pub duration: Duration,
+    pub statement: String,
+    pub outcome: &'static str,
+    pub detail: String,
+}
+
+impl std::fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "time={} session={} duration_us={} outcome={} statement={:?} detail={:?}",
+            self.time,
+            self.session,
+            self.duration.as_micros(),
+            self.outcome,
+            self.statement,
+            self.detail
+        )
+    }
+}
+
+// Appends one line per executed statement to a file, which is moved aside to
+// `<path>.1` once it grows beyond `max_bytes`.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    recent: VecDeque<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        session: &str,
+        statement: &str,
+        started: Instant,
+        outcome: &'static str,
+        detail: &str,
+    ) {
+        let entry = AuditEntry {
+            time: trash::now() / 1000,
+            session: session.chars().take(SESSION_PREFIX).collect(),
+            duration: started.elapsed(),
+            statement: statement.to_string(),
+            outcome,
+            detail: detail.lines().next().unwrap_or_default().chars().take(120).collect(),
+        };
+
+        if let Err(e) = self.append(&entry) {
+            eprintln!("Failed to write audit log: {e}");
+        }
+
+        if self.recent.len() == AUDIT_HISTORY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(entry);
+    }
+
+    // The entries kept in memory, the oldest first.
+    pub fn recent(&self) -> impl DoubleEndedIterator<Item = &AuditEntry> {
+        self.recent.iter()
+    }
+
+    fn append(&self, entry: &AuditEntry) -> io::Result<()> {
+        if fs::metadata(&self.path).map_or(false, |m| m.len() >= self.max_bytes) {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, rotated)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        writeln!(file, "{entry}")
+    }
+}
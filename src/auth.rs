@@ -0,0 +1,108 @@
+use std::env;
+
+use crate::server::constant_time_eq;
+use crate::statement::Statement;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthScope {
+    Everything,
+    Mutations,
+}
+
+// The credentials the web frontend accepts in the `Authorization` header.
+#[derive(Clone, Debug)]
+pub struct Auth {
+    token: Option<String>,
+    basic: Option<String>,
+    pub scope: AuthScope,
+}
+
+impl Auth {
+    // `None` if neither a token nor a user and password are given, so that
+    // nothing requires authentication.
+    pub fn new(
+        token: Option<String>,
+        user_password: Option<(&str, &str)>,
+        scope: AuthScope,
+    ) -> Option<Self> {
+        let basic = user_password.map(|(user, password)| base64(format!("{user}:{password}").as_bytes()));
+
+        if token.is_none() && basic.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            token,
+            basic,
+            scope,
+        })
+    }
+
+    pub fn from_env() -> Option<Self> {
+        let user = env::var("DAMASC_AUTH_USER");
+        let password = env::var("DAMASC_AUTH_PASSWORD");
+        let scope = match env::var("DAMASC_AUTH_SCOPE").as_deref() {
+            Ok("all") => AuthScope::Everything,
+            _ => AuthScope::Mutations,
+        };
+
+        Self::new(
+            env::var("DAMASC_AUTH_TOKEN").ok(),
+            user.as_deref().ok().zip(password.as_deref().ok()),
+            scope,
+        )
+    }
+
+    // Whether the value of an `Authorization` header carries the token or
+    // the user and password.
+    pub fn accepts(&self, authorization: &str) -> bool {
+        match authorization.split_once(' ') {
+            Some(("Bearer", t)) => self
+                .token
+                .as_ref()
+                .map_or(false, |token| constant_time_eq(t, token)),
+            Some(("Basic", b)) => self
+                .basic
+                .as_ref()
+                .map_or(false, |basic| constant_time_eq(b, basic)),
+            _ => false,
+        }
+    }
+
+    pub fn has_basic(&self) -> bool {
+        self.basic.is_some()
+    }
+
+    // Whether the statement, or the page if there is none, requires
+    // authentication.
+    pub fn is_required(&self, statement: Option<&Statement>) -> bool {
+        match self.scope {
+            AuthScope::Everything => true,
+            AuthScope::Mutations => statement.map_or(false, |s| s.is_mutating()),
+        }
+    }
+}
+
+pub fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+
+use crate::value::Value;
+
+#[derive(Debug)]
+pub enum BackendError {
+    Unsupported,
+    Failed(String),
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::Unsupported => write!(f, "Value can not be stored by the backend"),
+            BackendError::Failed(e) => write!(f, "Backend failed: {e}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    LessThan,
+    LessThanEqual,
+    GreaterThan,
+    GreaterThanEqual,
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Comparison::LessThan => write!(f, "<"),
+            Comparison::LessThanEqual => write!(f, "<="),
+            Comparison::GreaterThan => write!(f, ">"),
+            Comparison::GreaterThanEqual => write!(f, ">="),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Bound<'s, 'v> {
+    pub key: String,
+    pub comparison: Comparison,
+    pub value: Value<'s, 'v>,
+}
+
+impl std::fmt::Display for Bound<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.key, self.comparison, self.value)
+    }
+}
+
+// Constraints of a query on the keys returned by `filter_keys`: equalities
+// from the pattern or the guard and comparisons like `p.age >= 18` from the
+// guard. A backend may use any of them to skip values that can not match but
+// it does not have to: the pattern and guard of the query are checked on
+// every scanned value, so a backend can ignore constraints it can not
+// translate, eg comparisons with values it can not store.
+#[derive(Clone, Debug, Default)]
+pub struct ScanFilter<'s, 'v> {
+    pub equals: BTreeMap<String, Value<'s, 'v>>,
+    pub bounds: Vec<Bound<'s, 'v>>,
+}
+
+// Storage of a bag outside of damasc, eg a database table. Values are
+// scanned once per query. Changes are collected by the transaction and
+// handed to `delete` and `insert` when it is committed.
+pub trait BagBackend<'s, 'v>: Send + Sync {
+    fn filter_keys(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn scan(&self, filter: &ScanFilter<'s, 'v>) -> Result<Vec<Value<'s, 'v>>, BackendError>;
+
+    fn insert(&self, values: &[Value<'s, 'v>]) -> Result<(), BackendError>;
+
+    fn delete(&self, values: &[Value<'s, 'v>]) -> Result<(), BackendError>;
+
+    // Writes the changes of a committed transaction. Backends that can, eg
+    // databases, should write them at once so that nothing is lost if one
+    // of the changes fails.
+    fn write(&self, deleted: &[Value<'s, 'v>], inserted: &[Value<'s, 'v>]) -> Result<(), BackendError> {
+        self.delete(deleted)?;
+        self.insert(inserted)
+    }
+}
@@ -1,46 +1,136 @@
-use std::{borrow::Cow, collections::BTreeMap};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
 use gen_iter::gen_iter;
+use serde::{Deserialize, Serialize};
 
 const MAX_JOIN_SIZE: usize = 6;
 
 use crate::{
+    backend::{BackendError, BagBackend, Bound, ScanFilter},
+    bag_bundle::BagDelta,
     env::{Environment, EvalError},
-    expression::Expression,
+    expression::{ArrayItem, Expression},
+    identifier::Identifier,
+    index::{required_bounds, required_key, Index},
     matcher::Matcher,
+    metrics,
+    observer::Mutation,
     pattern::Pattern,
+    plan::PatternPlan,
+    profile,
+    statistics::{estimated_size, BagStatistics},
     query::{
-        check_value, DeletionQuery, Insertion, Predicate, ProjectionQuery, TransferQuery,
+        check_value, Accumulator, CrossPredicate, DeletionQuery, Insertion, Predicate, ProjectionQuery, TransferQuery,
         UpdateQuery,
     },
     value::Value,
+    virtual_bag::VirtualSource,
 };
 
+#[derive(Clone, Copy)]
+pub(crate) enum ItemFilter<'d> {
+    All,
+    Pivot {
+        new_items: Option<&'d BagDelta>,
+        position: usize,
+        pivot: usize,
+    },
+}
+
+impl<'d> ItemFilter<'d> {
+    fn admits(&self, index: usize) -> bool {
+        match self {
+            ItemFilter::All => true,
+            ItemFilter::Pivot {
+                new_items,
+                position,
+                pivot,
+            } => {
+                let is_new = new_items.map_or(false, |n| n.contains(index));
+                match position.cmp(pivot) {
+                    std::cmp::Ordering::Less => !is_new,
+                    std::cmp::Ordering::Equal => is_new,
+                    std::cmp::Ordering::Greater => true,
+                }
+            }
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            ItemFilter::All => ItemFilter::All,
+            ItemFilter::Pivot {
+                new_items,
+                position,
+                pivot,
+            } => ItemFilter::Pivot {
+                new_items,
+                position: position + 1,
+                pivot,
+            },
+        }
+    }
+}
+
+// How a bag treats a value inserted while an equal one is already stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageMode {
+    // Every inserted value is stored, duplicates included.
+    #[default]
+    Multiset,
+    // A bag created with `distinct` stores each value once and ignores the
+    // insertion of duplicates.
+    Distinct,
+    // A bag created with `counted` stores each value once together with the
+    // number of times it was inserted. Queries match every value once,
+    // `count_of` tells how often it is stored.
+    Counted,
+}
+
 #[derive(Clone)]
 pub struct ValueBag<'i, 's, 'v> {
     pub(crate) items: Vec<Cow<'v, Value<'s, 'v>>>,
     pub(crate) guard: Predicate<'s>,
+    pub(crate) mode: StorageMode,
+    // The positions of the items by their hash, only kept for distinct and
+    // counted bags to find duplicates without scanning all items.
+    members: HashMap<u64, Vec<usize>>,
+    // The multiplicity of each item of a counted bag.
+    counts: Vec<usize>,
+    pub(crate) indexes: BTreeMap<String, Index<'s, 'v>>,
+    pub(crate) source: Option<VirtualSource<'s, 'v>>,
+    pub(crate) backend: Option<Arc<dyn BagBackend<'s, 'v>>>,
+    // The values scanned from the backend once a transaction needs all of
+    // them to change or delete values. `items` then holds the full content.
+    loaded: Option<Vec<Value<'s, 'v>>>,
+    // The mutations made while a transaction needs to know them, eg for the
+    // observers of the bag.
+    pub(crate) journal: Option<Vec<Mutation<'s, 'v>>>,
     env: Environment<'i, 's, 'v>,
 }
 
 pub(crate) enum InsertionResult {
     Success(usize),
     GuardError,
-    EvalError,
+    EvalError(EvalError),
 }
 pub(crate) enum DeletionResult {
     Success(usize),
-    EvalError,
+    EvalError(EvalError),
 }
 pub(crate) enum UpdateResult {
     Success(usize),
     GuardError,
-    EvalError,
+    EvalError(EvalError),
 }
 pub(crate) enum TransferResult {
     Success(usize),
     GuardError,
-    EvalError,
+    EvalError(EvalError),
 }
 
 impl<'i, 's, 'v> ValueBag<'i, 's, 'v> {
@@ -48,9 +138,123 @@ impl<'i, 's, 'v> ValueBag<'i, 's, 'v> {
         Self {
             items: vec![],
             guard,
-            env: Environment {
-                bindings: BTreeMap::new(),
-            },
+            mode: StorageMode::Multiset,
+            members: HashMap::new(),
+            counts: vec![],
+            indexes: BTreeMap::new(),
+            source: None,
+            backend: None,
+            loaded: None,
+            journal: None,
+            env: Environment::new(),
+        }
+    }
+
+    pub(crate) fn new_in_mode(guard: Predicate<'s>, mode: StorageMode) -> Self {
+        Self {
+            mode,
+            ..Self::new(guard)
+        }
+    }
+
+    pub(crate) fn new_backend(backend: Arc<dyn BagBackend<'s, 'v>>) -> Self {
+        Self {
+            backend: Some(backend),
+            ..Self::new(Predicate::any())
+        }
+    }
+
+    pub(crate) fn new_virtual(source: VirtualSource<'s, 'v>) -> Self {
+        Self {
+            source: Some(source),
+            ..Self::new(Predicate::any())
+        }
+    }
+
+    pub(crate) fn is_virtual(&self) -> bool {
+        self.source.is_some()
+    }
+
+    // Until the backend is loaded `items` only holds the values inserted
+    // by the current transaction.
+    fn unloaded_backend(&self) -> Option<&Arc<dyn BagBackend<'s, 'v>>> {
+        self.backend.as_ref().filter(|_| self.loaded.is_none())
+    }
+
+    pub(crate) fn load_backend(&mut self) -> Result<(), BackendError> {
+        let Some(backend) = self.unloaded_backend() else {
+            return Ok(());
+        };
+
+        let values = backend.scan(&ScanFilter::default())?;
+        let inserted = std::mem::take(&mut self.items);
+        self.items = values.iter().cloned().map(Cow::Owned).chain(inserted).collect();
+        self.loaded = Some(values);
+        self.reindex();
+
+        Ok(())
+    }
+
+    // The values stay unchanged if the backend fails, so that writing them
+    // can be tried again.
+    pub(crate) fn flush_backend(&mut self) -> Result<(), BackendError> {
+        let Some(backend) = self.backend.clone() else {
+            return Ok(());
+        };
+
+        let mut counts = BTreeMap::<&Value<'s, 'v>, isize>::new();
+        for v in self.loaded.iter().flatten() {
+            *counts.entry(v).or_default() -= 1;
+        }
+        for v in self.iter() {
+            *counts.entry(v.as_ref()).or_default() += 1;
+        }
+
+        let mut deleted = vec![];
+        let mut inserted = vec![];
+        for (value, count) in counts {
+            let target = if count < 0 { &mut deleted } else { &mut inserted };
+            target.extend(std::iter::repeat(value).cloned().take(count.unsigned_abs()));
+        }
+
+        backend.write(&deleted, &inserted)?;
+        self.loaded = None;
+        self.items.clear();
+        self.counts.clear();
+        self.reindex();
+        Ok(())
+    }
+
+    fn scan_filter(
+        &self,
+        backend: &Arc<dyn BagBackend<'s, 'v>>,
+        env: &Environment<'i, 's, 'v>,
+        patterns: &[Pattern<'s>],
+        guard: &Expression<'s>,
+    ) -> ScanFilter<'s, 'v> {
+        let [pattern] = patterns else {
+            return ScanFilter::default();
+        };
+
+        let keys = backend.filter_keys();
+
+        ScanFilter {
+            equals: keys
+                .iter()
+                .filter_map(|key| Some((key.clone(), required_key(env, key, pattern, guard)?)))
+                .collect(),
+            bounds: keys
+                .iter()
+                .flat_map(|key| {
+                    required_bounds(env, key, pattern, guard)
+                        .into_iter()
+                        .map(|(comparison, value)| Bound {
+                            key: key.clone(),
+                            comparison,
+                            value,
+                        })
+                })
+                .collect(),
         }
     }
 
@@ -62,7 +266,7 @@ impl<'i, 's, 'v> ValueBag<'i, 's, 'v> {
         let mut counter = 0;
         for expr in &insertion.expressions.expressions {
             match self.insert_one(env, expr) {
-                InsertionResult::Success(_) => counter += 1,
+                InsertionResult::Success(count) => counter += count,
                 err => return err,
             }
         }
@@ -75,33 +279,177 @@ impl<'i, 's, 'v> ValueBag<'i, 's, 'v> {
         env: &'e Environment<'i, 's, 'v>,
         expression: &'e Expression<'s>,
     ) -> InsertionResult {
-        let eval_result = env.eval_expr(expression);
+        let value = match env.eval_expr(expression) {
+            Ok(value) => value,
+            Err(e) => return InsertionResult::EvalError(e),
+        };
 
-        if let Ok(value) = eval_result {
-            if check_value(&self.env, &self.guard, &value, self.len()) {
-                self.items.push(Cow::Owned(value.clone()));
-                InsertionResult::Success(1)
-            } else {
-                InsertionResult::GuardError
+        if check_value(&self.env, &self.guard, &value, self.len()) {
+            match (self.mode, self.position(&value)) {
+                (StorageMode::Multiset, _) => {}
+                (StorageMode::Distinct, Some(_)) => return InsertionResult::Success(0),
+                (StorageMode::Counted, Some(position)) => {
+                    self.counts[position] += 1;
+                    record(&mut self.journal, || Mutation::Insert(value));
+                    return InsertionResult::Success(1);
+                }
+                (StorageMode::Distinct | StorageMode::Counted, None) => {
+                    self.members
+                        .entry(hash(&value))
+                        .or_default()
+                        .push(self.items.len());
+                    if self.mode == StorageMode::Counted {
+                        self.counts.push(1);
+                    }
+                }
+            }
+            for (field, index) in &mut self.indexes {
+                index.add(field, self.items.len(), &value);
             }
+            self.items.push(Cow::Owned(value.clone()));
+            record(&mut self.journal, || Mutation::Insert(value));
+            InsertionResult::Success(1)
         } else {
-            InsertionResult::EvalError
+            InsertionResult::GuardError
         }
     }
 
+    // The position of the value in a distinct or counted bag.
+    fn position(&self, value: &Value<'s, 'v>) -> Option<usize> {
+        self.members
+            .get(&hash(value))?
+            .iter()
+            .copied()
+            .find(|&p| self.items[p].as_ref() == value)
+    }
+
+    fn count(&self, position: usize) -> usize {
+        self.counts.get(position).copied().unwrap_or(1)
+    }
+
+    // The number of values, counting each item of a counted bag as often
+    // as it is stored.
     pub(crate) fn len(&self) -> usize {
-        self.items.len()
+        match self.mode {
+            StorageMode::Counted => self.counts.iter().sum(),
+            _ => self.items.len(),
+        }
+    }
+
+    // The number of values also of virtual bags and backend bags, which are
+    // generated or scanned for it. None if they are unbounded or can not be
+    // scanned.
+    pub(crate) fn size(&self) -> Option<usize> {
+        if let Some(source) = &self.source {
+            return source.is_bounded().then(|| source.values().count());
+        }
+        if let Some(backend) = self.unloaded_backend() {
+            let scanned = backend.scan(&ScanFilter::default()).ok()?;
+            return Some(scanned.len() + self.items.len());
+        }
+
+        Some(self.len())
+    }
+
+    // None for virtual bags and backend bags not loaded by a transaction, as
+    // their values are not stored in the bag.
+    pub fn statistics(&self) -> Option<BagStatistics> {
+        if self.is_virtual() || self.unloaded_backend().is_some() {
+            return None;
+        }
+
+        if self.mode != StorageMode::Counted {
+            return Some(BagStatistics::collect(&self.items));
+        }
+
+        // Each item of a counted bag is held in memory only once.
+        let mut statistics = BagStatistics::collect(&self.iter().cloned().collect::<Vec<_>>());
+        statistics.bytes = self.items.iter().map(|v| estimated_size(v)).sum();
+        Some(statistics)
     }
 
     pub(crate) fn pop(&mut self, value: &Value<'s, 'v>) -> bool {
         if let Some(pos) = self.items.iter().position(|i| i.as_ref() == value) {
-            self.items.swap_remove(pos);
+            record(&mut self.journal, || Mutation::Delete(value.clone()));
+            if self.count(pos) > 1 {
+                self.counts[pos] -= 1;
+                return true;
+            }
+            self.swap_remove(pos);
             true
         } else {
             false
         }
     }
 
+    // Removes the item at the position by moving the last item in its place.
+    // Only the positions of these two items are updated in the indexes, so
+    // that popping single values does not rebuild them for the whole bag.
+    fn swap_remove(&mut self, position: usize) {
+        let last = self.items.len() - 1;
+        let removed = self.items.swap_remove(position);
+        if self.mode == StorageMode::Counted {
+            self.counts.swap_remove(position);
+        }
+        let moved = self.items.get(position).filter(|_| position != last);
+
+        if self.mode != StorageMode::Multiset {
+            let positions = self.members.entry(hash(&removed)).or_default();
+            positions.retain(|&p| p != position);
+            if positions.is_empty() {
+                self.members.remove(&hash(&removed));
+            }
+            if let Some(moved) = moved {
+                for p in self.members.entry(hash(moved)).or_default() {
+                    if *p == last {
+                        *p = position;
+                    }
+                }
+            }
+        }
+
+        for (field, index) in &mut self.indexes {
+            index.swap_remove(field, position, &removed, last, moved.map(|m| m.as_ref()));
+        }
+    }
+
+    // Removes the values at the given positions, keeping the order of the
+    // remaining ones. Items of a counted bag only lose one of their copies.
+    pub(crate) fn remove_positions(&mut self, positions: &BTreeSet<usize>) {
+        let mut removed = BTreeSet::new();
+        for &position in positions {
+            let Some(item) = self.items.get(position) else {
+                continue;
+            };
+            record(&mut self.journal, || Mutation::Delete(item.as_ref().clone()));
+            if self.count(position) > 1 {
+                self.counts[position] -= 1;
+            } else {
+                removed.insert(position);
+            }
+        }
+        self.remove_items(&removed);
+        self.reindex();
+    }
+
+    fn remove_items(&mut self, positions: &BTreeSet<usize>) {
+        let mut position = 0;
+        self.items.retain(|_| {
+            position += 1;
+            !positions.contains(&(position - 1))
+        });
+        self.remove_counts(positions);
+    }
+
+    // Keeps the counts aligned with the items after the items at the given
+    // positions were removed.
+    fn remove_counts(&mut self, positions: &BTreeSet<usize>) {
+        let mut position = 0;
+        self.counts.retain(|_| {
+            position += 1;
+            !positions.contains(&(position - 1))
+        });
+    }
 
     pub(crate) fn query<'e, 'x: 'e>(
         &'x self,
@@ -110,56 +458,254 @@ impl<'i, 's, 'v> ValueBag<'i, 's, 'v> {
     ) -> impl Iterator<Item = Result<Value<'s, 'v>, EvalError>> + 'e {
         gen_iter!(move {
             let matcher = Matcher::new(&env);
+            let offset = query.predicate.offset.unwrap_or(0);
             let mut count = 0;
 
+            // All results have to be known before the first one can be
+            // returned, so the key is projected along with each result
+            // and the offset and limit applied after sorting.
+            if let Some(order) = &query.order {
+                if let Err(e) = order.collation.check() {
+                    yield Err(e);
+                    return;
+                }
+                let unordered = ProjectionQuery {
+                    projection: Expression::Array(vec![
+                        ArrayItem::Single(order.key.clone()),
+                        ArrayItem::Single(query.projection.clone()),
+                    ]),
+                    predicate: CrossPredicate {
+                        offset: None,
+                        limit: None,
+                        ..query.predicate.clone()
+                    },
+                    order: None,
+                    ..query.clone()
+                };
+                let mut rows = vec![];
+                for result in self.query(env, &unordered).collect::<Vec<_>>() {
+                    match result {
+                        Ok(Value::Array(mut pair)) if pair.len() == 2 => {
+                            let value = pair.pop().unwrap().into_owned();
+                            let key = pair.pop().unwrap().into_owned();
+                            rows.push((key, value));
+                        }
+                        Ok(_) => unreachable!("ordered results are projected as [key, value]"),
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    }
+                }
+
+                let mut failed = None;
+                rows.sort_by(|(a, _), (b, _)| {
+                    let (a, b) = if order.descending { (b, a) } else { (a, b) };
+                    order.collation.compare(a, b).unwrap_or_else(|e| {
+                        failed.get_or_insert(e);
+                        a.cmp(b)
+                    })
+                });
+                if let Some(e) = failed {
+                    yield Err(e);
+                    return;
+                }
+                let offset = query.predicate.offset.unwrap_or(0);
+                let limit = query.predicate.limit.unwrap_or(rows.len());
+                for (_, value) in rows.into_iter().skip(offset).take(limit) {
+                    yield Ok(value);
+                }
+                return;
+            }
+
             if query.predicate.patterns.len() > MAX_JOIN_SIZE {
                 yield Err(EvalError::Overflow);
                 return;
             }
 
+            let unbounded = self.source.as_ref().map_or(false, |s| !s.is_bounded());
+            if unbounded
+                && (query.predicate.limit.is_none()
+                    || query.is_aggregation()
+                    || query.predicate.patterns.len() > 1)
+            {
+                yield Err(EvalError::OutOfFuel);
+                return;
+            }
+
+            if let Some(backend) = self.unloaded_backend() {
+                let filter = self.scan_filter(backend, env, &query.predicate.patterns, &query.predicate.guard);
+                let scanned = match backend.scan(&filter) {
+                    Ok(values) => values,
+                    Err(_) => {
+                        yield Err(EvalError::BackendFailed);
+                        return;
+                    }
+                };
+
+                let mut bag = ValueBag::new(self.guard.clone());
+                bag.items = scanned.into_iter().map(Cow::Owned).chain(self.items.iter().cloned()).collect();
+                let results = bag.query(env, query).collect::<Vec<_>>();
+                for result in results {
+                    yield result;
+                }
+                return;
+            }
+
             let duplicates = Vec::with_capacity(query.predicate.patterns.len());
 
-            for (m, _) in self.cross_query_helper(query.outer, duplicates, matcher, &query.predicate.patterns) {
-                let mut env = env.clone();
-                m.into_env().merge(&mut env);
-                if let Ok(Value::Boolean(true)) = env.eval_expr(&query.predicate.guard) {
+            let candidates = match query.predicate.patterns.as_slice() {
+                [pattern] if query.allows_index() => self.candidates(env, pattern, &query.predicate.guard),
+                _ => None,
+            };
+
+            if query.is_aggregation() {
+                let groups = match self.aggregate(env, query, matcher, duplicates, candidates) {
+                    Ok(groups) => groups,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+
+                for (mut env, accumulators) in groups.into_iter().skip(offset) {
+                    for (aggregate, accumulator) in query.aggregates.iter().zip(accumulators) {
+                        env.bindings.insert(
+                            Identifier { name: Cow::Owned(aggregate.binding.name.to_string()) },
+                            accumulator.finish(),
+                        );
+                    }
                     yield env.eval_expr(&query.projection);
-                    count+=1;
+                    count += 1;
                     if let Some(l) = query.predicate.limit {
                         if count >= l {
                             break;
                         }
                     }
                 }
+                return;
+            }
+
+            for (m, matched) in self.cross_query_helper(query.outer, duplicates, matcher, &query.predicate.patterns, query.predicate.plans(), ItemFilter::All, candidates) {
+                let mut env = env.clone();
+                m.merge_into(&mut env);
+                env.multiplicities = self.multiplicities(&matched);
+                if profile::accepts(&env, &query.predicate.guard) {
+                    count+=1;
+                    if count <= offset {
+                        continue;
+                    }
+                    yield env.eval_expr(&query.projection);
+                    if let Some(l) = query.predicate.limit {
+                        if count >= offset + l {
+                            return;
+                        }
+                    }
+                }
+            }
+
+            // The scan budget ran out before the limit was reached.
+            if unbounded {
+                yield Err(EvalError::OutOfFuel);
             }
         })
     }
 
+    fn aggregate<'e, 'x: 'e>(
+        &'x self,
+        env: &'e Environment<'i, 's, 'v>,
+        query: &'e ProjectionQuery<'s>,
+        matcher: Matcher<'i, 's, 'v, 'e>,
+        duplicates: Vec<usize>,
+        candidates: Option<Vec<usize>>,
+    ) -> Result<Vec<(Environment<'i, 's, 'v>, Vec<Accumulator<'s, 'v>>)>, EvalError> {
+        let mut groups = Vec::new();
+        let mut positions = BTreeMap::new();
+
+        for (m, matched) in self.cross_query_helper(query.outer, duplicates, matcher, &query.predicate.patterns, query.predicate.plans(), ItemFilter::All, candidates) {
+            let mut env = env.clone();
+            m.merge_into(&mut env);
+            env.multiplicities = self.multiplicities(&matched);
+            if !profile::accepts(&env, &query.predicate.guard) {
+                continue;
+            }
+
+            let key = match &query.group {
+                Some(g) => env.eval_expr(g)?,
+                None => Value::Null,
+            };
+            let position = *positions.entry(key).or_insert_with(|| {
+                groups.push((env.clone(), query.aggregates.iter().map(|a| Accumulator::new(a.function)).collect::<Vec<_>>()));
+                groups.len() - 1
+            });
+
+            for (aggregate, accumulator) in query.aggregates.iter().zip(&mut groups[position].1) {
+                let argument = aggregate.argument.as_ref().map(|a| env.eval_expr(a)).transpose()?;
+                accumulator.add(argument)?;
+            }
+        }
+
+        if groups.is_empty() && query.group.is_none() {
+            groups.push((env.clone(), query.aggregates.iter().map(|a| Accumulator::new(a.function)).collect()));
+        }
+
+        Ok(groups)
+    }
+
+    // The counts of the items matched by one result of a query on a counted
+    // bag, for `count_of`.
+    fn multiplicities(&self, matched: &[usize]) -> Option<Vec<(Value<'s, 'v>, usize)>> {
+        if self.mode != StorageMode::Counted {
+            return None;
+        }
+
+        Some(
+            matched
+                .iter()
+                .map(|&p| (self.items[p].as_ref().clone(), self.counts[p]))
+                .collect(),
+        )
+    }
+
     pub(crate) fn cross_query_helper<'e, 'x: 'e, 'dup>(
         &'x self,
         outer: bool,
         mut skip: Vec<usize>,
-        matcher: Matcher<'i, 's, 'v, 'e>,
+        mut matcher: Matcher<'i, 's, 'v, 'e>,
         patterns: &'e [Pattern<'s>],
+        plans: &'e [PatternPlan],
+        filter: ItemFilter<'e>,
+        candidates: Option<Vec<usize>>,
     ) -> Box<dyn Iterator<Item = (Matcher<'i, 's, 'v, 'e>, Vec<usize>)> + 'e> {
-        let Some(pattern) = patterns.get(0) else {
+        let (Some(pattern), Some(plan)) = (patterns.get(0), plans.get(0)) else {
             return Box::new(Some((matcher.clone(), skip)).into_iter())
         };
 
         Box::new(gen_iter!(move {
-            for (idx, item) in self.items.iter().enumerate() {
-                if !outer && skip.contains(&idx) {
+            let checkpoint = matcher.checkpoint();
+            let items: Box<dyn Iterator<Item = (usize, Cow<Value<'s, 'v>>)>> = match (&self.source, candidates) {
+                (Some(source), _) => Box::new(source.values().map(Cow::Owned).enumerate()),
+                (None, Some(c)) => Box::new(c.into_iter().map(|idx| (idx, Cow::Borrowed(self.items[idx].as_ref())))),
+                (None, None) => Box::new(self.items.iter().map(|i| Cow::Borrowed(i.as_ref())).enumerate()),
+            };
+
+            for (idx, item) in items {
+                if !outer && skip.contains(&idx) || !filter.admits(idx) {
                     continue;
                 }
 
-                let mut m = matcher.clone();
-                let Ok(()) = m.match_pattern(pattern, item) else {
+                matcher.rollback(checkpoint);
+                metrics::count_scanned();
+                let mut solutions = plan.solutions(pattern, &mut matcher, item).peekable();
+                if solutions.peek().is_none() {
                     continue;
-                };
+                }
 
                 skip.push(idx);
-                for mm in self.cross_query_helper(outer, skip.clone(), m, &patterns[1..]) {
-                    yield mm;
+                for m in solutions {
+                    for mm in self.cross_query_helper(outer, skip.clone(), m, &patterns[1..], &plans[1..], filter.next(), None) {
+                        yield mm;
+                    }
                 }
                 skip.pop();
             }
@@ -172,13 +718,26 @@ impl<'i, 's, 'v> ValueBag<'i, 's, 'v> {
         deletion: &'e DeletionQuery<'s>,
     ) -> DeletionResult {
         let mut counter = 0;
-        let mut eval_error = false;
+        let mut skipped = 0;
+        let mut eval_error = None;
         let mut matcher = Matcher::new(&env);
+        let plan = deletion.predicate.plan();
+        let candidates = self.candidates(env, &deletion.predicate.pattern, &deletion.predicate.guard);
+        let mut position = 0;
+        let mut removed = BTreeSet::new();
+        let counts = &mut self.counts;
+        let journal = &mut self.journal;
 
         self.items.retain(|item| {
-            if eval_error {
+            position += 1;
+            if eval_error.is_some() {
                 return true;
             }
+            if let Some(c) = &candidates {
+                if c.binary_search(&(position - 1)).is_err() {
+                    return true;
+                }
+            }
             if let Some(limit) = deletion.predicate.limit {
                 if limit <= counter {
                     return true;
@@ -186,21 +745,39 @@ impl<'i, 's, 'v> ValueBag<'i, 's, 'v> {
             }
 
             matcher.clear();
+            metrics::count_scanned();
 
             if !matches!(
-                matcher.match_pattern(&deletion.predicate.pattern, item.as_ref()),
+                plan.execute(&deletion.predicate.pattern, &mut matcher, item.as_ref()),
                 Ok(())
             ) {
                 true
             } else {
                 let mut env = env.clone();
-                matcher.local_env.clone().merge(&mut env);
-                let Ok(Value::Boolean(shall_delete)) = env.eval_expr(&deletion.predicate.guard) else {
-                    eval_error = true;
-                    return true;
+                matcher.merge_into(&mut env);
+                let shall_delete = match env.eval_guard(&deletion.predicate.guard) {
+                    Ok(shall_delete) => shall_delete,
+                    Err(e) => {
+                        eval_error = Some(e);
+                        return true;
+                    }
                 };
-                if shall_delete {
-                    counter += 1;
+                if shall_delete && skipped < deletion.predicate.offset.unwrap_or(0) {
+                    skipped += 1;
+                    true
+                } else if shall_delete {
+                    let stored = counts.get(position - 1).copied().unwrap_or(1);
+                    let copies = deletion.predicate.limit.map_or(stored, |l| stored.min(l - counter));
+                    for _ in 0..copies {
+                        record(journal, || Mutation::Delete(item.as_ref().clone()));
+                    }
+                    counter += copies;
+                    // The limit may leave some copies of a counted item.
+                    if copies < stored {
+                        counts[position - 1] -= copies;
+                        return true;
+                    }
+                    removed.insert(position - 1);
                     false
                 } else {
                     true
@@ -208,10 +785,14 @@ impl<'i, 's, 'v> ValueBag<'i, 's, 'v> {
             }
         });
 
-        if eval_error {
-            DeletionResult::EvalError
-        } else {
-            DeletionResult::Success(counter)
+        if counter > 0 {
+            self.remove_counts(&removed);
+            self.reindex();
+        }
+
+        match eval_error {
+            Some(e) => DeletionResult::EvalError(e),
+            None => DeletionResult::Success(counter),
         }
     }
     pub(crate) fn update<'e, 'x: 'e>(
@@ -220,53 +801,232 @@ impl<'i, 's, 'v> ValueBag<'i, 's, 'v> {
         update: &'e UpdateQuery<'s>,
     ) -> UpdateResult {
         let mut counter = 0;
+        let result = self.update_items(env, update, &mut counter);
+
+        if counter > 0 {
+            self.reindex();
+        }
+
+        result
+    }
 
+    fn update_items<'e>(
+        &mut self,
+        env: &'e Environment<'i, 's, 'v>,
+        update: &'e UpdateQuery<'s>,
+        counter: &mut usize,
+    ) -> UpdateResult {
         let mut matcher = Matcher::new(&env);
+        let plan = update.predicate.plan();
+        let mut skipped = 0;
 
         let bag_size = self.items.len();
+        let positions = self
+            .candidates(env, &update.predicate.pattern, &update.predicate.guard)
+            .unwrap_or_else(|| (0..bag_size).collect());
 
-        for item in &mut self.items {
+        for position in positions {
             if let Some(limit) = update.predicate.limit {
-                if limit <= counter {
-                    return UpdateResult::Success(counter);
+                if limit <= *counter {
+                    return UpdateResult::Success(*counter);
                 }
             }
 
             matcher.clear();
+            metrics::count_scanned();
 
-            if matches!(
-                matcher.match_pattern(&update.predicate.pattern, item.as_ref()),
-                Ok(())
-            ) {
+            let item = &mut self.items[position];
+            if plan.execute(&update.predicate.pattern, &mut matcher, item.as_ref()).is_err() {
                 continue;
-            } else {
-                let mut env = env.clone();
-                matcher.local_env.clone().merge(&mut env);
-                let Ok(Value::Boolean(should_update)) = env.eval_expr(&update.predicate.guard) else {
-                    return UpdateResult::EvalError;
-                };
+            }
 
-                if should_update {
-                    let Ok(val) = env.eval_expr(&update.projection) else {
-                        return UpdateResult::EvalError;
-                    };
-                    if check_value(&env, &self.guard, &val, bag_size) {
-                        *item = Cow::Owned(val);
-                        counter += 1;
-                    } else {
-                        return UpdateResult::GuardError;
+            let mut env = env.clone();
+            matcher.merge_into(&mut env);
+            let should_update = match env.eval_guard(&update.predicate.guard) {
+                Ok(should_update) => should_update,
+                Err(e) => return UpdateResult::EvalError(e),
+            };
+
+            if should_update && skipped < update.predicate.offset.unwrap_or(0) {
+                skipped += 1;
+            } else if should_update {
+                let val = match env.eval_expr(&update.projection) {
+                    Ok(val) => val,
+                    Err(e) => return UpdateResult::EvalError(e),
+                };
+                if check_value(&env, &self.guard, &val, bag_size) {
+                    for _ in 0..self.counts.get(position).copied().unwrap_or(1) {
+                        record(&mut self.journal, || Mutation::Update {
+                            before: item.as_ref().clone(),
+                            after: val.clone(),
+                        });
                     }
+                    *item = Cow::Owned(val);
+                    *counter += 1;
                 } else {
-                    continue;
+                    return UpdateResult::GuardError;
+                }
+            }
+        }
+        UpdateResult::Success(*counter)
+    }
+
+    pub(crate) fn create_index(&mut self, field: &str) -> usize {
+        let mut index = Index::default();
+        for (position, item) in self.items.iter().enumerate() {
+            index.add(field, position, item);
+        }
+        self.indexes.insert(field.to_string(), index);
+
+        self.items.len()
+    }
+
+    // Rebuilds the indexes after items were removed or changed. A distinct
+    // bag drops the duplicates an update may have produced, keeping the first
+    // of them, a counted bag adds up their counts.
+    fn reindex(&mut self) {
+        if self.mode != StorageMode::Multiset {
+            self.members.clear();
+            let items = std::mem::take(&mut self.items);
+            let counts = std::mem::take(&mut self.counts);
+            for (position, item) in items.into_iter().enumerate() {
+                let count = counts.get(position).copied().unwrap_or(1);
+                match self.position(&item) {
+                    Some(p) if self.mode == StorageMode::Counted => self.counts[p] += count,
+                    Some(_) => {}
+                    None => {
+                        self.members
+                            .entry(hash(&item))
+                            .or_default()
+                            .push(self.items.len());
+                        self.items.push(item);
+                        if self.mode == StorageMode::Counted {
+                            self.counts.push(count);
+                        }
+                    }
                 }
             }
         }
-        UpdateResult::Success(counter)
+
+        let fields = self.indexes.keys().cloned().collect::<Vec<_>>();
+        for field in fields {
+            self.create_index(&field);
+        }
     }
 
-    pub(crate) fn iter<'x>(&'x self) -> std::slice::Iter<'x, std::borrow::Cow<'v, Value<'s, 'v>>> {
-        self.items.iter()
+    fn candidates(
+        &self,
+        env: &Environment<'i, 's, 'v>,
+        pattern: &Pattern<'s>,
+        guard: &Expression<'s>,
+    ) -> Option<Vec<usize>> {
+        self.indexes.iter().find_map(|(field, index)| {
+            let key = required_key(env, field, pattern, guard)?;
+            Some(index.get(&key).to_vec())
+        })
     }
+
+    // The values matching the predicate, the items of a counted bag repeated
+    // by their count.
+    pub(crate) fn matching<'e>(
+        &self,
+        env: &'e Environment<'i, 's, 'v>,
+        predicate: &'e Predicate<'s>,
+    ) -> Result<Vec<Value<'s, 'v>>, EvalError> {
+        let unbounded = self.source.as_ref().map_or(false, |s| !s.is_bounded());
+        if unbounded && predicate.limit.is_none() {
+            return Err(EvalError::OutOfFuel);
+        }
+
+        let values: Box<dyn Iterator<Item = Cow<Value<'s, 'v>>>> = match &self.source {
+            Some(source) => Box::new(source.values().map(Cow::Owned)),
+            None => Box::new(self.iter().map(|v| Cow::Borrowed(v.as_ref()))),
+        };
+        let mut matcher = Matcher::new(env);
+        let plan = predicate.plan();
+        let mut skipped = 0;
+        let mut result = vec![];
+
+        for value in values {
+            if predicate.limit.map_or(false, |l| result.len() >= l) {
+                break;
+            }
+
+            matcher.clear();
+            metrics::count_scanned();
+            if plan.execute(&predicate.pattern, &mut matcher, value.as_ref()).is_err() {
+                continue;
+            }
+
+            let mut env = env.clone();
+            matcher.merge_into(&mut env);
+            match env.eval_expr(&predicate.guard)? {
+                Value::Boolean(true) if skipped < predicate.offset.unwrap_or(0) => skipped += 1,
+                Value::Boolean(true) => result.push(value.into_owned()),
+                Value::Boolean(false) => {}
+                _ => return Err(EvalError::TypeError),
+            }
+        }
+
+        if unbounded && predicate.limit.map_or(false, |l| result.len() < l) {
+            return Err(EvalError::OutOfFuel);
+        }
+
+        Ok(result)
+    }
+
+    // Every value, the items of a counted bag repeated by their count.
+    pub(crate) fn iter<'x>(&'x self) -> impl Iterator<Item = &'x Cow<'v, Value<'s, 'v>>> + 'x {
+        self.items
+            .iter()
+            .enumerate()
+            .flat_map(|(position, item)| std::iter::repeat(item).take(self.count(position)))
+    }
+
+    pub(crate) fn write<W: std::io::Write>(&self, name: &Identifier, out: &mut W) -> std::io::Result<()> {
+        match &self.source {
+            Some(VirtualSource::Range(range)) => return writeln!(out, ".bag {name} as virtual {range}"),
+            Some(VirtualSource::Union(bags, _)) => {
+                let bags = bags.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+                return writeln!(out, ".view {name} := union of {}", bags.join(", "));
+            }
+            Some(VirtualSource::External(_) | VirtualSource::System(_)) => return Ok(()),
+            None if self.backend.is_some() => return Ok(()),
+            None => {}
+        }
+
+        write!(out, ".bag {name} as {} where {}", self.guard.pattern, self.guard.guard)?;
+        if let Some(l) = self.guard.limit {
+            write!(out, " limit {l}")?;
+        }
+        match self.mode {
+            StorageMode::Multiset => {}
+            StorageMode::Distinct => write!(out, " distinct")?,
+            StorageMode::Counted => write!(out, " counted")?,
+        }
+        writeln!(out)?;
+
+        for v in self.iter() {
+            writeln!(out, "{v};")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn record<'s, 'v>(
+    journal: &mut Option<Vec<Mutation<'s, 'v>>>,
+    mutation: impl FnOnce() -> Mutation<'s, 'v>,
+) {
+    if let Some(journal) = journal {
+        journal.push(mutation());
+    }
+}
+
+pub(crate) fn hash(value: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
 }
 
 pub(crate) struct ValueBagTransfer<'x, 'i, 's, 'v> {
@@ -287,10 +1047,17 @@ impl<'x, 'i, 's, 'v> ValueBagTransfer<'x, 'i, 's, 'v> {
         transfer: &'e TransferQuery<'s>,
     ) -> TransferResult {
         let mut counter: usize = 0;
+        let mut skipped = 0;
         let mut short_circuit: Option<TransferResult> = None;
         let mut matcher = Matcher::new(&env);
+        let plan = transfer.predicate.plan();
+        let mut position = 0;
+        let mut removed = BTreeSet::new();
+        let counts = &mut self.source.counts;
+        let journal = &mut self.source.journal;
 
         self.source.items.retain(|item| {
+            position += 1;
             if short_circuit.is_some() {
                 return true;
             }
@@ -302,40 +1069,62 @@ impl<'x, 'i, 's, 'v> ValueBagTransfer<'x, 'i, 's, 'v> {
             }
 
             matcher.clear();
+            metrics::count_scanned();
 
             if !matches!(
-                matcher.match_pattern(&transfer.predicate.pattern, item.as_ref()),
+                plan.execute(&transfer.predicate.pattern, &mut matcher, item.as_ref()),
                 Ok(())
             ) {
                 true
             } else {
                 let mut env = env.clone();
-                matcher.local_env.clone().merge(&mut env);
-                let Ok(Value::Boolean(shall_transfer)) = env.eval_expr(&transfer.predicate.guard) else {
-                    short_circuit = Some(TransferResult::EvalError);
-                    return true;
+                matcher.merge_into(&mut env);
+                let shall_transfer = match env.eval_guard(&transfer.predicate.guard) {
+                    Ok(shall_transfer) => shall_transfer,
+                    Err(e) => {
+                        short_circuit = Some(TransferResult::EvalError(e));
+                        return true;
+                    }
                 };
-                if shall_transfer {
-                    match self.target.insert_one(&env, &transfer.projection) {
-                        InsertionResult::Success(_) => {
-                            counter += 1;
-                            false
-                        },
-                        InsertionResult::EvalError => {
-                            short_circuit = Some(TransferResult::EvalError);
-                            true
-                        }
-                        InsertionResult::GuardError => {
-                            short_circuit = Some(TransferResult::GuardError);
-                            true
+                if shall_transfer && skipped < transfer.predicate.offset.unwrap_or(0) {
+                    skipped += 1;
+                    true
+                } else if shall_transfer {
+                    let stored = counts.get(position - 1).copied().unwrap_or(1);
+                    let copies = transfer.predicate.limit.map_or(stored, |l| stored.min(l - counter));
+                    for _ in 0..copies {
+                        match self.target.insert_one(&env, &transfer.projection) {
+                            InsertionResult::Success(_) => {}
+                            InsertionResult::EvalError(e) => {
+                                short_circuit = Some(TransferResult::EvalError(e));
+                                return true;
+                            }
+                            InsertionResult::GuardError => {
+                                short_circuit = Some(TransferResult::GuardError);
+                                return true;
+                            }
                         }
+                        record(journal, || Mutation::Delete(item.as_ref().clone()));
+                    }
+                    counter += copies;
+                    // The limit may leave some copies of a counted item.
+                    if copies < stored {
+                        counts[position - 1] -= copies;
+                        return true;
                     }
+                    removed.insert(position - 1);
+                    false
                 } else {
                     true
                 }
             }
         });
 
+        if counter > 0 {
+            self.source.remove_counts(&removed);
+            self.source.reindex();
+        }
+
         short_circuit.unwrap_or(TransferResult::Success(counter))
     }
 }
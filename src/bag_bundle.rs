@@ -1,40 +1,415 @@
 use std::{
     borrow::Cow,
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
 };
 
 use crate::bag::DeletionResult;
 use crate::bag::TransferResult;
 use crate::{
+    backend::BagBackend,
     bag::{InsertionResult, UpdateResult},
-    bag::{ValueBag, ValueBagTransfer},
+    bag::{StorageMode, ValueBag, ValueBagTransfer},
     env::{Environment, EvalError},
     expression::Expression,
+    fingerprint,
+    graph_solver::ChangeSet,
     identifier::Identifier,
+    merge::{self, MergeResult, Preference},
+    observer::{Mutation, MutationObserver},
     query::{DeletionQuery, Insertion, Predicate, ProjectionQuery, TransferQuery, UpdateQuery},
+    storage::Storage,
     value::Value,
+    virtual_bag::{self, ValueGenerator, VirtualSource},
 };
 
 #[derive(Clone)]
 pub struct BagBundle<'b, 'i, 's, 'v> {
     pub bags: HashMap<Identifier<'s>, Cow<'b, ValueBag<'i, 's, 'v>>>,
+    pub(crate) changes: Delta<'s>,
+    pub(crate) storage: Option<Storage>,
+    pub(crate) observers: Vec<(Identifier<'s>, Arc<dyn MutationObserver<'s, 'v>>)>,
+    // The changes of observed bags in the current transaction.
+    pub(crate) mutations: Vec<(Identifier<'s>, Mutation<'s, 'v>)>,
+    // While a transaction opened by `.begin` is running, the bags changed and
+    // the mutations observed by the statements committed so far. They are
+    // flushed, notified and persisted once it is finished.
+    pub(crate) pending: Option<Pending<'s, 'v>>,
+    journal: Journal<'s, 'v>,
+    // The number of transactions committed before this bundle was, and the
+    // number committed to any bundle derived from the same one. A transaction
+    // on an outdated bundle can not be committed.
+    version: u64,
+    head: Arc<AtomicU64>,
+    // The values of the views by the fingerprint of their definition, along
+    // with the bags they are the union of, see `fingerprint::view`.
+    views: HashMap<u64, (Vec<Identifier<'s>>, Arc<OnceLock<Vec<Value<'s, 'v>>>>)>,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Pending<'s, 'v> {
+    changed: BTreeSet<Identifier<'s>>,
+    mutations: Vec<(Identifier<'s>, Mutation<'s, 'v>)>,
+    // Whether an attempt to finish stored the changes before it failed to
+    // notify the observers, so that trying again does not store them twice.
+    stored: bool,
+}
+
+// The mutations of the bags watched via `watch`, across the transactions
+// committed meanwhile, along with the connection making them, if any.
+#[derive(Clone, Default)]
+struct Journal<'s, 'v> {
+    watched: Vec<Identifier<'s>>,
+    entries: Vec<Journaled<'s, 'v>>,
+    producer: Option<Identifier<'s>>,
+}
+
+pub(crate) type Journaled<'s, 'v> = (Identifier<'s>, Mutation<'s, 'v>, Option<Identifier<'s>>);
+
+#[derive(Clone, Debug)]
+pub(crate) enum BagDelta {
+    Appended(BTreeSet<usize>),
+    Rewritten,
+}
+
+impl BagDelta {
+    pub(crate) fn contains(&self, index: usize) -> bool {
+        match self {
+            BagDelta::Appended(indices) => indices.contains(&index),
+            BagDelta::Rewritten => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Delta<'s> {
+    bags: BTreeMap<Identifier<'s>, BagDelta>,
+}
+
+impl<'s> Delta<'s> {
+    pub(crate) fn get(&self, bag: &Identifier<'s>) -> Option<&BagDelta> {
+        self.bags.get(bag)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Identifier<'s>, &BagDelta)> {
+        self.bags.iter()
+    }
+
+    fn append(&mut self, bag: &Identifier<'s>, indices: std::ops::Range<usize>) {
+        match self
+            .bags
+            .entry(bag.clone())
+            .or_insert_with(|| BagDelta::Appended(BTreeSet::new()))
+        {
+            BagDelta::Appended(existing) => existing.extend(indices),
+            BagDelta::Rewritten => {}
+        }
+    }
+
+    // Inserting into a counted bag may only increase the count of an item,
+    // which can not be appended, so the bag is rewritten as a whole.
+    fn insert(&mut self, bag_name: &Identifier<'s>, bag: &ValueBag, before: usize) {
+        if bag.mode == StorageMode::Counted {
+            self.rewrite(bag_name);
+        } else {
+            self.append(bag_name, before..bag.items.len());
+        }
+    }
+
+    fn rewrite(&mut self, bag: &Identifier<'s>) {
+        self.bags.insert(bag.clone(), BagDelta::Rewritten);
+    }
+
+    fn merge(&mut self, other: &Delta<'s>) {
+        for (bag, delta) in &other.bags {
+            match delta {
+                BagDelta::Appended(indices) => match self
+                    .bags
+                    .entry(bag.clone())
+                    .or_insert_with(|| BagDelta::Appended(BTreeSet::new()))
+                {
+                    BagDelta::Appended(existing) => existing.extend(indices),
+                    BagDelta::Rewritten => {}
+                },
+                BagDelta::Rewritten => self.rewrite(bag),
+            }
+        }
+    }
 }
 
 impl<'b, 'i, 's, 'v> BagBundle<'b, 'i, 's, 'v> {
     pub(crate) fn new() -> Self {
         Self {
             bags: HashMap::new(),
+            changes: Delta::default(),
+            storage: None,
+            observers: vec![],
+            mutations: vec![],
+            pending: None,
+            journal: Journal::default(),
+            version: 0,
+            head: Arc::new(AtomicU64::new(0)),
+            views: HashMap::new(),
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    // Whether no transaction was committed since this bundle was, so that
+    // one can be committed to it.
+    pub(crate) fn is_latest(&self) -> bool {
+        self.version == self.head.load(Ordering::SeqCst)
+    }
+
+    // Goes back to an earlier snapshot, eg on `.rollback`, discarding the
+    // changes committed to this bundle since. The changes committed by
+    // others meanwhile can not be discarded, so this fails with a conflict
+    // then and drops the effects deferred since `begin` instead. The bags
+    // watched meanwhile stay watched.
+    pub(crate) fn restore(&mut self, snapshot: BagBundle<'b, 'i, 's, 'v>) -> Result<(), TransactionError> {
+        if !self.is_latest() {
+            self.pending = None;
+            return Err(TransactionError::Conflict);
+        }
+
+        let journal = std::mem::take(&mut self.journal);
+        let version = self.version;
+        *self = snapshot;
+        self.journal = journal;
+        self.version = version;
+
+        Ok(())
+    }
+
+    // Journals the mutations of the bags until `unwatch`, eg to find the
+    // values hitting a breakpoint. Returns the start of their journal.
+    pub(crate) fn watch(&mut self, bags: &[Identifier<'s>]) -> usize {
+        self.journal.watched.extend(bags.iter().cloned());
+        self.journal.entries.len()
+    }
+
+    // The mutations of the bags committed since `start`, in the order they
+    // were made.
+    pub(crate) fn unwatch(&mut self, bags: &[Identifier<'s>], start: usize) -> Vec<Journaled<'s, 'v>> {
+        let journal = &mut self.journal;
+        let entries = journal
+            .entries
+            .get(start..)
+            .unwrap_or_default()
+            .iter()
+            .filter(|(b, ..)| bags.contains(b))
+            .cloned()
+            .collect();
+        for bag in bags {
+            if let Some(p) = journal.watched.iter().position(|b| b == bag) {
+                journal.watched.swap_remove(p);
+            }
+        }
+        if journal.watched.is_empty() {
+            journal.entries.clear();
+        }
+
+        entries
+    }
+
+    fn is_journaled(&self, bag_name: &Identifier<'s>) -> bool {
+        self.journal.watched.contains(bag_name) || self.observers.iter().any(|(b, _)| b == bag_name)
+    }
+
+    fn journal(&mut self, bag_name: &Identifier<'s>, mutations: Vec<Mutation<'s, 'v>>) {
+        if self.observers.iter().any(|(b, _)| b == bag_name) {
+            self.mutations
+                .extend(mutations.iter().map(|m| (bag_name.clone(), m.clone())));
+        }
+        if self.journal.watched.contains(bag_name) {
+            let producer = &self.journal.producer;
+            let entries = mutations
+                .into_iter()
+                .map(|m| (bag_name.clone(), m, producer.clone()));
+            self.journal.entries.extend(entries);
+        }
+    }
+
+    // Defers the effects of committing statements until `finish`.
+    pub(crate) fn begin(&mut self) {
+        self.pending = Some(Pending::default());
+    }
+
+    // Applies the effects deferred since `begin`. They stay deferred if that
+    // fails, so that finishing can be tried again.
+    pub(crate) fn finish(&mut self) -> Result<(), TransactionError> {
+        let Some(mut pending) = self.pending.take() else {
+            return Ok(());
+        };
+
+        let mut result = Ok(());
+        if !pending.stored {
+            result = self.store(&pending.changed);
+            pending.stored = result.is_ok();
+        }
+        result = result.and_then(|_| self.notify(&pending.mutations));
+        if result.is_err() {
+            self.pending = Some(pending);
+        }
+        result
+    }
+
+    fn publish(
+        &mut self,
+        changed: &BTreeSet<Identifier<'s>>,
+        mutations: &[(Identifier<'s>, Mutation<'s, 'v>)],
+    ) -> Result<(), TransactionError> {
+        self.store(changed)?;
+        self.notify(mutations)
+    }
+
+    // Writes the changed backend bags and persists the bundle.
+    fn store(&mut self, changed: &BTreeSet<Identifier<'s>>) -> Result<(), TransactionError> {
+        for bag_name in changed {
+            let Some(bag) = self.bags.get_mut(bag_name) else {
+                continue;
+            };
+            if bag.backend.is_some() {
+                bag.to_mut()
+                    .flush_backend()
+                    .map_err(|_| TransactionError::StorageFailed)?;
+            }
+        }
+
+        if let Some(storage) = &self.storage {
+            storage
+                .persist(self)
+                .map_err(|_| TransactionError::StorageFailed)?;
+        }
+
+        Ok(())
+    }
+
+    // Only told about the changes once they are stored.
+    fn notify(&self, mutations: &[(Identifier<'s>, Mutation<'s, 'v>)]) -> Result<(), TransactionError> {
+        for (bag_name, observer) in &self.observers {
+            let observed = mutations
+                .iter()
+                .filter(|(b, _)| b == bag_name)
+                .map(|(_, m)| m.clone())
+                .collect::<Vec<_>>();
+            if !observed.is_empty() {
+                observer
+                    .notify(bag_name, &observed)
+                    .map_err(|_| TransactionError::StorageFailed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The bag to be read. The values of a view are resolved from the bags
+    // it is the union of, unless they are known since the bags last changed.
+    // A bag dropped since the view was created is skipped.
+    pub(crate) fn bag(&self, name: &Identifier<'s>) -> Option<&Cow<'b, ValueBag<'i, 's, 'v>>> {
+        let bag = self.bags.get(name)?;
+        if let Some(VirtualSource::Union(bags, values)) = &bag.source {
+            values.get_or_init(|| {
+                bags.iter()
+                    .filter_map(|b| Some((b, self.bags.get(b)?)))
+                    .flat_map(|(b, bag)| bag.iter().map(move |v| virtual_bag::tagged(b, v.as_ref().clone())))
+                    .collect()
+            });
         }
+
+        Some(bag)
+    }
+
+    // Forgets the values of the views over the bag once it changes. The
+    // views sharing their values keep sharing the new ones.
+    fn forget_views(&mut self, bag_name: &Identifier<'s>) {
+        let mut fresh = HashMap::<_, Arc<OnceLock<_>>>::new();
+        for bag in self.bags.values_mut() {
+            let Some(VirtualSource::Union(bags, values)) = &bag.source else {
+                continue;
+            };
+            if bags.contains(bag_name) {
+                let values = fresh.entry(Arc::as_ptr(values)).or_insert_with(Arc::default).clone();
+                let bags = bags.clone();
+                bag.to_mut().source = Some(VirtualSource::Union(bags, values));
+            }
+        }
+        for (bags, values) in self.views.values_mut() {
+            if bags.contains(bag_name) {
+                *values = fresh.get(&Arc::as_ptr(values)).cloned().unwrap_or_default();
+            }
+        }
+    }
+
+    // The values of the views defined alike, so that they are resolved once
+    // for all of them.
+    fn shared_view(&mut self, bags: Vec<Identifier<'s>>) -> VirtualSource<'s, 'v> {
+        // The views of the dropped ones are forgotten.
+        self.views.retain(|_, (_, values)| Arc::strong_count(values) > 1);
+        let (_, values) = self
+            .views
+            .entry(fingerprint::view(&bags))
+            .or_insert_with(|| (bags.clone(), Arc::default()));
+
+        VirtualSource::Union(bags, values.clone())
     }
 
     pub(crate) fn bag_names(&self) -> BTreeSet<Identifier<'v>> {
         self.bags.keys().cloned().collect()
     }
+
+    pub fn register_virtual_bag(
+        &mut self,
+        bag_name: Identifier<'s>,
+        generator: ValueGenerator<'s, 'v>,
+    ) -> bool {
+        if self.bags.contains_key(&bag_name) {
+            return false;
+        }
+
+        let bag = ValueBag::new_virtual(VirtualSource::External(generator));
+        self.bags.insert(bag_name, Cow::Owned(bag));
+        true
+    }
+
+    pub fn register_backend_bag(
+        &mut self,
+        bag_name: Identifier<'s>,
+        backend: Arc<dyn BagBackend<'s, 'v>>,
+    ) -> bool {
+        if self.bags.contains_key(&bag_name) {
+            return false;
+        }
+
+        self.bags.insert(bag_name, Cow::Owned(ValueBag::new_backend(backend)));
+        true
+    }
+
+    pub fn observe(
+        &mut self,
+        bag_name: Identifier<'s>,
+        observer: Arc<dyn MutationObserver<'s, 'v>>,
+    ) -> bool {
+        if !self.bags.contains_key(&bag_name) {
+            return false;
+        }
+
+        self.observers.push((bag_name, observer));
+        true
+    }
 }
 
 pub(crate) enum Transaction<'b, 'i, 's, 'v> {
     Clean {
         working_copy: Cow<'b, BagBundle<'b, 'i, 's, 'v>>,
+        // Whether the working copy was borrowed mutably. Transactions that
+        // only read are committed without claiming a new version.
+        modified: bool,
     },
     Failed,
 }
@@ -42,13 +417,18 @@ pub(crate) enum Transaction<'b, 'i, 's, 'v> {
 #[derive(Debug)]
 pub(crate) enum TransactionError {
     BagDoesNotExist,
+    ReadOnly,
     Aborted,
+    StorageFailed,
+    // The bundle was changed by another transaction since the snapshot was
+    // taken. The transaction can be retried on the changed bundle.
+    Conflict,
 }
 
 impl<'b, 'i, 's, 'v> Transaction<'b, 'i, 's, 'v> {
     fn get_working_copy(&self) -> Result<&Cow<'b, BagBundle<'b, 'i, 's, 'v>>, TransactionError> {
         match self {
-            Transaction::Clean { working_copy } => Ok(working_copy),
+            Transaction::Clean { working_copy, .. } => Ok(working_copy),
             Transaction::Failed => Err(TransactionError::Aborted),
         }
     }
@@ -57,14 +437,83 @@ impl<'b, 'i, 's, 'v> Transaction<'b, 'i, 's, 'v> {
         &mut self,
     ) -> Result<&mut Cow<'b, BagBundle<'b, 'i, 's, 'v>>, TransactionError> {
         match self {
-            Transaction::Clean { working_copy } => Ok(working_copy),
+            Transaction::Clean {
+                working_copy,
+                modified,
+            } => {
+                *modified = true;
+                Ok(working_copy)
+            }
             Transaction::Failed => Err(TransactionError::Aborted),
         }
     }
 
+    fn check_writable(&mut self, bag_name: &Identifier<'s>) -> Result<(), TransactionError> {
+        let working_copy = self.get_working_copy()?;
+        if working_copy.bags.get(bag_name).map_or(false, |b| b.is_virtual()) {
+            *self = Self::Failed;
+            return Err(TransactionError::ReadOnly);
+        }
+
+        Ok(())
+    }
+
+    // Changing or deleting values of a backend bag requires all of its values.
+    fn load_backend(&mut self, bag_name: &Identifier<'s>) -> Result<(), TransactionError> {
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+        let Some(bag) = working_copy.bags.get_mut(bag_name) else {
+            return Ok(());
+        };
+
+        if bag.backend.is_some() && bag.to_mut().load_backend().is_err() {
+            *self = Self::Failed;
+            return Err(TransactionError::StorageFailed);
+        }
+
+        Ok(())
+    }
+
+    // Called before the bag changes. Forgets the values of the views over
+    // the bag and lets it journal its mutations if they are observed or
+    // watched, see `record_mutations`.
+    fn observe(&mut self, bag_name: &Identifier<'s>) {
+        let Ok(working_copy) = self.get_working_copy_mut() else {
+            return;
+        };
+        let working_copy = working_copy.to_mut();
+        working_copy.forget_views(bag_name);
+        if !working_copy.is_journaled(bag_name) {
+            return;
+        }
+        if let Some(bag) = working_copy.bags.get_mut(bag_name) {
+            bag.to_mut().journal = Some(vec![]);
+        }
+    }
+
+    fn record_mutations(&mut self, bag_name: &Identifier<'s>) {
+        let Ok(working_copy) = self.get_working_copy_mut() else {
+            return;
+        };
+        let working_copy = working_copy.to_mut();
+
+        let mutations = working_copy
+            .bags
+            .get_mut(bag_name)
+            .filter(|b| b.journal.is_some())
+            .and_then(|b| b.to_mut().journal.take());
+        if let Some(mutations) = mutations {
+            working_copy.journal(bag_name, mutations);
+        }
+    }
+
     pub fn new(snapshot: &BagBundle<'b, 'i, 's, 'v>) -> Self {
+        let mut working_copy = snapshot.clone();
+        working_copy.changes = Delta::default();
+        working_copy.mutations = vec![];
+
         Self::Clean {
-            working_copy: Cow::Owned(snapshot.clone()),
+            working_copy: Cow::Owned(working_copy),
+            modified: false,
         }
     }
 
@@ -80,43 +529,64 @@ impl<'b, 'i, 's, 'v> Transaction<'b, 'i, 's, 'v> {
         env: &'e Environment<'i, 's, 'v>,
         insertion: &Insertion<'s>,
     ) -> Result<InsertionResult, TransactionError> {
-        let working_copy = self.get_working_copy_mut()?;
-        let Some(bag) = working_copy.to_mut().bags.get_mut(bag_name) else {
+        self.check_writable(bag_name)?;
+        self.observe(bag_name);
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+        let Some(bag) = working_copy.bags.get_mut(bag_name) else {
             *self = Self::Failed;
             return Err(TransactionError::BagDoesNotExist)
         };
 
-        Ok(bag.to_mut().insert(env, insertion))
+        let before = bag.items.len();
+        let result = bag.to_mut().insert(env, insertion);
+        working_copy.changes.insert(bag_name, bag, before);
+        self.record_mutations(bag_name);
+
+        Ok(result)
     }
 
     pub(crate) fn update<'e>(
         &mut self,
-        bag: &Identifier<'s>,
+        bag_name: &Identifier<'s>,
         env: &'e Environment<'i, 's, 'v>,
         update: &'e UpdateQuery<'s>,
     ) -> Result<UpdateResult, TransactionError> {
-        let working_copy = self.get_working_copy_mut()?;
-        let Some(bag) = working_copy.to_mut().bags.get_mut(bag) else {
+        self.check_writable(bag_name)?;
+        self.load_backend(bag_name)?;
+        self.observe(bag_name);
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+        let Some(bag) = working_copy.bags.get_mut(bag_name) else {
             *self = Self::Failed;
             return Err(TransactionError::BagDoesNotExist)
         };
 
-        Ok(bag.to_mut().update(env, update))
+        working_copy.changes.rewrite(bag_name);
+        let result = bag.to_mut().update(env, update);
+        self.record_mutations(bag_name);
+
+        Ok(result)
     }
 
     pub(crate) fn delete<'e>(
         &mut self,
-        bag: &Identifier<'s>,
+        bag_name: &Identifier<'s>,
         env: &'e Environment<'i, 's, 'v>,
         deletion: &'e DeletionQuery<'s>,
     ) -> Result<DeletionResult, TransactionError> {
-        let working_copy = self.get_working_copy_mut()?;
-        let Some(bag) = working_copy.to_mut().bags.get_mut(bag) else {
+        self.check_writable(bag_name)?;
+        self.load_backend(bag_name)?;
+        self.observe(bag_name);
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+        let Some(bag) = working_copy.bags.get_mut(bag_name) else {
             *self = Self::Failed;
             return Err(TransactionError::BagDoesNotExist)
         };
 
-        Ok(bag.to_mut().delete(env, deletion))
+        working_copy.changes.rewrite(bag_name);
+        let result = bag.to_mut().delete(env, deletion);
+        self.record_mutations(bag_name);
+
+        Ok(result)
     }
 
     pub(crate) fn create_bag(
@@ -124,12 +594,23 @@ impl<'b, 'i, 's, 'v> Transaction<'b, 'i, 's, 'v> {
         bag_name: Identifier<'s>,
         predicate: Predicate<'s>,
     ) -> Result<bool, TransactionError> {
-        let working_copy = self.get_working_copy_mut()?;
-        
+        self.create_bag_in_mode(bag_name, predicate, StorageMode::Multiset)
+    }
+
+    pub(crate) fn create_bag_in_mode(
+        &mut self,
+        bag_name: Identifier<'s>,
+        predicate: Predicate<'s>,
+        mode: StorageMode,
+    ) -> Result<bool, TransactionError> {
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+        working_copy.forget_views(&bag_name);
+
         if let std::collections::hash_map::Entry::Vacant(e) =
-            working_copy.to_mut().bags.entry(bag_name)
+            working_copy.bags.entry(bag_name.clone())
         {
-            e.insert(Cow::Owned(ValueBag::new(predicate)));
+            e.insert(Cow::Owned(ValueBag::new_in_mode(predicate, mode)));
+            working_copy.changes.rewrite(&bag_name);
 
             Ok(true)
         } else {
@@ -137,6 +618,44 @@ impl<'b, 'i, 's, 'v> Transaction<'b, 'i, 's, 'v> {
         }
     }
 
+    pub(crate) fn create_virtual_bag(
+        &mut self,
+        bag_name: Identifier<'s>,
+        source: VirtualSource<'s, 'v>,
+    ) -> Result<bool, TransactionError> {
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+
+        if working_copy.bags.contains_key(&bag_name) {
+            return Ok(false);
+        }
+        let source = match source {
+            VirtualSource::Union(bags, _) => working_copy.shared_view(bags),
+            source => source,
+        };
+        working_copy
+            .bags
+            .insert(bag_name.clone(), Cow::Owned(ValueBag::new_virtual(source)));
+        working_copy.changes.rewrite(&bag_name);
+
+        Ok(true)
+    }
+
+    pub(crate) fn create_index(
+        &mut self,
+        bag_name: &Identifier<'s>,
+        field: &str,
+    ) -> Result<usize, TransactionError> {
+        self.check_writable(bag_name)?;
+        self.load_backend(bag_name)?;
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+        let Some(bag) = working_copy.bags.get_mut(bag_name) else {
+            *self = Self::Failed;
+            return Err(TransactionError::BagDoesNotExist)
+        };
+
+        Ok(bag.to_mut().create_index(field))
+    }
+
     pub(crate) fn get_bag_info(
         &mut self,
         bag_name: &Identifier<'s>,
@@ -165,13 +684,13 @@ impl<'b, 'i, 's, 'v> Transaction<'b, 'i, 's, 'v> {
 
     pub(crate) fn query<'e, 'x: 'e>(
         &'x self,
-        bag_name: &'x Identifier,
+        bag_name: &'x Identifier<'s>,
         env: &'e Environment<'i, 's, 'v>,
         query: &'e ProjectionQuery<'s>,
     ) -> Result<impl Iterator<Item = Result<Value<'s, 'v>, EvalError>> + 'e, TransactionError> {
         let working_copy = self.get_working_copy()?;
 
-        let Some(b) = working_copy.bags.get(bag_name) else {
+        let Some(b) = working_copy.bag(bag_name) else {
             return Err(TransactionError::BagDoesNotExist);
         };
 
@@ -185,8 +704,15 @@ impl<'b, 'i, 's, 'v> Transaction<'b, 'i, 's, 'v> {
         env: &'e Environment<'i, 's, 'v>,
         query: TransferQuery<'s>,
     ) -> Result<TransferResult, TransactionError> {
-        let working_copy = self.get_working_copy_mut()?;
-        let Some([a,b]) = working_copy.to_mut().bags.get_many_mut([source, sink]) else {
+        self.check_writable(source)?;
+        self.check_writable(sink)?;
+        self.load_backend(source)?;
+        self.observe(source);
+        self.observe(sink);
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+        working_copy.changes.rewrite(source);
+        working_copy.changes.rewrite(sink);
+        let Some([a,b]) = working_copy.bags.get_many_mut([source, sink]) else {
             return Err(TransactionError::BagDoesNotExist);
         };
 
@@ -194,8 +720,11 @@ impl<'b, 'i, 's, 'v> Transaction<'b, 'i, 's, 'v> {
         let b = b.to_mut();
 
         let mut trans = ValueBagTransfer::new(a, b);
+        let result = trans.transfer(env, &query);
+        self.record_mutations(source);
+        self.record_mutations(sink);
 
-        Ok(trans.transfer(env, &query))
+        Ok(result)
     }
 
     pub(crate) fn pop<'x>(
@@ -203,18 +732,54 @@ impl<'b, 'i, 's, 'v> Transaction<'b, 'i, 's, 'v> {
         bag_name: &Identifier<'s>,
         value: &'x Value<'s, 'v>,
     ) -> Result<bool, TransactionError> {
-        let working_copy = self.get_working_copy_mut()?;
+        self.check_writable(bag_name)?;
+        self.load_backend(bag_name)?;
+        self.observe(bag_name);
+        let working_copy = self.get_working_copy_mut()?.to_mut();
 
-        let Some(b) = working_copy.to_mut().bags.get_mut(bag_name) else {
+        let Some(b) = working_copy.bags.get_mut(bag_name) else {
             return Err(TransactionError::BagDoesNotExist);
         };
 
-        Ok(b.to_mut().pop(value))
+        working_copy.changes.rewrite(bag_name);
+        let popped = b.to_mut().pop(value);
+        self.record_mutations(bag_name);
+
+        Ok(popped)
     }
 
     pub(crate) fn commit(self) -> Result<BagBundle<'b, 'i, 's, 'v>, TransactionError> {
         match self {
-            Transaction::Clean { working_copy } => Ok(working_copy.as_ref().to_owned()),
+            Transaction::Clean {
+                working_copy,
+                modified: false,
+            } => Ok(working_copy.into_owned()),
+            Transaction::Clean { working_copy, .. } => {
+                let mut bundle = working_copy.into_owned();
+                let changed = bundle.changes.iter().map(|(b, _)| b.clone()).collect::<BTreeSet<_>>();
+                let mutations = std::mem::take(&mut bundle.mutations);
+
+                let base = bundle.version;
+                if bundle
+                    .head
+                    .compare_exchange(base, base + 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
+                {
+                    return Err(TransactionError::Conflict);
+                }
+
+                if let Some(pending) = &mut bundle.pending {
+                    pending.changed.extend(changed);
+                    pending.mutations.extend(mutations);
+                } else if let Err(e) = bundle.publish(&changed, &mutations) {
+                    // The snapshot stays the latest version, so the caller
+                    // can go on with it.
+                    bundle.head.store(base, Ordering::SeqCst);
+                    return Err(e);
+                }
+                bundle.version = base + 1;
+                Ok(bundle)
+            }
             Transaction::Failed => Err(TransactionError::Aborted),
         }
     }
@@ -225,18 +790,239 @@ impl<'b, 'i, 's, 'v> Transaction<'b, 'i, 's, 'v> {
         env: &'e Environment<'i, 's, 'v>,
         expr: &'x Expression<'s>,
     ) -> Result<InsertionResult, TransactionError> {
-        let working_copy = self.get_working_copy_mut()?;
+        self.check_writable(bag_name)?;
+        self.observe(bag_name);
+        let working_copy = self.get_working_copy_mut()?.to_mut();
 
-        let Some(b) = working_copy.to_mut().bags.get_mut(bag_name) else {
+        let Some(b) = working_copy.bags.get_mut(bag_name) else {
             return Err(TransactionError::BagDoesNotExist);
         };
 
-        Ok(b.to_mut().insert_one(env, expr))
+        let before = b.items.len();
+        let result = b.to_mut().insert_one(env, expr);
+        working_copy.changes.insert(bag_name, b, before);
+        self.record_mutations(bag_name);
+
+        Ok(result)
+    }
+
+    pub(crate) fn changes(&self) -> Result<&Delta<'s>, TransactionError> {
+        Ok(&self.get_working_copy()?.changes)
+    }
+
+    // Like `BagBundle::watch`, for the mutations made within this
+    // transaction.
+    pub(crate) fn watch(&mut self, bags: &[Identifier<'s>]) -> Result<usize, TransactionError> {
+        Ok(self.get_working_copy_mut()?.to_mut().watch(bags))
+    }
+
+    pub(crate) fn unwatch(
+        &mut self,
+        bags: &[Identifier<'s>],
+        start: usize,
+    ) -> Result<Vec<Journaled<'s, 'v>>, TransactionError> {
+        Ok(self.get_working_copy_mut()?.to_mut().unwatch(bags, start))
+    }
+
+    // Lets `f` look at the working copy as if it had made only the given
+    // changes, so that connections can be solved incrementally against them.
+    pub(crate) fn with_changes<R>(
+        &mut self,
+        changes: &Delta<'s>,
+        f: impl FnOnce(&BagBundle<'b, 'i, 's, 'v>) -> R,
+    ) -> Result<R, TransactionError> {
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+        let own = std::mem::replace(&mut working_copy.changes, changes.clone());
+        let result = f(working_copy);
+        working_copy.changes = own;
+
+        Ok(result)
+    }
+
+    // Applies the change sets of solved connections, each along with the
+    // connection it was found for. The positions of all change sets refer to
+    // the values before any of them is applied. Returns the changes made by
+    // the change sets alone.
+    pub(crate) fn apply<'e>(
+        &mut self,
+        env: &'e Environment<'i, 's, 'v>,
+        changesets: &[(Option<&Identifier<'s>>, &ChangeSet<'s, 'v>)],
+    ) -> Result<Delta<'s>, TransactionError> {
+        let own = std::mem::take(&mut self.get_working_copy_mut()?.to_mut().changes);
+        if let Err(e) = self.apply_changesets(env, changesets) {
+            *self = Self::Failed;
+            return Err(e);
+        }
+
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+        let applied = std::mem::replace(&mut working_copy.changes, own);
+        working_copy.changes.merge(&applied);
+
+        Ok(applied)
+    }
+
+    fn apply_changesets<'e>(
+        &mut self,
+        env: &'e Environment<'i, 's, 'v>,
+        changesets: &[(Option<&Identifier<'s>>, &ChangeSet<'s, 'v>)],
+    ) -> Result<(), TransactionError> {
+        let mut deletions = BTreeMap::<&Identifier<'s>, BTreeSet<usize>>::new();
+        for (_, changeset) in changesets {
+            for (bag_name, positions) in &changeset.deletions {
+                deletions.entry(bag_name).or_default().extend(positions);
+            }
+        }
+
+        for (bag_name, positions) in deletions {
+            self.check_writable(bag_name)?;
+            self.load_backend(bag_name)?;
+            self.observe(bag_name);
+            let working_copy = self.get_working_copy_mut()?.to_mut();
+            let Some(bag) = working_copy.bags.get_mut(bag_name) else {
+                return Err(TransactionError::BagDoesNotExist);
+            };
+
+            working_copy.changes.rewrite(bag_name);
+            bag.to_mut().remove_positions(&positions);
+            self.record_mutations(bag_name);
+        }
+
+        for (connection, changeset) in changesets {
+            self.get_working_copy_mut()?.to_mut().journal.producer = connection.cloned();
+            for (bag_name, values) in &changeset.insertions {
+                for value in values {
+                    let expression = value.to_expression();
+                    match self.insert_one(bag_name, env, &expression)? {
+                        InsertionResult::Success(_) => {}
+                        InsertionResult::GuardError | InsertionResult::EvalError(_) => {
+                            return Err(TransactionError::Aborted)
+                        }
+                    }
+                }
+            }
+        }
+        self.get_working_copy_mut()?.to_mut().journal.producer = None;
+
+        Ok(())
+    }
+
+    // Moves the bag along with its values, indexes and observers. Returns
+    // false if there is no bag `from` or already one named `to`.
+    pub(crate) fn rename_bag(
+        &mut self,
+        from: &Identifier<'s>,
+        to: Identifier<'s>,
+    ) -> Result<bool, TransactionError> {
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+        if working_copy.bags.contains_key(&to) {
+            return Ok(false);
+        }
+        let Some(bag) = working_copy.bags.remove(from) else {
+            return Ok(false);
+        };
+
+        working_copy.changes.rewrite(from);
+        working_copy.changes.rewrite(&to);
+        working_copy.forget_views(&to);
+        for (observed, _) in &mut working_copy.observers {
+            if observed == from {
+                *observed = to.clone();
+            }
+        }
+        working_copy.bags.insert(to, bag);
+
+        Ok(true)
+    }
+
+    // Creates the bag `target` with the guard and storage mode of `source`
+    // and inserts the values of `source` matching the predicate. Returns
+    // None if `target` already exists.
+    pub(crate) fn copy_bag<'e>(
+        &mut self,
+        source: &Identifier<'s>,
+        target: Identifier<'s>,
+        env: &'e Environment<'i, 's, 'v>,
+        predicate: &'e Predicate<'s>,
+    ) -> Result<Option<InsertionResult>, TransactionError> {
+        self.load_backend(source)?;
+        let working_copy = self.get_working_copy()?;
+        let Some(bag) = working_copy.bag(source) else {
+            return Err(TransactionError::BagDoesNotExist);
+        };
+
+        let guard = bag.guard.clone();
+        let mode = bag.mode;
+        let values = match bag.matching(env, predicate) {
+            Ok(values) => values,
+            Err(e) => return Ok(Some(InsertionResult::EvalError(e))),
+        };
+        if !self.create_bag_in_mode(target.clone(), guard, mode)? {
+            return Ok(None);
+        }
+
+        for value in &values {
+            match self.insert_one(&target, env, &value.to_expression())? {
+                InsertionResult::Success(_) => {}
+                error => return Ok(Some(error)),
+            }
+        }
+
+        Ok(Some(InsertionResult::Success(values.len())))
+    }
+
+    // Upserts the values of `source` into `target` by the key function, see
+    // `merge::plan`.
+    pub(crate) fn merge_bag<'e>(
+        &mut self,
+        source: &Identifier<'s>,
+        target: &Identifier<'s>,
+        env: &'e Environment<'i, 's, 'v>,
+        key: &Expression<'s>,
+        preference: &Preference<'s>,
+    ) -> Result<MergeResult, TransactionError> {
+        self.check_writable(target)?;
+        self.load_backend(source)?;
+        self.load_backend(target)?;
+        let working_copy = self.get_working_copy()?;
+        let (Some(s), Some(t)) = (working_copy.bag(source), working_copy.bags.get(target)) else {
+            return Err(TransactionError::BagDoesNotExist);
+        };
+
+        let values = match s.matching(env, &Predicate::any()) {
+            Ok(values) => values,
+            Err(e) => return Ok(MergeResult::EvalError(e)),
+        };
+        let items = t.items.iter().map(|i| i.as_ref().clone()).collect::<Vec<_>>();
+        let plan = match merge::plan(env, &values, &items, key, preference) {
+            Ok(plan) => plan,
+            Err(e) => return Ok(MergeResult::EvalError(e)),
+        };
+
+        let changeset = ChangeSet {
+            deletions: BTreeMap::from([(target.clone(), plan.replaced.into_iter().collect())]),
+            touches: BTreeMap::new(),
+            insertions: BTreeMap::from([(target.clone(), plan.insertions)]),
+        };
+        match self.apply(env, &[(None, &changeset)]) {
+            Ok(_) => Ok(MergeResult::Success(plan.counts)),
+            Err(TransactionError::Aborted) => Ok(MergeResult::GuardError),
+            Err(e) => Err(e),
+        }
     }
 
     pub(crate) fn drop_bag(&mut self, bag_name: Identifier<'s>) -> Result<bool, TransactionError> {
-        let working_copy = self.get_working_copy_mut()?;
-        
-        Ok(working_copy.to_mut().bags.remove(&bag_name).is_some())
+        let working_copy = self.get_working_copy_mut()?.to_mut();
+
+        working_copy.changes.rewrite(&bag_name);
+        working_copy.forget_views(&bag_name);
+        let Some(bag) = working_copy.bags.remove(&bag_name) else {
+            return Ok(false);
+        };
+        if working_copy.is_journaled(&bag_name) {
+            let mutations = bag.iter().map(|v| Mutation::Delete(v.as_ref().clone())).collect();
+            working_copy.journal(&bag_name, mutations);
+        }
+
+        Ok(true)
     }
 }
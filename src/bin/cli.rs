@@ -1,15 +1,199 @@
 #![cfg(feature = "cli")]
 #![feature(map_try_insert)]
 
-use damasc::{parser::statement, repl::ReplError};
+use damasc::{
+    capabilities::Capabilities,
+    error_catalog::ErrorCode,
+    parser::{is_blank, is_incomplete},
+    presentation::Theme,
+    repl::{Repl, ReplError},
+    repl_helper::ReplHelper,
+    server,
+    statement::{ErrorPolicy, Statement},
+    storage::Storage,
+};
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use std::io::{BufRead, IsTerminal};
+use std::sync::{Arc, Mutex};
 
 const INITIAL_BAG_NAME: &str = "init";
 
+// Runs the statements without a prompt, in a single transaction, and prints
+// their outputs. Returns the exit code, 1 if a statement could not be parsed
+// or failed, even if it was skipped because of `.on error continue`.
+fn run_batch(repl: &mut Repl, script: &str) -> i32 {
+    match repl.execute_script(script) {
+        Ok(report) => {
+            let theme = Theme::new(false);
+            for output in &report.outputs {
+                print!("{}", theme.render(output));
+            }
+            if report.failed.is_empty() {
+                0
+            } else {
+                eprint!("{report}");
+                1
+            }
+        }
+        Err(e) => {
+            eprintln!("Error[{}]: {e:?}", e.code());
+            1
+        }
+    }
+}
+
+// Executes the statements piped into stdin as they arrive, one per line.
+// A line that is not a statement on its own begins a block of lines that
+// ends with a line ending in `;;`. Returns the exit code, 1 if a statement
+// could not be parsed or failed. The statements after a failing one are
+// executed as well unless an `.on error abort` stops at it.
+fn run_stream(repl: &mut Repl, input: impl BufRead) -> i32 {
+    let theme = Theme::new(false);
+    let mut code = 0;
+    let mut block = String::new();
+    let _ = repl.execute(Statement::OnError(ErrorPolicy::Continue));
+
+    for line in input.lines() {
+        let Ok(line) = line else {
+            eprintln!("Failed to read input");
+            return 2;
+        };
+        let end = line.trim_end().strip_suffix(";;");
+
+        let parsed = match end {
+            None if block.is_empty() && is_blank(&line) => continue,
+            None if block.is_empty() => match repl.parse(&line) {
+                Err(_) if is_incomplete(&line) => {
+                    block = line;
+                    continue;
+                }
+                parsed => parsed,
+            },
+            None => {
+                block.push('\n');
+                block.push_str(&line);
+                continue;
+            }
+            Some(end) => {
+                if !block.is_empty() {
+                    block.push('\n');
+                }
+                block.push_str(end);
+                let parsed = repl.parse(&block);
+                block.clear();
+                parsed
+            }
+        };
+
+        let stmt = match parsed {
+            Ok(stmt) => stmt,
+            Err(e) => {
+                eprintln!("read error[{}]: {e}", e.code());
+                if repl.error_policy() == ErrorPolicy::Abort {
+                    return 1;
+                }
+                code = 1;
+                continue;
+            }
+        };
+        match repl.execute_next(stmt) {
+            Ok(output) => print!("{}", theme.render(&output)),
+            Err(ReplError::Exit) => return code,
+            Err(e) => {
+                eprintln!("Error[{}]: {e:?}", e.code());
+                if repl.error_policy() == ErrorPolicy::Abort {
+                    return 1;
+                }
+                code = 1;
+            }
+        }
+    }
+
+    if !block.is_empty() {
+        eprintln!("read error: block not terminated by ;;");
+        code = 1;
+    }
+    code
+}
+
 pub(crate) fn main() -> rustyline::Result<()> {
-    let mut repl = damasc::repl::Repl::new(INITIAL_BAG_NAME);
-    let mut rl = Editor::<()>::new()?;
+    let storage = match Storage::from_env().transpose() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Storage error: {e}");
+            return Ok(());
+        }
+    };
+    let mut repl = match damasc::repl::Repl::open(INITIAL_BAG_NAME, storage) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Failed to load stored bags: {e:?}");
+            return Ok(());
+        }
+    };
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["--eval", script] => std::process::exit(run_batch(&mut repl, script)),
+        ["--file", path] => {
+            let Ok(script) = std::fs::read_to_string(path) else {
+                eprintln!("Failed to read file '{path}'");
+                std::process::exit(2);
+            };
+            std::process::exit(run_batch(&mut repl, &script))
+        }
+        _ => {}
+    }
+    if !args.is_empty() {
+        type Listen = fn(server::SharedRepl, Arc<str>, &str) -> std::io::Result<()>;
+        let (listen, target): (Listen, &str) =
+            match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+                #[cfg(unix)]
+                ["serve", "--socket", path] => (server::listen_unix, path),
+                ["serve", "--tcp", address] => (server::listen_tcp, address),
+                _ => {
+                    eprintln!(
+                        "Usage: cli [--eval <statements> | --file <path> | serve --socket <path> | serve --tcp <address>]"
+                    );
+                    std::process::exit(2);
+                }
+            };
+
+        // Clients may use every statement but those accessing the files of
+        // the server, after sending the token. Settings, macros, prepared
+        // statements and connections are shared by all clients, like the
+        // bags, only the bindings, the current bag, open transactions and
+        // the history are kept per client.
+        repl.capabilities = Capabilities::ALL.without(Capabilities::IO);
+        let token: Arc<str> = match std::env::var("DAMASC_SERVE_TOKEN") {
+            Ok(token) if !token.is_empty() => token.into(),
+            _ => {
+                let token = server::random_token();
+                println!("Token: {token}");
+                token.into()
+            }
+        };
+        println!("Listening on {target}");
+        if let Err(e) = listen(Arc::new(Mutex::new(repl)), token, target) {
+            eprintln!("Server error: {e}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        std::process::exit(run_stream(&mut repl, std::io::stdin().lock()));
+    }
+
+    // See https://no-color.org
+    repl.settings.color = std::io::stdout().is_terminal()
+        && std::env::var_os("NO_COLOR").map_or(true, |v| v.is_empty());
+
+    let mut rl = Editor::<ReplHelper>::new()?;
+    rl.set_helper(Some(ReplHelper {
+        context: repl.completion_context(),
+        color: repl.settings.color,
+    }));
     if rl.load_history("history.txt").is_err() {
         println!("No previous history.");
     }
@@ -19,27 +203,54 @@ pub(crate) fn main() -> rustyline::Result<()> {
     println!(".bag");
     println!("Current Bag: {}", repl.current_bag);
 
+    // Breakpoints drop into the inspection prompt until `.continue`.
+    repl.interactive = true;
     loop {
-        let readline = rl.readline(">> ");
+        let prompt = if repl.is_paused() { "break> " } else { ">> " };
+        let readline = rl.readline(prompt);
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str());
                 let input = line.as_str();
 
-                let stmt = match statement(input) {
-                    Ok((_, s)) => s,
+                let theme = Theme::new(repl.settings.color);
+                let stmt = match repl.parse(input) {
+                    Ok(s) => s,
                     Err(e) => {
-                        println!("read error: {e}");
+                        println!("{}", theme.error(&format!("read error[{}]: {e}", e.code())));
                         continue;
                     }
                 };
 
-                match repl.execute(stmt) {
+                // A statement changing many values is run again once confirmed.
+                let retry = stmt.clone();
+                let result = match repl.execute_next(stmt) {
+                    Err(ReplError::Unconfirmed(count)) => {
+                        let prompt = format!("This affects {count} items. Continue? [y/N] ");
+                        let confirmed = rl
+                            .readline(&prompt)
+                            .map_or(false, |answer| matches!(answer.trim(), "y" | "yes"));
+                        if !confirmed {
+                            println!("Cancelled.");
+                            continue;
+                        }
+                        repl.confirming(true, |repl| repl.execute_next(retry))
+                    }
+                    result => result,
+                };
+
+                match result {
                     Ok(r) => {
-                        println!("{r}")
+                        // `.set color` takes effect with its own output.
+                        println!("{}", Theme::new(repl.settings.color).render(&r))
                     }
                     Err(ReplError::Exit) => break,
-                    Err(e) => println!("Error: {e:?}"),
+                    Err(e) => println!("{}", theme.error(&format!("Error[{}]: {e:?}", e.code()))),
+                }
+
+                if let Some(helper) = rl.helper_mut() {
+                    helper.context = repl.completion_context();
+                    helper.color = repl.settings.color;
                 }
             }
             Err(ReadlineError::Interrupted) => {
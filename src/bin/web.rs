@@ -1,39 +1,163 @@
 #![cfg(feature = "web")]
 
 use std::env;
+use std::fs;
 use std::io::Error;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::{collections::BTreeSet, sync::Mutex};
+use std::time::Instant;
+use std::{collections::BTreeSet, sync::Mutex, sync::PoisonError};
 
 use actix_files::Files;
 use actix_web::{
+    cookie::Cookie,
     get,
     http::StatusCode,
     post,
     web::{self, Data},
-    App, HttpResponse, HttpServer, Responder,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
+use actix_web::http::header;
 use askama::Template;
-use damasc::repl::Repl;
-use damasc::{identifier::Identifier, parser::statement, statement::Statement};
+use damasc::repl::{Repl, ReplError};
+use damasc::jail::{JailSettings, Jails};
+use damasc::limits::Limits;
+use damasc::{
+    audit::{AuditEntry, AuditLog},
+    auth::Auth,
+    capabilities::Capabilities,
+    error_catalog::ErrorCode,
+    identifier::Identifier,
+    parser::{checked_statement, is_blank, is_incomplete},
+    presentation, server,
+    statement::Statement,
+    storage::Storage,
+};
 
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 struct ReplInput {
     statement: String,
+    // Set to execute a statement that exceeds the confirmation threshold.
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[derive(Deserialize)]
+struct ScriptInput {
+    // One statement per line, run in a single transaction.
+    script: String,
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[derive(Deserialize)]
+struct QueryInput {
+    // Everything following `.query`, eg `{age, ...} where age > 30`. All
+    // values are returned if it is empty.
+    #[serde(default)]
+    query: String,
+}
+
+#[derive(Deserialize)]
+struct IncompleteInput {
+    input: String,
+}
+
+#[derive(Deserialize)]
+struct CompleteInput {
+    input: String,
+    cursor: Option<usize>,
 }
 
 #[derive(Template)]
 #[template(path = "404.html.j2")]
 struct NotFoundTemplate {}
 
+#[derive(Template)]
+#[template(path = "audit.html.j2")]
+struct AuditTemplate<'x> {
+    entries: Vec<&'x AuditEntry>,
+}
+
+const SESSION_COOKIE: &str = "damasc_session";
+// Carries `DAMASC_ADMIN_TOKEN`. It is not accepted in the query string, which
+// ends up in logs and the browser history.
+const ADMIN_TOKEN_HEADER: &str = "X-Admin-Token";
+
+fn is_authorized(auth: &Auth, request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |value| auth.accepts(value))
+}
+
+fn challenge(auth: &Auth) -> HttpResponse {
+    let mut response = HttpResponse::Unauthorized();
+    if auth.has_basic() {
+        response.insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"damasc\""));
+    }
+    response
+        .content_type("text/plain")
+        .body("Authentication required")
+}
+
+fn login_required(
+    auth: &Option<Auth>,
+    request: &HttpRequest,
+    statement: Option<&Statement>,
+) -> Option<HttpResponse> {
+    let auth = auth.as_ref()?;
+
+    if auth.is_required(statement) && !is_authorized(auth, request) {
+        Some(challenge(auth))
+    } else {
+        None
+    }
+}
+
+// The id of the session of the request, a new one unless the request has a
+// cookie the server issued.
+fn session_id(request: &HttpRequest, jails: &Option<Mutex<Jails>>) -> String {
+    let cookie = request.cookie(SESSION_COOKIE);
+    let Some(jails) = jails else {
+        return cookie.map_or_else(server::random_token, |c| c.value().to_string());
+    };
+
+    let jails = jails.lock().unwrap_or_else(PoisonError::into_inner);
+    match cookie {
+        Some(c) if jails.is_issued(c.value()) => c.value().to_string(),
+        _ => jails.issue(),
+    }
+}
+
+// Runs `f` in the jail of the session, if visitors are put in jails.
+fn jailed<'b, 'i, 's, 'v, R>(
+    jails: &Option<Mutex<Jails<'s, 'v>>>,
+    repl: &mut Repl<'b, 'i, 's, 'v>,
+    session: &str,
+    f: impl FnOnce(&mut Repl<'b, 'i, 's, 'v>) -> R,
+) -> Result<R, ReplError> {
+    match jails {
+        Some(jails) => jails
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .run(repl, session, f),
+        None => Ok(f(repl)),
+    }
+}
+
 #[derive(Template)]
 #[template(path = "result.html.j2")]
 struct ResultTemplate<'x> {
     repl: &'x ReplInput,
     error: Option<String>,
+    error_code: Option<&'static str>,
     output: Option<String>,
+    unconfirmed: bool,
     bags: BTreeSet<Identifier<'x>>,
     vars: BTreeSet<Identifier<'x>>,
 }
@@ -46,80 +170,601 @@ struct HomeTemplate<'x> {
 
 #[post("/")]
 async fn eval(
+    request: HttpRequest,
     repl: web::Form<ReplInput>,
-    env_mutex: Data<Arc<Mutex<Repl<'_, '_, '_, '_>>>>,
+    env_mutex: Data<Arc<Mutex<Repl<'_, '_, 'static, 'static>>>>,
+    audit_mutex: Data<Mutex<AuditLog>>,
+    auth: Data<Option<Auth>>,
+    jails: Data<Option<Mutex<Jails<'static, 'static>>>>,
 ) -> impl Responder {
+    let session = session_id(&request, &jails);
+    let started = Instant::now();
+
+    // Requests that need a login regardless of their statement are turned
+    // away before they wait for the repl.
+    if let Some(response) = login_required(&auth, &request, None) {
+        if let Ok(mut audit) = audit_mutex.lock() {
+            audit.record(&session, &repl.statement, started, "unauthorized", "");
+        }
+        return response;
+    }
+
     let Ok(mut repl_state) = env_mutex.lock() else {
         return HttpResponse::Ok().content_type("text/html").body("Locked");
     };
+    let parsed = repl_state.parse(&repl.statement);
+
+    if let Some(response) = login_required(&auth, &request, parsed.as_ref().ok()) {
+        if let Ok(mut audit) = audit_mutex.lock() {
+            audit.record(&session, &repl.statement, started, "unauthorized", "");
+        }
+        return response;
+    }
 
     let bags = repl_state.bags();
     let vars = repl_state.vars();
 
-    if repl.statement.len() > 500 {
-        return HttpResponse::Ok().content_type("text/html").body(
+    let executed = jailed(&jails, &mut repl_state, &session, |repl_state| match parsed {
+        Ok(stmt) => {
+            let deny = !repl_state.permits(&stmt);
+
+            if deny {
+                let error = "This command has been disabled in the web UI".to_string();
+                (
+                    ResultTemplate {
+                        error: Some(error.clone()),
+                        error_code: None,
+                        repl: &repl,
+                        output: None,
+                        unconfirmed: false,
+                        bags,
+                        vars,
+                    },
+                    "denied",
+                    error,
+                )
+            } else {
+                let result = repl_state.confirming(repl.confirm, |r| r.execute(stmt));
+                let (output, error, error_code) = match result {
+                    Err(damasc::repl::ReplError::Unconfirmed(count)) => {
+                        let error = format!(
+                            "This statement affects {count} items, submit it with confirm=true to execute it"
+                        );
+                        return (
+                            ResultTemplate {
+                                error: Some(error.clone()),
+                                error_code: None,
+                                repl: &repl,
+                                output: None,
+                                unconfirmed: true,
+                                bags,
+                                vars,
+                            },
+                            "unconfirmed",
+                            error,
+                        );
+                    }
+                    Ok(r) => (Some((format!("{r}"), presentation::html(&r))), None, None),
+                    Err(damasc::repl::ReplError::Exit) => (None, None, None),
+                    Err(e) => (None, Some(format!("[{}] {e:?}", e.code())), Some(e.code())),
+                };
+
+                // The audit log keeps the plain text, the page gets the HTML.
+                let (outcome, detail) = match (&output, &error) {
+                    (_, Some(e)) => ("error", e.clone()),
+                    (Some((o, _)), None) => ("ok", o.clone()),
+                    (None, None) => ("ok", String::new()),
+                };
+                let output = output.map(|(_, html)| html);
+
+                let bags = repl_state.bags();
+                let vars = repl_state.vars();
+
+                (
+                    ResultTemplate {
+                        error,
+                        error_code,
+                        repl: &repl,
+                        output,
+                        unconfirmed: false,
+                        bags,
+                        vars,
+                    },
+                    outcome,
+                    detail,
+                )
+            }
+        }
+
+        Err(e) => (
             ResultTemplate {
-                error: Some("Input length is limited to 500 characters".to_string()),
+                error: Some(format!("[{}] {e}", e.code())),
+                error_code: Some(e.code()),
                 repl: &repl,
                 output: None,
+                unconfirmed: false,
                 bags,
                 vars,
+            },
+            "rejected",
+            format!("[{}] {e}", e.code()),
+        ),
+    });
+    let (template, outcome, detail) = match executed {
+        Ok(executed) => executed,
+        Err(e) => {
+            let error = format!("[{}] {e}", e.code());
+            if let Ok(mut audit) = audit_mutex.lock() {
+                audit.record(&session, &repl.statement, started, "error", &error);
             }
-            .render()
-            .unwrap(),
-        );
+            return HttpResponse::build(jail_status(&e))
+                .content_type("text/plain")
+                .body(error);
+        }
+    };
+
+    if let Ok(mut audit) = audit_mutex.lock() {
+        audit.record(&session, &repl.statement, started, outcome, &detail);
     }
 
-    match statement(&repl.statement) {
-        Ok((_, stmt)) => {
-            let deny = match &stmt {
-                Statement::UseBag(id, ..) => !repl_state.bags().contains(id),
-                Statement::Import(..) => true,
-                Statement::Export(..) => true,
-                _ => false,
-            };
+    template
+        .render()
+        .map(|s| {
+            HttpResponse::Ok()
+                .cookie(Cookie::new(SESSION_COOKIE, session))
+                .content_type("text/html")
+                .body(s)
+        })
+        .unwrap_or_else(template_error)
+}
 
-            if deny {
-                ResultTemplate {
-                    error: Some("This command has been disabled in the web UI".into()),
-                    repl: &repl,
-                    output: None,
-                    bags,
-                    vars,
-                }
-            } else {
-                let (output, error) = match repl_state.execute(stmt) {
-                    Ok(r) => (Some(format!("{r}")), None),
-                    Err(damasc::repl::ReplError::Exit) => (None, None),
-                    Err(e) => (None, Some(format!("{e:?}"))),
-                };
+// Lets the client keep its session, and with it its jail.
+fn with_session(mut response: HttpResponse, session: String) -> HttpResponse {
+    let _ = response.add_cookie(&Cookie::new(SESSION_COOKIE, session));
+    response
+}
 
-                let bags = repl_state.bags();
-                let vars = repl_state.vars();
+fn api_error(status: StatusCode, code: Option<&str>, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({
+        "status": "error",
+        "code": code,
+        "message": message,
+    }))
+}
 
-                ResultTemplate {
-                    error,
-                    repl: &repl,
-                    output,
-                    bags,
-                    vars,
-                }
-            }
+// Executes a statement of the JSON API under the same rules as the form:
+// denied commands and statements lacking a confirmation are rejected.
+fn api_execute<'s>(
+    repl: &mut Repl<'_, '_, 's, '_>,
+    stmt: Statement<'s, 's>,
+    confirm: bool,
+) -> (HttpResponse, &'static str, String) {
+    if !repl.permits(&stmt) {
+        let error = "This command has been disabled in the web UI";
+        return (api_error(StatusCode::FORBIDDEN, None, error), "denied", error.to_string());
+    }
+
+    match repl.confirming(confirm, |r| r.execute(stmt)) {
+        Ok(r) => (
+            HttpResponse::Ok().json(serde_json::json!({
+                "status": "ok",
+                "result": r.to_json(),
+            })),
+            "ok",
+            r.to_string(),
+        ),
+        Err(damasc::repl::ReplError::Exit) => (
+            HttpResponse::Ok().json(serde_json::json!({"status": "ok", "result": null})),
+            "ok",
+            String::new(),
+        ),
+        Err(damasc::repl::ReplError::Unconfirmed(count)) => {
+            let error = format!(
+                "This statement affects {count} items, submit it with confirm=true to execute it"
+            );
+            (api_error(StatusCode::CONFLICT, None, &error), "unconfirmed", error)
         }
+        Err(e) => {
+            let error = format!("{e:?}");
+            (
+                api_error(StatusCode::UNPROCESSABLE_ENTITY, Some(e.code()), &error),
+                "error",
+                format!("[{}] {error}", e.code()),
+            )
+        }
+    }
+}
+
+fn jail_status(e: &ReplError) -> StatusCode {
+    match e {
+        ReplError::Denied => StatusCode::FORBIDDEN,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+fn jail_error(e: ReplError) -> (HttpResponse, &'static str, String) {
+    let error = e.to_string();
+    (
+        api_error(jail_status(&e), Some(e.code()), &error),
+        "error",
+        format!("[{}] {error}", e.code()),
+    )
+}
 
-        Err(e) => ResultTemplate {
-            error: Some(e.to_string()),
-            repl: &repl,
-            output: None,
-            bags,
-            vars,
+#[post("/api/eval")]
+async fn api_eval(
+    request: HttpRequest,
+    input: web::Json<ReplInput>,
+    env_mutex: Data<Arc<Mutex<Repl<'_, '_, 'static, 'static>>>>,
+    audit_mutex: Data<Mutex<AuditLog>>,
+    auth: Data<Option<Auth>>,
+    jails: Data<Option<Mutex<Jails<'static, 'static>>>>,
+) -> impl Responder {
+    let session = session_id(&request, &jails);
+    let started = Instant::now();
+
+    if let Some(response) = login_required(&auth, &request, None) {
+        if let Ok(mut audit) = audit_mutex.lock() {
+            audit.record(&session, &input.statement, started, "unauthorized", "");
+        }
+        return response;
+    }
+
+    let Ok(mut repl_state) = env_mutex.lock() else {
+        return api_error(StatusCode::SERVICE_UNAVAILABLE, None, "Locked");
+    };
+    let parsed = repl_state.parse(&input.statement);
+
+    if let Some(response) = login_required(&auth, &request, parsed.as_ref().ok()) {
+        if let Ok(mut audit) = audit_mutex.lock() {
+            audit.record(&session, &input.statement, started, "unauthorized", "");
+        }
+        return response;
+    }
+
+    let (response, outcome, detail) = match parsed {
+        Ok(stmt) => jailed(&jails, &mut repl_state, &session, |repl| {
+            api_execute(repl, stmt, input.confirm)
+        })
+        .unwrap_or_else(jail_error),
+        Err(e) => {
+            let error = e.to_string();
+            (
+                api_error(StatusCode::BAD_REQUEST, Some(e.code()), &error),
+                "rejected",
+                format!("[{}] {error}", e.code()),
+            )
+        }
+    };
+
+    if let Ok(mut audit) = audit_mutex.lock() {
+        audit.record(&session, &input.statement, started, outcome, &detail);
+    }
+
+    with_session(response, session)
+}
+
+// Runs the statements of a script in a single transaction. Nothing is
+// executed if one of them can not be parsed, is denied or lacks a
+// confirmation.
+#[post("/api/script")]
+async fn api_script(
+    request: HttpRequest,
+    input: web::Json<ScriptInput>,
+    env_mutex: Data<Arc<Mutex<Repl<'_, '_, 'static, 'static>>>>,
+    audit_mutex: Data<Mutex<AuditLog>>,
+    auth: Data<Option<Auth>>,
+    jails: Data<Option<Mutex<Jails<'static, 'static>>>>,
+) -> impl Responder {
+    let session = session_id(&request, &jails);
+    let started = Instant::now();
+
+    if let Some(response) = login_required(&auth, &request, None) {
+        if let Ok(mut audit) = audit_mutex.lock() {
+            audit.record(&session, &input.script, started, "unauthorized", "");
+        }
+        return response;
+    }
+
+    let Ok(mut repl_state) = env_mutex.lock() else {
+        return api_error(StatusCode::SERVICE_UNAVAILABLE, None, "Locked");
+    };
+    let parsed = input
+        .script
+        .lines()
+        .filter(|line| !is_blank(line))
+        .map(|line| repl_state.parse(line))
+        .collect::<Result<Vec<_>, _>>();
+
+    let statements = parsed.as_deref().unwrap_or_default();
+    let unauthorized = statements
+        .iter()
+        .find_map(|s| login_required(&auth, &request, Some(s)));
+    if let Some(response) = unauthorized {
+        if let Ok(mut audit) = audit_mutex.lock() {
+            audit.record(&session, &input.script, started, "unauthorized", "");
+        }
+        return response;
+    }
+
+    let executed = jailed(&jails, &mut repl_state, &session, |repl_state| match parsed {
+        Err(e) => {
+            let error = e.to_string();
+            (
+                api_error(StatusCode::BAD_REQUEST, Some(e.code()), &error),
+                "rejected",
+                format!("[{}] {error}", e.code()),
+            )
+        }
+        Ok(statements) if statements.iter().any(|s| !repl_state.permits(s)) => {
+            let error = "This command has been disabled in the web UI";
+            (api_error(StatusCode::FORBIDDEN, None, error), "denied", error.to_string())
+        }
+        Ok(statements) => match repl_state.confirming(input.confirm, |r| r.execute_many(statements)) {
+            Ok(outputs) => (
+                HttpResponse::Ok().json(serde_json::json!({
+                    "status": "ok",
+                    "results": outputs.iter().map(|r| r.to_json()).collect::<Vec<_>>(),
+                })),
+                "ok",
+                format!("{} statements", outputs.len()),
+            ),
+            Err((index, damasc::repl::ReplError::Unconfirmed(count))) => {
+                let error = format!(
+                    "Statement {index} affects {count} items, submit the script with confirm=true to execute it"
+                );
+                (api_error(StatusCode::CONFLICT, None, &error), "unconfirmed", error)
+            }
+            Err((index, e)) => {
+                let error = format!("{e:?}");
+                (
+                    HttpResponse::UnprocessableEntity().json(serde_json::json!({
+                        "status": "error",
+                        "code": e.code(),
+                        "message": error,
+                        "index": index,
+                    })),
+                    "error",
+                    format!("[{}] statement {index}: {error}", e.code()),
+                )
+            }
         },
+    });
+    let (response, outcome, detail) = executed.unwrap_or_else(jail_error);
+
+    if let Ok(mut audit) = audit_mutex.lock() {
+        audit.record(&session, &input.script, started, outcome, &detail);
+    }
+
+    with_session(response, session)
+}
+
+#[get("/api/bags")]
+async fn api_bags(
+    request: HttpRequest,
+    env_mutex: Data<Arc<Mutex<Repl<'_, '_, '_, '_>>>>,
+    auth: Data<Option<Auth>>,
+) -> impl Responder {
+    if let Some(response) = login_required(&auth, &request, None) {
+        return response;
+    }
+
+    let Ok(repl_state) = env_mutex.lock() else {
+        return api_error(StatusCode::SERVICE_UNAVAILABLE, None, "Locked");
+    };
+
+    HttpResponse::Ok().json(repl_state.bags_to_json())
+}
+
+// Queries the given bag without changing the current bag of the session.
+// Sessions with a jail can only query their jail.
+#[post("/api/bags/{name}/query")]
+async fn api_query(
+    request: HttpRequest,
+    name: web::Path<String>,
+    input: web::Json<QueryInput>,
+    env_mutex: Data<Arc<Mutex<Repl<'_, '_, 'static, 'static>>>>,
+    audit_mutex: Data<Mutex<AuditLog>>,
+    auth: Data<Option<Auth>>,
+    jails: Data<Option<Mutex<Jails<'static, 'static>>>>,
+) -> impl Responder {
+    let session = session_id(&request, &jails);
+    let started = Instant::now();
+    let statement = format!(".query {}", input.query);
+
+    if let Some(response) = login_required(&auth, &request, None) {
+        if let Ok(mut audit) = audit_mutex.lock() {
+            audit.record(&session, &statement, started, "unauthorized", "");
+        }
+        return response;
+    }
+
+    let Ok(mut repl_state) = env_mutex.lock() else {
+        return api_error(StatusCode::SERVICE_UNAVAILABLE, None, "Locked");
+    };
+    let parsed = repl_state.parse(&statement);
+
+    if let Some(response) = login_required(&auth, &request, parsed.as_ref().ok()) {
+        if let Ok(mut audit) = audit_mutex.lock() {
+            audit.record(&session, &statement, started, "unauthorized", "");
+        }
+        return response;
+    }
+
+    let bag = Identifier {
+        name: Cow::Owned(name.into_inner()),
+    };
+    let (response, outcome, detail) = match parsed {
+        Ok(stmt @ Statement::Query(_)) if repl_state.bags().contains(&bag) => {
+            jailed(&jails, &mut repl_state, &session, |repl_state| {
+                if jails.is_some() && repl_state.current_bag != bag {
+                    let error = format!("Bag {bag} belongs to another session");
+                    return (api_error(StatusCode::FORBIDDEN, None, &error), "denied", error);
+                }
+                let current = std::mem::replace(&mut repl_state.current_bag, bag);
+                let result = api_execute(repl_state, stmt, false);
+                repl_state.current_bag = current;
+                result
+            })
+            .unwrap_or_else(jail_error)
+        }
+        Ok(Statement::Query(_)) => {
+            let error = format!("Bag {bag} does not exist");
+            (api_error(StatusCode::NOT_FOUND, None, &error), "rejected", error)
+        }
+        Ok(_) => {
+            let error = "Expected a query";
+            (api_error(StatusCode::BAD_REQUEST, None, error), "rejected", error.to_string())
+        }
+        Err(e) => {
+            let error = e.to_string();
+            (
+                api_error(StatusCode::BAD_REQUEST, Some(e.code()), &error),
+                "rejected",
+                format!("[{}] {error}", e.code()),
+            )
+        }
+    };
+
+    if let Ok(mut audit) = audit_mutex.lock() {
+        audit.record(&session, &statement, started, outcome, &detail);
+    }
+
+    with_session(response, session)
+}
+
+#[get("/complete")]
+async fn complete(
+    request: HttpRequest,
+    query: web::Query<CompleteInput>,
+    env_mutex: Data<Arc<Mutex<Repl<'_, '_, '_, '_>>>>,
+    auth: Data<Option<Auth>>,
+) -> impl Responder {
+    // The candidates name the bags and variables.
+    if let Some(response) = login_required(&auth, &request, None) {
+        return response;
+    }
+
+    let context = {
+        let Ok(repl_state) = env_mutex.lock() else {
+            return HttpResponse::ServiceUnavailable()
+                .content_type("text/plain")
+                .body("Locked");
+        };
+        repl_state.completion_context()
+    };
+
+    let cursor = query.cursor.unwrap_or(query.input.len());
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(context.complete(&query.input, cursor).to_json().to_string())
+}
+
+// Whether the form continues the input on a new line when pressing Enter.
+#[get("/incomplete")]
+async fn incomplete(query: web::Query<IncompleteInput>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::json!({"incomplete": is_incomplete(&query.input)}).to_string())
+}
+
+#[get("/admin/audit")]
+async fn admin_audit(
+    request: HttpRequest,
+    audit_mutex: Data<Mutex<AuditLog>>,
+) -> impl Responder {
+    let Ok(token) = env::var("DAMASC_ADMIN_TOKEN") else {
+        return not_found().await;
+    };
+    let given = request
+        .headers()
+        .get(ADMIN_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok());
+    if !given.map_or(false, |given| server::constant_time_eq(given, &token)) {
+        return not_found().await;
+    }
+
+    let Ok(audit) = audit_mutex.lock() else {
+        return HttpResponse::Ok().content_type("text/html").body("Locked");
+    };
+
+    AuditTemplate {
+        entries: audit.recent().rev().collect(),
     }
     .render()
     .map(|s| HttpResponse::Ok().content_type("text/html").body(s))
     .unwrap_or_else(template_error)
 }
 
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().content_type("text/plain").body("ok")
+}
+
+#[get("/readyz")]
+async fn readyz(env_mutex: Data<Arc<Mutex<Repl<'_, '_, '_, '_>>>>) -> impl Responder {
+    if env_mutex.is_poisoned() {
+        return HttpResponse::ServiceUnavailable()
+            .content_type("text/plain")
+            .body("not ready");
+    }
+
+    HttpResponse::Ok().content_type("text/plain").body("ready")
+}
+
+#[get("/snapshot")]
+async fn snapshot(
+    request: HttpRequest,
+    env_mutex: Data<Arc<Mutex<Repl<'_, '_, '_, '_>>>>,
+    audit_mutex: Data<Mutex<AuditLog>>,
+    auth: Data<Option<Auth>>,
+    jails: Data<Option<Mutex<Jails<'static, 'static>>>>,
+) -> impl Responder {
+    let Some(auth) = auth.as_ref() else {
+        return not_found().await;
+    };
+
+    let session = session_id(&request, &jails);
+    let started = Instant::now();
+
+    if !is_authorized(auth, &request) {
+        if let Ok(mut audit) = audit_mutex.lock() {
+            audit.record(&session, "GET /snapshot", started, "unauthorized", "");
+        }
+        return challenge(auth);
+    }
+
+    let bundle = {
+        let Ok(repl_state) = env_mutex.lock() else {
+            return HttpResponse::ServiceUnavailable()
+                .content_type("text/plain")
+                .body("Locked");
+        };
+
+        let mut bundle = Vec::new();
+        if repl_state.write_bundle(&mut bundle).is_err() {
+            return HttpResponse::InternalServerError()
+                .content_type("text/plain")
+                .body("Snapshot failed");
+        }
+        bundle
+    };
+
+    if let Ok(mut audit) = audit_mutex.lock() {
+        let detail = format!("{} bytes", bundle.len());
+        audit.record(&session, "GET /snapshot", started, "ok", &detail);
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"damasc-snapshot.bundle\"",
+        ))
+        .body(bundle)
+}
+
 fn template_error(_: askama::Error) -> HttpResponse {
     HttpResponse::InternalServerError()
         .content_type("text/html")
@@ -127,10 +772,15 @@ fn template_error(_: askama::Error) -> HttpResponse {
 }
 
 #[get("/")]
-async fn home() -> impl Responder {
+async fn home(request: HttpRequest, auth: Data<Option<Auth>>) -> impl Responder {
+    if let Some(response) = login_required(&auth, &request, None) {
+        return response;
+    }
+
     HomeTemplate {
         repl: &ReplInput {
             statement: "".to_owned(),
+            confirm: false,
         },
     }
     .render()
@@ -148,37 +798,176 @@ async fn not_found() -> HttpResponse {
 struct Configuration {
     ip: String,
     port: u16,
+    shutdown_timeout: u64,
+    state_file: Option<PathBuf>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    confirm_threshold: Option<usize>,
+    // Every session gets a bag of its own unless disabled.
+    jail: Option<JailSettings>,
 }
 
-#[actix_web::main] // or #[tokio::main]
-async fn main() -> std::io::Result<()> {
-    let mut repl = Repl::new("init");
-    let Ok((_, stmt)) = statement(".bag jail as _ limit 30") else {
-        return Err(Error::new(std::io::ErrorKind::Other, "Failed to parse statement"));
-    };
-    let Ok(_) = repl.execute(stmt) else {
-        return Err(Error::new(std::io::ErrorKind::Other, "Failed to create bag"));
+fn restore_state(repl: &mut Repl, path: &Path) -> Result<(), Error> {
+    repl.bag_bundle.bags.clear();
+
+    match repl.execute(Statement::LoadBundle(Cow::Owned(
+        path.to_string_lossy().to_string(),
+    ))) {
+        Ok(r) => {
+            println!("{r}");
+            Ok(())
+        }
+        Err(e) => Err(Error::new(
+            std::io::ErrorKind::Other,
+            format!("Failed to restore state from {}: {e:?}", path.display()),
+        )),
+    }
+}
+
+// The previous state is only replaced once the new one is completely
+// written, so that it survives being interrupted during shutdown.
+fn save_state(repl: &Repl, path: &Path) -> Result<(), Error> {
+    let partial = path.with_extension("partial");
+    let written = fs::File::create(&partial)
+        .and_then(|file| {
+            let mut writer = std::io::BufWriter::new(file);
+            repl.write_bundle(&mut writer)?;
+            writer.into_inner()?.sync_all()
+        })
+        .and_then(|_| fs::rename(&partial, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+
+    println!("State saved to {}", path.display());
+    Ok(())
+}
+
+#[cfg(feature = "tls")]
+fn tls_config(cert: &Path, key: &Path) -> Result<rustls::ServerConfig, Error> {
+    let invalid = |e: String| Error::new(std::io::ErrorKind::InvalidData, e);
+
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(fs::File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(key) = rustls_pemfile::private_key(&mut std::io::BufReader::new(fs::File::open(key)?))? else {
+        return Err(invalid(format!("No private key found in {}", key.display())));
     };
-    let repl_mutex = Arc::new(Mutex::new(Repl::new("init")));
-    let repl_mutex_data = Data::new(repl_mutex.clone());
 
+    rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|b| b.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| invalid(e.to_string()))
+}
+
+#[actix_web::main] // or #[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let jail_defaults = JailSettings::default();
     let conf = Configuration {
         ip: env::var("DAMASC_HOST").unwrap_or("127.0.0.1".into()),
         port: env::var("DAMASC_PORT")
             .ok()
             .and_then(|s| s.parse::<u16>().ok())
             .unwrap_or(8080),
+        shutdown_timeout: env::var("DAMASC_SHUTDOWN_TIMEOUT")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(30),
+        state_file: env::var("DAMASC_STATE_FILE").ok().map(PathBuf::from),
+        tls_cert: env::var("DAMASC_TLS_CERT").ok().map(PathBuf::from),
+        tls_key: env::var("DAMASC_TLS_KEY").ok().map(PathBuf::from),
+        confirm_threshold: env::var("DAMASC_CONFIRM_THRESHOLD")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok()),
+        jail: (env::var("DAMASC_JAIL").as_deref() != Ok("off")).then(|| JailSettings {
+            item_ttl: match env::var("DAMASC_JAIL_ITEM_TTL").as_deref() {
+                Ok("off") => None,
+                Ok(s) => s.parse::<u64>().ok().or(jail_defaults.item_ttl),
+                Err(_) => jail_defaults.item_ttl,
+            },
+            max_items: env::var("DAMASC_JAIL_MAX_ITEMS")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(jail_defaults.max_items),
+            session_ttl: env::var("DAMASC_JAIL_SESSION_TTL")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(jail_defaults.session_ttl),
+            max_sessions: env::var("DAMASC_JAIL_MAX_SESSIONS")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(jail_defaults.max_sessions),
+        }),
     };
 
+    let storage = Storage::from_env().transpose()?;
+    let mut shared_repl = Repl::open("init", storage).map_err(|e| {
+        Error::new(
+            std::io::ErrorKind::Other,
+            format!("Failed to load stored bags: {e:?}"),
+        )
+    })?;
+    if let Some(path) = conf.state_file.as_ref().filter(|p| p.exists()) {
+        restore_state(&mut shared_repl, path)?;
+    }
+    shared_repl.settings.limits = Limits::playground();
+    shared_repl.settings.confirm_threshold = conf.confirm_threshold;
+    // Visitors share the repl, they may neither access the files of the
+    // server nor create bags or change the settings guarding it.
+    shared_repl.capabilities = Capabilities::ALL
+        .without(Capabilities::IO | Capabilities::BAG_CREATE | Capabilities::SESSION);
+    let repl_mutex = Arc::new(Mutex::new(shared_repl));
+    let repl_mutex_data = Data::new(repl_mutex.clone());
+    let jails_data = Data::new(conf.jail.clone().map(|s| Mutex::new(Jails::new(s))));
+    let jails = jails_data.clone();
+    let audit_mutex_data = Data::new(Mutex::new(AuditLog::new(
+        env::var("DAMASC_AUDIT_LOG").unwrap_or("audit.log".into()),
+        env::var("DAMASC_AUDIT_MAX_BYTES")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(1 << 20),
+    )));
+    #[cfg(feature = "cli")]
+    let audit_mutex = audit_mutex_data.clone();
+    let auth_data = Data::new(Auth::from_env());
+
     let server = HttpServer::new(move || {
         App::new()
             .app_data(repl_mutex_data.clone())
+            .app_data(audit_mutex_data.clone())
+            .app_data(auth_data.clone())
+            .app_data(jails_data.clone())
             .service(home)
             .service(eval)
+            .service(complete)
+            .service(incomplete)
+            .service(api_eval)
+            .service(api_script)
+            .service(api_bags)
+            .service(api_query)
+            .service(admin_audit)
+            .service(healthz)
+            .service(readyz)
+            .service(snapshot)
             .service(Files::new("/", "./public/"))
             .default_service(web::route().to(not_found))
     })
-    .bind((conf.ip, conf.port))?;
+    .shutdown_timeout(conf.shutdown_timeout);
+
+    let server = match (&conf.tls_cert, &conf.tls_key) {
+        #[cfg(feature = "tls")]
+        (Some(cert), Some(key)) => {
+            server.bind_rustls_0_23((conf.ip.as_str(), conf.port), tls_config(cert, key)?)?
+        }
+        #[cfg(not(feature = "tls"))]
+        (Some(_), Some(_)) => {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "TLS requires the 'tls' feature",
+            ))
+        }
+        _ => server.bind((conf.ip.as_str(), conf.port))?,
+    };
 
     println!("Server started");
     for (adr, scheme) in server.addrs_with_scheme() {
@@ -187,24 +976,41 @@ async fn main() -> std::io::Result<()> {
 
     let running = server.run();
     #[cfg(feature = "cli")]
-    {
+    let result = {
         use futures::try_join;
 
-        match try_join!(running, cli(repl_mutex.clone())) {
+        match try_join!(running, cli(repl_mutex.clone(), audit_mutex.clone())) {
             Ok(_) => Ok(()),
             Err(e) if e.kind() == std::io::ErrorKind::Interrupted => Ok(()),
             Err(e) => Err(e),
         }
-    }
+    };
 
     #[cfg(not(feature = "cli"))]
-    {
-        return running.await;
+    let result = running.await;
+
+    if let Some(path) = &conf.state_file {
+        let Ok(mut repl) = repl_mutex.lock() else {
+            return Err(Error::new(std::io::ErrorKind::Other, "Failed to lock state"));
+        };
+        // The jails end with the sessions, they are not saved.
+        if let Some(jails) = jails.as_ref() {
+            let cleared = jails.lock().unwrap_or_else(PoisonError::into_inner).clear(&mut repl);
+            if let Err(e) = cleared {
+                println!("Failed to drop the jails: {e:?}");
+            }
+        }
+        save_state(&repl, path)?;
     }
+
+    result
 }
 
 #[cfg(feature = "cli")]
-async fn cli(repl_mutex: Arc<Mutex<Repl<'_, '_, '_, '_>>>) -> Result<(), Error> {
+async fn cli(
+    repl_mutex: Arc<Mutex<Repl<'_, '_, '_, '_>>>,
+    audit_mutex: Data<Mutex<AuditLog>>,
+) -> Result<(), Error> {
     use damasc::repl::ReplError;
     use rustyline::error::ReadlineError;
     use rustyline::Editor;
@@ -228,10 +1034,10 @@ async fn cli(repl_mutex: Arc<Mutex<Repl<'_, '_, '_, '_>>>) -> Result<(), Error>
                     rl.add_history_entry(line.as_str());
                     let input = line.as_str();
 
-                    let stmt = match statement(input) {
-                        Ok((_, s)) => s,
+                    let stmt = match checked_statement(input, &Limits::default()) {
+                        Ok(s) => s,
                         Err(e) => {
-                            println!("read error: {e}");
+                            println!("read error[{}]: {e}", e.code());
                             continue;
                         }
                     };
@@ -240,7 +1046,21 @@ async fn cli(repl_mutex: Arc<Mutex<Repl<'_, '_, '_, '_>>>) -> Result<(), Error>
                         continue;
                     };
 
-                    match repl.execute(stmt) {
+                    // The console of the operator may do everything.
+                    let capabilities = std::mem::replace(&mut repl.capabilities, Capabilities::ALL);
+                    let started = Instant::now();
+                    let result = repl.execute(stmt);
+                    repl.capabilities = capabilities;
+                    if let Ok(mut audit) = audit_mutex.lock() {
+                        match &result {
+                            Ok(r) => audit.record("console", input, started, "ok", &r.to_string()),
+                            Err(e) => {
+                                audit.record("console", input, started, "error", &format!("[{}] {e:?}", e.code()))
+                            }
+                        }
+                    }
+
+                    match result {
                         Ok(r) => {
                             println!("{r}")
                         }
@@ -250,7 +1070,7 @@ async fn cli(repl_mutex: Arc<Mutex<Repl<'_, '_, '_, '_>>>) -> Result<(), Error>
                                 "Closed by user",
                             ))
                         }
-                        Err(e) => println!("Error: {e:?}"),
+                        Err(e) => println!("Error[{}]: {e:?}", e.code()),
                     }
                 }
                 Err(ReadlineError::Interrupted) => {
@@ -0,0 +1,124 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use crate::env::Environment;
+use crate::expression::Expression;
+use crate::identifier::Identifier;
+use crate::matcher::Matcher;
+use crate::pattern::Pattern;
+use crate::statement::Statement;
+use crate::value::Value;
+
+// Pauses the statements of an interactive frontend once a value matching
+// the pattern and guard enters the bag. Without a pattern the guard sees
+// the value as `$` and the properties of an object by their name.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Breakpoint<'s> {
+    pub(crate) bag: Identifier<'s>,
+    pub(crate) pattern: Option<Pattern<'s>>,
+    pub(crate) guard: Expression<'s>,
+}
+
+impl<'s> Breakpoint<'s> {
+    pub(crate) fn matches<'v>(&self, value: &Value<'s, 'v>) -> bool {
+        let outer = Environment::new();
+        let mut env = Environment::new();
+        match &self.pattern {
+            Some(pattern) => {
+                let mut matcher = Matcher::new(&outer);
+                if matcher.match_pattern(pattern, value).is_err() {
+                    return false;
+                }
+                matcher.merge_into(&mut env);
+            }
+            None => {
+                if let Value::Object(properties) = value {
+                    for (key, v) in properties {
+                        let name = Cow::Owned(key.to_string());
+                        env.bindings.insert(Identifier { name }, v.as_ref().clone());
+                    }
+                }
+                let name = Cow::Borrowed("$");
+                env.bindings.insert(Identifier { name }, value.clone());
+            }
+        }
+
+        matches!(env.eval_expr(&self.guard), Ok(Value::Boolean(true)))
+    }
+}
+
+impl std::fmt::Display for Breakpoint<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", Statement::Break(self.clone()))
+    }
+}
+
+// Where a value hitting a breakpoint came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    // The statement, in its canonical form.
+    Statement(String),
+    Connection(String),
+}
+
+// The value that hit a breakpoint, along with the number of statements
+// waiting for `.continue`.
+#[derive(Clone, Debug)]
+pub struct Hit<'s, 'v> {
+    pub bag: Identifier<'s>,
+    pub value: Value<'s, 'v>,
+    pub origin: Origin,
+    pub queued: usize,
+}
+
+impl std::fmt::Display for Hit<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "BREAK on insert into {}.", self.bag)?;
+        writeln!(f, "{};", self.value)?;
+        match &self.origin {
+            Origin::Statement(statement) => writeln!(f, "-- from {statement}")?,
+            Origin::Connection(connection) => writeln!(f, "-- from connection {connection}")?,
+        }
+        match self.queued {
+            0 => writeln!(f, "-- .continue or .abort"),
+            1 => writeln!(f, "-- 1 statement queued, .continue or .abort"),
+            n => writeln!(f, "-- {n} statements queued, .continue or .abort"),
+        }
+    }
+}
+
+// The state of a frontend paused by a breakpoint. The statements changing
+// the bags are queued until `.continue`, the others inspect the bags as
+// they were left by the statement that hit the breakpoint.
+pub(crate) struct Pause<'s, 'v> {
+    hit: Hit<'s, 'v>,
+    queue: Vec<Statement<'s, 's>>,
+    // The hit was shown along with the output of a statement.
+    pub(crate) reported: bool,
+}
+
+impl<'s, 'v> Pause<'s, 'v> {
+    pub(crate) fn new(hit: Hit<'s, 'v>, queue: Vec<Statement<'s, 's>>) -> Self {
+        Self {
+            hit,
+            queue,
+            reported: false,
+        }
+    }
+
+    pub(crate) fn hit(&self) -> Hit<'s, 'v> {
+        Hit {
+            queued: self.queue.len(),
+            ..self.hit.clone()
+        }
+    }
+
+    pub(crate) fn enqueue(&mut self, stmt: Statement<'s, 's>) {
+        self.queue.push(stmt);
+    }
+
+    pub(crate) fn finish(self) -> Vec<Statement<'s, 's>> {
+        self.queue
+    }
+}
@@ -0,0 +1,173 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use crate::env::EvalError;
+use crate::value::Value;
+
+// The aggregate functions of queries, they are not called like the others.
+const AGGREGATES: &[&str] = &["avg", "count", "max", "min", "sum"];
+
+// The builtins whose result depends on nothing but their arguments, so that
+// their calls can be memoized. Unlike eg `eval` or `count_of`, which depend
+// on the bindings and the matched value.
+const PURE: &[&str] = &["diff", "flatten", "keys", "length", "sort", "type", "unique", "values"];
+
+// The builtins whose result depends on more than their arguments and the
+// bindings of the repl, so that specialized guards keep their calls:
+// `count_of` reads the bag being queried, `eval` and `matches` see the
+// bindings of the query and `assert` records its failures.
+const CONTEXT_DEPENDENT: &[&str] = &["assert", "count_of", "eval", "matches"];
+
+// The builtin functions grouped by what they do, so that an application can
+// offer a smaller language, eg a playground without `eval`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BuiltinGroup {
+    // `length`, `keys`, `values`, `sort`, `unique` and `flatten`.
+    Collections,
+    // `map`, `filter` and `reduce`, which call closures.
+    HigherOrder,
+    // `type`, `matches`, `eval` and `diff`, which inspect values and quoted
+    // expressions.
+    Reflection,
+    // `assert` and `count_of`, mostly used in guards.
+    Guards,
+}
+
+impl BuiltinGroup {
+    pub const ALL: [BuiltinGroup; 4] = [
+        BuiltinGroup::Collections,
+        BuiltinGroup::HigherOrder,
+        BuiltinGroup::Reflection,
+        BuiltinGroup::Guards,
+    ];
+
+    pub fn functions(self) -> &'static [&'static str] {
+        match self {
+            BuiltinGroup::Collections => &["flatten", "keys", "length", "sort", "unique", "values"],
+            BuiltinGroup::HigherOrder => &["filter", "map", "reduce"],
+            BuiltinGroup::Reflection => &["diff", "eval", "matches", "type"],
+            BuiltinGroup::Guards => &["assert", "count_of"],
+        }
+    }
+
+    // The group of a builtin function, None for other names.
+    pub fn of(function: &str) -> Option<BuiltinGroup> {
+        Self::ALL
+            .into_iter()
+            .find(|group| group.functions().contains(&function))
+    }
+
+    // The name used by frontends, eg `higher_order`.
+    pub fn name(self) -> &'static str {
+        match self {
+            BuiltinGroup::Collections => "collections",
+            BuiltinGroup::HigherOrder => "higher_order",
+            BuiltinGroup::Reflection => "reflection",
+            BuiltinGroup::Guards => "guards",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BuiltinGroup> {
+        Self::ALL.into_iter().find(|group| group.name() == name)
+    }
+}
+
+// Whether the call can be memoized. Closures passed to a pure builtin may
+// call impure ones.
+pub(crate) fn is_pure(function: &str, arguments: &[Value]) -> bool {
+    PURE.contains(&function) && !arguments.iter().any(|a| matches!(a, Value::Closure(_)))
+}
+
+pub(crate) fn is_context_dependent(function: &str) -> bool {
+    CONTEXT_DEPENDENT.contains(&function)
+}
+
+// A function provided by the application, called with the evaluated
+// arguments. It fails with `EvalError::FunctionFailed` if it can not
+// compute a result, eg because a callback into the host threw.
+pub type ExternalFunction =
+    dyn for<'s, 'v> Fn(&[Value<'s, 'v>]) -> Result<Value<'s, 'v>, EvalError> + Send + Sync;
+
+// The functions callable by the statements of a repl: the enabled groups of
+// builtins and the functions registered by the application.
+#[derive(Clone, Default)]
+pub struct Builtins {
+    disabled: BTreeSet<BuiltinGroup>,
+    functions: BTreeMap<String, Arc<ExternalFunction>>,
+}
+
+impl Builtins {
+    pub fn enable(&mut self, group: BuiltinGroup) {
+        self.disabled.remove(&group);
+    }
+
+    pub fn disable(&mut self, group: BuiltinGroup) {
+        self.disabled.insert(group);
+    }
+
+    pub fn is_enabled(&self, group: BuiltinGroup) -> bool {
+        !self.disabled.contains(&group)
+    }
+
+    // Adds a function, returns false if its name is already used by a
+    // builtin, disabled or not, or by another registered function.
+    pub fn register<F>(&mut self, name: &str, function: F) -> bool
+    where
+        F: for<'s, 'v> Fn(&[Value<'s, 'v>]) -> Result<Value<'s, 'v>, EvalError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        if BuiltinGroup::of(name).is_some()
+            || AGGREGATES.contains(&name)
+            || self.functions.contains_key(name)
+        {
+            return false;
+        }
+        self.functions.insert(name.to_string(), Arc::new(function));
+
+        true
+    }
+
+    // The names of the callable functions, eg for completion.
+    pub fn names(&self) -> BTreeSet<String> {
+        BuiltinGroup::ALL
+            .into_iter()
+            .filter(|group| self.is_enabled(*group))
+            .flat_map(|group| group.functions())
+            .chain(AGGREGATES)
+            .map(|name| name.to_string())
+            .chain(self.functions.keys().cloned())
+            .collect()
+    }
+}
+
+thread_local! {
+    static ACTIVE: RefCell<Builtins> = RefCell::new(Builtins::default());
+}
+
+// Makes the builtins of a repl the ones used by the evaluation on this
+// thread, until other ones are activated.
+pub(crate) fn activate(builtins: &Builtins) {
+    ACTIVE.with(|active| *active.borrow_mut() = builtins.clone());
+}
+
+pub(crate) enum Lookup {
+    Builtin,
+    External(Arc<ExternalFunction>),
+    Unknown,
+}
+
+pub(crate) fn lookup(function: &str) -> Lookup {
+    ACTIVE.with(|active| {
+        let active = active.borrow();
+        if let Some(f) = active.functions.get(function) {
+            return Lookup::External(f.clone());
+        }
+        match BuiltinGroup::of(function) {
+            Some(group) if active.is_enabled(group) => Lookup::Builtin,
+            _ => Lookup::Unknown,
+        }
+    })
+}
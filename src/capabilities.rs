@@ -0,0 +1,93 @@
+use std::collections::BTreeSet;
+use std::ops::BitOr;
+
+use crate::identifier::Identifier;
+use crate::statement::Statement;
+
+// The kinds of statements a frontend allows, checked by `Repl::execute`
+// before a statement is run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    // Reading and writing files, eg `.load`, `.dump`, `.cdc` or `.run`.
+    pub const IO: Capabilities = Capabilities(1);
+    // Creating new bags, eg `.bag` with a new name, `.copy` or `.view`.
+    pub const BAG_CREATE: Capabilities = Capabilities(2);
+    // Defining, removing and running connections.
+    pub const CONNECTIONS: Capabilities = Capabilities(4);
+    // Everything `Statement::is_mutating`.
+    pub const MUTATION: Capabilities = Capabilities(8);
+    // Changing what all users of a shared repl depend on: transactions and
+    // the settings, eg the limits and the budget guarding the server.
+    pub const SESSION: Capabilities = Capabilities(16);
+    pub const ALL: Capabilities = Capabilities(31);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn without(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+
+    // The capabilities needed to run the statement, given the existing bags.
+    // Defining macros and prepared statements needs those of their body,
+    // which are checked again once they are run.
+    pub fn required(statement: &Statement, bags: &BTreeSet<Identifier>) -> Capabilities {
+        let mutation = if statement.is_mutating() {
+            Capabilities::MUTATION
+        } else {
+            Capabilities::NONE
+        };
+
+        mutation
+            | match statement {
+                // The inputs of every user of the repl.
+                Statement::UseBag(id, ..) if id.name == "$history" => Capabilities::SESSION,
+                Statement::UseBag(id, ..) if !bags.contains(id) => Capabilities::BAG_CREATE,
+                Statement::RenameBag(..)
+                | Statement::CopyBag(..)
+                | Statement::CreateVirtualBag(..)
+                | Statement::CreateView(..) => Capabilities::BAG_CREATE,
+                Statement::Import(..)
+                | Statement::Export(..)
+                | Statement::LoadBundle(_)
+                | Statement::Save(_)
+                | Statement::Restore(_)
+                | Statement::Run(_)
+                | Statement::Cdc(..)
+                | Statement::Replicate(_)
+                | Statement::Log(..) => Capabilities::IO,
+                Statement::Connect(..)
+                | Statement::Disconnect(_)
+                | Statement::Trigger(..)
+                | Statement::Solve(..) => Capabilities::CONNECTIONS,
+                // The stepping is kept until decided on, across statements.
+                Statement::SolveStep(..) | Statement::Step(_) => {
+                    Capabilities::CONNECTIONS | Capabilities::SESSION
+                }
+                Statement::Begin
+                | Statement::Commit
+                | Statement::Rollback
+                | Statement::Break(_)
+                | Statement::ListBreakpoints
+                | Statement::ClearBreakpoints
+                | Statement::OnError(_)
+                | Statement::Set(..) => Capabilities::SESSION,
+                Statement::DefineMacro(_, _, body) | Statement::Prepare(_, body) => {
+                    Capabilities::required(body, bags)
+                }
+                _ => Capabilities::NONE,
+            }
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    identifier::Identifier,
+    observer::{Mutation, MutationObserver, ObserverError},
+    value::Value,
+};
+
+// Appends one JSON change record per line to a file, so that other systems
+// can follow the changes of a bag by tailing it.
+pub struct CdcFile {
+    file: Mutex<File>,
+}
+
+impl CdcFile {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    // Written when the capture starts, so that a replica reading the file
+    // does not depend on the state of the bag before.
+    pub fn snapshot(&self, bag: &Identifier, items: &[Value]) -> io::Result<()> {
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "poisoned"))?;
+        let record = snapshot_record(bag, items)?;
+        writeln!(file, "{record}")?;
+        file.flush()
+    }
+}
+
+// The values are written in their typed encoding, see `value_serde`, so
+// that a replica reads back the same values. Functions can not be written.
+pub fn change_record(bag: &Identifier, mutation: &Mutation) -> serde_json::Result<serde_json::Value> {
+    Ok(match mutation {
+        Mutation::Insert(after) => serde_json::json!({
+            "bag": bag.name,
+            "op": "insert",
+            "after": serde_json::to_value(after)?,
+        }),
+        Mutation::Update { before, after } => serde_json::json!({
+            "bag": bag.name,
+            "op": "update",
+            "before": serde_json::to_value(before)?,
+            "after": serde_json::to_value(after)?,
+        }),
+        Mutation::Delete(before) => serde_json::json!({
+            "bag": bag.name,
+            "op": "delete",
+            "before": serde_json::to_value(before)?,
+        }),
+    })
+}
+
+pub fn snapshot_record(bag: &Identifier, items: &[Value]) -> serde_json::Result<serde_json::Value> {
+    Ok(serde_json::json!({
+        "bag": bag.name,
+        "op": "snapshot",
+        "items": serde_json::to_value(items)?,
+    }))
+}
+
+pub enum ChangeRecord<'s, 'v> {
+    Snapshot(Vec<Value<'s, 'v>>),
+    Change(Mutation<'s, 'v>),
+}
+
+// The inverse of `change_record` and `snapshot_record`.
+pub fn read_record<'s, 'v>(record: &serde_json::Value) -> Option<(String, ChangeRecord<'s, 'v>)> {
+    let bag = record.get("bag")?.as_str()?.to_string();
+    let value = |key| record.get(key).and_then(|v| Value::deserialize(v).ok());
+
+    let change = match record.get("op")?.as_str()? {
+        "snapshot" => ChangeRecord::Snapshot(Vec::deserialize(record.get("items")?).ok()?),
+        "insert" => ChangeRecord::Change(Mutation::Insert(value("after")?)),
+        "update" => ChangeRecord::Change(Mutation::Update {
+            before: value("before")?,
+            after: value("after")?,
+        }),
+        "delete" => ChangeRecord::Change(Mutation::Delete(value("before")?)),
+        _ => return None,
+    };
+
+    Some((bag, change))
+}
+
+impl<'s, 'v> MutationObserver<'s, 'v> for CdcFile {
+    fn notify(
+        &self,
+        bag: &Identifier<'s>,
+        mutations: &[Mutation<'s, 'v>],
+    ) -> Result<(), ObserverError> {
+        let mut records = String::new();
+        for mutation in mutations {
+            let record = change_record(bag, mutation).map_err(|_| ObserverError)?;
+            records.push_str(&record.to_string());
+            records.push('\n');
+        }
+
+        let mut file = self.file.lock().map_err(|_| ObserverError)?;
+        file.write_all(records.as_bytes())
+            .and_then(|_| file.flush())
+            .map_err(|_| ObserverError)
+    }
+}
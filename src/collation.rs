@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::env::EvalError;
+use crate::value::Value;
+
+// How strings are compared after `collate`, eg `name == "ann" collate nocase`
+// or `order by name collate "de"`. Values other than strings are compared as
+// usual.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Collation {
+    #[default]
+    Binary,
+    NoCase,
+    // The rules of a language, eg `"de"` or `"sv-SE"`. Requires the
+    // `collation` feature.
+    Locale(String),
+}
+
+impl std::fmt::Display for Collation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Collation::Binary => write!(f, "binary"),
+            Collation::NoCase => write!(f, "nocase"),
+            Collation::Locale(locale) => write!(f, "\"{locale}\""),
+        }
+    }
+}
+
+impl Collation {
+    // The value compared in place of the given one, eg the lowercased string
+    // for `nocase`. Strings inside of arrays are replaced as well, so keys
+    // like `[last, first]` are ordered the same way.
+    pub(crate) fn key<'s, 'v>(&self, value: &Value<'s, 'v>) -> Value<'s, 'v> {
+        match (self, value) {
+            (Collation::NoCase, Value::String(s)) => Value::String(Cow::Owned(s.to_lowercase())),
+            (Collation::NoCase, Value::Array(a)) => {
+                Value::Array(a.iter().map(|v| Cow::Owned(self.key(v))).collect())
+            }
+            _ => value.clone(),
+        }
+    }
+
+    // Fails if the locale is not known, so a query can report it once
+    // instead of for every comparison.
+    pub(crate) fn check(&self) -> Result<(), EvalError> {
+        match self {
+            Collation::Locale(locale) => locale_compare(locale, "", "").map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn compare<'s, 'v>(
+        &self,
+        left: &Value<'s, 'v>,
+        right: &Value<'s, 'v>,
+    ) -> Result<Ordering, EvalError> {
+        match (self, left, right) {
+            (Collation::Locale(locale), Value::String(l), Value::String(r)) => {
+                locale_compare(locale, l, r)
+            }
+            (Collation::Locale(_), Value::Array(l), Value::Array(r)) => {
+                for (l, r) in l.iter().zip(r.iter()) {
+                    match self.compare(l, r)? {
+                        Ordering::Equal => {}
+                        ordering => return Ok(ordering),
+                    }
+                }
+                Ok(l.len().cmp(&r.len()))
+            }
+            _ => Ok(self.key(left).cmp(&self.key(right))),
+        }
+    }
+}
+
+#[cfg(feature = "collation")]
+fn locale_compare(locale: &str, left: &str, right: &str) -> Result<Ordering, EvalError> {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use icu_collator::{Collator, CollatorOptions};
+    use icu_locid::Locale;
+
+    thread_local! {
+        static COLLATORS: RefCell<HashMap<String, Collator>> = RefCell::new(HashMap::new());
+    }
+
+    COLLATORS.with(|collators| {
+        let mut collators = collators.borrow_mut();
+        if !collators.contains_key(locale) {
+            let parsed = locale
+                .parse::<Locale>()
+                .map_err(|_| EvalError::InvalidCollation)?;
+            let collator = Collator::try_new(&(&parsed).into(), CollatorOptions::new())
+                .map_err(|_| EvalError::InvalidCollation)?;
+            collators.insert(locale.to_string(), collator);
+        }
+
+        Ok(collators[locale].compare(left, right))
+    })
+}
+
+#[cfg(not(feature = "collation"))]
+fn locale_compare(_locale: &str, _left: &str, _right: &str) -> Result<Ordering, EvalError> {
+    Err(EvalError::InvalidCollation)
+}
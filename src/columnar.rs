@@ -0,0 +1,120 @@
+use std::{borrow::Cow, collections::BTreeMap, io::Write, sync::Arc};
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Int64Array, NullArray, RecordBatch, RecordBatchOptions, StringArray,
+    TimestampMillisecondArray,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use parquet::arrow::ArrowWriter;
+
+use crate::value::{Value, ValueType};
+
+#[derive(Debug)]
+pub enum ParquetError {
+    NotAnObject,
+    MixedTypes(String),
+    UnsupportedValue(String),
+    WriteFailed,
+}
+
+// The arrow type of a column. Missing properties and nulls become null
+// entries of the column.
+fn column_type(values: &[Option<&Value>]) -> Result<ValueType, ParquetError> {
+    let mut column = ValueType::Null;
+    for value in values.iter().flatten() {
+        let value_type = match value {
+            Value::Null => continue,
+            Value::String(_) => ValueType::String,
+            Value::Integer(_) => ValueType::Integer,
+            Value::Boolean(_) => ValueType::Boolean,
+            Value::DateTime(_) => ValueType::Datetime,
+            _ => return Err(ParquetError::UnsupportedValue(value.to_string())),
+        };
+        if column != ValueType::Null && column != value_type {
+            return Err(ParquetError::MixedTypes(format!("{column} and {value_type}")));
+        }
+        column = value_type;
+    }
+    Ok(column)
+}
+
+fn column(values: &[Option<&Value>]) -> Result<(DataType, ArrayRef), ParquetError> {
+    Ok(match column_type(values)? {
+        ValueType::String => (
+            DataType::Utf8,
+            Arc::new(StringArray::from_iter(values.iter().map(|v| match v {
+                Some(Value::String(s)) => Some(s.as_ref()),
+                _ => None,
+            }))),
+        ),
+        ValueType::Integer => (
+            DataType::Int64,
+            Arc::new(Int64Array::from_iter(values.iter().map(|v| match v {
+                Some(Value::Integer(i)) => Some(*i),
+                _ => None,
+            }))),
+        ),
+        ValueType::Boolean => (
+            DataType::Boolean,
+            Arc::new(BooleanArray::from_iter(values.iter().map(|v| match v {
+                Some(Value::Boolean(b)) => Some(*b),
+                _ => None,
+            }))),
+        ),
+        ValueType::Datetime => (
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            Arc::new(
+                TimestampMillisecondArray::from_iter(values.iter().map(|v| match v {
+                    Some(Value::DateTime(t)) => Some(*t),
+                    _ => None,
+                }))
+                .with_timezone("UTC"),
+            ),
+        ),
+        _ => (DataType::Null, Arc::new(NullArray::new(values.len()))),
+    })
+}
+
+// Converts a bag of objects into one record batch with a column per property.
+// Nested arrays and objects have no column type and are rejected.
+pub fn record_batch(values: &[&Value]) -> Result<RecordBatch, ParquetError> {
+    let rows = values
+        .iter()
+        .map(|v| match v {
+            Value::Object(o) => Ok(o),
+            _ => Err(ParquetError::NotAnObject),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut columns = BTreeMap::<&Cow<str>, Vec<Option<&Value>>>::new();
+    for row in &rows {
+        for key in row.keys() {
+            columns.entry(key).or_default();
+        }
+    }
+    for row in &rows {
+        for (key, cells) in &mut columns {
+            cells.push(row.get(*key).map(|v| v.as_ref()));
+        }
+    }
+
+    let mut fields = vec![];
+    let mut arrays = vec![];
+    for (key, cells) in columns {
+        let (data_type, array) = column(&cells)?;
+        fields.push(Field::new(key.as_ref(), data_type, true));
+        arrays.push(array);
+    }
+
+    let options = RecordBatchOptions::new().with_row_count(Some(rows.len()));
+    RecordBatch::try_new_with_options(Arc::new(Schema::new(fields)), arrays, &options)
+        .map_err(|_| ParquetError::WriteFailed)
+}
+
+pub fn write<W: Write + Send>(out: W, batch: &RecordBatch) -> Result<(), ParquetError> {
+    let mut writer =
+        ArrowWriter::try_new(out, batch.schema(), None).map_err(|_| ParquetError::WriteFailed)?;
+    writer.write(batch).map_err(|_| ParquetError::WriteFailed)?;
+    writer.close().map_err(|_| ParquetError::WriteFailed)?;
+    Ok(())
+}
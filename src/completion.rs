@@ -0,0 +1,151 @@
+use std::collections::BTreeSet;
+
+use crate::{help, parser::KEYWORDS, repl::Repl, value::ValueType};
+
+const BAG_COMMANDS: &[&str] = &[
+    ".bag", ".cdc", ".copy", ".delta", ".drop", ".index", ".rename", ".stats",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CompletionKind {
+    Command,
+    Keyword,
+    Function,
+    Type,
+    Bag,
+    Variable,
+    Macro,
+    Environment,
+}
+
+impl std::fmt::Display for CompletionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                CompletionKind::Command => "command",
+                CompletionKind::Keyword => "keyword",
+                CompletionKind::Function => "function",
+                CompletionKind::Type => "type",
+                CompletionKind::Bag => "bag",
+                CompletionKind::Variable => "variable",
+                CompletionKind::Macro => "macro",
+                CompletionKind::Environment => "environment",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Completion {
+    pub text: String,
+    pub kind: CompletionKind,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Completions {
+    pub start: usize,
+    pub candidates: Vec<Completion>,
+}
+
+impl Completions {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "start": self.start,
+            "candidates": self
+                .candidates
+                .iter()
+                .map(|c| serde_json::json!({"text": c.text, "kind": c.kind.to_string()}))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompletionContext {
+    pub bags: BTreeSet<String>,
+    pub variables: BTreeSet<String>,
+    pub macros: BTreeSet<String>,
+    pub environments: BTreeSet<String>,
+    pub functions: BTreeSet<String>,
+}
+
+impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
+    pub fn completion_context(&self) -> CompletionContext {
+        CompletionContext {
+            bags: self.bags().iter().map(|b| b.name.to_string()).collect(),
+            variables: self.vars().iter().map(|v| v.name.to_string()).collect(),
+            macros: self.macros.keys().map(|m| m.name.to_string()).collect(),
+            environments: self.environments.keys().map(|e| e.name.to_string()).collect(),
+            functions: self.builtins.names(),
+        }
+    }
+}
+
+impl CompletionContext {
+    pub fn complete(&self, input: &str, cursor: usize) -> Completions {
+        let mut cursor = cursor.min(input.len());
+        while !input.is_char_boundary(cursor) {
+            cursor -= 1;
+        }
+        let before = &input[..cursor];
+        let start = before
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let previous = before[..start].chars().last();
+        let line = before[..start].trim_start();
+
+        let mut candidates = BTreeSet::new();
+        let mut add = |names: &mut dyn Iterator<Item = &str>, kind| {
+            for name in names.filter(|n| n.starts_with(word)) {
+                candidates.insert(Completion {
+                    text: name.to_string(),
+                    kind,
+                });
+            }
+        };
+
+        match previous {
+            Some('.') if line == "." => {
+                add(&mut help::commands(), CompletionKind::Command);
+            }
+            _ if line.split_whitespace().eq([".help"]) => {
+                add(&mut help::commands(), CompletionKind::Command);
+            }
+            Some('!') if line == "!" => {
+                add(&mut self.macros.iter().map(String::as_str), CompletionKind::Macro);
+            }
+            _ if BAG_COMMANDS
+                .iter()
+                .any(|c| line.split_whitespace().eq([*c])) =>
+            {
+                add(&mut self.bags.iter().map(String::as_str), CompletionKind::Bag);
+            }
+            _ if line.starts_with(".move") && previous == Some('(') => {
+                add(&mut self.bags.iter().map(String::as_str), CompletionKind::Bag);
+            }
+            _ if line.split_whitespace().eq([".unset"]) => {
+                add(&mut self.variables.iter().map(String::as_str), CompletionKind::Variable);
+            }
+            _ if line.split_whitespace().eq([".env"]) => {
+                add(&mut ["load", "save"].into_iter(), CompletionKind::Keyword);
+            }
+            _ if line.starts_with(".env ") && line.split_whitespace().count() == 2 => {
+                add(&mut self.environments.iter().map(String::as_str), CompletionKind::Environment);
+            }
+            _ => {
+                add(&mut KEYWORDS.iter().copied(), CompletionKind::Keyword);
+                add(&mut self.functions.iter().map(String::as_str), CompletionKind::Function);
+                add(&mut ValueType::ALL.iter().map(ValueType::name), CompletionKind::Type);
+                add(&mut self.variables.iter().map(String::as_str), CompletionKind::Variable);
+            }
+        }
+
+        Completions {
+            start,
+            candidates: candidates.into_iter().collect(),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bag_bundle::BagBundle;
+use crate::graph::{Connection, Consumption};
+use crate::graph_solver::ChangeSet;
+use crate::identifier::Identifier;
+use crate::matcher::Bindings;
+use crate::statement::Statement;
+use crate::value::Value;
+
+// What to do with the candidate shown while stepping through the solutions
+// of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StepAction {
+    // Accepts the candidate, unless it uses a value deleted by a candidate
+    // accepted before.
+    Continue,
+    Skip,
+    // Ends the stepping without applying any candidate.
+    Abort,
+}
+
+// A solution of a connection as shown by `.solve <connection> <argument>
+// step`, numbered from 1.
+#[derive(Clone, Debug)]
+pub struct Candidate<'s, 'v> {
+    pub connection: Identifier<'s>,
+    pub number: usize,
+    pub count: usize,
+    // The bindings of the matcher, each with the consumer that bound it, eg
+    // `&orders.consume`, or `parameter` for the argument of the connection.
+    pub bindings: Vec<(Identifier<'s>, Value<'s, 'v>, String)>,
+    // The values the candidate would consume, test and produce, each with
+    // its consumer or producer, eg `&orders.consume` or `&totals.produce`.
+    pub changes: Vec<(String, Value<'s, 'v>)>,
+    // The candidate uses a value deleted by a candidate accepted before, it
+    // is skipped even on `.continue`.
+    pub conflicting: bool,
+}
+
+impl std::fmt::Display for Candidate<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "CANDIDATE {} of {} for {}.", self.number, self.count, self.connection)?;
+        for (id, value, origin) in &self.bindings {
+            writeln!(f, "{id} := {value}; -- {origin}")?;
+        }
+        for (component, value) in &self.changes {
+            writeln!(f, "{component} {value};")?;
+        }
+        if self.conflicting {
+            writeln!(f, "-- conflicts with an accepted candidate")?;
+        }
+        writeln!(f, "-- .continue, .skip or .abort")
+    }
+}
+
+// The state of `.solve <connection> <argument> step`. The solutions are
+// found up front and the accepted ones are applied together once all of
+// them were shown, like `.solve` does. Since they refer to the values of
+// the bags by position, the statements changing the bags are queued until
+// then.
+pub(crate) struct Stepping<'s, 'v> {
+    connection: Identifier<'s>,
+    pending: VecDeque<(ChangeSet<'s, 'v>, Candidate<'s, 'v>)>,
+    accepted: Vec<ChangeSet<'s, 'v>>,
+    queue: Vec<Statement<'s, 's>>,
+}
+
+impl<'s, 'v> Stepping<'s, 'v> {
+    pub(crate) fn new(
+        name: &Identifier<'s>,
+        connection: &Connection<'s>,
+        bag_bundle: &BagBundle<'_, '_, 's, 'v>,
+        solutions: Vec<(ChangeSet<'s, 'v>, Bindings<'_, 's, 'v>)>,
+    ) -> Self {
+        let count = solutions.len();
+        let pending = solutions
+            .into_iter()
+            .enumerate()
+            .map(|(index, (changeset, bindings))| {
+                let candidate = Candidate {
+                    connection: name.clone(),
+                    number: index + 1,
+                    count,
+                    bindings: bindings
+                        .iter()
+                        .map(|(id, value)| {
+                            let id = Identifier {
+                                name: Cow::Owned(id.name.to_string()),
+                            };
+                            let origin = origin(connection, &id);
+                            (id, value.clone(), origin)
+                        })
+                        .collect(),
+                    changes: changes(bag_bundle, &changeset),
+                    conflicting: false,
+                };
+                (changeset, candidate)
+            })
+            .collect();
+
+        Self {
+            connection: name.clone(),
+            pending,
+            accepted: vec![],
+            queue: vec![],
+        }
+    }
+
+    // The candidate to decide on next, None once all were shown.
+    pub(crate) fn current(&self) -> Option<Candidate<'s, 'v>> {
+        let (changeset, candidate) = self.pending.front()?;
+
+        Some(Candidate {
+            conflicting: self.accepted.iter().any(|a| a.conflicts(changeset)),
+            ..candidate.clone()
+        })
+    }
+
+    pub(crate) fn step(&mut self, action: StepAction) {
+        let Some((changeset, _)) = self.pending.pop_front() else {
+            return;
+        };
+        if action == StepAction::Continue && !self.accepted.iter().any(|a| a.conflicts(&changeset)) {
+            self.accepted.push(changeset);
+        }
+    }
+
+    pub(crate) fn enqueue(&mut self, stmt: Statement<'s, 's>) {
+        self.queue.push(stmt);
+    }
+
+    // The connection, the accepted change sets and the queued statements.
+    pub(crate) fn finish(
+        self,
+    ) -> (Identifier<'s>, Vec<ChangeSet<'s, 'v>>, Vec<Statement<'s, 's>>) {
+        (self.connection, self.accepted, self.queue)
+    }
+}
+
+fn consumption(consumption: &Consumption) -> &'static str {
+    match consumption {
+        Consumption::Test => "test",
+        Consumption::Take => "consume",
+    }
+}
+
+fn origin(connection: &Connection, id: &Identifier) -> String {
+    connection
+        .consumers
+        .iter()
+        .find(|c| c.patterns.iter().any(|p| p.get_identifiers().any(|i| i.name == id.name)))
+        .map_or("parameter".to_string(), |c| {
+            format!("&{}.{}", c.source_bag, consumption(&c.consumption))
+        })
+}
+
+fn changes<'s, 'v>(
+    bag_bundle: &BagBundle<'_, '_, 's, 'v>,
+    changeset: &ChangeSet<'s, 'v>,
+) -> Vec<(String, Value<'s, 'v>)> {
+    let used = |consumption: Consumption, bags: &BTreeMap<Identifier<'s>, Vec<usize>>| {
+        bags.iter()
+            .flat_map(|(bag, positions)| {
+                let component = format!("&{bag}.{}", self::consumption(&consumption));
+                positions.iter().filter_map(move |p| {
+                    let value = bag_bundle.bags.get(bag)?.iter().nth(*p)?;
+                    Some((component.clone(), value.as_ref().clone()))
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+
+    used(Consumption::Take, &changeset.deletions)
+        .into_iter()
+        .chain(used(Consumption::Test, &changeset.touches))
+        .chain(changeset.insertions.iter().flat_map(|(bag, values)| {
+            values.iter().map(move |v| (format!("&{bag}.produce"), v.clone()))
+        }))
+        .collect()
+}
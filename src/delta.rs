@@ -0,0 +1,87 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::env::{Environment, EvalError};
+use crate::value::{Closure, Value};
+
+// The difference between the values of two bags, as computed by `.delta`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Delta<'s, 'v> {
+    pub added: Vec<Value<'s, 'v>>,
+    pub removed: Vec<Value<'s, 'v>>,
+    // The key and the values before and after, of values present in both
+    // bags under the same key but differing otherwise.
+    pub changed: Vec<(Value<'s, 'v>, Value<'s, 'v>, Value<'s, 'v>)>,
+}
+
+// Without a key the bags are compared as multisets, a value occurring more
+// often in `after` counts as added once per additional copy. With a key,
+// values of the same key are paired in the order they occur in the bags.
+pub fn delta<'s, 'v>(
+    before: &[Value<'s, 'v>],
+    after: &[Value<'s, 'v>],
+    key: Option<&Closure<'s, 'v>>,
+) -> Result<Delta<'s, 'v>, EvalError> {
+    let key_of = |value: &Value<'s, 'v>| match key {
+        Some(closure) => Environment::apply(closure, value),
+        None => Ok(value.clone()),
+    };
+
+    let mut remaining = BTreeMap::<Value, Vec<&Value>>::new();
+    for value in after {
+        remaining.entry(key_of(value)?).or_default().push(value);
+    }
+
+    let mut delta = Delta::default();
+    let mut matched = BTreeMap::<Value, usize>::new();
+    for value in before {
+        let key = key_of(value)?;
+        let position = matched.entry(key.clone()).or_default();
+        match remaining.get(&key).and_then(|values| values.get(*position)) {
+            Some(other) => {
+                if *other != value {
+                    delta.changed.push((key, value.clone(), (*other).clone()));
+                }
+                *position += 1;
+            }
+            None => delta.removed.push(value.clone()),
+        }
+    }
+
+    for value in after {
+        let key = key_of(value)?;
+        match matched.get_mut(&key) {
+            Some(position) if *position > 0 => *position -= 1,
+            _ => delta.added.push(value.clone()),
+        }
+    }
+
+    Ok(delta)
+}
+
+impl<'s, 'v> Delta<'s, 'v> {
+    // An object of the form `{added: [...], removed: [...], changed: [...]}`
+    // with each change being an object `{key, before, after}`.
+    pub fn to_value(&self) -> Value<'s, 'v> {
+        let array = |values: &[Value<'s, 'v>]| {
+            Cow::Owned(Value::Array(values.iter().cloned().map(Cow::Owned).collect()))
+        };
+        let changed = self
+            .changed
+            .iter()
+            .map(|(key, before, after)| {
+                Cow::Owned(Value::Object(BTreeMap::from([
+                    (Cow::Borrowed("key"), Cow::Owned(key.clone())),
+                    (Cow::Borrowed("before"), Cow::Owned(before.clone())),
+                    (Cow::Borrowed("after"), Cow::Owned(after.clone())),
+                ])))
+            })
+            .collect();
+
+        Value::Object(BTreeMap::from([
+            (Cow::Borrowed("added"), array(&self.added)),
+            (Cow::Borrowed("removed"), array(&self.removed)),
+            (Cow::Borrowed("changed"), Cow::Owned(Value::Array(changed))),
+        ]))
+    }
+}
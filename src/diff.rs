@@ -0,0 +1,99 @@
+use std::borrow::Cow;
+
+use crate::value::Value;
+
+// One difference between two values. The path leads from the compared values
+// to the differing part, eg `.tags[1]`, and is empty if the values differ as
+// a whole. A part only present on one side has no value on the other.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change<'s, 'v> {
+    pub path: String,
+    pub before: Option<Value<'s, 'v>>,
+    pub after: Option<Value<'s, 'v>>,
+}
+
+// Objects are compared property by property and arrays item by item, so a
+// changed field shows up as a single change instead of two whole values.
+pub fn diff<'s, 'v>(before: &Value<'s, 'v>, after: &Value<'s, 'v>) -> Vec<Change<'s, 'v>> {
+    let mut changes = vec![];
+    collect(String::new(), before, after, &mut changes);
+    changes
+}
+
+fn collect<'s, 'v>(
+    path: String,
+    before: &Value<'s, 'v>,
+    after: &Value<'s, 'v>,
+    changes: &mut Vec<Change<'s, 'v>>,
+) {
+    match (before, after) {
+        _ if before == after => {}
+        (Value::Object(b), Value::Object(a)) => {
+            let mut keys = b.keys().chain(a.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = format!("{path}.{key}");
+                match (b.get(key), a.get(key)) {
+                    (Some(b), Some(a)) => collect(path, b, a, changes),
+                    (b, a) => changes.push(Change {
+                        path,
+                        before: b.map(|v| v.as_ref().clone()),
+                        after: a.map(|v| v.as_ref().clone()),
+                    }),
+                }
+            }
+        }
+        (Value::Array(b), Value::Array(a)) => {
+            for i in 0..b.len().max(a.len()) {
+                let path = format!("{path}[{i}]");
+                match (b.get(i), a.get(i)) {
+                    (Some(b), Some(a)) => collect(path, b, a, changes),
+                    (b, a) => changes.push(Change {
+                        path,
+                        before: b.map(|v| v.as_ref().clone()),
+                        after: a.map(|v| v.as_ref().clone()),
+                    }),
+                }
+            }
+        }
+        _ => changes.push(Change {
+            path,
+            before: Some(before.clone()),
+            after: Some(after.clone()),
+        }),
+    }
+}
+
+impl<'s, 'v> Change<'s, 'v> {
+    // The value of the `diff` builtin: an object with the path and the
+    // values present on either side.
+    pub fn to_value(&self) -> Value<'s, 'v> {
+        let mut object = std::collections::BTreeMap::new();
+        object.insert(
+            Cow::Borrowed("path"),
+            Cow::Owned(Value::String(Cow::Owned(self.path.clone()))),
+        );
+        if let Some(before) = &self.before {
+            object.insert(Cow::Borrowed("before"), Cow::Owned(before.clone()));
+        }
+        if let Some(after) = &self.after {
+            object.insert(Cow::Borrowed("after"), Cow::Owned(after.clone()));
+        }
+        Value::Object(object)
+    }
+
+    // The lines of a unified diff, without the trailing newline.
+    pub fn lines(&self) -> Vec<String> {
+        let line = |sign, value: &Value| match self.path.as_str() {
+            "" => format!("{sign} {value}"),
+            path => format!("{sign} {path}: {value}"),
+        };
+
+        self.before
+            .iter()
+            .map(|v| line('-', v))
+            .chain(self.after.iter().map(|v| line('+', v)))
+            .collect()
+    }
+}
@@ -0,0 +1,169 @@
+use crate::statement::DataFormat;
+use crate::value::Value;
+
+#[derive(Debug)]
+pub enum DocumentError {
+    Malformed,
+    UnsupportedValue,
+    FeatureDisabled,
+}
+
+// The cargo feature a document format needs, if it is not enabled in this
+// build.
+pub fn missing_feature(format: DataFormat) -> Option<&'static str> {
+    match format {
+        DataFormat::Yaml if cfg!(not(feature = "yaml")) => Some("yaml"),
+        DataFormat::Toml if cfg!(not(feature = "toml")) => Some("toml"),
+        DataFormat::Xml if cfg!(not(feature = "xml")) => Some("xml"),
+        _ => None,
+    }
+}
+
+// Converts a YAML, TOML or XML document into the values to be inserted.
+pub fn read<'s, 'v>(format: DataFormat, input: &str) -> Result<Vec<Value<'s, 'v>>, DocumentError> {
+    match format {
+        #[cfg(feature = "yaml")]
+        DataFormat::Yaml => from_yaml(input),
+        #[cfg(feature = "toml")]
+        DataFormat::Toml => from_toml(input),
+        #[cfg(feature = "xml")]
+        DataFormat::Xml => from_xml(input),
+        _ => {
+            let _ = input;
+            Err(DocumentError::FeatureDisabled)
+        }
+    }
+}
+
+// Like `.load_json`, a document holding a sequence is inserted item by item.
+// Each document of a YAML stream is read on its own, empty documents are
+// skipped.
+#[cfg(feature = "yaml")]
+fn from_yaml<'s, 'v>(input: &str) -> Result<Vec<Value<'s, 'v>>, DocumentError> {
+    use serde::Deserialize;
+
+    let mut values = vec![];
+    for document in serde_yaml::Deserializer::from_str(input) {
+        let json = serde_json::Value::deserialize(document).map_err(|_| DocumentError::Malformed)?;
+        let items = match json {
+            serde_json::Value::Null => continue,
+            serde_json::Value::Array(items) => items,
+            json => vec![json],
+        };
+        for item in &items {
+            values.push(crate::json::from_json(item).ok_or(DocumentError::UnsupportedValue)?);
+        }
+    }
+
+    Ok(values)
+}
+
+// A TOML document is always a table and becomes a single object.
+#[cfg(feature = "toml")]
+fn from_toml<'s, 'v>(input: &str) -> Result<Vec<Value<'s, 'v>>, DocumentError> {
+    let table = input
+        .parse::<toml::Table>()
+        .map_err(|_| DocumentError::Malformed)?;
+
+    Ok(vec![toml_value(&toml::Value::Table(table))?])
+}
+
+// Floats are rejected just like in JSON. Local dates and times that are no
+// full datetime are kept as strings.
+#[cfg(feature = "toml")]
+fn toml_value<'s, 'v>(value: &toml::Value) -> Result<Value<'s, 'v>, DocumentError> {
+    use std::borrow::Cow;
+
+    Ok(match value {
+        toml::Value::String(s) => Value::String(Cow::Owned(s.clone())),
+        toml::Value::Integer(i) => Value::Integer(*i),
+        toml::Value::Float(_) => return Err(DocumentError::UnsupportedValue),
+        toml::Value::Boolean(b) => Value::Boolean(*b),
+        toml::Value::Datetime(d) => {
+            let text = d.to_string();
+            match d.date {
+                Some(_) => Value::parse_datetime(&text),
+                None => None,
+            }
+            .unwrap_or(Value::String(Cow::Owned(text)))
+        }
+        toml::Value::Array(a) => Value::Array(
+            a.iter()
+                .map(|v| toml_value(v).map(Cow::Owned))
+                .collect::<Result<_, _>>()?,
+        ),
+        toml::Value::Table(t) => Value::Object(
+            t.iter()
+                .map(|(k, v)| Ok((Cow::Owned(k.clone()), Cow::Owned(toml_value(v)?))))
+                .collect::<Result<_, _>>()?,
+        ),
+    })
+}
+
+// The root element is taken as container: each of its child elements becomes
+// one value.
+#[cfg(feature = "xml")]
+fn from_xml<'s, 'v>(input: &str) -> Result<Vec<Value<'s, 'v>>, DocumentError> {
+    let document = roxmltree::Document::parse(input).map_err(|_| DocumentError::Malformed)?;
+
+    Ok(document
+        .root_element()
+        .children()
+        .filter(|n| n.is_element())
+        .map(xml_value)
+        .collect())
+}
+
+// An element without attributes and child elements becomes the string of its
+// text. Otherwise it becomes an object of its attributes and child elements,
+// child elements with the same name are collected into an array and the text
+// is kept as `_text`. XML has no types, so all leaves are strings.
+#[cfg(feature = "xml")]
+fn xml_value<'s, 'v>(node: roxmltree::Node) -> Value<'s, 'v> {
+    use std::borrow::Cow;
+    use std::collections::BTreeMap;
+
+    let text = node
+        .children()
+        .filter_map(|n| n.is_text().then(|| n.text()).flatten())
+        .collect::<String>();
+    let text = text.trim();
+    let mut elements = node.children().filter(|n| n.is_element()).peekable();
+    if node.attributes().len() == 0 && elements.peek().is_none() {
+        return Value::String(Cow::Owned(text.to_string()));
+    }
+
+    let mut properties = BTreeMap::<String, Vec<Value>>::new();
+    for attribute in node.attributes() {
+        properties
+            .entry(attribute.name().to_string())
+            .or_default()
+            .push(Value::String(Cow::Owned(attribute.value().to_string())));
+    }
+    for element in elements {
+        properties
+            .entry(element.tag_name().name().to_string())
+            .or_default()
+            .push(xml_value(element));
+    }
+    if !text.is_empty() {
+        properties
+            .entry("_text".to_string())
+            .or_default()
+            .push(Value::String(Cow::Owned(text.to_string())));
+    }
+
+    Value::Object(
+        properties
+            .into_iter()
+            .map(|(name, mut values)| {
+                let value = if values.len() == 1 {
+                    values.remove(0)
+                } else {
+                    Value::Array(values.into_iter().map(Cow::Owned).collect())
+                };
+                (Cow::Owned(name), Cow::Owned(value))
+            })
+            .collect(),
+    )
+}
@@ -1,15 +1,62 @@
-use std::{borrow::Cow, collections::BTreeMap};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashSet},
+};
+
+use num_bigint::{BigInt, Sign};
+use unicode_segmentation::UnicodeSegmentation;
 
 use crate::{
+    builtins::{self, Lookup},
+    collation::Collation,
+    diff,
     expression::*,
+    formatter,
     identifier::Identifier,
     literal::Literal,
-    value::{Value, ValueType},
+    matcher::Matcher,
+    memo::{Memo, MemoKey},
+    regex_cache,
+    value::{Closure, QuotedExpression, QuotedPattern, Value, ValueType},
 };
 
+const MAX_EVAL_DEPTH: usize = 16;
+const MAX_INTEGER_BITS: u64 = 1 << 16;
+
+thread_local! {
+    static EVAL_DEPTH: Cell<usize> = Cell::new(0);
+    static LENIENT: Cell<bool> = const { Cell::new(false) };
+    static UNKNOWN: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+    static FAILED_ASSERTIONS: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// In lenient mode unknown identifiers evaluate to `null` instead of failing.
+// Their names are collected until taken, so the caller can warn about them.
+pub fn set_lenient(lenient: bool) {
+    LENIENT.with(|l| l.set(lenient));
+}
+
+pub fn take_unknown_identifiers() -> Vec<String> {
+    UNKNOWN.with(|unknown| unknown.take().into_iter().collect())
+}
+
+// The messages of the `assert` calls failed since last taken. Guards treat
+// an error like `false`, so the message is kept even if the error is not
+// reported.
+pub fn take_failed_assertions() -> Vec<String> {
+    FAILED_ASSERTIONS.with(|failed| failed.take().into_iter().collect())
+}
+
 #[derive(Clone, Debug)]
 pub struct Environment<'i, 's, 'v> {
     pub bindings: BTreeMap<Identifier<'i>, Value<'s, 'v>>,
+    pub memo: Option<Memo<'s, 'v>>,
+    // While a query matches values of a counted bag, the matched values
+    // along with how often they are stored, for `count_of`. Outside of
+    // counted bags every value is stored once.
+    pub(crate) multiplicities: Option<Vec<(Value<'s, 'v>, usize)>>,
 }
 
 #[derive(Debug)]
@@ -23,6 +70,15 @@ pub enum EvalError {
     OutOfBound,
     Overflow,
     UnknownFunction,
+    ArgumentMismatch,
+    InvalidRegex,
+    OutOfFuel,
+    BackendFailed,
+    InvalidDatetime,
+    InvalidCollation,
+    // The message of the failed `assert`.
+    AssertionFailed(String),
+    FunctionFailed,
 }
 
 impl<'i, 's, 'v> Environment<'i, 's, 'v> {
@@ -30,6 +86,29 @@ impl<'i, 's, 'v> Environment<'i, 's, 'v> {
         self.bindings.clear();
     }
 
+    // Removes a single binding, returns false if there was none.
+    pub fn unset(&mut self, name: &Identifier) -> bool {
+        let count = self.bindings.len();
+        self.bindings.retain(|bound, _| bound.name != name.name);
+        self.bindings.len() != count
+    }
+
+    // The bound names along with the types of their values, ordered by name.
+    pub fn variables(&self) -> Vec<(&Identifier<'i>, ValueType)> {
+        self.bindings
+            .iter()
+            .map(|(name, value)| (name, value.get_type()))
+            .collect()
+    }
+
+    // Evaluates the `where` clause of a statement, it has to be a boolean.
+    pub(crate) fn eval_guard<'x>(&self, expression: &'x Expression<'x>) -> Result<bool, EvalError> {
+        match self.eval_expr(expression)? {
+            Value::Boolean(b) => Ok(b),
+            _ => Err(EvalError::TypeError),
+        }
+    }
+
     pub fn eval_expr<'x>(
         &self,
         expression: &'x Expression<'x>,
@@ -63,23 +142,110 @@ impl<'i, 's, 'v> Environment<'i, 's, 'v> {
             }) => self
                 .eval_expr(argument)
                 .and_then(|v| self.eval_unary(operator, &v)),
-            Expression::Call(CallExpression { function, argument }) => {
-                self.eval_call(function, &self.eval_expr(argument)?)
+            Expression::Call(CallExpression { function, arguments }) => {
+                let arguments = arguments
+                    .iter()
+                    .map(|a| self.eval_expr(a))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.eval_call_memoized(function, arguments)
+            }
+            Expression::Pattern(pattern) => {
+                Ok(Value::Pattern(QuotedPattern(Box::new(pattern.deep_clone()))))
+            }
+            Expression::Quote(quoted) => {
+                Ok(Value::Expression(QuotedExpression(Box::new(quoted.deep_clone()))))
+            }
+            Expression::Template(template) => self.eval_template_memoized(template),
+            Expression::Collate(inner, collation) => self.eval_collated(inner, collation),
+            Expression::Lambda(LambdaExpression { parameter, body }) => {
+                let captured = expression
+                    .get_identifiers()
+                    .chain(body.get_callees())
+                    .filter_map(|id| Some((id.deep_clone(), self.bindings.get(id)?.clone())))
+                    .collect();
+
+                Ok(Value::Closure(Box::new(Closure {
+                    parameter: parameter.deep_clone(),
+                    body: body.deep_clone(),
+                    captured,
+                })))
+            }
+            Expression::Let(LetExpression { pattern, value, body }) => {
+                let value = self.eval_expr(value)?;
+                let pattern = pattern.deep_clone();
+                let mut matcher = Matcher::new(self);
+
+                if matcher.match_pattern(&pattern, &value).is_err() {
+                    return Err(EvalError::ArgumentMismatch);
+                }
+
+                let mut local = self.clone();
+                matcher.into_env().merge(&mut local);
+                local.eval_expr(body)
             }
-            Expression::Template(template) => self.eval_template(template),
         }
     }
 
+    fn eval_call_memoized(
+        &self,
+        function: &Identifier,
+        arguments: Vec<Value<'s, 'v>>,
+    ) -> Result<Value<'s, 'v>, EvalError> {
+        // The functions of the application may not be pure.
+        let Some(memo) = self.memo.as_ref().filter(|_| {
+            !self.bindings.contains_key(function)
+                && matches!(builtins::lookup(&function.name), Lookup::Builtin)
+                && builtins::is_pure(&function.name, &arguments)
+        }) else {
+            return self.eval_call(function, &arguments);
+        };
+
+        let key = MemoKey::Call(function.name.to_string(), arguments.clone());
+        if let Some(v) = memo.get(&key) {
+            return Ok(v);
+        }
+
+        let result = self.eval_call(function, &arguments)?;
+        memo.put(key, result.clone());
+        Ok(result)
+    }
+
+    fn eval_template_memoized<'x>(
+        &self,
+        template: &'x StringTemplate<'x>,
+    ) -> Result<Value<'s, 'v>, EvalError> {
+        let Some(memo) = &self.memo else {
+            return self.eval_template(template);
+        };
+
+        let Some(inputs) = template
+            .parts
+            .iter()
+            .flat_map(|p| p.dynamic_end.get_identifiers())
+            .map(|id| self.bindings.get(id).cloned())
+            .collect::<Option<Vec<_>>>() else {
+            return self.eval_template(template);
+        };
+
+        let key = MemoKey::Template(formatter::template(template), inputs);
+        if let Some(v) = memo.get(&key) {
+            return Ok(v);
+        }
+
+        let result = self.eval_template(template)?;
+        memo.put(key, result.clone());
+        Ok(result)
+    }
+
     fn eval_lit<'x>(&self, literal: &'x Literal<'x>) -> Result<Value<'s, 'v>, EvalError> {
         match literal {
             Literal::Null => Ok(Value::Null),
             Literal::String(s) => Ok(Value::<'s, 'v>::String(Cow::Owned(s.to_string()))),
-            Literal::Number(s) => str::parse::<i64>(s)
-                .map(Value::Integer)
-                .map(Ok)
-                .unwrap_or(Err(EvalError::InvalidNumber)),
+            Literal::Number(s) => Value::parse_integer(s).ok_or(EvalError::InvalidNumber),
             Literal::Boolean(b) => Ok(Value::Boolean(*b)),
             Literal::Type(t) => Ok(Value::Type(*t)),
+            Literal::Regex(r) => Ok(Value::String(Cow::Owned(r.to_string()))),
+            Literal::DateTime(t) => Value::parse_datetime(t).ok_or(EvalError::InvalidDatetime),
         }
     }
 
@@ -92,105 +258,40 @@ impl<'i, 's, 'v> Environment<'i, 's, 'v> {
         match op {
             BinaryOperator::StrictEqual => Ok(Value::Boolean(left == right)),
             BinaryOperator::StrictNotEqual => Ok(Value::Boolean(left != right)),
-            BinaryOperator::LessThan => {
-                let Value::Integer(l) = left else {
-                    return Err(EvalError::TypeError);
-                };
-                let Value::Integer(r) = right else {
-                    return Err(EvalError::TypeError);
-                };
-                Ok(Value::Boolean(l < r))
-            }
+            BinaryOperator::LessThan => compare_ordered(left, right).map(|o| Value::Boolean(o.is_lt())),
             BinaryOperator::GreaterThan => {
-                let Value::Integer(l) = left else {
-                    return Err(EvalError::TypeError);
-                };
-                let Value::Integer(r) = right else {
-                    return Err(EvalError::TypeError);
-                };
-                Ok(Value::Boolean(l > r))
+                compare_ordered(left, right).map(|o| Value::Boolean(o.is_gt()))
             }
             BinaryOperator::LessThanEqual => {
-                let Value::Integer(l) = left else {
-                    return Err(EvalError::TypeError);
-                };
-                let Value::Integer(r) = right else {
-                    return Err(EvalError::TypeError);
-                };
-                Ok(Value::Boolean(l <= r))
+                compare_ordered(left, right).map(|o| Value::Boolean(o.is_le()))
             }
             BinaryOperator::GreaterThanEqual => {
-                let Value::Integer(l) = left else {
-                    return Err(EvalError::TypeError);
-                };
-                let Value::Integer(r) = right else {
-                    return Err(EvalError::TypeError);
-                };
-                Ok(Value::Boolean(l >= r))
-            }
-            BinaryOperator::Plus => {
-                let Value::Integer(l) = left else {
-                    return Err(EvalError::TypeError);
-                };
-                let Value::Integer(r) = right else {
-                    return Err(EvalError::TypeError);
-                };
-                l.checked_add(*r)
-                    .map(Value::Integer)
-                    .map(Ok)
-                    .unwrap_or(Err(EvalError::Overflow))
-            }
-            BinaryOperator::Minus => {
-                let Value::Integer(l) = left else {
-                    return Err(EvalError::TypeError);
-                };
-                let Value::Integer(r) = right else {
-                    return Err(EvalError::TypeError);
-                };
-                l.checked_sub(*r)
-                    .map(Value::Integer)
-                    .map(Ok)
-                    .unwrap_or(Err(EvalError::Overflow))
-            }
-            BinaryOperator::Times => {
-                let Value::Integer(l) = left else {
-                    return Err(EvalError::TypeError);
-                };
-                let Value::Integer(r) = right else {
-                    return Err(EvalError::TypeError);
-                };
-                l.checked_mul(*r)
-                    .map(Value::Integer)
-                    .map(Ok)
-                    .unwrap_or(Err(EvalError::Overflow))
+                compare_ordered(left, right).map(|o| Value::Boolean(o.is_ge()))
             }
-            BinaryOperator::Over => {
-                let Value::Integer(l) = left else {
-                    return Err(EvalError::TypeError);
-                };
-                let Value::Integer(r) = right else {
-                    return Err(EvalError::TypeError);
-                };
-                if *r == 0 {
+            BinaryOperator::Plus => match (left, right) {
+                (Value::DateTime(t), d) | (d, Value::DateTime(t)) => shift_datetime(*t, d, 1),
+                _ => integer_operation(left, right, i64::checked_add, |l, r| Ok(l + r)),
+            },
+            BinaryOperator::Minus => match (left, right) {
+                (Value::DateTime(l), Value::DateTime(r)) => {
+                    l.checked_sub(*r).map(Value::Integer).ok_or(EvalError::Overflow)
+                }
+                (Value::DateTime(t), d) => shift_datetime(*t, d, -1),
+                _ => integer_operation(left, right, i64::checked_sub, |l, r| Ok(l - r)),
+            },
+            BinaryOperator::Times => integer_operation(left, right, i64::checked_mul, |l, r| Ok(l * r)),
+            BinaryOperator::Over => integer_operation(left, right, i64::checked_div, |l, r| {
+                if r.sign() == Sign::NoSign {
                     return Err(EvalError::MathDivision);
                 }
-                l.checked_div(*r)
-                    .map(Value::Integer)
-                    .map(Ok)
-                    .unwrap_or(Err(EvalError::Overflow))
-            }
-            BinaryOperator::Mod => {
-                let Value::Integer(l) = left else {
-                    return Err(EvalError::TypeError);
-                };
-                let Value::Integer(r) = right else {
-                    return Err(EvalError::TypeError);
-                };
-                l.checked_rem(*r)
-                    .map(Value::Integer)
-                    .map(Ok)
-                    .unwrap_or(Err(EvalError::Overflow))
-            }
+                Ok(l / r)
+            }),
+            BinaryOperator::Mod => integer_operation(left, right, i64::checked_rem, |l, r| {
+                if r.sign() == Sign::NoSign {
+                    return Err(EvalError::MathDivision);
+                }
+                Ok(l % r)
+            }),
             BinaryOperator::In => {
                 let Value::String(s) = left else {
                     return Err(EvalError::TypeError);
@@ -200,18 +301,20 @@ impl<'i, 's, 'v> Environment<'i, 's, 'v> {
                 };
                 Ok(Value::Boolean(o.contains_key(s)))
             }
-            BinaryOperator::PowerOf => {
-                let Value::Integer(l) = left else {
-                    return Err(EvalError::TypeError);
-                };
-                let Value::Integer(r) = right else {
-                    return Err(EvalError::TypeError);
-                };
-                l.checked_pow(*r as u32)
-                    .map(Value::Integer)
-                    .map(Ok)
-                    .unwrap_or(Err(EvalError::Overflow))
-            }
+            BinaryOperator::PowerOf => integer_operation(
+                left,
+                right,
+                |l, r| u32::try_from(r).ok().and_then(|e| l.checked_pow(e)),
+                |l, r| {
+                    let Ok(exponent) = u32::try_from(&r) else {
+                        return Err(EvalError::Overflow);
+                    };
+                    if (l.bits().saturating_sub(1)).saturating_mul(exponent as u64) > MAX_INTEGER_BITS {
+                        return Err(EvalError::Overflow);
+                    }
+                    Ok(l.pow(exponent))
+                },
+            ),
             BinaryOperator::Is => {
                 let Value::Type(specified_type) = right else {
                     return Err(EvalError::KindError);
@@ -231,23 +334,40 @@ impl<'i, 's, 'v> Environment<'i, 's, 'v> {
 
                 Ok(v)
             }
+            BinaryOperator::Matches => match right {
+                Value::Pattern(QuotedPattern(pattern)) => {
+                    let mut matcher = Matcher::new(self);
+                    Ok(Value::Boolean(matcher.match_pattern(pattern, left).is_ok()))
+                }
+                Value::String(source) => {
+                    let Value::String(s) = left else {
+                        return Err(EvalError::TypeError);
+                    };
+                    let Some(regex) = regex_cache::compile(source) else {
+                        return Err(EvalError::InvalidRegex);
+                    };
+                    Ok(Value::Boolean(regex.is_match(s)))
+                }
+                _ => Err(EvalError::TypeError),
+            },
         }
     }
 
     fn eval_unary(&self, op: &UnaryOperator, arg: &Value) -> Result<Value<'s, 'v>, EvalError> {
         match op {
-            UnaryOperator::Minus => {
-                let Value::Integer(v) = arg else {
-                    return Err(EvalError::TypeError);
-                };
-                Ok(Value::Integer(-v))
-            }
-            UnaryOperator::Plus => {
-                let Value::Integer(v) = arg else {
-                    return Err(EvalError::TypeError);
-                };
-                Ok(Value::Integer(*v))
-            }
+            UnaryOperator::Minus => match arg {
+                Value::Integer(v) => Ok(v
+                    .checked_neg()
+                    .map(Value::Integer)
+                    .unwrap_or_else(|| Value::from_bigint(-BigInt::from(*v)))),
+                Value::BigInt(v) => Ok(Value::from_bigint(-v)),
+                _ => Err(EvalError::TypeError),
+            },
+            UnaryOperator::Plus => match arg {
+                Value::Integer(v) => Ok(Value::Integer(*v)),
+                Value::BigInt(v) => Ok(Value::BigInt(v.clone())),
+                _ => Err(EvalError::TypeError),
+            },
             UnaryOperator::Not => {
                 let Value::Boolean(b) = arg else {
                     return Err(EvalError::TypeError);
@@ -383,24 +503,60 @@ impl<'i, 's, 'v> Environment<'i, 's, 'v> {
                 let Value::Integer(i) = prop else {
                     return Err(EvalError::TypeError);
                 };
+                // Strings are indexed by graphemes, so an accented letter
+                // made of several code points is a single item.
+                let graphemes = s.graphemes(true).collect::<Vec<_>>();
                 let index = if *i < 0 {
-                    s.len() - i.unsigned_abs() as usize
+                    graphemes.len().checked_sub(i.unsigned_abs() as usize)
                 } else {
-                    *i as usize
+                    Some(*i as usize)
                 };
 
-                let Some(val) = s.chars().nth(index).map(|v|v.clone().to_string()) else {
+                let Some(val) = index.and_then(|i| graphemes.get(i)) else {
                     return Err(EvalError::OutOfBound);
                 };
 
-                Ok(Value::String(Cow::Owned(val)))
+                Ok(Value::String(Cow::Owned(val.to_string())))
             }
             _ => Err(EvalError::TypeError),
         }
     }
 
+    fn eval_collated(
+        &self,
+        inner: &Expression,
+        collation: &Collation,
+    ) -> Result<Value<'s, 'v>, EvalError> {
+        let Expression::Binary(BinaryExpression { operator, left, right }) = inner else {
+            return self.eval_expr(inner);
+        };
+        let left = self.eval_expr(left)?;
+        let right = self.eval_expr(right)?;
+
+        let ordering = match (collation, &left, &right) {
+            (Collation::Locale(_), Value::String(_), Value::String(_)) => {
+                collation.compare(&left, &right)?
+            }
+            _ => return self.eval_binary(operator, &collation.key(&left), &collation.key(&right)),
+        };
+
+        Ok(Value::Boolean(match operator {
+            BinaryOperator::StrictEqual => ordering.is_eq(),
+            BinaryOperator::StrictNotEqual => ordering.is_ne(),
+            BinaryOperator::LessThan => ordering.is_lt(),
+            BinaryOperator::GreaterThan => ordering.is_gt(),
+            BinaryOperator::LessThanEqual => ordering.is_le(),
+            BinaryOperator::GreaterThanEqual => ordering.is_ge(),
+            _ => return self.eval_binary(operator, &left, &right),
+        }))
+    }
+
     fn eval_identifier(&self, id: &Identifier) -> Result<Value<'s, 'v>, EvalError> {
         let Some(val) = self.bindings.get(id) else {
+            if LENIENT.with(|l| l.get()) {
+                UNKNOWN.with(|unknown| unknown.borrow_mut().insert(id.to_string()));
+                return Ok(Value::Null);
+            }
             return Err(EvalError::UnknownIdentifier);
         };
 
@@ -410,31 +566,165 @@ impl<'i, 's, 'v> Environment<'i, 's, 'v> {
     fn eval_call(
         &self,
         function: &Identifier,
-        argument: &Value<'s, 'v>,
+        arguments: &[Value<'s, 'v>],
     ) -> Result<Value<'s, 'v>, EvalError> {
-        Ok(match function.name.as_ref() {
-            "length" => Value::Integer(match argument {
-                Value::String(s) => s.len() as i64,
+        if let Some(Value::Closure(closure)) = self.bindings.get(function) {
+            let [argument] = arguments else {
+                return Err(EvalError::ArgumentMismatch);
+            };
+            return Self::apply(closure, argument);
+        }
+        match builtins::lookup(&function.name) {
+            Lookup::External(f) => return f(arguments),
+            Lookup::Unknown => return Err(EvalError::UnknownFunction),
+            Lookup::Builtin => {}
+        }
+
+        Ok(match (function.name.as_ref(), arguments) {
+            ("length", [argument]) => Value::Integer(match argument {
+                Value::String(s) => s.graphemes(true).count() as i64,
                 Value::Array(a) => a.len() as i64,
                 Value::Object(o) => o.len() as i64,
                 _ => return Err(EvalError::TypeError),
             }),
-            "keys" => Value::Array(match argument {
+            ("keys", [argument]) => Value::Array(match argument {
                 Value::Object(o) => o
                     .keys()
                     .map(|k| Cow::Owned(Value::String(Cow::Owned(k.to_string()))))
                     .collect(),
                 _ => return Err(EvalError::TypeError),
             }),
-            "values" => Value::Array(match argument {
+            ("values", [argument]) => Value::Array(match argument {
                 Value::Object(o) => o.values().cloned().collect(),
                 _ => return Err(EvalError::TypeError),
             }),
-            "type" => Value::Type(argument.get_type()),
+            ("type", [argument]) => Value::Type(argument.get_type()),
+            ("matches", [value, pattern]) => {
+                let Value::Pattern(QuotedPattern(pattern)) = pattern else {
+                    return Err(EvalError::TypeError);
+                };
+                let mut matcher = Matcher::new(self);
+                Value::Boolean(matcher.match_pattern(pattern, value).is_ok())
+            }
+            ("eval", [Value::Expression(QuotedExpression(quoted))]) => self.eval_quoted(quoted)?,
+            ("eval", [_]) => return Err(EvalError::TypeError),
+            ("diff", [before, after]) => Value::Array(
+                diff::diff(before, after)
+                    .iter()
+                    .map(|c| Cow::Owned(c.to_value()))
+                    .collect(),
+            ),
+            ("map", [Value::Array(a), Value::Closure(f)]) => Value::Array(
+                a.iter()
+                    .map(|v| Self::apply(f, v).map(Cow::Owned))
+                    .collect::<Result<_, _>>()?,
+            ),
+            ("filter", [Value::Array(a), Value::Closure(f)]) => {
+                let mut result = vec![];
+                for v in a {
+                    match Self::apply(f, v)? {
+                        Value::Boolean(true) => result.push(v.clone()),
+                        Value::Boolean(false) => {}
+                        _ => return Err(EvalError::TypeError),
+                    }
+                }
+                Value::Array(result)
+            }
+            // The function is called with the pair [accumulator, item].
+            ("reduce", [Value::Array(a), Value::Closure(f), initial]) => {
+                a.iter().try_fold(initial.clone(), |accumulator, v| {
+                    Self::apply(f, &Value::Array(vec![Cow::Owned(accumulator), v.clone()]))
+                })?
+            }
+            ("sort", [Value::Array(a)]) => {
+                let mut sorted = a.clone();
+                sorted.sort();
+                Value::Array(sorted)
+            }
+            // Sorts by the keys the function computes, items with equal keys
+            // keep their order.
+            ("sort", [Value::Array(a), Value::Closure(f)]) => {
+                let mut keyed = a
+                    .iter()
+                    .map(|v| Ok((Self::apply(f, v)?, v.clone())))
+                    .collect::<Result<Vec<_>, _>>()?;
+                keyed.sort_by(|(l, _), (r, _)| l.cmp(r));
+                Value::Array(keyed.into_iter().map(|(_, v)| v).collect())
+            }
+            ("unique", [Value::Array(a)]) => {
+                let mut seen = BTreeSet::new();
+                Value::Array(a.iter().filter(|v| seen.insert(*v)).cloned().collect())
+            }
+            ("flatten", [Value::Array(a)]) => Value::Array(
+                a.iter()
+                    .flat_map(|v| match v.as_ref() {
+                        Value::Array(inner) => inner.clone(),
+                        _ => vec![v.clone()],
+                    })
+                    .collect(),
+            ),
+            ("count_of", [value]) => Value::Integer(self.multiplicity(value) as i64),
+            ("assert", [Value::Boolean(true), Value::String(_)]) => Value::Boolean(true),
+            ("assert", [Value::Boolean(false), Value::String(message)]) => {
+                FAILED_ASSERTIONS.with(|failed| failed.borrow_mut().insert(message.to_string()));
+                return Err(EvalError::AssertionFailed(message.to_string()));
+            }
+            ("map" | "filter" | "assert", [_, _])
+            | ("reduce", [_, _, _])
+            | ("sort", [_] | [_, _])
+            | ("unique" | "flatten", [_]) => return Err(EvalError::TypeError),
+            (
+                "length" | "keys" | "values" | "type" | "matches" | "eval" | "diff" | "map"
+                | "filter" | "reduce" | "sort" | "unique" | "flatten" | "assert" | "count_of",
+                _,
+            ) => return Err(EvalError::ArgumentMismatch),
             _ => return Err(EvalError::UnknownFunction),
         })
     }
 
+    fn eval_quoted(&self, quoted: &Expression<'s>) -> Result<Value<'s, 'v>, EvalError> {
+        let depth = EVAL_DEPTH.with(|d| d.replace(d.get() + 1));
+        let result = if depth < MAX_EVAL_DEPTH {
+            self.eval_expr(quoted)
+        } else {
+            Err(EvalError::OutOfFuel)
+        };
+        EVAL_DEPTH.with(|d| d.set(depth));
+
+        result
+    }
+
+    fn multiplicity(&self, value: &Value<'s, 'v>) -> usize {
+        match &self.multiplicities {
+            None => 1,
+            Some(multiplicities) => multiplicities
+                .iter()
+                .filter(|(v, _)| v == value)
+                .map(|(_, count)| count)
+                .sum(),
+        }
+    }
+
+    pub(crate) fn apply(
+        closure: &Closure<'s, 'v>,
+        argument: &Value<'s, 'v>,
+    ) -> Result<Value<'s, 'v>, EvalError> {
+        let env = Environment {
+            bindings: closure.captured.clone(),
+            memo: None,
+            multiplicities: None,
+        };
+        let mut matcher = Matcher::new(&env);
+
+        if matcher.match_pattern(&closure.parameter, argument).is_err() {
+            return Err(EvalError::ArgumentMismatch);
+        }
+
+        let mut local = env.clone();
+        matcher.into_env().merge(&mut local);
+        local.eval_expr(&closure.body)
+    }
+
     fn eval_template<'x>(
         &self,
         template: &'x StringTemplate<'x>,
@@ -464,6 +754,155 @@ impl<'i, 's, 'v> Environment<'i, 's, 'v> {
         self.bindings.keys().collect()
     }
 
+    pub fn specialize(
+        &self,
+        expression: &Expression<'s>,
+        shadowed: &HashSet<&Identifier>,
+    ) -> Expression<'s> {
+        let is_constant = expression
+            .get_identifiers()
+            .all(|id| !shadowed.contains(id) && self.bindings.contains_key(id))
+            && !expression
+                .get_callees()
+                .iter()
+                .any(|f| builtins::is_context_dependent(&f.name));
+
+        if is_constant {
+            return match self.eval_expr(expression) {
+                Ok(v) => v.to_expression(),
+                Err(_) => expression.clone(),
+            };
+        }
+
+        match expression {
+            Expression::Array(items) => Expression::Array(
+                items
+                    .iter()
+                    .map(|item| match item {
+                        ArrayItem::Single(e) => ArrayItem::Single(self.specialize(e, shadowed)),
+                        ArrayItem::Spread(e) => ArrayItem::Spread(self.specialize(e, shadowed)),
+                    })
+                    .collect(),
+            ),
+            Expression::Binary(BinaryExpression {
+                operator,
+                left,
+                right,
+            }) => Expression::Binary(BinaryExpression {
+                operator: *operator,
+                left: Box::new(self.specialize(left, shadowed)),
+                right: Box::new(self.specialize(right, shadowed)),
+            }),
+            Expression::Identifier(_) | Expression::Literal(_) => expression.clone(),
+            Expression::Logical(LogicalExpression {
+                operator,
+                left,
+                right,
+            }) => Expression::Logical(LogicalExpression {
+                operator: *operator,
+                left: Box::new(self.specialize(left, shadowed)),
+                right: Box::new(self.specialize(right, shadowed)),
+            }),
+            Expression::Member(MemberExpression { object, property }) => {
+                Expression::Member(MemberExpression {
+                    object: Box::new(self.specialize(object, shadowed)),
+                    property: Box::new(self.specialize(property, shadowed)),
+                })
+            }
+            Expression::Object(props) => Expression::Object(
+                props
+                    .iter()
+                    .map(|prop| match prop {
+                        ObjectProperty::Single(id) => {
+                            match self.bindings.get(id).filter(|_| !shadowed.contains(id)) {
+                                Some(v) => ObjectProperty::Property(Property {
+                                    key: PropertyKey::Identifier(id.clone()),
+                                    value: v.to_expression(),
+                                }),
+                                None => ObjectProperty::Single(id.clone()),
+                            }
+                        }
+                        ObjectProperty::Property(Property { key, value }) => {
+                            ObjectProperty::Property(Property {
+                                key: match key {
+                                    PropertyKey::Identifier(id) => {
+                                        PropertyKey::Identifier(id.clone())
+                                    }
+                                    PropertyKey::Expression(e) => {
+                                        PropertyKey::Expression(self.specialize(e, shadowed))
+                                    }
+                                },
+                                value: self.specialize(value, shadowed),
+                            })
+                        }
+                        ObjectProperty::Spread(e) => {
+                            ObjectProperty::Spread(self.specialize(e, shadowed))
+                        }
+                    })
+                    .collect(),
+            ),
+            Expression::Unary(UnaryExpression { operator, argument }) => {
+                Expression::Unary(UnaryExpression {
+                    operator: operator.clone(),
+                    argument: Box::new(self.specialize(argument, shadowed)),
+                })
+            }
+            Expression::Call(CallExpression { function, arguments }) => {
+                Expression::Call(CallExpression {
+                    function: function.clone(),
+                    arguments: arguments
+                        .iter()
+                        .map(|a| self.specialize(a, shadowed))
+                        .collect(),
+                })
+            }
+            Expression::Pattern(_) | Expression::Quote(_) => expression.clone(),
+            Expression::Collate(inner, collation) => Expression::Collate(
+                Box::new(self.specialize(inner, shadowed)),
+                collation.clone(),
+            ),
+            Expression::Template(StringTemplate { parts, suffix }) => {
+                Expression::Template(StringTemplate {
+                    parts: parts
+                        .iter()
+                        .map(|p| StringTemplatePart {
+                            fixed_start: p.fixed_start.clone(),
+                            dynamic_end: Box::new(self.specialize(&p.dynamic_end, shadowed)),
+                        })
+                        .collect(),
+                    suffix: suffix.clone(),
+                })
+            }
+            Expression::Lambda(LambdaExpression { parameter, body }) => {
+                let mut inner = shadowed.clone();
+                inner.extend(parameter.get_identifiers());
+
+                Expression::Lambda(LambdaExpression {
+                    parameter: parameter.clone(),
+                    body: Box::new(self.specialize(body, &inner)),
+                })
+            }
+            Expression::Let(LetExpression { pattern, value, body }) => {
+                let mut inner = shadowed.clone();
+                inner.extend(pattern.get_identifiers());
+
+                Expression::Let(LetExpression {
+                    pattern: pattern.clone(),
+                    value: Box::new(self.specialize(value, shadowed)),
+                    body: Box::new(self.specialize(body, &inner)),
+                })
+            }
+        }
+    }
+
+    pub fn with_memo(&self) -> Self {
+        Self {
+            bindings: self.bindings.clone(),
+            memo: Some(Memo::default()),
+            multiplicities: None,
+        }
+    }
+
     pub fn merge<'e>(mut self, tmp_env: &'e mut Environment<'i, 's, 'v>) {
         tmp_env.bindings.append(&mut self.bindings);
     }
@@ -471,9 +910,69 @@ impl<'i, 's, 'v> Environment<'i, 's, 'v> {
 
 
 impl Environment<'_, '_, '_> {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             bindings: BTreeMap::new(),
+            memo: None,
+            multiplicities: None,
+        }
+    }
+}
+
+impl Default for Environment<'_, '_, '_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn compare_ordered<'s, 'v>(left: &Value<'s, 'v>, right: &Value<'s, 'v>) -> Result<Ordering, EvalError> {
+    match (left, right) {
+        (Value::Integer(_) | Value::BigInt(_), Value::Integer(_) | Value::BigInt(_)) => {
+            Ok(left.cmp(right))
         }
+        (Value::DateTime(l), Value::DateTime(r)) => Ok(l.cmp(r)),
+        _ => Err(EvalError::TypeError),
     }
 }
+
+// Durations are integers counting milliseconds.
+fn shift_datetime<'s, 'v>(time: i64, duration: &Value, sign: i64) -> Result<Value<'s, 'v>, EvalError> {
+    let Value::Integer(millis) = duration else {
+        return Err(match duration {
+            Value::BigInt(_) => EvalError::Overflow,
+            _ => EvalError::TypeError,
+        });
+    };
+
+    millis
+        .checked_mul(sign)
+        .and_then(|d| time.checked_add(d))
+        .and_then(Value::from_timestamp_millis)
+        .ok_or(EvalError::Overflow)
+}
+
+// Integer arithmetic is done on i64 and only falls back to BigInt when the
+// result does not fit. Results are limited to MAX_INTEGER_BITS.
+pub(crate) fn integer_operation<'s, 'v>(
+    left: &Value,
+    right: &Value,
+    small: impl Fn(i64, i64) -> Option<i64>,
+    big: impl Fn(BigInt, BigInt) -> Result<BigInt, EvalError>,
+) -> Result<Value<'s, 'v>, EvalError> {
+    if let (Value::Integer(l), Value::Integer(r)) = (left, right) {
+        if let Some(result) = small(*l, *r) {
+            return Ok(Value::Integer(result));
+        }
+    }
+
+    let (Some(l), Some(r)) = (left.to_bigint(), right.to_bigint()) else {
+        return Err(EvalError::TypeError);
+    };
+    let result = big(l, r)?;
+
+    if result.bits() > MAX_INTEGER_BITS {
+        return Err(EvalError::Overflow);
+    }
+
+    Ok(Value::from_bigint(result))
+}
@@ -0,0 +1,373 @@
+use crate::{env::EvalError, limits::LimitError, parser::StatementError, repl::ReplError};
+
+pub struct CatalogEntry {
+    pub code: &'static str,
+    pub name: &'static str,
+    pub explanation: &'static str,
+}
+
+pub trait ErrorCode {
+    fn code(&self) -> &'static str;
+}
+
+pub const CATALOG: &[CatalogEntry] = &[
+    CatalogEntry {
+        code: "E0001",
+        name: "TypeMismatch",
+        explanation: "An operator or function was applied to a value of the wrong type, \
+            eg `1 + \"a\"` or `length(5)`. Use `type(x)` to inspect the type of a value \
+            and `x is Integer` to check it before using it.",
+    },
+    CatalogEntry {
+        code: "E0002",
+        name: "KindError",
+        explanation: "The right side of `is` or of a cast is not a type, eg `5 is 3`. \
+            Use a type literal like `Integer` or `String` instead.",
+    },
+    CatalogEntry {
+        code: "E0003",
+        name: "UnknownIdentifier",
+        explanation: "The expression refers to a variable that is not bound. \
+            Bind it first via `.let x = 42` or check the spelling.",
+    },
+    CatalogEntry {
+        code: "E0004",
+        name: "InvalidNumber",
+        explanation: "A number literal could not be read as an integer.",
+    },
+    CatalogEntry {
+        code: "E0005",
+        name: "DivisionByZero",
+        explanation: "The right operand of `/` or `%` evaluated to zero.",
+    },
+    CatalogEntry {
+        code: "E0006",
+        name: "KeyNotDefined",
+        explanation: "An object was accessed with a key it does not contain, eg `{a: 1}.b`. \
+            Check the available keys via `keys(obj)`.",
+    },
+    CatalogEntry {
+        code: "E0007",
+        name: "OutOfBound",
+        explanation: "An array or string was indexed outside of its length. \
+            Negative indices count from the end, so valid indices of `[1,2,3]` are -3 to 2.",
+    },
+    CatalogEntry {
+        code: "E0008",
+        name: "Overflow",
+        explanation: "An arithmetic operation produced an integer of more than 65536 bits, \
+            eg `2 ^ 100000`. Smaller results that do not fit into 64 bits are computed \
+            with arbitrary precision. Datetime arithmetic overflows when the result \
+            is not between the years 0 and 9999.",
+    },
+    CatalogEntry {
+        code: "E0009",
+        name: "UnknownFunction",
+        explanation: "The called function does not exist or was disabled by the \
+            application. Builtin functions are length, keys, values, sort, unique, \
+            flatten, map, filter, reduce, type, matches, eval, diff, assert and count_of.",
+    },
+    CatalogEntry {
+        code: "E0010",
+        name: "ArgumentMismatch",
+        explanation: "A function was called with the wrong number of arguments or with \
+            arguments that do not match its parameter patterns, or the value bound by \
+            `let ... in` does not match its pattern.",
+    },
+    CatalogEntry {
+        code: "E0011",
+        name: "InvalidRegex",
+        explanation: "The right side of `matches` is not a valid regular expression.",
+    },
+    CatalogEntry {
+        code: "E0012",
+        name: "OutOfFuel",
+        explanation: "The evaluation was aborted because it nested too deeply, \
+            eg an `eval` of a quoted expression that evaluates itself again, or because \
+            a query on an unbounded virtual bag like `1..` has no `limit`, joins it or \
+            does not find enough values to reach its limit within the first 1048576.",
+    },
+    CatalogEntry {
+        code: "E0013",
+        name: "BackendFailed",
+        explanation: "A bag stored in an external backend, eg a database table, \
+            could not be read. Check that the backend is still reachable.",
+    },
+    CatalogEntry {
+        code: "E0014",
+        name: "InvalidDatetime",
+        explanation: "A datetime literal does not denote an existing point in time, \
+            eg `@2023-02-30`. Datetimes are written in ISO-8601 like `@2024-01-15`, \
+            `@2024-01-15T10:30:00Z` or `@2024-01-15T10:30:00.250+02:00`.",
+    },
+    CatalogEntry {
+        code: "E0015",
+        name: "InvalidCollation",
+        explanation: "A `collate` names a locale that is not known, eg `collate \"xx-!\"`, \
+            or damasc was built without the `collation` feature, which is required \
+            for collations other than `binary` and `nocase`.",
+    },
+    CatalogEntry {
+        code: "E0016",
+        name: "AssertionFailed",
+        explanation: "The condition of `assert(condition, message)` is `false`. The \
+            statement reports the message. Inside of a guard the failed assertion \
+            rejects the value and its message is shown as warning.",
+    },
+    CatalogEntry {
+        code: "E0017",
+        name: "FunctionFailed",
+        explanation: "A function registered by the application, eg a JavaScript \
+            callback of the browser build, failed or returned a value that damasc \
+            can not represent.",
+    },
+    CatalogEntry {
+        code: "E0100",
+        name: "SyntaxError",
+        explanation: "The input could not be parsed as a statement. The caret below the \
+            input marks the position where parsing failed and the message lists the tokens \
+            that would have been valid there. Type `.help` for an overview of the available statements.",
+    },
+    CatalogEntry {
+        code: "E0101",
+        name: "AmbiguousStatement",
+        explanation: "The input can be read as a binding as well as a comparison. \
+            Use `.let` to assign variables, `.match` to match a pattern \
+            or `==` to compare values.",
+    },
+    CatalogEntry {
+        code: "E0102",
+        name: "LimitExceeded",
+        explanation: "The input exceeds one of the configured limits, eg it is nested \
+            too deeply, too long or joins too many patterns. `.set` lists the current limits.",
+    },
+    CatalogEntry {
+        code: "E0103",
+        name: "InvalidNode",
+        explanation: "A statement given as JSON syntax tree does not describe a valid statement. \
+            The error names the path of the invalid node. Use `.ast` to see the syntax tree \
+            of a statement written in the text syntax.",
+    },
+    CatalogEntry {
+        code: "E0104",
+        name: "InvalidArguments",
+        explanation: "The arguments of a statement added by the application embedding \
+            damasc were rejected by it. The message describes what is wrong with them.",
+    },
+    CatalogEntry {
+        code: "E0200",
+        name: "Exit",
+        explanation: "The session was closed via `.exit`.",
+    },
+    CatalogEntry {
+        code: "E0201",
+        name: "ReadError",
+        explanation: "A file could not be read or its content could not be parsed.",
+    },
+    CatalogEntry {
+        code: "E0202",
+        name: "ParseError",
+        explanation: "A stored value or expression could not be parsed.",
+    },
+    CatalogEntry {
+        code: "E0204",
+        name: "AssignmentError",
+        explanation: "The values of a `.let` do not match the patterns on its left side.",
+    },
+    CatalogEntry {
+        code: "E0205",
+        name: "IoError",
+        explanation: "Reading or writing a file or the storage directory failed.",
+    },
+    CatalogEntry {
+        code: "E0206",
+        name: "BagError",
+        explanation: "The referenced bag does not exist or can not be used here. \
+            `.bags` lists all bags.",
+    },
+    CatalogEntry {
+        code: "E0207",
+        name: "TransactionAborted",
+        explanation: "The statement was rolled back because it would leave the bags \
+            in an invalid state, eg an inserted value violates the bag's guard \
+            or a virtual bag was modified.",
+    },
+    CatalogEntry {
+        code: "E0208",
+        name: "TransferError",
+        explanation: "Values could not be moved into the target bag.",
+    },
+    CatalogEntry {
+        code: "E0209",
+        name: "GuardError",
+        explanation: "The guard of a bag could not be evaluated.",
+    },
+    CatalogEntry {
+        code: "E0210",
+        name: "ConnectionError",
+        explanation: "A connection between bags could not be created or evaluated, \
+            or connections triggered by an insertion kept triggering each other. \
+            `.connections` lists the existing connections and triggers.",
+    },
+    CatalogEntry {
+        code: "E0211",
+        name: "SettingError",
+        explanation: "The setting does not exist or the value has the wrong type. \
+            `.set` lists all settings with their current values.",
+    },
+    CatalogEntry {
+        code: "E0212",
+        name: "MacroError",
+        explanation: "A macro was expanded with the wrong number of arguments or its \
+            definition refers to another macro.",
+    },
+    CatalogEntry {
+        code: "E0213",
+        name: "PreparedError",
+        explanation: "A prepared statement does not exist or the arguments given to `.execute` \
+            do not provide exactly its `$` parameters, eg `.execute find {id: 5}` for \
+            `.prepare find := .query x where x.id == $id`.",
+    },
+    CatalogEntry {
+        code: "E0214",
+        name: "VerificationError",
+        explanation: "A file imported with `verify` has no manifest or does not match it: \
+            its checksum or its number of values differs from the ones recorded by the \
+            export, eg because the file was truncated or modified afterwards.",
+    },
+    CatalogEntry {
+        code: "E0215",
+        name: "TransactionState",
+        explanation: "`.begin` was used while a transaction is already running, or \
+            `.commit` or `.rollback` while none is. Transactions can not be nested.",
+    },
+    CatalogEntry {
+        code: "E0216",
+        name: "TransactionConflict",
+        explanation: "The bags were changed by another statement while this one was \
+            running, eg by another client of the web server. Nothing was changed, the \
+            statement can be run again on the current bags.",
+    },
+    CatalogEntry {
+        code: "E0217",
+        name: "AssertionError",
+        explanation: "A statement failed because of an `assert(condition, message)` whose \
+            condition is `false`. The error carries the message of the assertion.",
+    },
+    CatalogEntry {
+        code: "E0218",
+        name: "ScriptFailed",
+        explanation: "A statement of a script run via `.run` could not be parsed or \
+            failed. The error carries the number of the line and its error, the \
+            statements before it have been executed. Statements queued while stepping \
+            through a connection are reported the same way, numbered from 1.",
+    },
+    CatalogEntry {
+        code: "E0219",
+        name: "Denied",
+        explanation: "The statement is not allowed by the frontend, eg the web UI \
+            does not access files or create new bags. Frontends configure the allowed \
+            statements via `Repl::capabilities`. A session of the web UI with a bag of \
+            its own can neither switch to another bag nor use the bags of the others.",
+    },
+    CatalogEntry {
+        code: "E0220",
+        name: "SteppingState",
+        explanation: "`.continue`, `.skip` and `.abort` decide on the candidates shown by \
+            `.solve <connection> <argument> step` and fail if no connection is being \
+            stepped through. When paused by a breakpoint `.continue` and `.abort` resume \
+            the queued statements or drop them, `.skip` fails. A second `.solve ... step` \
+            fails until the first one was finished or aborted.",
+    },
+    CatalogEntry {
+        code: "E0221",
+        name: "TooManySessions",
+        explanation: "The server gives every session a bag of its own and has as many \
+            sessions as it allows. Try again once idle sessions have expired.",
+    },
+    CatalogEntry {
+        code: "E0222",
+        name: "Unconfirmed",
+        explanation: "A `.delete` or `.change` would remove or change more values than \
+            `.set confirm_threshold` allows, or a `.drop` would remove a bag holding more. \
+            Nothing was changed. The error carries the number of values, frontends ask \
+            for a confirmation and run the statement again, the web server once it is \
+            submitted with `confirm=true`.",
+    },
+];
+
+pub fn lookup(code: &str) -> Option<&'static CatalogEntry> {
+    CATALOG.iter().find(|e| e.code.eq_ignore_ascii_case(code))
+}
+
+impl ErrorCode for EvalError {
+    fn code(&self) -> &'static str {
+        match self {
+            EvalError::TypeError => "E0001",
+            EvalError::KindError => "E0002",
+            EvalError::UnknownIdentifier => "E0003",
+            EvalError::InvalidNumber => "E0004",
+            EvalError::MathDivision => "E0005",
+            EvalError::KeyNotDefined => "E0006",
+            EvalError::OutOfBound => "E0007",
+            EvalError::Overflow => "E0008",
+            EvalError::UnknownFunction => "E0009",
+            EvalError::ArgumentMismatch => "E0010",
+            EvalError::InvalidRegex => "E0011",
+            EvalError::OutOfFuel => "E0012",
+            EvalError::BackendFailed => "E0013",
+            EvalError::InvalidDatetime => "E0014",
+            EvalError::InvalidCollation => "E0015",
+            EvalError::AssertionFailed(_) => "E0016",
+            EvalError::FunctionFailed => "E0017",
+        }
+    }
+}
+
+impl ErrorCode for LimitError {
+    fn code(&self) -> &'static str {
+        "E0102"
+    }
+}
+
+impl ErrorCode for StatementError {
+    fn code(&self) -> &'static str {
+        match self {
+            StatementError::Syntax(_) => "E0100",
+            StatementError::Ambiguous(_) => "E0101",
+            StatementError::InvalidNode { .. } => "E0103",
+            StatementError::InvalidArguments { .. } => "E0104",
+            StatementError::Limit(e) => e.code(),
+        }
+    }
+}
+
+impl ErrorCode for ReplError {
+    fn code(&self) -> &'static str {
+        match self {
+            ReplError::Exit => "E0200",
+            ReplError::ReadError => "E0201",
+            ReplError::ParseError => "E0202",
+            ReplError::EvalError(e) => e.code(),
+            ReplError::AssignmentError => "E0204",
+            ReplError::IoError => "E0205",
+            ReplError::BagError => "E0206",
+            ReplError::TranscationAborted => "E0207",
+            ReplError::TransferError => "E0208",
+            ReplError::GuardError => "E0209",
+            ReplError::ConnectionError => "E0210",
+            ReplError::SettingError => "E0211",
+            ReplError::MacroError => "E0212",
+            ReplError::PreparedError => "E0213",
+            ReplError::VerificationError => "E0214",
+            ReplError::TransactionState => "E0215",
+            ReplError::TransactionConflict => "E0216",
+            ReplError::AssertionError(_) => "E0217",
+            ReplError::ScriptFailed(..) => "E0218",
+            ReplError::Denied => "E0219",
+            ReplError::SteppingState => "E0220",
+            ReplError::TooManySessions => "E0221",
+            ReplError::Unconfirmed(_) => "E0222",
+        }
+    }
+}
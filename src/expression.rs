@@ -1,11 +1,16 @@
 use std::borrow::Cow;
 use std::collections::VecDeque;
 
+use serde::{Deserialize, Serialize};
+
+use crate::collation::Collation;
+use crate::formatter;
 use crate::identifier::Identifier;
 use crate::literal::Literal;
+use crate::pattern::Pattern;
 use gen_iter::gen_iter;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Expression<'s> {
     Array(ArrayExpression<'s>),
     Binary(BinaryExpression<'s>),
@@ -17,90 +22,21 @@ pub enum Expression<'s> {
     Unary(UnaryExpression<'s>),
     Call(CallExpression<'s>),
     Template(StringTemplate<'s>),
+    Lambda(LambdaExpression<'s>),
+    Let(LetExpression<'s>),
+    Pattern(Box<Pattern<'s>>),
+    Quote(Box<Expression<'s>>),
+    // A comparison of strings by the given collation.
+    Collate(Box<Expression<'s>>, Collation),
 }
 
 impl std::fmt::Display for Expression<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Expression::Literal(l) => write!(f, "{l}"),
-            Expression::Array(arr) => {
-                write!(f, "[")?;
-                for item in arr {
-                    match item {
-                        ArrayItem::Single(i) => write!(f, "{i},")?,
-                        ArrayItem::Spread(i) => write!(f, "...({i}),")?,
-                    }
-                }
-                write!(f, "[")
-            },
-            Expression::Binary(BinaryExpression {operator, left, right}) => {
-                write!(f, "({left} {} {right})", match operator {
-                    BinaryOperator::StrictEqual => "==",
-                    BinaryOperator::StrictNotEqual => "!=",
-                    BinaryOperator::LessThan => "<",
-                    BinaryOperator::GreaterThan => ">",
-                    BinaryOperator::LessThanEqual => "<=",
-                    BinaryOperator::GreaterThanEqual => ">=",
-                    BinaryOperator::Plus => "+",
-                    BinaryOperator::Minus => "-",
-                    BinaryOperator::Times => "*",
-                    BinaryOperator::Over => "/",
-                    BinaryOperator::Mod => "%",
-                    BinaryOperator::In => "in",
-                    BinaryOperator::PowerOf => "^",
-                    BinaryOperator::Is => "is",
-                    BinaryOperator::Cast => "cast",
-                })
-            },
-            Expression::Identifier(id) => write!(f, "{id}"),
-            Expression::Logical(LogicalExpression{left, right, operator}) => {
-                write!(f, "({left} {} {right})", match operator {
-                    LogicalOperator::Or => "||",
-                    LogicalOperator::And => "&&",
-                })
-            },
-            Expression::Member(MemberExpression{ object, property }) => {
-                write!(f, "{object}[{property}]")
-            },
-            Expression::Object(props) => {
-                write!(f, "{{")?;
-                for prop in props {
-                    match prop {
-                        ObjectProperty::Single(id) => write!(f, "{id},")?,
-                        ObjectProperty::Property(Property{ key, value }) => {
-                            match key {
-                                PropertyKey::Identifier(id) => write!(f, "{id}: {value},"),
-                                PropertyKey::Expression(expr) => write!(f, "[{expr}]: {value},"),
-                            }?;
-                        },
-                        ObjectProperty::Spread(expr) => write!(f, "...({expr}),")?,
-                    }
-                }
-                write!(f, "}}")
-            },
-            Expression::Unary(UnaryExpression { operator, argument  }) => {
-                write!(f, "({} {argument})", match operator {
-                    UnaryOperator::Minus => "-",
-                    UnaryOperator::Plus => "+",
-                    UnaryOperator::Not => "!",
-                })
-            },
-            Expression::Call(CallExpression { function, argument  }) => {
-                write!(f, "{function}({argument})")
-            },
-            Expression::Template(StringTemplate { parts, suffix }) => {
-                write!(f, "$`")?;
-                for p in parts {
-                    write!(f, "{}${{{}}}", p.fixed_start, p.dynamic_end)?;
-                }
-                write!(f, "{suffix}`")
-            },
-            
-        }
+        write!(f, "{}", formatter::expression(self))
     }
 }
 
-impl Expression<'_> {
+impl<'s> Expression<'s> {
     pub(crate) fn get_identifiers(&self) -> impl Iterator<Item = &Identifier> {
         gen_iter!(move {
             let mut expression_stack : VecDeque<&Expression> = VecDeque::new();
@@ -159,29 +95,302 @@ impl Expression<'_> {
                     Expression::Unary(UnaryExpression{argument, ..}) => {
                         expression_stack.push_front(argument);
                     },
-                    Expression::Call(CallExpression{argument,..}) => {
-                        expression_stack.push_front(argument);
-
+                    Expression::Call(CallExpression{arguments,..}) => {
+                        for argument in arguments {
+                            expression_stack.push_front(argument);
+                        }
                     },
+                    Expression::Pattern(_) | Expression::Quote(_) => {},
+                    Expression::Collate(inner, _) => expression_stack.push_front(inner),
                     Expression::Template(StringTemplate{parts, ..}) => {
                         for p in parts {
                             expression_stack.push_front(&p.dynamic_end);
                         }
                     },
+                    Expression::Lambda(LambdaExpression{parameter, body}) => {
+                        let free = {
+                            let bound = parameter.get_identifiers().collect::<Vec<_>>();
+                            body.get_identifiers().filter(|id| !bound.contains(id)).collect::<Vec<_>>()
+                        };
+                        for id in free {
+                            yield id;
+                        }
+                    },
+                    Expression::Let(LetExpression{pattern, value, body}) => {
+                        expression_stack.push_front(value);
+                        let free = {
+                            let bound = pattern.get_identifiers().collect::<Vec<_>>();
+                            body.get_identifiers().filter(|id| !bound.contains(id)).collect::<Vec<_>>()
+                        };
+                        for id in free {
+                            yield id;
+                        }
+                    },
                 }
             }
         })
     }
+
+    pub(crate) fn children(&self) -> Vec<&Expression<'s>> {
+        match self {
+            Expression::Identifier(_) | Expression::Literal(_) => vec![],
+            Expression::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    ArrayItem::Single(e) | ArrayItem::Spread(e) => e,
+                })
+                .collect(),
+            Expression::Binary(b) => vec![&b.left, &b.right],
+            Expression::Logical(l) => vec![&l.left, &l.right],
+            Expression::Member(m) => vec![&m.object, &m.property],
+            Expression::Object(props) => props
+                .iter()
+                .flat_map(|prop| match prop {
+                    ObjectProperty::Single(_) => vec![],
+                    ObjectProperty::Property(p) => match &p.key {
+                        PropertyKey::Expression(k) => vec![k, &p.value],
+                        PropertyKey::Identifier(_) => vec![&p.value],
+                    },
+                    ObjectProperty::Spread(e) => vec![e],
+                })
+                .collect(),
+            Expression::Unary(u) => vec![&u.argument],
+            Expression::Call(c) => c.arguments.iter().collect(),
+            Expression::Pattern(_) => vec![],
+            Expression::Quote(q) | Expression::Collate(q, _) => vec![q],
+            Expression::Template(t) => t.parts.iter().map(|p| p.dynamic_end.as_ref()).collect(),
+            Expression::Lambda(l) => vec![&l.body],
+            Expression::Let(l) => vec![&l.value, &l.body],
+        }
+    }
+
+    pub(crate) fn get_callees(&self) -> Vec<&Identifier<'s>> {
+        let mut callees = vec![];
+        let mut stack = vec![self];
+
+        while let Some(e) = stack.pop() {
+            if let Expression::Call(CallExpression { function, .. }) = e {
+                callees.push(function);
+            }
+            stack.extend(e.children());
+        }
+
+        callees
+    }
+
+    pub(crate) fn deep_clone<'x,'y>(&'x self) -> Expression<'y> {
+        match self {
+            Expression::Array(items) => Expression::Array(
+                items
+                    .iter()
+                    .map(|item| match item {
+                        ArrayItem::Single(e) => ArrayItem::Single(e.deep_clone()),
+                        ArrayItem::Spread(e) => ArrayItem::Spread(e.deep_clone()),
+                    })
+                    .collect(),
+            ),
+            Expression::Binary(BinaryExpression { operator, left, right }) => {
+                Expression::Binary(BinaryExpression {
+                    operator: *operator,
+                    left: Box::new(left.deep_clone()),
+                    right: Box::new(right.deep_clone()),
+                })
+            }
+            Expression::Identifier(id) => Expression::Identifier(id.deep_clone()),
+            Expression::Literal(l) => Expression::Literal(l.deep_clone()),
+            Expression::Logical(LogicalExpression { operator, left, right }) => {
+                Expression::Logical(LogicalExpression {
+                    operator: *operator,
+                    left: Box::new(left.deep_clone()),
+                    right: Box::new(right.deep_clone()),
+                })
+            }
+            Expression::Member(MemberExpression { object, property }) => {
+                Expression::Member(MemberExpression {
+                    object: Box::new(object.deep_clone()),
+                    property: Box::new(property.deep_clone()),
+                })
+            }
+            Expression::Object(props) => Expression::Object(
+                props
+                    .iter()
+                    .map(|prop| match prop {
+                        ObjectProperty::Single(id) => ObjectProperty::Single(id.deep_clone()),
+                        ObjectProperty::Property(Property { key, value }) => {
+                            ObjectProperty::Property(Property {
+                                key: key.deep_clone(),
+                                value: value.deep_clone(),
+                            })
+                        }
+                        ObjectProperty::Spread(e) => ObjectProperty::Spread(e.deep_clone()),
+                    })
+                    .collect(),
+            ),
+            Expression::Unary(UnaryExpression { operator, argument }) => {
+                Expression::Unary(UnaryExpression {
+                    operator: operator.clone(),
+                    argument: Box::new(argument.deep_clone()),
+                })
+            }
+            Expression::Call(CallExpression { function, arguments }) => {
+                Expression::Call(CallExpression {
+                    function: function.deep_clone(),
+                    arguments: arguments.iter().map(|a| a.deep_clone()).collect(),
+                })
+            }
+            Expression::Pattern(pattern) => Expression::Pattern(Box::new(pattern.deep_clone())),
+            Expression::Quote(quoted) => Expression::Quote(Box::new(quoted.deep_clone())),
+            Expression::Collate(inner, collation) => {
+                Expression::Collate(Box::new(inner.deep_clone()), collation.clone())
+            }
+            Expression::Template(StringTemplate { parts, suffix }) => {
+                Expression::Template(StringTemplate {
+                    parts: parts
+                        .iter()
+                        .map(|p| StringTemplatePart {
+                            fixed_start: Cow::Owned(p.fixed_start.as_ref().into()),
+                            dynamic_end: Box::new(p.dynamic_end.deep_clone()),
+                        })
+                        .collect(),
+                    suffix: Cow::Owned(suffix.as_ref().into()),
+                })
+            }
+            Expression::Lambda(LambdaExpression { parameter, body }) => {
+                Expression::Lambda(LambdaExpression {
+                    parameter: parameter.deep_clone(),
+                    body: Box::new(body.deep_clone()),
+                })
+            }
+            Expression::Let(LetExpression { pattern, value, body }) => {
+                Expression::Let(LetExpression {
+                    pattern: pattern.deep_clone(),
+                    value: Box::new(value.deep_clone()),
+                    body: Box::new(body.deep_clone()),
+                })
+            }
+        }
+    }
+
+    pub(crate) fn rewrite<F>(&self, f: &mut F) -> Expression<'s>
+    where
+        F: FnMut(&Expression<'s>) -> Option<Expression<'s>>,
+    {
+        if let Some(replacement) = f(self) {
+            return replacement;
+        }
+
+        match self {
+            Expression::Array(items) => Expression::Array(
+                items
+                    .iter()
+                    .map(|item| match item {
+                        ArrayItem::Single(e) => ArrayItem::Single(e.rewrite(f)),
+                        ArrayItem::Spread(e) => ArrayItem::Spread(e.rewrite(f)),
+                    })
+                    .collect(),
+            ),
+            Expression::Binary(BinaryExpression { operator, left, right }) => {
+                Expression::Binary(BinaryExpression {
+                    operator: *operator,
+                    left: Box::new(left.rewrite(f)),
+                    right: Box::new(right.rewrite(f)),
+                })
+            }
+            Expression::Logical(LogicalExpression { operator, left, right }) => {
+                Expression::Logical(LogicalExpression {
+                    operator: *operator,
+                    left: Box::new(left.rewrite(f)),
+                    right: Box::new(right.rewrite(f)),
+                })
+            }
+            Expression::Member(MemberExpression { object, property }) => {
+                Expression::Member(MemberExpression {
+                    object: Box::new(object.rewrite(f)),
+                    property: Box::new(property.rewrite(f)),
+                })
+            }
+            Expression::Object(props) => Expression::Object(
+                props
+                    .iter()
+                    .map(|prop| match prop {
+                        ObjectProperty::Single(id) => ObjectProperty::Single(id.clone()),
+                        ObjectProperty::Property(Property { key, value }) => {
+                            ObjectProperty::Property(Property {
+                                key: match key {
+                                    PropertyKey::Identifier(id) => {
+                                        PropertyKey::Identifier(id.clone())
+                                    }
+                                    PropertyKey::Expression(e) => {
+                                        PropertyKey::Expression(e.rewrite(f))
+                                    }
+                                },
+                                value: value.rewrite(f),
+                            })
+                        }
+                        ObjectProperty::Spread(e) => ObjectProperty::Spread(e.rewrite(f)),
+                    })
+                    .collect(),
+            ),
+            Expression::Unary(UnaryExpression { operator, argument }) => {
+                Expression::Unary(UnaryExpression {
+                    operator: operator.clone(),
+                    argument: Box::new(argument.rewrite(f)),
+                })
+            }
+            Expression::Call(CallExpression { function, arguments }) => {
+                Expression::Call(CallExpression {
+                    function: function.clone(),
+                    arguments: arguments.iter().map(|a| a.rewrite(f)).collect(),
+                })
+            }
+            Expression::Template(StringTemplate { parts, suffix }) => {
+                Expression::Template(StringTemplate {
+                    parts: parts
+                        .iter()
+                        .map(|p| StringTemplatePart {
+                            fixed_start: p.fixed_start.clone(),
+                            dynamic_end: Box::new(p.dynamic_end.rewrite(f)),
+                        })
+                        .collect(),
+                    suffix: suffix.clone(),
+                })
+            }
+            Expression::Collate(inner, collation) => {
+                Expression::Collate(Box::new(inner.rewrite(f)), collation.clone())
+            }
+            Expression::Let(LetExpression { pattern, value, body }) => {
+                Expression::Let(LetExpression {
+                    pattern: pattern.clone(),
+                    value: Box::new(value.rewrite(f)),
+                    body: Box::new(body.rewrite(f)),
+                })
+            }
+            Expression::Identifier(_)
+            | Expression::Literal(_)
+            | Expression::Lambda(_)
+            | Expression::Pattern(_)
+            | Expression::Quote(_) => self.clone(),
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
+impl PropertyKey<'_> {
+    pub(crate) fn deep_clone<'x,'y>(&'x self) -> PropertyKey<'y> {
+        match self {
+            PropertyKey::Identifier(id) => PropertyKey::Identifier(id.deep_clone()),
+            PropertyKey::Expression(e) => PropertyKey::Expression(e.deep_clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ExpressionSet<'s> {
     pub expressions: Vec<Expression<'s>>,
 }
 
 type ArrayExpression<'a> = Vec<ArrayItem<'a>>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ArrayItem<'a> {
     Single(Expression<'a>),
     Spread(Expression<'a>),
@@ -189,64 +398,79 @@ pub enum ArrayItem<'a> {
 
 pub type ObjectExpression<'a> = Vec<ObjectProperty<'a>>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ObjectProperty<'a> {
     Single(Identifier<'a>),
     Property(Property<'a>),
     Spread(Expression<'a>),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Property<'a> {
     pub key: PropertyKey<'a>,
     pub value: Expression<'a>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum PropertyKey<'a> {
     Identifier(Identifier<'a>),
     Expression(Expression<'a>),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CallExpression<'a> {
     pub function: Identifier<'a>,
-    pub argument: Box<Expression<'a>>,
+    pub arguments: Vec<Expression<'a>>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StringTemplate<'a> {
     pub parts: Vec<StringTemplatePart<'a>>,
     pub suffix: Cow<'a, str>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StringTemplatePart<'a> {
     pub fixed_start: Cow<'a, str>,
     pub dynamic_end: Box<Expression<'a>>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LambdaExpression<'a> {
+    pub parameter: Pattern<'a>,
+    pub body: Box<Expression<'a>>,
+}
+
+// `let pattern = value in body`, the variables of the pattern are only
+// bound while evaluating the body.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LetExpression<'a> {
+    pub pattern: Pattern<'a>,
+    pub value: Box<Expression<'a>>,
+    pub body: Box<Expression<'a>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UnaryExpression<'a> {
     pub operator: UnaryOperator,
     pub argument: Box<Expression<'a>>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BinaryExpression<'a> {
     pub operator: BinaryOperator,
     pub left: Box<Expression<'a>>,
     pub right: Box<Expression<'a>>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LogicalExpression<'a> {
     pub operator: LogicalOperator,
     pub left: Box<Expression<'a>>,
     pub right: Box<Expression<'a>>,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum BinaryOperator {
     StrictEqual,
     StrictNotEqual,
@@ -263,9 +487,33 @@ pub enum BinaryOperator {
     PowerOf,
     Is,
     Cast,
+    Matches,
+}
+
+impl std::fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            BinaryOperator::StrictEqual => "==",
+            BinaryOperator::StrictNotEqual => "!=",
+            BinaryOperator::LessThan => "<",
+            BinaryOperator::GreaterThan => ">",
+            BinaryOperator::LessThanEqual => "<=",
+            BinaryOperator::GreaterThanEqual => ">=",
+            BinaryOperator::Plus => "+",
+            BinaryOperator::Minus => "-",
+            BinaryOperator::Times => "*",
+            BinaryOperator::Over => "/",
+            BinaryOperator::Mod => "%",
+            BinaryOperator::In => "in",
+            BinaryOperator::PowerOf => "^",
+            BinaryOperator::Is => "is",
+            BinaryOperator::Cast => "as",
+            BinaryOperator::Matches => "matches",
+        })
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum LogicalOperator {
     Or,
     And,
@@ -280,14 +528,14 @@ impl LogicalOperator {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum UnaryOperator {
     Minus,
     Plus,
     Not,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MemberExpression<'a> {
     pub object: Box<Expression<'a>>,
     pub property: Box<Expression<'a>>,
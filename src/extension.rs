@@ -0,0 +1,32 @@
+use crate::capabilities::Capabilities;
+use crate::repl::{Repl, ReplError, ReplOutput};
+
+// A statement `.<name> <arguments>` provided by an application embedding
+// damasc, eg a domain specific command, registered via `Repl::register`.
+// The repl recognizes it before its own statements, so its name can not be
+// the name of a builtin command.
+pub trait CustomStatement<'b, 'i, 's, 'v>: Send + Sync {
+    // The name of the statement without the leading dot, eg `greet` for
+    // `.greet world`.
+    fn name(&self) -> &str;
+
+    // Checks the text following the name when the statement is parsed,
+    // returning a message describing what is wrong with it. The parsers of
+    // `damasc::parser` can be used to read expressions or patterns.
+    fn parse(&self, _arguments: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // The capabilities the frontend has to allow besides `MUTATION`, which
+    // every custom statement needs, eg `IO` if it reads files.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::NONE
+    }
+
+    // Executes the statement with the arguments accepted by `parse`.
+    fn execute(
+        &self,
+        repl: &mut Repl<'b, 'i, 's, 'v>,
+        arguments: &str,
+    ) -> Result<ReplOutput<'i, 's, 'v>, ReplError>;
+}
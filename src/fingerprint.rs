@@ -0,0 +1,68 @@
+use std::borrow::Cow;
+
+use serde_json::Value as Json;
+
+use crate::identifier::Identifier;
+use crate::statement::Statement;
+
+// The operators whose operands can be swapped without changing the result.
+const COMMUTATIVE: [&str; 4] = ["StrictEqual", "StrictNotEqual", "Plus", "Times"];
+
+// A hash of the syntax tree of the statement, so that equivalent statements
+// sent by different sessions can be recognized and evaluated once. It does
+// not depend on whitespace, parentheses or the order of the operands of
+// `==`, `!=`, `+` and `*`, eg `x == 1 + y` and `(y+1) == x` have the same
+// fingerprint. It is stable across runs and platforms.
+pub fn statement(stmt: &Statement) -> u64 {
+    let mut json = stmt.to_json();
+    normalize(&mut json);
+
+    fnv1a(json.to_string().as_bytes())
+}
+
+// The fingerprint of the definition of a view over the bags, regardless of
+// its name. Views with the same fingerprint share their values.
+pub fn view(bags: &[Identifier]) -> u64 {
+    let name = Identifier { name: Cow::Borrowed("") };
+
+    statement(&Statement::CreateView(name, bags.to_vec()))
+}
+
+fn normalize(json: &mut Json) {
+    match json {
+        Json::Array(items) => items.iter_mut().for_each(normalize),
+        Json::Object(fields) => {
+            fields.values_mut().for_each(normalize);
+
+            let Some(Json::Object(binary)) = fields.get_mut("Binary") else {
+                return;
+            };
+            let commutative = binary
+                .get("operator")
+                .and_then(Json::as_str)
+                .map_or(false, |operator| COMMUTATIVE.contains(&operator));
+            if !commutative {
+                return;
+            }
+            let (Some(left), Some(right)) = (binary.remove("left"), binary.remove("right")) else {
+                return;
+            };
+            let (left, right) = if left.to_string() > right.to_string() {
+                (right, left)
+            } else {
+                (left, right)
+            };
+            binary.insert("left".into(), left);
+            binary.insert("right".into(), right);
+        }
+        _ => {}
+    }
+}
+
+// FNV-1a, unlike the hasher of the standard library its results are
+// guaranteed to stay the same.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
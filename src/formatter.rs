@@ -0,0 +1,853 @@
+use std::borrow::Cow;
+
+use crate::{
+    assignment::AssignmentSet,
+    bag::StorageMode,
+    debugger::StepAction,
+    expression::{
+        ArrayItem, BinaryExpression, BinaryOperator, CallExpression, Expression, LambdaExpression,
+        LetExpression, LogicalExpression, LogicalOperator, MemberExpression, ObjectProperty,
+        Property, PropertyKey, StringTemplate, UnaryExpression, UnaryOperator,
+    },
+    graph::{Connection, Consumption},
+    identifier::Identifier,
+    literal::Literal,
+    merge::Preference,
+    pattern::{ArrayPatternItem, ObjectPropertyPattern, Pattern, PropertyPattern, Rest},
+    query::{Aggregate, Predicate, ProjectionQuery, UpdateQuery},
+    statement::{DataFormat, Statement},
+};
+
+// Renders statements, expressions and patterns as source that is parsed
+// back into the same syntax tree, eg for `.format`, for dumping definitions
+// or for checking the parser. Operands are only put in parentheses where
+// the grammar requires it.
+
+// The width `.format` breaks long arrays and objects at.
+pub const WIDTH: usize = 80;
+
+const INDENT: &str = "  ";
+
+pub fn expression(expression: &Expression) -> String {
+    Printer::FLAT.expression(expression, 0)
+}
+
+pub fn pattern(pattern: &Pattern) -> String {
+    Printer::FLAT.pattern(pattern, 0)
+}
+
+// The statement on a single line.
+pub fn statement(statement: &Statement) -> String {
+    Printer::FLAT.statement(statement, 0)
+}
+
+// Arrays, objects and argument lists not fitting into `width` get one item
+// per line, connections one component per line.
+pub fn pretty(statement: &Statement, width: usize) -> String {
+    Printer { width }.statement(statement, 0)
+}
+
+pub(crate) fn template(template: &StringTemplate) -> String {
+    let mut out = "`".to_string();
+    for part in &template.parts {
+        out += &part.fixed_start;
+        out += &format!("${{{}}}", Printer::FLAT.expression(&part.dynamic_end, 0));
+    }
+    out + &template.suffix + "`"
+}
+
+pub(crate) fn connection(connection: &Connection) -> String {
+    Printer { width: WIDTH }.connection(connection, 0)
+}
+
+#[derive(Clone, Copy)]
+struct Printer {
+    width: usize,
+}
+
+impl Printer {
+    const FLAT: Printer = Printer { width: usize::MAX };
+
+    fn statement(&self, statement: &Statement, indent: usize) -> String {
+        match statement {
+            Statement::Noop => String::new(),
+            Statement::Clear => ".clear".into(),
+            Statement::Exit => ".exit".into(),
+            Statement::Help(None) => ".help".into(),
+            Statement::Help(Some(topic)) => format!(".help {topic}"),
+            Statement::Inspect(e) => format!(".inspect {}", self.expression(e, indent)),
+            Statement::Format(s) => format!(".format {}", self.statement(s, indent)),
+            Statement::Eval(set) => self.expressions(&set.expressions, indent),
+            Statement::Literal(e) => format!(".literal {}", self.expression(e, indent)),
+            Statement::Pattern(p) => format!(".pattern {}", self.pattern(p, indent)),
+            Statement::AssignSet(a) => format!(".let {}", self.assignments(a, indent)),
+            Statement::MatchSet(a) => format!(".match {}", self.assignments(a, indent)),
+            Statement::Insert(insertion) => {
+                format!(
+                    ".insert {}",
+                    self.expressions(&insertion.expressions.expressions, indent)
+                )
+            }
+            Statement::Pop(e) => format!(".pop {}", self.expression(e, indent)),
+            Statement::Log(level, e) => format!(".log {level} {}", self.expression(e, indent)),
+            Statement::Query(query) => self.query(query, indent),
+            Statement::Deletion(deletion) => {
+                format!(".delete {}", self.predicate(&deletion.predicate, indent))
+            }
+            Statement::Update(update) => self.change(update, indent),
+            Statement::Preview(update) => format!(".preview {}", self.change(update, indent)),
+            Statement::Move(bag, transfer) => {
+                let mut out = format!(".move({bag})");
+                if let Pattern::Capture(id, pattern) = &transfer.predicate.pattern {
+                    if id.name == "$" {
+                        out += &format!(" {}", self.pattern(pattern, indent));
+                        if !is_identifier(&transfer.projection, "$") {
+                            out +=
+                                &format!(" into {}", self.expression(&transfer.projection, indent));
+                        }
+                        out += &self.guard(&transfer.predicate.guard, indent);
+                    }
+                }
+                out + &clause("limit", transfer.predicate.limit)
+            }
+            Statement::Import(file, format, verify) => {
+                format!(".load{} {file}{}", suffix(*format), verified(*verify))
+            }
+            Statement::Export(file, format, verify) => {
+                format!(".dump{} {file}{}", suffix(*format), verified(*verify))
+            }
+            Statement::LoadBundle(file) => format!(".load_bundle {file}"),
+            Statement::Save(file) => format!(".save {file}"),
+            Statement::Restore(file) => format!(".restore {file}"),
+            Statement::Run(file) => format!(".run {file}"),
+            Statement::OnError(policy) => format!(".on error {policy}"),
+            Statement::EnableTrash(None) => ".trash on".into(),
+            Statement::EnableTrash(Some(ttl)) => format!(".trash on ttl {ttl}"),
+            Statement::DisableTrash => ".trash off".into(),
+            Statement::ListTrash => ".trash".into(),
+            Statement::RestoreTrash(predicate) => {
+                let pattern = match &predicate.pattern {
+                    Pattern::Discard => String::new(),
+                    p => format!("{} ", self.pattern(p, indent)),
+                };
+                format!(
+                    ".restore {pattern}from trash{}",
+                    self.guard(&predicate.guard, indent)
+                )
+            }
+            Statement::PurgeTrash => ".purge".into(),
+            Statement::UseBag(bag, None, _) => format!(".bag {bag}"),
+            Statement::UseBag(bag, Some(predicate), mode) => {
+                let mode = match mode {
+                    StorageMode::Multiset => "",
+                    StorageMode::Distinct => " distinct",
+                    StorageMode::Counted => " counted",
+                };
+                format!(
+                    ".bag {bag} as {}{}{}{mode}",
+                    self.pattern(&predicate.pattern, indent),
+                    self.guard(&predicate.guard, indent),
+                    clause("limit", predicate.limit),
+                )
+            }
+            Statement::DropBag(bag) => format!(".drop {bag}"),
+            Statement::RenameBag(from, to) => format!(".rename {from} {to}"),
+            Statement::CopyBag(source, target, predicate) => {
+                let filter =
+                    if matches!(predicate.pattern, Pattern::Discard) && is_true(&predicate.guard) {
+                        String::new()
+                    } else {
+                        format!(
+                            " {}{}",
+                            self.pattern(&predicate.pattern, indent),
+                            self.guard(&predicate.guard, indent)
+                        )
+                    };
+                format!(
+                    ".copy {source} {target}{filter}{}",
+                    clause("limit", predicate.limit)
+                )
+            }
+            Statement::CreateVirtualBag(bag, range) => format!(
+                ".bag {bag} as virtual {}..{}",
+                range.start,
+                range.end.map(|e| e.to_string()).unwrap_or_default()
+            ),
+            Statement::CreateView(view, bags) => {
+                format!(".view {view} := union of {}", join(bags.iter(), ", "))
+            }
+            Statement::CreateIndex(bag, field) => format!(".index {bag} on .{field}"),
+            Statement::Cdc(bag, file) => format!(".cdc {bag} to {file}"),
+            Statement::Replicate(file) => format!(".replicate from {file}"),
+            Statement::Connect(_, connection) => self.connection(connection, indent),
+            Statement::Disconnect(name) => format!(".disconnect {name}"),
+            Statement::Trigger(connection, bag) => {
+                format!(".trigger {connection} on insert into {bag}")
+            }
+            Statement::ListConnections => ".connections".into(),
+            Statement::TellBag => ".bag".into(),
+            Statement::ListBags => ".bags".into(),
+            Statement::Validate => ".validate".into(),
+            Statement::Solve(name, e) => format!(".solve {name} {}", self.expression(e, indent)),
+            Statement::SolveStep(name, e) => {
+                format!(".solve {name} {} step", self.expression(e, indent))
+            }
+            Statement::Step(StepAction::Continue) => ".continue".into(),
+            Statement::Step(StepAction::Skip) => ".skip".into(),
+            Statement::Step(StepAction::Abort) => ".abort".into(),
+            Statement::Break(breakpoint) => {
+                let mut out = format!(".break on insert into {}", breakpoint.bag);
+                if let Some(pattern) = &breakpoint.pattern {
+                    out += &format!(" {}", self.pattern(pattern, indent));
+                }
+                if !matches!(breakpoint.guard, Expression::Literal(Literal::Boolean(true))) {
+                    out += &format!(" where {}", self.expression(&breakpoint.guard, indent));
+                }
+                out
+            }
+            Statement::ListBreakpoints => ".break".into(),
+            Statement::ClearBreakpoints => ".break off".into(),
+            Statement::Delta(before, after, key) => {
+                let key = match key {
+                    Some(key) => format!(" on {}", self.expression(key, indent)),
+                    None => String::new(),
+                };
+                format!(".delta {before} {after}{key}")
+            }
+            Statement::Merge(source, target, key, preference) => {
+                let preference = match preference {
+                    Preference::Source => String::new(),
+                    Preference::Target => " prefer target".into(),
+                    Preference::Newest(e) => {
+                        format!(" prefer newest by {}", self.expression(e, indent))
+                    }
+                };
+                format!(
+                    ".merge from {source} into {target} on {}{preference}",
+                    self.expression(key, indent)
+                )
+            }
+            Statement::Explain(s) => format!(".explain {}", self.statement(s, indent)),
+            Statement::ExplainError(code) => format!(".explain {code}"),
+            Statement::Ast(s) => format!(".ast {}", self.statement(s, indent)),
+            Statement::ListSettings => ".set".into(),
+            Statement::ListVariables => ".vars".into(),
+            Statement::Unset(None) => ".unset *".into(),
+            Statement::Unset(Some(name)) => format!(".unset {name}"),
+            Statement::SaveEnv(name) => format!(".env save {name}"),
+            Statement::LoadEnv(name) => format!(".env load {name}"),
+            Statement::Custom(name, arguments) if arguments.is_empty() => format!(".{name}"),
+            Statement::Custom(name, arguments) => format!(".{name} {arguments}"),
+            Statement::Stats(None) => ".stats".into(),
+            Statement::Stats(Some(bag)) => format!(".stats {bag}"),
+            Statement::Profile(false) => ".profile".into(),
+            Statement::Profile(true) => ".profile reset".into(),
+            Statement::Begin => ".begin".into(),
+            Statement::Commit => ".commit".into(),
+            Statement::Rollback => ".rollback".into(),
+            Statement::Set(name, e) => format!(".set {name} {}", self.expression(e, indent)),
+            Statement::DefineMacro(name, parameters, body) if parameters.is_empty() => {
+                format!(".macro {name} := {}", self.statement(body, indent))
+            }
+            Statement::DefineMacro(name, parameters, body) => format!(
+                ".macro {name}({}) := {}",
+                join(parameters.iter(), ", "),
+                self.statement(body, indent)
+            ),
+            Statement::ExpandMacro(name, None) => format!("!{name}"),
+            Statement::ExpandMacro(name, Some(arguments)) => {
+                format!("!{name}{}", self.arguments(arguments, indent))
+            }
+            Statement::Prepare(name, body) => {
+                format!(".prepare {name} := {}", self.statement(body, indent))
+            }
+            Statement::Prepared(name, None) => format!(".execute {name}"),
+            Statement::Prepared(name, Some(arguments)) => {
+                format!(".execute {name} {}", self.expression(arguments, indent))
+            }
+        }
+    }
+
+    // The desugaring of the parser is undone: the captures `$0`, `$1`, ...
+    // of the patterns, the default projection and the bindings of the
+    // aggregates are removed again.
+    fn query(&self, query: &ProjectionQuery, indent: usize) -> String {
+        let predicate = &query.predicate;
+        let mut out = if query.outer { ".queryx" } else { ".query" }.to_string();
+
+        if !query.hints.is_empty() {
+            out += &format!(" /*+ {} */", join(query.hints.iter(), ", "));
+        }
+
+        let bare = matches!(predicate.patterns.as_slice(), [p] if is_pattern_identifier(p, "$"));
+        if !bare {
+            let patterns = predicate.patterns.iter().enumerate().map(|(i, p)| match p {
+                Pattern::Capture(id, p) if id.name == format!("${i}") => p.as_ref(),
+                p => p,
+            });
+            out += " ";
+            out += &patterns
+                .map(|p| self.pattern(p, indent))
+                .collect::<Vec<_>>()
+                .join("; ");
+
+            let default_projection = match (predicate.patterns.len(), &query.projection) {
+                (1, projection) => is_identifier(projection, "$0"),
+                (n, Expression::Array(items)) => {
+                    items.len() == n
+                        && items.iter().enumerate().all(|(i, item)| {
+                            matches!(item, ArrayItem::Single(e) if is_identifier(e, &format!("${i}")))
+                        })
+                }
+                _ => false,
+            };
+            if !default_projection {
+                let projection = with_aggregates(&query.projection, &query.aggregates);
+                out += &format!(" into {}", self.expression(&projection, indent));
+            }
+
+            out += &self.guard(&predicate.guard, indent);
+            if let Some(group) = &query.group {
+                out += &format!(" group by {}", self.expression(group, indent));
+            }
+            if let Some(order) = &query.order {
+                let key = with_aggregates(&order.key, &query.aggregates);
+                out += &format!(" order by {}", self.expression(&key, indent));
+                if order.collation != Default::default() {
+                    out += &format!(" collate {}", order.collation);
+                }
+                if order.descending {
+                    out += " desc";
+                }
+            }
+        }
+
+        out + &clause("limit", predicate.limit) + &clause("offset", predicate.offset)
+    }
+
+    fn predicate(&self, predicate: &Predicate, indent: usize) -> String {
+        format!(
+            "{}{}{}{}",
+            self.pattern(&predicate.pattern, indent),
+            self.guard(&predicate.guard, indent),
+            clause("limit", predicate.limit),
+            clause("offset", predicate.offset),
+        )
+    }
+
+    fn change(&self, update: &UpdateQuery, indent: usize) -> String {
+        let predicate = &update.predicate;
+        format!(
+            ".change {} into {}{}{}{}",
+            self.pattern(&predicate.pattern, indent),
+            self.expression(&update.projection, indent),
+            self.guard(&predicate.guard, indent),
+            clause("limit", predicate.limit),
+            clause("offset", predicate.offset),
+        )
+    }
+
+    fn guard(&self, guard: &Expression, indent: usize) -> String {
+        if is_true(guard) {
+            String::new()
+        } else {
+            format!(" where {}", self.expression(guard, indent))
+        }
+    }
+
+    // The guards of the consumers are part of the guard of the connection,
+    // so they are not repeated. Their `where true` keeps the following `;`
+    // from being taken for the end of their patterns.
+    fn connection(&self, connection: &Connection, indent: usize) -> String {
+        let inner = if self.width == usize::MAX {
+            indent
+        } else {
+            indent + 1
+        };
+        let mut components = vec![];
+
+        for consumer in &connection.consumers {
+            let consumption = match consumer.consumption {
+                Consumption::Test => "test",
+                Consumption::Take => "consume",
+            };
+            components.push(format!(
+                "&{}.{consumption} {} where true",
+                consumer.source_bag,
+                join(
+                    consumer.patterns.iter().map(|p| self.pattern(p, inner)),
+                    "; "
+                )
+            ));
+        }
+        for absence in &connection.absences {
+            components.push(format!(
+                "&{}.absent {} where {}",
+                absence.source_bag,
+                join(
+                    absence.patterns.iter().map(|p| self.pattern(p, inner)),
+                    "; "
+                ),
+                self.expression(&absence.guard, inner)
+            ));
+        }
+        if !connection.patterns.assignments.is_empty() {
+            components.push(format!(
+                "let {}",
+                self.assignments(&connection.patterns, inner)
+            ));
+        }
+        if let Some(guard) = without_true(&connection.guard) {
+            components.push(format!("guard {}", self.expression(&guard, inner)));
+        }
+        // Producers come last, their expressions would take a following
+        // `let` or `guard` for an identifier.
+        for producer in &connection.producers {
+            components.push(format!(
+                "&{}.produce {}",
+                producer.target_bag,
+                join(
+                    producer
+                        .projections
+                        .iter()
+                        .map(|e| self.expression(e, inner)),
+                    "; "
+                )
+            ));
+        }
+
+        let signature = &connection.signature;
+        let parameter = match &signature.parameter {
+            Pattern::Discard => String::new(),
+            p => self.pattern(p, indent),
+        };
+        let head = format!(".connection {}({parameter})", signature.name);
+
+        if self.width == usize::MAX {
+            format!("{head} {{ {} }}", components.join("; "))
+        } else {
+            let separator = format!(";\n{}", INDENT.repeat(inner));
+            format!(
+                "{head} {{\n{}{}\n{}}}",
+                INDENT.repeat(inner),
+                components.join(&separator),
+                INDENT.repeat(indent)
+            )
+        }
+    }
+
+    fn assignments(&self, assignments: &AssignmentSet, indent: usize) -> String {
+        join(
+            assignments.assignments.iter().map(|a| {
+                format!(
+                    "{} = {}",
+                    self.pattern(&a.pattern, indent),
+                    self.expression(&a.expression, indent)
+                )
+            }),
+            "; ",
+        )
+    }
+
+    fn expressions(&self, expressions: &[Expression], indent: usize) -> String {
+        join(expressions.iter().map(|e| self.expression(e, indent)), "; ")
+    }
+
+    fn arguments(&self, arguments: &[Expression], indent: usize) -> String {
+        self.list("(", ")", indent, |p, indent| {
+            arguments.iter().map(|a| p.expression(a, indent)).collect()
+        })
+    }
+
+    fn expression(&self, expression: &Expression, indent: usize) -> String {
+        match expression {
+            Expression::Literal(l) => l.to_string(),
+            Expression::Identifier(id) => id.to_string(),
+            Expression::Array(items) => self.list("[", "]", indent, |p, indent| {
+                items
+                    .iter()
+                    .map(|item| match item {
+                        ArrayItem::Single(e) => p.expression(e, indent),
+                        ArrayItem::Spread(e) => format!("...{}", p.expression(e, indent)),
+                    })
+                    .collect()
+            }),
+            Expression::Object(properties) => self.list("{", "}", indent, |p, indent| {
+                properties
+                    .iter()
+                    .map(|property| match property {
+                        ObjectProperty::Single(id) => id.to_string(),
+                        ObjectProperty::Property(Property { key, value }) => {
+                            let key = match key {
+                                PropertyKey::Identifier(id) if is_identifier_name(&id.name) => {
+                                    id.name.to_string()
+                                }
+                                PropertyKey::Identifier(id) => format!("\"{}\"", id.name),
+                                PropertyKey::Expression(e) => {
+                                    format!("[{}]", p.expression(e, indent))
+                                }
+                            };
+                            format!("{key}: {}", p.expression(value, indent))
+                        }
+                        ObjectProperty::Spread(e) => format!("...{}", p.expression(e, indent)),
+                    })
+                    .collect()
+            }),
+            Expression::Binary(BinaryExpression {
+                operator,
+                left,
+                right,
+            }) => {
+                let level = precedence(expression);
+                let (left_level, right_level) = match operator {
+                    BinaryOperator::Is | BinaryOperator::Matches => (level + 1, level + 2),
+                    _ => (level, level + 1),
+                };
+                format!(
+                    "{} {operator} {}",
+                    self.operand(left, left_level, indent),
+                    self.operand(right, right_level, indent)
+                )
+            }
+            Expression::Logical(LogicalExpression {
+                operator,
+                left,
+                right,
+            }) => {
+                let level = precedence(expression);
+                let operator = match operator {
+                    LogicalOperator::Or => "||",
+                    LogicalOperator::And => "&&",
+                };
+                format!(
+                    "{} {operator} {}",
+                    self.operand(left, level, indent),
+                    self.operand(right, level + 1, indent)
+                )
+            }
+            Expression::Collate(comparison, collation) => {
+                format!(
+                    "{} collate {collation}",
+                    self.operand(comparison, precedence(expression), indent)
+                )
+            }
+            Expression::Unary(UnaryExpression { operator, argument }) => {
+                let (operator, level) = match operator {
+                    UnaryOperator::Not => ("!", PRIMARY),
+                    UnaryOperator::Minus => ("-", MEMBER),
+                    UnaryOperator::Plus => ("+", MEMBER),
+                };
+                let argument = self.operand(argument, level, indent);
+                // `-1` would be read as a negative number, `--x` is fine
+                // but `- -x` reads better as `-(-x)`.
+                if operator != "!"
+                    && argument
+                        .starts_with(['-', '+', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9'])
+                {
+                    format!("{operator}({argument})")
+                } else {
+                    format!("{operator}{argument}")
+                }
+            }
+            Expression::Member(MemberExpression { object, property }) => match property.as_ref() {
+                Expression::Literal(Literal::String(name)) if is_identifier_name(name) => {
+                    // Only primary expressions and other `.` accesses can be
+                    // followed by `.`, not indexed ones.
+                    let object = match object.as_ref() {
+                        Expression::Member(m) if !is_dot_access(m) => {
+                            format!("({})", self.expression(object, indent))
+                        }
+                        object => self.operand(object, MEMBER, indent),
+                    };
+                    format!("{object}.{name}")
+                }
+                property => format!(
+                    "{}[{}]",
+                    self.operand(object, MEMBER, indent),
+                    self.expression(property, indent)
+                ),
+            },
+            Expression::Call(CallExpression {
+                function,
+                arguments,
+            }) => format!("{function}{}", self.arguments(arguments, indent)),
+            Expression::Template(t) => template(t),
+            Expression::Lambda(LambdaExpression { parameter, body }) => format!(
+                "fn({}) => {}",
+                self.pattern(parameter, indent),
+                self.expression(body, indent)
+            ),
+            Expression::Let(LetExpression {
+                pattern,
+                value,
+                body,
+            }) => format!(
+                "let {} = {} in {}",
+                self.pattern(pattern, indent),
+                self.operand(value, ADDITIVE, indent),
+                self.expression(body, indent)
+            ),
+            Expression::Pattern(p) => format!("pattern({})", self.pattern(p, indent)),
+            Expression::Quote(e) => format!("quote({})", self.expression(e, indent)),
+        }
+    }
+
+    fn operand(&self, expression: &Expression, level: u8, indent: usize) -> String {
+        if precedence(expression) < level {
+            format!("({})", self.expression(expression, indent))
+        } else {
+            self.expression(expression, indent)
+        }
+    }
+
+    fn pattern(&self, pattern: &Pattern, indent: usize) -> String {
+        match pattern {
+            Pattern::Discard => "_".into(),
+            Pattern::Identifier(id) => id.to_string(),
+            Pattern::TypedDiscard(t) => format!("_ is {t}"),
+            Pattern::TypedIdentifier(id, t) => format!("{id} is {t}"),
+            Pattern::Literal(l) => l.to_string(),
+            Pattern::Regex(r) => format!("/{r}/"),
+            Pattern::Capture(id, p) => match p.as_ref() {
+                Pattern::Literal(_)
+                | Pattern::Array(..)
+                | Pattern::Object(..)
+                | Pattern::Descendant(_) => {
+                    format!("{id} @ {}", self.pattern(p, indent))
+                }
+                p => format!("{id} @ ({})", self.pattern(p, indent)),
+            },
+            Pattern::Array(items, rest) => self.list("[", "]", indent, |p, indent| {
+                items
+                    .iter()
+                    .map(|item| match item {
+                        ArrayPatternItem::Pattern(item) => p.pattern(item, indent),
+                    })
+                    .chain(p.rest(rest, indent))
+                    .collect()
+            }),
+            Pattern::Object(properties, rest) => self.list("{", "}", indent, |p, indent| {
+                properties
+                    .iter()
+                    .map(|property| match property {
+                        ObjectPropertyPattern::Single(id) => id.to_string(),
+                        ObjectPropertyPattern::Match(PropertyPattern { key, value }) => {
+                            match (key, value) {
+                                (PropertyKey::Identifier(id), Pattern::Guarded(bound, guard))
+                                    if is_pattern_identifier(bound, &id.name) =>
+                                {
+                                    format!("{id} if {}", p.expression(guard, indent))
+                                }
+                                (PropertyKey::Identifier(id), value)
+                                    if is_identifier_name(&id.name) =>
+                                {
+                                    format!("{}: {}", id.name, p.pattern(value, indent))
+                                }
+                                (PropertyKey::Identifier(id), value) => {
+                                    format!("\"{}\": {}", id.name, p.pattern(value, indent))
+                                }
+                                (PropertyKey::Expression(e), value) => {
+                                    format!(
+                                        "[{}]: {}",
+                                        p.expression(e, indent),
+                                        p.pattern(value, indent)
+                                    )
+                                }
+                            }
+                        }
+                    })
+                    .chain(p.rest(rest, indent))
+                    .collect()
+            }),
+            Pattern::Or(alternatives) => join(
+                alternatives.iter().map(|a| match a {
+                    Pattern::Or(_) | Pattern::Guarded(..) => {
+                        format!("({})", self.pattern(a, indent))
+                    }
+                    a => self.pattern(a, indent),
+                }),
+                " | ",
+            ),
+            Pattern::Descendant(p) => match p.as_ref() {
+                Pattern::Or(_) | Pattern::Guarded(..) => format!("**({})", self.pattern(p, indent)),
+                p => format!("**{}", self.pattern(p, indent)),
+            },
+            Pattern::Guarded(p, guard) => format!(
+                "{} if {}",
+                self.pattern(p, indent),
+                self.expression(guard, indent)
+            ),
+        }
+    }
+
+    fn rest(&self, rest: &Rest, indent: usize) -> Option<String> {
+        match rest {
+            Rest::Exact => None,
+            Rest::Discard => Some("...".into()),
+            Rest::Collect(p) => Some(format!("...{}", self.pattern(p, indent))),
+        }
+    }
+
+    // The items on one line if they fit, otherwise on their own lines
+    // indented one level deeper than the closing bracket.
+    fn list(
+        &self,
+        open: &str,
+        close: &str,
+        indent: usize,
+        items: impl Fn(&Printer, usize) -> Vec<String>,
+    ) -> String {
+        let flat = format!("{open}{}{close}", items(&Printer::FLAT, 0).join(", "));
+        if self.width == usize::MAX || INDENT.len() * indent + flat.chars().count() <= self.width {
+            return flat;
+        }
+
+        let items = items(self, indent + 1);
+        if items.is_empty() {
+            return flat;
+        }
+        let separator = format!(",\n{}", INDENT.repeat(indent + 1));
+        format!(
+            "{open}\n{}{}\n{}{close}",
+            INDENT.repeat(indent + 1),
+            items.join(&separator),
+            INDENT.repeat(indent)
+        )
+    }
+}
+
+// The binding strength of the expression, following the levels of the
+// parser from `||` up to primary expressions. Lambdas and `let` extend as
+// far as possible, so they are put in parentheses wherever they are an
+// operand.
+const ADDITIVE: u8 = 6;
+const MEMBER: u8 = 10;
+const PRIMARY: u8 = 11;
+
+fn precedence(expression: &Expression) -> u8 {
+    match expression {
+        Expression::Lambda(_) | Expression::Let(_) => 0,
+        Expression::Logical(LogicalExpression {
+            operator: LogicalOperator::Or,
+            ..
+        }) => 1,
+        Expression::Logical(_) => 2,
+        Expression::Binary(BinaryExpression { operator, .. }) => match operator {
+            BinaryOperator::Is | BinaryOperator::Matches => 3,
+            BinaryOperator::Cast => 4,
+            BinaryOperator::Plus | BinaryOperator::Minus => ADDITIVE,
+            BinaryOperator::Times | BinaryOperator::Over | BinaryOperator::Mod => 7,
+            BinaryOperator::PowerOf => 8,
+            _ => 5,
+        },
+        Expression::Collate(..) => 5,
+        Expression::Unary(_) => 9,
+        Expression::Member(_) => MEMBER,
+        _ => PRIMARY,
+    }
+}
+
+fn is_dot_access(member: &MemberExpression) -> bool {
+    matches!(member.property.as_ref(), Expression::Literal(Literal::String(name)) if is_identifier_name(name))
+}
+
+// Whether the name can be written as it is as a property name, see
+// `identifier_name` in the parser.
+pub(crate) fn is_identifier_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some('_') => {
+            let rest = chars.as_str();
+            !rest.is_empty() && rest.chars().all(unicode_ident::is_xid_continue)
+        }
+        Some(c) if unicode_ident::is_xid_start(c) => chars.all(unicode_ident::is_xid_continue),
+        _ => false,
+    }
+}
+
+fn is_identifier(expression: &Expression, name: &str) -> bool {
+    matches!(expression, Expression::Identifier(id) if id.name == name)
+}
+
+fn is_pattern_identifier(pattern: &Pattern, name: &str) -> bool {
+    matches!(pattern, Pattern::Identifier(id) if id.name == name)
+}
+
+fn is_true(expression: &Expression) -> bool {
+    matches!(expression, Expression::Literal(Literal::Boolean(true)))
+}
+
+// The guard without the `true` conjuncts added by the parser, None if
+// nothing else is left.
+fn without_true<'s>(expression: &Expression<'s>) -> Option<Expression<'s>> {
+    match expression {
+        e if is_true(e) => None,
+        Expression::Logical(LogicalExpression {
+            operator: LogicalOperator::And,
+            left,
+            right,
+        }) => match (without_true(left), without_true(right)) {
+            (None, rest) | (rest, None) => rest,
+            (Some(left), Some(right)) => Some(Expression::Logical(LogicalExpression {
+                operator: LogicalOperator::And,
+                left: Box::new(left),
+                right: Box::new(right),
+            })),
+        },
+        e => Some(e.clone()),
+    }
+}
+
+// Puts the calls of the aggregate functions back in place of the bindings
+// the parser replaced them with.
+fn with_aggregates<'s>(
+    expression: &Expression<'s>,
+    aggregates: &[Aggregate<'s>],
+) -> Expression<'s> {
+    expression.rewrite(&mut |e| {
+        let Expression::Identifier(id) = e else {
+            return None;
+        };
+        let aggregate = aggregates.iter().find(|a| &a.binding == id)?;
+        Some(Expression::Call(CallExpression {
+            function: Identifier {
+                name: Cow::Borrowed(aggregate.function.name()),
+            },
+            arguments: aggregate.argument.iter().cloned().collect(),
+        }))
+    })
+}
+
+fn suffix(format: DataFormat) -> &'static str {
+    match format {
+        DataFormat::Lines => "",
+        DataFormat::Json => "_json",
+        DataFormat::Ndjson => "_ndjson",
+        DataFormat::Parquet => "_parquet",
+        DataFormat::Yaml => "_yaml",
+        DataFormat::Toml => "_toml",
+        DataFormat::Xml => "_xml",
+    }
+}
+
+fn verified(verify: bool) -> &'static str {
+    if verify {
+        " verify"
+    } else {
+        ""
+    }
+}
+
+fn clause(keyword: &str, value: Option<usize>) -> String {
+    match value {
+        Some(value) => format!(" {keyword} {value}"),
+        None => String::new(),
+    }
+}
+
+fn join<T: ToString>(items: impl Iterator<Item = T>, separator: &str) -> String {
+    items
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
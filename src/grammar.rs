@@ -0,0 +1,97 @@
+// The grammar of damasc, split into parsers of its fragments. Tools like
+// formatters, highlighters or tests can parse an expression, a pattern or a
+// single clause without wrapping it into a statement.
+//
+// A fragment parser consumes a prefix of its input and returns the rest
+// together with its result, so fragments compose with the combinators of
+// nom the same way the statements are built from them. `parse` runs a
+// fragment parser on a complete input.
+
+use crate::assignment::AssignmentSet;
+use crate::expression::{Expression, ExpressionSet};
+use crate::identifier::Identifier;
+use crate::parser::{self, Expected, ParseError, ParseFailure};
+use crate::pattern::Pattern;
+use crate::query::OrderBy;
+use crate::statement::Statement;
+
+// Incremented whenever an input accepted before is rejected or parsed into
+// something else. New syntax alone keeps the version.
+pub const VERSION: u32 = 3;
+
+pub type Fragment<'i, O> = nom::IResult<&'i str, O, ParseFailure<&'i str>>;
+
+// Runs a fragment parser on the whole input. Surrounding whitespace is
+// allowed, anything else left over is an error.
+pub fn parse<'i, O>(
+    mut fragment: impl FnMut(&'i str) -> Fragment<'i, O>,
+    input: &'i str,
+) -> Result<O, ParseError> {
+    match fragment(input.trim_start()) {
+        Ok((rest, output)) if rest.trim().is_empty() => Ok(output),
+        Ok((rest, _)) => Err(ParseError::new(
+            input,
+            nom::Err::Error(ParseFailure {
+                remaining: rest.trim_start(),
+                expected: vec![Expected::EndOfInput],
+            }),
+        )),
+        Err(e) => Err(ParseError::new(input, e)),
+    }
+}
+
+pub fn statement<'i, 'a, 'b>(input: &'i str) -> Fragment<'i, Statement<'a, 'b>> {
+    parser::statement(input)
+}
+
+pub fn expression<'i, 'v>(input: &'i str) -> Fragment<'i, Expression<'v>> {
+    parser::expression(input)
+}
+
+// Expressions separated by `;`, up to the end of the input.
+pub fn expressions<'i, 'v>(input: &'i str) -> Fragment<'i, ExpressionSet<'v>> {
+    parser::expression_multi(input)
+}
+
+pub fn pattern<'i, 'v>(input: &'i str) -> Fragment<'i, Pattern<'v>> {
+    parser::pattern(input)
+}
+
+// Patterns matched against expressions, eg `[x, y] = [1, 2]; z = 3`.
+pub fn assignments<'i, 'v>(input: &'i str) -> Fragment<'i, AssignmentSet<'v, 'v>> {
+    parser::assignment_list(input)
+}
+
+pub fn identifier<'i, 'v>(input: &'i str) -> Fragment<'i, Identifier<'v>> {
+    parser::identifier(input)
+}
+
+// `into <expression>`
+pub fn into_clause<'i, 'v>(input: &'i str) -> Fragment<'i, Expression<'v>> {
+    parser::into_clause(input)
+}
+
+// `where <guard>`
+pub fn where_clause<'i, 'v>(input: &'i str) -> Fragment<'i, Expression<'v>> {
+    parser::where_clause(input)
+}
+
+// `group by <expression>`
+pub fn group_clause<'i, 'v>(input: &'i str) -> Fragment<'i, Expression<'v>> {
+    parser::group_clause(input)
+}
+
+// `order by <expression> [collate <collation>] [asc|desc]`
+pub fn order_clause<'i, 'v>(input: &'i str) -> Fragment<'i, OrderBy<'v>> {
+    parser::order_clause(input)
+}
+
+// `limit <n>`
+pub fn limit_clause(input: &str) -> Fragment<'_, usize> {
+    parser::limit_clause(input)
+}
+
+// `offset <n>`
+pub fn offset_clause(input: &str) -> Fragment<'_, usize> {
+    parser::offset_clause(input)
+}
@@ -1,19 +1,58 @@
 use std::collections::{BTreeSet, HashMap, HashSet};
 
-use crate::{identifier::Identifier, expression::Expression, pattern::Pattern, assignment::AssignmentSet, literal::Literal, topology::{TopologyError, sort_topological, Node}};
+use serde::{Deserialize, Serialize};
+
+use crate::{identifier::Identifier, expression::Expression, formatter, pattern::Pattern, assignment::AssignmentSet, topology::{TopologyError, sort_topological, Node}, network::Network, plan::Plans};
 
 #[derive(Clone)]
 pub struct Graph<'s> {
-    pub(crate) connections: HashMap<Identifier<'s>, Connection<'s>>
+    pub(crate) connections: HashMap<Identifier<'s>, Connection<'s>>,
+    pub(crate) network: Network<'s>,
+    // Pairs of a connection and the bag whose insertions run it.
+    pub(crate) triggers: BTreeSet<(Identifier<'s>, Identifier<'s>)>,
 }
 
 impl<'s> Graph<'s> {
     pub(crate) fn new() -> Self {
         Self {
             connections: HashMap::new(),
+            network: Network::default(),
+            triggers: BTreeSet::new(),
         }
     }
 
+    pub(crate) fn connect(&mut self, name: Identifier<'s>, connection: Connection<'s>) {
+        self.network.add(&name, &connection);
+        self.connections.insert(name, connection);
+    }
+
+    pub(crate) fn disconnect(&mut self, name: &Identifier<'s>) -> bool {
+        self.network.remove(name);
+        self.triggers.retain(|(connection, _)| connection != name);
+        self.connections.remove(name).is_some()
+    }
+
+    pub(crate) fn add_trigger(&mut self, connection: Identifier<'s>, bag: Identifier<'s>) -> bool {
+        self.triggers.insert((connection, bag))
+    }
+
+    pub(crate) fn rename_bag(&mut self, from: &Identifier<'s>, to: &Identifier<'s>) {
+        self.triggers = std::mem::take(&mut self.triggers)
+            .into_iter()
+            .map(|(connection, bag)| (connection, if &bag == from { to.clone() } else { bag }))
+            .collect();
+    }
+
+    pub(crate) fn triggered_by<'x>(
+        &'x self,
+        bag: &'x Identifier<'s>,
+    ) -> impl Iterator<Item = &'x Identifier<'s>> {
+        self.triggers
+            .iter()
+            .filter(move |(_, b)| b == bag)
+            .map(|(connection, _)| connection)
+    }
+
     pub(crate) fn bags(&'s self) -> BTreeSet<Identifier<'s>> {
         self.connections.values().flat_map(|con| {
             con.bags()
@@ -26,15 +65,20 @@ impl std::fmt::Display for Graph<'_> {
         for con in self.connections.values() {
             writeln!(f,"{con}")?;
         }
+        for (connection, bag) in &self.triggers {
+            writeln!(f, ".trigger {connection} on insert into {bag}")?;
+        }
         Ok(())
     }
 }
 
-#[derive(Clone,Debug)]
+#[derive(Clone,Debug, Serialize, Deserialize)]
 pub struct Connection<'s> {
     pub(crate) signature: Signature<'s>,
     pub(crate) consumers: Vec<Consumer<'s>>,
     pub(crate) producers: Vec<Producer<'s>>,
+    #[serde(default)]
+    pub(crate) absences: Vec<Absence<'s>>,
     pub(crate) patterns: AssignmentSet<'s,'s>,
     pub(crate) guard: Expression<'s>,
 }
@@ -45,6 +89,8 @@ impl<'s> Connection<'s> {
     pub(crate) fn bags(&'s self) -> impl Iterator<Item = &Identifier<'s>> {
         self.consumers.iter().map(|c| &c.source_bag).chain(
             self.producers.iter().map(|p| &p.target_bag)
+        ).chain(
+            self.absences.iter().map(|a| &a.source_bag)
         )
     }
     pub fn sort_topological<'x>(
@@ -62,60 +108,30 @@ impl<'s> Connection<'s> {
 
 impl std::fmt::Display for Connection<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, ".connection ")?;
-        write!(f, "{}({})", self.signature.name, self.signature.parameter)?;
-        writeln!(f,"{{")?;
-
-        for c in &self.consumers {
-            write!(f, "  &{}.{} ", c.source_bag, match c.consumption {
-                Consumption::Test => "test",
-                Consumption::Take => "consume",
-            })?;
-            for p in &c.patterns {
-                write!(f, "{p};")?;
-            }
-        }
-
-        for c in &self.producers {
-            write!(f, "  &{}.produce ", c.target_bag)?;
-            for p in &c.projections {
-                write!(f, "{p};")?;
-            }
-            writeln!(f,";")?;
-        }
-
-        if !self.patterns.assignments.is_empty() {
-            write!(f,"  let ")?;
-            for p in &self.patterns.assignments {
-                write!(f, "{p},")?;
-            }
-            writeln!(f,";")?;
-        }
-        if !matches!(self.guard, Expression::Literal(Literal::Boolean(true))) {
-            writeln!(f,"  guard {}", self.guard)?;
-        }
-        writeln!(f,"}}")
+        write!(f, "{}", formatter::connection(self))
     }
 }
 
 
-#[derive(Clone,Debug)]
+#[derive(Clone,Debug, Serialize, Deserialize)]
 pub(crate) struct Signature<'s> {
     pub(crate) name: Identifier<'s>,
     pub(crate) parameter: Pattern<'s>,
 }
 
-#[derive(Clone,Debug)]
+#[derive(Clone,Debug, Serialize, Deserialize)]
 pub(crate) enum Consumption {
     Test,
     Take,
 }
 
-#[derive(Clone,Debug)]
+#[derive(Clone,Debug, Serialize, Deserialize)]
 pub(crate) struct Consumer<'s> {
     pub(crate) consumption: Consumption,
     pub(crate) source_bag: Identifier<'s>,
     pub(crate) patterns: Vec<Pattern<'s>>,
+    #[serde(skip)]
+    pub(crate) plans: Plans,
 }
 
 impl Node for Consumer<'_> {
@@ -133,7 +149,19 @@ impl Node for Consumer<'_> {
     }
 }
 
-#[derive(Clone,Debug)]
+// Values that must not exist for the connection to fire. Its patterns and
+// guard are checked after all consumers matched, so they can refer to their
+// bindings.
+#[derive(Clone,Debug, Serialize, Deserialize)]
+pub(crate) struct Absence<'s> {
+    pub(crate) source_bag: Identifier<'s>,
+    pub(crate) patterns: Vec<Pattern<'s>>,
+    pub(crate) guard: Expression<'s>,
+    #[serde(skip)]
+    pub(crate) plans: Plans,
+}
+
+#[derive(Clone,Debug, Serialize, Deserialize)]
 pub(crate) struct Producer<'s> {
     pub(crate) target_bag: Identifier<'s>,
     pub(crate) projections: Vec<Expression<'s>>,
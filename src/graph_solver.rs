@@ -1,6 +1,7 @@
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 
-use crate::{bag_bundle::BagBundle, env::Environment, graph::{Connection, Consumer, Producer, Consumption}, matcher::Matcher, value::Value, identifier::Identifier, query::check_value};
+use crate::{bag::ItemFilter, bag_bundle::BagBundle, env::Environment, graph::{Absence, Connection, Consumer, Producer, Consumption}, matcher::{Bindings, Matcher}, value::Value, identifier::Identifier, query::check_value};
 use gen_iter::gen_iter;
 
 pub(crate) struct GraphSolver<'bb, 'ei,'es, 'ev> {
@@ -10,9 +11,9 @@ pub(crate) struct GraphSolver<'bb, 'ei,'es, 'ev> {
 
 #[derive(Clone,Debug)]
 pub(crate) struct ChangeSet<'s,'v> {
-    deletions: BTreeMap<Identifier<'s>, Vec<usize>>,
-    touches: BTreeMap<Identifier<'s>, Vec<usize>>,
-    insertions: BTreeMap<Identifier<'s>, Vec<Value<'s, 'v>>>,
+    pub(crate) deletions: BTreeMap<Identifier<'s>, Vec<usize>>,
+    pub(crate) touches: BTreeMap<Identifier<'s>, Vec<usize>>,
+    pub(crate) insertions: BTreeMap<Identifier<'s>, Vec<Value<'s, 'v>>>,
 }
 impl ChangeSet<'_,'_> {
     fn new() -> Self {
@@ -22,6 +23,49 @@ impl ChangeSet<'_,'_> {
             insertions: BTreeMap::new(),
         }
     }
+
+    fn uses(&self, bag: &Identifier, position: usize) -> bool {
+        self.deletions.get(bag).into_iter().chain(self.touches.get(bag)).flatten().any(|p| *p == position)
+    }
+
+    // Two change sets can not both be applied if one of them deletes a value
+    // the other one consumes or tests.
+    pub(crate) fn conflicts(&self, other: &Self) -> bool {
+        self.deletions.iter().any(|(bag, positions)| positions.iter().any(|p| other.uses(bag, *p)))
+            || other.deletions.iter().any(|(bag, positions)| positions.iter().any(|p| self.uses(bag, *p)))
+    }
+}
+
+// Keeps the change sets that can be applied together, greedily in the order
+// they are found.
+pub(crate) fn compatible<'s, 'v>(changesets: impl IntoIterator<Item = ChangeSet<'s, 'v>>) -> Vec<ChangeSet<'s, 'v>> {
+    compatible_by(changesets.into_iter().map(|c| ((), c))).into_iter().map(|(_, c)| c).collect()
+}
+
+// Like `compatible`, keeping what each change set is tagged with, eg the
+// connection it was found for.
+pub(crate) fn compatible_by<'s, 'v, T>(changesets: impl IntoIterator<Item = (T, ChangeSet<'s, 'v>)>) -> Vec<(T, ChangeSet<'s, 'v>)> {
+    let mut accepted = Vec::<(T, ChangeSet)>::new();
+    for (tag, changeset) in changesets {
+        if !accepted.iter().any(|(_, a)| a.conflicts(&changeset)) {
+            accepted.push((tag, changeset));
+        }
+    }
+    accepted
+}
+
+// The number of values deleted from and inserted into each bag.
+pub(crate) fn counts<'s>(changesets: &[ChangeSet<'s, '_>]) -> BTreeMap<Identifier<'s>, (usize, usize)> {
+    let mut counts = BTreeMap::<Identifier, (usize, usize)>::new();
+    for changeset in changesets {
+        for (bag, positions) in &changeset.deletions {
+            counts.entry(bag.clone()).or_default().0 += positions.len();
+        }
+        for (bag, values) in &changeset.insertions {
+            counts.entry(bag.clone()).or_default().1 += values.len();
+        }
+    }
+    counts
 }
 
 impl<'bb, 'ei,'es, 'ev> GraphSolver<'bb,'ei,'es, 'ev> {
@@ -34,46 +78,108 @@ impl<'bb, 'ei,'es, 'ev> GraphSolver<'bb,'ei,'es, 'ev> {
 
     pub fn solve<'slf, 'con:'slf>(&'slf self, connection: &'con Connection<'es>, argument: Option<Value<'es,'ev>>)
     -> Box<dyn Iterator<Item = ChangeSet<'es, 'ev>> + 'slf> {
-        let mut matcher = Matcher::new(&self.env);
+        Box::new(self.solve_with_pivot(connection, argument, None, false).map(|(changeset, _)| changeset))
+    }
+
+    pub fn solve_incremental<'slf, 'con:'slf>(&'slf self, connection: &'con Connection<'es>, argument: Option<Value<'es,'ev>>)
+    -> Box<dyn Iterator<Item = ChangeSet<'es, 'ev>> + 'slf> {
+        Box::new(self.solve_with_pivots(connection, argument, false).map(|(changeset, _)| changeset))
+    }
+
+    // The change sets along with the bindings of the matcher they were
+    // produced by, eg to step through them.
+    pub fn solutions<'slf, 'con:'slf>(&'slf self, connection: &'con Connection<'es>, argument: Option<Value<'es,'ev>>, incremental: bool)
+    -> Box<dyn Iterator<Item = (ChangeSet<'es, 'ev>, Bindings<'ei, 'es, 'ev>)> + 'slf> {
+        let solutions = if incremental {
+            self.solve_with_pivots(connection, argument, true)
+        } else {
+            self.solve_with_pivot(connection, argument, None, true)
+        };
+
+        Box::new(solutions.filter_map(|(changeset, bindings)| Some((changeset, bindings?))))
+    }
+
+    fn solve_with_pivots<'slf, 'con:'slf>(&'slf self, connection: &'con Connection<'es>, argument: Option<Value<'es,'ev>>, with_bindings: bool)
+    -> Box<dyn Iterator<Item = (ChangeSet<'es, 'ev>, Option<Bindings<'ei, 'es, 'ev>>)> + 'slf> {
+        let positions = connection.consumers.iter().map(|c| c.patterns.len()).sum::<usize>();
+
+        Box::new((0..positions).flat_map(move |pivot| {
+            self.solve_with_pivot(connection, argument.clone(), Some(pivot), with_bindings)
+        }))
+    }
+
+    // The bindings are only cloned for each solution if asked for.
+    fn solve_with_pivot<'slf, 'con:'slf>(&'slf self, connection: &'con Connection<'es>, argument: Option<Value<'es,'ev>>, pivot: Option<usize>, with_bindings: bool)
+    -> Box<dyn Iterator<Item = (ChangeSet<'es, 'ev>, Option<Bindings<'ei, 'es, 'ev>>)> + 'slf> {
+        let matcher = Matcher::new(&self.env);
         let changeset = ChangeSet::new();
 
-        if let Some(v) = argument {
-            let Ok(()) = matcher.match_pattern(&connection.signature.parameter, &v) else {
-                return Box::new(None.into_iter());
-            };
-        }
+        let matchers: Box<dyn Iterator<Item = _> + 'slf> = match argument {
+            Some(v) => Box::new(matcher.match_all(&connection.signature.parameter, Cow::Owned(v))),
+            None => Box::new(std::iter::once(matcher)),
+        };
         
         Box::new(gen_iter!(move {
-            for (cc, mc) in self.solve_consumers(&connection.consumers, matcher, changeset) {
-                match mc.clone().into_env().eval_expr(&connection.guard) {
-                    Ok(Value::Boolean(true)) => {
-                        for cp in self.solve_producers(&connection.producers, mc, cc) {
-                            yield cp
-                        }
-                    },
-                    _ => {}
+            for matcher in matchers {
+                for (cc, mc) in self.solve_consumers(&connection.consumers, matcher, changeset.clone(), 0, pivot) {
+                    if !self.all_absent(&connection.absences, &mc) {
+                        continue;
+                    }
+                    match mc.clone().into_env().eval_expr(&connection.guard) {
+                        Ok(Value::Boolean(true)) => {
+                            let bindings = with_bindings.then(|| mc.bindings.clone());
+                            for cp in self.solve_producers(&connection.producers, mc, cc) {
+                                yield (cp, bindings.clone())
+                            }
+                        },
+                        _ => {}
+                    }
                 }
             }
         }))
     }
 
 
+    // True if no values of the absence bags match the bindings of the consumers.
+    fn all_absent<'slf, 'con:'slf>(&'slf self,
+    absences: &'con [Absence<'es>],
+    matcher: &Matcher<'ei,'es, 'ev,'slf>) -> bool {
+        absences.iter().all(|absence| {
+            let Some(bag) = self.bag_bundle.bag(&absence.source_bag) else {
+                return true;
+            };
+            let duplicates = Vec::with_capacity(absence.patterns.len());
+            !bag.cross_query_helper(false, duplicates, matcher.clone(), &absence.patterns, absence.plans.of(&absence.patterns), ItemFilter::All, None)
+                .any(|(m, _)| matches!(m.into_env().eval_expr(&absence.guard), Ok(Value::Boolean(true))))
+        })
+    }
+
     fn solve_consumers<'slf, 'con:'slf>(&'slf self, 
     consumers: &'con [Consumer<'es>], 
     matcher: Matcher<'ei,'es, 'ev,'slf>,
-    changeset: ChangeSet<'es, 'ev>) 
+    changeset: ChangeSet<'es, 'ev>,
+    position: usize,
+    pivot: Option<usize>) 
     -> Box<dyn Iterator<Item = (ChangeSet<'es, 'ev>, Matcher<'ei,'es, 'ev,'slf>)> + 'slf>{
         let Some(consumer) = consumers.get(0) else {
             return Box::new(Some((changeset, matcher)).into_iter())
         };
-        let Some(test_bag) = self.bag_bundle.bags.get(&consumer.source_bag) else {
+        let Some(test_bag) = self.bag_bundle.bag(&consumer.source_bag) else {
             return Box::new(None.into_iter());
         };
         let duplicates = Vec::with_capacity(consumer.patterns.len());
         let matcher = matcher.clone();
+        let filter = match pivot {
+            Some(pivot) => ItemFilter::Pivot {
+                new_items: self.bag_bundle.changes.get(&consumer.source_bag),
+                position,
+                pivot,
+            },
+            None => ItemFilter::All,
+        };
         
         Box::new(gen_iter!(move {
-            for (m, dups) in test_bag.cross_query_helper(false, duplicates, matcher, &consumer.patterns) {
+            for (m, dups) in test_bag.cross_query_helper(false, duplicates, matcher, &consumer.patterns, consumer.plans.of(&consumer.patterns), filter, None) {
                 let mut cs_new = changeset.clone();
                 match consumer.consumption {
                     Consumption::Test => {
@@ -83,7 +189,7 @@ impl<'bb, 'ei,'es, 'ev> GraphSolver<'bb,'ei,'es, 'ev> {
                         cs_new.deletions.entry(consumer.source_bag.clone()).or_insert(Vec::new()).append(&mut dups.clone())
                     },
                 }
-                for (cs, mm) in self.solve_consumers(&consumers[1..], m, cs_new) {
+                for (cs, mm) in self.solve_consumers(&consumers[1..], m, cs_new, position + consumer.patterns.len(), pivot) {
                     yield (cs, mm);
                 }
             }
@@ -115,7 +221,7 @@ impl<'bb, 'ei,'es, 'ev> GraphSolver<'bb,'ei,'es, 'ev> {
         Box::new(gen_iter!(move {
             for p in &producer.projections {
                 let mut env = self.env.clone();
-                matcher.clone().into_env().merge(&mut env);
+                matcher.merge_into(&mut env);
 
                 match env.eval_expr(p) {
                     Ok(v) => {
@@ -0,0 +1,559 @@
+// Documentation of every command. Completion takes its command names from
+// this table and the aliases and the tests check that every example parses, so the help
+// can not drift from the parser unnoticed.
+pub struct HelpEntry {
+    pub command: &'static str,
+    pub syntax: &'static [&'static str],
+    pub summary: &'static str,
+    pub examples: &'static [&'static str],
+}
+
+pub const HELP: &[HelpEntry] = &[
+    HelpEntry {
+        command: "ast",
+        syntax: &[".ast <statement>"],
+        summary: "Shows the syntax tree of a statement as JSON.",
+        examples: &[".ast .query x where x > 5"],
+    },
+    HelpEntry {
+        command: "bag",
+        syntax: &[
+            ".bag",
+            ".bag <name>",
+            ".bag <name> as <pattern> [where <guard>] [limit <n>] [distinct|counted]",
+            ".bag <name> as virtual <start>..[<end>]",
+            ".bag $bags|$connections|$settings|$history",
+        ],
+        summary: "Shows the current bag or switches to another one, creating it if \
+            it does not exist. A new bag only accepts values matching the pattern \
+            and guard, up to the limit. A distinct bag ignores duplicates, a counted \
+            bag stores them once along with their count, see count_of. Virtual \
+            bags hold a range of integers. The read-only system bags starting with \
+            $ describe the bags, connections, settings and input history.",
+        examples: &[
+            ".bag people",
+            ".bag adults as {age, ...} where age >= 18",
+            ".bag tags as _ is String distinct",
+            ".bag digits as virtual 0..10",
+            ".bag $bags",
+        ],
+    },
+    HelpEntry {
+        command: "bags",
+        syntax: &[".bags"],
+        summary: "Lists all bags.",
+        examples: &[".bags"],
+    },
+    HelpEntry {
+        command: "begin",
+        syntax: &[".begin", ".commit", ".rollback"],
+        summary: "Starts a transaction. The following statements change the bags as usual, \
+            but backends, observers and the storage only see their changes once `.commit` \
+            finishes the transaction. `.rollback` discards them instead.",
+        examples: &[".begin", ".commit", ".rollback"],
+    },
+    HelpEntry {
+        command: "break",
+        syntax: &[
+            ".break on insert into <bag> [<pattern>] [where <guard>]",
+            ".break",
+            ".break off",
+        ],
+        summary: "Pauses the interactive prompt after a statement or connection inserts a \
+            matching value into the bag, showing the value and where it came from. Without \
+            a pattern the guard sees the value as $ and the properties of an object by \
+            their name. While paused the bags can be inspected, statements changing them \
+            are queued until .continue. `.break` lists the breakpoints, `.break off` \
+            removes them.",
+        examples: &[
+            ".break on insert into ordered where total > 1000",
+            ".break on insert into alerts {sensor: \"s1\", ...}",
+            ".break",
+            ".break off",
+        ],
+    },
+    HelpEntry {
+        command: "cdc",
+        syntax: &[".cdc <bag> to <file>"],
+        summary: "Appends every change of the bag as JSON line to the file, \
+            starting with a snapshot of its values.",
+        examples: &[".cdc people to changes"],
+    },
+    HelpEntry {
+        command: "change",
+        syntax: &[".change <pattern> into <expression> [where <guard>] [limit <n>] [offset <n>]"],
+        summary: "Replaces the matching values of the current bag.",
+        examples: &[".change {age, ...} into {age: age + 1} where age < 100"],
+    },
+    HelpEntry {
+        command: "clear",
+        syntax: &[".clear"],
+        summary: "Removes all variables.",
+        examples: &[".clear"],
+    },
+    HelpEntry {
+        command: "connection",
+        syntax: &[
+            ".connection <name>(<pattern>) { <clause>; ... }",
+            "  &<bag>.consume <patterns> [where <guard>]",
+            "  &<bag>.test <patterns> [where <guard>]",
+            "  &<bag>.absent <patterns> [where <guard>]",
+            "  &<bag>.produce <expressions>",
+        ],
+        summary: "Defines how values flow between bags: consumed values are removed, \
+            tested values are kept, absent values must not exist and produced values \
+            are inserted. Run it via .solve or .trigger.",
+        examples: &[".connection double() { &a.consume x where true; &b.produce x * 2 }"],
+    },
+    HelpEntry {
+        command: "connections",
+        syntax: &[".connections"],
+        summary: "Lists all connections and triggers.",
+        examples: &[".connections"],
+    },
+    HelpEntry {
+        command: "continue",
+        syntax: &[".continue", ".skip", ".abort"],
+        summary: "Decides on the candidate shown by `.solve <connection> <expression> step`. \
+            `.continue` accepts it and `.skip` drops it, both show the next candidate or, \
+            after the last one, apply the accepted candidates and run the statements \
+            queued meanwhile. `.abort` applies none of them. When paused by a .break, \
+            `.continue` runs the queued statements and `.abort` drops them.",
+        examples: &[".continue", ".skip", ".abort"],
+    },
+    HelpEntry {
+        command: "copy",
+        syntax: &[".copy <bag> <new bag> [<pattern> [where <guard>]] [limit <n>]"],
+        summary: "Creates a new bag with the guard of the bag and copies the matching values \
+            into it.",
+        examples: &[".copy people backup", ".copy people adults {age, ...} where age >= 18"],
+    },
+    HelpEntry {
+        command: "delete",
+        syntax: &[".delete <pattern> [where <guard>] [limit <n>] [offset <n>]"],
+        summary: "Removes the matching values from the current bag.",
+        examples: &[".delete {age, ...} where age > 100", ".delete _ limit 1"],
+    },
+    HelpEntry {
+        command: "delta",
+        syntax: &[".delta <bag> <bag> [on <key function>]"],
+        summary: "Lists the values added, removed and changed between two bags, \
+            values with equal keys are compared as changed.",
+        examples: &[".delta before after", ".delta before after on fn({id, ...}) => id"],
+    },
+    HelpEntry {
+        command: "disconnect",
+        syntax: &[".disconnect <name>"],
+        summary: "Removes a connection and its triggers.",
+        examples: &[".disconnect double"],
+    },
+    HelpEntry {
+        command: "drop",
+        syntax: &[".drop <bag>"],
+        summary: "Removes a bag with all its values.",
+        examples: &[".drop people"],
+    },
+    HelpEntry {
+        command: "dump",
+        syntax: &[".dump <file> [verify]"],
+        summary: "Writes the values of the current bag to the file, one per line. With \
+            verify, the number of values and a checksum are written to <file>.manifest.",
+        examples: &[".dump values", ".dump values verify"],
+    },
+    HelpEntry {
+        command: "dump_json",
+        syntax: &[".dump_json <file> [verify]"],
+        summary: "Writes the values of the current bag to the file as JSON array.",
+        examples: &[".dump_json values"],
+    },
+    HelpEntry {
+        command: "dump_ndjson",
+        syntax: &[".dump_ndjson <file> [verify]"],
+        summary: "Writes the values of the current bag to the file, one JSON document per line.",
+        examples: &[".dump_ndjson values"],
+    },
+    HelpEntry {
+        command: "dump_parquet",
+        syntax: &[".dump_parquet <file> [verify]"],
+        summary: "Writes the objects of the current bag to the file as Parquet table. \
+            Requires the parquet feature.",
+        examples: &[".dump_parquet values"],
+    },
+    HelpEntry {
+        command: "env",
+        syntax: &[".env save <name>", ".env load <name>"],
+        summary: "Keeps a copy of the variables under the name, or replaces the variables \
+            with the copy kept under it.",
+        examples: &[".env save small", ".env load small"],
+    },
+    HelpEntry {
+        command: "execute",
+        syntax: &[".execute <name> [{<parameter>: <expression>, ...}]"],
+        summary: "Executes a prepared statement.",
+        examples: &[".execute find {id: 42}"],
+    },
+    HelpEntry {
+        command: "exit",
+        syntax: &[".exit", ".quit"],
+        summary: "Leaves the repl.",
+        examples: &[".exit"],
+    },
+    HelpEntry {
+        command: "explain",
+        syntax: &[".explain <statement>", ".explain <error code>"],
+        summary: "Shows how a statement is executed or what an error code means.",
+        examples: &[".explain .query x where x > 5", ".explain E0005"],
+    },
+    HelpEntry {
+        command: "format",
+        syntax: &[".format <statement>"],
+        summary: "Shows a statement in its canonical form.",
+        examples: &[".format 1+2*3", ".format .query {x,y}where x>y limit 3"],
+    },
+    HelpEntry {
+        command: "help",
+        syntax: &[".help [<command>]", ".h [<command>]"],
+        summary: "Lists all commands or describes one of them.",
+        examples: &[".help", ".help query"],
+    },
+    HelpEntry {
+        command: "index",
+        syntax: &[".index <bag> on .<field>"],
+        summary: "Indexes the values of a bag by a field to speed up queries on it.",
+        examples: &[".index people on .id"],
+    },
+    HelpEntry {
+        command: "insert",
+        syntax: &[".insert <expression>; ..."],
+        summary: "Inserts values into the current bag.",
+        examples: &[".insert 1; 2; {name: \"ada\", age: 36}"],
+    },
+    HelpEntry {
+        command: "inspect",
+        syntax: &[".inspect <expression>"],
+        summary: "Shows the syntax tree of an expression.",
+        examples: &[".inspect 5+5"],
+    },
+    HelpEntry {
+        command: "let",
+        syntax: &[".let <pattern> = <expression>; ..."],
+        summary: "Binds variables by matching values against patterns.",
+        examples: &[".let x = 42", ".let [a, b] = [1, 2]; c = a + b"],
+    },
+    HelpEntry {
+        command: "literal",
+        syntax: &[".literal <expression>"],
+        summary: "Shows the value of an expression as literal.",
+        examples: &[".literal [1, 2, 3]"],
+    },
+    HelpEntry {
+        command: "load",
+        syntax: &[".load <file> [verify]"],
+        summary: "Inserts the values of the file, one per line, into the current bag. With \
+            verify, the file is rejected unless it matches <file>.manifest.",
+        examples: &[".load values", ".load values verify"],
+    },
+    HelpEntry {
+        command: "load_bundle",
+        syntax: &[".load_bundle <file>"],
+        summary: "Creates the bags and values written by the web frontend's download.",
+        examples: &[".load_bundle backup"],
+    },
+    HelpEntry {
+        command: "load_json",
+        syntax: &[".load_json <file> [verify]"],
+        summary: "Inserts the values of a JSON array in the file into the current bag.",
+        examples: &[".load_json values"],
+    },
+    HelpEntry {
+        command: "load_ndjson",
+        syntax: &[".load_ndjson <file> [verify]"],
+        summary: "Inserts the values of the file, one JSON document per line, into the \
+            current bag. Malformed lines are skipped and reported.",
+        examples: &[".load_ndjson events"],
+    },
+    HelpEntry {
+        command: "load_toml",
+        syntax: &[".load_toml <file> [verify]"],
+        summary: "Inserts the TOML document in the file as one object into the current bag. \
+            Requires the toml feature.",
+        examples: &[".load_toml config"],
+    },
+    HelpEntry {
+        command: "load_xml",
+        syntax: &[".load_xml <file> [verify]"],
+        summary: "Inserts each child element of the XML root element into the current bag. \
+            Requires the xml feature.",
+        examples: &[".load_xml export"],
+    },
+    HelpEntry {
+        command: "load_yaml",
+        syntax: &[".load_yaml <file> [verify]"],
+        summary: "Inserts the YAML documents in the file into the current bag, the items of \
+            a sequence one by one. Requires the yaml feature.",
+        examples: &[".load_yaml config"],
+    },
+    HelpEntry {
+        command: "log",
+        syntax: &[".log [debug|info|warn|error] <expression>"],
+        summary: "Appends {time, level, value} to the log bag, `log` unless changed \
+            with .set log_bag. The level defaults to info.",
+        examples: &[".log {event: \"start\"}", ".log warn count"],
+    },
+    HelpEntry {
+        command: "macro",
+        syntax: &[".macro <name>[(<parameter>, ...)] := <statement>", "!<name>[(<expression>, ...)]"],
+        summary: "Stores a statement under a name to execute it later via !name.",
+        examples: &[".macro older(n) := .query {age, ...} into age where age > n", "!older(50)"],
+    },
+    HelpEntry {
+        command: "match",
+        syntax: &[".match <pattern> = <expression>; ..."],
+        summary: "Checks whether values match patterns without binding variables.",
+        examples: &[".match [x, _] = [1, 2]"],
+    },
+    HelpEntry {
+        command: "merge",
+        syntax: &[
+            ".merge from <bag> into <bag> on <key function> [prefer source|target|newest by <function>]",
+        ],
+        summary: "Inserts the values of one bag into another, replacing the values of the \
+            same key depending on the preference, which is source by default.",
+        examples: &[
+            ".merge from imported into people on fn({id, ...}) => id",
+            ".merge from imported into people on fn({id, ...}) => id prefer newest by fn({time, ...}) => time",
+        ],
+    },
+    HelpEntry {
+        command: "move",
+        syntax: &[".move(<bag>) [<pattern> [into <expression>] [where <guard>]] [limit <n>]"],
+        summary: "Moves the matching values of the current bag into another bag.",
+        examples: &[".move(archive) {age, ...} where age > 100", ".move(archive) limit 10"],
+    },
+    HelpEntry {
+        command: "on",
+        syntax: &[".on error abort|continue|rollback_statement"],
+        summary: "Sets how the statements after it handle errors: abort stops at a failing \
+            one, continue skips it, rollback_statement skips it and undoes its effects. Scripts, \
+            .run files and bundles begin with abort.",
+        examples: &[".on error continue"],
+    },
+    HelpEntry {
+        command: "pattern",
+        syntax: &[".pattern <pattern>"],
+        summary: "Shows the syntax tree of a pattern.",
+        examples: &[".pattern [_, _ is Boolean, {x}, ...]"],
+    },
+    HelpEntry {
+        command: "pop",
+        syntax: &[".pop <expression>"],
+        summary: "Removes one value equal to the expression from the current bag.",
+        examples: &[".pop 42"],
+    },
+    HelpEntry {
+        command: "prepare",
+        syntax: &[".prepare <name> := <statement>"],
+        summary: "Parses a statement once to execute it repeatedly via .execute. \
+            Every $name in it is a parameter.",
+        examples: &[".prepare find := .query {id, v} into v where id == $id"],
+    },
+    HelpEntry {
+        command: "preview",
+        syntax: &[".preview .change <pattern> into <expression> [where <guard>] [limit <n>] [offset <n>]"],
+        summary: "Runs a .change without committing it and shows how some of the \
+            changed values would differ.",
+        examples: &[".preview .change {age, ...} into {age: age + 1} where age < 100"],
+    },
+    HelpEntry {
+        command: "profile",
+        syntax: &[".profile", ".profile reset"],
+        summary: "Shows how often each operand of the `&&` chain of a bag or query guard \
+            was evaluated and how often it rejected a value, while the `profile` setting \
+            is enabled. Operands rejecting most values are best placed first.",
+        examples: &[".set profile true", ".profile", ".profile reset"],
+    },
+    HelpEntry {
+        command: "purge",
+        syntax: &[".purge"],
+        summary: "Removes all values from the trash of the current bag for good.",
+        examples: &[".purge"],
+    },
+    HelpEntry {
+        command: "query",
+        syntax: &[
+            ".query [<pattern>; ...] [into <expression>] [where <guard>] [group by <expression>]",
+            "       [order by <expression> [collate <collation>] [asc|desc]] [limit <n>] [offset <n>]",
+            ".query /*+ no_index, join_order(<pattern name>, ...) */ <pattern>; ...",
+        ],
+        summary: "Lists the values of the current bag matching the patterns. Several \
+            patterns join the bag with itself.",
+        examples: &[
+            ".query {age, name} into name where age > 18 order by age desc limit 10",
+            ".query {dept, salary} into {dept, total: sum(salary)} group by dept",
+        ],
+    },
+    HelpEntry {
+        command: "queryx",
+        syntax: &[".queryx <pattern>; ... [into <expression>] [where <guard>] ..."],
+        summary: "Like .query, but a value may be matched by several patterns at once.",
+        examples: &[".queryx x; y into [x, y] where x < y"],
+    },
+    HelpEntry {
+        command: "rename",
+        syntax: &[".rename <bag> <new name>"],
+        summary: "Renames a bag along with its trash. Bags used by connections can not be \
+            renamed.",
+        examples: &[".rename people persons"],
+    },
+    HelpEntry {
+        command: "replicate",
+        syntax: &[".replicate from <file>"],
+        summary: "Replays the snapshots and changes written by .cdc into the bags.",
+        examples: &[".replicate from changes"],
+    },
+    HelpEntry {
+        command: "restore",
+        syntax: &[".restore <file>", ".restore [<pattern>] from trash [where <guard>]"],
+        summary: "Replaces all bags with the bags, guards, indexes and values saved in the \
+            file by .save. With `from trash` it instead moves the matching deleted values \
+            back from the trash of the current bag.",
+        examples: &[
+            ".restore workspace",
+            ".restore from trash",
+            ".restore {id, ...} from trash where id == 3",
+        ],
+    },
+    HelpEntry {
+        command: "run",
+        syntax: &[".run <file>"],
+        summary: "Executes the statements in the lines of the file, stopping at the first \
+            line that fails unless the file sets another .on error policy.",
+        examples: &[".run setup"],
+    },
+    HelpEntry {
+        command: "save",
+        syntax: &[".save <file>"],
+        summary: "Writes all bags with their guards, indexes and values to the file, to \
+            be restored via .restore.",
+        examples: &[".save workspace"],
+    },
+    HelpEntry {
+        command: "set",
+        syntax: &[".set", ".set <name> <expression>"],
+        summary: "Lists all settings or changes one of them.",
+        examples: &[".set", ".set verbose true"],
+    },
+    HelpEntry {
+        command: "solve",
+        syntax: &[".solve <connection> <expression>", ".solve <connection> <expression> step"],
+        summary: "Runs a connection once with the given argument and applies its results. \
+            With `step` the candidate solutions are shown one at a time along with the \
+            bindings of their consumers and their changes, see .continue. Statements \
+            changing the bags are queued until the last candidate was decided on.",
+        examples: &[".solve double null", ".solve pairs 5 step"],
+    },
+    HelpEntry {
+        command: "stats",
+        syntax: &[".stats", ".stats <bag>"],
+        summary: "Shows how many parsed statements are cached and how often the cache \
+            was hit. With a bag, shows the number of its values, the number of distinct \
+            values, an estimate of their memory, how many values there are of each type \
+            and the most common keys of its objects.",
+        examples: &[".stats", ".stats init"],
+    },
+    HelpEntry {
+        command: "trash",
+        syntax: &[".trash", ".trash on [ttl <seconds>]", ".trash off"],
+        summary: "Lists the trash of the current bag, or enables or disables it. Values \
+            deleted from a bag with a trash are moved to the bag `<name>::trash` along \
+            with the time of deletion. With a time to live they are purged once it is over.",
+        examples: &[".trash", ".trash on ttl 86400", ".trash off"],
+    },
+    HelpEntry {
+        command: "trigger",
+        syntax: &[".trigger <connection> on insert into <bag>"],
+        summary: "Runs a connection automatically whenever values are inserted into the bag.",
+        examples: &[".trigger double on insert into a"],
+    },
+    HelpEntry {
+        command: "unset",
+        syntax: &[".unset <name>", ".unset *"],
+        summary: "Removes a variable, or all variables with *.",
+        examples: &[".unset x", ".unset *"],
+    },
+    HelpEntry {
+        command: "validate",
+        syntax: &[".validate"],
+        summary: "Checks that all bags used by connections exist.",
+        examples: &[".validate"],
+    },
+    HelpEntry {
+        command: "vars",
+        syntax: &[".vars"],
+        summary: "Lists the bound variables along with the types of their values.",
+        examples: &[".vars"],
+    },
+    HelpEntry {
+        command: "view",
+        syntax: &[".view <name> := union of <bag>, <bag>, ..."],
+        summary: "Creates a read-only bag holding the values of the given bags, each as \
+            {origin, value} with the name of the bag it originates from.",
+        examples: &[".view all_logs := union of logs_a, logs_b"],
+    },
+];
+
+// The commands documented by the entry of another one.
+const ALIASES: &[(&str, &str)] = &[
+    ("abort", "continue"),
+    ("commit", "begin"),
+    ("h", "help"),
+    ("quit", "exit"),
+    ("rollback", "begin"),
+    ("skip", "continue"),
+];
+
+// The name of every command, including the aliases.
+pub fn commands() -> impl Iterator<Item = &'static str> {
+    HELP.iter()
+        .map(|e| e.command)
+        .chain(ALIASES.iter().map(|(alias, _)| *alias))
+}
+
+pub fn lookup(command: &str) -> Option<&'static HelpEntry> {
+    let command = command.strip_prefix('.').unwrap_or(command);
+    let command = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == command)
+        .map_or(command, |(_, documented)| documented);
+
+    HELP.iter().find(|e| e.command == command)
+}
+
+impl std::fmt::Display for HelpEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for syntax in self.syntax {
+            writeln!(f, "{syntax}")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{}", self.summary)?;
+        writeln!(f)?;
+        writeln!(f, "Examples:")?;
+        for example in self.examples {
+            writeln!(f, "  {example}")?;
+        }
+        Ok(())
+    }
+}
+
+// The overview shown by `.help` without a command.
+pub fn overview() -> String {
+    let width = HELP.iter().map(|e| e.command.len()).max().unwrap_or(0);
+    let mut result = String::from("Commands:\n");
+    for entry in HELP {
+        let summary = entry.summary.split(". ").next().unwrap_or(entry.summary);
+        let summary = summary.trim_end_matches('.');
+        result.push_str(&format!("  .{:width$}  {summary}\n", entry.command));
+    }
+    result.push_str("\nType .help <command> for details and examples.");
+    result
+}
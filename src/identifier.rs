@@ -1,13 +1,25 @@
 use std::borrow::Cow;
 
-#[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct Identifier<'a> {
     pub name: Cow<'a, str>,
 }
 
+// Words that start a clause. Identifiers with such a name are written as
+// `#name`, which is read as identifier everywhere. `order` and `group` only
+// start a clause when followed by `by`, which never follows an identifier,
+// so they are written as they are.
+pub const KEYWORDS: &[&str] = &["where", "into", "limit", "offset"];
+
 impl std::fmt::Display for Identifier<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.name)
+        if KEYWORDS.contains(&self.name.as_ref()) {
+            write!(f, "#{}", self.name)
+        } else {
+            write!(f, "{}", self.name)
+        }
     }
 }
 
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    backend::Comparison,
+    env::Environment,
+    expression::{
+        BinaryExpression, BinaryOperator, Expression, LogicalExpression, LogicalOperator,
+        MemberExpression, PropertyKey,
+    },
+    identifier::Identifier,
+    literal::Literal,
+    pattern::{ObjectPropertyPattern, Pattern, PropertyPattern},
+    plan::literal_value,
+    value::Value,
+};
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Index<'s, 'v> {
+    positions: BTreeMap<Value<'s, 'v>, Vec<usize>>,
+}
+
+impl<'s, 'v> Index<'s, 'v> {
+    pub(crate) fn add(&mut self, field: &str, position: usize, value: &Value<'s, 'v>) {
+        if let Some(key) = field_key(field, value) {
+            self.positions.entry(key.clone()).or_default().push(position);
+        }
+    }
+
+    // Forgets the item at `position`, and that the last item, previously at
+    // `last`, took its place, as after `Vec::swap_remove`.
+    pub(crate) fn swap_remove(
+        &mut self,
+        field: &str,
+        position: usize,
+        removed: &Value<'s, 'v>,
+        last: usize,
+        moved: Option<&Value<'s, 'v>>,
+    ) {
+        self.forget(field, position, removed);
+        if let Some(moved) = moved {
+            self.forget(field, last, moved);
+            self.insert(field, position, moved);
+        }
+    }
+
+    fn forget(&mut self, field: &str, position: usize, value: &Value<'s, 'v>) {
+        let Some(key) = field_key(field, value) else {
+            return;
+        };
+        let Some(positions) = self.positions.get_mut(key) else {
+            return;
+        };
+
+        positions.retain(|&p| p != position);
+        if positions.is_empty() {
+            self.positions.remove(key);
+        }
+    }
+
+    // Keeps the positions of each key in ascending order, the order the bag
+    // is scanned in.
+    fn insert(&mut self, field: &str, position: usize, value: &Value<'s, 'v>) {
+        let Some(key) = field_key(field, value) else {
+            return;
+        };
+        let positions = self.positions.entry(key.clone()).or_default();
+        let at = positions.partition_point(|&p| p < position);
+        positions.insert(at, position);
+    }
+
+    pub(crate) fn get(&self, key: &Value<'s, 'v>) -> &[usize] {
+        self.positions.get(key).map_or(&[], Vec::as_slice)
+    }
+}
+
+fn field_key<'x, 's, 'v>(field: &str, value: &'x Value<'s, 'v>) -> Option<&'x Value<'s, 'v>> {
+    let Value::Object(o) = value else {
+        return None;
+    };
+
+    o.get(field).map(|k| k.as_ref())
+}
+
+// Finds the value the given field is required to have by the pattern
+// or by an `==` comparison in the top level conjunction of the guard.
+pub(crate) fn required_key<'s, 'v>(
+    env: &Environment<'_, 's, 'v>,
+    field: &str,
+    pattern: &Pattern<'s>,
+    guard: &Expression<'s>,
+) -> Option<Value<'s, 'v>> {
+    if let Some(literal) = field_reference(field, pattern).literal {
+        return literal_value(literal);
+    }
+
+    field_comparisons(env, field, pattern, guard)
+        .into_iter()
+        .find_map(|(op, value)| matches!(op, BinaryOperator::StrictEqual).then_some(value))
+}
+
+// Finds the `<`, `<=`, `>` and `>=` comparisons of the given field
+// in the top level conjunction of the guard.
+pub(crate) fn required_bounds<'s, 'v>(
+    env: &Environment<'_, 's, 'v>,
+    field: &str,
+    pattern: &Pattern<'s>,
+    guard: &Expression<'s>,
+) -> Vec<(Comparison, Value<'s, 'v>)> {
+    field_comparisons(env, field, pattern, guard)
+        .into_iter()
+        .filter_map(|(op, value)| {
+            let comparison = match op {
+                BinaryOperator::LessThan => Comparison::LessThan,
+                BinaryOperator::LessThanEqual => Comparison::LessThanEqual,
+                BinaryOperator::GreaterThan => Comparison::GreaterThan,
+                BinaryOperator::GreaterThanEqual => Comparison::GreaterThanEqual,
+                _ => return None,
+            };
+            Some((comparison, value))
+        })
+        .collect()
+}
+
+// The ways the guard can refer to a field of the values matched by the
+// pattern: via the whole value, eg `p.id`, or via an identifier bound to
+// the field, eg `id`. The pattern may also fix the field to a literal.
+struct FieldReference<'p, 's> {
+    whole: Vec<&'p Identifier<'s>>,
+    bound: Vec<&'p Identifier<'s>>,
+    literal: Option<&'p Literal<'s>>,
+}
+
+fn field_reference<'p, 's>(field: &str, pattern: &'p Pattern<'s>) -> FieldReference<'p, 's> {
+    let mut reference = FieldReference {
+        whole: vec![],
+        bound: vec![],
+        literal: None,
+    };
+    let mut current = pattern;
+
+    loop {
+        match current {
+            Pattern::Capture(id, inner) => {
+                reference.whole.push(id);
+                current = inner;
+            }
+            Pattern::Identifier(id) | Pattern::TypedIdentifier(id, _) => {
+                reference.whole.push(id);
+                break;
+            }
+            Pattern::Object(props, _) => {
+                for prop in props {
+                    match prop {
+                        ObjectPropertyPattern::Single(id) if id.name == field => {
+                            reference.bound.push(id)
+                        }
+                        ObjectPropertyPattern::Match(PropertyPattern {
+                            key: PropertyKey::Identifier(key),
+                            value,
+                        }) if key.name == field => match value {
+                            Pattern::Literal(l) => reference.literal = Some(l),
+                            Pattern::Identifier(id) => reference.bound.push(id),
+                            _ => {}
+                        },
+                        _ => {}
+                    }
+                }
+                break;
+            }
+            _ => break,
+        }
+    }
+
+    reference
+}
+
+// Collects the comparisons between the field and an expression independent
+// of the pattern in the top level conjunction of the guard. The comparisons
+// are turned around if needed so that the field is on the left side.
+fn field_comparisons<'s, 'v>(
+    env: &Environment<'_, 's, 'v>,
+    field: &str,
+    pattern: &Pattern<'s>,
+    guard: &Expression<'s>,
+) -> Vec<(BinaryOperator, Value<'s, 'v>)> {
+    let FieldReference { whole, bound, .. } = field_reference(field, pattern);
+    let refers_to_field = |e: &Expression| match e {
+        Expression::Identifier(id) => bound.contains(&id),
+        Expression::Member(MemberExpression { object, property }) => {
+            matches!(object.as_ref(), Expression::Identifier(id) if whole.contains(&id))
+                && matches!(property.as_ref(), Expression::Literal(Literal::String(s)) if s == field)
+        }
+        _ => false,
+    };
+    let pattern_identifiers = pattern.get_identifiers().collect::<Vec<&Identifier>>();
+    let is_independent = |e: &Expression| {
+        e.get_identifiers()
+            .all(|id| !pattern_identifiers.contains(&id))
+    };
+
+    let mut comparisons = vec![];
+    let mut stack = vec![guard];
+    while let Some(e) = stack.pop() {
+        match e {
+            Expression::Logical(LogicalExpression {
+                operator: LogicalOperator::And,
+                left,
+                right,
+            }) => {
+                stack.push(left);
+                stack.push(right);
+            }
+            Expression::Binary(BinaryExpression {
+                operator,
+                left,
+                right,
+            }) => {
+                let flipped = match operator {
+                    BinaryOperator::StrictEqual => BinaryOperator::StrictEqual,
+                    BinaryOperator::LessThan => BinaryOperator::GreaterThan,
+                    BinaryOperator::LessThanEqual => BinaryOperator::GreaterThanEqual,
+                    BinaryOperator::GreaterThan => BinaryOperator::LessThan,
+                    BinaryOperator::GreaterThanEqual => BinaryOperator::LessThanEqual,
+                    _ => continue,
+                };
+                for (a, b, op) in [(left, right, *operator), (right, left, flipped)] {
+                    if refers_to_field(a) && is_independent(b) {
+                        if let Ok(value) = env.eval_expr(b) {
+                            comparisons.push((op, value));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    comparisons
+}
@@ -0,0 +1,260 @@
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{BuildHasher, Hasher};
+
+use serde::Deserialize;
+
+use crate::bag_bundle::Transaction;
+use crate::identifier::Identifier;
+use crate::observer::Mutation;
+use crate::query::Predicate;
+use crate::repl::{Repl, ReplError};
+use crate::server;
+use crate::statement::Statement;
+use crate::trash;
+use crate::value::Value;
+
+// How long the values and the bags of a jail are kept, in seconds.
+#[derive(Clone, Debug, Deserialize)]
+pub struct JailSettings {
+    // Values are removed this long after they were inserted, never if None.
+    pub item_ttl: Option<u64>,
+    // The limit of the guard of the bag.
+    pub max_items: usize,
+    // The bag is dropped once the session was idle this long.
+    pub session_ttl: u64,
+    // Sessions beyond this many are turned away until others expired.
+    pub max_sessions: usize,
+}
+
+impl Default for JailSettings {
+    fn default() -> Self {
+        Self {
+            item_ttl: Some(600),
+            max_items: 30,
+            session_ttl: 3600,
+            max_sessions: 1000,
+        }
+    }
+}
+
+// The bags a shared repl gives each session of a public playground, so
+// that visitors do not fill a common bag. A session can neither switch to
+// another bag nor name the bags of the others.
+pub struct Jails<'s, 'v> {
+    pub settings: JailSettings,
+    sessions: BTreeMap<String, Jail<'s, 'v>>,
+    created: u64,
+    // Signs the ids of the sessions, so that visitors can not make up their
+    // own to get further jails.
+    key: RandomState,
+}
+
+struct Jail<'s, 'v> {
+    bag: Identifier<'s>,
+    last_seen: i64,
+    // The values of the bag with the time they were inserted.
+    items: Vec<(i64, Value<'s, 'v>)>,
+}
+
+impl<'s, 'v> Jails<'s, 'v> {
+    pub fn new(settings: JailSettings) -> Self {
+        Self {
+            settings,
+            sessions: BTreeMap::new(),
+            created: 0,
+            key: RandomState::new(),
+        }
+    }
+
+    // A new session id, the only kind of id `run` creates jails for.
+    pub fn issue(&self) -> String {
+        let id = server::random_token();
+        let signature = self.sign(&id);
+
+        format!("{id}.{signature}")
+    }
+
+    pub fn is_issued(&self, session: &str) -> bool {
+        session
+            .split_once('.')
+            .map_or(false, |(id, signature)| server::constant_time_eq(signature, &self.sign(id)))
+    }
+
+    fn sign(&self, id: &str) -> String {
+        let mut hasher = self.key.build_hasher();
+        hasher.write(id.as_bytes());
+
+        format!("{:016x}", hasher.finish())
+    }
+
+    // The bag of the session, None before its first statement.
+    pub fn bag(&self, session: &str) -> Option<&Identifier<'s>> {
+        self.sessions.get(session).map(|j| &j.bag)
+    }
+
+    // Runs `f` on the repl with the jail of the session as current bag,
+    // creating the jail on its first visit. Jails of expired sessions and
+    // expired values are removed beforehand.
+    pub fn run<'b, 'i, R>(
+        &mut self,
+        repl: &mut Repl<'b, 'i, 's, 'v>,
+        session: &str,
+        f: impl FnOnce(&mut Repl<'b, 'i, 's, 'v>) -> R,
+    ) -> Result<R, ReplError> {
+        let now = trash::now();
+        self.expire(repl, now)?;
+
+        if !self.sessions.contains_key(session) {
+            if !self.is_issued(session) {
+                return Err(ReplError::Denied);
+            }
+            if self.sessions.len() >= self.settings.max_sessions {
+                return Err(ReplError::TooManySessions);
+            }
+            let jail = self.create(repl, now)?;
+            self.sessions.insert(session.to_string(), jail);
+        }
+        let foreign = self
+            .sessions
+            .iter()
+            .filter(|(id, _)| id.as_str() != session)
+            .flat_map(|(_, j)| [j.bag.clone(), trash::bag_name(&j.bag)])
+            .collect();
+        let Some(jail) = self.sessions.get_mut(session) else {
+            return Err(ReplError::BagError);
+        };
+
+        let watched = [jail.bag.clone()];
+        let start = repl.bag_bundle.watch(&watched);
+        let outer = std::mem::replace(&mut repl.current_bag, jail.bag.clone());
+        repl.confinement = Some(foreign);
+        let result = f(repl);
+        repl.confinement = None;
+        repl.current_bag = outer;
+        jail.last_seen = now;
+
+        for (_, mutation, _) in repl.bag_bundle.unwatch(&watched, start) {
+            match mutation {
+                Mutation::Insert(value) => jail.items.push((now, value)),
+                Mutation::Delete(value) => {
+                    if let Some(p) = jail.items.iter().position(|(_, v)| v == &value) {
+                        jail.items.remove(p);
+                    }
+                }
+                // The changed value expires when the value it replaced would.
+                Mutation::Update { before, after } => {
+                    if let Some((_, v)) = jail.items.iter_mut().find(|(_, v)| v == &before) {
+                        *v = after;
+                    }
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Drops the bags of the sessions idle for longer than the session ttl
+    // and removes the values older than the item ttl from the others.
+    pub fn expire(&mut self, repl: &mut Repl<'_, '_, 's, 'v>, now: i64) -> Result<(), ReplError> {
+        let session_ttl = self.settings.session_ttl as i64 * 1000;
+        let item_ttl = self.settings.item_ttl.map(|t| t as i64 * 1000);
+        let mut trans = Transaction::new(&repl.bag_bundle);
+
+        let current_bag = &repl.current_bag;
+        let mut failed = false;
+        self.sessions.retain(|_, jail| {
+            if jail.last_seen.saturating_add(session_ttl) <= now && &jail.bag != current_bag {
+                failed |= trans.drop_bag(jail.bag.clone()).is_err();
+                failed |= trans.drop_bag(trash::bag_name(&jail.bag)).is_err();
+                return false;
+            }
+
+            if let Some(ttl) = item_ttl {
+                jail.items.retain(|(inserted, value)| {
+                    if inserted.saturating_add(ttl) > now {
+                        return true;
+                    }
+                    failed |= trans.pop(&jail.bag, value).is_err();
+                    false
+                });
+            }
+            true
+        });
+
+        if failed {
+            return Err(ReplError::TranscationAborted);
+        }
+        repl.bag_bundle = trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+
+        Ok(())
+    }
+
+    // Drops all jails, eg before the state of the server is saved.
+    pub fn clear(&mut self, repl: &mut Repl<'_, '_, 's, 'v>) -> Result<(), ReplError> {
+        for jail in self.sessions.values_mut() {
+            jail.last_seen = i64::MIN;
+        }
+
+        self.expire(repl, trash::now())
+    }
+
+    fn create(&mut self, repl: &mut Repl<'_, '_, 's, 'v>, now: i64) -> Result<Jail<'s, 'v>, ReplError> {
+        let bag = loop {
+            self.created += 1;
+            let name = Identifier {
+                name: Cow::Owned(format!("jail_{}", self.created)),
+            };
+            if !repl.bag_bundle.bags.contains_key(&name) {
+                break name;
+            }
+        };
+
+        let mut trans = Transaction::new(&repl.bag_bundle);
+        trans
+            .create_bag(
+                bag.clone(),
+                Predicate {
+                    limit: Some(self.settings.max_items),
+                    ..Predicate::any()
+                },
+            )
+            .map_err(|_| ReplError::TranscationAborted)?;
+        repl.bag_bundle = trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+
+        Ok(Jail {
+            bag,
+            last_seen: now,
+            items: vec![],
+        })
+    }
+}
+
+// Whether the statement of a session stays in its current bag, the jail,
+// given the bags of the other sessions.
+pub(crate) fn permits(
+    statement: &Statement,
+    current_bag: &Identifier,
+    foreign: &BTreeSet<Identifier>,
+) -> bool {
+    match statement {
+        Statement::UseBag(bag, ..) => bag == current_bag,
+        Statement::DropBag(bag)
+        | Statement::Move(bag, _)
+        | Statement::CreateIndex(bag, _)
+        | Statement::Cdc(bag, _)
+        | Statement::Trigger(_, bag)
+        | Statement::Stats(Some(bag)) => !foreign.contains(bag),
+        Statement::RenameBag(a, b)
+        | Statement::CopyBag(a, b, _)
+        | Statement::Delta(a, b, _)
+        | Statement::Merge(a, b, ..) => !foreign.contains(a) && !foreign.contains(b),
+        Statement::CreateView(_, bags) => !bags.iter().any(|b| foreign.contains(b)),
+        Statement::Connect(_, connection) => !connection.bags().any(|b| foreign.contains(b)),
+        Statement::DefineMacro(_, _, body) | Statement::Prepare(_, body) => {
+            permits(body, current_bag, foreign)
+        }
+        _ => true,
+    }
+}
@@ -0,0 +1,20 @@
+use serde::Deserialize;
+
+use crate::value::Value;
+
+// The JSON form of values is the one of their serde implementation, so
+// exports, change events and the web API read and write the same tagged
+// encoding as embedders do.
+pub fn to_json(value: &Value) -> Option<serde_json::Value> {
+    serde_json::to_value(value).ok()
+}
+
+// Values that have no JSON representation, eg functions, are written as
+// their damasc literal.
+pub fn to_json_lossy(value: &Value) -> serde_json::Value {
+    to_json(value).unwrap_or_else(|| serde_json::Value::String(value.to_string()))
+}
+
+pub fn from_json<'s, 'v>(json: &serde_json::Value) -> Option<Value<'s, 'v>> {
+    Value::deserialize(json).ok()
+}
@@ -3,19 +3,70 @@
 #![feature(type_alias_impl_trait)]
 
 pub mod assignment;
+pub mod audit;
+pub mod auth;
+pub mod backend;
 pub mod bag;
 pub mod bag_bundle;
+pub mod breakpoint;
+pub mod builtins;
+pub mod capabilities;
+pub mod cdc;
+pub mod collation;
+#[cfg(feature = "parquet")]
+pub mod columnar;
+pub mod completion;
+pub mod debugger;
+pub mod delta;
+pub mod diff;
+pub mod documents;
 pub mod env;
+pub mod error_catalog;
 pub mod expression;
+pub mod extension;
+pub mod fingerprint;
+pub mod formatter;
+pub mod grammar;
+pub mod help;
 pub mod identifier;
+pub(crate) mod index;
+pub mod jail;
+pub mod json;
+pub mod limits;
 pub mod literal;
+pub mod log;
+pub mod manifest;
 pub mod matcher;
+pub mod memo;
+pub mod merge;
+pub mod metrics;
+pub mod network;
+pub mod observer;
 pub mod parser;
 pub mod pattern;
+pub(crate) mod plan;
+pub mod plan_cache;
+pub mod presentation;
+pub mod profile;
 pub mod query;
+pub(crate) mod regex_cache;
 pub mod repl;
+#[cfg(feature = "cli")]
+pub mod repl_helper;
+pub mod server;
+pub mod session;
+pub mod settings;
+pub mod snapshot;
+pub mod statistics;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_backend;
 pub mod statement;
+pub mod storage;
+pub mod system_bag;
+pub mod trash;
 pub mod value;
+pub mod value_serde;
+pub mod virtual_bag;
 pub mod wasm;
 pub mod graph;
 pub mod graph_solver;
@@ -33,6 +84,10 @@ impl<'s, 'v> Value<'s, 'v> {
             Value::Null => Expression::Literal(Literal::Null),
             Value::String(s) => Expression::Literal(Literal::String(s.clone())),
             Value::Integer(i) => Expression::Literal(Literal::Number(Cow::Owned(i.to_string()))),
+            Value::BigInt(i) => Expression::Literal(Literal::Number(Cow::Owned(i.to_string()))),
+            Value::DateTime(t) => {
+                Expression::Literal(Literal::DateTime(Cow::Owned(value::format_datetime(*t))))
+            }
             Value::Boolean(b) => Expression::Literal(Literal::Boolean(*b)),
             Value::Array(a) => Expression::Array(
                 a.iter()
@@ -53,6 +108,21 @@ impl<'s, 'v> Value<'s, 'v> {
                     .collect(),
             ),
             Value::Type(t) => Expression::Literal(Literal::Type(*t)),
+            Value::Closure(c) => {
+                let env = env::Environment {
+                    bindings: c.captured.clone(),
+                    memo: None,
+                    multiplicities: None,
+                };
+                let shadowed = c.parameter.get_identifiers().collect();
+
+                Expression::Lambda(LambdaExpression {
+                    parameter: c.parameter.clone(),
+                    body: Box::new(env.specialize(&c.body, &shadowed)),
+                })
+            }
+            Value::Pattern(p) => Expression::Pattern(p.0.clone()),
+            Value::Expression(e) => Expression::Quote(e.0.clone()),
         }
     }
 }
@@ -0,0 +1,500 @@
+use crate::expression::{Expression, PropertyKey};
+use crate::merge::Preference;
+use crate::pattern::{ArrayPatternItem, ObjectPropertyPattern, Pattern, Rest};
+use crate::statement::Statement;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_nesting: usize,
+    pub max_expression_depth: usize,
+    pub max_pattern_depth: usize,
+    pub max_template_nesting: usize,
+    pub budget: Option<Budget>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Budget {
+    pub max_input_length: usize,
+    pub max_nodes: usize,
+    pub max_join_arity: usize,
+    pub max_array_items: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_nesting: 64,
+            max_expression_depth: 128,
+            max_pattern_depth: 32,
+            max_template_nesting: 8,
+            budget: None,
+        }
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            max_input_length: 2000,
+            max_nodes: 400,
+            max_join_arity: 4,
+            max_array_items: 64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LimitError {
+    NestingTooDeep { depth: usize, limit: usize, offset: usize },
+    TemplateTooDeep { depth: usize, limit: usize, offset: usize },
+    ExpressionTooDeep { depth: usize, limit: usize },
+    PatternTooDeep { depth: usize, limit: usize },
+    InputTooLong { length: usize, limit: usize },
+    TooManyNodes { count: usize, limit: usize },
+    JoinTooWide { arity: usize, limit: usize },
+    ArrayTooLarge { length: usize, limit: usize },
+}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitError::NestingTooDeep {
+                depth,
+                limit,
+                offset,
+            } => write!(
+                f,
+                "nesting depth {depth} at offset {offset} exceeds the limit of {limit}"
+            ),
+            LimitError::TemplateTooDeep {
+                depth,
+                limit,
+                offset,
+            } => write!(
+                f,
+                "template nesting depth {depth} at offset {offset} exceeds the limit of {limit}"
+            ),
+            LimitError::ExpressionTooDeep { depth, limit } => {
+                write!(f, "expression depth {depth} exceeds the limit of {limit}")
+            }
+            LimitError::PatternTooDeep { depth, limit } => {
+                write!(f, "pattern depth {depth} exceeds the limit of {limit}")
+            }
+            LimitError::InputTooLong { length, limit } => {
+                write!(f, "input length {length} exceeds the limit of {limit} characters")
+            }
+            LimitError::TooManyNodes { count, limit } => {
+                write!(f, "statement has {count} nodes, exceeding the limit of {limit}")
+            }
+            LimitError::JoinTooWide { arity, limit } => {
+                write!(f, "join of {arity} patterns exceeds the limit of {limit}")
+            }
+            LimitError::ArrayTooLarge { length, limit } => {
+                write!(f, "array literal with {length} items exceeds the limit of {limit}")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Frame {
+    Bracket,
+    String,
+    Template,
+    Interpolation,
+}
+
+impl Limits {
+    pub fn playground() -> Self {
+        Self {
+            budget: Some(Budget::default()),
+            ..Self::default()
+        }
+    }
+
+    pub fn check_input(&self, input: &str) -> Result<(), LimitError> {
+        if let Some(budget) = &self.budget {
+            let length = input.chars().count();
+            if length > budget.max_input_length {
+                return Err(LimitError::InputTooLong {
+                    length,
+                    limit: budget.max_input_length,
+                });
+            }
+        }
+
+        let mut stack = Vec::new();
+        let mut templates = 0;
+        let mut prefix_run = 0;
+        let mut chars = input.char_indices().peekable();
+
+        while let Some((offset, c)) = chars.next() {
+            let nesting_error = |depth| LimitError::NestingTooDeep {
+                depth,
+                limit: self.max_nesting,
+                offset,
+            };
+
+            match stack.last() {
+                Some(Frame::String) => {
+                    if c == '"' {
+                        stack.pop();
+                    }
+                    continue;
+                }
+                Some(Frame::Template) => {
+                    if c == '`' {
+                        stack.pop();
+                        templates -= 1;
+                    } else if c == '$' && matches!(chars.peek(), Some((_, '{'))) {
+                        chars.next();
+                        stack.push(Frame::Interpolation);
+                        if stack.len() > self.max_nesting {
+                            return Err(nesting_error(stack.len()));
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            match c {
+                '"' => stack.push(Frame::String),
+                '`' => {
+                    stack.push(Frame::Template);
+                    templates += 1;
+                    if templates > self.max_template_nesting {
+                        return Err(LimitError::TemplateTooDeep {
+                            depth: templates,
+                            limit: self.max_template_nesting,
+                            offset,
+                        });
+                    }
+                }
+                '(' | '[' | '{' => stack.push(Frame::Bracket),
+                ')' | ']' => {
+                    if stack.last() == Some(&Frame::Bracket) {
+                        stack.pop();
+                    }
+                }
+                '}' => {
+                    if matches!(stack.last(), Some(Frame::Bracket | Frame::Interpolation)) {
+                        stack.pop();
+                    }
+                }
+                _ => {}
+            }
+
+            prefix_run = match c {
+                '!' | '-' | '+' => prefix_run + 1,
+                c if c.is_whitespace() => prefix_run,
+                _ => 0,
+            };
+
+            if stack.len() + prefix_run > self.max_nesting {
+                return Err(nesting_error(stack.len() + prefix_run));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_statement(&self, statement: &Statement) -> Result<(), LimitError> {
+        let (expressions, patterns) = statement_parts(statement);
+
+        expressions
+            .iter()
+            .try_for_each(|e| self.check_expression(e))?;
+        patterns.iter().try_for_each(|p| self.check_pattern(p))?;
+
+        match &self.budget {
+            Some(budget) => budget.check(statement, expressions, patterns),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_expression(&self, expression: &Expression) -> Result<(), LimitError> {
+        let mut stack = vec![(expression, 1)];
+
+        while let Some((e, depth)) = stack.pop() {
+            if depth > self.max_expression_depth {
+                return Err(LimitError::ExpressionTooDeep {
+                    depth,
+                    limit: self.max_expression_depth,
+                });
+            }
+
+            stack.extend(e.children().into_iter().map(|c| (c, depth + 1)));
+        }
+
+        Ok(())
+    }
+
+    pub fn check_pattern(&self, pattern: &Pattern) -> Result<(), LimitError> {
+        let mut stack = vec![(pattern, 1)];
+
+        while let Some((p, depth)) = stack.pop() {
+            if depth > self.max_pattern_depth {
+                return Err(LimitError::PatternTooDeep {
+                    depth,
+                    limit: self.max_pattern_depth,
+                });
+            }
+
+            let (patterns, expressions) = pattern_children(p);
+            expressions
+                .into_iter()
+                .try_for_each(|e| self.check_expression(e))?;
+            stack.extend(patterns.into_iter().map(|c| (c, depth + 1)));
+        }
+
+        Ok(())
+    }
+}
+
+impl Budget {
+    fn check<'x>(
+        &self,
+        statement: &Statement,
+        mut expressions: Vec<&'x Expression<'x>>,
+        mut patterns: Vec<&'x Pattern<'x>>,
+    ) -> Result<(), LimitError> {
+        let arity = join_arity(statement);
+        if arity > self.max_join_arity {
+            return Err(LimitError::JoinTooWide {
+                arity,
+                limit: self.max_join_arity,
+            });
+        }
+
+        let mut count = 0;
+
+        loop {
+            let length = if let Some(e) = expressions.pop() {
+                let children = e.children();
+                let length = match e {
+                    Expression::Array(_) => children.len(),
+                    _ => 0,
+                };
+                expressions.extend(children);
+                length
+            } else if let Some(p) = patterns.pop() {
+                let (children, keys) = pattern_children(p);
+                let length = match p {
+                    Pattern::Array(items, _) => items.len(),
+                    _ => 0,
+                };
+                patterns.extend(children);
+                expressions.extend(keys);
+                length
+            } else {
+                return Ok(());
+            };
+
+            if length > self.max_array_items {
+                return Err(LimitError::ArrayTooLarge {
+                    length,
+                    limit: self.max_array_items,
+                });
+            }
+
+            count += 1;
+            if count > self.max_nodes {
+                return Err(LimitError::TooManyNodes {
+                    count,
+                    limit: self.max_nodes,
+                });
+            }
+        }
+    }
+}
+
+fn join_arity(statement: &Statement) -> usize {
+    match statement {
+        Statement::Query(query) => query.predicate.patterns.len(),
+        Statement::Connect(_, connection) => {
+            connection.consumers.iter().map(|c| c.patterns.len()).sum()
+        }
+        Statement::Explain(inner)
+        | Statement::Ast(inner)
+        | Statement::Format(inner)
+        | Statement::Prepare(_, inner) => join_arity(inner),
+        _ => 1,
+    }
+}
+
+pub(crate) fn statement_parts<'x>(
+    statement: &'x Statement,
+) -> (Vec<&'x Expression<'x>>, Vec<&'x Pattern<'x>>) {
+    let mut expressions = Vec::new();
+    let mut patterns = Vec::new();
+
+    match statement {
+        Statement::Noop
+        | Statement::Clear
+        | Statement::Exit
+        | Statement::Help(_)
+        | Statement::Import(..)
+        | Statement::Export(..)
+        | Statement::LoadBundle(_)
+        | Statement::Save(_)
+        | Statement::Restore(_)
+        | Statement::Run(_)
+        | Statement::OnError(_)
+        | Statement::EnableTrash(_)
+        | Statement::DisableTrash
+        | Statement::ListTrash
+        | Statement::PurgeTrash
+        | Statement::DropBag(_)
+        | Statement::RenameBag(..)
+        | Statement::CreateVirtualBag(..)
+        | Statement::CreateView(..)
+        | Statement::CreateIndex(..)
+        | Statement::Cdc(..)
+        | Statement::Replicate(_)
+        | Statement::Disconnect(_)
+        | Statement::Trigger(..)
+        | Statement::ListConnections
+        | Statement::TellBag
+        | Statement::ListBags
+        | Statement::Validate
+        | Statement::ExplainError(_)
+        | Statement::ListSettings
+        | Statement::ListVariables
+        | Statement::Unset(_)
+        | Statement::SaveEnv(_)
+        | Statement::LoadEnv(_)
+        | Statement::Custom(..)
+        | Statement::Stats(_)
+        | Statement::Profile(_)
+        | Statement::Begin
+        | Statement::Commit
+        | Statement::Rollback
+        | Statement::Step(_)
+        | Statement::ListBreakpoints
+        | Statement::ClearBreakpoints
+        | Statement::UseBag(_, None, _) => {}
+        Statement::Inspect(e)
+        | Statement::Literal(e)
+        | Statement::Pop(e)
+        | Statement::Log(_, e)
+        | Statement::Solve(_, e)
+        | Statement::SolveStep(_, e)
+        | Statement::Set(_, e) => expressions.push(e),
+        Statement::Eval(set) => expressions.extend(&set.expressions),
+        Statement::Insert(insertion) => expressions.extend(&insertion.expressions.expressions),
+        Statement::Pattern(p) => patterns.push(p),
+        Statement::AssignSet(set) | Statement::MatchSet(set) => {
+            for a in &set.assignments {
+                patterns.push(&a.pattern);
+                expressions.push(&a.expression);
+            }
+        }
+        Statement::Query(query) => {
+            patterns.extend(&query.predicate.patterns);
+            expressions.push(&query.predicate.guard);
+            expressions.push(&query.projection);
+            expressions.extend(&query.group);
+            expressions.extend(query.order.as_ref().map(|o| &o.key));
+            expressions.extend(query.aggregates.iter().filter_map(|a| a.argument.as_ref()));
+        }
+        Statement::Deletion(query) => {
+            patterns.push(&query.predicate.pattern);
+            expressions.push(&query.predicate.guard);
+        }
+        Statement::Update(query) | Statement::Preview(query) => {
+            patterns.push(&query.predicate.pattern);
+            expressions.push(&query.predicate.guard);
+            expressions.push(&query.projection);
+        }
+        Statement::Move(_, query) => {
+            patterns.push(&query.predicate.pattern);
+            expressions.push(&query.predicate.guard);
+            expressions.push(&query.projection);
+        }
+        Statement::UseBag(_, Some(predicate), _)
+        | Statement::RestoreTrash(predicate)
+        | Statement::CopyBag(_, _, predicate) => {
+            patterns.push(&predicate.pattern);
+            expressions.push(&predicate.guard);
+        }
+        Statement::Break(breakpoint) => {
+            patterns.extend(&breakpoint.pattern);
+            expressions.push(&breakpoint.guard);
+        }
+        Statement::Connect(_, connection) => {
+            patterns.push(&connection.signature.parameter);
+            for consumer in &connection.consumers {
+                patterns.extend(&consumer.patterns);
+            }
+            for producer in &connection.producers {
+                expressions.extend(&producer.projections);
+            }
+            for a in &connection.patterns.assignments {
+                patterns.push(&a.pattern);
+                expressions.push(&a.expression);
+            }
+            expressions.push(&connection.guard);
+        }
+        Statement::Explain(inner)
+        | Statement::Ast(inner)
+        | Statement::Format(inner)
+        | Statement::DefineMacro(_, _, inner)
+        | Statement::Prepare(_, inner) => return statement_parts(inner),
+        Statement::ExpandMacro(_, arguments) => expressions.extend(arguments.iter().flatten()),
+        Statement::Merge(_, _, key, preference) => {
+            expressions.push(key);
+            if let Preference::Newest(by) = preference {
+                expressions.push(by);
+            }
+        }
+        Statement::Prepared(_, arguments) | Statement::Delta(_, _, arguments) => {
+            expressions.extend(arguments)
+        }
+    }
+
+    (expressions, patterns)
+}
+
+fn pattern_children<'x>(pattern: &'x Pattern<'x>) -> (Vec<&'x Pattern<'x>>, Vec<&'x Expression<'x>>) {
+    let mut patterns = Vec::new();
+    let mut expressions = Vec::new();
+
+    match pattern {
+        Pattern::Discard
+        | Pattern::Identifier(_)
+        | Pattern::TypedDiscard(_)
+        | Pattern::TypedIdentifier(..)
+        | Pattern::Literal(_)
+        | Pattern::Regex(_) => {}
+        Pattern::Capture(_, inner) | Pattern::Descendant(inner) => patterns.push(inner.as_ref()),
+        Pattern::Guarded(inner, guard) => {
+            patterns.push(inner.as_ref());
+            expressions.push(guard.as_ref());
+        }
+        Pattern::Or(alternatives) => patterns.extend(alternatives),
+        Pattern::Object(props, rest) => {
+            for prop in props {
+                if let ObjectPropertyPattern::Match(m) = prop {
+                    if let PropertyKey::Expression(k) = &m.key {
+                        expressions.push(k);
+                    }
+                    patterns.push(&m.value);
+                }
+            }
+            if let Rest::Collect(r) = rest {
+                patterns.push(r.as_ref());
+            }
+        }
+        Pattern::Array(items, rest) => {
+            for ArrayPatternItem::Pattern(item) in items {
+                patterns.push(item);
+            }
+            if let Rest::Collect(r) = rest {
+                patterns.push(r.as_ref());
+            }
+        }
+    }
+
+    (patterns, expressions)
+}
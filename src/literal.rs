@@ -1,14 +1,18 @@
 use std::borrow::Cow;
 
+use serde::{Deserialize, Serialize};
+
 use crate::value::ValueType;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Literal<'s> {
     Null,
     String(Cow<'s, str>),
     Number(Cow<'s, str>),
     Boolean(bool),
     Type(ValueType),
+    Regex(Cow<'s, str>),
+    DateTime(Cow<'s, str>),
 }
 
 impl<'a> std::fmt::Display for Literal<'a> {
@@ -19,6 +23,22 @@ impl<'a> std::fmt::Display for Literal<'a> {
             Literal::Number(n) => write!(f, "{n}"),
             Literal::Boolean(b) => write!(f, "{b}"),
             Literal::Type(t) => write!(f, "{t}"),
+            Literal::Regex(r) => write!(f, "/{r}/"),
+            Literal::DateTime(t) => write!(f, "@{t}"),
+        }
+    }
+}
+
+impl Literal<'_> {
+    pub(crate) fn deep_clone<'x,'y>(&'x self) -> Literal<'y> {
+        match self {
+            Literal::Null => Literal::Null,
+            Literal::String(s) => Literal::String(Cow::Owned(s.to_string())),
+            Literal::Number(n) => Literal::Number(Cow::Owned(n.to_string())),
+            Literal::Boolean(b) => Literal::Boolean(*b),
+            Literal::Type(t) => Literal::Type(*t),
+            Literal::Regex(r) => Literal::Regex(Cow::Owned(r.to_string())),
+            Literal::DateTime(t) => Literal::DateTime(Cow::Owned(t.to_string())),
         }
     }
 }
@@ -0,0 +1,44 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::value::Value;
+
+// The bag `.log` appends to, unless configured otherwise with
+// `.set log_bag "name"`.
+pub const DEFAULT_BAG: &str = "log";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogLevel {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogLevel::Debug => write!(f, "debug"),
+            LogLevel::Info => write!(f, "info"),
+            LogLevel::Warn => write!(f, "warn"),
+            LogLevel::Error => write!(f, "error"),
+        }
+    }
+}
+
+// A logged value together with its level and the time it was logged, eg
+// `{time: ..., level: "info", value: 42}`.
+pub(crate) fn entry<'s, 'v>(level: LogLevel, value: Value<'s, 'v>, now: i64) -> Value<'s, 'v> {
+    let mut object = BTreeMap::new();
+    object.insert(Cow::Borrowed("time"), Cow::Owned(Value::DateTime(now)));
+    object.insert(
+        Cow::Borrowed("level"),
+        Cow::Owned(Value::String(Cow::Owned(level.to_string()))),
+    );
+    object.insert(Cow::Borrowed("value"), Cow::Owned(value));
+
+    Value::Object(object)
+}
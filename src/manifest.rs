@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+// Written next to an exported file when the export is verified. It records
+// the number of values and a checksum of the file's bytes, so that a
+// truncated or corrupted file is rejected by a verified import before any
+// of its values are inserted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub count: usize,
+    pub checksum: String,
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io,
+    Missing,
+    Malformed,
+    ChecksumMismatch,
+}
+
+pub fn path(file: &str) -> PathBuf {
+    PathBuf::from(format!("{file}.manifest"))
+}
+
+// FNV-1a with 64 bits. It is no cryptographic hash and only meant to
+// detect accidental damage.
+pub fn checksum(mut input: impl Read) -> io::Result<String> {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut buffer = [0; 8192];
+    loop {
+        let read = input.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for byte in &buffer[..read] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    Ok(format!("fnv1a64:{hash:016x}"))
+}
+
+pub fn write(file: &str, count: usize) -> Result<Manifest, ManifestError> {
+    let checksum = File::open(file)
+        .and_then(|f| checksum(BufReader::new(f)))
+        .map_err(|_| ManifestError::Io)?;
+    let manifest = Manifest { count, checksum };
+    let json = serde_json::to_string_pretty(&manifest).map_err(|_| ManifestError::Io)?;
+    std::fs::write(path(file), json + "\n").map_err(|_| ManifestError::Io)?;
+
+    Ok(manifest)
+}
+
+// Checks the file against its manifest. The count can only be checked by
+// the caller once the values are parsed.
+pub fn verify(file: &str) -> Result<Manifest, ManifestError> {
+    let json = match std::fs::read_to_string(path(file)) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(ManifestError::Missing),
+        Err(_) => return Err(ManifestError::Io),
+    };
+    let manifest: Manifest = serde_json::from_str(&json).map_err(|_| ManifestError::Malformed)?;
+    let checksum = File::open(file)
+        .and_then(|f| checksum(BufReader::new(f)))
+        .map_err(|_| ManifestError::Io)?;
+    if checksum != manifest.checksum {
+        return Err(ManifestError::ChecksumMismatch);
+    }
+
+    Ok(manifest)
+}
@@ -1,10 +1,10 @@
 use std::borrow::Cow;
-use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, BTreeSet};
 
-use crate::expression::PropertyKey;
+use crate::expression::{Expression, PropertyKey};
 use crate::literal::Literal;
 use crate::pattern::*;
+use crate::regex_cache;
 use crate::{env::Environment, identifier::Identifier, value::Value, value::ValueObjectMap};
 
 #[derive(Debug)]
@@ -18,28 +18,118 @@ pub enum PatternFail {
     ObjectKeyMismatch,
     EvalError,
     LiteralMismatch,
+    NoAlternativeMatched,
+    GuardFailed,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Bindings<'i, 's, 'v> {
+    entries: Vec<(Identifier<'i>, Value<'s, 'v>)>,
+}
+
+impl<'i, 's, 'v> Bindings<'i, 's, 'v> {
+    pub fn get(&self, name: &str) -> Option<&Value<'s, 'v>> {
+        self.entries
+            .iter()
+            .find(|(id, _)| id.name == name)
+            .map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Identifier<'i>, &Value<'s, 'v>)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn push(&mut self, id: Identifier<'i>, value: Value<'s, 'v>) {
+        self.entries.push((id, value));
+    }
+
+    pub fn checkpoint(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn rollback(&mut self, checkpoint: usize) {
+        self.entries.truncate(checkpoint);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn merge_into(&self, env: &mut Environment<'i, 's, 'v>) {
+        for (k, v) in &self.entries {
+            env.bindings.insert(k.clone(), v.clone());
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Matcher<'i, 's, 'v, 'e> {
     pub outer_env: &'e Environment<'i, 's, 'v>,
-    pub local_env: Environment<'i, 's, 'v>,
+    pub bindings: Bindings<'i, 's, 'v>,
 }
 
 impl<'i, 's, 'v, 'e> Matcher<'i, 's, 'v, 'e> {
     pub fn into_env<'x>(self) -> Environment<'i, 's, 'v> {
-        self.local_env
+        let mut env = Environment::new();
+        self.bindings.merge_into(&mut env);
+        env
+    }
+
+    pub fn merge_into(&self, env: &mut Environment<'i, 's, 'v>) {
+        self.bindings.merge_into(env);
+    }
+
+    pub fn checkpoint(&self) -> usize {
+        self.bindings.checkpoint()
+    }
+
+    pub fn rollback(&mut self, checkpoint: usize) {
+        self.bindings.rollback(checkpoint);
     }
 
     pub fn match_pattern<'x>(
         &'x mut self,
         pattern: &'x Pattern<'s>,
         value: &Value<'s, 'v>,
+    ) -> Result<(), PatternFail> {
+        if !pattern.is_ambiguous() {
+            return self.match_single(pattern, value);
+        }
+
+        let Some(first) = self.match_all(pattern, Cow::Borrowed(value)).next() else {
+            return Err(PatternFail::NoAlternativeMatched);
+        };
+        *self = first;
+        Ok(())
+    }
+
+    pub fn match_all<'x>(
+        &self,
+        pattern: &'x Pattern<'s>,
+        value: Cow<'x, Value<'s, 'v>>,
+    ) -> Solutions<'i, 's, 'v, 'e, 'x> {
+        Solutions {
+            stack: vec![(self.clone(), vec![Goal::Match(Cow::Borrowed(pattern), value)])],
+        }
+    }
+
+    fn match_single<'x>(
+        &'x mut self,
+        pattern: &'x Pattern<'s>,
+        value: &Value<'s, 'v>,
     ) -> Result<(), PatternFail> {
         match &pattern {
             Pattern::Discard => Ok(()),
             Pattern::Capture(name, pat) => self
-                .match_pattern(pat, value)
+                .match_single(pat, value)
                 .and_then(|_| self.match_identifier(name, value)),
             Pattern::Identifier(name) => self.match_identifier(name, value),
             Pattern::TypedDiscard(t) => {
@@ -55,68 +145,107 @@ impl<'i, 's, 'v, 'e> Matcher<'i, 's, 'v, 'e> {
                 }
                 self.match_identifier(name, value)
             }
-            Pattern::Object(pattern, rest) => {
+            Pattern::Regex(source) => {
+                let Value::String(s) = value else {
+                    return Err(PatternFail::TypeMismatch);
+                };
+                match regex_cache::compile(source) {
+                    Some(regex) if regex.is_match(s) => Ok(()),
+                    _ => Err(PatternFail::LiteralMismatch),
+                }
+            }
+            Pattern::Object(props, rest) => {
                 let Value::Object(o) = value else {
                     return Err(PatternFail::ObjectMissmatch);
                 };
-                self.match_object(pattern, rest, o)
+                for (p, v) in self.object_parts(props, rest, o)? {
+                    self.match_single(&p, &v)?
+                }
+                Ok(())
             }
             Pattern::Array(items, rest) => {
                 let Value::Array(a) = value else {
                     return Err(PatternFail::ArrayMissmatch);
                 };
-                self.match_array(items, rest, a)
+                for (p, v) in array_parts(items, rest, a)? {
+                    self.match_single(&p, &v)?
+                }
+                Ok(())
             }
             Pattern::Literal(l) => self.match_literal(l, value),
+            Pattern::Guarded(pat, guard) => {
+                self.match_single(pat, value)?;
+                self.match_guard(guard)
+            }
+            Pattern::Or(_) | Pattern::Descendant(_) => self.match_pattern(pattern, value),
         }
     }
 
-    fn match_identifier<'x>(
+    pub(crate) fn match_identifier<'x>(
         &'x mut self,
         name: &'x Identifier<'x>,
         value: &Value<'s, 'v>,
     ) -> Result<(), PatternFail> {
-        let id = Identifier {
-            name: Cow::Owned(name.name.to_string()),
-        };
-
-        match self.local_env.bindings.entry(id) {
-            Entry::Occupied(entry) => {
-                if value == entry.get() {
-                    Ok(())
-                } else {
-                    Err(PatternFail::IdentifierConflict)
-                }
-            }
-            Entry::Vacant(entry) => {
-                entry.insert(value.clone());
+        match self.bindings.get(&name.name) {
+            Some(bound) if bound == value => Ok(()),
+            Some(_) => Err(PatternFail::IdentifierConflict),
+            None => {
+                self.bindings.push(name.deep_clone(), value.clone());
                 Ok(())
             }
         }
     }
 
-    fn match_object<'x>(
-        &'x mut self,
-        props: &[ObjectPropertyPattern<'s>],
-        rest: &Rest<'s>,
-        value: &ValueObjectMap<'s, 'v>,
-    ) -> Result<(), PatternFail> {
+    // The guard sees the outer environment together with everything bound so
+    // far, ie the guarded pattern itself and the parts left of it.
+    fn match_guard(&self, guard: &Expression<'s>) -> Result<(), PatternFail> {
+        let mut env = self.outer_env.clone();
+        self.bindings.merge_into(&mut env);
+        match env.eval_expr(guard) {
+            Ok(Value::Boolean(true)) => Ok(()),
+            Ok(_) => Err(PatternFail::GuardFailed),
+            Err(_) => Err(PatternFail::EvalError),
+        }
+    }
+
+    // The patterns of the properties or items of an object or array pattern
+    // along with the values they have to match.
+    fn parts<'p, 'x>(
+        &self,
+        pattern: &'p Pattern<'s>,
+        value: &'x Value<'s, 'v>,
+    ) -> Result<Vec<(Cow<'p, Pattern<'s>>, Cow<'x, Value<'s, 'v>>)>, PatternFail> {
+        match (pattern, value) {
+            (Pattern::Object(props, rest), Value::Object(o)) => self.object_parts(props, rest, o),
+            (Pattern::Object(..), _) => Err(PatternFail::ObjectMissmatch),
+            (Pattern::Array(items, rest), Value::Array(a)) => array_parts(items, rest, a),
+            _ => Err(PatternFail::ArrayMissmatch),
+        }
+    }
+
+    fn object_parts<'p, 'x>(
+        &self,
+        props: &'p [ObjectPropertyPattern<'s>],
+        rest: &'p Rest<'s>,
+        value: &'x ValueObjectMap<'s, 'v>,
+    ) -> Result<Vec<(Cow<'p, Pattern<'s>>, Cow<'x, Value<'s, 'v>>)>, PatternFail> {
         if let Rest::Exact = rest {
             if value.len() != props.len() {
                 return Err(PatternFail::ObjectLengthMismatch);
             }
         }
 
+        let mut parts = Vec::with_capacity(props.len() + 1);
         let mut keys = value.keys().collect::<BTreeSet<_>>();
         for prop in props {
             let (k, v) = match prop {
                 ObjectPropertyPattern::Single(key) => {
-                    (key.name.clone(), Pattern::Identifier(key.clone()))
+                    (key.name.clone(), Cow::Owned(Pattern::Identifier(key.clone())))
                 }
                 ObjectPropertyPattern::Match(PropertyPattern {
                     key: PropertyKey::Identifier(key),
                     value,
-                }) => (key.name.clone(), value.clone()),
+                }) => (key.name.clone(), Cow::Borrowed(value)),
                 ObjectPropertyPattern::Match(PropertyPattern {
                     key: PropertyKey::Expression(exp),
                     value,
@@ -124,7 +253,7 @@ impl<'i, 's, 'v, 'e> Matcher<'i, 's, 'v, 'e> {
                     let Ok(Value::String(k)) = self.outer_env.eval_expr(exp) else {
                         return Err(PatternFail::EvalError);
                     };
-                    (k.clone(), value.clone())
+                    (k.clone(), Cow::Borrowed(value))
                 }
             };
 
@@ -136,7 +265,7 @@ impl<'i, 's, 'v, 'e> Matcher<'i, 's, 'v, 'e> {
                 return Err(PatternFail::ObjectKeyMismatch);
             };
 
-            self.match_pattern(&v, actual_value.as_ref())?
+            parts.push((v, Cow::Borrowed(actual_value.as_ref())));
         }
 
         if let Rest::Collect(rest_pattern) = rest {
@@ -144,44 +273,17 @@ impl<'i, 's, 'v, 'e> Matcher<'i, 's, 'v, 'e> {
                 .iter()
                 .map(|&k| (k.clone(), value.get(k).unwrap().clone()))
                 .collect();
-            self.match_pattern(rest_pattern, &Value::Object(remaining))
-        } else {
-            Ok(())
-        }
-    }
-
-    fn match_array<'x>(
-        &'x mut self,
-        items: &[ArrayPatternItem<'s>],
-        rest: &Rest<'s>,
-        value: &Vec<Cow<'v, Value<'s, 'v>>>,
-    ) -> Result<(), PatternFail> {
-        if let Rest::Exact = rest {
-            if value.len() != items.len() {
-                return Err(PatternFail::ArrayLengthMismatch);
-            }
+            parts.push((
+                Cow::Borrowed(rest_pattern.as_ref()),
+                Cow::Owned(Value::Object(remaining)),
+            ));
         }
 
-        if value.len() < items.len() {
-            return Err(PatternFail::ArrayLengthMismatch);
-        }
-
-        for (ArrayPatternItem::Pattern(p), val) in std::iter::zip(items, value.iter()) {
-            self.match_pattern(p, val.as_ref())?
-        }
-
-        if let Rest::Collect(rest_pattern) = rest {
-            self.match_pattern(
-                rest_pattern,
-                &Value::Array(value.iter().skip(items.len()).cloned().collect()),
-            )
-        } else {
-            Ok(())
-        }
+        Ok(parts)
     }
 
     pub fn clear(&mut self) {
-        self.local_env.bindings.clear();
+        self.bindings.clear();
     }
 
     fn match_literal(&self, literal: &Literal, value: &Value) -> Result<(), PatternFail> {
@@ -191,8 +293,10 @@ impl<'i, 's, 'v, 'e> Matcher<'i, 's, 'v, 'e> {
             (Literal::Number(n), Value::Integer(i)) => {
                 str::parse::<i64>(n).map(|p| &p == i).unwrap_or(false)
             }
+            (Literal::Number(n), v @ Value::BigInt(_)) => Value::parse_integer(n).as_ref() == Some(v),
             (Literal::Boolean(a), Value::Boolean(b)) => a == b,
             (Literal::Type(a), Value::Type(b)) => a == b,
+            (Literal::DateTime(t), v @ Value::DateTime(_)) => Value::parse_datetime(t).as_ref() == Some(v),
             _ => false,
         };
 
@@ -205,7 +309,148 @@ impl<'i, 's, 'v, 'e> Matcher<'i, 's, 'v, 'e> {
     pub fn new<'x:'e>(env: &'x Environment<'i, 's, 'v>) -> Self {
         Self {
             outer_env: &env,
-            local_env: Environment::new(),
+            bindings: Bindings::default(),
         }
     }
 }
+
+// A step left to find a solution: a pattern to match against a value, or
+// the binding or guard of a capture or guarded pattern around an ambiguous
+// one, to be done once the inner pattern matched.
+#[derive(Clone)]
+enum Goal<'x, 's, 'v> {
+    Match(Cow<'x, Pattern<'s>>, Cow<'x, Value<'s, 'v>>),
+    Bind(&'x Identifier<'s>, Cow<'x, Value<'s, 'v>>),
+    Guard(&'x Expression<'s>),
+}
+
+// The solutions of matching a value against a pattern, found one after the
+// other by backtracking. They are ordered by the alternatives of or patterns
+// and by the position of the values descendant patterns descend into.
+pub struct Solutions<'i, 's, 'v, 'e, 'x> {
+    // The goals of each matcher are kept in reverse, the next one last.
+    stack: Vec<(Matcher<'i, 's, 'v, 'e>, Vec<Goal<'x, 's, 'v>>)>,
+}
+
+impl<'i, 's, 'v, 'e, 'x> Iterator for Solutions<'i, 's, 'v, 'e, 'x> {
+    type Item = Matcher<'i, 's, 'v, 'e>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((mut matcher, mut goals)) = self.stack.pop() {
+            let (pattern, value) = match goals.pop() {
+                None => return Some(matcher),
+                Some(Goal::Match(pattern, value)) => (pattern, value),
+                Some(Goal::Bind(name, value)) => {
+                    if matcher.match_identifier(name, &value).is_ok() {
+                        self.stack.push((matcher, goals));
+                    }
+                    continue;
+                }
+                Some(Goal::Guard(guard)) => {
+                    if matcher.match_guard(guard).is_ok() {
+                        self.stack.push((matcher, goals));
+                    }
+                    continue;
+                }
+            };
+
+            // Only the identifiers of shorthand properties are owned patterns
+            // and they are never ambiguous.
+            let pattern = match pattern {
+                Cow::Borrowed(p) if p.is_ambiguous() => p,
+                p => {
+                    if matcher.match_single(&p, &value).is_ok() {
+                        self.stack.push((matcher, goals));
+                    }
+                    continue;
+                }
+            };
+
+            match pattern {
+                Pattern::Or(alternatives) => {
+                    for alternative in alternatives.iter().rev() {
+                        let mut goals = goals.clone();
+                        goals.push(Goal::Match(Cow::Borrowed(alternative), value.clone()));
+                        self.stack.push((matcher.clone(), goals));
+                    }
+                }
+                Pattern::Descendant(inner) => {
+                    let children = match &value {
+                        Cow::Borrowed(v) => children(v),
+                        Cow::Owned(v) => children(v).into_iter().map(|c| Cow::Owned(c.into_owned())).collect(),
+                    };
+                    for child in children.into_iter().rev() {
+                        let mut goals = goals.clone();
+                        goals.push(Goal::Match(Cow::Borrowed(pattern), child));
+                        self.stack.push((matcher.clone(), goals));
+                    }
+                    goals.push(Goal::Match(Cow::Borrowed(inner), value));
+                    self.stack.push((matcher, goals));
+                }
+                Pattern::Capture(name, inner) => {
+                    goals.push(Goal::Bind(name, value.clone()));
+                    goals.push(Goal::Match(Cow::Borrowed(inner), value));
+                    self.stack.push((matcher, goals));
+                }
+                Pattern::Guarded(inner, guard) => {
+                    goals.push(Goal::Guard(guard));
+                    goals.push(Goal::Match(Cow::Borrowed(inner), value));
+                    self.stack.push((matcher, goals));
+                }
+                Pattern::Object(..) | Pattern::Array(..) => {
+                    let parts = match &value {
+                        Cow::Borrowed(v) => matcher.parts(pattern, v),
+                        Cow::Owned(v) => matcher.parts(pattern, v).map(|parts| {
+                            parts.into_iter().map(|(p, v)| (p, Cow::Owned(v.into_owned()))).collect()
+                        }),
+                    };
+                    let Ok(parts) = parts else {
+                        continue;
+                    };
+                    goals.extend(parts.into_iter().rev().map(|(p, v)| Goal::Match(p, v)));
+                    self.stack.push((matcher, goals));
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+}
+
+fn children<'x, 's, 'v>(value: &'x Value<'s, 'v>) -> Vec<Cow<'x, Value<'s, 'v>>> {
+    match value {
+        Value::Array(a) => a.iter().map(|v| Cow::Borrowed(v.as_ref())).collect(),
+        Value::Object(o) => o.values().map(|v| Cow::Borrowed(v.as_ref())).collect(),
+        _ => vec![],
+    }
+}
+
+fn array_parts<'p, 'x, 's, 'v>(
+    items: &'p [ArrayPatternItem<'s>],
+    rest: &'p Rest<'s>,
+    value: &'x [Cow<'v, Value<'s, 'v>>],
+) -> Result<Vec<(Cow<'p, Pattern<'s>>, Cow<'x, Value<'s, 'v>>)>, PatternFail> {
+    if let Rest::Exact = rest {
+        if value.len() != items.len() {
+            return Err(PatternFail::ArrayLengthMismatch);
+        }
+    }
+
+    if value.len() < items.len() {
+        return Err(PatternFail::ArrayLengthMismatch);
+    }
+
+    let mut parts = std::iter::zip(items, value.iter())
+        .map(|(ArrayPatternItem::Pattern(p), val)| (Cow::Borrowed(p), Cow::Borrowed(val.as_ref())))
+        .collect::<Vec<_>>();
+
+    if let Rest::Collect(rest_pattern) = rest {
+        parts.push((
+            Cow::Borrowed(rest_pattern.as_ref()),
+            Cow::Owned(Value::Array(value.iter().skip(items.len()).cloned().collect())),
+        ));
+    }
+
+    Ok(parts)
+}
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::value::Value;
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub(crate) enum MemoKey<'s, 'v> {
+    Call(String, Vec<Value<'s, 'v>>),
+    // Templates are identified by their source, so that equal templates
+    // share their entries.
+    Template(String, Vec<Value<'s, 'v>>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Memo<'s, 'v> {
+    entries: Arc<Mutex<HashMap<MemoKey<'s, 'v>, Value<'s, 'v>>>>,
+}
+
+impl<'s, 'v> Memo<'s, 'v> {
+    pub(crate) fn get(&self, key: &MemoKey<'s, 'v>) -> Option<Value<'s, 'v>> {
+        self.entries.lock().ok()?.get(key).cloned()
+    }
+
+    pub(crate) fn put(&self, key: MemoKey<'s, 'v>, value: Value<'s, 'v>) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(key, value);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().map(|e| e.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
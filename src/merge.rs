@@ -0,0 +1,130 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::env::{Environment, EvalError};
+use crate::expression::Expression;
+use crate::value::{Closure, Value};
+
+// Which value is kept by `.merge` if a value of the source bag has the same
+// key as a value of the target bag. `Newest` keeps the value for which the
+// given function results in the greater value, eg `newest by fn({time, ...}) => time`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum Preference<'s> {
+    #[default]
+    Source,
+    Target,
+    Newest(Expression<'s>),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MergeCounts {
+    pub inserted: usize,
+    pub updated: usize,
+    pub skipped: usize,
+}
+
+pub(crate) enum MergeResult {
+    Success(MergeCounts),
+    GuardError,
+    EvalError(EvalError),
+}
+
+// The positions of the target values to be replaced and the values to be
+// inserted into the target bag.
+pub(crate) struct MergePlan<'s, 'v> {
+    pub(crate) replaced: BTreeSet<usize>,
+    pub(crate) insertions: Vec<Value<'s, 'v>>,
+    pub(crate) counts: MergeCounts,
+}
+
+fn closure<'s, 'v>(
+    env: &Environment<'_, 's, 'v>,
+    expression: &Expression<'s>,
+) -> Result<Closure<'s, 'v>, EvalError> {
+    match env.eval_expr(expression)? {
+        Value::Closure(closure) => Ok(*closure),
+        _ => Err(EvalError::TypeError),
+    }
+}
+
+// The value currently stored for a key, the position of the target value it
+// replaces and its position in the insertions, if it is to be inserted.
+struct Slot<'s, 'v> {
+    value: Value<'s, 'v>,
+    position: Option<usize>,
+    insertion: Option<usize>,
+}
+
+// Each source value is merged with the value stored for its key so far,
+// which is either the first target value of that key or an already merged
+// source value. Every source value counts as either inserted, updated or
+// skipped.
+pub(crate) fn plan<'s, 'v>(
+    env: &Environment<'_, 's, 'v>,
+    source: &[Value<'s, 'v>],
+    target: &[Value<'s, 'v>],
+    key: &Expression<'s>,
+    preference: &Preference<'s>,
+) -> Result<MergePlan<'s, 'v>, EvalError> {
+    let key = closure(env, key)?;
+    let newest = match preference {
+        Preference::Newest(by) => Some(closure(env, by)?),
+        _ => None,
+    };
+
+    let mut slots = BTreeMap::new();
+    for (position, value) in target.iter().enumerate() {
+        slots.entry(Environment::apply(&key, value)?).or_insert(Slot {
+            value: value.clone(),
+            position: Some(position),
+            insertion: None,
+        });
+    }
+
+    let mut plan = MergePlan {
+        replaced: BTreeSet::new(),
+        insertions: vec![],
+        counts: MergeCounts::default(),
+    };
+    for value in source {
+        let k = Environment::apply(&key, value)?;
+        let Some(slot) = slots.get_mut(&k) else {
+            plan.counts.inserted += 1;
+            plan.insertions.push(value.clone());
+            slots.insert(
+                k,
+                Slot {
+                    value: value.clone(),
+                    position: None,
+                    insertion: Some(plan.insertions.len() - 1),
+                },
+            );
+            continue;
+        };
+
+        let replace = slot.value != *value
+            && match (preference, &newest) {
+                (Preference::Target, _) => false,
+                (_, Some(by)) => Environment::apply(by, value)? > Environment::apply(by, &slot.value)?,
+                _ => true,
+            };
+        if !replace {
+            plan.counts.skipped += 1;
+            continue;
+        }
+
+        plan.counts.updated += 1;
+        slot.value = value.clone();
+        match slot.insertion {
+            Some(i) => plan.insertions[i] = value.clone(),
+            None => {
+                plan.insertions.push(value.clone());
+                slot.insertion = Some(plan.insertions.len() - 1);
+                plan.replaced.extend(slot.position);
+            }
+        }
+    }
+
+    Ok(plan)
+}
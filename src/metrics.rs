@@ -0,0 +1,58 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static SCANNED: Cell<usize> = const { Cell::new(0) };
+}
+
+// Called for every value a pattern is matched against.
+pub(crate) fn count_scanned() {
+    SCANNED.with(|scanned| scanned.set(scanned.get() + 1));
+}
+
+pub(crate) fn take_scanned() -> usize {
+    SCANNED.with(|scanned| scanned.replace(0))
+}
+
+// There is no clock in wasm32-unknown-unknown, so durations are reported as
+// zero there.
+pub(crate) struct Stopwatch(Option<Instant>);
+
+impl Stopwatch {
+    pub(crate) fn start() -> Self {
+        Self((!cfg!(target_arch = "wasm32")).then(Instant::now))
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.0.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub duration: Duration,
+    pub scanned: usize,
+    pub affected: usize,
+}
+
+impl Metrics {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "duration_us": self.duration.as_micros() as u64,
+            "scanned": self.scanned,
+            "affected": self.affected,
+        })
+    }
+}
+
+impl std::fmt::Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.3}ms, {} scanned, {} affected",
+            self.duration.as_secs_f64() * 1000.0,
+            self.scanned,
+            self.affected
+        )
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::expression::PropertyKey;
+use crate::graph::Connection;
+use crate::identifier::Identifier;
+use crate::literal::Literal;
+use crate::pattern::{ObjectPropertyPattern, Pattern, PropertyPattern};
+use crate::value::{Value, ValueType};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum LiteralKey {
+    Null,
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Type(ValueType),
+    DateTime(i64),
+}
+
+impl LiteralKey {
+    fn from_literal(literal: &Literal) -> Option<Self> {
+        Some(match literal {
+            Literal::Null => LiteralKey::Null,
+            Literal::String(s) => LiteralKey::String(s.to_string()),
+            Literal::Number(n) => LiteralKey::Integer(str::parse::<i64>(n).ok()?),
+            Literal::Boolean(b) => LiteralKey::Boolean(*b),
+            Literal::Type(t) => LiteralKey::Type(*t),
+            Literal::DateTime(t) => match Value::parse_datetime(t)? {
+                Value::DateTime(t) => LiteralKey::DateTime(t),
+                _ => return None,
+            },
+            Literal::Regex(_) => return None,
+        })
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::Null => LiteralKey::Null,
+            Value::String(s) => LiteralKey::String(s.to_string()),
+            Value::Integer(i) => LiteralKey::Integer(*i),
+            Value::Boolean(b) => LiteralKey::Boolean(*b),
+            Value::Type(t) => LiteralKey::Type(*t),
+            Value::DateTime(t) => LiteralKey::DateTime(*t),
+            Value::BigInt(_)
+            | Value::Array(_)
+            | Value::Object(_)
+            | Value::Closure(_)
+            | Value::Pattern(_)
+            | Value::Expression(_) => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rule<'s> {
+    pub connection: Identifier<'s>,
+    pub consumer: usize,
+    pub pattern: usize,
+}
+
+impl std::fmt::Display for Rule<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (consumer {}, pattern {})",
+            self.connection, self.consumer, self.pattern
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct BagNode<'s> {
+    any: Vec<Rule<'s>>,
+    by_type: BTreeMap<ValueType, Vec<Rule<'s>>>,
+    by_literal: HashMap<LiteralKey, Vec<Rule<'s>>>,
+    by_field: BTreeMap<String, HashMap<LiteralKey, Vec<Rule<'s>>>>,
+}
+
+impl<'s> BagNode<'s> {
+    fn add(&mut self, pattern: &Pattern, rule: Rule<'s>) {
+        match pattern {
+            Pattern::Capture(_, p) | Pattern::Guarded(p, _) => self.add(p, rule),
+            Pattern::Or(alternatives) => {
+                for p in alternatives {
+                    self.add(p, rule.clone());
+                }
+            }
+            // Literals without a key, eg integers beyond i64, are tested by
+            // the solver for every value.
+            Pattern::Literal(l) => match LiteralKey::from_literal(l) {
+                Some(key) => self.by_literal.entry(key).or_default().push(rule),
+                None => self.any.push(rule),
+            },
+            Pattern::TypedDiscard(t) | Pattern::TypedIdentifier(_, t) => {
+                self.by_type.entry(*t).or_default().push(rule)
+            }
+            Pattern::Regex(_) => self.by_type.entry(ValueType::String).or_default().push(rule),
+            Pattern::Object(props, _) => {
+                let discriminator = props.iter().find_map(|p| match p {
+                    ObjectPropertyPattern::Match(PropertyPattern {
+                        key: PropertyKey::Identifier(key),
+                        value: Pattern::Literal(l),
+                    }) => Some((key.name.to_string(), LiteralKey::from_literal(l)?)),
+                    _ => None,
+                });
+
+                match discriminator {
+                    Some((field, key)) => self
+                        .by_field
+                        .entry(field)
+                        .or_default()
+                        .entry(key)
+                        .or_default()
+                        .push(rule),
+                    None => self.by_type.entry(ValueType::Object).or_default().push(rule),
+                }
+            }
+            Pattern::Array(..) => self.by_type.entry(ValueType::Array).or_default().push(rule),
+            Pattern::Discard | Pattern::Identifier(_) | Pattern::Descendant(_) => {
+                self.any.push(rule)
+            }
+        }
+    }
+
+    fn remove(&mut self, connection: &Identifier) {
+        let keep = |r: &Rule| &r.connection != connection;
+
+        self.any.retain(keep);
+        for rules in self.by_type.values_mut() {
+            rules.retain(keep);
+        }
+        for rules in self.by_literal.values_mut() {
+            rules.retain(keep);
+        }
+        for values in self.by_field.values_mut() {
+            for rules in values.values_mut() {
+                rules.retain(keep);
+            }
+        }
+    }
+
+    fn candidates<'x>(&'x self, value: &Value) -> impl Iterator<Item = &'x Rule<'s>> {
+        let by_field = match value {
+            Value::Object(o) => o
+                .iter()
+                .filter_map(|(k, v)| {
+                    let values = self.by_field.get(k.as_ref())?;
+                    values.get(&LiteralKey::from_value(v)?)
+                })
+                .collect(),
+            _ => vec![],
+        };
+
+        self.any
+            .iter()
+            .chain(self.by_type.get(&value.get_type()).into_iter().flatten())
+            .chain(
+                LiteralKey::from_value(value)
+                    .and_then(|k| self.by_literal.get(&k))
+                    .into_iter()
+                    .flatten(),
+            )
+            .chain(by_field.into_iter().flatten())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Network<'s> {
+    bags: HashMap<Identifier<'s>, BagNode<'s>>,
+}
+
+impl<'s> Network<'s> {
+    pub(crate) fn add(&mut self, name: &Identifier<'s>, connection: &Connection<'s>) {
+        for (c, consumer) in connection.consumers.iter().enumerate() {
+            let node = self.bags.entry(consumer.source_bag.clone()).or_default();
+            for (p, pattern) in consumer.patterns.iter().enumerate() {
+                node.add(
+                    pattern,
+                    Rule {
+                        connection: name.clone(),
+                        consumer: c,
+                        pattern: p,
+                    },
+                );
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, name: &Identifier) {
+        for node in self.bags.values_mut() {
+            node.remove(name);
+        }
+    }
+
+    pub fn candidates(&self, bag: &Identifier<'s>, value: &Value) -> Vec<&Rule<'s>> {
+        let Some(node) = self.bags.get(bag) else {
+            return vec![];
+        };
+
+        let mut rules = node.candidates(value).collect::<Vec<_>>();
+        rules.sort();
+        rules.dedup();
+        rules
+    }
+}
+
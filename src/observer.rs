@@ -0,0 +1,55 @@
+use std::borrow::Cow;
+
+use crate::{identifier::Identifier, value::Value};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mutation<'s, 'v> {
+    Insert(Value<'s, 'v>),
+    Update {
+        before: Value<'s, 'v>,
+        after: Value<'s, 'v>,
+    },
+    Delete(Value<'s, 'v>),
+}
+
+#[derive(Debug)]
+pub struct ObserverError;
+
+// Gets notified about the changes of a bag once per committed transaction,
+// after backends are flushed and the bundle is persisted. An error fails the
+// commit.
+pub trait MutationObserver<'s, 'v>: Send + Sync {
+    fn notify(
+        &self,
+        bag: &Identifier<'s>,
+        mutations: &[Mutation<'s, 'v>],
+    ) -> Result<(), ObserverError>;
+}
+
+impl<'s, 'v, F> MutationObserver<'s, 'v> for F
+where
+    F: Fn(&Identifier<'s>, &[Mutation<'s, 'v>]) + Send + Sync,
+{
+    fn notify(
+        &self,
+        bag: &Identifier<'s>,
+        mutations: &[Mutation<'s, 'v>],
+    ) -> Result<(), ObserverError> {
+        self(bag, mutations);
+        Ok(())
+    }
+}
+
+// Values changed in place, compared position by position.
+pub(crate) fn updates<'s, 'v>(
+    before: &[Cow<'v, Value<'s, 'v>>],
+    after: &[Cow<'v, Value<'s, 'v>>],
+) -> Vec<Mutation<'s, 'v>> {
+    std::iter::zip(before, after)
+        .filter(|(b, a)| b != a)
+        .map(|(b, a)| Mutation::Update {
+            before: b.as_ref().clone(),
+            after: a.as_ref().clone(),
+        })
+        .collect()
+}
@@ -1,28 +1,135 @@
 use std::borrow::Cow;
 
 use nom::branch::alt;
-use nom::bytes::complete::{is_not, tag, take_until};
-use nom::character::complete::{alpha1, alphanumeric1, char, i64, multispace0, space0, space1};
-use nom::combinator::{all_consuming, map, opt, recognize, value, verify};
-use nom::error::ParseError;
+use nom::bytes::complete::{is_not, take_until, take_while, take_while1, take_while_m_n};
+use nom::character::complete::{
+    alpha1, anychar, char, digit1, i64, multispace0, one_of, satisfy, space0,
+    space1,
+};
+use nom::combinator::{consumed, map, map_res, not, opt, recognize, value, verify};
+use nom::error::{ErrorKind, FromExternalError, ParseError as NomParseError};
 use nom::multi::{
-    fold_many0, many0, many0_count, many1, many1_count, separated_list0, separated_list1,
+    fold_many0, many0, many1, separated_list0, separated_list1,
 };
 use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
-use nom::IResult;
 
 use crate::assignment::{Assignment, AssignmentSet};
+use crate::bag::StorageMode;
+use crate::breakpoint::Breakpoint;
+use crate::collation::Collation;
+use crate::debugger::StepAction;
 use crate::expression::*;
-use crate::graph::{Connection, Consumer, Producer, Signature, Consumption};
+use crate::graph::{Absence, Connection, Consumer, Producer, Signature, Consumption};
 use crate::identifier::Identifier;
+use crate::limits::{LimitError, Limits};
 use crate::literal::Literal;
+use crate::log::LogLevel;
+use crate::merge::Preference;
 use crate::pattern::*;
+use crate::plan::Plans;
 use crate::query::{
-    CrossPredicate, DeletionQuery, Insertion, Predicate, ProjectionQuery, TransferQuery,
-    UpdateQuery,
+    extract_aggregates, CrossPredicate, DeletionQuery, Insertion, OrderBy, Predicate, ProjectionQuery,
+    QueryHint, TransferQuery, UpdateQuery,
 };
-use crate::statement::Statement;
+use crate::statement::{DataFormat, ErrorPolicy, Statement};
 use crate::value::ValueType;
+use crate::virtual_bag::IntegerRange;
+
+type IResult<I, O, E = ParseFailure<I>> = nom::IResult<I, O, E>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expected {
+    Token(&'static str),
+    Char(char),
+    EndOfInput,
+}
+
+impl std::fmt::Display for Expected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expected::Token(t) => write!(f, "`{}`", t.trim()),
+            Expected::Char(c) => write!(f, "`{c}`"),
+            Expected::EndOfInput => write!(f, "end of input"),
+        }
+    }
+}
+
+// Error type of all parsers in this module. Of the alternatives tried by `alt`
+// the one that got furthest into the input is kept, so the reported position
+// points at the actual mistake instead of the start of the statement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseFailure<I> {
+    pub remaining: I,
+    pub expected: Vec<Expected>,
+}
+
+impl<I> ParseFailure<I> {
+    fn expecting(remaining: I, expected: Expected) -> Self {
+        Self {
+            remaining,
+            expected: vec![expected],
+        }
+    }
+}
+
+impl<'a> NomParseError<&'a str> for ParseFailure<&'a str> {
+    fn from_error_kind(input: &'a str, _kind: ErrorKind) -> Self {
+        Self {
+            remaining: input,
+            expected: vec![],
+        }
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn from_char(input: &'a str, c: char) -> Self {
+        Self::expecting(input, Expected::Char(c))
+    }
+
+    fn or(mut self, other: Self) -> Self {
+        match self.remaining.len().cmp(&other.remaining.len()) {
+            std::cmp::Ordering::Less => self,
+            std::cmp::Ordering::Greater => other,
+            std::cmp::Ordering::Equal => {
+                for e in other.expected {
+                    if !self.expected.contains(&e) {
+                        self.expected.push(e);
+                    }
+                }
+                self
+            }
+        }
+    }
+}
+
+impl<'a, E> FromExternalError<&'a str, E> for ParseFailure<&'a str> {
+    fn from_external_error(input: &'a str, kind: ErrorKind, _e: E) -> Self {
+        Self::from_error_kind(input, kind)
+    }
+}
+
+fn tag<'a>(token: &'static str) -> impl Fn(&'a str) -> IResult<&'a str, &'a str> {
+    move |input| {
+        nom::bytes::complete::tag(token)(input)
+            .map_err(|e| e.map(|_: ParseFailure<&str>| ParseFailure::expecting(input, Expected::Token(token))))
+    }
+}
+
+fn all_consuming<'a, O, F>(mut parser: F) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O>,
+{
+    move |input| {
+        let (rest, output) = parser(input)?;
+        if rest.is_empty() {
+            Ok((rest, output))
+        } else {
+            Err(nom::Err::Error(ParseFailure::expecting(rest, Expected::EndOfInput)))
+        }
+    }
+}
 
 fn array_item_expression<'v>(input: &str) -> IResult<&str, ArrayItem<'v>> {
     alt((
@@ -31,25 +138,57 @@ fn array_item_expression<'v>(input: &str) -> IResult<&str, ArrayItem<'v>> {
     ))(input)
 }
 
-fn ws<'a, F, O, E: ParseError<&'a str>>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, E>
+fn ws<'a, F, O, E: NomParseError<&'a str>>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, E>
 where
     F: FnMut(&'a str) -> IResult<&'a str, O, E>,
 {
     delimited(multispace0, inner, multispace0)
 }
 
+// The words of expressions and clauses, completed and highlighted by the
+// frontends along with the names of the types.
+pub(crate) const KEYWORDS: &[&str] = &[
+    "as", "asc", "by", "desc", "false", "fn", "group", "in", "into", "is", "limit", "matches",
+    "null", "offset", "on", "order", "pattern", "quote", "true", "virtual", "where",
+];
+
+// A word that must not continue as an identifier, eg `as` but not the
+// start of `ascending`.
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(tag(word), not(satisfy(|c: char| c.is_alphanumeric() || c == '_')))
+}
+
 fn expression_call<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     map(
         pair(
             identifier,
+            delimited(
+                ws(tag("(")),
+                separated_list0(ws(tag(",")), expression),
+                ws(tag(")")),
+            ),
+        ),
+        |(function, arguments)| Expression::Call(CallExpression { function, arguments }),
+    )(input)
+}
+
+fn expression_quoted_pattern<'v>(input: &str) -> IResult<&str, Expression<'v>> {
+    map(
+        preceded(
+            tag("pattern"),
+            delimited(ws(tag("(")), pattern, ws(tag(")"))),
+        ),
+        |p| Expression::Pattern(Box::new(p)),
+    )(input)
+}
+
+fn expression_quoted<'v>(input: &str) -> IResult<&str, Expression<'v>> {
+    map(
+        preceded(
+            tag("quote"),
             delimited(ws(tag("(")), expression, ws(tag(")"))),
         ),
-        |(function, arg)| {
-            Expression::Call(CallExpression {
-                function,
-                argument: Box::new(arg),
-            })
-        },
+        |e| Expression::Quote(Box::new(e)),
     )(input)
 }
 
@@ -83,7 +222,7 @@ fn object_prop_expression<'v>(input: &str) -> IResult<&str, ObjectProperty<'v>>
             },
         ),
         map(
-            separated_pair(identifier, ws(tag(":")), expression),
+            separated_pair(property_name, ws(tag(":")), expression),
             |(prop, value)| {
                 ObjectProperty::Property(Property {
                     key: PropertyKey::Identifier(prop),
@@ -137,13 +276,29 @@ fn expression_atom<'v>(input: &str) -> IResult<&str, Expression<'v>> {
             literal_bool,
             literal_number,
             literal_type,
+            literal_regex,
+            literal_datetime,
         )),
         Expression::Literal,
     )(input)
 }
 
 fn expression_identifier<'v>(input: &str) -> IResult<&str, Expression<'v>> {
-    map(identifier, Expression::Identifier)(input)
+    map(alt((identifier, parameter)), Expression::Identifier)(input)
+}
+
+fn parameter<'v>(input: &str) -> IResult<&str, Identifier<'v>> {
+    map(recognize(preceded(tag("$"), identifier_name)), |name: &str| {
+        Identifier {
+            name: Cow::Owned(name.to_string()),
+        }
+    })(input)
+}
+
+// Whether `name` is written as a parameter, unlike the bindings introduced
+// internally, eg `$` and `$0` for the values of a query.
+pub(crate) fn is_parameter(name: &str) -> bool {
+    matches!(parameter(name), Ok(("", _)))
 }
 
 fn literal_null<'v>(input: &str) -> IResult<&str, Literal<'v>> {
@@ -153,7 +308,8 @@ fn literal_null<'v>(input: &str) -> IResult<&str, Literal<'v>> {
 fn string_template_part<'v>(input: &str) -> IResult<&str, StringTemplatePart<'v>> {
     map(
         tuple((
-            recognize(take_until("${")),
+            // The next `${` has to be inside of this template.
+            verify(take_until("${"), |s: &str| !s.contains('`')),
             delimited(tag("${"), expression, tag("}")),
         )),
         |(fixed_start, dynamic_end)| StringTemplatePart {
@@ -186,6 +342,55 @@ fn literal_string_raw<'v>(input: &str) -> IResult<&str, Cow<'v, str>> {
     )(input)
 }
 
+fn regex_source<'v>(input: &str) -> IResult<&str, Cow<'v, str>> {
+    map_res(
+        delimited(
+            char('/'),
+            recognize(many1(alt((
+                recognize(pair(char('\\'), anychar)),
+                is_not("/\\"),
+            )))),
+            char('/'),
+        ),
+        |s: &str| regex::Regex::new(s).map(|_| Cow::Owned(s.to_string())),
+    )(input)
+}
+
+fn literal_regex<'v>(input: &str) -> IResult<&str, Literal<'v>> {
+    map(regex_source, Literal::Regex)(input)
+}
+
+fn digits(count: usize) -> impl Fn(&str) -> IResult<&str, &str> {
+    move |input| take_while_m_n(count, count, |c: char| c.is_ascii_digit())(input)
+}
+
+fn datetime_source(input: &str) -> IResult<&str, &str> {
+    recognize(tuple((
+        digits(4),
+        char('-'),
+        digits(2),
+        char('-'),
+        digits(2),
+        opt(tuple((
+            char('T'),
+            digits(2),
+            char(':'),
+            digits(2),
+            opt(tuple((char(':'), digits(2), opt(pair(char('.'), digit1))))),
+            opt(alt((
+                recognize(char('Z')),
+                recognize(tuple((one_of("+-"), digits(2), char(':'), digits(2)))),
+            ))),
+        ))),
+    )))(input)
+}
+
+fn literal_datetime<'v>(input: &str) -> IResult<&str, Literal<'v>> {
+    map(preceded(tag("@"), datetime_source), |s: &str| {
+        Literal::DateTime(Cow::Owned(s.to_string()))
+    })(input)
+}
+
 fn literal_string<'v>(input: &str) -> IResult<&str, Literal<'v>> {
     map(literal_string_raw, Literal::String)(input)
 }
@@ -198,24 +403,34 @@ fn literal_bool<'v>(input: &str) -> IResult<&str, Literal<'v>> {
 }
 
 fn literal_number<'v>(input: &str) -> IResult<&str, Literal<'v>> {
-    map(recognize(i64), |s: &str| {
+    map(recognize(pair(opt(one_of("+-")), digit1)), |s: &str| {
         Literal::Number(Cow::Owned(s.to_owned()))
     })(input)
 }
 
-fn no_keyword(input: &str) -> bool {
-    !matches!(input, "where" | "into" | "limit")
+// The words starting a clause. `order` and `group` only do so when followed
+// by `by`, otherwise they are plain identifiers.
+fn clause_keyword(input: &str) -> IResult<&str, &str> {
+    alt((
+        keyword("where"),
+        keyword("into"),
+        keyword("limit"),
+        keyword("offset"),
+        recognize(tuple((alt((tag("order"), tag("group"))), space1, keyword("by")))),
+    ))(input)
 }
 
+// Identifiers follow the Unicode rules also used by Rust, eg `größe` or
+// `名前`. A single `_` is the discard pattern instead.
 fn identifier_name(input: &str) -> IResult<&str, &str> {
     recognize(alt((
-        pair(alpha1, many0_count(alt((alphanumeric1, tag("_"))))),
-        pair(tag("_"), many1_count(alt((alphanumeric1, tag("_"))))),
+        pair(satisfy(unicode_ident::is_xid_start), take_while(unicode_ident::is_xid_continue)),
+        pair(char('_'), take_while1(unicode_ident::is_xid_continue)),
     )))(input)
 }
 
 fn non_keyword_identifier<'v>(input: &str) -> IResult<&str, Identifier<'v>> {
-    map(verify(identifier_name, no_keyword), |name: &str| {
+    map(preceded(not(clause_keyword), identifier_name), |name: &str| {
         Identifier {
             name: Cow::Owned(name.to_string()),
         }
@@ -230,13 +445,63 @@ fn raw_identifier<'v>(input: &str) -> IResult<&str, Identifier<'v>> {
     })(input)
 }
 
-fn identifier<'v>(input: &str) -> IResult<&str, Identifier<'v>> {
+pub(crate) fn identifier<'v>(input: &str) -> IResult<&str, Identifier<'v>> {
     alt((raw_identifier, non_keyword_identifier))(input)
 }
 
+fn query_hint<'v>(input: &str) -> IResult<&str, QueryHint<'v>> {
+    map(
+        consumed(pair(
+            identifier_name,
+            opt(delimited(
+                ws(tag("(")),
+                separated_list0(ws(tag(",")), ws(identifier)),
+                ws(tag(")")),
+            )),
+        )),
+        |(hint, (name, arguments))| match (name, arguments) {
+            ("no_index", None) => QueryHint::NoIndex,
+            ("join_order", Some(names)) if !names.is_empty() => QueryHint::JoinOrder(names),
+            _ => QueryHint::Unknown(hint.trim_end().to_string()),
+        },
+    )(input)
+}
+
+fn hint_block<'v>(input: &str) -> IResult<&str, Vec<QueryHint<'v>>> {
+    delimited(
+        ws(tag("/*+")),
+        separated_list0(ws(tag(",")), ws(query_hint)),
+        ws(tag("*/")),
+    )(input)
+}
+
+// A bag name, or one of the system bags starting with `$`.
+fn bag_name<'v>(input: &str) -> IResult<&str, Identifier<'v>> {
+    alt((
+        identifier,
+        map(recognize(pair(char('$'), identifier_name)), |name: &str| Identifier {
+            name: Cow::Owned(name.to_string()),
+        }),
+    ))(input)
+}
+
+// Object keys and member names are never followed by a clause, so keywords
+// can be used there as they are, eg `{where: 1}.where`.
+fn property_name<'v>(input: &str) -> IResult<&str, Identifier<'v>> {
+    alt((
+        raw_identifier,
+        map(identifier_name, |name: &str| Identifier {
+            name: Cow::Owned(name.to_string()),
+        }),
+    ))(input)
+}
+
 fn expression_logic_additive<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     let (input, init) = expression_logic_multiplicative(input)?;
+    logic_additive_from(input, init)
+}
 
+fn logic_additive_from<'a, 'v>(input: &'a str, init: Expression<'v>) -> IResult<&'a str, Expression<'v>> {
     fold_many0(
         pair(
             ws(alt((value(LogicalOperator::Or, tag("||")),))),
@@ -255,7 +520,10 @@ fn expression_logic_additive<'v>(input: &str) -> IResult<&str, Expression<'v>> {
 
 fn expression_logic_multiplicative<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     let (input, init) = expression_type_predicate(input)?;
+    logic_multiplicative_from(input, init)
+}
 
+fn logic_multiplicative_from<'a, 'v>(input: &'a str, init: Expression<'v>) -> IResult<&'a str, Expression<'v>> {
     fold_many0(
         pair(
             ws(alt((value(LogicalOperator::And, tag("&&")),))),
@@ -273,15 +541,15 @@ fn expression_logic_multiplicative<'v>(input: &str) -> IResult<&str, Expression<
 }
 
 fn literal_type_raw(input: &str) -> IResult<&str, ValueType> {
-    alt((
-        value(ValueType::Type, tag("Type")),
-        value(ValueType::Null, tag("Null")),
-        value(ValueType::Boolean, tag("Boolean")),
-        value(ValueType::Integer, tag("Integer")),
-        value(ValueType::Array, tag("Array")),
-        value(ValueType::Object, tag("Object")),
-        value(ValueType::String, tag("String")),
-    ))(input)
+    let mut failure = ParseFailure::from_error_kind(input, ErrorKind::Tag);
+    for value_type in ValueType::ALL {
+        match tag(value_type.name())(input) {
+            Ok((rest, _)) => return Ok((rest, value_type)),
+            Err(nom::Err::Error(e)) => failure = failure.or(e),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(nom::Err::Error(failure))
 }
 
 fn literal_type<'v>(input: &str) -> IResult<&str, Literal<'v>> {
@@ -290,9 +558,13 @@ fn literal_type<'v>(input: &str) -> IResult<&str, Literal<'v>> {
 
 fn expression_type_predicate<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     let (input, init) = expression_type_additive(input)?;
+    type_predicate_from(input, init)
+}
 
+fn type_predicate_from<'a, 'v>(input: &'a str, init: Expression<'v>) -> IResult<&'a str, Expression<'v>> {
     let Ok((input, (op, t))) = tuple((ws(alt((
         value(BinaryOperator::Is, tag("is")),
+        value(BinaryOperator::Matches, tag("matches")),
     ))), expression_numeric_predicative))(input) else {
         return Ok((input, init));
     };
@@ -309,10 +581,13 @@ fn expression_type_predicate<'v>(input: &str) -> IResult<&str, Expression<'v>> {
 
 fn expression_type_additive<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     let (input, init) = expression_numeric_predicative(input)?;
+    type_additive_from(input, init)
+}
 
+fn type_additive_from<'a, 'v>(input: &'a str, init: Expression<'v>) -> IResult<&'a str, Expression<'v>> {
     fold_many0(
         pair(
-            ws(alt((value(BinaryOperator::Cast, tag("as")),))),
+            ws(alt((value(BinaryOperator::Cast, keyword("as")),))),
             expression_numeric_predicative,
         ),
         move || init.clone(),
@@ -326,9 +601,45 @@ fn expression_type_additive<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     )(input)
 }
 
+// `collate nocase`, `collate binary` or `collate "<locale>"`
+fn collation(input: &str) -> IResult<&str, Collation> {
+    preceded(
+        ws(keyword("collate")),
+        alt((
+            value(Collation::NoCase, keyword("nocase")),
+            value(Collation::Binary, keyword("binary")),
+            map(literal_string_raw, |l| Collation::Locale(l.into_owned())),
+        )),
+    )(input)
+}
+
+fn error_policy(input: &str) -> IResult<&str, ErrorPolicy> {
+    alt((
+        value(ErrorPolicy::Abort, keyword("abort")),
+        value(ErrorPolicy::Continue, keyword("continue")),
+        value(ErrorPolicy::RollbackStatement, keyword("rollback_statement")),
+    ))(input)
+}
+
+pub(crate) fn on_error(input: &str) -> IResult<&str, ErrorPolicy> {
+    preceded(ws(tag(".on error ")), all_consuming(ws(error_policy)))(input)
+}
+
+fn log_level(input: &str) -> IResult<&str, LogLevel> {
+    alt((
+        value(LogLevel::Debug, keyword("debug")),
+        value(LogLevel::Info, keyword("info")),
+        value(LogLevel::Warn, keyword("warn")),
+        value(LogLevel::Error, keyword("error")),
+    ))(input)
+}
+
 fn expression_numeric_predicative<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     let (input, init) = expression_numeric_additive(input)?;
+    numeric_predicative_from(input, init)
+}
 
+fn numeric_predicative_from<'a, 'v>(input: &'a str, init: Expression<'v>) -> IResult<&'a str, Expression<'v>> {
     fold_many0(
         pair(
             ws(alt((
@@ -340,15 +651,19 @@ fn expression_numeric_predicative<'v>(input: &str) -> IResult<&str, Expression<'
                 value(BinaryOperator::StrictNotEqual, tag("!=")),
                 value(BinaryOperator::In, tag("in")),
             ))),
-            expression_numeric_additive,
+            pair(expression_numeric_additive, opt(collation)),
         ),
         move || init.clone(),
-        |left, (operator, right)| {
-            Expression::Binary(BinaryExpression {
+        |left, (operator, (right, collation))| {
+            let comparison = Expression::Binary(BinaryExpression {
                 operator,
                 left: Box::new(left),
                 right: Box::new(right),
-            })
+            });
+            match collation {
+                Some(collation) => Expression::Collate(Box::new(comparison), collation),
+                None => comparison,
+            }
         },
     )(input)
 }
@@ -436,7 +751,7 @@ fn expression_member<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     let (input, init) = expression_primary(input)?;
 
     fold_many0(
-        alt((preceded(ws(char('.')), identifier),)),
+        alt((preceded(ws(char('.')), property_name),)),
         move || init.clone(),
         |acc, ident| {
             Expression::Member(MemberExpression {
@@ -449,6 +764,10 @@ fn expression_member<'v>(input: &str) -> IResult<&str, Expression<'v>> {
 
 fn expression_primary<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     alt((
+        expression_lambda,
+        expression_let,
+        expression_quoted_pattern,
+        expression_quoted,
         expression_with_paren,
         expression_literal,
         expression_identifier,
@@ -456,6 +775,43 @@ fn expression_primary<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     ))(input)
 }
 
+fn expression_lambda<'v>(input: &str) -> IResult<&str, Expression<'v>> {
+    map(
+        preceded(
+            tag("fn"),
+            pair(
+                delimited(ws(tag("(")), pattern, ws(tag(")"))),
+                preceded(ws(tag("=>")), expression),
+            ),
+        ),
+        |(parameter, body)| {
+            Expression::Lambda(LambdaExpression {
+                parameter,
+                body: Box::new(body),
+            })
+        },
+    )(input)
+}
+
+// The bound value can not contain `in`, so comparisons and logical operators
+// have to be put in parentheses, eg `let ok = (a > b) in ...`.
+fn expression_let<'v>(input: &str) -> IResult<&str, Expression<'v>> {
+    map(
+        tuple((
+            preceded(keyword("let"), ws(pattern)),
+            preceded(tag("="), ws(expression_numeric_additive)),
+            preceded(ws(keyword("in")), expression),
+        )),
+        |(pattern, value, body)| {
+            Expression::Let(LetExpression {
+                pattern,
+                value: Box::new(value),
+                body: Box::new(body),
+            })
+        },
+    )(input)
+}
+
 fn expression_with_paren<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     delimited(tag("("), expression, tag(")"))(input)
 }
@@ -497,10 +853,19 @@ fn expression_unary_numeric<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     )(input)
 }
 
-fn expression<'v>(input: &str) -> IResult<&str, Expression<'v>> {
+pub(crate) fn expression<'v>(input: &str) -> IResult<&str, Expression<'v>> {
     alt((expression_logic_additive,))(input)
 }
 
+// Continues an expression whose leftmost sum is already parsed.
+fn expression_from<'a, 'v>(input: &'a str, init: Expression<'v>) -> IResult<&'a str, Expression<'v>> {
+    let (input, init) = numeric_predicative_from(input, init)?;
+    let (input, init) = type_additive_from(input, init)?;
+    let (input, init) = type_predicate_from(input, init)?;
+    let (input, init) = logic_multiplicative_from(input, init)?;
+    logic_additive_from(input, init)
+}
+
 fn expression_bag<'v>(input: &str) -> IResult<&str, std::vec::Vec<Expression<'v>>> {
     terminated(separated_list1(ws(tag(";")), expression), opt(ws(tag(";"))))(input)
 }
@@ -551,7 +916,7 @@ fn object_prop_pattern<'v>(input: &str) -> IResult<&str, ObjectPropertyPattern<'
             separated_pair(
                 delimited(ws(tag("[")), expression, ws(tag("]"))),
                 ws(tag(":")),
-                pattern,
+                pattern_guarded,
             ),
             |(prop, value)| {
                 ObjectPropertyPattern::Match(PropertyPattern {
@@ -561,7 +926,7 @@ fn object_prop_pattern<'v>(input: &str) -> IResult<&str, ObjectPropertyPattern<'
             },
         ),
         map(
-            separated_pair(identifier, ws(tag(":")), pattern),
+            separated_pair(property_name, ws(tag(":")), pattern_guarded),
             |(prop, value)| {
                 ObjectPropertyPattern::Match(PropertyPattern {
                     key: PropertyKey::Identifier(prop),
@@ -569,6 +934,21 @@ fn object_prop_pattern<'v>(input: &str) -> IResult<&str, ObjectPropertyPattern<'
                 })
             },
         ),
+        map(
+            separated_pair(literal_string_raw, ws(tag(":")), pattern_guarded),
+            |(prop, value)| {
+                ObjectPropertyPattern::Match(PropertyPattern {
+                    key: PropertyKey::Identifier(Identifier { name: prop }),
+                    value,
+                })
+            },
+        ),
+        map(pair(identifier, guard), |(prop, guard)| {
+            ObjectPropertyPattern::Match(PropertyPattern {
+                key: PropertyKey::Identifier(prop.clone()),
+                value: Pattern::Guarded(Box::new(Pattern::Identifier(prop)), Box::new(guard)),
+            })
+        }),
         map(identifier, ObjectPropertyPattern::Single),
     ))(input)
 }
@@ -606,7 +986,7 @@ fn pattern_array<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
             map(pattern_rest, |r| Pattern::Array(vec![], r)),
             map(
                 tuple((
-                    separated_list0(ws(tag(",")), map(pattern, ArrayPatternItem::Pattern)),
+                    separated_list0(ws(tag(",")), map(pattern_guarded, ArrayPatternItem::Pattern)),
                     opt(preceded(ws(tag(",")), opt(pattern_rest))),
                 )),
                 |(items, rest)| Pattern::Array(items, rest.flatten().unwrap_or(Rest::Exact)),
@@ -621,12 +1001,28 @@ fn pattern_capture<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
         separated_pair(
             ws(identifier),
             ws(tag("@")),
-            alt((pattern_atom, pattern_array, pattern_object)),
+            alt((
+                pattern_atom,
+                pattern_array,
+                pattern_object,
+                pattern_group,
+                pattern_descendant,
+            )),
         ),
         |(id, pat)| Pattern::Capture(id, Box::new(pat)),
     )(input)
 }
 
+fn pattern_group<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
+    delimited(ws(tag("(")), pattern, ws(tag(")")))(input)
+}
+
+fn pattern_descendant<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
+    map(preceded(ws(tag("**")), pattern_single), |p| {
+        Pattern::Descendant(Box::new(p))
+    })(input)
+}
+
 fn pattern_atom<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
     map(
         alt((
@@ -635,14 +1031,16 @@ fn pattern_atom<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
             literal_bool,
             literal_number,
             literal_type,
+            literal_datetime,
         )),
         Pattern::Literal,
     )(input)
 }
 
-pub fn pattern<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
+fn pattern_single<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
     alt((
         pattern_atom,
+        map(regex_source, Pattern::Regex),
         pattern_capture,
         pattern_array,
         pattern_typed_identifier,
@@ -650,76 +1048,266 @@ pub fn pattern<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
         pattern_identifier,
         pattern_discard,
         pattern_object,
+        pattern_group,
+        pattern_descendant,
     ))(input)
 }
 
-pub(crate) fn assignment_multi<'v>(input: &str) -> IResult<&str, AssignmentSet<'v,'v>> {
+pub fn pattern<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
     map(
-        delimited(
-            ws(tag("let ")),
-            separated_list1(
-                ws(tag(";")),
-                map(
-                    separated_pair(pattern, ws(tag("=")), expression),
-                    |(pattern, expression)| Assignment {
-                        pattern,
-                        expression,
-                    },
-                ),
-            ),
-            alt((ws(tag(";")), space0)),
-        ),
-        |assignments| AssignmentSet { assignments },
+        separated_list1(ws(terminated(tag("|"), not(char('|')))), pattern_single),
+        |mut alternatives| {
+            if alternatives.len() == 1 {
+                alternatives.remove(0)
+            } else {
+                Pattern::Or(alternatives)
+            }
+        },
     )(input)
 }
 
-pub fn try_match_multi<'v, 'w>(input: &str) -> IResult<&str, Statement<'v, 'w>> {
+fn guard<'v>(input: &str) -> IResult<&str, Expression<'v>> {
+    preceded(ws(keyword("if")), expression)(input)
+}
+
+// Items of arrays and values of objects can be guarded by a condition on
+// what is bound so far, eg `[x, y if y > x]`.
+fn pattern_guarded<'v>(input: &str) -> IResult<&str, Pattern<'v>> {
+    map(pair(pattern, opt(guard)), |(pattern, guard)| match guard {
+        Some(guard) => Pattern::Guarded(Box::new(pattern), Box::new(guard)),
+        None => pattern,
+    })(input)
+}
+
+fn assignments<'v>(input: &str) -> IResult<&str, AssignmentSet<'v, 'v>> {
     map(
-        terminated(
-            separated_list1(
-                ws(tag(";")),
-                map(
-                    separated_pair(pattern, ws(tag("=")), expression),
-                    |(pattern, expression)| Assignment {
-                        pattern,
-                        expression,
-                    },
-                ),
+        separated_list1(
+            ws(tag(";")),
+            map(
+                separated_pair(pattern, ws(tag("=")), expression),
+                |(pattern, expression)| Assignment {
+                    pattern,
+                    expression,
+                },
             ),
-            alt((ws(tag(";")), space0)),
         ),
-        |assignments| Statement::MatchSet(AssignmentSet { assignments }),
+        |assignments| AssignmentSet { assignments },
     )(input)
 }
 
+pub(crate) fn assignment_list<'v>(input: &str) -> IResult<&str, AssignmentSet<'v, 'v>> {
+    terminated(assignments, alt((ws(tag(";")), space0)))(input)
+}
+
+// A line starting with `let` assigns variables, unless it is a scoped
+// expression like `let x = 2 in x * x` rather than an assignment of
+// `2 in x * x`.
+fn let_statement<'v, 'w>(input: &str) -> IResult<&str, Statement<'v, 'w>> {
+    let (input, pattern) = terminated(preceded(ws(tag("let ")), pattern), ws(tag("=")))(input)?;
+    let (input, value) = ws(expression_numeric_additive)(input)?;
+
+    if let Ok(("", expressions)) = scoped_expressions(input, &pattern, &value) {
+        return Ok(("", Statement::Eval(ExpressionSet { expressions })));
+    }
+
+    let (input, expression) = expression_from(input, value)?;
+    let (input, rest) = opt(preceded(ws(tag(";")), assignment_list))(input)?;
+    let assignments = std::iter::once(Assignment { pattern, expression })
+        .chain(rest.into_iter().flat_map(|set| set.assignments))
+        .collect();
+
+    Ok((input, Statement::AssignSet(AssignmentSet { assignments })))
+}
+
+fn scoped_expressions<'a, 'v>(
+    input: &'a str,
+    pattern: &Pattern<'v>,
+    value: &Expression<'v>,
+) -> IResult<&'a str, Vec<Expression<'v>>> {
+    let (input, body) = preceded(ws(keyword("in")), expression)(input)?;
+    let scoped = Expression::Let(LetExpression {
+        pattern: pattern.clone(),
+        value: Box::new(value.clone()),
+        body: Box::new(body),
+    });
+    let (input, first) = expression_from(input, scoped)?;
+    let (input, rest) = terminated(
+        many0(preceded(ws(tag(";")), expression)),
+        alt((ws(tag(";")), space0)),
+    )(input)?;
+
+    Ok((input, std::iter::once(first).chain(rest).collect()))
+}
+
+pub fn try_match_multi<'v, 'w>(input: &str) -> IResult<&str, Statement<'v, 'w>> {
+    map(assignment_list, Statement::MatchSet)(input)
+}
+
 fn filename(input: &str) -> IResult<&str, &str> {
     recognize(many1(alt((alpha1, tag("_")))))(input)
 }
 
-fn bag_creation<'a,'b>(input:&str) -> IResult<&str, (Identifier<'a>, Option<Predicate<'b>>)> {
+fn verified_filename(input: &str) -> IResult<&str, (&str, bool)> {
+    pair(
+        filename,
+        map(opt(preceded(space1, tag("verify"))), |v| v.is_some()),
+    )(input)
+}
+
+fn bag_creation<'a,'b>(input:&str) -> IResult<&str, (Identifier<'a>, Option<Predicate<'b>>, StorageMode)> {
     map(
         preceded(
             ws(tag(".bag ")),
             tuple((
                 identifier,
                 preceded(ws(tag("as")), pattern),
-                opt(preceded(ws(tag("where")), expression)),
-                opt(preceded(ws(tag("limit")), nom::character::complete::u32)),
+                opt(where_clause),
+                opt(limit_clause),
+                opt(ws(alt((
+                    value(StorageMode::Distinct, keyword("distinct")),
+                    value(StorageMode::Counted, keyword("counted")),
+                )))),
             )),
         ),
-        |(name, pattern, guard, limit)| {
+        |(name, pattern, guard, limit, mode)| {
             (
                 name,
                 Some(Predicate {
                     pattern,
                     guard: guard.unwrap_or(Expression::Literal(Literal::Boolean(true))),
-                    limit: limit.map(|l| l as usize),
+                    offset: None,
+                    limit,
+                    plans: Plans::default(),
                 }),
+                mode.unwrap_or_default(),
             )
         },
     )(input)
 }
 
+fn virtual_bag_creation<'a>(input: &str) -> IResult<&str, (Identifier<'a>, IntegerRange)> {
+    all_consuming(preceded(
+        ws(tag(".bag ")),
+        separated_pair(
+            identifier,
+            ws(tag("as virtual")),
+            map(
+                separated_pair(i64, tag(".."), opt(i64)),
+                |(start, end)| IntegerRange { start, end },
+            ),
+        ),
+    ))(input)
+}
+
+fn view_creation<'a>(input: &str) -> IResult<&str, (Identifier<'a>, Vec<Identifier<'a>>)> {
+    all_consuming(preceded(
+        ws(tag(".view ")),
+        separated_pair(
+            ws(identifier),
+            tuple((tag(":="), space0, tag("union"), space1, tag("of"), space1)),
+            separated_list1(ws(tag(",")), ws(identifier)),
+        ),
+    ))(input)
+}
+
+fn error_code(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        one_of("Ee"),
+        take_while_m_n(4, 4, |c: char| c.is_ascii_digit()),
+    ))(input)
+}
+
+// The clauses of the statements selecting values of a bag, in the order
+// they are written in.
+pub(crate) fn into_clause<'v>(input: &str) -> IResult<&str, Expression<'v>> {
+    preceded(ws(tag("into")), expression)(input)
+}
+
+pub(crate) fn where_clause<'v>(input: &str) -> IResult<&str, Expression<'v>> {
+    preceded(ws(tag("where")), expression)(input)
+}
+
+pub(crate) fn group_clause<'v>(input: &str) -> IResult<&str, Expression<'v>> {
+    preceded(ws(tuple((tag("group"), space1, tag("by")))), expression)(input)
+}
+
+pub(crate) fn order_clause<'v>(input: &str) -> IResult<&str, OrderBy<'v>> {
+    map(
+        preceded(
+            ws(tuple((tag("order"), space1, tag("by")))),
+            tuple((
+                expression,
+                opt(collation),
+                opt(ws(alt((value(false, keyword("asc")), value(true, keyword("desc")))))),
+            )),
+        ),
+        |(key, collation, descending)| OrderBy {
+            key,
+            descending: descending.unwrap_or_default(),
+            collation: collation.unwrap_or_default(),
+        },
+    )(input)
+}
+
+pub(crate) fn limit_clause(input: &str) -> IResult<&str, usize> {
+    map(preceded(ws(tag("limit")), nom::character::complete::u32), |l| l as usize)(input)
+}
+
+pub(crate) fn offset_clause(input: &str) -> IResult<&str, usize> {
+    map(preceded(ws(tag("offset")), nom::character::complete::u32), |o| o as usize)(input)
+}
+
+fn change_query<'a>(input: &str) -> IResult<&str, UpdateQuery<'a>> {
+    map(
+        preceded(
+            ws(tag(".change ")),
+            tuple((
+                ws(pattern),
+                into_clause,
+                opt(where_clause),
+                opt(limit_clause),
+                opt(offset_clause),
+            )),
+        ),
+        |(pattern, projection, guard, limit, offset)| UpdateQuery {
+            predicate: Predicate {
+                pattern,
+                guard: guard.unwrap_or(Expression::Literal(Literal::Boolean(true))),
+                offset,
+                limit,
+                plans: Plans::default(),
+            },
+            projection,
+        },
+    )(input)
+}
+
+// Tried before `.restore <file>`, which would take the pattern for a file
+// name.
+fn restore_trash<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
+    map(
+        all_consuming(preceded(
+            ws(tag(".restore ")),
+            tuple((
+                alt((
+                    value(None, ws(keyword("from"))),
+                    map(terminated(ws(pattern), ws(keyword("from"))), Some),
+                )),
+                ws(keyword("trash")),
+                opt(where_clause),
+            )),
+        )),
+        |(pattern, _, guard)| {
+            Statement::RestoreTrash(Predicate {
+                pattern: pattern.unwrap_or(Pattern::Discard),
+                guard: guard.unwrap_or(Expression::Literal(Literal::Boolean(true))),
+                offset: None,
+                limit: None,
+                plans: Plans::default(),
+            })
+        },
+    )(input)
+}
 
 pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
     all_consuming(alt((
@@ -729,28 +1317,92 @@ pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
                 Statement::Exit,
                 ws(alt((tag(".exit"), tag(".quit")))),
             )),
-            all_consuming(value(Statement::Help, ws(alt((tag(".help"), tag(".h")))))),
-            map(preceded(ws(tag(".load ")), filename), |f| {
-                Statement::Import(Cow::Owned(f.into()))
-            }),
-            map(preceded(ws(tag(".dump ")), filename), |f| {
-                Statement::Export(Cow::Owned(f.into()))
-            }),
-            map(preceded(ws(tag(".load_bundle ")), filename), |f| {
-                Statement::LoadBundle(Cow::Owned(f.into()))
-            }),
+            map(
+                all_consuming(ws(preceded(
+                    alt((tag(".help"), tag(".h"))),
+                    opt(preceded(
+                        space1,
+                        take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '.'),
+                    )),
+                ))),
+                |command: Option<&str>| Statement::Help(command.map(|c| Cow::Owned(c.into()))),
+            ),
+            alt((
+                map(preceded(ws(tag(".load ")), verified_filename), |(f, verified)| {
+                    Statement::Import(Cow::Owned(f.into()), DataFormat::Lines, verified)
+                }),
+                map(preceded(ws(tag(".dump ")), verified_filename), |(f, verified)| {
+                    Statement::Export(Cow::Owned(f.into()), DataFormat::Lines, verified)
+                }),
+                map(preceded(ws(tag(".load_json ")), verified_filename), |(f, verified)| {
+                    Statement::Import(Cow::Owned(f.into()), DataFormat::Json, verified)
+                }),
+                map(preceded(ws(tag(".dump_json ")), verified_filename), |(f, verified)| {
+                    Statement::Export(Cow::Owned(f.into()), DataFormat::Json, verified)
+                }),
+                map(preceded(ws(tag(".load_ndjson ")), verified_filename), |(f, verified)| {
+                    Statement::Import(Cow::Owned(f.into()), DataFormat::Ndjson, verified)
+                }),
+                map(preceded(ws(tag(".dump_ndjson ")), verified_filename), |(f, verified)| {
+                    Statement::Export(Cow::Owned(f.into()), DataFormat::Ndjson, verified)
+                }),
+                map(preceded(ws(tag(".dump_parquet ")), verified_filename), |(f, verified)| {
+                    Statement::Export(Cow::Owned(f.into()), DataFormat::Parquet, verified)
+                }),
+                map(preceded(ws(tag(".load_yaml ")), verified_filename), |(f, verified)| {
+                    Statement::Import(Cow::Owned(f.into()), DataFormat::Yaml, verified)
+                }),
+                map(preceded(ws(tag(".load_toml ")), verified_filename), |(f, verified)| {
+                    Statement::Import(Cow::Owned(f.into()), DataFormat::Toml, verified)
+                }),
+                map(preceded(ws(tag(".load_xml ")), verified_filename), |(f, verified)| {
+                    Statement::Import(Cow::Owned(f.into()), DataFormat::Xml, verified)
+                }),
+                map(preceded(ws(tag(".load_bundle ")), filename), |f| {
+                    Statement::LoadBundle(Cow::Owned(f.into()))
+                }),
+                map(preceded(ws(tag(".save ")), filename), |f| {
+                    Statement::Save(Cow::Owned(f.into()))
+                }),
+                restore_trash,
+                map(preceded(ws(tag(".restore ")), filename), |f| {
+                    Statement::Restore(Cow::Owned(f.into()))
+                }),
+                map(preceded(ws(tag(".run ")), all_consuming(filename)), |f| {
+                    Statement::Run(Cow::Owned(f.into()))
+                }),
+                map(on_error, Statement::OnError),
+            )),
             map(
                 preceded(ws(tag(".inspect ")), full_expression),
                 Statement::Inspect,
             ),
             map(
-                preceded(ws(tag(".format ")), full_expression),
-                Statement::Format,
+                preceded(ws(tag(".format ")), statement),
+                |s| Statement::Format(Box::new(s)),
             ),
             map(
                 preceded(ws(tag(".pattern ")), full_pattern),
                 Statement::Pattern,
             ),
+            map(
+                preceded(ws(tag(".explain ")), all_consuming(ws(error_code))),
+                |code: &str| Statement::ExplainError(Cow::Owned(code.to_string())),
+            ),
+            map(preceded(ws(tag(".explain ")), statement), |s| {
+                Statement::Explain(Box::new(s))
+            }),
+            map(preceded(ws(tag(".ast ")), statement), |s| {
+                Statement::Ast(Box::new(s))
+            }),
+            value(Statement::ListSettings, all_consuming(ws(tag(".set")))),
+            map(
+                preceded(
+                    ws(tag(".set ")),
+                    separated_pair(identifier, space1, full_expression),
+                ),
+                |(name, value)| Statement::Set(name, value),
+            ),
         )),
         map(
             preceded(ws(tag(".insert ")), expression_bag),
@@ -766,50 +1418,33 @@ pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
                 ws(tag(".delete ")),
                 tuple((
                     ws(pattern),
-                    opt(preceded(ws(tag("where")), expression)),
-                    opt(preceded(ws(tag("limit")), nom::character::complete::u32)),
+                    opt(where_clause),
+                    opt(limit_clause),
+                    opt(offset_clause),
                 )),
             ),
-            |(pattern, guard, limit)| {
+            |(pattern, guard, limit, offset)| {
                 Statement::Deletion(DeletionQuery {
                     predicate: Predicate {
                         pattern,
                         guard: guard.unwrap_or(Expression::Literal(Literal::Boolean(true))),
-                        limit: limit.map(|l| l as usize),
+                        offset,
+                        limit,
+                        plans: Plans::default(),
                     },
                 })
             },
         ),
-        map(
-            preceded(
-                ws(tag(".change ")),
-                tuple((
-                    ws(pattern),
-                    preceded(ws(tag("into")), expression),
-                    opt(preceded(ws(tag("where")), expression)),
-                    opt(preceded(ws(tag("limit")), nom::character::complete::u32)),
-                )),
-            ),
-            |(pattern, projection, guard, limit)| {
-                Statement::Update(UpdateQuery {
-                    predicate: Predicate {
-                        pattern,
-                        guard: guard.unwrap_or(Expression::Literal(Literal::Boolean(true))),
-                        limit: limit.map(|l| l as usize),
-                    },
-                    projection,
-                })
-            },
-        ),
+        map(change_query, Statement::Update),
         map(
             preceded(
                 ws(tag(".move")),
                 tuple((
                     delimited(ws(tag("(")), identifier, ws(tag(")"))),
                     ws(pattern),
-                    opt(preceded(ws(tag("into")), expression)),
-                    opt(preceded(ws(tag("where")), expression)),
-                    opt(preceded(ws(tag("limit")), nom::character::complete::u32)),
+                    opt(into_clause),
+                    opt(where_clause),
+                    opt(limit_clause),
                 )),
             ),
             |(to_bag, pattern, projection, guard, limit)| {
@@ -824,7 +1459,9 @@ pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
                                 Box::new(pattern),
                             ),
                             guard: guard.unwrap_or(Expression::Literal(Literal::Boolean(true))),
-                            limit: limit.map(|l| l as usize),
+                            offset: None,
+                            limit,
+                            plans: Plans::default(),
                         },
                         projection: projection.unwrap_or(Expression::Identifier(Identifier {
                             name: Cow::Borrowed("$"),
@@ -838,7 +1475,7 @@ pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
                 ws(tag(".move")),
                 tuple((
                     delimited(ws(tag("(")), identifier, ws(tag(")"))),
-                    opt(preceded(ws(tag("limit")), nom::character::complete::u32)),
+                    opt(limit_clause),
                 )),
             ),
             |(to_bag, limit)| {
@@ -853,7 +1490,9 @@ pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
                                 name: Cow::Borrowed("$"),
                             }),
                             guard: Expression::Literal(Literal::Boolean(true)),
-                            limit: limit.map(|l| l as usize),
+                            offset: None,
+                            limit,
+                            plans: Plans::default(),
                         },
                     },
                 )
@@ -866,16 +1505,31 @@ pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
                     value(false, tag(".query ")),
                 ))),
                 tuple((
+                    opt(hint_block),
                     separated_list1(ws(tag(";")), ws(pattern)),
-                    opt(preceded(ws(tag("into")), expression)),
-                    opt(preceded(ws(tag("where")), expression)),
-                    opt(preceded(ws(tag("limit")), nom::character::complete::u32)),
+                    opt(into_clause),
+                    opt(where_clause),
+                    opt(group_clause),
+                    opt(order_clause),
+                    opt(limit_clause),
+                    opt(offset_clause),
                 )),
             )),
-            |(outer, (patterns, proj, guard, limit))| {
+            |(outer, (hints, patterns, proj, guard, group, order, limit, offset))| {
+                let mut aggregates = vec![];
+                let projection = proj.map(|p| extract_aggregates(&p, &mut aggregates));
+                let order = order.map(|order| OrderBy {
+                    key: extract_aggregates(&order.key, &mut aggregates),
+                    ..order
+                });
+
                 Statement::Query(ProjectionQuery {
                     outer,
-                    projection: proj.unwrap_or_else(|| {
+                    group,
+                    aggregates,
+                    order,
+                    hints: hints.unwrap_or_default(),
+                    projection: projection.unwrap_or_else(|| {
                         if patterns.len() == 1 {
                             Expression::Identifier(Identifier {
                                 name: Cow::Borrowed("$0"),
@@ -906,7 +1560,9 @@ pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
                             })
                             .collect(),
                         guard: guard.unwrap_or(Expression::Literal(Literal::Boolean(true))),
-                        limit: limit.map(|l| l as usize),
+                        offset,
+                        limit,
+                        plans: Plans::default(),
                     },
                 })
             },
@@ -914,11 +1570,18 @@ pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
         map(
             preceded(
                 ws(tuple((tag(".query"), opt(tag(" "))))),
-                opt(preceded(ws(tag("limit")), nom::character::complete::u32)),
+                pair(
+                    opt(limit_clause),
+                    opt(offset_clause),
+                ),
             ),
-            |limit| {
+            |(limit, offset)| {
                 Statement::Query(ProjectionQuery {
                     outer: false,
+                    group: None,
+                    aggregates: vec![],
+                    order: None,
+                    hints: vec![],
                     projection: Expression::Identifier(Identifier {
                         name: Cow::Borrowed("$"),
                     }),
@@ -927,7 +1590,9 @@ pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
                             name: Cow::Borrowed("$"),
                         })],
                         guard: Expression::Literal(Literal::Boolean(true)),
-                        limit: limit.map(|l| l as usize),
+                        offset,
+                        limit,
+                        plans: Plans::default(),
                     },
                 })
             },
@@ -939,43 +1604,294 @@ pub fn statement<'a, 'b>(input: &str) -> IResult<&str, Statement<'a, 'b>> {
         value(Statement::TellBag, all_consuming(ws(tag(".bag")))),
         value(Statement::ListBags, all_consuming(ws(tag(".bags")))),
         map(
-            preceded(ws(tag(".bag ")), all_consuming(ws(identifier))),
-            |p| Statement::UseBag(p, None),
+            preceded(ws(tag(".bag ")), all_consuming(ws(bag_name))),
+            |p| Statement::UseBag(p, None, StorageMode::Multiset),
         ),
         map(
             preceded(ws(tag(".drop ")), all_consuming(ws(identifier))),
             Statement::DropBag,
         ),
-        map(bag_creation, |(name, pred)| Statement::UseBag(name, pred)),
+        alt((
+            map(
+                preceded(
+                    ws(tag(".rename ")),
+                    all_consuming(pair(ws(identifier), ws(identifier))),
+                ),
+                |(from, to)| Statement::RenameBag(from, to),
+            ),
+            map(
+                preceded(
+                    ws(tag(".copy ")),
+                    all_consuming(tuple((
+                        ws(identifier),
+                        ws(identifier),
+                        opt(pair(ws(pattern), opt(where_clause))),
+                        opt(limit_clause),
+                    ))),
+                ),
+                |(source, target, filter, limit)| {
+                    let (pattern, guard) = filter.unwrap_or((Pattern::Discard, None));
+                    Statement::CopyBag(
+                        source,
+                        target,
+                        Predicate {
+                            pattern,
+                            guard: guard.unwrap_or(Expression::Literal(Literal::Boolean(true))),
+                            offset: None,
+                            limit,
+                            plans: Plans::default(),
+                        },
+                    )
+                },
+            ),
+            map(virtual_bag_creation, |(name, range)| Statement::CreateVirtualBag(name, range)),
+            map(view_creation, |(name, bags)| Statement::CreateView(name, bags)),
+            map(bag_creation, |(name, pred, mode)| Statement::UseBag(name, pred, mode)),
+        )),
         map(preceded(ws(tag(".connection ")), connection), |con| Statement::Connect(con.signature.name.clone(), con)),
         map(preceded(ws(tag(".disconnect ")), identifier), Statement::Disconnect),
         alt((
-            map(all_consuming(assignment_multi), Statement::AssignSet),
+            map(
+                all_consuming(preceded(ws(tag(".let ")), assignment_list)),
+                Statement::AssignSet,
+            ),
+            map(
+                all_consuming(preceded(ws(tag(".match ")), assignment_list)),
+                Statement::MatchSet,
+            ),
+            all_consuming(let_statement),
             all_consuming(try_match_multi),
+            value(Statement::ListVariables, all_consuming(ws(tag(".vars")))),
+            map(
+                all_consuming(preceded(
+                    ws(tag(".unset ")),
+                    alt((value(None, ws(tag("*"))), map(ws(identifier), Some))),
+                )),
+                Statement::Unset,
+            ),
+            map(
+                all_consuming(preceded(ws(tag(".env save ")), ws(identifier))),
+                Statement::SaveEnv,
+            ),
+            map(
+                all_consuming(preceded(ws(tag(".env load ")), ws(identifier))),
+                Statement::LoadEnv,
+            ),
+        )),
+        alt((
+            map(
+                preceded(
+                    ws(tag(".macro ")),
+                    tuple((
+                        ws(identifier),
+                        map(
+                            opt(delimited(
+                                ws(tag("(")),
+                                separated_list0(ws(tag(",")), ws(identifier)),
+                                ws(tag(")")),
+                            )),
+                            Option::unwrap_or_default,
+                        ),
+                        preceded(ws(tag(":=")), statement),
+                    )),
+                ),
+                |(name, parameters, body)| {
+                    Statement::DefineMacro(name, parameters, Box::new(body))
+                },
+            ),
+            map(
+                all_consuming(ws(preceded(
+                    tag("!"),
+                    pair(
+                        verify(identifier, |id: &Identifier| {
+                            all_consuming(expression_literal)(&id.name).is_err()
+                        }),
+                        opt(delimited(
+                            ws(tag("(")),
+                            separated_list0(ws(tag(",")), expression),
+                            ws(tag(")")),
+                        )),
+                    ),
+                ))),
+                |(name, arguments)| Statement::ExpandMacro(name, arguments),
+            ),
+            map(
+                preceded(
+                    ws(tag(".prepare ")),
+                    separated_pair(ws(identifier), ws(tag(":=")), statement),
+                ),
+                |(name, body)| Statement::Prepare(name, Box::new(body)),
+            ),
+            map(
+                all_consuming(preceded(
+                    ws(tag(".execute ")),
+                    pair(ws(identifier), opt(ws(expression_object))),
+                )),
+                |(name, arguments)| Statement::Prepared(name, arguments),
+            ),
+            map(expression_multi, Statement::Eval),
         )),
-        map(expression_multi, Statement::Eval),
         value(Statement::Noop, all_consuming(space0)),
         alt((
             value(Statement::ListConnections, all_consuming(ws(tag(".connections")))),
             value(Statement::Validate, all_consuming(ws(tag(".validate")))),
-            map(all_consuming(preceded(ws(tag(".solve")), separated_pair(identifier, tag(" "), expression))), |(i,e)| Statement::Solve(i,e)),
+            map(
+                preceded(
+                    ws(tag(".index ")),
+                    all_consuming(separated_pair(
+                        ws(identifier),
+                        tag("on"),
+                        ws(preceded(char('.'), identifier)),
+                    )),
+                ),
+                |(bag, field)| Statement::CreateIndex(bag, field),
+            ),
+            map(
+                preceded(
+                    ws(tag(".cdc ")),
+                    all_consuming(separated_pair(ws(identifier), tag("to"), ws(filename))),
+                ),
+                |(bag, f)| Statement::Cdc(bag, Cow::Owned(f.into())),
+            ),
+            map(preceded(ws(tag(".replicate from ")), filename), |f| {
+                Statement::Replicate(Cow::Owned(f.into()))
+            }),
+            map(
+                preceded(
+                    ws(tag(".trigger ")),
+                    all_consuming(separated_pair(
+                        ws(identifier),
+                        tuple((tag("on"), space1, tag("insert"), space1, tag("into"))),
+                        ws(identifier),
+                    )),
+                ),
+                |(connection, bag)| Statement::Trigger(connection, bag),
+            ),
+            alt((
+                map(
+                    all_consuming(preceded(
+                        ws(tag(".solve")),
+                        separated_pair(identifier, tag(" "), terminated(expression, ws(keyword("step")))),
+                    )),
+                    |(i, e)| Statement::SolveStep(i, e),
+                ),
+                map(all_consuming(preceded(ws(tag(".solve")), separated_pair(identifier, tag(" "), expression))), |(i,e)| Statement::Solve(i,e)),
+            )),
+            map(preceded(ws(tag(".preview")), change_query), Statement::Preview),
+            value(Statement::ListTrash, all_consuming(ws(tag(".trash")))),
+            map(
+                all_consuming(preceded(
+                    ws(tag(".trash on")),
+                    opt(preceded(ws(tag("ttl")), ws(nom::character::complete::u64))),
+                )),
+                Statement::EnableTrash,
+            ),
+            value(Statement::DisableTrash, all_consuming(ws(tag(".trash off")))),
+            value(Statement::PurgeTrash, all_consuming(ws(tag(".purge")))),
+            map(
+                all_consuming(preceded(ws(tag(".stats")), opt(ws(identifier)))),
+                Statement::Stats,
+            ),
+            value(Statement::Profile(true), all_consuming(ws(tag(".profile reset")))),
+            value(Statement::Profile(false), all_consuming(ws(tag(".profile")))),
+            alt((
+                value(Statement::Begin, all_consuming(ws(tag(".begin")))),
+                value(Statement::Commit, all_consuming(ws(tag(".commit")))),
+                value(Statement::Rollback, all_consuming(ws(tag(".rollback")))),
+                value(Statement::Step(StepAction::Continue), all_consuming(ws(tag(".continue")))),
+                value(Statement::Step(StepAction::Skip), all_consuming(ws(tag(".skip")))),
+                value(Statement::Step(StepAction::Abort), all_consuming(ws(tag(".abort")))),
+                map(
+                    preceded(
+                        ws(tuple((tag(".break"), space1, tag("on"), space1, tag("insert"), space1, tag("into")))),
+                        all_consuming(pair(
+                            ws(identifier),
+                            opt(alt((
+                                map(where_clause, |guard| (None, Some(guard))),
+                                pair(map(ws(pattern), Some), opt(where_clause)),
+                            ))),
+                        )),
+                    ),
+                    |(bag, clauses)| {
+                        let (pattern, guard) = clauses.unwrap_or((None, None));
+                        Statement::Break(Breakpoint {
+                            bag,
+                            pattern,
+                            guard: guard.unwrap_or(Expression::Literal(Literal::Boolean(true))),
+                        })
+                    },
+                ),
+                value(Statement::ClearBreakpoints, all_consuming(ws(tag(".break off")))),
+                value(Statement::ListBreakpoints, all_consuming(ws(tag(".break")))),
+            )),
+            map(
+                preceded(
+                    ws(tag(".log ")),
+                    alt((
+                        pair(terminated(log_level, space1), full_expression),
+                        map(full_expression, |e| (LogLevel::Info, e)),
+                    )),
+                ),
+                |(level, e)| Statement::Log(level, e),
+            ),
+            map(
+                preceded(
+                    ws(tag(".merge from ")),
+                    all_consuming(tuple((
+                        ws(identifier),
+                        preceded(keyword("into"), ws(identifier)),
+                        preceded(keyword("on"), ws(expression)),
+                        opt(preceded(
+                            keyword("prefer"),
+                            ws(alt((
+                                value(Preference::Source, keyword("source")),
+                                value(Preference::Target, keyword("target")),
+                                map(
+                                    preceded(pair(keyword("newest"), ws(keyword("by"))), expression),
+                                    Preference::Newest,
+                                ),
+                            ))),
+                        )),
+                    ))),
+                ),
+                |(source, target, key, preference)| {
+                    Statement::Merge(source, target, key, preference.unwrap_or_default())
+                },
+            ),
+            map(
+                preceded(
+                    ws(tag(".delta ")),
+                    all_consuming(tuple((
+                        ws(identifier),
+                        ws(identifier),
+                        opt(preceded(keyword("on"), ws(expression))),
+                    ))),
+                ),
+                |(before, after, key)| Statement::Delta(before, after, key),
+            ),
         )),
     )))(input)
 }
 
 #[derive(Debug)]
 pub(crate) enum BundleCommand<'v> {
-    Bag(Identifier<'v>, Option<Predicate<'v>>),
-    Values(ExpressionSet<'v>)
+    Bag(Identifier<'v>, Option<Predicate<'v>>, StorageMode),
+    VirtualBag(Identifier<'v>, IntegerRange),
+    View(Identifier<'v>, Vec<Identifier<'v>>),
+    Values(ExpressionSet<'v>),
+    OnError(ErrorPolicy),
 }
 
 pub(crate) fn bundle_line<'x>(input:&str) -> IResult<&str, BundleCommand<'x>> {
     alt((
         map(
             preceded(ws(tag(".bag ")), all_consuming(ws(identifier))),
-            |name| BundleCommand::Bag(name, None),
+            |name| BundleCommand::Bag(name, None, StorageMode::Multiset),
         ),
-        map(bag_creation, |(name, pred)| BundleCommand::Bag(name, pred)),
+        map(virtual_bag_creation, |(name, range)| BundleCommand::VirtualBag(name, range)),
+        map(view_creation, |(name, bags)| BundleCommand::View(name, bags)),
+        map(bag_creation, |(name, pred, mode)| BundleCommand::Bag(name, pred, mode)),
+        map(on_error, BundleCommand::OnError),
         map(expression_multi, BundleCommand::Values),
     ))(input)
 }
@@ -993,7 +1909,7 @@ pub(crate) fn bundle_line<'x>(input:&str) -> IResult<&str, BundleCommand<'x>> {
 fn predicate<'x>(input:&str) -> IResult<&str, (Vec<Pattern<'x>>, Option<Expression<'x>>)> {
     tuple((
         terminated(separated_list1(ws(tag(";")), ws(pattern)), opt(ws(tag(";")))),
-        opt(preceded(ws(tag("where")), expression)),
+        opt(where_clause),
     ))(input)
 }
 
@@ -1004,6 +1920,7 @@ fn connection_tester<'x>(input:&str) -> IResult<&str, (Consumer<'x>, Expression<
         consumption: Consumption::Test,
         source_bag,
         patterns,
+        plans: Plans::default(),
     }, guard.unwrap_or(Expression::Literal(Literal::Boolean(true)))))(input)
 }
 
@@ -1014,9 +1931,21 @@ fn connection_consumer<'x>(input:&str) -> IResult<&str, (Consumer<'x>, Expressio
         consumption: Consumption::Take,
         source_bag,
         patterns,
+        plans: Plans::default(),
     }, guard.unwrap_or(Expression::Literal(Literal::Boolean(true)))))(input)
 }
 
+fn connection_absence<'x>(input:&str) -> IResult<&str, Absence<'x>> {
+    map(separated_pair(delimited(tag("&"), identifier, tag(".absent")), space1,
+    predicate
+    ), |(source_bag, (patterns, guard))| Absence {
+        source_bag,
+        patterns,
+        guard: guard.unwrap_or(Expression::Literal(Literal::Boolean(true))),
+        plans: Plans::default(),
+    })(input)
+}
+
 fn connection_producer<'x>(input:&str) -> IResult<&str, Producer<'x>> {
     map(separated_pair(delimited(tag("&"), identifier, tag(".produce")), space1,  
     separated_list1(ws(tag(";")), ws(expression))
@@ -1026,8 +1955,10 @@ fn connection_producer<'x>(input:&str) -> IResult<&str, Producer<'x>> {
     })(input)
 }
 
+// Unlike `assignment_list` leaves the `;` separating it from the next
+// component of the connection.
 fn consumer_pattern<'a>(input:&str) -> IResult<&str, AssignmentSet<'a,'a>>{
-    preceded(ws(tag("let ")), assignment_multi)(input)
+    preceded(ws(tag("let ")), assignments)(input)
 
 }
 
@@ -1045,6 +1976,7 @@ fn connection_signature<'x>(input:&str) -> IResult<&str, Signature<'x>>{
 enum ConnectionComponent<'a, 'b> {
     Consumer((Consumer<'a>, Expression<'a>)),
     Producer(Producer<'a>),
+    Absence(Absence<'a>),
     Pattern(AssignmentSet<'a, 'b>),
     Guard(Expression<'a>),
 }
@@ -1055,6 +1987,7 @@ fn connection<'x>(input: &str) -> IResult<&str, Connection<'x>> {
         map(ws(connection_tester), ConnectionComponent::Consumer),
         map(ws(connection_consumer), ConnectionComponent::Consumer),
         map(ws(connection_producer), ConnectionComponent::Producer),
+        map(ws(connection_absence), ConnectionComponent::Absence),
         map(ws(consumer_pattern), ConnectionComponent::Pattern),
         map(ws(consumer_guard), ConnectionComponent::Guard),
     ))), opt(ws(tag(";")))), ws(tag("}"))))(input)?;
@@ -1075,6 +2008,14 @@ fn connection<'x>(input: &str) -> IResult<&str, Connection<'x>> {
         }
     }).cloned().collect();
 
+    let absences = parts.iter().filter_map(|p| {
+        if let ConnectionComponent::Absence(a) = p {
+            Some(a)
+        } else {
+            None
+        }
+    }).cloned().collect();
+
     let patterns = parts.iter().find_map(|p| {
         if let ConnectionComponent::Pattern(c) = p {
             Some(c)
@@ -1107,7 +2048,244 @@ fn connection<'x>(input: &str) -> IResult<&str, Connection<'x>> {
         signature,
         consumers,
         producers,
+        absences,
         patterns: patterns.unwrap_or(AssignmentSet{assignments:vec![]}),
         guard,
     }))
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub expected: Vec<Expected>,
+    pub source_line: String,
+}
+
+impl ParseError {
+    pub fn new(input: &str, err: nom::Err<ParseFailure<&str>>) -> Self {
+        let (offset, expected) = match err {
+            nom::Err::Error(f) | nom::Err::Failure(f) => (input.len() - f.remaining.len(), f.expected),
+            nom::Err::Incomplete(_) => (input.len(), vec![]),
+        };
+        let line_start = input[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[offset..].find('\n').map_or(input.len(), |i| offset + i);
+
+        Self {
+            offset,
+            line: input[..offset].matches('\n').count() + 1,
+            column: input[line_start..offset].chars().count() + 1,
+            expected,
+            source_line: input[line_start..line_end].to_string(),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        format!("{}\n{}^", self.source_line, " ".repeat(self.column - 1))
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Syntax error at line {}, column {}", self.line, self.column)?;
+        if !self.expected.is_empty() {
+            write!(f, ", expected ")?;
+            for (i, e) in self.expected.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " or ")?;
+                }
+                write!(f, "{e}")?;
+            }
+        }
+        write!(f, "\n{}", self.render())
+    }
+}
+
+#[derive(Debug)]
+pub enum StatementError {
+    Limit(LimitError),
+    Syntax(ParseError),
+    Ambiguous(String),
+    InvalidNode { path: String, message: String },
+    // The arguments of a custom statement were rejected by its parser.
+    InvalidArguments { name: String, message: String },
+}
+
+impl std::fmt::Display for StatementError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatementError::Limit(e) => write!(f, "{e}"),
+            StatementError::Syntax(e) => write!(f, "{e}"),
+            StatementError::Ambiguous(suggestion) => write!(f, "Ambiguous statement, {suggestion}"),
+            StatementError::InvalidNode { path, message } => write!(f, "Invalid node at {path}: {message}"),
+            StatementError::InvalidArguments { name, message } => {
+                write!(f, "Invalid arguments for .{name}: {message}")
+            }
+        }
+    }
+}
+
+// Whether the input is only the beginning of a statement that continues on
+// the next line, because a bracket is still open or it ends with `;`, eg
+// between the clauses of a connection. Brackets in literals are ignored.
+pub fn is_incomplete(input: &str) -> bool {
+    let input = strip_comments(input);
+    let mut depth = 0;
+    let mut offset = 0;
+
+    while let Some(c) = input[offset..].chars().next() {
+        if let Some(len) = literal_length(&input[offset..], &input[..offset]) {
+            offset += len;
+            continue;
+        }
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ => {}
+        }
+        offset += c.len_utf8();
+    }
+
+    depth > 0 || input.trim_end().ends_with(';')
+}
+
+// The keywords an expression can follow, a slash after them starts a regular
+// expression rather than dividing.
+const OPERAND_KEYWORDS: &[&str] = &["by", "guard", "if", "in", "into", "matches", "of", "where"];
+
+// The length of the string, template or regular expression literal the input
+// starts with, including its delimiters. As in the grammar, strings and
+// templates end at the next quote, an unterminated one at the end of the
+// input, and regular expressions at the next slash not escaped by a
+// backslash. A slash only starts a regular expression if the code `before`
+// it expects an operand and it does not start a comment or end the hints
+// of a query.
+pub(crate) fn literal_length(input: &str, before: &str) -> Option<usize> {
+    match input.chars().next()? {
+        c @ ('"' | '`') => Some(input[1..].find(c).map_or(input.len(), |i| i + 2)),
+        '/' if !input.starts_with("//")
+            && !input.starts_with("/*")
+            && !before.ends_with('*')
+            && expects_operand(before) =>
+        {
+            let mut escaped = false;
+            input.char_indices().skip(1).find_map(|(i, c)| match c {
+                _ if escaped => {
+                    escaped = false;
+                    None
+                }
+                '\\' => {
+                    escaped = true;
+                    None
+                }
+                '/' => Some(i + 1),
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn expects_operand(before: &str) -> bool {
+    let before = before.trim_end();
+    let word = before.trim_end_matches(|c: char| c.is_alphanumeric() || c == '_');
+    match before.chars().last() {
+        None => true,
+        // The name of a command, eg `.insert /a/`.
+        Some(c) if c.is_alphanumeric() || c == '_' => {
+            word.trim() == "." || OPERAND_KEYWORDS.contains(&&before[word.len()..])
+        }
+        Some(c) => !matches!(c, ')' | ']' | '}' | '"' | '`'),
+    }
+}
+
+// Replaces the `// line` and `/* block */` comments of the input by spaces.
+// Line breaks are kept and each byte of a comment becomes a space, so errors
+// point to the same positions in the original input. A block starting with
+// `/*+` is not a comment but the hints of a query. Comment markers inside of
+// literals are ignored, an unterminated block is kept as it is and fails to
+// parse.
+pub fn strip_comments(input: &str) -> Cow<'_, str> {
+    if !input.contains("//") && !input.contains("/*") {
+        return Cow::Borrowed(input);
+    }
+
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(c) = rest.chars().next() {
+        let comment = if rest.starts_with("//") {
+            Some(rest.find('\n').unwrap_or(rest.len()))
+        } else if rest.starts_with("/*") && !rest.starts_with("/*+") {
+            rest.find("*/").map(|i| i + 2)
+        } else {
+            None
+        };
+        if let Some(len) = comment {
+            for c in rest[..len].chars() {
+                match c {
+                    '\n' => output.push('\n'),
+                    c => output.extend(std::iter::repeat(' ').take(c.len_utf8())),
+                }
+            }
+            rest = &rest[len..];
+            continue;
+        }
+
+        let len = literal_length(rest, &output).unwrap_or(c.len_utf8());
+        output.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    Cow::Owned(output)
+}
+
+// Whether the input consists of nothing but whitespace and comments, eg a
+// line of a script that is skipped.
+pub fn is_blank(input: &str) -> bool {
+    strip_comments(input).trim().is_empty()
+}
+
+pub fn checked_statement<'a, 'b>(
+    input: &str,
+    limits: &Limits,
+) -> Result<Statement<'a, 'b>, StatementError> {
+    limits.check_input(input).map_err(StatementError::Limit)?;
+
+    let stripped = strip_comments(input);
+    // The spaces left by a comment at the end are not part of the statement.
+    let code = match &stripped {
+        Cow::Borrowed(code) => code,
+        Cow::Owned(code) => code.trim_end(),
+    };
+    let (_, stmt) = statement(code)
+        .map_err(|e| StatementError::Syntax(ParseError::new(&input[..code.len()], e)))?;
+
+    limits.check_statement(&stmt).map_err(StatementError::Limit)?;
+
+    Ok(stmt)
+}
+
+pub fn explicit_statement<'a, 'b>(
+    input: &str,
+    limits: &Limits,
+) -> Result<Statement<'a, 'b>, StatementError> {
+    let stmt = checked_statement(input, limits)?;
+    let stripped = strip_comments(input);
+    let input = stripped.trim();
+
+    if input.starts_with(".let ") || input.starts_with(".match ") {
+        return Ok(stmt);
+    }
+
+    match stmt {
+        Statement::AssignSet(_) => Err(StatementError::Ambiguous(format!(
+            "write `.let {}` to assign variables",
+            input.strip_prefix("let").unwrap_or(input).trim_start()
+        ))),
+        Statement::MatchSet(_) => Err(StatementError::Ambiguous(format!(
+            "write `.match {input}` to match a pattern, `.let {input}` to assign variables or use `==` to compare values"
+        ))),
+        stmt => Ok(stmt),
+    }
+}
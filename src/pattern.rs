@@ -1,6 +1,10 @@
+use std::borrow::Cow;
 use std::collections::VecDeque;
 
+use serde::{Deserialize, Serialize};
+
 use crate::expression::{PropertyKey, Expression};
+use crate::formatter;
 use crate::identifier::Identifier;
 use crate::literal::Literal;
 use crate::value::ValueType;
@@ -8,7 +12,7 @@ use crate::value::ValueType;
 use gen_iter::gen_iter;
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Pattern<'s> {
     Discard,
     Capture(Identifier<'s>, Box<Pattern<'s>>),
@@ -18,74 +22,41 @@ pub enum Pattern<'s> {
     Literal(Literal<'s>),
     Object(ObjectPattern<'s>, Rest<'s>),
     Array(ArrayPattern<'s>, Rest<'s>),
+    Or(Vec<Pattern<'s>>),
+    Descendant(Box<Pattern<'s>>),
+    Regex(Cow<'s, str>),
+    Guarded(Box<Pattern<'s>>, Box<Expression<'s>>),
 }
 
 impl<'a> std::fmt::Display for Pattern<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let _ = match self {
-            Pattern::Discard => write!(f, "_"),
-            Pattern::Literal(l) => write!(f, "{l}"),
-            Pattern::Capture(id, pat) => write!(f, "{pat} @ {id}"),
-            Pattern::TypedDiscard(t) => write!(f, "_ is {t}"),
-            Pattern::Identifier(id) => write!(f, "{id}"),
-            Pattern::TypedIdentifier(id, t) => write!(f, "{id} is {t}"),
-            Pattern::Object(props, rest) => {
-                let _ = write!(f, "{{");
-
-                for prop in props {
-                    let _ = match prop {
-                        ObjectPropertyPattern::Single(p) => write!(f, "{p}"),
-                        ObjectPropertyPattern::Match(PropertyPattern { key, value }) => {
-                            let _ = match key {
-                                PropertyKey::Identifier(id) => {
-                                    write!(f, "{id}")
-                                }
-                                PropertyKey::Expression(e) => {
-                                    write!(f, "{e}")
-                                }
-                            };
-
-                            write!(f, ": {value}")
-                        }
-                    };
-                    let _ = write!(f, ",");
-                }
+        write!(f, "{}", formatter::pattern(self))
+    }
+}
 
-                match rest {
-                    Rest::Exact => {}
-                    Rest::Discard => {
-                        let _ = write!(f, "...");
-                    }
-                    Rest::Collect(p) => {
-                        let _ = write!(f, "...{p}");
+impl Pattern<'_> {
+    pub(crate) fn is_ambiguous(&self) -> bool {
+        match self {
+            Pattern::Or(_) | Pattern::Descendant(_) => true,
+            Pattern::Capture(_, p) | Pattern::Guarded(p, _) => p.is_ambiguous(),
+            Pattern::Object(props, rest) => {
+                props.iter().any(|p| match p {
+                    ObjectPropertyPattern::Single(_) => false,
+                    ObjectPropertyPattern::Match(PropertyPattern { value, .. }) => {
+                        value.is_ambiguous()
                     }
-                };
-
-                write!(f, "}}")
+                }) || rest.is_ambiguous()
             }
             Pattern::Array(items, rest) => {
-                let _ = write!(f, "[");
-                for ArrayPatternItem::Pattern(item) in items {
-                    let _ = write!(f, "{item},");
-                }
-
-                match rest {
-                    Rest::Exact => {}
-                    Rest::Discard => {
-                        let _ = write!(f, "...");
-                    }
-                    Rest::Collect(p) => {
-                        let _ = write!(f, "...{p}");
-                    }
-                };
-                write!(f, "]")
+                items
+                    .iter()
+                    .any(|ArrayPatternItem::Pattern(p)| p.is_ambiguous())
+                    || rest.is_ambiguous()
             }
-        };
-        write!(f, "")
+            _ => false,
+        }
     }
-}
 
-impl Pattern<'_> {
     pub(crate) fn get_identifiers(&self) -> impl Iterator<Item = &Identifier> {
         gen_iter!(move {
             let mut stack = VecDeque::new();
@@ -93,7 +64,10 @@ impl Pattern<'_> {
             while let Some(p) = stack.pop_front() {
                 match &p {
                     Pattern::Discard => {},
-                    Pattern::Capture(id, _) => yield id,
+                    Pattern::Capture(id, p) => {
+                        yield id;
+                        stack.push_front(p);
+                    },
                     Pattern::Identifier(id) => yield id,
                     Pattern::TypedDiscard(_) => {},
                     Pattern::TypedIdentifier(id, _) => yield id,
@@ -123,6 +97,14 @@ impl Pattern<'_> {
                             stack.push_front(p);
                         }
                     },
+                    Pattern::Or(alternatives) => {
+                        for p in alternatives {
+                            stack.push_front(p);
+                        }
+                    },
+                    Pattern::Descendant(p) => stack.push_front(p),
+                    Pattern::Regex(_) => {},
+                    Pattern::Guarded(p, _) => stack.push_front(p),
                 }
             }
         })
@@ -135,7 +117,7 @@ impl Pattern<'_> {
             while let Some(p) = pattern_stack.pop_front() {
                 match &p {
                     Pattern::Discard => {},
-                    Pattern::Capture(_id, _) => {},
+                    Pattern::Capture(_id, p) => pattern_stack.push_front(p),
                     Pattern::Identifier(_id) => {},
                     Pattern::TypedDiscard(_) => {},
                     Pattern::TypedIdentifier(_id, _) => {},
@@ -167,35 +149,108 @@ impl Pattern<'_> {
                             pattern_stack.push_front(p);
                         }
                     },
+                    Pattern::Or(alternatives) => {
+                        for p in alternatives {
+                            pattern_stack.push_front(p);
+                        }
+                    },
+                    Pattern::Descendant(p) => pattern_stack.push_front(p),
+                    Pattern::Regex(_) => {},
+                    Pattern::Guarded(p, guard) => {
+                        yield guard;
+                        pattern_stack.push_front(p);
+                    },
                 }
             };
         })
     }
 }
 
-#[derive(Clone, Debug)]
+impl Pattern<'_> {
+    pub(crate) fn deep_clone<'x,'y>(&'x self) -> Pattern<'y> {
+        match self {
+            Pattern::Discard => Pattern::Discard,
+            Pattern::Capture(id, p) => Pattern::Capture(id.deep_clone(), Box::new(p.deep_clone())),
+            Pattern::Identifier(id) => Pattern::Identifier(id.deep_clone()),
+            Pattern::TypedDiscard(t) => Pattern::TypedDiscard(*t),
+            Pattern::TypedIdentifier(id, t) => Pattern::TypedIdentifier(id.deep_clone(), *t),
+            Pattern::Literal(l) => Pattern::Literal(l.deep_clone()),
+            Pattern::Object(props, rest) => Pattern::Object(
+                props
+                    .iter()
+                    .map(|p| match p {
+                        ObjectPropertyPattern::Single(id) => {
+                            ObjectPropertyPattern::Single(id.deep_clone())
+                        }
+                        ObjectPropertyPattern::Match(PropertyPattern { key, value }) => {
+                            ObjectPropertyPattern::Match(PropertyPattern {
+                                key: key.deep_clone(),
+                                value: value.deep_clone(),
+                            })
+                        }
+                    })
+                    .collect(),
+                rest.deep_clone(),
+            ),
+            Pattern::Array(items, rest) => Pattern::Array(
+                items
+                    .iter()
+                    .map(|ArrayPatternItem::Pattern(p)| ArrayPatternItem::Pattern(p.deep_clone()))
+                    .collect(),
+                rest.deep_clone(),
+            ),
+            Pattern::Or(alternatives) => {
+                Pattern::Or(alternatives.iter().map(|p| p.deep_clone()).collect())
+            }
+            Pattern::Descendant(p) => Pattern::Descendant(Box::new(p.deep_clone())),
+            Pattern::Regex(r) => Pattern::Regex(Cow::Owned(r.to_string())),
+            Pattern::Guarded(p, guard) => {
+                Pattern::Guarded(Box::new(p.deep_clone()), Box::new(guard.deep_clone()))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Rest<'s> {
     Exact,
     Discard,
     Collect(Box<Pattern<'s>>),
 }
 
+impl Rest<'_> {
+    fn is_ambiguous(&self) -> bool {
+        match self {
+            Rest::Collect(p) => p.is_ambiguous(),
+            _ => false,
+        }
+    }
+
+    fn deep_clone<'x,'y>(&'x self) -> Rest<'y> {
+        match self {
+            Rest::Exact => Rest::Exact,
+            Rest::Discard => Rest::Discard,
+            Rest::Collect(p) => Rest::Collect(Box::new(p.deep_clone())),
+        }
+    }
+}
+
 pub type ObjectPattern<'a> = Vec<ObjectPropertyPattern<'a>>;
 pub type ArrayPattern<'a> = Vec<ArrayPatternItem<'a>>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ArrayPatternItem<'a> {
     Pattern(Pattern<'a>),
     //Expression(Expression<'a>),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ObjectPropertyPattern<'a> {
     Single(Identifier<'a>),
     Match(PropertyPattern<'a>),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PropertyPattern<'a> {
     pub key: PropertyKey<'a>,
     pub value: Pattern<'a>,
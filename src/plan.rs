@@ -0,0 +1,185 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::sync::{Arc, OnceLock};
+
+use crate::expression::PropertyKey;
+use crate::literal::Literal;
+use crate::matcher::{Matcher, PatternFail};
+use crate::pattern::{ObjectPropertyPattern, Pattern, PropertyPattern, Rest};
+use crate::value::Value;
+
+// Owns its values so that it does not tie the plan to the input.
+#[derive(Clone, Debug)]
+pub(crate) enum LiteralFilter {
+    Equals(Value<'static, 'static>),
+    Fields(Vec<(String, Value<'static, 'static>)>, bool),
+}
+
+impl LiteralFilter {
+    fn compile(pattern: &Pattern<'_>) -> Option<Self> {
+        match pattern {
+            Pattern::Literal(l) => owned_value(l).map(LiteralFilter::Equals),
+            Pattern::Object(props, rest) => {
+                let exact = match rest {
+                    Rest::Exact => true,
+                    Rest::Discard => false,
+                    Rest::Collect(_) => return None,
+                };
+                let mut seen = BTreeSet::new();
+                let mut fields = Vec::with_capacity(props.len());
+
+                for prop in props {
+                    let ObjectPropertyPattern::Match(PropertyPattern {
+                        key: PropertyKey::Identifier(key),
+                        value: Pattern::Literal(l),
+                    }) = prop else {
+                        return None;
+                    };
+                    if !seen.insert(key.name.clone()) {
+                        return None;
+                    }
+                    fields.push((key.name.to_string(), owned_value(l)?));
+                }
+
+                Some(LiteralFilter::Fields(fields, exact))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn test(&self, value: &Value<'_, '_>) -> bool {
+        match self {
+            LiteralFilter::Equals(expected) => equals_scalar(expected, value),
+            LiteralFilter::Fields(fields, exact) => {
+                let Value::Object(o) = value else {
+                    return false;
+                };
+                if *exact && o.len() != fields.len() {
+                    return false;
+                }
+                fields
+                    .iter()
+                    .all(|(k, expected)| o.get(k.as_str()).map_or(false, |v| equals_scalar(expected, v)))
+            }
+        }
+    }
+}
+
+pub(crate) fn literal_value<'s, 'v>(literal: &Literal<'s>) -> Option<Value<'s, 'v>> {
+    match literal {
+        Literal::String(s) => Some(Value::String(s.clone())),
+        _ => scalar_value(literal),
+    }
+}
+
+// The filters only hold scalars, which are compared without requiring
+// the value to live as long as the filter.
+fn equals_scalar(expected: &Value<'static, 'static>, value: &Value<'_, '_>) -> bool {
+    match (expected, value) {
+        (Value::Null, Value::Null) => true,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::Integer(a), Value::Integer(b)) | (Value::DateTime(a), Value::DateTime(b)) => a == b,
+        (Value::BigInt(a), Value::BigInt(b)) => a == b,
+        (Value::Boolean(a), Value::Boolean(b)) => a == b,
+        (Value::Type(a), Value::Type(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn owned_value(literal: &Literal<'_>) -> Option<Value<'static, 'static>> {
+    match literal {
+        Literal::String(s) => Some(Value::String(Cow::Owned(s.to_string()))),
+        _ => scalar_value(literal),
+    }
+}
+
+fn scalar_value<'s, 'v>(literal: &Literal<'_>) -> Option<Value<'s, 'v>> {
+    Some(match literal {
+        Literal::Null => Value::Null,
+        Literal::Number(n) => Value::parse_integer(n)?,
+        Literal::Boolean(b) => Value::Boolean(*b),
+        Literal::Type(t) => Value::Type(*t),
+        Literal::DateTime(t) => Value::parse_datetime(t)?,
+        Literal::String(_) | Literal::Regex(_) => return None,
+    })
+}
+
+// How the values are matched against a pattern. The plan does not borrow
+// the pattern, so that it can be kept along with it, see `Plans`.
+#[derive(Clone, Debug)]
+pub(crate) enum PatternPlan {
+    Literal(LiteralFilter),
+    Full,
+    Backtracking,
+}
+
+impl PatternPlan {
+    pub(crate) fn new(pattern: &Pattern<'_>) -> Self {
+        if pattern.is_ambiguous() {
+            return PatternPlan::Backtracking;
+        }
+
+        let inner = match pattern {
+            Pattern::Capture(_, inner) => inner.as_ref(),
+            _ => pattern,
+        };
+
+        match LiteralFilter::compile(inner) {
+            Some(filter) => PatternPlan::Literal(filter),
+            None => PatternPlan::Full,
+        }
+    }
+
+    // Matches the value against the pattern the plan was made for.
+    pub(crate) fn execute<'i, 's, 'v, 'e>(
+        &self,
+        pattern: &Pattern<'s>,
+        matcher: &mut Matcher<'i, 's, 'v, 'e>,
+        value: &Value<'s, 'v>,
+    ) -> Result<(), PatternFail> {
+        match (self, pattern) {
+            (PatternPlan::Literal(filter), _) if !filter.test(value) => Err(PatternFail::LiteralMismatch),
+            (PatternPlan::Literal(_), Pattern::Capture(id, _)) => matcher.match_identifier(id, value),
+            (PatternPlan::Literal(_), _) => Ok(()),
+            (PatternPlan::Full | PatternPlan::Backtracking, pattern) => matcher.match_pattern(pattern, value),
+        }
+    }
+
+    pub(crate) fn solutions<'i, 's, 'v, 'e, 'x>(
+        &self,
+        pattern: &'x Pattern<'s>,
+        matcher: &mut Matcher<'i, 's, 'v, 'e>,
+        value: Cow<'x, Value<'s, 'v>>,
+    ) -> Box<dyn Iterator<Item = Matcher<'i, 's, 'v, 'e>> + 'x>
+    where
+        'i: 'x,
+        'e: 'x,
+    {
+        match self {
+            PatternPlan::Backtracking => Box::new(matcher.match_all(pattern, value)),
+            _ => Box::new(self.execute(pattern, matcher, &value).ok().map(|_| matcher.clone()).into_iter()),
+        }
+    }
+}
+
+impl std::fmt::Display for PatternPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatternPlan::Literal(_) => write!(f, "literal comparison"),
+            PatternPlan::Full => write!(f, "pattern matching"),
+            PatternPlan::Backtracking => write!(f, "backtracking pattern matching"),
+        }
+    }
+}
+
+// The plans of the patterns of a predicate, made when it is matched first.
+// The clones of the predicate share them, eg the statements served by the
+// plan cache are only planned once.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Plans(Arc<OnceLock<Vec<PatternPlan>>>);
+
+impl Plans {
+    pub(crate) fn of(&self, patterns: &[Pattern<'_>]) -> &[PatternPlan] {
+        self.0.get_or_init(|| patterns.iter().map(PatternPlan::new).collect())
+    }
+}
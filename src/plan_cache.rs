@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use crate::statement::Statement;
+
+// Parsed and checked statements by their input, so that a statement sent
+// again and again, eg by a dashboard polling the web server, is parsed only
+// once. The statements served share the plans of their patterns, so those
+// are made only once as well. Once the cache is full the least recently
+// used statement is evicted.
+pub struct PlanCache<'s> {
+    capacity: usize,
+    entries: HashMap<String, (Statement<'s, 's>, u64)>,
+    clock: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+// Surrounding whitespace is ignored. Whitespace inside of the input is kept
+// as it is, it may be part of a string or a regex.
+pub fn normalize(input: &str) -> &str {
+    input.trim()
+}
+
+impl<'s> PlanCache<'s> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&mut self, input: &str) -> Option<Statement<'s, 's>> {
+        self.clock += 1;
+        let Some((statement, used)) = self.entries.get_mut(normalize(input)) else {
+            self.misses += 1;
+            return None;
+        };
+        *used = self.clock;
+        self.hits += 1;
+
+        Some(statement.clone())
+    }
+
+    pub fn insert(&mut self, input: &str, statement: Statement<'s, 's>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+                self.evictions += 1;
+            }
+        }
+        self.entries
+            .insert(normalize(input).to_string(), (statement, self.clock));
+    }
+
+    // Statements are checked against the limits in effect when they were
+    // parsed, so the cache is cleared whenever the settings change.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl std::fmt::Display for PlanCache<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} statements cached, {} hits, {} misses, {} evictions",
+            self.len(),
+            self.capacity,
+            self.hits,
+            self.misses,
+            self.evictions
+        )
+    }
+}
@@ -0,0 +1,307 @@
+use crate::parser::{literal_length, strip_comments, KEYWORDS};
+use crate::repl::ReplOutput;
+use crate::value::ValueType;
+
+// ANSI SGR codes used by the terminal frontend. An empty code leaves the text
+// unstyled.
+#[derive(Clone, Copy, Debug)]
+pub struct Theme {
+    pub string: &'static str,
+    pub number: &'static str,
+    pub keyword: &'static str,
+    pub identifier: &'static str,
+    pub notice: &'static str,
+    pub border: &'static str,
+    pub error: &'static str,
+    pub removed: &'static str,
+    pub added: &'static str,
+}
+
+impl Theme {
+    pub const PLAIN: Theme = Theme {
+        string: "",
+        number: "",
+        keyword: "",
+        identifier: "",
+        notice: "",
+        border: "",
+        error: "",
+        removed: "",
+        added: "",
+    };
+
+    pub const COLORED: Theme = Theme {
+        string: "32",
+        number: "36",
+        keyword: "35",
+        identifier: "33",
+        notice: "1",
+        border: "2",
+        error: "31",
+        removed: "31",
+        added: "32",
+    };
+
+    pub fn new(color: bool) -> Self {
+        if color {
+            Self::COLORED
+        } else {
+            Self::PLAIN
+        }
+    }
+
+    fn paint(code: &str, text: &str) -> String {
+        if code.is_empty() || text.is_empty() {
+            text.to_string()
+        } else {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        }
+    }
+
+    pub fn error(&self, text: &str) -> String {
+        Self::paint(self.error, text)
+    }
+
+    // Colors the tokens of a value written in damasc syntax. Strings are
+    // written without escapes, so a string ends at the next quote.
+    pub fn value(&self, text: &str) -> String {
+        let mut result = String::new();
+        let mut rest = text;
+
+        while let Some(c) = rest.chars().next() {
+            let (code, len) = if c == '"' {
+                let len = rest[1..].find('"').map_or(rest.len(), |i| i + 2);
+                (self.string, len)
+            } else if c.is_ascii_digit() {
+                let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                (self.number, len)
+            } else if c.is_alphabetic() || c == '_' {
+                let len = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                let code = match &rest[..len] {
+                    "true" | "false" | "null" => self.keyword,
+                    _ => "",
+                };
+                (code, len)
+            } else {
+                ("", c.len_utf8())
+            };
+
+            result.push_str(&Self::paint(code, &rest[..len]));
+            rest = &rest[len..];
+        }
+
+        result
+    }
+
+    // Colors an input while it is typed, like `value` but also commands,
+    // keywords, identifiers and comments. Brackets without a matching one are
+    // colored as errors.
+    pub fn input(&self, text: &str) -> String {
+        // Comments are replaced by as many spaces, the positions stay the same.
+        let stripped = strip_comments(text);
+        let unmatched = unmatched_brackets(&stripped);
+        let mut result = String::new();
+        let mut offset = 0;
+
+        while let Some(c) = text[offset..].chars().next() {
+            let rest = &text[offset..];
+            let command = c == '.'
+                && text[..offset].trim().is_empty()
+                && rest[1..].starts_with(char::is_alphabetic);
+            let comment = stripped[offset..].len() - stripped[offset..].trim_start().len();
+            let (code, len) = if c == '/' && comment > 0 {
+                let len = rest[..comment].trim_end().len();
+                (self.border, len)
+            } else if let Some(len) = literal_length(&stripped[offset..], &stripped[..offset]) {
+                (self.string, len)
+            } else if c.is_ascii_digit() {
+                let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                (self.number, len)
+            } else if command || c.is_alphabetic() || c == '_' {
+                let len = rest
+                    .char_indices()
+                    .skip(1)
+                    .find(|(_, c)| !(c.is_alphanumeric() || *c == '_'))
+                    .map_or(rest.len(), |(i, _)| i);
+                let word = &rest[..len];
+                let code = if command
+                    || matches!(word, "true" | "false" | "null")
+                    || KEYWORDS.contains(&word)
+                    || ValueType::ALL.iter().any(|t| t.name() == word)
+                {
+                    self.keyword
+                } else {
+                    self.identifier
+                };
+                (code, len)
+            } else if unmatched.contains(&offset) {
+                (self.error, c.len_utf8())
+            } else {
+                ("", c.len_utf8())
+            };
+
+            result.push_str(&Self::paint(code, &rest[..len]));
+            offset += len;
+        }
+
+        result
+    }
+
+    // The candidates of `.solve ... step` and the breakpoint hits, with their
+    // comments dimmed.
+    fn debugger(&self, text: &str) -> String {
+        text.lines()
+            .map(|line| match line.split_once(" -- ") {
+                Some((binding, origin)) => format!(
+                    "{binding} {}\n",
+                    Self::paint(self.border, &format!("-- {origin}"))
+                ),
+                None if line.starts_with("--") => format!("{}\n", Self::paint(self.border, line)),
+                None if line.starts_with("CANDIDATE") || line.starts_with("BREAK") => {
+                    format!("{}\n", Self::paint(self.keyword, line))
+                }
+                None => format!("{line}\n"),
+            })
+            .collect()
+    }
+
+    // Same layout as the `Display` of the output, with colors.
+    pub fn render(&self, output: &ReplOutput) -> String {
+        match output {
+            ReplOutput::Ack | ReplOutput::No | ReplOutput::PatternMissmatch => {
+                let text = output.to_string();
+                format!("{}\n", Self::paint(self.keyword, text.trim_end()))
+            }
+            ReplOutput::Values(values) => values
+                .iter()
+                .map(|v| format!("{};\n", self.value(&v.to_string())))
+                .collect(),
+            ReplOutput::Bindings(bindings) => {
+                let mut result = format!("{}\n", Self::paint(self.keyword, "YES."));
+                for (k, v) in bindings {
+                    result.push_str(&format!("{k} := {};\n", self.value(&v.to_string())));
+                }
+                result
+            }
+            ReplOutput::Deleted(_)
+            | ReplOutput::Inserted(_)
+            | ReplOutput::Updated(_)
+            | ReplOutput::Transferd(_) => {
+                let text = output.to_string();
+                format!("{}\n", Self::paint(self.notice, text.trim_end()))
+            }
+            ReplOutput::Notice(_) => output.to_string(),
+            ReplOutput::Solved(bags) => {
+                let mut result = format!("{}\n", Self::paint(self.notice, "SOLVED."));
+                for (bag, deleted, inserted) in bags {
+                    result.push_str(&format!(
+                        "{bag}: {} deleted, {} inserted\n",
+                        Self::paint(self.number, &deleted.to_string()),
+                        Self::paint(self.number, &inserted.to_string()),
+                    ));
+                }
+                result
+            }
+            ReplOutput::Preview(..) => output
+                .to_string()
+                .lines()
+                .map(|line| {
+                    let code = match line.chars().next() {
+                        Some('@') => self.border,
+                        Some('-') => self.removed,
+                        Some('+') => self.added,
+                        _ => self.notice,
+                    };
+                    format!("{}\n", Self::paint(code, line))
+                })
+                .collect(),
+            ReplOutput::Measured(output, metrics) => format!(
+                "{}{}\n",
+                self.render(output),
+                Self::paint(self.border, &format!("-- {metrics}"))
+            ),
+            ReplOutput::Warned(output, warnings) => {
+                let mut result = self.render(output);
+                for warning in warnings {
+                    result.push_str(&format!(
+                        "{}\n",
+                        Self::paint(self.notice, &format!("-- warning: {warning}"))
+                    ));
+                }
+                result
+            }
+            ReplOutput::Candidate(candidate) => self.debugger(&candidate.to_string()),
+            ReplOutput::Paused(output, hit) => {
+                format!("{}{}", self.render(output), self.debugger(&hit.to_string()))
+            }
+            ReplOutput::Resumed(output, queued) => {
+                let mut result = self.render(output);
+                for output in queued {
+                    result.push_str(&self.render(output));
+                }
+                result
+            }
+        }
+    }
+}
+
+// The positions of the brackets that are not closed or not opened, ignoring
+// those inside of literals.
+fn unmatched_brackets(text: &str) -> Vec<usize> {
+    let mut open: Vec<(usize, char)> = vec![];
+    let mut unmatched = vec![];
+    let mut offset = 0;
+
+    while let Some(c) = text[offset..].chars().next() {
+        if let Some(len) = literal_length(&text[offset..], &text[..offset]) {
+            offset += len;
+            continue;
+        }
+        match c {
+            '(' | '[' | '{' => open.push((offset, c)),
+            ')' | ']' | '}' => {
+                let opening = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                if open.last().map_or(false, |(_, o)| *o == opening) {
+                    open.pop();
+                } else {
+                    unmatched.push(offset);
+                }
+            }
+            _ => {}
+        }
+        offset += c.len_utf8();
+    }
+
+    unmatched.extend(open.into_iter().map(|(i, _)| i));
+    unmatched
+}
+
+// The output for the web frontend, escaped for HTML. The lines of a preview
+// are marked so that removed and added values can be told apart.
+pub fn html(output: &ReplOutput) -> String {
+    let text = output.to_string();
+    let escaped = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+
+    if !matches!(output, ReplOutput::Preview(..)) {
+        return escaped;
+    }
+
+    escaped
+        .lines()
+        .map(|line| match line.chars().next() {
+            Some('-') => format!("<span class=\"diff-removed\">{line}</span>\n"),
+            Some('+') => format!("<span class=\"diff-added\">{line}</span>\n"),
+            _ => format!("{line}\n"),
+        })
+        .collect()
+}
@@ -0,0 +1,132 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use crate::env::Environment;
+use crate::expression::{Expression, LogicalExpression, LogicalOperator};
+use crate::value::Value;
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    static GUARDS: RefCell<BTreeMap<String, Vec<Conjunct>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// One operand of the top level `&&` chain of a guard.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conjunct {
+    pub expression: String,
+    pub evaluated: u64,
+    pub rejected: u64,
+}
+
+// Guards are profiled while the `profile` setting is enabled.
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.with(|e| e.set(enabled));
+}
+
+pub(crate) fn reset() {
+    GUARDS.with(|guards| guards.borrow_mut().clear());
+}
+
+// The guards evaluated since the last reset, by their source.
+pub fn guards() -> Vec<(String, Vec<Conjunct>)> {
+    GUARDS.with(|guards| {
+        guards
+            .borrow()
+            .iter()
+            .map(|(guard, conjuncts)| (guard.clone(), conjuncts.clone()))
+            .collect()
+    })
+}
+
+fn conjuncts<'x, 'e>(guard: &'x Expression<'e>, out: &mut Vec<&'x Expression<'e>>) {
+    match guard {
+        Expression::Logical(LogicalExpression {
+            operator: LogicalOperator::And,
+            left,
+            right,
+        }) => {
+            conjuncts(left, out);
+            conjuncts(right, out);
+        }
+        other => out.push(other),
+    }
+}
+
+// Whether the guard evaluates to `true`. While profiling, the operands of
+// its `&&` chain are evaluated one by one, counting how often each of them
+// is reached and how often it stops the chain, which is the same result
+// `&&` short-circuits to.
+pub(crate) fn accepts(env: &Environment, guard: &Expression) -> bool {
+    if !ENABLED.with(|e| e.get()) {
+        return matches!(env.eval_expr(guard), Ok(Value::Boolean(true)));
+    }
+
+    let mut operands = vec![];
+    conjuncts(guard, &mut operands);
+    let rejected_by = operands
+        .iter()
+        .position(|o| !matches!(env.eval_expr(o), Ok(Value::Boolean(true))));
+
+    GUARDS.with(|guards| {
+        let mut guards = guards.borrow_mut();
+        let stats = guards.entry(guard.to_string()).or_insert_with(|| {
+            operands
+                .iter()
+                .map(|o| Conjunct {
+                    expression: o.to_string(),
+                    evaluated: 0,
+                    rejected: 0,
+                })
+                .collect()
+        });
+        for c in &mut stats[..rejected_by.map_or(operands.len(), |r| r + 1)] {
+            c.evaluated += 1;
+        }
+        if let Some(r) = rejected_by {
+            stats[r].rejected += 1;
+        }
+    });
+
+    rejected_by.is_none()
+}
+
+pub fn report() -> String {
+    let guards = guards();
+    if guards.is_empty() {
+        return "No guards evaluated. Enable profiling with `.set profile true`.".to_string();
+    }
+
+    let mut out = String::new();
+    for (guard, conjuncts) in guards {
+        out.push_str(&guard);
+        out.push('\n');
+        for c in &conjuncts {
+            let rate = if c.evaluated == 0 {
+                0.0
+            } else {
+                c.rejected as f64 * 100.0 / c.evaluated as f64
+            };
+            out.push_str(&format!(
+                "  {}: {} evaluated, {} rejected ({rate:.0}%)\n",
+                c.expression, c.evaluated, c.rejected
+            ));
+        }
+        // An operand rejecting more often than the one before it saves
+        // evaluations when moved to the front.
+        let rates = conjuncts
+            .iter()
+            .filter(|c| c.evaluated > 0)
+            .map(|c| (c, c.rejected as f64 / c.evaluated as f64))
+            .collect::<Vec<_>>();
+        if let Some((best, _)) = rates
+            .iter()
+            .skip(1)
+            .filter(|(_, rate)| *rate > rates[0].1)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        {
+            out.push_str(&format!("  hint: check {} first\n", best.expression));
+        }
+    }
+
+    out.trim_end().to_string()
+}
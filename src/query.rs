@@ -1,24 +1,212 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    env::Environment,
-    expression::{Expression, ExpressionSet},
+    collation::Collation,
+    bag_bundle::BagBundle,
+    env::{integer_operation, Environment, EvalError},
+    expression::{CallExpression, Expression, ExpressionSet},
+    identifier::Identifier,
     literal::Literal,
     matcher::Matcher,
     pattern::Pattern,
+    plan::{PatternPlan, Plans},
+    profile,
     value::Value,
 };
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ProjectionQuery<'s> {
     pub outer: bool,
     pub predicate: CrossPredicate<'s>,
     pub projection: Expression<'s>,
+    pub group: Option<Expression<'s>>,
+    pub aggregates: Vec<Aggregate<'s>>,
+    pub order: Option<OrderBy<'s>>,
+    #[serde(default)]
+    pub hints: Vec<QueryHint<'s>>,
+}
+
+// Hints given in a `/*+ ... */` block right after `.query`, eg
+// `.query /*+ no_index, join_order(b, a) */ a; b where ...`. They override
+// decisions of the planner, `join_order` is kept for once joins are planned.
+// Hints that are not known are kept as written and reported as warnings.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueryHint<'s> {
+    NoIndex,
+    JoinOrder(Vec<Identifier<'s>>),
+    Unknown(String),
 }
 
-#[derive(Clone,Debug)]
+impl std::fmt::Display for QueryHint<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryHint::NoIndex => write!(f, "no_index"),
+            QueryHint::JoinOrder(names) => {
+                write!(f, "join_order(")?;
+                for (i, name) in names.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{name}")?;
+                }
+                write!(f, ")")
+            }
+            QueryHint::Unknown(hint) => write!(f, "{hint}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrderBy<'s> {
+    pub key: Expression<'s>,
+    pub descending: bool,
+    #[serde(default)]
+    pub collation: Collation,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl AggregateFunction {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "count" => Self::Count,
+            "sum" => Self::Sum,
+            "min" => Self::Min,
+            "max" => Self::Max,
+            "avg" => Self::Avg,
+            _ => return None,
+        })
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Count => "count",
+            Self::Sum => "sum",
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Avg => "avg",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Aggregate<'s> {
+    pub binding: Identifier<'s>,
+    pub function: AggregateFunction,
+    pub argument: Option<Expression<'s>>,
+}
+
+impl<'s> std::fmt::Display for Aggregate<'s> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.argument {
+            Some(a) => write!(f, "{}({a})", self.function.name()),
+            None => write!(f, "{}()", self.function.name()),
+        }
+    }
+}
+
+pub(crate) fn extract_aggregates<'s>(
+    projection: &Expression<'s>,
+    aggregates: &mut Vec<Aggregate<'s>>,
+) -> Expression<'s> {
+    projection.rewrite(&mut |e| {
+        let Expression::Call(CallExpression { function, arguments }) = e else {
+            return None;
+        };
+        let function = AggregateFunction::from_name(&function.name)?;
+        let argument = match (function, arguments.as_slice()) {
+            (AggregateFunction::Count, []) => None,
+            (AggregateFunction::Count, _) | (_, []) => return None,
+            (_, [argument]) => Some(argument.clone()),
+            _ => return None,
+        };
+        let binding = Identifier {
+            name: Cow::Owned(format!("$aggregate{}", aggregates.len())),
+        };
+        aggregates.push(Aggregate {
+            binding: binding.clone(),
+            function,
+            argument,
+        });
+
+        Some(Expression::Identifier(binding))
+    })
+}
+
+pub(crate) struct Accumulator<'s, 'v> {
+    function: AggregateFunction,
+    count: i64,
+    value: Option<Value<'s, 'v>>,
+}
+
+impl<'s, 'v> Accumulator<'s, 'v> {
+    pub(crate) fn new(function: AggregateFunction) -> Self {
+        Self {
+            function,
+            count: 0,
+            value: None,
+        }
+    }
+
+    pub(crate) fn add(&mut self, argument: Option<Value<'s, 'v>>) -> Result<(), EvalError> {
+        self.count += 1;
+
+        let Some(argument) = argument else {
+            return Ok(());
+        };
+
+        self.value = Some(match (self.function, self.value.take(), argument) {
+            (
+                AggregateFunction::Sum | AggregateFunction::Avg,
+                None,
+                i @ (Value::Integer(_) | Value::BigInt(_)),
+            ) => i,
+            (AggregateFunction::Sum | AggregateFunction::Avg, Some(acc), i) => {
+                integer_operation(&acc, &i, i64::checked_add, |l, r| Ok(l + r))?
+            }
+            (AggregateFunction::Sum | AggregateFunction::Avg, None, _) => {
+                return Err(EvalError::TypeError)
+            }
+            (_, None, v) => v,
+            (AggregateFunction::Min, Some(acc), v) => std::cmp::min(acc, v),
+            (AggregateFunction::Max, Some(acc), v) => std::cmp::max(acc, v),
+            (AggregateFunction::Count, Some(_), _) => return Err(EvalError::ArgumentMismatch),
+        });
+
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Value<'s, 'v> {
+        match (self.function, self.value) {
+            (AggregateFunction::Count, _) => Value::Integer(self.count),
+            (AggregateFunction::Sum, None) => Value::Integer(0),
+            (AggregateFunction::Avg, Some(Value::Integer(sum))) => Value::Integer(sum / self.count),
+            (AggregateFunction::Avg, Some(Value::BigInt(sum))) => {
+                Value::from_bigint(sum / self.count)
+            }
+            (_, None) => Value::Null,
+            (_, Some(v)) => v,
+        }
+    }
+}
+
+#[derive(Clone,Debug, Serialize, Deserialize)]
 pub struct Predicate<'s> {
     pub pattern: Pattern<'s>,
     pub guard: Expression<'s>,
+    pub offset: Option<usize>,
     pub limit: Option<usize>,
+    #[serde(skip)]
+    pub(crate) plans: Plans,
 }
 
 impl<'s> Predicate<'s> {
@@ -26,59 +214,204 @@ impl<'s> Predicate<'s> {
         Self {
             pattern: Pattern::Discard,
             guard: Expression::Literal(Literal::Boolean(true)),
+            offset: None,
             limit: None,
+            plans: Plans::default(),
         }
     }
 
+    pub(crate) fn plan(&self) -> &PatternPlan {
+        &self.plans.of(std::slice::from_ref(&self.pattern))[0]
+    }
+
     fn is_any(&self) -> bool {
         matches!(self.pattern, Pattern::Discard)
             && matches!(self.guard, Expression::Literal(Literal::Boolean(true)))
     }
+
+    pub(crate) fn specialize(&self, env: &Environment<'_, 's, '_>) -> Self {
+        let shadowed = self.pattern.get_identifiers().collect();
+
+        Self {
+            pattern: self.pattern.clone(),
+            guard: env.specialize(&self.guard, &shadowed),
+            offset: self.offset,
+            limit: self.limit,
+            // The pattern stays the same.
+            plans: self.plans.clone(),
+        }
+    }
 }
 
 impl<'s> std::fmt::Display for Predicate<'s> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut clauses = vec![];
         if !self.is_any() {
-            write!(f, "{}", self.pattern)?;
-            write!(f, " where {}", self.guard)?;
+            clauses.push(format!("{} where {}", self.pattern, self.guard));
+        }
+        if let Some(l) = self.limit {
+            clauses.push(format!("limit {l}"));
+        }
+        if let Some(o) = self.offset {
+            clauses.push(format!("offset {o}"));
+        }
 
-            if let Some(l) = self.limit {
-                write!(f, " limit {l}")?;
-            }
-        } else if let Some(l) = self.limit {
-            write!(f, "limit {l}")?;
-        } else {
+        if clauses.is_empty() {
             write!(f, "none")?;
+        } else {
+            write!(f, "{}", clauses.join(" "))?;
         }
 
         Ok(())
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CrossPredicate<'s> {
     pub patterns: Vec<Pattern<'s>>,
     pub guard: Expression<'s>,
+    pub offset: Option<usize>,
     pub limit: Option<usize>,
+    #[serde(skip)]
+    pub(crate) plans: Plans,
+}
+
+impl<'s> CrossPredicate<'s> {
+    pub(crate) fn plans(&self) -> &[PatternPlan] {
+        self.plans.of(&self.patterns)
+    }
+}
+
+impl<'s> ProjectionQuery<'s> {
+    pub(crate) fn specialize(&self, env: &Environment<'_, 's, '_>) -> Self {
+        let shadowed = self
+            .predicate
+            .patterns
+            .iter()
+            .flat_map(|p| p.get_identifiers())
+            .collect();
+
+        Self {
+            outer: self.outer,
+            predicate: CrossPredicate {
+                patterns: self.predicate.patterns.clone(),
+                guard: env.specialize(&self.predicate.guard, &shadowed),
+                offset: self.predicate.offset,
+                limit: self.predicate.limit,
+                plans: self.predicate.plans.clone(),
+            },
+            projection: env.specialize(&self.projection, &shadowed),
+            group: self.group.as_ref().map(|g| env.specialize(g, &shadowed)),
+            aggregates: self
+                .aggregates
+                .iter()
+                .map(|a| Aggregate {
+                    binding: a.binding.clone(),
+                    function: a.function,
+                    argument: a.argument.as_ref().map(|e| env.specialize(e, &shadowed)),
+                })
+                .collect(),
+            order: self.order.as_ref().map(|o| OrderBy {
+                key: env.specialize(&o.key, &shadowed),
+                descending: o.descending,
+                collation: o.collation.clone(),
+            }),
+            hints: self.hints.clone(),
+        }
+    }
+
+    pub(crate) fn is_aggregation(&self) -> bool {
+        self.group.is_some() || !self.aggregates.is_empty()
+    }
+
+    pub(crate) fn allows_index(&self) -> bool {
+        !self.hints.contains(&QueryHint::NoIndex)
+    }
+
+    pub(crate) fn unknown_hints(&self) -> impl Iterator<Item = &str> {
+        self.hints.iter().filter_map(|hint| match hint {
+            QueryHint::Unknown(hint) => Some(hint.as_str()),
+            _ => None,
+        })
+    }
+}
+
+impl<'s> std::fmt::Display for ProjectionQuery<'s> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "patterns:")?;
+        for p in &self.predicate.patterns {
+            writeln!(f, "  {p}")?;
+        }
+        writeln!(f, "guard: {}", self.predicate.guard)?;
+        writeln!(f, "projection: {}", self.projection)?;
+        for a in &self.aggregates {
+            writeln!(f, "  {}: {a}", a.binding)?;
+        }
+        if let Some(g) = &self.group {
+            writeln!(f, "group by: {g}")?;
+        }
+        if let Some(o) = &self.order {
+            write!(f, "order by: {}", o.key)?;
+            if o.collation != Collation::Binary {
+                write!(f, " collate {}", o.collation)?;
+            }
+            writeln!(f, "{}", if o.descending { " desc" } else { "" })?;
+        }
+        if let Some(l) = self.predicate.limit {
+            writeln!(f, "limit: {l}")?;
+        }
+        if let Some(o) = self.predicate.offset {
+            writeln!(f, "offset: {o}")?;
+        }
+        if self.outer {
+            writeln!(f, "duplicates: joined")?;
+        }
+        for h in &self.hints {
+            writeln!(f, "hint: {h}")?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DeletionQuery<'s> {
     pub predicate: Predicate<'s>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct UpdateQuery<'s> {
     pub predicate: Predicate<'s>,
     pub projection: Expression<'s>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TransferQuery<'s> {
     pub predicate: Predicate<'s>,
     pub projection: Expression<'s>,
 }
 
+impl<'s> UpdateQuery<'s> {
+    pub(crate) fn specialize(&self, env: &Environment<'_, 's, '_>) -> Self {
+        let shadowed = self.predicate.pattern.get_identifiers().collect();
+
+        Self {
+            predicate: self.predicate.specialize(env),
+            projection: env.specialize(&self.projection, &shadowed),
+        }
+    }
+}
+
+impl<'s> TransferQuery<'s> {
+    pub(crate) fn specialize(&self, env: &Environment<'_, 's, '_>) -> Self {
+        let shadowed = self.predicate.pattern.get_identifiers().collect();
+
+        Self {
+            predicate: self.predicate.specialize(env),
+            projection: env.specialize(&self.projection, &shadowed),
+        }
+    }
+}
+
 pub(crate) fn check_value<'s, 'v>(
     env: &Environment<'_, 's, 'v>,
     pred: &Predicate<'s>,
@@ -99,14 +432,34 @@ pub(crate) fn check_value<'s, 'v>(
 
     let local_env = matcher.into_env();
 
-    let Ok(Value::Boolean(true)) = local_env.eval_expr(&pred.guard) else {
-        return false;
-    };
-
-    true
+    profile::accepts(&local_env, &pred.guard)
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Insertion<'s> {
     pub(crate) expressions: ExpressionSet<'s>,
 }
+
+pub struct QueryEngine<'x, 'b, 'i, 's, 'v> {
+    bundle: &'x BagBundle<'b, 'i, 's, 'v>,
+    env: Environment<'i, 's, 'v>,
+}
+
+impl<'x, 'b, 'i, 's, 'v> QueryEngine<'x, 'b, 'i, 's, 'v> {
+    pub fn new(bundle: &'x BagBundle<'b, 'i, 's, 'v>, env: Environment<'i, 's, 'v>) -> Self {
+        Self { bundle, env }
+    }
+
+    // Returns None if the bag does not exist. The values are computed lazily
+    // while the iterator is advanced.
+    pub fn query<'e>(
+        &'e self,
+        bag: &Identifier<'s>,
+        query: &'e ProjectionQuery<'s>,
+    ) -> Option<impl Iterator<Item = Result<Value<'s, 'v>, EvalError>> + 'e>
+    where
+        'x: 'e,
+    {
+        self.bundle.bag(bag).map(|b| b.query(&self.env, query))
+    }
+}
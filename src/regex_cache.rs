@@ -0,0 +1,27 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use regex::Regex;
+
+const CAPACITY: usize = 64;
+
+thread_local! {
+    static COMPILED: RefCell<HashMap<String, Regex>> = RefCell::new(HashMap::new());
+}
+
+pub(crate) fn compile(source: &str) -> Option<Regex> {
+    COMPILED.with(|compiled| {
+        let mut compiled = compiled.borrow_mut();
+        if let Some(regex) = compiled.get(source) {
+            return Some(regex.clone());
+        }
+
+        let regex = Regex::new(source).ok()?;
+        if compiled.len() >= CAPACITY {
+            compiled.clear();
+        }
+        compiled.insert(source.to_string(), regex.clone());
+
+        Some(regex)
+    })
+}
@@ -1,30 +1,126 @@
 use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
-use std::io::{self, BufRead, LineWriter};
+use std::io::{self, BufRead};
 use std::ops::Sub;
+use std::sync::Arc;
 
-use crate::bag::{DeletionResult, InsertionResult, TransferResult, UpdateResult};
+use crate::bag::{DeletionResult, InsertionResult, TransferResult, UpdateResult, ValueBag};
 use crate::bag_bundle::BagBundle;
-use crate::bag_bundle::Transaction;
-use crate::env::Environment;
+use crate::bag_bundle::{Journaled, Transaction, TransactionError};
+use crate::builtins::{self, Builtins};
+use crate::capabilities::Capabilities;
+use crate::cdc::{read_record, CdcFile, ChangeRecord};
+use crate::env::{self, Environment, EvalError};
+use crate::breakpoint::{Breakpoint, Hit, Origin, Pause};
+use crate::debugger::{Candidate, StepAction, Stepping};
+use crate::documents;
+use crate::error_catalog::{self, ErrorCode};
+use crate::help;
 use crate::expression::*;
+use crate::extension::CustomStatement;
+use crate::fingerprint;
+use crate::formatter;
 use crate::graph::Graph;
-use crate::graph_solver::GraphSolver;
+use crate::graph_solver::{self, ChangeSet, GraphSolver};
 use crate::identifier::Identifier;
 use crate::matcher::Matcher;
-use crate::parser::{full_expression, pattern, bundle_line, BundleCommand};
-use crate::statement::Statement;
+use crate::metrics::{self, Metrics, Stopwatch};
+use crate::delta;
+use crate::diff;
+use crate::observer::{self, Mutation};
+use crate::parser::{
+    bundle_line, checked_statement, explicit_statement, full_expression, is_blank, is_parameter,
+    strip_comments, BundleCommand, StatementError,
+};
+use crate::plan_cache::PlanCache;
+use crate::profile;
+use crate::settings::Settings;
+use crate::snapshot::{self, Snapshot, SnapshotError};
+use crate::jail;
+use crate::json;
+use crate::limits::statement_parts;
+use crate::log;
+use crate::manifest::{self, ManifestError};
+use crate::merge::MergeResult;
+use crate::statement::{DataFormat, ErrorPolicy, Macro, PreparedStatement, Statement};
+use crate::storage::Storage;
+use crate::system_bag;
+use crate::trash;
 use crate::value::Value;
+use crate::virtual_bag::VirtualSource;
 
-use crate::assignment::Assignment;
-use crate::query::Predicate;
+use crate::assignment::{Assignment, AssignmentSet};
+use crate::pattern::{ObjectPropertyPattern, Pattern, PropertyPattern, Rest};
+use crate::query::{DeletionQuery, Predicate, TransferQuery};
+
+// Rounds of triggered connections after which an insertion is aborted.
+const MAX_TRIGGER_ROUNDS: usize = 64;
+
+// Malformed lines listed by their number after an import.
+const MAX_REPORTED_LINES: usize = 10;
+
+// Changed values shown by `.preview`.
+const PREVIEW_SAMPLES: usize = 5;
+
+// Distinct statements kept parsed by `Repl::parse`.
+const PLAN_CACHE_CAPACITY: usize = 256;
+const HISTORY_CAPACITY: usize = 1000;
 
 pub struct Repl<'b, 'i, 's, 'v> {
     pub env: Environment<'i, 's, 'v>,
+    // The bindings kept via `.env save`, restored via `.env load`.
+    pub environments: BTreeMap<Identifier<'s>, Environment<'i, 's, 'v>>,
     pub current_bag: Identifier<'s>,
     pub bag_bundle: BagBundle<'b, 'i, 's, 'v>,
     pub bag_graph: Graph<'s>,
+    pub settings: Settings,
+    pub macros: BTreeMap<Identifier<'s>, Macro<'s>>,
+    pub prepared: BTreeMap<Identifier<'s>, PreparedStatement<'s>>,
+    // The time to live in seconds of the values in the trash of a bag. It is
+    // not persisted, a trash restored from storage keeps its values forever.
+    pub trash_ttl: BTreeMap<Identifier<'s>, u64>,
+    pub plan_cache: PlanCache<'s>,
+    // The state as of `.begin`, restored by `.rollback`.
+    pub transaction: Option<Savepoint<'b, 'i, 's, 'v>>,
+    // The inputs parsed so far, the most recent last, see `$history`.
+    pub history: Vec<String>,
+    // The statements the frontend allows, all by default.
+    pub capabilities: Capabilities,
+    // The functions statements can call, all builtins by default.
+    pub builtins: Builtins,
+    // The solutions of the connection being stepped through.
+    pub(crate) stepping: Option<Stepping<'s, 'v>>,
+    pub breakpoints: Vec<Breakpoint<'s>>,
+    // Breakpoints only pause the statements of frontends with a prompt to
+    // inspect the bags, not eg scripts run via `--eval`.
+    pub interactive: bool,
+    pub(crate) paused: Option<Pause<'s, 'v>>,
+    extensions: Vec<Arc<dyn CustomStatement<'b, 'i, 's, 'v>>>,
+    // The bags of the other sessions while running the statements of a
+    // jailed one, which may not leave its current bag, see `Jails::run`.
+    pub(crate) confinement: Option<BTreeSet<Identifier<'s>>>,
+    // Whether the lines of a `.run` script are being executed.
+    running_script: bool,
+    // Whether statements changing more values than the `confirm_threshold`
+    // have been confirmed, see `confirming`.
+    confirmed: bool,
+    // How the statements after the last `.on error` handle errors.
+    error_policy: ErrorPolicy,
+}
+
+// The state of a repl statements can change. The macros, connections and
+// settings are shared by the sessions of a server, so going back to a
+// savepoint also undoes the changes the others made to them meanwhile.
+#[derive(Clone)]
+pub struct Savepoint<'b, 'i, 's, 'v> {
+    pub(crate) bag_bundle: BagBundle<'b, 'i, 's, 'v>,
+    pub(crate) current_bag: Identifier<'s>,
+    env: Environment<'i, 's, 'v>,
+    macros: BTreeMap<Identifier<'s>, Macro<'s>>,
+    prepared: BTreeMap<Identifier<'s>, PreparedStatement<'s>>,
+    bag_graph: Graph<'s>,
+    settings: Settings,
 }
 
 impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
@@ -32,9 +128,52 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
         self.bag_bundle.bag_names()
     }
 
+    // The bags as array of `{name, count, current}`, eg for listing them in a
+    // user interface. The count is null for unbounded bags.
+    pub fn bags_to_json(&self) -> serde_json::Value {
+        self.bag_bundle
+            .bags
+            .keys()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|name| {
+                serde_json::json!({
+                    "name": name.name,
+                    "count": self.bag_bundle.bag(name).and_then(|b| b.size()),
+                    "current": name == &self.current_bag,
+                })
+            })
+            .collect()
+    }
+
+    // The variables as object from their names to their values.
+    pub fn bindings_to_json(&self) -> serde_json::Value {
+        self.env
+            .bindings
+            .iter()
+            .map(|(k, v)| (k.name.to_string(), json::to_json_lossy(v)))
+            .collect()
+    }
+
     pub fn vars(&self) -> BTreeSet<Identifier<'i>> {
         self.env.bindings.keys().cloned().collect()
     }
+
+    // Whether a breakpoint paused the statements, until `.continue` or
+    // `.abort`.
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    pub fn write_bundle<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        for name in self.bag_bundle.bag_names() {
+            if let Some(bag) = self.bag_bundle.bags.get(&name) {
+                bag.write(&name, out)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -49,6 +188,142 @@ pub enum ReplOutput<'x, 's, 'v> {
     Updated(usize),
     Transferd(usize),
     Notice(String),
+    // The values deleted from and inserted into each bag by `.solve`.
+    Solved(Vec<(Identifier<'s>, usize, usize)>),
+    // The number of values a `.change` would match, some of the values it
+    // would change before and after the change, and the number of changed
+    // values not shown.
+    Preview(usize, Vec<(Value<'s, 'v>, Value<'s, 'v>)>, usize),
+    Measured(Box<ReplOutput<'x, 's, 'v>>, Metrics),
+    // Eg the unknown identifiers evaluated to `null` by the `lenient`
+    // setting, or the failed assertions of guards.
+    Warned(Box<ReplOutput<'x, 's, 'v>>, Vec<String>),
+    // A solution shown by `.solve <connection> <argument> step`.
+    Candidate(Box<Candidate<'s, 'v>>),
+    // The output of the statement ending the stepping through a connection,
+    // followed by those of the statements queued meanwhile.
+    Resumed(Box<ReplOutput<'x, 's, 'v>>, Vec<ReplOutput<'x, 's, 'v>>),
+    // The output of a statement that hit a breakpoint.
+    Paused(Box<ReplOutput<'x, 's, 'v>>, Box<Hit<'s, 'v>>),
+}
+
+impl<'x, 's, 'v> ReplOutput<'x, 's, 'v> {
+    pub fn to_json(&self) -> serde_json::Value {
+        let count = |kind, count| serde_json::json!({"kind": kind, "count": count});
+
+        match self {
+            ReplOutput::Ack => serde_json::json!({"kind": "ack"}),
+            ReplOutput::No | ReplOutput::PatternMissmatch => serde_json::json!({"kind": "no"}),
+            ReplOutput::Values(values) => serde_json::json!({
+                "kind": "values",
+                "values": values.iter().map(json::to_json_lossy).collect::<Vec<_>>(),
+            }),
+            ReplOutput::Bindings(bindings) => serde_json::json!({
+                "kind": "bindings",
+                "bindings": bindings
+                    .iter()
+                    .map(|(k, v)| (k.name.to_string(), json::to_json_lossy(v)))
+                    .collect::<serde_json::Map<_, _>>(),
+            }),
+            ReplOutput::Deleted(c) => count("deleted", c),
+            ReplOutput::Inserted(c) => count("inserted", c),
+            ReplOutput::Updated(c) => count("updated", c),
+            ReplOutput::Transferd(c) => count("moved", c),
+            ReplOutput::Notice(n) => serde_json::json!({"kind": "notice", "message": n}),
+            ReplOutput::Solved(bags) => serde_json::json!({
+                "kind": "solved",
+                "bags": bags
+                    .iter()
+                    .map(|(bag, deleted, inserted)| {
+                        let counts = serde_json::json!({"deleted": deleted, "inserted": inserted});
+                        (bag.name.to_string(), counts)
+                    })
+                    .collect::<serde_json::Map<_, _>>(),
+            }),
+            ReplOutput::Preview(count, samples, omitted) => serde_json::json!({
+                "kind": "preview",
+                "count": count,
+                "omitted": omitted,
+                "samples": samples
+                    .iter()
+                    .map(|(before, after)| serde_json::json!({
+                        "before": json::to_json_lossy(before),
+                        "after": json::to_json_lossy(after),
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            ReplOutput::Measured(output, metrics) => {
+                let mut json = output.to_json();
+                json["metrics"] = metrics.to_json();
+                json
+            }
+            ReplOutput::Warned(output, warnings) => {
+                let mut json = output.to_json();
+                json["warnings"] = warnings.iter().cloned().collect();
+                json
+            }
+            ReplOutput::Candidate(candidate) => serde_json::json!({
+                "kind": "candidate",
+                "connection": candidate.connection.name,
+                "number": candidate.number,
+                "count": candidate.count,
+                "bindings": candidate
+                    .bindings
+                    .iter()
+                    .map(|(id, value, origin)| serde_json::json!({
+                        "name": id.name,
+                        "value": json::to_json_lossy(value),
+                        "origin": origin,
+                    }))
+                    .collect::<Vec<_>>(),
+                "changes": candidate
+                    .changes
+                    .iter()
+                    .map(|(component, value)| serde_json::json!({
+                        "component": component,
+                        "value": json::to_json_lossy(value),
+                    }))
+                    .collect::<Vec<_>>(),
+                "conflicting": candidate.conflicting,
+            }),
+            ReplOutput::Resumed(output, queued) => {
+                let mut json = output.to_json();
+                json["queued"] = queued.iter().map(ReplOutput::to_json).collect();
+                json
+            }
+            ReplOutput::Paused(output, hit) => {
+                let mut json = output.to_json();
+                json["break"] = serde_json::json!({
+                    "bag": hit.bag.name,
+                    "value": json::to_json_lossy(&hit.value),
+                    "origin": match &hit.origin {
+                        Origin::Statement(statement) => serde_json::json!({"statement": statement}),
+                        Origin::Connection(connection) => serde_json::json!({"connection": connection}),
+                    },
+                    "queued": hit.queued,
+                });
+                json
+            }
+        }
+    }
+
+    // The number of values inserted, changed, moved or deleted.
+    pub fn affected(&self) -> usize {
+        match self {
+            ReplOutput::Deleted(c)
+            | ReplOutput::Inserted(c)
+            | ReplOutput::Updated(c)
+            | ReplOutput::Transferd(c) => *c,
+            ReplOutput::Solved(bags) => bags.iter().map(|(_, d, i)| d + i).sum(),
+            ReplOutput::Measured(output, _)
+            | ReplOutput::Warned(output, _)
+            | ReplOutput::Paused(output, _) => output.affected(),
+            ReplOutput::Resumed(output, queued) => {
+                output.affected() + queued.iter().map(ReplOutput::affected).sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
 }
 
 impl<'x, 's, 'v> std::fmt::Display for ReplOutput<'x, 's, 'v> {
@@ -75,6 +350,45 @@ impl<'x, 's, 'v> std::fmt::Display for ReplOutput<'x, 's, 'v> {
             ReplOutput::Inserted(count) => writeln!(f, "INSERTED {count} items."),
             ReplOutput::Notice(n) => writeln!(f, "{n}"),
             ReplOutput::PatternMissmatch => writeln!(f, "NO."),
+            ReplOutput::Solved(bags) => {
+                writeln!(f, "SOLVED.")?;
+                for (bag, deleted, inserted) in bags {
+                    writeln!(f, "{bag}: {deleted} deleted, {inserted} inserted")?;
+                }
+                Ok(())
+            }
+            ReplOutput::Preview(count, samples, omitted) => {
+                writeln!(f, "WOULD CHANGE {count} items.")?;
+                for (i, (before, after)) in samples.iter().enumerate() {
+                    writeln!(f, "@@ item {} @@", i + 1)?;
+                    for change in diff::diff(before, after) {
+                        for line in change.lines() {
+                            writeln!(f, "{line}")?;
+                        }
+                    }
+                }
+                if *omitted > 0 {
+                    writeln!(f, "... and {omitted} more.")?;
+                }
+                Ok(())
+            }
+            ReplOutput::Measured(output, metrics) => writeln!(f, "{output}-- {metrics}"),
+            ReplOutput::Warned(output, warnings) => {
+                write!(f, "{output}")?;
+                for warning in warnings {
+                    writeln!(f, "-- warning: {warning}")?;
+                }
+                Ok(())
+            }
+            ReplOutput::Candidate(candidate) => write!(f, "{candidate}"),
+            ReplOutput::Resumed(output, queued) => {
+                write!(f, "{output}")?;
+                for output in queued {
+                    write!(f, "{output}")?;
+                }
+                Ok(())
+            }
+            ReplOutput::Paused(output, hit) => write!(f, "{output}{hit}"),
         }
     }
 }
@@ -83,7 +397,7 @@ impl<'x, 's, 'v> std::fmt::Display for ReplOutput<'x, 's, 'v> {
 pub enum ReplError {
     ReadError,
     ParseError,
-    EvalError,
+    EvalError(EvalError),
     AssignmentError,
     IoError,
     Exit,
@@ -92,34 +406,801 @@ pub enum ReplError {
     TransferError,
     GuardError,
     ConnectionError,
+    SettingError,
+    MacroError,
+    PreparedError,
+    VerificationError,
+    TransactionState,
+    TransactionConflict,
+    // The messages of the failed `assert` calls.
+    AssertionError(String),
+    // The number of the line of a `.run` script that failed and its error.
+    ScriptFailed(usize, String),
+    // The statement needs a capability the frontend does not allow.
+    Denied,
+    // A new session would exceed the `max_sessions` of the jails.
+    TooManySessions,
+    // The number of values the statement would remove or change, more than
+    // the `confirm_threshold` allows without a confirmation.
+    Unconfirmed(usize),
+    // A `.continue`, `.skip` or `.abort` without stepping through a
+    // connection or being paused by a breakpoint, or a `.solve ... step`
+    // while already stepping.
+    SteppingState,
+}
+
+type BatchResult<'i, 's, 'v> = (Vec<ReplOutput<'i, 's, 'v>>, Vec<(usize, ReplError)>);
+
+// The outputs of the statements of a script that succeeded and the errors of
+// those skipped because of their `ErrorPolicy`, along with their line.
+#[derive(Debug)]
+pub struct ScriptReport<'i, 's, 'v> {
+    pub outputs: Vec<ReplOutput<'i, 's, 'v>>,
+    pub failed: Vec<(usize, ReplError)>,
+}
+
+impl std::fmt::Display for ScriptReport<'_, '_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} statements succeeded, {} failed.",
+            self.outputs.len(),
+            self.failed.len()
+        )?;
+        for (line, e) in &self.failed {
+            writeln!(f, "line {line}: Error[{}]: {e:?}", e.code())?;
+        }
+
+        Ok(())
+    }
+}
+
+// The name of the error in the catalog, followed by its details.
+impl std::fmt::Display for ReplError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = error_catalog::lookup(self.code()).map_or("Error", |e| e.name);
+        match self {
+            ReplError::AssertionError(message) => write!(f, "{name}: {message}"),
+            ReplError::ScriptFailed(line, error) => write!(f, "{name} in line {line}: {error}"),
+            _ => write!(f, "{name}"),
+        }
+    }
+}
+
+// A conflict is reported on its own, the statement can simply be run again.
+fn commit_error(e: TransactionError) -> ReplError {
+    match e {
+        TransactionError::Conflict => ReplError::TransactionConflict,
+        _ => ReplError::TranscationAborted,
+    }
+}
+
+// An `assert` failing in an expression the statement evaluates itself fails
+// the statement with its message. Assertions failing in guards only reject
+// values, they are reported as warnings.
+fn eval_error(e: EvalError) -> ReplError {
+    match e {
+        EvalError::AssertionFailed(message) => ReplError::AssertionError(message),
+        e => ReplError::EvalError(e),
+    }
+}
+
+// Returns the number of values the file must contain.
+fn verify_manifest(filename: &str) -> Result<usize, ReplError> {
+    match manifest::verify(filename) {
+        Ok(manifest) => Ok(manifest.count),
+        Err(ManifestError::Io) => Err(ReplError::IoError),
+        Err(_) => Err(ReplError::VerificationError),
+    }
 }
 
 impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
+    // A repl keeping its bags in memory only, frontends storing them in
+    // `DAMASC_DATA_DIR` use `open` to report when they can not be loaded.
     pub fn new(initial_bag: &'s str) -> Self {
-        let env = Environment {
-            bindings: BTreeMap::new(),
-        };
+        Self::open(initial_bag, None).expect("an in-memory repl can always be created")
+    }
 
+    pub fn open(initial_bag: &'s str, storage: Option<Storage>) -> Result<Self, ReplError> {
         let current_bag = Identifier {
             name: Cow::Borrowed(initial_bag),
         };
-        let mut bag_bundle = BagBundle::new();
 
-        let mut trans = Transaction::new(&bag_bundle);
-        let _ = trans.create_bag(current_bag.clone(), Predicate::any());
-        if let Ok(r) = trans.commit() {
-            bag_bundle = r;
+        let mut repl = Self {
+            env: Environment::new(),
+            environments: BTreeMap::new(),
+            current_bag: current_bag.clone(),
+            bag_bundle: BagBundle::new(),
+            bag_graph: Graph::new(),
+            settings: Settings::default(),
+            macros: BTreeMap::new(),
+            prepared: BTreeMap::new(),
+            trash_ttl: BTreeMap::new(),
+            plan_cache: PlanCache::new(PLAN_CACHE_CAPACITY),
+            transaction: None,
+            history: vec![],
+            capabilities: Capabilities::ALL,
+            builtins: Builtins::default(),
+            stepping: None,
+            breakpoints: vec![],
+            interactive: false,
+            paused: None,
+            extensions: vec![],
+            confinement: None,
+            running_script: false,
+            confirmed: false,
+            error_policy: ErrorPolicy::Abort,
         };
 
-        Self {
-            env,
-            current_bag,
-            bag_bundle,
-            bag_graph: Graph::new(),
+        if let Some(storage) = storage {
+            let files = storage.files().map_err(|_| ReplError::IoError)?;
+            for file in files {
+                repl.execute(Statement::LoadBundle(Cow::Owned(
+                    file.to_string_lossy().to_string(),
+                )))?;
+            }
+            repl.current_bag = current_bag.clone();
+            repl.bag_bundle.storage = Some(storage);
+        }
+
+        let mut trans = Transaction::new(&repl.bag_bundle);
+        let _ = trans.create_bag(current_bag, Predicate::any());
+        repl.bag_bundle = trans.commit().map_err(commit_error)?;
+
+        Ok(repl)
+    }
+
+    // Starts journaling the deletions from the current bag, if the bag has a
+    // trash to move the deleted values to.
+    fn watch_trash(&self, trans: &mut Transaction<'b, 'i, 's, 'v>) -> Result<Option<usize>, ReplError> {
+        if !self.bag_bundle.bags.contains_key(&trash::bag_name(&self.current_bag)) {
+            return Ok(None);
+        }
+
+        trans
+            .watch(std::slice::from_ref(&self.current_bag))
+            .map(Some)
+            .map_err(|_| ReplError::TranscationAborted)
+    }
+
+    // Moves the values deleted from the current bag since `watch_trash` into
+    // its trash, as part of the same transaction.
+    fn trash_deleted(
+        &self,
+        trans: &mut Transaction<'b, 'i, 's, 'v>,
+        start: Option<usize>,
+    ) -> Result<(), ReplError> {
+        let Some(start) = start else {
+            return Ok(());
+        };
+        let journal = trans
+            .unwatch(std::slice::from_ref(&self.current_bag), start)
+            .map_err(|_| ReplError::TranscationAborted)?;
+
+        let now = trash::now();
+        let ttl = self.trash_ttl.get(&self.current_bag).copied();
+        let bin = trash::bag_name(&self.current_bag);
+        let env = Environment::new();
+        for (_, mutation, _) in journal {
+            if let Mutation::Delete(value) = mutation {
+                let entry = trash::entry(value, now, ttl).to_expression();
+                trans
+                    .insert_one(&bin, &env, &entry)
+                    .map_err(|_| ReplError::TranscationAborted)?;
+            }
+        }
+
+        self.purge_expired(trans, now)
+    }
+
+    fn purge_expired(
+        &self,
+        trans: &mut Transaction<'b, 'i, 's, 'v>,
+        now: i64,
+    ) -> Result<(), ReplError> {
+        let bin = trash::bag_name(&self.current_bag);
+        let expired = trans
+            .read(&bin)
+            .map_err(|_| ReplError::BagError)?
+            .filter(|entry| trash::is_expired(entry, now))
+            .map(|entry| entry.as_ref().clone())
+            .collect::<Vec<_>>();
+        for entry in &expired {
+            trans
+                .pop(&bin, entry)
+                .map_err(|_| ReplError::TranscationAborted)?;
+        }
+
+        Ok(())
+    }
+
+    // Runs the connections triggered by insertions into `bags` as part of the
+    // same transaction. Values produced by a connection may trigger further
+    // connections, so the number of rounds is bounded.
+    fn fire_triggers(
+        &self,
+        trans: &mut Transaction<'b, 'i, 's, 'v>,
+        env: &Environment<'i, 's, 'v>,
+        mut bags: BTreeSet<Identifier<'s>>,
+    ) -> Result<(), ReplError> {
+        let mut changes = trans
+            .changes()
+            .map_err(|_| ReplError::TranscationAborted)?
+            .clone();
+
+        for _ in 0..MAX_TRIGGER_ROUNDS {
+            let triggered = bags
+                .iter()
+                .flat_map(|b| self.bag_graph.triggered_by(b))
+                .collect::<BTreeSet<_>>();
+            let consumed = triggered
+                .iter()
+                .filter_map(|name| self.bag_graph.connections.get(*name))
+                .flat_map(|c| c.consumers.iter().map(|consumer| &consumer.source_bag))
+                .collect::<BTreeSet<_>>();
+
+            // A solution consumes at least one new value, so only the
+            // connections with a pattern the network considers for one of
+            // them can match.
+            let mut considered = BTreeSet::new();
+            for (bag, delta) in changes.iter().filter(|(b, _)| consumed.contains(b)) {
+                let values = trans.read(bag).map_err(|_| ReplError::TranscationAborted)?;
+                for (_, value) in values.enumerate().filter(|(i, _)| delta.contains(*i)) {
+                    let rules = self.bag_graph.network.candidates(bag, value);
+                    considered.extend(rules.into_iter().map(|r| &r.connection));
+                }
+            }
+            let connections = triggered
+                .into_iter()
+                .filter(|c| considered.contains(c))
+                .collect::<BTreeSet<_>>();
+            if connections.is_empty() {
+                return Ok(());
+            }
+
+            let solutions = trans
+                .with_changes(&changes, |bundle| {
+                    let solver = GraphSolver::new(env.clone(), bundle);
+                    graph_solver::compatible_by(
+                        connections
+                            .into_iter()
+                            .filter_map(|name| Some((name, self.bag_graph.connections.get(name)?)))
+                            .flat_map(|(name, connection)| {
+                                solver.solve_incremental(connection, None).map(move |c| (name, c))
+                            }),
+                    )
+                })
+                .map_err(|_| ReplError::TranscationAborted)?;
+            if solutions.is_empty() {
+                return Ok(());
+            }
+            let changesets = solutions
+                .iter()
+                .map(|(name, changeset)| (Some(*name), changeset))
+                .collect::<Vec<_>>();
+
+            let inserted = changesets
+                .iter()
+                .flat_map(|(_, c)| c.insertions.keys().cloned())
+                .collect();
+            changes = trans
+                .apply(env, &changesets)
+                .map_err(|_| ReplError::TranscationAborted)?;
+            bags = inserted;
+        }
+
+        Err(ReplError::ConnectionError)
+    }
+
+    // Inserts one value per line while reading, so files of any size can be
+    // imported. Lines that are no valid JSON are skipped and reported.
+    fn import_ndjson(
+        &mut self,
+        reader: impl BufRead,
+        filename: &str,
+        expected: Option<usize>,
+    ) -> Result<ReplOutput<'i, 's, 'v>, ReplError> {
+        let env = self.statement_env();
+        let mut trans = Transaction::new(&self.bag_bundle);
+        let mut counter = 0;
+        let mut read = 0;
+        let mut skipped = vec![];
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.map_err(|_| ReplError::ReadError)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let value = serde_json::from_str(&line)
+                .ok()
+                .and_then(|item| json::from_json(&item));
+            let Some(value) = value else {
+                skipped.push(number + 1);
+                continue;
+            };
+            read += 1;
+
+            let result = trans
+                .insert_one(&self.current_bag, &env, &value.to_expression())
+                .map_err(|_| ReplError::TranscationAborted)?;
+            match result {
+                InsertionResult::Success(c) => counter += c,
+                InsertionResult::GuardError => return Err(ReplError::GuardError),
+                InsertionResult::EvalError(e) => return Err(ReplError::EvalError(e)),
+            }
         }
+        if expected.is_some_and(|count| count != read) {
+            return Err(ReplError::VerificationError);
+        }
+
+        self.fire_triggers(&mut trans, &env, BTreeSet::from([self.current_bag.clone()]))?;
+        self.bag_bundle = trans.commit().map_err(commit_error)?;
+
+        let mut notice = format!(
+            "Imported {} values from file '{filename}' into current bag({}){}",
+            counter,
+            self.current_bag,
+            if expected.is_some() { ", verified by manifest" } else { "" }
+        );
+        if !skipped.is_empty() {
+            let lines = skipped
+                .iter()
+                .take(MAX_REPORTED_LINES)
+                .map(|l| l.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let more = if skipped.len() > MAX_REPORTED_LINES { ", ..." } else { "" };
+            notice.push_str(&format!(
+                "\nSkipped {} malformed lines: {lines}{more}",
+                skipped.len()
+            ));
+        }
+
+        Ok(ReplOutput::Notice(notice))
     }
 
+    // With the `lenient` setting identifiers neither known nor bound by the
+    // assignments do not keep them from being ordered, they evaluate to
+    // `null`.
+    fn lenient_identifiers<'x>(&self, assignments: &AssignmentSet) -> Vec<Identifier<'x>> {
+        if self.settings.lenient {
+            assignments.free_identifiers()
+        } else {
+            vec![]
+        }
+    }
+
+    fn statement_env(&self) -> Environment<'i, 's, 'v> {
+        if self.settings.memoize {
+            self.env.with_memo()
+        } else {
+            self.env.clone()
+        }
+    }
+
+    // Parses a statement of user input with the settings' limits, reusing the
+    // statement parsed from the same input before.
+    pub fn parse(&mut self, input: &str) -> Result<Statement<'s, 's>, StatementError> {
+        if self.history.len() == HISTORY_CAPACITY {
+            self.history.remove(0);
+        }
+        self.history.push(input.to_string());
+
+        self.parse_line(input)
+    }
+
+    // Parses a line not typed by the user, eg of a `.run` script, which is
+    // not kept in the history.
+    fn parse_line(&mut self, input: &str) -> Result<Statement<'s, 's>, StatementError> {
+        if let Some(statement) = self.parse_custom(input)? {
+            return Ok(statement);
+        }
+        if let Some(statement) = self.plan_cache.get(input) {
+            return Ok(statement);
+        }
+
+        let parse = if self.settings.bare_bindings {
+            checked_statement
+        } else {
+            explicit_statement
+        };
+        let statement = parse(input, &self.settings.limits)?;
+        self.plan_cache.insert(input, statement.clone());
+
+        Ok(statement)
+    }
+
+    // Adds a statement provided by the application, returns false if its
+    // name is already used by a builtin command or another custom statement.
+    pub fn register(&mut self, statement: impl CustomStatement<'b, 'i, 's, 'v> + 'static) -> bool {
+        let name = statement.name();
+        if help::lookup(name).is_some() || self.custom(name).is_some() {
+            return false;
+        }
+        self.extensions.push(Arc::new(statement));
+
+        true
+    }
+
+    fn custom(&self, name: &str) -> Option<Arc<dyn CustomStatement<'b, 'i, 's, 'v>>> {
+        self.extensions.iter().find(|c| c.name() == name).cloned()
+    }
+
+    fn parse_custom(&self, input: &str) -> Result<Option<Statement<'s, 's>>, StatementError> {
+        let Some(rest) = input.trim().strip_prefix('.') else {
+            return Ok(None);
+        };
+        let (name, arguments) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let Some(custom) = self.custom(name) else {
+            return Ok(None);
+        };
+        let arguments = arguments.trim();
+        custom
+            .parse(arguments)
+            .map_err(|message| StatementError::InvalidArguments {
+                name: name.to_string(),
+                message,
+            })?;
+
+        Ok(Some(Statement::Custom(
+            Cow::Owned(name.to_string()),
+            Cow::Owned(arguments.to_string()),
+        )))
+    }
+
+    // Whether the capabilities allow the statement.
+    pub fn permits(&self, stmt: &Statement) -> bool {
+        let declared = match stmt {
+            Statement::Custom(name, _) => self.custom(name).map_or(Capabilities::NONE, |c| c.capabilities()),
+            _ => Capabilities::NONE,
+        };
+        self.capabilities
+            .contains(Capabilities::required(stmt, &self.bags()) | declared)
+            && self
+                .confinement
+                .as_ref()
+                .map_or(true, |foreign| jail::permits(stmt, &self.current_bag, foreign))
+    }
+
+    // Runs `f` with the statements above the `confirm_threshold` confirmed if
+    // `confirmed` holds, eg once the user agreed to the count reported by
+    // `ReplError::Unconfirmed`.
+    pub fn confirming<R>(&mut self, confirmed: bool, f: impl FnOnce(&mut Self) -> R) -> R {
+        let outer = std::mem::replace(&mut self.confirmed, confirmed);
+        let result = f(self);
+        self.confirmed = outer;
+
+        result
+    }
+
+    // A `.delete`, `.change` or `.drop` removing or changing more values than
+    // the `confirm_threshold` is only committed once confirmed. The values are
+    // counted by the transaction of the statement itself, so the statements
+    // of macros, scripts and batches are checked as well.
+    fn check_confirmed(&self, count: usize) -> Result<(), ReplError> {
+        match self.settings.confirm_threshold {
+            Some(threshold) if count > threshold && !self.confirmed => Err(ReplError::Unconfirmed(count)),
+            _ => Ok(()),
+        }
+    }
+
+    // With the `verbose` setting the output carries the metrics of the
+    // statement.
     pub fn execute(&mut self, stmt: Statement<'s, 's>) -> Result<ReplOutput<'i, 's, 'v>, ReplError> {
+        let (output, metrics) = self.execute_measured(stmt)?;
+
+        Ok(if self.settings.verbose {
+            ReplOutput::Measured(Box::new(output), metrics)
+        } else {
+            output
+        })
+    }
+
+    // Runs the statements one after another in a single transaction and
+    // returns their outputs. If one of them fails, the changes of those
+    // before it are rolled back and its index is returned along with its
+    // error, the number of statements if the transaction could not be
+    // committed. Inside of a transaction begun via `.begin` the statements
+    // just become part of it.
+    pub fn execute_many(
+        &mut self,
+        statements: Vec<Statement<'s, 's>>,
+    ) -> Result<Vec<ReplOutput<'i, 's, 'v>>, (usize, ReplError)> {
+        self.execute_batch(statements).map(|(outputs, _)| outputs)
+    }
+
+    // Like `execute_many`, but the statements after an `.on error continue`
+    // or `.on error rollback_statement` are skipped if they fail, their
+    // errors are returned along with their index instead. The policy set by
+    // the statements ends with them.
+    fn execute_batch(
+        &mut self,
+        statements: Vec<Statement<'s, 's>>,
+    ) -> Result<BatchResult<'i, 's, 'v>, (usize, ReplError)> {
+        let outer = std::mem::take(&mut self.error_policy);
+        let result = self.run_batch(statements);
+        self.error_policy = outer;
+
+        result
+    }
+
+    fn run_batch(
+        &mut self,
+        statements: Vec<Statement<'s, 's>>,
+    ) -> Result<BatchResult<'i, 's, 'v>, (usize, ReplError)> {
+        let own = self.transaction.is_none();
+        if own {
+            self.execute_statement(Statement::Begin).map_err(|e| (0, e))?;
+        }
+
+        let count = statements.len();
+        let mut outputs = Vec::with_capacity(count);
+        let mut failed = vec![];
+        for (index, stmt) in statements.into_iter().enumerate() {
+            let policy = self.error_policy;
+            // Only the statements doing something count as succeeded.
+            let sets_policy = matches!(stmt, Statement::OnError(_));
+            let result = match stmt {
+                Statement::Begin | Statement::Commit | Statement::Rollback => {
+                    Err(ReplError::TransactionState)
+                }
+                stmt if policy == ErrorPolicy::RollbackStatement => self.execute_undoable(stmt),
+                stmt => self.execute(stmt),
+            };
+            match result {
+                Ok(_) if sets_policy => {}
+                Ok(output) => outputs.push(output),
+                Err(e) if policy == ErrorPolicy::Abort || matches!(e, ReplError::Exit) => {
+                    if own {
+                        self.execute_statement(Statement::Rollback).map_err(|e| (index, e))?;
+                    }
+                    return Err((index, e));
+                }
+                Err(e) => failed.push((index, e)),
+            }
+        }
+
+        if own {
+            if let Err(e) = self.execute_statement(Statement::Commit) {
+                self.execute_statement(Statement::Rollback).map_err(|e| (count, e))?;
+                return Err((count, e));
+            }
+        }
+
+        Ok((outputs, failed))
+    }
+
+    // Executes the statement, its effects are undone if it fails. Outside of
+    // a transaction it runs in its own one, inside only the statements that
+    // can change the state need a savepoint to go back to.
+    fn execute_undoable(&mut self, stmt: Statement<'s, 's>) -> Result<ReplOutput<'i, 's, 'v>, ReplError> {
+        if self.transaction.is_none() {
+            return self
+                .execute_many(vec![stmt])
+                .map(|mut outputs| outputs.remove(0))
+                .map_err(|(_, e)| e);
+        }
+
+        let savepoint = stmt.is_mutating().then(|| self.savepoint());
+        let result = self.execute(stmt);
+        if let (Err(_), Some(savepoint)) = (&result, savepoint) {
+            self.restore(savepoint)?;
+        }
+
+        result
+    }
+
+    // Executes a statement entered at the prompt or piped into the repl,
+    // after an `.on error rollback_statement` the effects of a failing one
+    // are undone, eg of the lines of a `.run` before the failing one.
+    pub fn execute_next(&mut self, stmt: Statement<'s, 's>) -> Result<ReplOutput<'i, 's, 'v>, ReplError> {
+        match stmt {
+            Statement::Begin | Statement::Commit | Statement::Rollback => self.execute(stmt),
+            stmt if self.error_policy == ErrorPolicy::RollbackStatement && stmt.is_mutating() => {
+                self.execute_undoable(stmt)
+            }
+            stmt => self.execute(stmt),
+        }
+    }
+
+    // How the statements after the last `.on error` handle errors.
+    pub fn error_policy(&self) -> ErrorPolicy {
+        self.error_policy
+    }
+
+    // Parses the non-empty lines of the script and runs them via
+    // `execute_many`, nothing is executed if one of them can not be parsed.
+    // Errors are reported as `ScriptFailed` with the number of the line.
+    // A line `.on error continue|abort|rollback_statement` sets how the
+    // statements below it handle errors, see `ErrorPolicy`.
+    pub fn execute_script(&mut self, script: &str) -> Result<ScriptReport<'i, 's, 'v>, ReplError> {
+        let mut lines = vec![];
+        let mut statements = vec![];
+        for (number, line) in script.lines().enumerate() {
+            if is_blank(line) {
+                continue;
+            }
+            let stmt = self.parse(line).map_err(|e| {
+                ReplError::ScriptFailed(number + 1, format!("Error[{}]: {e}", e.code()))
+            })?;
+            lines.push(number + 1);
+            statements.push(stmt);
+        }
+
+        let line = |index: usize| lines.get(index).or(lines.last()).copied().unwrap_or(0);
+        let (outputs, failed) = self.execute_batch(statements).map_err(|(index, e)| {
+            ReplError::ScriptFailed(line(index), format!("Error[{}]: {e:?}", e.code()))
+        })?;
+
+        Ok(ScriptReport {
+            outputs,
+            failed: failed.into_iter().map(|(index, e)| (line(index), e)).collect(),
+        })
+    }
+
+    // Executes the lines of a `.run` script, returns how many were executed
+    // and the errors of the lines skipped because of their `.on error`
+    // policy. The policy set by the script ends with it.
+    fn run_lines(&mut self, script: &str) -> Result<(usize, Vec<String>), ReplError> {
+        let outer = std::mem::take(&mut self.error_policy);
+        let result = self.run_script_lines(script);
+        self.error_policy = outer;
+
+        result
+    }
+
+    fn run_script_lines(&mut self, script: &str) -> Result<(usize, Vec<String>), ReplError> {
+        let mut counter = 0;
+        let mut skipped = vec![];
+        for (number, line) in script.lines().enumerate() {
+            if is_blank(line) {
+                continue;
+            }
+            let failed = |message: String| ReplError::ScriptFailed(number + 1, message);
+
+            let stmt = self
+                .parse_line(line)
+                .map_err(|e| failed(format!("Error[{}]: {e}", e.code())))?;
+            // The rest of the script waits for `.continue`.
+            if let Some(paused) = &mut self.paused {
+                paused.enqueue(stmt);
+                continue;
+            }
+            let policy = self.error_policy;
+            let result = match policy {
+                ErrorPolicy::RollbackStatement if stmt.is_mutating() => self.execute_undoable(stmt),
+                _ => self.execute_measured(stmt).map(|(output, _)| output),
+            };
+            let message = match result {
+                Ok(_) => {
+                    counter += 1;
+                    continue;
+                }
+                Err(ReplError::Exit) => return Err(ReplError::Exit),
+                Err(ReplError::ScriptFailed(_, message)) => message,
+                Err(e) => format!("Error[{}]: {e:?}", e.code()),
+            };
+            if policy == ErrorPolicy::Abort {
+                return Err(failed(message));
+            }
+            skipped.push(format!("line {}: {message}", number + 1));
+        }
+
+        Ok((counter, skipped))
+    }
+
+    pub fn execute_measured(
+        &mut self,
+        stmt: Statement<'s, 's>,
+    ) -> Result<(ReplOutput<'i, 's, 'v>, Metrics), ReplError> {
+        if !self.permits(&stmt) {
+            return Err(ReplError::Denied);
+        }
+        // Breakpoints can be changed while paused.
+        let queueable = stmt.is_mutating()
+            && !matches!(
+                stmt,
+                Statement::Step(_) | Statement::Break(_) | Statement::ClearBreakpoints
+            );
+        if queueable {
+            if let Some(stepping) = &mut self.stepping {
+                if !matches!(stmt, Statement::SolveStep(..)) {
+                    stepping.enqueue(stmt);
+                    let output = ReplOutput::Notice("QUEUED until the stepping ends.".into());
+                    return Ok((output, Metrics::default()));
+                }
+            } else if let Some(paused) = &mut self.paused {
+                paused.enqueue(stmt);
+                let output = ReplOutput::Notice("QUEUED until .continue.".into());
+                return Ok((output, Metrics::default()));
+            }
+        }
+        // The lines of a script leave their warnings and metrics to the
+        // `.run` statement.
+        let line = self.running_script;
+        if !line {
+            metrics::take_scanned();
+            profile::set_enabled(self.settings.profile);
+            env::set_lenient(self.settings.lenient);
+            builtins::activate(&self.builtins);
+            env::take_unknown_identifiers();
+            env::take_failed_assertions();
+        }
+        self.refresh_system_bags();
+        let watched = self.paused.is_none().then(|| self.watched_bags()).flatten();
+        let origin = watched.as_ref().map(|_| stmt.to_string());
+        let start = watched.as_ref().map(|bags| self.bag_bundle.watch(bags));
+        let stopwatch = Stopwatch::start();
+        let result = self.execute_statement(stmt);
+        let journal = watched
+            .as_ref()
+            .zip(start)
+            .map(|(bags, start)| self.bag_bundle.unwatch(bags, start));
+        if line {
+            let output = result?;
+            if let (Some(journal), Some(origin), None) = (journal, origin, &self.paused) {
+                self.paused = self.breakpoint_hit(journal, origin).map(|hit| Pause::new(hit, vec![]));
+            }
+            return Ok((output, Metrics::default()));
+        }
+        let assertions = env::take_failed_assertions();
+        let mut output = result?;
+        if let (Some(journal), Some(origin), None) = (journal, origin, &self.paused) {
+            if let Some(hit) = self.breakpoint_hit(journal, origin) {
+                self.paused = Some(Pause::new(hit, vec![]));
+            }
+        }
+        if let Some(paused) = self.paused.as_mut().filter(|p| !p.reported) {
+            paused.reported = true;
+            output = ReplOutput::Paused(Box::new(output), Box::new(paused.hit()));
+        }
+        let warnings = env::take_unknown_identifiers()
+            .into_iter()
+            .map(|id| format!("unknown identifier {id} evaluated to null"))
+            .chain(assertions.into_iter().map(|m| format!("assertion failed: {m}")))
+            .collect::<Vec<_>>();
+        if !warnings.is_empty() {
+            output = match output {
+                ReplOutput::Warned(output, mut earlier) => {
+                    earlier.extend(warnings);
+                    ReplOutput::Warned(output, earlier)
+                }
+                output => ReplOutput::Warned(Box::new(output), warnings),
+            };
+        }
+        let metrics = Metrics {
+            duration: stopwatch.elapsed(),
+            scanned: metrics::take_scanned(),
+            affected: output.affected(),
+        };
+
+        Ok((output, metrics))
+    }
+
+    // Only the current system bag is kept in the bundle, holding the state
+    // of the engine right before the statement.
+    fn refresh_system_bags(&mut self) {
+        let current_bag = &self.current_bag;
+        if self
+            .bag_bundle
+            .bags
+            .keys()
+            .any(|name| system_bag::is_system_bag(name) && name != current_bag)
+        {
+            self.bag_bundle
+                .bags
+                .retain(|name, _| !system_bag::is_system_bag(name) || name == current_bag);
+        }
+
+        if let Some(values) = system_bag::values(self, &self.current_bag.name) {
+            self.bag_bundle.bags.insert(
+                self.current_bag.clone(),
+                Cow::Owned(ValueBag::new_virtual(VirtualSource::System(values))),
+            );
+        }
+    }
+
+    fn execute_statement(&mut self, stmt: Statement<'s, 's>) -> Result<ReplOutput<'i, 's, 'v>, ReplError> {
         match stmt {
             Statement::Noop => {
                 self.env.clear();
@@ -130,9 +1211,11 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
                 Ok(ReplOutput::Ack)
             }
             Statement::Exit => Err(ReplError::Exit),
-            Statement::Help => {
-                return Ok(ReplOutput::Notice("Interactive help is not yet implemented. Please take a look at the README.md file".to_string()));
-            }
+            Statement::Help(None) => Ok(ReplOutput::Notice(help::overview())),
+            Statement::Help(Some(command)) => Ok(ReplOutput::Notice(match help::lookup(&command) {
+                Some(entry) => entry.to_string().trim_end().to_string(),
+                None => format!("Unknown command {command}. Type .help for a list of commands."),
+            })),
             Statement::TellBag => {
                 let mut trans = Transaction::new(&self.bag_bundle);
                 let Ok((size, guard)) = trans.get_bag_info(&self.current_bag) else {
@@ -158,15 +1241,74 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
                         .join(", ")
                 )))
             }
+            Statement::RenameBag(from, to) => {
+                // Connections and views refer to their bags by name.
+                let connected = self
+                    .bag_graph
+                    .connections
+                    .values()
+                    .any(|c| c.bags().any(|b| b == &from));
+                let viewed = self.bag_bundle.bags.values().any(|bag| {
+                    matches!(&bag.source, Some(VirtualSource::Union(bags, _)) if bags.contains(&from))
+                });
+                if connected || viewed || system_bag::is_system_bag(&from) {
+                    return Err(ReplError::BagError);
+                }
+
+                let mut trans = Transaction::new(&self.bag_bundle);
+                if !trans
+                    .rename_bag(&from, to.clone())
+                    .map_err(|_| ReplError::TranscationAborted)?
+                {
+                    return Err(ReplError::BagError);
+                }
+                trans
+                    .rename_bag(&trash::bag_name(&from), trash::bag_name(&to))
+                    .map_err(|_| ReplError::TranscationAborted)?;
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
+
+                self.bag_graph.rename_bag(&from, &to);
+                if self.current_bag == from {
+                    self.current_bag = to;
+                }
+
+                Ok(ReplOutput::Notice("BAG RENAMED".into()))
+            }
+            Statement::CopyBag(source, target, predicate) => {
+                let env = self.statement_env();
+                let mut trans = Transaction::new(&self.bag_bundle);
+                let result = trans
+                    .copy_bag(&source, target.clone(), &env, &predicate)
+                    .map_err(|e| match e {
+                        TransactionError::BagDoesNotExist => ReplError::BagError,
+                        _ => ReplError::TranscationAborted,
+                    })?;
+
+                match result {
+                    None => Err(ReplError::BagError),
+                    Some(InsertionResult::Success(count)) => {
+                        self.fire_triggers(&mut trans, &env, BTreeSet::from([target]))?;
+                        self.bag_bundle = trans.commit().map_err(commit_error)?;
+                        Ok(ReplOutput::Inserted(count))
+                    }
+                    Some(InsertionResult::GuardError) => Err(ReplError::GuardError),
+                    Some(InsertionResult::EvalError(e)) => Err(ReplError::EvalError(e)),
+                }
+            }
             Statement::DropBag(bag_id) => {
                 if self.current_bag == bag_id {
                     Err(ReplError::BagError)
                 } else {
+                    // The trash of the bag is kept, so that its values can be
+                    // restored into a bag created again under the same name.
                     let mut trans = Transaction::new(&self.bag_bundle);
+                    if let Ok((count, _)) = trans.get_bag_info(&bag_id) {
+                        self.check_confirmed(count)?;
+                    }
                     let result = trans.drop_bag(bag_id).map_err(|_| ReplError::TranscationAborted)?;
 
                     if result {
-                        self.bag_bundle = trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                        self.bag_bundle = trans.commit().map_err(commit_error)?;
 
                         Ok(ReplOutput::Notice("BAG REMOVED".into()))
                     } else {
@@ -174,24 +1316,30 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
                     }
                 }
             }
-            Statement::UseBag(bag_id, pred) => {
+            Statement::UseBag(bag_id, pred, _) if system_bag::is_system_bag(&bag_id) => {
+                if pred.is_some() || !system_bag::NAMES.contains(&bag_id.name.as_ref()) {
+                    return Err(ReplError::BagError);
+                }
+                self.current_bag = bag_id;
+                self.refresh_system_bags();
+
+                Ok(ReplOutput::Notice("SWITCHED BAG".into()))
+            }
+            Statement::UseBag(bag_id, pred, mode) => {
                 self.current_bag = bag_id.clone();
                 let wants_create = pred.is_some();
 
                 let mut trans = Transaction::new(&self.bag_bundle);
                 let created = trans
-                    .create_bag(
+                    .create_bag_in_mode(
                         bag_id.clone(),
-                        pred.unwrap_or(Predicate {
-                            pattern: pattern("_").unwrap().1,
-                            guard: full_expression("true").unwrap().1,
-                            limit: None,
-                        }),
+                        pred.unwrap_or_else(Predicate::any),
+                        mode,
                     )
                     .map_err(|_| ReplError::TranscationAborted)?;
 
                 if created {
-                    self.bag_bundle = trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                    self.bag_bundle = trans.commit().map_err(commit_error)?;
                     Ok(ReplOutput::Notice("BAG CREATED".into()))
                 } else if wants_create {
                     Ok(ReplOutput::Notice("ALREADY EXISTS, SWITCHED BAG".into()))
@@ -199,7 +1347,72 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
                     Ok(ReplOutput::Notice("SWITCHED BAG".into()))
                 }
             }
+            Statement::CreateView(view, bags) => {
+                // Views are computed from stored values only.
+                let stored = bags.iter().all(|b| {
+                    self.bag_bundle
+                        .bags
+                        .get(b)
+                        .map_or(false, |bag| !bag.is_virtual() && bag.backend.is_none())
+                });
+                if !stored {
+                    return Err(ReplError::BagError);
+                }
+
+                let mut trans = Transaction::new(&self.bag_bundle);
+                let created = trans
+                    .create_virtual_bag(view.clone(), VirtualSource::union(bags))
+                    .map_err(|_| ReplError::TranscationAborted)?;
+
+                if !created {
+                    return Err(ReplError::BagError);
+                }
+
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
+                self.current_bag = view;
+                Ok(ReplOutput::Notice("VIEW CREATED".into()))
+            }
+            Statement::CreateVirtualBag(bag_id, range) => {
+                let mut trans = Transaction::new(&self.bag_bundle);
+                let created = trans
+                    .create_virtual_bag(bag_id.clone(), VirtualSource::Range(range))
+                    .map_err(|_| ReplError::TranscationAborted)?;
+
+                if !created {
+                    return Err(ReplError::BagError);
+                }
+
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
+                self.current_bag = bag_id;
+                Ok(ReplOutput::Notice("BAG CREATED".into()))
+            }
+            Statement::OnError(policy) => {
+                self.error_policy = policy;
+                Ok(ReplOutput::Ack)
+            }
+            Statement::Run(filename) => {
+                // A script running itself would never end.
+                if self.running_script {
+                    return Err(ReplError::ScriptFailed(0, "Scripts can not run other scripts".into()));
+                }
+                let Ok(script) = std::fs::read_to_string(filename.as_ref()) else {
+                    return Err(ReplError::IoError);
+                };
+
+                self.running_script = true;
+                let result = self.run_lines(&script);
+                self.running_script = false;
+
+                let (counter, skipped) = result?;
+                let notice = ReplOutput::Notice(format!("Ran {counter} statements from file '{filename}'"));
+                Ok(if skipped.is_empty() {
+                    notice
+                } else {
+                    ReplOutput::Warned(Box::new(notice), skipped)
+                })
+            }
             Statement::LoadBundle(filename) => {
+                let env = self.statement_env();
                 let Ok(file) = File::open(filename.as_ref()) else {
                     return Err(ReplError::IoError);
                 };
@@ -208,232 +1421,701 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
 
                 let mut counter = 0;
                 let mut bag_counter = 0;
-                for l in lines {
+                // The lines after an `.on error continue|rollback_statement`
+                // are skipped if they fail, with rollback_statement the
+                // values inserted before the failing one are removed again.
+                let mut policy = ErrorPolicy::Abort;
+                let mut skipped = vec![];
+                let created_bag = |created| if created { Ok(()) } else { Err(ReplError::BagError) };
+                for (number, l) in lines.enumerate() {
                     let Ok(line) = l else {
                         return Err(ReplError::ReadError);
                     };
 
-                    let Ok((_, cmd)) = bundle_line(&line) else {
+                    if is_blank(&line) {
+                        continue;
+                    }
+                    let Ok((_, cmd)) = bundle_line(&strip_comments(&line)) else {
                         return Err(ReplError::ParseError);
                     };
 
-                    match cmd {
-                        BundleCommand::Bag(bag_id, pred) => {
+                    let mut inserted = vec![];
+                    let result = match cmd {
+                        BundleCommand::OnError(p) => {
+                            policy = p;
+                            Ok(())
+                        }
+                        BundleCommand::Bag(bag_id, pred, mode) => {
                             self.current_bag = bag_id.clone();
-                            let created = trans
-                            .create_bag(
-                                bag_id.clone(),
-                                pred.unwrap_or(Predicate {
-                                    pattern: pattern("_").unwrap().1,
-                                    guard: full_expression("true").unwrap().1,
-                                    limit: None,
-                                }),
-                            )
-                            .map_err(|_| ReplError::TranscationAborted)?;
-
-                            if created {
-                                bag_counter += 1;
-                            } else {
-                                return Err(ReplError::BagError)
-                            }
+                            trans
+                                .create_bag_in_mode(bag_id, pred.unwrap_or_else(Predicate::any), mode)
+                                .map_err(|_| ReplError::TranscationAborted)
+                                .and_then(created_bag)
+                                .map(|()| bag_counter += 1)
                         },
-                        BundleCommand::Values(expr) => {
-                            if bag_counter<1 {
-                                return Err(ReplError::BagError)
-                            }
-                            for ex in expr.expressions {
-                                let r = trans.insert_one(&self.current_bag, &self.env, &ex)
+                        BundleCommand::VirtualBag(bag_id, range) => {
+                            self.current_bag = bag_id.clone();
+                            trans
+                                .create_virtual_bag(bag_id, VirtualSource::Range(range))
+                                .map_err(|_| ReplError::TranscationAborted)
+                                .and_then(created_bag)
+                                .map(|()| bag_counter += 1)
+                        },
+                        BundleCommand::View(view, bags) => {
+                            self.current_bag = view.clone();
+                            trans
+                                .create_virtual_bag(view, VirtualSource::union(bags))
+                                .map_err(|_| ReplError::TranscationAborted)
+                                .and_then(created_bag)
+                                .map(|()| bag_counter += 1)
+                        },
+                        BundleCommand::Values(_) if bag_counter < 1 => Err(ReplError::BagError),
+                        BundleCommand::Values(expr) => expr.expressions.iter().try_for_each(|ex| {
+                            let r = trans.insert_one(&self.current_bag, &env, ex)
                                 .map_err(|_| ReplError::TranscationAborted)?;
-                                
-                                match r {
-                                    InsertionResult::Success(c) => {
-                                        counter+= c ;
-                                    },
-                                    InsertionResult::GuardError => return Err(ReplError::GuardError),
-                                    InsertionResult::EvalError => return Err(ReplError::EvalError),
-                                }
+
+                            match r {
+                                InsertionResult::Success(c) => {
+                                    counter += c;
+                                    if c > 0 && policy == ErrorPolicy::RollbackStatement {
+                                        inserted.extend(env.eval_expr(ex));
+                                    }
+                                    Ok(())
+                                },
+                                InsertionResult::GuardError => Err(ReplError::GuardError),
+                                InsertionResult::EvalError(e) => Err(ReplError::EvalError(e)),
                             }
-                        },
+                        }),
+                    };
+
+                    match result {
+                        Ok(()) => {}
+                        Err(e) if policy == ErrorPolicy::Abort => return Err(e),
+                        Err(e) => {
+                            for value in &inserted {
+                                trans.pop(&self.current_bag, value).map_err(|_| ReplError::TranscationAborted)?;
+                                counter -= 1;
+                            }
+                            skipped.push(format!("line {}: Error[{}]: {e:?}", number + 1, e.code()));
+                        }
                     }
-                }                
-                self.bag_bundle = trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                }
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
 
-                Ok(ReplOutput::Notice(format!(
+                let notice = ReplOutput::Notice(format!(
                     "Imported {} bags with {} values in total from file '{filename}' into current bag({})",
                     bag_counter, counter, self.current_bag
+                ));
+                Ok(if skipped.is_empty() {
+                    notice
+                } else {
+                    ReplOutput::Warned(Box::new(notice), skipped)
+                })
+            }
+            Statement::Save(filename) => {
+                let snapshot = Snapshot::of(&self.bag_bundle);
+                let Ok(file) = File::create(filename.as_ref()) else {
+                    return Err(ReplError::IoError);
+                };
+                snapshot
+                    .write(io::BufWriter::new(file))
+                    .map_err(|_| ReplError::IoError)?;
+
+                Ok(ReplOutput::Notice(format!(
+                    "Saved {} bags to file '{filename}'",
+                    snapshot.bags.len()
                 )))
             }
-            Statement::Import(filename) => {
+            Statement::Restore(filename) => {
+                let env = self.statement_env();
                 let Ok(file) = File::open(filename.as_ref()) else {
                     return Err(ReplError::IoError);
                 };
-                let lines = io::BufReader::new(file).lines();
+                let snapshot = Snapshot::read(io::BufReader::new(file)).map_err(|e| match e {
+                    SnapshotError::Io => ReplError::ReadError,
+                    SnapshotError::Malformed | SnapshotError::UnsupportedVersion(_) => {
+                        ReplError::ParseError
+                    }
+                })?;
+
+                // The snapshot replaces all bags it could have contained.
                 let mut trans = Transaction::new(&self.bag_bundle);
-                let mut counter = 0;
+                let replaced = self
+                    .bag_bundle
+                    .bags
+                    .iter()
+                    .filter(|(_, bag)| snapshot::is_saved(bag))
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>();
+                for name in replaced {
+                    trans
+                        .drop_bag(name)
+                        .map_err(|_| ReplError::TranscationAborted)?;
+                }
 
-                for l in lines {
-                    let Ok(line) = l else {
-                        return Err(ReplError::ReadError);
-                    };
-                    let Ok((_, expr)) = full_expression(&line) else {
-                        return Err(ReplError::ParseError);
+                let mut counter = 0;
+                for bag in &snapshot.bags {
+                    let name = Identifier {
+                        name: Cow::Owned(bag.name.clone()),
                     };
+                    let created = match bag.range {
+                        Some(range) => {
+                            trans.create_virtual_bag(name.clone(), VirtualSource::Range(range))
+                        }
+                        None if !bag.union.is_empty() => {
+                            let bags = bag
+                                .union
+                                .iter()
+                                .map(|b| Identifier { name: Cow::Owned(b.clone()) })
+                                .collect();
+                            trans.create_virtual_bag(name.clone(), VirtualSource::union(bags))
+                        }
+                        None => trans.create_bag_in_mode(name.clone(), bag.predicate.clone(), bag.mode),
+                    }
+                    .map_err(|_| ReplError::TranscationAborted)?;
+                    if !created {
+                        return Err(ReplError::BagError);
+                    }
+
+                    for ex in &bag.values {
+                        match trans
+                            .insert_one(&name, &env, ex)
+                            .map_err(|_| ReplError::TranscationAborted)?
+                        {
+                            InsertionResult::Success(c) => counter += c,
+                            InsertionResult::GuardError => return Err(ReplError::GuardError),
+                            InsertionResult::EvalError(e) => return Err(ReplError::EvalError(e)),
+                        }
+                    }
+                    for field in &bag.indexes {
+                        trans
+                            .create_index(&name, field)
+                            .map_err(|_| ReplError::TranscationAborted)?;
+                    }
+                }
+
+                let _ = trans.create_bag(self.current_bag.clone(), Predicate::any());
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
+
+                Ok(ReplOutput::Notice(format!(
+                    "Restored {} bags with {} values in total from file '{filename}'",
+                    snapshot.bags.len(),
+                    counter
+                )))
+            }
+            Statement::EnableTrash(ttl) => {
+                let mut trans = Transaction::new(&self.bag_bundle);
+                trans
+                    .create_bag(trash::bag_name(&self.current_bag), Predicate::any())
+                    .map_err(|_| ReplError::TranscationAborted)?;
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
 
+                match ttl {
+                    Some(ttl) => {
+                        self.trash_ttl.insert(self.current_bag.clone(), ttl);
+                        Ok(ReplOutput::Notice(format!(
+                            "Trash enabled for bag {}, deleted values are purged after {ttl} seconds",
+                            self.current_bag
+                        )))
+                    }
+                    None => {
+                        self.trash_ttl.remove(&self.current_bag);
+                        Ok(ReplOutput::Notice(format!(
+                            "Trash enabled for bag {}",
+                            self.current_bag
+                        )))
+                    }
+                }
+            }
+            Statement::DisableTrash => {
+                let mut trans = Transaction::new(&self.bag_bundle);
+                trans
+                    .drop_bag(trash::bag_name(&self.current_bag))
+                    .map_err(|_| ReplError::TranscationAborted)?;
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
+                self.trash_ttl.remove(&self.current_bag);
+
+                Ok(ReplOutput::Notice(format!(
+                    "Trash disabled for bag {}",
+                    self.current_bag
+                )))
+            }
+            Statement::ListTrash => {
+                let now = trash::now();
+                let trans = Transaction::new(&self.bag_bundle);
+                let entries = trans
+                    .read(&trash::bag_name(&self.current_bag))
+                    .map_err(|_| ReplError::BagError)?
+                    .filter(|entry| !trash::is_expired(entry, now))
+                    .map(|entry| entry.as_ref().clone())
+                    .collect();
+
+                Ok(ReplOutput::Values(entries))
+            }
+            Statement::RestoreTrash(predicate) => {
+                let env = self.statement_env();
+                let predicate = if self.settings.specialize_queries {
+                    predicate.specialize(&self.env)
+                } else {
+                    predicate
+                };
+                // Matches `{value: <pattern> @ $, ...}` against the entries
+                // and moves `$` back.
+                let restored = Identifier {
+                    name: Cow::Borrowed("$"),
+                };
+                let query = TransferQuery {
+                    predicate: Predicate {
+                        pattern: Pattern::Object(
+                            vec![ObjectPropertyPattern::Match(PropertyPattern {
+                                key: PropertyKey::Identifier(Identifier {
+                                    name: Cow::Borrowed("value"),
+                                }),
+                                value: Pattern::Capture(
+                                    restored.clone(),
+                                    Box::new(predicate.pattern),
+                                ),
+                            })],
+                            Rest::Discard,
+                        ),
+                        ..predicate
+                    },
+                    projection: Expression::Identifier(restored),
+                };
+                let bin = trash::bag_name(&self.current_bag);
+                let mut trans = Transaction::new(&self.bag_bundle);
+                self.purge_expired(&mut trans, trash::now())?;
+
+                let result = trans
+                    .transfer(&bin, &self.current_bag, &env, query)
+                    .map_err(|_| ReplError::TranscationAborted)?;
+
+                match result {
+                    TransferResult::Success(count) => {
+                        self.bag_bundle =
+                            trans.commit().map_err(commit_error)?;
+                        Ok(ReplOutput::Transferd(count))
+                    }
+                    TransferResult::GuardError => Err(ReplError::GuardError),
+                    TransferResult::EvalError(e) => Err(ReplError::EvalError(e)),
+                }
+            }
+            Statement::PurgeTrash => {
+                let bin = trash::bag_name(&self.current_bag);
+                let mut trans = Transaction::new(&self.bag_bundle);
+                let (count, _) = trans
+                    .get_bag_info(&bin)
+                    .map_err(|_| ReplError::BagError)?;
+                trans
+                    .drop_bag(bin.clone())
+                    .map_err(|_| ReplError::TranscationAborted)?;
+                trans
+                    .create_bag(bin, Predicate::any())
+                    .map_err(|_| ReplError::TranscationAborted)?;
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
+
+                Ok(ReplOutput::Notice(format!(
+                    "Purged {count} values from the trash of bag {}",
+                    self.current_bag
+                )))
+            }
+            Statement::Import(filename, format, verify) => {
+                if let Some(feature) = documents::missing_feature(format) {
+                    return Ok(ReplOutput::Notice(format!(
+                        "{format:?} import requires building with --features {feature}"
+                    )));
+                }
+                let expected = if verify {
+                    Some(verify_manifest(&filename)?)
+                } else {
+                    None
+                };
+
+                let env = self.statement_env();
+                let Ok(file) = File::open(filename.as_ref()) else {
+                    return Err(ReplError::IoError);
+                };
+                let reader = io::BufReader::new(file);
+                let expressions = match format {
+                    DataFormat::Lines => {
+                        let mut expressions = vec![];
+                        for l in reader.lines() {
+                            let Ok(line) = l else {
+                                return Err(ReplError::ReadError);
+                            };
+                            let Ok((_, expr)) = full_expression(&line) else {
+                                return Err(ReplError::ParseError);
+                            };
+                            expressions.push(expr);
+                        }
+                        expressions
+                    }
+                    DataFormat::Json => {
+                        let Ok(serde_json::Value::Array(items)) = serde_json::from_reader(reader) else {
+                            return Err(ReplError::ParseError);
+                        };
+                        items
+                            .iter()
+                            .map(|item| json::from_json(item).map(|v| v.to_expression()))
+                            .collect::<Option<Vec<_>>>()
+                            .ok_or(ReplError::ParseError)?
+                    }
+                    DataFormat::Ndjson => return self.import_ndjson(reader, &filename, expected),
+                    DataFormat::Parquet => return Err(ReplError::ParseError),
+                    DataFormat::Yaml | DataFormat::Toml | DataFormat::Xml => {
+                        let input = io::read_to_string(reader).map_err(|_| ReplError::ReadError)?;
+                        documents::read(format, &input)
+                            .map_err(|_| ReplError::ParseError)?
+                            .iter()
+                            .map(|v| v.to_expression())
+                            .collect()
+                    }
+                };
+                if expected.is_some_and(|count| count != expressions.len()) {
+                    return Err(ReplError::VerificationError);
+                }
+                let mut trans = Transaction::new(&self.bag_bundle);
+                let mut counter = 0;
+
+                for expr in &expressions {
                     let result = trans
-                        .insert_one(&self.current_bag, &self.env, &expr)
+                        .insert_one(&self.current_bag, &env, expr)
                         .map_err(|_| ReplError::TranscationAborted)?;
                     match result {
                         InsertionResult::Success(c) => counter += c,
                         InsertionResult::GuardError => return Err(ReplError::GuardError),
-                        InsertionResult::EvalError => return Err(ReplError::EvalError),
+                        InsertionResult::EvalError(e) => return Err(ReplError::EvalError(e)),
                     }
                 }
 
-                self.bag_bundle = trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                self.fire_triggers(&mut trans, &env, BTreeSet::from([self.current_bag.clone()]))?;
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
 
                 Ok(ReplOutput::Notice(format!(
-                    "Imported {} values from file '{filename}' into current bag({})",
-                    counter, self.current_bag
+                    "Imported {} values from file '{filename}' into current bag({}){}",
+                    counter,
+                    self.current_bag,
+                    if verify { ", verified by manifest" } else { "" }
                 )))
             }
-            Statement::Export(filename) => {
+            Statement::Export(filename, format, verify) => {
                 use std::io::Write;
 
-                let Ok(file) = File::create(filename.as_ref()) else {
+                if matches!(format, DataFormat::Yaml | DataFormat::Toml | DataFormat::Xml) {
+                    return Err(ReplError::ParseError);
+                }
+                if format == DataFormat::Parquet && cfg!(not(feature = "parquet")) {
+                    return Ok(ReplOutput::Notice(
+                        "Parquet export requires building with --features parquet".into(),
+                    ));
+                }
+
+                let trans = Transaction::new(&self.bag_bundle);
+                let values = trans
+                    .read(&self.current_bag)
+                    .map_err(|_| ReplError::TranscationAborted)?
+                    .collect::<Vec<_>>();
+                let count = values.len();
+
+                // The whole export is rendered before the file is written, so
+                // a value that can not be exported leaves no partial file.
+                let mut content = vec![];
+                match format {
+                    DataFormat::Lines => {
+                        for v in values {
+                            let _ = writeln!(content, "{v}");
+                        }
+                    }
+                    DataFormat::Json => {
+                        let Some(items) = values.into_iter().map(|v| json::to_json(v)).collect::<Option<Vec<_>>>() else {
+                            return Err(ReplError::EvalError(EvalError::TypeError));
+                        };
+                        serde_json::to_writer_pretty(&mut content, &items).map_err(|_| ReplError::IoError)?;
+                        let _ = writeln!(content);
+                    }
+                    DataFormat::Ndjson => {
+                        for v in values {
+                            let Some(item) = json::to_json(v) else {
+                                return Err(ReplError::EvalError(EvalError::TypeError));
+                            };
+                            let _ = writeln!(content, "{item}");
+                        }
+                    }
+                    DataFormat::Parquet => {
+                        #[cfg(feature = "parquet")]
+                        {
+                            use crate::columnar::{self, ParquetError};
+
+                            let values = values.into_iter().map(|v| v.as_ref()).collect::<Vec<_>>();
+                            match columnar::record_batch(&values)
+                                .and_then(|batch| columnar::write(&mut content, &batch))
+                            {
+                                Ok(()) => {}
+                                Err(ParquetError::WriteFailed) => return Err(ReplError::IoError),
+                                Err(_) => return Err(ReplError::EvalError(EvalError::TypeError)),
+                            }
+                        }
+                        #[cfg(not(feature = "parquet"))]
+                        unreachable!("checked before rendering");
+                    }
+                    DataFormat::Yaml | DataFormat::Toml | DataFormat::Xml => {
+                        unreachable!("rejected before rendering")
+                    }
+                }
+
+                // An export replacing an earlier one only does so once it is
+                // completely written.
+                let partial = format!("{filename}.partial");
+                let written = File::create(&partial)
+                    .and_then(|mut file| {
+                        file.write_all(&content)?;
+                        file.sync_all()
+                    })
+                    .and_then(|_| std::fs::rename(&partial, filename.as_ref()));
+                if written.is_err() {
+                    let _ = std::fs::remove_file(&partial);
                     return Err(ReplError::IoError);
-                };
-
-                let mut file = LineWriter::new(file);
-                let trans = Transaction::new(&self.bag_bundle);
-                for v in trans
-                    .read(&self.current_bag)
-                    .map_err(|_| ReplError::TranscationAborted)?
-                {
-                    let _ = writeln!(file, "{v}");
                 }
 
-                trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                trans.commit().map_err(commit_error)?;
 
-                return Ok(ReplOutput::Notice(format!(
+                let mut notice = format!(
                     "Current bag({}) written to file: {filename}",
                     self.current_bag
-                )));
+                );
+                if verify {
+                    manifest::write(&filename, count).map_err(|_| ReplError::IoError)?;
+                    notice.push_str(&format!(
+                        "\nManifest written to file: {}",
+                        manifest::path(&filename).display()
+                    ));
+                }
+
+                return Ok(ReplOutput::Notice(notice));
             }
             Statement::Insert(insertion) => {
+                let env = self.statement_env();
                 let mut trans = Transaction::new(&self.bag_bundle);
                 let result = trans
-                    .insert(&self.current_bag, &self.env, &insertion)
+                    .insert(&self.current_bag, &env, &insertion)
                     .map_err(|_| ReplError::TranscationAborted)?;
 
                 match result {
                     InsertionResult::Success(count) => {
+                        self.fire_triggers(&mut trans, &env, BTreeSet::from([self.current_bag.clone()]))?;
                         self.bag_bundle =
-                            trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                            trans.commit().map_err(commit_error)?;
 
                         Ok(ReplOutput::Inserted(count))
                     }
                     InsertionResult::GuardError => Err(ReplError::GuardError),
-                    InsertionResult::EvalError => Err(ReplError::EvalError),
+                    InsertionResult::EvalError(e) => Err(ReplError::EvalError(e)),
                 }
             }
             Statement::Query(query) => {
+                let env = self.statement_env();
+                let query = if self.settings.specialize_queries {
+                    query.specialize(&self.env)
+                } else {
+                    query
+                };
                 let trans = Transaction::new(&self.bag_bundle);
 
                 let result = trans
-                    .query(&self.current_bag, &self.env, &query)
+                    .query(&self.current_bag, &env, &query)
                     .map_err(|_| ReplError::TranscationAborted)?
                     .collect::<Result<Vec<_>, _>>()
                     .map(ReplOutput::Values)
-                    .map_err(|_| ReplError::EvalError);
+                    .map_err(ReplError::EvalError);
 
-                trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                trans.commit().map_err(commit_error)?;
 
-                result
+                let warnings = query
+                    .unknown_hints()
+                    .map(|hint| format!("unknown query hint {hint} ignored"))
+                    .collect::<Vec<_>>();
+                if warnings.is_empty() {
+                    result
+                } else {
+                    result.map(|output| ReplOutput::Warned(Box::new(output), warnings))
+                }
             }
             Statement::Deletion(deletion) => {
+                let env = self.statement_env();
+                let deletion = if self.settings.specialize_queries {
+                    DeletionQuery {
+                        predicate: deletion.predicate.specialize(&self.env),
+                    }
+                } else {
+                    deletion
+                };
                 let mut trans = Transaction::new(&self.bag_bundle);
+                let start = self.watch_trash(&mut trans)?;
 
                 let result = trans
-                    .delete(&self.current_bag, &self.env, &deletion)
+                    .delete(&self.current_bag, &env, &deletion)
                     .map_err(|_| ReplError::TranscationAborted)?;
 
                 match result {
                     DeletionResult::Success(count) => {
+                        self.check_confirmed(count)?;
+                        self.trash_deleted(&mut trans, start)?;
                         self.bag_bundle =
-                            trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                            trans.commit().map_err(commit_error)?;
                         Ok(ReplOutput::Deleted(count))
                     }
-                    DeletionResult::EvalError => Err(ReplError::EvalError),
+                    DeletionResult::EvalError(e) => Err(ReplError::EvalError(e)),
                 }
             }
             Statement::Update(update) => {
+                let env = self.statement_env();
+                let update = if self.settings.specialize_queries {
+                    update.specialize(&self.env)
+                } else {
+                    update
+                };
                 let mut trans = Transaction::new(&self.bag_bundle);
 
                 let result = trans
-                    .update(&self.current_bag, &self.env, &update)
+                    .update(&self.current_bag, &env, &update)
                     .map_err(|_| ReplError::TranscationAborted)?;
 
                 match result {
                     UpdateResult::Success(count) => {
+                        self.check_confirmed(count)?;
                         self.bag_bundle =
-                            trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                            trans.commit().map_err(commit_error)?;
                         Ok(ReplOutput::Updated(count))
                     }
                     UpdateResult::GuardError => Err(ReplError::GuardError),
-                    UpdateResult::EvalError => Err(ReplError::EvalError),
+                    UpdateResult::EvalError(e) => Err(ReplError::EvalError(e)),
+                }
+            }
+            Statement::Preview(update) => {
+                let env = self.statement_env();
+                let update = if self.settings.specialize_queries {
+                    update.specialize(&self.env)
+                } else {
+                    update
+                };
+                let mut trans = Transaction::new(&self.bag_bundle);
+                let before = trans
+                    .read(&self.current_bag)
+                    .map_err(|_| ReplError::BagError)?
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                let result = trans
+                    .update(&self.current_bag, &env, &update)
+                    .map_err(|_| ReplError::TranscationAborted)?;
+
+                match result {
+                    // The transaction is dropped without being committed.
+                    UpdateResult::Success(count) => {
+                        let after = trans
+                            .read(&self.current_bag)
+                            .map_err(|_| ReplError::TranscationAborted)?
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        // Values the change leaves as they are are not shown.
+                        let mut samples = observer::updates(&before, &after)
+                            .into_iter()
+                            .filter_map(|m| match m {
+                                Mutation::Update { before, after } => Some((before, after)),
+                                _ => None,
+                            })
+                            .collect::<Vec<_>>();
+                        let omitted = samples.len().saturating_sub(PREVIEW_SAMPLES);
+                        samples.truncate(PREVIEW_SAMPLES);
+                        Ok(ReplOutput::Preview(count, samples, omitted))
+                    }
+                    UpdateResult::GuardError => Err(ReplError::GuardError),
+                    UpdateResult::EvalError(e) => Err(ReplError::EvalError(e)),
                 }
             }
             Statement::Move(to, query) => {
+                let env = self.statement_env();
+                let query = if self.settings.specialize_queries {
+                    query.specialize(&self.env)
+                } else {
+                    query
+                };
                 let mut trans = Transaction::new(&self.bag_bundle);
 
                 let result = trans
-                    .transfer(&self.current_bag, &to, &self.env, query)
+                    .transfer(&self.current_bag, &to, &env, query)
                     .map_err(|_| ReplError::TranscationAborted)?;
 
                 match result {
                     TransferResult::Success(count) => {
+                        self.fire_triggers(&mut trans, &env, BTreeSet::from([to.clone()]))?;
                         self.bag_bundle =
-                            trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                            trans.commit().map_err(commit_error)?;
                         Ok(ReplOutput::Transferd(count))
                     }
                     TransferResult::GuardError => Err(ReplError::GuardError),
-                    TransferResult::EvalError => Err(ReplError::EvalError),
+                    TransferResult::EvalError(e) => Err(ReplError::EvalError(e)),
                 }
             }
             Statement::Pop(expression) => {
-                let value = self
-                    .env
-                    .eval_expr(&expression)
-                    .map_err(|_| ReplError::EvalError)?;
+                let value = self.env.eval_expr(&expression).map_err(eval_error)?;
 
                 let mut trans = Transaction::new(&self.bag_bundle);
+                let start = self.watch_trash(&mut trans)?;
 
                 let result = trans
                     .pop(&self.current_bag, &value)
                     .map_err(|_| ReplError::TranscationAborted)?;
 
                 if result {
-                    self.bag_bundle = trans.commit().map_err(|_| ReplError::TranscationAborted)?;
+                    self.trash_deleted(&mut trans, start)?;
+                    self.bag_bundle = trans.commit().map_err(commit_error)?;
                     Ok(ReplOutput::Ack)
                 } else {
                     Ok(ReplOutput::No)
                 }
             }
+            Statement::Log(level, expression) => {
+                let value = self.env.eval_expr(&expression).map_err(eval_error)?;
+                let bag = Identifier {
+                    name: Cow::Owned(self.settings.log_bag.clone()),
+                };
+                let entry = log::entry(level, value, trash::now()).to_expression();
+
+                let mut trans = Transaction::new(&self.bag_bundle);
+                trans
+                    .create_bag(bag.clone(), Predicate::any())
+                    .map_err(|_| ReplError::TranscationAborted)?;
+                let env = Environment::new();
+                match trans
+                    .insert_one(&bag, &env, &entry)
+                    .map_err(|_| ReplError::TranscationAborted)?
+                {
+                    InsertionResult::Success(_) => {
+                        self.bag_bundle = trans.commit().map_err(commit_error)?;
+                        Ok(ReplOutput::Ack)
+                    }
+                    InsertionResult::GuardError => Err(ReplError::GuardError),
+                    InsertionResult::EvalError(e) => Err(ReplError::EvalError(e)),
+                }
+            }
             Statement::Inspect(ex) => {
                 return Ok(ReplOutput::Notice(format!("{ex:?}")));
             }
-            Statement::Format(ex) => {
-                return Ok(ReplOutput::Notice(format!("{ex:?}")));
+            Statement::Format(statement) => {
+                return Ok(ReplOutput::Notice(formatter::pretty(&statement, formatter::WIDTH)));
             }
 
             Statement::Eval(ExpressionSet { expressions }) => expressions
                 .into_iter()
-                .map(|e| self.env.eval_expr(&e).map_err(|_| ReplError::EvalError))
+                .map(|e| self.env.eval_expr(&e).map_err(eval_error))
                 .collect::<Result<Vec<_>, _>>()
-                .map(ReplOutput::Values)
-                .map_err(|_| ReplError::EvalError),
+                .map(ReplOutput::Values),
             Statement::MatchSet(assignments) => {
-                match assignments.sort_topological(self.env.identifiers()) {
+                let free = self.lenient_identifiers(&assignments);
+                let mut known = self.env.identifiers();
+                known.extend(free.iter());
+                match assignments.sort_topological(known) {
                     Ok(assignments) => {
                         let result = assignments.assignments.iter().fold(
                             Ok(Ok(self.env.clone())),
@@ -449,8 +2131,8 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
         
                                 let result = match tmp_env.eval_expr(expression) {
                                     Ok(r) => r,
-                                    Err(_err) => {
-                                        return Err(ReplError::EvalError);
+                                    Err(err) => {
+                                        return Err(eval_error(err));
                                     }
                                 };
         
@@ -476,7 +2158,10 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
                 }
             }
             Statement::AssignSet(assignments) => {
-                match assignments.sort_topological(self.env.identifiers()) {
+                let free = self.lenient_identifiers(&assignments);
+                let mut known = self.env.identifiers();
+                known.extend(free.iter());
+                match assignments.sort_topological(known) {
                     Ok(assignments) => {
                         let mut bindings = Environment::new();
                         let result = assignments.assignments.iter().fold(
@@ -494,15 +2179,15 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
 
                                 let result = match tmp_env.eval_expr(expression) {
                                     Ok(r) => r,
-                                    Err(_err) => {
-                                        return Err(ReplError::EvalError);
+                                    Err(err) => {
+                                        return Err(eval_error(err));
                                     }
                                 };
 
                                 match matcher.match_pattern(pattern, &result) {
                                     Ok(_) => {
-                                        matcher.local_env.clone().merge(&mut bindings);
-                                        matcher.local_env.clone().merge(&mut tmp_env);
+                                        matcher.merge_into(&mut bindings);
+                                        matcher.into_env().merge(&mut tmp_env);
                                         Ok(Ok(tmp_env))
                                     }
                                     Err(e) => Ok(Err(e)),
@@ -528,8 +2213,8 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
             Statement::Literal(ex) => {
                 let result = match self.env.eval_expr(&ex) {
                     Ok(r) => r.to_expression(),
-                    Err(_err) => {
-                        return Err(ReplError::EvalError);
+                    Err(err) => {
+                        return Err(eval_error(err));
                     }
                 };
 
@@ -542,7 +2227,7 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
                 } else {
                     match con.sort_topological(self.env.identifiers()) {
                         Ok(con) => {
-                            self.bag_graph.connections.insert(name, con.clone());
+                            self.bag_graph.connect(name, con.clone());
                             Ok(ReplOutput::Notice(format!("Connection created:\n\n{con}"))) 
                         },
                         Err(e) => {
@@ -552,12 +2237,26 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
                 }
             },
             Statement::Disconnect(name) => {
-                if self.bag_graph.connections.remove(&name).is_some() {
+                if self.bag_graph.disconnect(&name) {
                     Ok(ReplOutput::Notice("Connection removed".into()))
                 } else {
                     Err(ReplError::ConnectionError)
                 }
             }
+            Statement::Trigger(connection, bag) => {
+                if !self.bag_graph.connections.contains_key(&connection) {
+                    return Err(ReplError::ConnectionError);
+                }
+                if !self.bag_bundle.bags.contains_key(&bag) {
+                    return Err(ReplError::BagError);
+                }
+
+                if self.bag_graph.add_trigger(connection, bag) {
+                    Ok(ReplOutput::Notice("Trigger created".into()))
+                } else {
+                    Ok(ReplOutput::Notice("Trigger already exists".into()))
+                }
+            }
             Statement::ListConnections => {
                 return Ok(ReplOutput::Notice(format!("Connections:\n\n{}\n\nUsing Bags: {:?}", self.bag_graph, self.bag_graph.bags())));
             },
@@ -572,23 +2271,692 @@ impl<'b, 'i, 's, 'v> Repl<'b, 'i, 's, 'v> {
                     Ok(ReplOutput::Notice(format!("Invalid, missing bags: {:?}", missing)))
                 }
             },
-            Statement::Solve(id, param) => {
-                let solver = GraphSolver::new(self.env.clone(), &self.bag_bundle);
-                let g = self.bag_graph.connections.clone();
-                if let Some(gg) = g.get(&id) {
-                    if let Ok(v) = self.env.eval_expr(&param) {
-                        for solution in solver.solve(gg, Some(v)) {
-                            println!("{solution:?}");
+            Statement::Explain(stmt) => {
+                let specialize = self.settings.specialize_queries;
+                let explanation = match *stmt {
+                    Statement::Query(query) => {
+                        let query = if specialize {
+                            query.specialize(&self.env)
+                        } else {
+                            query
+                        };
+                        let plans = query
+                            .predicate
+                            .patterns
+                            .iter()
+                            .zip(query.predicate.plans())
+                            .map(|(p, plan)| format!("\n  {p}: {plan}"))
+                            .collect::<String>();
+                        let fingerprint = fingerprint::statement(&Statement::Query(query.clone()));
+                        format!(
+                            "query on bag {}\n{query}plan:{plans}\nfingerprint: {fingerprint:016x}",
+                            self.current_bag
+                        )
+                    }
+                    Statement::Deletion(DeletionQuery { predicate }) => {
+                        let predicate = if specialize {
+                            predicate.specialize(&self.env)
+                        } else {
+                            predicate
+                        };
+                        format!("delete from bag {}\npredicate: {predicate}", self.current_bag)
+                    }
+                    Statement::Update(update) | Statement::Preview(update) => {
+                        let update = if specialize {
+                            update.specialize(&self.env)
+                        } else {
+                            update
+                        };
+                        format!(
+                            "change in bag {}\npredicate: {}\nprojection: {}",
+                            self.current_bag, update.predicate, update.projection
+                        )
+                    }
+                    Statement::Move(to, query) => {
+                        let query = if specialize {
+                            query.specialize(&self.env)
+                        } else {
+                            query
+                        };
+                        format!(
+                            "move from bag {} to bag {to}\npredicate: {}\nprojection: {}",
+                            self.current_bag, query.predicate, query.projection
+                        )
+                    }
+                    Statement::Insert(insertion) => {
+                        let mut lines = vec![format!("insert into bag {}", self.current_bag)];
+                        for expression in &insertion.expressions.expressions {
+                            let value = self
+                                .env
+                                .eval_expr(expression)
+                                .map_err(ReplError::EvalError)?;
+                            let rules = self.bag_graph.network.candidates(&self.current_bag, &value);
+                            if rules.is_empty() {
+                                lines.push(format!("{value}: no connection affected"));
+                            } else {
+                                lines.push(format!("{value}: tested by"));
+                                lines.extend(rules.iter().map(|r| format!("  {r}")));
+                            }
                         }
-                        return Ok(ReplOutput::Notice(format!("Solved")));
-                        
-                    } else {
-                        return Err(ReplError::EvalError);
+                        lines.join("\n")
                     }
-                } else {
+                    _ => "Only queries, insertions, deletions, changes and moves can be explained.".into(),
+                };
+
+                Ok(ReplOutput::Notice(explanation))
+            }
+            Statement::ExplainError(code) => Ok(ReplOutput::Notice(match error_catalog::lookup(&code) {
+                Some(entry) => format!("{}: {}\n{}", entry.code, entry.name, entry.explanation),
+                None => format!("Unknown error code {code}."),
+            })),
+            Statement::Ast(stmt) => Ok(ReplOutput::Notice(format!("{:#}", stmt.to_json()))),
+            Statement::ListSettings => Ok(ReplOutput::Notice(format!("{}", self.settings))),
+            Statement::ListVariables => {
+                let variables = self.env.variables();
+                if variables.is_empty() {
+                    return Ok(ReplOutput::Notice("No variables.".into()));
+                }
+
+                Ok(ReplOutput::Notice(
+                    variables
+                        .into_iter()
+                        .map(|(name, t)| format!("{name}: {t}"))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                ))
+            }
+            Statement::Unset(None) => {
+                self.env.clear();
+                Ok(ReplOutput::Ack)
+            }
+            Statement::Unset(Some(name)) => Ok(if self.env.unset(&name) {
+                ReplOutput::Ack
+            } else {
+                ReplOutput::No
+            }),
+            Statement::Custom(name, arguments) => {
+                let Some(custom) = self.custom(&name) else {
+                    return Err(ReplError::ParseError);
+                };
+                custom.execute(self, &arguments)
+            }
+            Statement::SaveEnv(name) => {
+                self.environments.insert(name, self.env.clone());
+                Ok(ReplOutput::Ack)
+            }
+            Statement::LoadEnv(name) => {
+                let Some(env) = self.environments.get(&name) else {
+                    return Ok(ReplOutput::No);
+                };
+                self.env = env.clone();
+                Ok(ReplOutput::Ack)
+            }
+            Statement::Stats(None) => Ok(ReplOutput::Notice(format!("Plan cache: {}", self.plan_cache))),
+            Statement::Stats(Some(bag)) => {
+                let statistics = self
+                    .bag_bundle
+                    .bags
+                    .get(&bag)
+                    .and_then(|b| b.statistics())
+                    .ok_or(ReplError::BagError)?;
+
+                Ok(ReplOutput::Notice(statistics.to_string().trim_end().to_string()))
+            }
+            Statement::Begin => {
+                if self.transaction.is_some() {
+                    return Err(ReplError::TransactionState);
+                }
+                self.transaction = Some(self.savepoint());
+                self.bag_bundle.begin();
+
+                Ok(ReplOutput::Notice("BEGIN.".to_string()))
+            }
+            Statement::Commit => {
+                if self.transaction.is_none() {
+                    return Err(ReplError::TransactionState);
+                }
+                self.bag_bundle
+                    .finish()
+                    .map_err(|_| ReplError::TranscationAborted)?;
+                self.transaction = None;
+
+                Ok(ReplOutput::Notice("COMMIT.".to_string()))
+            }
+            Statement::Rollback => {
+                let Some(savepoint) = self.transaction.take() else {
+                    return Err(ReplError::TransactionState);
+                };
+                self.restore(savepoint)?;
+
+                Ok(ReplOutput::Notice("ROLLBACK.".to_string()))
+            }
+            Statement::Profile(false) => Ok(ReplOutput::Notice(profile::report())),
+            Statement::Profile(true) => {
+                profile::reset();
+                Ok(ReplOutput::Ack)
+            }
+            Statement::Set(name, expression) => {
+                let value = self
+                    .env
+                    .eval_expr(&expression)
+                    .map_err(ReplError::EvalError)?;
+
+                self.settings
+                    .set(&name.name, &value)
+                    .map_err(|_| ReplError::SettingError)?;
+                self.plan_cache.clear();
+
+                Ok(ReplOutput::Ack)
+            }
+            Statement::CreateIndex(bag, field) => {
+                let mut trans = Transaction::new(&self.bag_bundle);
+                let count = trans
+                    .create_index(&bag, &field.name)
+                    .map_err(|_| ReplError::BagError)?;
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
+
+                Ok(ReplOutput::Notice(format!("INDEXED {count} items.")))
+            }
+            Statement::Cdc(bag, filename) => {
+                if !self.bag_bundle.bags.contains_key(&bag) {
+                    return Err(ReplError::BagError);
+                }
+                let Ok(cdc) = CdcFile::open(filename.as_ref()) else {
+                    return Err(ReplError::IoError);
+                };
+                let trans = Transaction::new(&self.bag_bundle);
+                let items = trans
+                    .read(&bag)
+                    .map_err(|_| ReplError::BagError)?
+                    .map(|v| v.as_ref().clone())
+                    .collect::<Vec<_>>();
+                cdc.snapshot(&bag, &items).map_err(|_| ReplError::IoError)?;
+                self.bag_bundle.observe(bag, Arc::new(cdc));
+
+                Ok(ReplOutput::Notice("CAPTURING CHANGES".into()))
+            }
+            Statement::Replicate(filename) => {
+                let env = self.statement_env();
+                let Ok(file) = File::open(filename.as_ref()) else {
+                    return Err(ReplError::IoError);
+                };
+                let mut trans = Transaction::new(&self.bag_bundle);
+                let insert = |trans: &mut Transaction<'b, 'i, 's, 'v>, bag: &Identifier<'s>, value: &Value<'s, 'v>| {
+                    let expression = value.to_expression();
+                    match trans.insert_one(bag, &env, &expression) {
+                        Ok(InsertionResult::Success(_)) => Ok(()),
+                        Ok(InsertionResult::GuardError) => Err(ReplError::GuardError),
+                        Ok(InsertionResult::EvalError(e)) => Err(ReplError::EvalError(e)),
+                        Err(_) => Err(ReplError::TranscationAborted),
+                    }
+                };
+
+                let mut counter = 0;
+                for l in io::BufReader::new(file).lines() {
+                    let Ok(line) = l else {
+                        return Err(ReplError::ReadError);
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let Some((bag, record)) = serde_json::from_str(&line)
+                        .ok()
+                        .and_then(|json| read_record(&json))
+                    else {
+                        return Err(ReplError::ParseError);
+                    };
+                    let bag = Identifier {
+                        name: Cow::Owned(bag),
+                    };
+                    trans
+                        .create_bag(bag.clone(), Predicate::any())
+                        .map_err(|_| ReplError::TranscationAborted)?;
+
+                    // Values are identified by equality: deleting a value
+                    // that is gone already is skipped and a snapshot only
+                    // changes the values that differ. As `.cdc` starts every
+                    // file with a snapshot, replaying it again is harmless.
+                    match record {
+                        ChangeRecord::Snapshot(mut items) => {
+                            let current = trans
+                                .read(&bag)
+                                .map_err(|_| ReplError::TranscationAborted)?
+                                .map(|v| v.as_ref().clone())
+                                .collect::<Vec<_>>();
+                            for value in current {
+                                if let Some(i) = items.iter().position(|v| v == &value) {
+                                    items.swap_remove(i);
+                                } else {
+                                    trans
+                                        .pop(&bag, &value)
+                                        .map_err(|_| ReplError::TranscationAborted)?;
+                                }
+                            }
+                            for value in &items {
+                                insert(&mut trans, &bag, value)?;
+                            }
+                        }
+                        ChangeRecord::Change(Mutation::Insert(after)) => {
+                            insert(&mut trans, &bag, &after)?;
+                        }
+                        ChangeRecord::Change(Mutation::Update { before, after }) => {
+                            if trans
+                                .pop(&bag, &before)
+                                .map_err(|_| ReplError::TranscationAborted)?
+                            {
+                                insert(&mut trans, &bag, &after)?;
+                            }
+                        }
+                        ChangeRecord::Change(Mutation::Delete(before)) => {
+                            trans
+                                .pop(&bag, &before)
+                                .map_err(|_| ReplError::TranscationAborted)?;
+                        }
+                    }
+                    counter += 1;
+                }
+
+                self.bag_bundle = trans.commit().map_err(commit_error)?;
+
+                Ok(ReplOutput::Notice(format!(
+                    "Replayed {counter} change records from file '{filename}'"
+                )))
+            }
+            Statement::DefineMacro(name, parameters, body) => {
+                if matches!(*body, Statement::DefineMacro(..) | Statement::ExpandMacro(..)) {
+                    return Err(ReplError::MacroError);
+                }
+
+                self.macros.insert(name, Macro { parameters, body: *body });
+
+                Ok(ReplOutput::Ack)
+            }
+            Statement::ExpandMacro(name, arguments) => {
+                let Some(definition) = self.macros.get(&name) else {
+                    // Without a macro of that name `!name` keeps meaning negation.
+                    let argument = match arguments {
+                        Some(arguments) => Expression::Call(CallExpression { function: name, arguments }),
+                        None => Expression::Identifier(name),
+                    };
+                    return self.execute_statement(Statement::Eval(ExpressionSet {
+                        expressions: vec![Expression::Unary(UnaryExpression {
+                            operator: UnaryOperator::Not,
+                            argument: Box::new(argument),
+                        })],
+                    }));
+                };
+
+                let arguments = arguments.unwrap_or_default();
+                if arguments.len() != definition.parameters.len() {
+                    return Err(ReplError::MacroError);
+                }
+
+                let mut env = Environment::new();
+                for (parameter, argument) in definition.parameters.iter().zip(&arguments) {
+                    let value = self.env.eval_expr(argument).map_err(ReplError::EvalError)?;
+                    env.bindings.insert(Identifier { name: Cow::Owned(parameter.name.to_string()) }, value);
+                }
+
+                let body = definition.body.specialize(&env);
+                if !self.permits(&body) {
+                    return Err(ReplError::Denied);
+                }
+                self.execute_statement(body)
+            }
+            Statement::Prepare(name, body) => {
+                if matches!(
+                    *body,
+                    Statement::DefineMacro(..)
+                        | Statement::ExpandMacro(..)
+                        | Statement::Prepare(..)
+                        | Statement::Prepared(..)
+                ) {
+                    return Err(ReplError::PreparedError);
+                }
+
+                let aggregates = match &*body {
+                    Statement::Query(query) => query.aggregates.iter().map(|a| &a.binding).collect(),
+                    _ => vec![],
+                };
+                let (expressions, patterns) = statement_parts(&body);
+                let parameters: BTreeSet<_> = expressions
+                    .into_iter()
+                    .chain(patterns.iter().flat_map(|p| p.get_expressions()))
+                    .flat_map(|e| e.get_identifiers())
+                    .filter(|id| is_parameter(&id.name) && !aggregates.contains(id))
+                    .map(|id| Identifier { name: Cow::Owned(id.name.to_string()) })
+                    .collect();
+
+                // Cyclic bindings are rejected when preparing rather than when executing.
+                let body = match *body {
+                    Statement::AssignSet(set) => Statement::AssignSet(
+                        set.sort_topological(self.env.identifiers().into_iter().chain(&parameters).collect())
+                            .map_err(|_| ReplError::AssignmentError)?,
+                    ),
+                    Statement::MatchSet(set) => Statement::MatchSet(
+                        set.sort_topological(self.env.identifiers().into_iter().chain(&parameters).collect())
+                            .map_err(|_| ReplError::AssignmentError)?,
+                    ),
+                    other => other,
+                };
+
+                self.prepared.insert(name, PreparedStatement { parameters, body });
+
+                Ok(ReplOutput::Ack)
+            }
+            Statement::Prepared(name, arguments) => {
+                let Some(prepared) = self.prepared.get(&name) else {
+                    return Err(ReplError::PreparedError);
+                };
+
+                let arguments = match arguments {
+                    Some(a) => self.env.eval_expr(&a).map_err(ReplError::EvalError)?,
+                    None => Value::Object(BTreeMap::new()),
+                };
+                let Value::Object(arguments) = arguments else {
+                    return Err(ReplError::PreparedError);
+                };
+                if arguments.len() != prepared.parameters.len() {
+                    return Err(ReplError::PreparedError);
+                }
+
+                let mut env = Environment::new();
+                for (key, value) in arguments {
+                    let parameter = Identifier { name: Cow::Owned(format!("${key}")) };
+                    if !prepared.parameters.contains(&parameter) {
+                        return Err(ReplError::PreparedError);
+                    }
+                    env.bindings.insert(parameter, value.into_owned());
+                }
+
+                let body = prepared.body.specialize(&env);
+                if !self.permits(&body) {
+                    return Err(ReplError::Denied);
+                }
+                self.execute_statement(body)
+            }
+            Statement::Merge(source, target, key, preference) => {
+                let env = self.statement_env();
+                let mut trans = Transaction::new(&self.bag_bundle);
+                let result = trans
+                    .merge_bag(&source, &target, &env, &key, &preference)
+                    .map_err(|e| match e {
+                        TransactionError::BagDoesNotExist => ReplError::BagError,
+                        _ => ReplError::TranscationAborted,
+                    })?;
+
+                match result {
+                    MergeResult::Success(counts) => {
+                        self.fire_triggers(&mut trans, &env, BTreeSet::from([target]))?;
+                        self.bag_bundle = trans.commit().map_err(commit_error)?;
+                        Ok(ReplOutput::Notice(format!(
+                            "MERGED: {} inserted, {} updated, {} skipped.",
+                            counts.inserted, counts.updated, counts.skipped
+                        )))
+                    }
+                    MergeResult::GuardError => Err(ReplError::GuardError),
+                    MergeResult::EvalError(e) => Err(ReplError::EvalError(e)),
+                }
+            }
+            Statement::Delta(before, after, key) => {
+                let key = match key.map(|k| self.env.eval_expr(&k)).transpose() {
+                    Ok(None) => None,
+                    Ok(Some(Value::Closure(closure))) => Some(closure),
+                    Ok(Some(_)) => return Err(ReplError::EvalError(EvalError::TypeError)),
+                    Err(e) => return Err(ReplError::EvalError(e)),
+                };
+                // Virtual bags are read as well, unbounded ones fail.
+                let env = self.statement_env();
+                let read = |bag| match self.bag_bundle.bag(bag) {
+                    Some(bag) => bag.matching(&env, &Predicate::any()).map_err(ReplError::EvalError),
+                    None => Err(ReplError::BagError),
+                };
+                let before = read(&before)?;
+                let after = read(&after)?;
+                let delta =
+                    delta::delta(&before, &after, key.as_deref()).map_err(ReplError::EvalError)?;
+
+                Ok(ReplOutput::Values(vec![delta.to_value()]))
+            }
+            Statement::Solve(id, param) => {
+                let Some(connection) = self.bag_graph.connections.get(&id) else {
                     return Ok(ReplOutput::Notice(format!("connection not defined")));
+                };
+                let argument = self.env.eval_expr(&param).map_err(ReplError::EvalError)?;
+
+                let changesets = {
+                    let solver = GraphSolver::new(self.statement_env(), &self.bag_bundle);
+                    let solutions = if self.settings.incremental_solve {
+                        solver.solve_incremental(connection, Some(argument))
+                    } else {
+                        solver.solve(connection, Some(argument))
+                    };
+                    graph_solver::compatible(solutions)
+                };
+
+                self.apply_solutions(&id, &changesets)
+            }
+            Statement::SolveStep(id, param) => {
+                if self.stepping.is_some() {
+                    return Err(ReplError::SteppingState);
                 }
-            },
+                let Some(connection) = self.bag_graph.connections.get(&id) else {
+                    return Ok(ReplOutput::Notice(format!("connection not defined")));
+                };
+                let argument = self.env.eval_expr(&param).map_err(ReplError::EvalError)?;
+
+                let stepping = {
+                    let solver = GraphSolver::new(self.statement_env(), &self.bag_bundle);
+                    let solutions = solver
+                        .solutions(connection, Some(argument), self.settings.incremental_solve)
+                        .collect();
+                    Stepping::new(&id, connection, &self.bag_bundle, solutions)
+                };
+
+                match stepping.current() {
+                    Some(candidate) => {
+                        self.stepping = Some(stepping);
+                        Ok(ReplOutput::Candidate(Box::new(candidate)))
+                    }
+                    None => Ok(ReplOutput::Solved(vec![])),
+                }
+            }
+            Statement::Step(action) => {
+                let Some(stepping) = &mut self.stepping else {
+                    return self.resume(action);
+                };
+                if action != StepAction::Abort {
+                    stepping.step(action);
+                    if let Some(candidate) = stepping.current() {
+                        return Ok(ReplOutput::Candidate(Box::new(candidate)));
+                    }
+                }
+
+                self.end_stepping(action != StepAction::Abort)
+            }
+            Statement::Break(breakpoint) => {
+                self.breakpoints.push(breakpoint);
+                Ok(ReplOutput::Notice("BREAKPOINT SET".into()))
+            }
+            Statement::ListBreakpoints => Ok(ReplOutput::Notice(
+                self.breakpoints
+                    .iter()
+                    .map(|b| b.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )),
+            Statement::ClearBreakpoints => {
+                self.breakpoints.clear();
+                Ok(ReplOutput::Notice("BREAKPOINTS REMOVED".into()))
+            }
+        }
+    }
+
+    // Applies the change sets found for a connection, along with those of
+    // the connections triggered by their insertions.
+    fn apply_solutions(
+        &mut self,
+        connection: &Identifier<'s>,
+        changesets: &[ChangeSet<'s, 'v>],
+    ) -> Result<ReplOutput<'i, 's, 'v>, ReplError> {
+        let env = self.statement_env();
+        let mut trans = Transaction::new(&self.bag_bundle);
+        let produced = changesets.iter().map(|c| (Some(connection), c)).collect::<Vec<_>>();
+        trans
+            .apply(&env, &produced)
+            .map_err(|_| ReplError::TranscationAborted)?;
+        let inserted = changesets
+            .iter()
+            .flat_map(|c| c.insertions.keys().cloned())
+            .collect();
+        self.fire_triggers(&mut trans, &env, inserted)?;
+        self.bag_bundle = trans.commit().map_err(commit_error)?;
+
+        Ok(ReplOutput::Solved(
+            graph_solver::counts(changesets)
+                .into_iter()
+                .map(|(bag, (deleted, inserted))| (bag, deleted, inserted))
+                .collect(),
+        ))
+    }
+
+    // Applies the accepted candidates, unless aborted, and then runs the
+    // statements queued while stepping. If applying fails the queued
+    // statements are dropped. If it hits a breakpoint they stay queued until
+    // `.continue`.
+    fn end_stepping(&mut self, apply: bool) -> Result<ReplOutput<'i, 's, 'v>, ReplError> {
+        let Some(stepping) = self.stepping.take() else {
+            return Err(ReplError::SteppingState);
+        };
+        let (connection, accepted, queue) = stepping.finish();
+        let watched = self.watched_bags();
+        let start = watched.as_ref().map(|bags| self.bag_bundle.watch(bags));
+        let output = if apply {
+            self.apply_solutions(&connection, &accepted)
+        } else {
+            Ok(ReplOutput::Notice("ABORTED.".into()))
+        };
+        let journal = watched
+            .as_ref()
+            .zip(start)
+            .map(|(bags, start)| self.bag_bundle.unwatch(bags, start));
+        let output = output?;
+        if let Some(hit) = journal.and_then(|journal| self.breakpoint_hit(journal, ".continue".into())) {
+            self.paused = Some(Pause::new(hit, queue));
+            return Ok(output);
+        }
+        if queue.is_empty() {
+            return Ok(output);
+        }
+
+        let queued = self.run_queue(queue)?;
+        Ok(ReplOutput::Resumed(Box::new(output), queued))
+    }
+
+    // Runs the statements paused by a breakpoint on `.continue`, drops them
+    // on `.abort`.
+    fn resume(&mut self, action: StepAction) -> Result<ReplOutput<'i, 's, 'v>, ReplError> {
+        if action == StepAction::Skip {
+            return Err(ReplError::SteppingState);
+        }
+        let Some(paused) = self.paused.take() else {
+            return Err(ReplError::SteppingState);
+        };
+        let queue = paused.finish();
+        if action == StepAction::Abort {
+            return Ok(ReplOutput::Notice(format!(
+                "ABORTED, {} queued statements dropped.",
+                queue.len()
+            )));
+        }
+
+        let output = ReplOutput::Notice("RESUMED.".into());
+        if queue.is_empty() {
+            return Ok(output);
+        }
+        let queued = self.run_queue(queue)?;
+        Ok(ReplOutput::Resumed(Box::new(output), queued))
+    }
+
+    // Runs the queued statements one after another. Once one of them hits a
+    // breakpoint the others are queued again. A failing statement is
+    // reported as `ScriptFailed` with its number, counted from 1.
+    fn run_queue(
+        &mut self,
+        queue: Vec<Statement<'s, 's>>,
+    ) -> Result<Vec<ReplOutput<'i, 's, 'v>>, ReplError> {
+        let mut outputs = Vec::with_capacity(queue.len());
+        for (index, stmt) in queue.into_iter().enumerate() {
+            if let Some(paused) = &mut self.paused {
+                paused.enqueue(stmt);
+                continue;
+            }
+            let output = self.execute(stmt).map_err(|e| {
+                ReplError::ScriptFailed(index + 1, format!("Error[{}]: {e:?}", e.code()))
+            })?;
+            outputs.push(output);
+        }
+
+        Ok(outputs)
+    }
+
+    fn savepoint(&self) -> Savepoint<'b, 'i, 's, 'v> {
+        Savepoint {
+            bag_bundle: self.bag_bundle.clone(),
+            current_bag: self.current_bag.clone(),
+            env: self.env.clone(),
+            macros: self.macros.clone(),
+            prepared: self.prepared.clone(),
+            bag_graph: self.bag_graph.clone(),
+            settings: self.settings.clone(),
+        }
+    }
+
+    // Goes back to the savepoint. The bags are kept if others committed
+    // meanwhile, see `BagBundle::restore`.
+    fn restore(&mut self, savepoint: Savepoint<'b, 'i, 's, 'v>) -> Result<(), ReplError> {
+        self.current_bag = savepoint.current_bag;
+        self.env = savepoint.env;
+        self.macros = savepoint.macros;
+        self.prepared = savepoint.prepared;
+        self.bag_graph = savepoint.bag_graph;
+        self.settings = savepoint.settings;
+
+        self.bag_bundle.restore(savepoint.bag_bundle).map_err(commit_error)
+    }
+
+    // The bags with breakpoints, None if breakpoints do not pause the
+    // statements.
+    fn watched_bags(&self) -> Option<Vec<Identifier<'s>>> {
+        if !self.interactive || self.breakpoints.is_empty() {
+            return None;
         }
+
+        Some(self.breakpoints.iter().map(|b| b.bag.clone()).collect())
+    }
+
+    // The first value of the journal inserted into a bag that matches a
+    // breakpoint, attributed to the statement unless a connection produced
+    // it.
+    fn breakpoint_hit(&self, journal: Vec<Journaled<'s, 'v>>, statement: String) -> Option<Hit<'s, 'v>> {
+        journal.into_iter().find_map(|(bag, mutation, producer)| {
+            let Mutation::Insert(value) = mutation else {
+                return None;
+            };
+            if !self.breakpoints.iter().any(|b| b.bag == bag && b.matches(&value)) {
+                return None;
+            }
+            let origin = match producer {
+                Some(connection) => Origin::Connection(connection.to_string()),
+                None => Origin::Statement(statement.clone()),
+            };
+
+            Some(Hit {
+                bag,
+                value,
+                origin,
+                queued: 0,
+            })
+        })
     }
 }
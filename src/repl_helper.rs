@@ -0,0 +1,78 @@
+use std::borrow::Cow;
+
+use rustyline::{
+    completion::{Completer, Pair},
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::{ValidationContext, ValidationResult, Validator},
+    Context, Helper,
+};
+
+use crate::completion::CompletionContext;
+use crate::parser::is_incomplete;
+use crate::presentation::Theme;
+
+#[derive(Default)]
+pub struct ReplHelper {
+    pub context: CompletionContext,
+    // Whether the input is highlighted as it is typed.
+    pub color: bool,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let completions = self.context.complete(line, pos);
+
+        Ok((
+            completions.start,
+            completions
+                .candidates
+                .into_iter()
+                .map(|c| Pair {
+                    display: format!("{} ({})", c.text, c.kind),
+                    replacement: c.text,
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if self.color {
+            Cow::Owned(Theme::COLORED.input(line))
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    // Typing a bracket can change the color of one far before it.
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        self.color
+    }
+}
+
+// Enter continues an incomplete statement on the next line instead of
+// submitting it.
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(if is_incomplete(ctx.input()) {
+            ValidationResult::Incomplete
+        } else {
+            ValidationResult::Valid(None)
+        })
+    }
+}
+
+impl Helper for ReplHelper {}
@@ -0,0 +1,195 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+};
+
+use crate::{
+    error_catalog::ErrorCode,
+    parser::is_blank,
+    repl::{Repl, ReplError},
+    session::Session,
+};
+
+// The longest line a client may send, so that a client that never ends a
+// line, eg before it is authenticated, can not exhaust the memory.
+pub const MAX_LINE: u64 = 1 << 20;
+
+pub type SharedRepl = Arc<Mutex<Repl<'static, 'static, 'static, 'static>>>;
+
+fn error(code: Option<&str>, message: &str) -> serde_json::Value {
+    serde_json::json!({
+        "status": "error",
+        "code": code,
+        "message": message,
+    })
+}
+
+// Executes one line of input and describes the outcome as JSON. Returns
+// `None` when the client asked to close the connection via `.exit`.
+pub fn respond(repl: &mut Repl, line: &str) -> Option<serde_json::Value> {
+    let statement = match repl.parse(line) {
+        Ok(s) => s,
+        Err(e) => return Some(error(Some(e.code()), &e.to_string())),
+    };
+
+    match repl.execute_measured(statement) {
+        Ok((output, metrics)) => Some(serde_json::json!({
+            "status": "ok",
+            "result": output.to_json(),
+            "metrics": metrics.to_json(),
+        })),
+        Err(ReplError::Exit) => None,
+        Err(e) => Some(error(Some(e.code()), &e.to_string())),
+    }
+}
+
+// Expects the token on the first line, then reads one statement per line and
+// answers each with one line of JSON until the client disconnects. Each
+// client has a session of its own, but the repl is locked per statement, so
+// statements of different clients are executed one after the other.
+pub fn serve<'b, 'i, 's, 'v>(
+    repl: &Mutex<Repl<'b, 'i, 's, 'v>>,
+    token: &str,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<()> {
+    let first = match read_line(&mut input) {
+        Ok(line) => line.unwrap_or_default(),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => String::new(),
+        Err(e) => return Err(e),
+    };
+    if !constant_time_eq(&first, token) {
+        writeln!(output, "{}", error(None, "Authentication required"))?;
+        return Ok(());
+    }
+    writeln!(output, "{}", serde_json::json!({"status": "ok", "result": null}))?;
+    output.flush()?;
+
+    let mut session = Session::new(&*lock(repl)?);
+    let result = serve_session(repl, &mut session, input, output);
+    session.close(&mut *lock(repl)?);
+
+    result
+}
+
+fn serve_session<'b, 'i, 's, 'v>(
+    repl: &Mutex<Repl<'b, 'i, 's, 'v>>,
+    session: &mut Session<'b, 'i, 's, 'v>,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<()> {
+    loop {
+        let line = match read_line(&mut input) {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                writeln!(output, "{}", error(None, &e.to_string()))?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if is_blank(&line) {
+            continue;
+        }
+
+        let response = session.run(&mut *lock(repl)?, |repl| respond(repl, &line));
+        let Some(response) = response else {
+            break;
+        };
+        writeln!(output, "{response}")?;
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+// Reads one line without its line break, None at the end of the input.
+// Lines longer than `MAX_LINE` are rejected as `InvalidData`, as is input
+// that is not UTF-8.
+fn read_line(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = vec![];
+    input.take(MAX_LINE + 1).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() as u64 > MAX_LINE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"));
+    }
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn lock<'r, 'b, 'i, 's, 'v>(
+    repl: &'r Mutex<Repl<'b, 'i, 's, 'v>>,
+) -> io::Result<MutexGuard<'r, Repl<'b, 'i, 's, 'v>>> {
+    repl.lock()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "repl poisoned"))
+}
+
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a
+            .bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+// A hard to guess token of 32 hex digits, eg to authenticate clients, made
+// of random bytes of the operating system.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("the operating system provides random bytes");
+
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn listen_tcp(repl: SharedRepl, token: Arc<str>, address: &str) -> io::Result<()> {
+    for stream in TcpListener::bind(address)?.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let repl = repl.clone();
+        let token = token.clone();
+        thread::spawn(move || {
+            let input = BufReader::new(stream.try_clone()?);
+            serve(&repl, &token, input, stream)
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn listen_unix(repl: SharedRepl, token: Arc<str>, path: &str) -> io::Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+    // A socket left behind by a previous run would make binding fail.
+    if std::fs::metadata(path).map_or(false, |m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+
+    for stream in UnixListener::bind(path)?.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let repl = repl.clone();
+        let token = token.clone();
+        thread::spawn(move || {
+            let input = BufReader::new(stream.try_clone()?);
+            serve(&repl, &token, input, stream)
+        });
+    }
+
+    Ok(())
+}
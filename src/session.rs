@@ -0,0 +1,89 @@
+use std::mem;
+
+use crate::bag_bundle::BagBundle;
+use crate::breakpoint::Pause;
+use crate::debugger::Stepping;
+use crate::env::Environment;
+use crate::identifier::Identifier;
+use crate::repl::{Repl, Savepoint};
+use crate::statement::Statement;
+
+// The state of one of several clients sharing a repl, eg the connections of
+// `server::serve`, so that their bindings, current bags, transactions,
+// steppings through connections and `$history` do not interleave. The bags
+// are shared, except while a transaction begun via `.begin` is open: the
+// client then works on its own copy that replaces the shared bags once
+// committed. The statements it commits in the meantime advance the version,
+// so those of the other clients fail with a conflict.
+pub struct Session<'b, 'i, 's, 'v> {
+    env: Environment<'i, 's, 'v>,
+    current_bag: Identifier<'s>,
+    transaction: Option<Savepoint<'b, 'i, 's, 'v>>,
+    // The bags changed by the open transaction.
+    bag_bundle: Option<BagBundle<'b, 'i, 's, 'v>>,
+    history: Vec<String>,
+    stepping: Option<Stepping<'s, 'v>>,
+    paused: Option<Pause<'s, 'v>>,
+}
+
+impl<'b, 'i, 's, 'v> Session<'b, 'i, 's, 'v> {
+    pub fn new(repl: &Repl<'b, 'i, 's, 'v>) -> Self {
+        Self {
+            env: Environment::new(),
+            current_bag: repl.current_bag.clone(),
+            transaction: None,
+            bag_bundle: None,
+            history: vec![],
+            stepping: None,
+            paused: None,
+        }
+    }
+
+    // Runs `f` on the repl as seen by the session.
+    pub fn run<R>(
+        &mut self,
+        repl: &mut Repl<'b, 'i, 's, 'v>,
+        f: impl FnOnce(&mut Repl<'b, 'i, 's, 'v>) -> R,
+    ) -> R {
+        let shared = self
+            .bag_bundle
+            .take()
+            .map(|b| mem::replace(&mut repl.bag_bundle, b));
+        self.swap(repl);
+        let result = f(repl);
+        self.swap(repl);
+
+        match (&self.transaction, shared) {
+            (Some(_), Some(shared)) => {
+                self.bag_bundle = Some(mem::replace(&mut repl.bag_bundle, shared));
+            }
+            // The bags as of `.begin` are still those of everyone else.
+            (Some(savepoint), None) => {
+                let bundle = savepoint.bag_bundle.clone();
+                self.bag_bundle = Some(mem::replace(&mut repl.bag_bundle, bundle));
+            }
+            // Committed or rolled back, the bags of the session are shared,
+            // unless a conflict left them behind those of the others.
+            (None, Some(shared)) if !repl.bag_bundle.is_latest() => repl.bag_bundle = shared,
+            (None, _) => {}
+        }
+
+        result
+    }
+
+    // Ends the session, rolling back its open transaction.
+    pub fn close(mut self, repl: &mut Repl<'b, 'i, 's, 'v>) {
+        if self.transaction.is_some() {
+            let _ = self.run(repl, |repl| repl.execute(Statement::Rollback));
+        }
+    }
+
+    fn swap(&mut self, repl: &mut Repl<'b, 'i, 's, 'v>) {
+        mem::swap(&mut self.env, &mut repl.env);
+        mem::swap(&mut self.current_bag, &mut repl.current_bag);
+        mem::swap(&mut self.transaction, &mut repl.transaction);
+        mem::swap(&mut self.history, &mut repl.history);
+        mem::swap(&mut self.stepping, &mut repl.stepping);
+        mem::swap(&mut self.paused, &mut repl.paused);
+    }
+}
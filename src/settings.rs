@@ -0,0 +1,203 @@
+use std::borrow::Cow;
+
+use crate::limits::{Budget, Limits};
+use crate::log;
+use crate::value::Value;
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub specialize_queries: bool,
+    pub memoize: bool,
+    pub incremental_solve: bool,
+    pub bare_bindings: bool,
+    // Unknown identifiers evaluate to `null`, with a warning.
+    pub lenient: bool,
+    pub verbose: bool,
+    pub color: bool,
+    // Counts how often the operands of guards are evaluated, see `.profile`.
+    pub profile: bool,
+    // The bag `.log` appends to, created on first use.
+    pub log_bag: String,
+    // Statements deleting or changing more values than this have to be
+    // confirmed by the frontend.
+    pub confirm_threshold: Option<usize>,
+    pub limits: Limits,
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    UnknownSetting,
+    InvalidValue,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            specialize_queries: true,
+            memoize: false,
+            incremental_solve: false,
+            bare_bindings: false,
+            lenient: false,
+            verbose: false,
+            color: false,
+            profile: false,
+            log_bag: log::DEFAULT_BAG.to_string(),
+            confirm_threshold: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn set(&mut self, name: &str, value: &Value) -> Result<(), SettingsError> {
+        match name {
+            "specialize" => {
+                let Value::Boolean(b) = value else {
+                    return Err(SettingsError::InvalidValue);
+                };
+                self.specialize_queries = *b;
+            }
+            "memoize" => {
+                let Value::Boolean(b) = value else {
+                    return Err(SettingsError::InvalidValue);
+                };
+                self.memoize = *b;
+            }
+            "incremental" => {
+                let Value::Boolean(b) = value else {
+                    return Err(SettingsError::InvalidValue);
+                };
+                self.incremental_solve = *b;
+            }
+            "bare_bindings" => {
+                let Value::Boolean(b) = value else {
+                    return Err(SettingsError::InvalidValue);
+                };
+                self.bare_bindings = *b;
+            }
+            "lenient" => {
+                let Value::Boolean(b) = value else {
+                    return Err(SettingsError::InvalidValue);
+                };
+                self.lenient = *b;
+            }
+            "verbose" => {
+                let Value::Boolean(b) = value else {
+                    return Err(SettingsError::InvalidValue);
+                };
+                self.verbose = *b;
+            }
+            "color" => {
+                let Value::Boolean(b) = value else {
+                    return Err(SettingsError::InvalidValue);
+                };
+                self.color = *b;
+            }
+            "profile" => {
+                let Value::Boolean(b) = value else {
+                    return Err(SettingsError::InvalidValue);
+                };
+                self.profile = *b;
+            }
+            "log_bag" => {
+                let Value::String(name) = value else {
+                    return Err(SettingsError::InvalidValue);
+                };
+                if name.is_empty() {
+                    return Err(SettingsError::InvalidValue);
+                }
+                self.log_bag = name.to_string();
+            }
+            "confirm_threshold" => {
+                self.confirm_threshold = match value {
+                    Value::Null => None,
+                    value => Some(limit_value(value)?),
+                };
+            }
+            "max_nesting" => self.limits.max_nesting = limit_value(value)?,
+            "max_expression_depth" => self.limits.max_expression_depth = limit_value(value)?,
+            "max_pattern_depth" => self.limits.max_pattern_depth = limit_value(value)?,
+            "max_template_nesting" => self.limits.max_template_nesting = limit_value(value)?,
+            "budget" => {
+                let Value::Boolean(b) = value else {
+                    return Err(SettingsError::InvalidValue);
+                };
+                self.limits.budget = b.then(Budget::default);
+            }
+            "max_input_length" => self.budget().max_input_length = limit_value(value)?,
+            "max_nodes" => self.budget().max_nodes = limit_value(value)?,
+            "max_join_arity" => self.budget().max_join_arity = limit_value(value)?,
+            "max_array_items" => self.budget().max_array_items = limit_value(value)?,
+            _ => return Err(SettingsError::UnknownSetting),
+        }
+
+        Ok(())
+    }
+
+    fn budget(&mut self) -> &mut Budget {
+        self.limits.budget.get_or_insert_with(Budget::default)
+    }
+
+    pub fn entries<'s, 'v>(&self) -> Vec<(&'static str, Value<'s, 'v>)> {
+        let mut entries = vec![
+            ("specialize", Value::Boolean(self.specialize_queries)),
+            ("memoize", Value::Boolean(self.memoize)),
+            ("incremental", Value::Boolean(self.incremental_solve)),
+            ("bare_bindings", Value::Boolean(self.bare_bindings)),
+            ("lenient", Value::Boolean(self.lenient)),
+            ("verbose", Value::Boolean(self.verbose)),
+            ("color", Value::Boolean(self.color)),
+            ("profile", Value::Boolean(self.profile)),
+            ("log_bag", Value::String(Cow::Owned(self.log_bag.clone()))),
+            (
+                "confirm_threshold",
+                self.confirm_threshold
+                    .map_or(Value::Null, |t| Value::Integer(t as i64)),
+            ),
+            ("max_nesting", Value::Integer(self.limits.max_nesting as i64)),
+            (
+                "max_expression_depth",
+                Value::Integer(self.limits.max_expression_depth as i64),
+            ),
+            (
+                "max_pattern_depth",
+                Value::Integer(self.limits.max_pattern_depth as i64),
+            ),
+            (
+                "max_template_nesting",
+                Value::Integer(self.limits.max_template_nesting as i64),
+            ),
+            ("budget", Value::Boolean(self.limits.budget.is_some())),
+        ];
+
+        if let Some(budget) = &self.limits.budget {
+            entries.extend([
+                (
+                    "max_input_length",
+                    Value::Integer(budget.max_input_length as i64),
+                ),
+                ("max_nodes", Value::Integer(budget.max_nodes as i64)),
+                ("max_join_arity", Value::Integer(budget.max_join_arity as i64)),
+                ("max_array_items", Value::Integer(budget.max_array_items as i64)),
+            ]);
+        }
+
+        entries
+    }
+}
+
+fn limit_value(value: &Value) -> Result<usize, SettingsError> {
+    match value {
+        Value::Integer(i) if *i > 0 => Ok(*i as usize),
+        _ => Err(SettingsError::InvalidValue),
+    }
+}
+
+impl std::fmt::Display for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, value) in self.entries() {
+            writeln!(f, "{name} = {value}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bag::{StorageMode, ValueBag},
+    bag_bundle::BagBundle,
+    expression::Expression,
+    query::Predicate,
+    virtual_bag::{IntegerRange, VirtualSource},
+};
+
+// Incremented whenever the layout changes in a way older versions can not
+// read.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io,
+    Malformed,
+    UnsupportedVersion(u32),
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(_: io::Error) -> Self {
+        SnapshotError::Io
+    }
+}
+
+// The whole content of a bag bundle. Values are stored as the expressions
+// that evaluate to them, so that no value is lost on the way, not even
+// closures or patterns.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot<'s> {
+    pub version: u32,
+    pub bags: Vec<BagSnapshot<'s>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BagSnapshot<'s> {
+    pub name: String,
+    pub predicate: Predicate<'s>,
+    #[serde(default)]
+    pub mode: StorageMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<IntegerRange>,
+    // The bags a view is the union of.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub union: Vec<String>,
+    #[serde(default)]
+    pub indexes: Vec<String>,
+    #[serde(default)]
+    pub values: Vec<Expression<'s>>,
+}
+
+// Bags registered by the host, backed by a generator or by a backend, are
+// not part of a snapshot and are kept when one is restored. Neither are
+// system bags.
+pub(crate) fn is_saved(bag: &ValueBag) -> bool {
+    !matches!(
+        bag.source,
+        Some(VirtualSource::External(_) | VirtualSource::System(_))
+    ) && bag.backend.is_none()
+}
+
+impl<'s> Snapshot<'s> {
+    pub fn of(bundle: &BagBundle<'_, '_, 's, '_>) -> Self {
+        let bags = bundle
+            .bag_names()
+            .into_iter()
+            .filter_map(|name| {
+                let bag = bundle.bags.get(&name).filter(|b| is_saved(b))?;
+                let range = match &bag.source {
+                    Some(VirtualSource::Range(range)) => Some(*range),
+                    _ => None,
+                };
+                let union = match &bag.source {
+                    Some(VirtualSource::Union(bags, _)) => {
+                        bags.iter().map(|b| b.name.to_string()).collect()
+                    }
+                    _ => vec![],
+                };
+
+                Some(BagSnapshot {
+                    name: name.name.to_string(),
+                    predicate: bag.guard.clone(),
+                    mode: bag.mode,
+                    range,
+                    union,
+                    indexes: bag.indexes.keys().cloned().collect(),
+                    values: if bag.is_virtual() {
+                        vec![]
+                    } else {
+                        bag.iter().map(|v| v.to_expression()).collect()
+                    },
+                })
+            })
+            .collect();
+
+        Self {
+            version: VERSION,
+            bags,
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> Result<(), SnapshotError> {
+        serde_json::to_writer_pretty(&mut out, self).map_err(|_| SnapshotError::Io)?;
+        writeln!(out)?;
+        out.flush()?;
+
+        Ok(())
+    }
+
+    pub fn read<R: Read>(input: R) -> Result<Self, SnapshotError> {
+        let json: serde_json::Value =
+            serde_json::from_reader(input).map_err(|_| SnapshotError::Malformed)?;
+
+        // The version is checked first, so that a snapshot of a newer
+        // version is not reported as malformed.
+        let version = json
+            .get("version")
+            .and_then(serde_json::Value::as_u64)
+            .ok_or(SnapshotError::Malformed)?;
+        if version != VERSION as u64 {
+            return Err(SnapshotError::UnsupportedVersion(version as u32));
+        }
+
+        serde_json::from_value(json).map_err(|_| SnapshotError::Malformed)
+    }
+}
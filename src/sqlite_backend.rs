@@ -0,0 +1,211 @@
+use std::{borrow::Cow, collections::BTreeMap, path::Path, sync::Mutex};
+
+use rusqlite::{params_from_iter, types::Value as SqlValue, Connection, Transaction};
+
+use crate::{
+    backend::{BackendError, BagBackend, ScanFilter},
+    value::Value,
+};
+
+// Proxies a table: every row is an object with one key per column.
+// Only null, integer and string values can be read and written, a table
+// holding eg a REAL fails to be scanned.
+pub struct SqliteBackend {
+    connection: Mutex<Connection>,
+    table: String,
+    columns: Vec<String>,
+}
+
+fn failed(e: rusqlite::Error) -> BackendError {
+    BackendError::Failed(e.to_string())
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn to_sql(value: &Value) -> Result<SqlValue, BackendError> {
+    Ok(match value {
+        Value::Null => SqlValue::Null,
+        Value::Integer(i) => SqlValue::Integer(*i),
+        Value::String(s) => SqlValue::Text(s.to_string()),
+        _ => return Err(BackendError::Unsupported),
+    })
+}
+
+fn from_sql<'s, 'v>(value: SqlValue) -> Result<Value<'s, 'v>, BackendError> {
+    Ok(match value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(i) => Value::Integer(i),
+        SqlValue::Text(s) => Value::String(Cow::Owned(s)),
+        SqlValue::Real(_) | SqlValue::Blob(_) => return Err(BackendError::Unsupported),
+    })
+}
+
+impl SqliteBackend {
+    pub fn open(path: impl AsRef<Path>, table: &str) -> Result<Self, BackendError> {
+        Self::new(Connection::open(path).map_err(failed)?, table)
+    }
+
+    pub fn new(connection: Connection, table: &str) -> Result<Self, BackendError> {
+        let columns = connection
+            .prepare(&format!("PRAGMA table_info({})", quote(table)))
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], |row| row.get::<_, String>(1))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(failed)?;
+
+        if columns.is_empty() {
+            return Err(BackendError::Failed(format!("Table {table} does not exist")));
+        }
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            table: table.to_string(),
+            columns,
+        })
+    }
+
+    fn insert_rows(&self, transaction: &Transaction, values: &[Value]) -> Result<(), BackendError> {
+        let rows = values
+            .iter()
+            .map(|v| self.row_values(v))
+            .collect::<Result<Vec<_>, _>>()?;
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote(&self.table),
+            self.columns.iter().map(|c| quote(c)).collect::<Vec<_>>().join(", "),
+            vec!["?"; self.columns.len()].join(", ")
+        );
+
+        for row in rows {
+            transaction
+                .execute(&sql, params_from_iter(row))
+                .map_err(failed)?;
+        }
+        Ok(())
+    }
+
+    fn delete_rows(&self, transaction: &Transaction, values: &[Value]) -> Result<(), BackendError> {
+        let rows = values
+            .iter()
+            .map(|v| self.row_values(v))
+            .collect::<Result<Vec<_>, _>>()?;
+        let table = quote(&self.table);
+        let sql = format!(
+            "DELETE FROM {table} WHERE rowid = (SELECT rowid FROM {table} WHERE {} LIMIT 1)",
+            self.columns
+                .iter()
+                .map(|c| format!("{} IS ?", quote(c)))
+                .collect::<Vec<_>>()
+                .join(" AND ")
+        );
+
+        for row in rows {
+            transaction
+                .execute(&sql, params_from_iter(row))
+                .map_err(failed)?;
+        }
+        Ok(())
+    }
+
+    fn row_values(&self, value: &Value) -> Result<Vec<SqlValue>, BackendError> {
+        let Value::Object(o) = value else {
+            return Err(BackendError::Unsupported);
+        };
+        if o.keys().any(|k| !self.columns.iter().any(|c| c == k)) {
+            return Err(BackendError::Unsupported);
+        }
+
+        self.columns
+            .iter()
+            .map(|c| o.get(c.as_str()).map_or(Ok(SqlValue::Null), |v| to_sql(v)))
+            .collect()
+    }
+}
+
+impl<'s, 'v> BagBackend<'s, 'v> for SqliteBackend {
+    fn filter_keys(&self) -> Vec<String> {
+        self.columns.clone()
+    }
+
+    fn scan(&self, filter: &ScanFilter<'s, 'v>) -> Result<Vec<Value<'s, 'v>>, BackendError> {
+        // Ordering in SQLite differs from damasc for mixed types, but every
+        // row that satisfies a damasc comparison with an integer also
+        // satisfies the SQL one, so only integer bounds are translated.
+        let conditions = filter
+            .equals
+            .iter()
+            .filter_map(|(column, value)| Some((format!("{} IS ?", quote(column)), to_sql(value).ok()?)))
+            .chain(filter.bounds.iter().filter_map(|bound| match bound.value {
+                Value::Integer(i) => Some((
+                    format!("{} {} ?", quote(&bound.key), bound.comparison),
+                    SqlValue::Integer(i),
+                )),
+                _ => None,
+            }))
+            .collect::<Vec<_>>();
+        let mut sql = format!(
+            "SELECT {} FROM {}",
+            self.columns.iter().map(|c| quote(c)).collect::<Vec<_>>().join(", "),
+            quote(&self.table)
+        );
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(
+                &conditions
+                    .iter()
+                    .map(|(condition, _)| condition.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" AND "),
+            );
+        }
+
+        let connection = self
+            .connection
+            .lock()
+            .map_err(|e| BackendError::Failed(e.to_string()))?;
+        let mut statement = connection.prepare(&sql).map_err(failed)?;
+        let rows = statement
+            .query_map(params_from_iter(conditions.into_iter().map(|(_, v)| v)), |row| {
+                (0..self.columns.len())
+                    .map(|i| row.get::<_, SqlValue>(i))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(failed)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(failed)?;
+
+        rows.into_iter()
+            .map(|row| {
+                let mut object = BTreeMap::new();
+                for (column, value) in self.columns.iter().zip(row) {
+                    object.insert(Cow::Owned(column.clone()), Cow::Owned(from_sql(value)?));
+                }
+                Ok(Value::Object(object))
+            })
+            .collect()
+    }
+
+    fn insert(&self, values: &[Value<'s, 'v>]) -> Result<(), BackendError> {
+        self.write(&[], values)
+    }
+
+    fn delete(&self, values: &[Value<'s, 'v>]) -> Result<(), BackendError> {
+        self.write(values, &[])
+    }
+
+    // Deletes and inserts in a single SQLite transaction.
+    fn write(&self, deleted: &[Value<'s, 'v>], inserted: &[Value<'s, 'v>]) -> Result<(), BackendError> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|e| BackendError::Failed(e.to_string()))?;
+        let transaction = connection.transaction().map_err(failed)?;
+        self.delete_rows(&transaction, deleted)?;
+        self.insert_rows(&transaction, inserted)?;
+        transaction.commit().map_err(failed)
+    }
+}
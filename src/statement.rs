@@ -1,21 +1,35 @@
 use std::borrow::Cow;
+use std::collections::{BTreeSet, HashSet};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    assignment::AssignmentSet,
+    assignment::{Assignment, AssignmentSet},
+    bag::StorageMode,
+    breakpoint::Breakpoint,
+    debugger::StepAction,
+    env::Environment,
     expression::{Expression, ExpressionSet},
+    formatter,
     identifier::Identifier,
+    limits::Limits,
+    log::LogLevel,
+    merge::Preference,
+    parser::StatementError,
     pattern::Pattern,
+    virtual_bag::IntegerRange,
     query::{DeletionQuery, Insertion, Predicate, ProjectionQuery, TransferQuery, UpdateQuery}, graph::Connection,
 };
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Statement<'a, 'b> {
     Noop,
     Clear,
     Exit,
-    Help,
+    Help(Option<Cow<'b, str>>),
     Inspect(Expression<'b>),
-    Format(Expression<'b>),
+    // Shows the statement in its canonical form.
+    Format(Box<Statement<'a, 'b>>),
     Eval(ExpressionSet<'b>),
     Literal(Expression<'b>),
     Pattern(Pattern<'b>),
@@ -23,20 +37,310 @@ pub enum Statement<'a, 'b> {
     MatchSet(AssignmentSet<'a, 'b>),
     Insert(Insertion<'b>),
     Pop(Expression<'b>),
+    // Appends the value to the log bag.
+    Log(LogLevel, Expression<'b>),
     Query(ProjectionQuery<'a>),
     Deletion(DeletionQuery<'a>),
     Update(UpdateQuery<'a>),
+    // Runs a `.change` without committing it.
+    Preview(UpdateQuery<'a>),
     Move(Identifier<'b>, TransferQuery<'a>),
-    Import(Cow<'b, str>),
-    Export(Cow<'b, str>),
+    // With `verify` the file is checked against its manifest on import,
+    // the manifest is written on export.
+    Import(Cow<'b, str>, DataFormat, bool),
+    Export(Cow<'b, str>, DataFormat, bool),
     LoadBundle(Cow<'b, str>),
-    UseBag(Identifier<'b>, Option<Predicate<'b>>),
+    Save(Cow<'b, str>),
+    Restore(Cow<'b, str>),
+    // Executes the statements in the lines of the file one after another.
+    Run(Cow<'b, str>),
+    // Sets how the statements after it handle errors.
+    OnError(ErrorPolicy),
+    // Enables the trash of the current bag, with the time to live of the
+    // trashed values in seconds.
+    EnableTrash(Option<u64>),
+    DisableTrash,
+    ListTrash,
+    // The predicate is applied to the trashed values, not their metadata.
+    RestoreTrash(Predicate<'a>),
+    PurgeTrash,
+    UseBag(Identifier<'b>, Option<Predicate<'b>>, StorageMode),
     DropBag(Identifier<'b>),
+    RenameBag(Identifier<'b>, Identifier<'b>),
+    CopyBag(Identifier<'b>, Identifier<'b>, Predicate<'b>),
+    CreateVirtualBag(Identifier<'b>, IntegerRange),
+    CreateView(Identifier<'b>, Vec<Identifier<'b>>),
+    CreateIndex(Identifier<'b>, Identifier<'b>),
+    Cdc(Identifier<'b>, Cow<'b, str>),
+    Replicate(Cow<'b, str>),
     Connect(Identifier<'b>,Connection<'b>),
     Disconnect(Identifier<'b>),
+    Trigger(Identifier<'b>, Identifier<'b>),
     ListConnections,
     TellBag,
     ListBags,
     Validate,
     Solve(Identifier<'b>, Expression<'b>),
+    // Shows the solutions of the connection one at a time instead of
+    // applying them, each decided on via `Step`.
+    SolveStep(Identifier<'b>, Expression<'b>),
+    Step(StepAction),
+    Break(Breakpoint<'b>),
+    ListBreakpoints,
+    ClearBreakpoints,
+    Delta(Identifier<'b>, Identifier<'b>, Option<Expression<'b>>),
+    Merge(Identifier<'b>, Identifier<'b>, Expression<'b>, Preference<'b>),
+    Explain(Box<Statement<'a, 'b>>),
+    ExplainError(Cow<'b, str>),
+    Ast(Box<Statement<'a, 'b>>),
+    ListSettings,
+    ListVariables,
+    // Removes the binding of the given name, or all bindings.
+    Unset(Option<Identifier<'b>>),
+    // Keeps a copy of the bindings under the given name, or replaces the
+    // bindings with the copy kept under it.
+    SaveEnv(Identifier<'b>),
+    LoadEnv(Identifier<'b>),
+    // The name and the arguments of a statement registered via
+    // `Repl::register`.
+    Custom(Cow<'b, str>, Cow<'b, str>),
+    // The plan cache, or the statistics of a bag.
+    Stats(Option<Identifier<'b>>),
+    // Shows the guard profile, or clears it if `true`.
+    Profile(bool),
+    Begin,
+    Commit,
+    Rollback,
+    Set(Identifier<'b>, Expression<'b>),
+    DefineMacro(Identifier<'b>, Vec<Identifier<'b>>, Box<Statement<'a, 'b>>),
+    ExpandMacro(Identifier<'b>, Option<Vec<Expression<'b>>>),
+    Prepare(Identifier<'b>, Box<Statement<'a, 'b>>),
+    Prepared(Identifier<'b>, Option<Expression<'b>>),
+}
+
+#[derive(Clone)]
+pub struct Macro<'s> {
+    pub parameters: Vec<Identifier<'s>>,
+    pub body: Statement<'s, 's>,
+}
+
+#[derive(Clone)]
+pub struct PreparedStatement<'s> {
+    pub parameters: BTreeSet<Identifier<'s>>,
+    pub body: Statement<'s, 's>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DataFormat {
+    Lines,
+    Json,
+    // One JSON document per line.
+    Ndjson,
+    // Only for export, requires the `parquet` feature.
+    Parquet,
+    // Only for import, each requires the feature of the same name.
+    Yaml,
+    Toml,
+    Xml,
+}
+
+// How the statements of a script handle errors. `Abort` rolls back the
+// whole script, `Continue` skips the failing statement but keeps what it
+// changed before failing, eg the statements of a `.run` before its failing
+// line, `RollbackStatement` undoes all of its effects, including the
+// variables it assigned, before going on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorPolicy {
+    #[default]
+    Abort,
+    Continue,
+    RollbackStatement,
+}
+
+impl std::fmt::Display for ErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorPolicy::Abort => write!(f, "abort"),
+            ErrorPolicy::Continue => write!(f, "continue"),
+            ErrorPolicy::RollbackStatement => write!(f, "rollback_statement"),
+        }
+    }
+}
+
+impl std::fmt::Display for Statement<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", formatter::statement(self))
+    }
+}
+
+impl Statement<'_, '_> {
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Statement::Clear
+            | Statement::Unset(_)
+            | Statement::LoadEnv(_)
+            | Statement::Custom(..)
+            | Statement::AssignSet(_)
+            | Statement::Insert(_)
+            | Statement::Pop(_)
+            | Statement::Log(..)
+            | Statement::Deletion(_)
+            | Statement::Update(_)
+            | Statement::Move(..)
+            | Statement::Import(..)
+            | Statement::LoadBundle(_)
+            | Statement::Restore(_)
+            | Statement::Run(_)
+            | Statement::EnableTrash(_)
+            | Statement::DisableTrash
+            | Statement::RestoreTrash(_)
+            | Statement::PurgeTrash
+            | Statement::UseBag(..)
+            | Statement::DropBag(_)
+            | Statement::RenameBag(..)
+            | Statement::CopyBag(..)
+            | Statement::CreateVirtualBag(..)
+            | Statement::CreateView(..)
+            | Statement::CreateIndex(..)
+            | Statement::Cdc(..)
+            | Statement::Replicate(_)
+            | Statement::Connect(..)
+            | Statement::Disconnect(_)
+            | Statement::Trigger(..)
+            | Statement::Solve(..)
+            | Statement::SolveStep(..)
+            | Statement::Step(_)
+            | Statement::Break(_)
+            | Statement::ClearBreakpoints
+            | Statement::Merge(..)
+            | Statement::Set(..)
+            | Statement::DefineMacro(..)
+            | Statement::ExpandMacro(..)
+            | Statement::Prepare(..)
+            | Statement::Prepared(..)
+            | Statement::Begin
+            | Statement::Commit
+            | Statement::Rollback => true,
+            Statement::Noop
+            | Statement::Exit
+            | Statement::OnError(_)
+            | Statement::Help(_)
+            | Statement::Inspect(_)
+            | Statement::Format(_)
+            | Statement::Eval(_)
+            | Statement::Literal(_)
+            | Statement::Pattern(_)
+            | Statement::MatchSet(_)
+            | Statement::Query(_)
+            | Statement::Export(..)
+            | Statement::Save(_)
+            | Statement::Preview(_)
+            | Statement::ListTrash
+            | Statement::ListConnections
+            | Statement::ListBreakpoints
+            | Statement::TellBag
+            | Statement::ListBags
+            | Statement::Validate
+            | Statement::Explain(_)
+            | Statement::ExplainError(_)
+            | Statement::Ast(_)
+            | Statement::ListSettings
+            | Statement::ListVariables
+            | Statement::SaveEnv(_)
+            | Statement::Stats(_)
+            | Statement::Delta(..)
+            | Statement::Profile(_) => false,
+        }
+    }
+}
+
+impl Statement<'_, '_> {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("the syntax tree only contains string keys")
+    }
+}
+
+impl<'a, 'b> Statement<'a, 'b> {
+    pub fn from_json(json: serde_json::Value, limits: &Limits) -> Result<Self, StatementError> {
+        let stmt: Self = serde_path_to_error::deserialize(json).map_err(|e| {
+            StatementError::InvalidNode {
+                path: e.path().to_string(),
+                message: e.into_inner().to_string(),
+            }
+        })?;
+
+        limits.check_statement(&stmt).map_err(StatementError::Limit)?;
+
+        Ok(stmt)
+    }
+}
+
+impl<'s> Statement<'s, 's> {
+    pub(crate) fn specialize(&self, env: &Environment<'_, 's, '_>) -> Self {
+        let unshadowed = HashSet::new();
+        let expression = |e: &Expression<'s>| env.specialize(e, &unshadowed);
+        let assignments = |set: &AssignmentSet<'s, 's>| AssignmentSet {
+            assignments: set
+                .assignments
+                .iter()
+                .map(|a| Assignment {
+                    pattern: a.pattern.clone(),
+                    expression: expression(&a.expression),
+                })
+                .collect(),
+        };
+
+        match self {
+            Statement::Inspect(e) => Statement::Inspect(expression(e)),
+            Statement::Eval(set) => Statement::Eval(ExpressionSet {
+                expressions: set.expressions.iter().map(expression).collect(),
+            }),
+            Statement::Literal(e) => Statement::Literal(expression(e)),
+            Statement::AssignSet(set) => Statement::AssignSet(assignments(set)),
+            Statement::MatchSet(set) => Statement::MatchSet(assignments(set)),
+            Statement::Insert(insertion) => Statement::Insert(Insertion {
+                expressions: ExpressionSet {
+                    expressions: insertion
+                        .expressions
+                        .expressions
+                        .iter()
+                        .map(expression)
+                        .collect(),
+                },
+            }),
+            Statement::Pop(e) => Statement::Pop(expression(e)),
+            Statement::Log(level, e) => Statement::Log(*level, expression(e)),
+            Statement::Query(query) => Statement::Query(query.specialize(env)),
+            Statement::Deletion(deletion) => Statement::Deletion(DeletionQuery {
+                predicate: deletion.predicate.specialize(env),
+            }),
+            Statement::Update(update) => Statement::Update(update.specialize(env)),
+            Statement::Preview(update) => Statement::Preview(update.specialize(env)),
+            Statement::Move(bag, transfer) => Statement::Move(bag.clone(), transfer.specialize(env)),
+            Statement::UseBag(bag, Some(predicate), mode) => {
+                Statement::UseBag(bag.clone(), Some(predicate.specialize(env)), *mode)
+            }
+            Statement::CopyBag(source, target, predicate) => {
+                Statement::CopyBag(source.clone(), target.clone(), predicate.specialize(env))
+            }
+            Statement::RestoreTrash(predicate) => Statement::RestoreTrash(predicate.specialize(env)),
+            Statement::Solve(bag, e) => Statement::Solve(bag.clone(), expression(e)),
+            Statement::SolveStep(bag, e) => Statement::SolveStep(bag.clone(), expression(e)),
+            Statement::Merge(source, target, key, preference) => Statement::Merge(
+                source.clone(),
+                target.clone(),
+                expression(key),
+                match preference {
+                    Preference::Newest(by) => Preference::Newest(expression(by)),
+                    other => other.clone(),
+                },
+            ),
+            Statement::Delta(before, after, key) => {
+                Statement::Delta(before.clone(), after.clone(), key.as_ref().map(expression))
+            }
+            Statement::Explain(inner) => Statement::Explain(Box::new(inner.specialize(env))),
+            Statement::Set(name, e) => Statement::Set(name.clone(), expression(e)),
+            other => other.clone(),
+        }
+    }
 }
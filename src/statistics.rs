@@ -0,0 +1,88 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem::size_of;
+
+use crate::value::{Value, ValueType};
+
+// The number of object keys listed by `.stats`.
+const TOP_KEYS: usize = 10;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BagStatistics {
+    pub count: usize,
+    pub distinct: usize,
+    // A rough estimate of the memory held by the values, in bytes.
+    pub bytes: usize,
+    pub types: BTreeMap<ValueType, usize>,
+    // The most common keys of the object values, the most common first.
+    pub keys: Vec<(String, usize)>,
+}
+
+impl BagStatistics {
+    pub(crate) fn collect(values: &[Cow<Value>]) -> Self {
+        let mut statistics = Self::default();
+        let mut distinct = BTreeSet::new();
+        let mut keys = BTreeMap::<&str, usize>::new();
+
+        for value in values {
+            let value = value.as_ref();
+            statistics.count += 1;
+            statistics.bytes += estimated_size(value);
+            *statistics.types.entry(value.get_type()).or_default() += 1;
+            if let Value::Object(object) = value {
+                for key in object.keys() {
+                    *keys.entry(key).or_default() += 1;
+                }
+            }
+            distinct.insert(value);
+        }
+
+        statistics.distinct = distinct.len();
+        let mut keys = keys.into_iter().collect::<Vec<_>>();
+        keys.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        statistics.keys = keys
+            .into_iter()
+            .take(TOP_KEYS)
+            .map(|(key, count)| (key.to_string(), count))
+            .collect();
+
+        statistics
+    }
+}
+
+// The size of the value itself plus the memory it owns. Shared and borrowed
+// parts are counted as if they were owned.
+pub(crate) fn estimated_size(value: &Value) -> usize {
+    size_of::<Value>()
+        + match value {
+            Value::String(s) => s.len(),
+            Value::BigInt(b) => b.bits().div_ceil(8) as usize,
+            Value::Array(items) => items.iter().map(|v| estimated_size(v)).sum(),
+            Value::Object(object) => object
+                .iter()
+                .map(|(k, v)| size_of::<String>() + k.len() + estimated_size(v))
+                .sum(),
+            _ => 0,
+        }
+}
+
+impl std::fmt::Display for BagStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "count: {}", self.count)?;
+        writeln!(f, "distinct: {}", self.distinct)?;
+        writeln!(f, "memory: ~{} bytes", self.bytes)?;
+        if !self.types.is_empty() {
+            writeln!(f, "types:")?;
+            for (t, count) in &self.types {
+                writeln!(f, "  {t}: {count}")?;
+            }
+        }
+        if !self.keys.is_empty() {
+            writeln!(f, "keys:")?;
+            for (key, count) in &self.keys {
+                writeln!(f, "  {key}: {count}")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::bag::ValueBag;
+use crate::bag_bundle::{BagBundle, BagDelta};
+use crate::identifier::Identifier;
+
+const EXTENSION: &str = "bag";
+
+#[derive(Clone, Debug)]
+pub struct Storage {
+    directory: PathBuf,
+}
+
+impl Storage {
+    pub fn new(directory: impl Into<PathBuf>) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+
+        Ok(Self { directory })
+    }
+
+    pub fn from_env() -> Option<io::Result<Self>> {
+        std::env::var("DAMASC_DATA_DIR").ok().map(Self::new)
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub(crate) fn files(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files = fs::read_dir(&self.directory)?
+            .map(|entry| entry.map(|e| e.path()))
+            .filter(|path| match path {
+                Ok(p) => p.extension().map_or(false, |e| e == EXTENSION),
+                Err(_) => true,
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        files.sort();
+
+        Ok(files)
+    }
+
+    fn bag_path(&self, name: &Identifier) -> PathBuf {
+        self.directory.join(format!("{}.{EXTENSION}", name.name))
+    }
+
+    pub(crate) fn persist(&self, bundle: &BagBundle) -> io::Result<()> {
+        for (name, delta) in bundle.changes.iter() {
+            let path = self.bag_path(name);
+
+            let Some(bag) = bundle.bags.get(name) else {
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => continue,
+                }
+            };
+
+            match delta {
+                BagDelta::Appended(indices) if path.exists() => {
+                    let mut file = BufWriter::new(OpenOptions::new().append(true).open(&path)?);
+                    for (_, v) in bag.iter().enumerate().filter(|(i, _)| indices.contains(i)) {
+                        writeln!(file, "{v};")?;
+                    }
+                    file.flush()?;
+                }
+                _ => self.rewrite(name, bag, &path)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rewrite(&self, name: &Identifier, bag: &ValueBag, path: &Path) -> io::Result<()> {
+        let partial = path.with_extension(format!("{EXTENSION}.partial"));
+        let mut file = BufWriter::new(File::create(&partial)?);
+        bag.write(name, &mut file)?;
+        file.into_inner()?.sync_all()?;
+
+        fs::rename(partial, path)
+    }
+}
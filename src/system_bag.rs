@@ -0,0 +1,104 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::identifier::Identifier;
+use crate::repl::Repl;
+use crate::snapshot;
+use crate::value::Value;
+
+// Read-only bags describing the state of the engine, eg `.bag $bags`
+// followed by `.query {name, size, ...} into name where size > 100`. Their
+// values are computed while one of them is the current bag.
+pub const NAMES: [&str; 4] = ["$bags", "$connections", "$settings", "$history"];
+
+// Names starting with `$` are reserved for system bags, user bags can not be
+// named like this.
+pub fn is_system_bag(name: &Identifier) -> bool {
+    name.name.starts_with('$')
+}
+
+fn object<'s, 'v>(fields: Vec<(&'static str, Value<'s, 'v>)>) -> Value<'s, 'v> {
+    Value::Object(
+        fields
+            .into_iter()
+            .map(|(key, value)| (Cow::Borrowed(key), Cow::Owned(value)))
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+fn string<'s, 'v>(s: impl ToString) -> Value<'s, 'v> {
+    Value::String(Cow::Owned(s.to_string()))
+}
+
+// The current values of the given system bag, None if there is no system
+// bag of this name.
+pub(crate) fn values<'s, 'v>(repl: &Repl, name: &str) -> Option<Vec<Value<'s, 'v>>> {
+    let values = match name {
+        "$bags" => repl
+            .bag_bundle
+            .bag_names()
+            .into_iter()
+            .filter(|name| !is_system_bag(name))
+            .filter_map(|name| {
+                let bag = repl.bag_bundle.bags.get(&name)?;
+                // The size of virtual and backend bags is not known without
+                // computing or loading all of their values.
+                let size = if bag.is_virtual() || !snapshot::is_saved(bag) {
+                    Value::Null
+                } else {
+                    Value::Integer(bag.len() as i64)
+                };
+
+                Some(object(vec![
+                    ("name", string(&name)),
+                    ("size", size),
+                    ("guard", string(&bag.guard)),
+                    ("virtual", Value::Boolean(bag.is_virtual())),
+                    ("current", Value::Boolean(name.name == repl.current_bag.name)),
+                ]))
+            })
+            .collect(),
+        "$connections" => {
+            let mut connections = repl.bag_graph.connections.iter().collect::<Vec<_>>();
+            connections.sort_by(|(a, _), (b, _)| a.cmp(b));
+            connections
+                .into_iter()
+                .map(|(name, connection)| {
+                    let triggers = repl
+                        .bag_graph
+                        .triggers
+                        .iter()
+                        .filter(|(c, _)| c == name)
+                        .map(|(_, bag)| Cow::Owned(string(bag)))
+                        .collect();
+
+                    object(vec![
+                        ("name", string(name)),
+                        ("definition", string(connection)),
+                        ("triggers", Value::Array(triggers)),
+                    ])
+                })
+                .collect()
+        }
+        "$settings" => repl
+            .settings
+            .entries()
+            .into_iter()
+            .map(|(name, value)| object(vec![("name", string(name)), ("value", value)]))
+            .collect(),
+        "$history" => repl
+            .history
+            .iter()
+            .enumerate()
+            .map(|(index, input)| {
+                object(vec![
+                    ("index", Value::Integer(index as i64)),
+                    ("input", string(input)),
+                ])
+            })
+            .collect(),
+        _ => return None,
+    };
+
+    Some(values)
+}
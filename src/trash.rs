@@ -0,0 +1,55 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::identifier::Identifier;
+use crate::value::Value;
+
+// The shadow bag the values deleted from `bag` are moved to. A bag has a
+// trash exactly if its shadow bag exists.
+pub fn bag_name<'s>(bag: &Identifier<'s>) -> Identifier<'s> {
+    Identifier {
+        name: Cow::Owned(format!("{}::trash", bag.name)),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now() -> i64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+// The system clock is not available in the browser, the one of the host is.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now() -> i64 {
+    js_sys::Date::now() as i64
+}
+
+// A deleted value together with the time it was deleted and, if the trash
+// has a time to live, the time after which it is purged.
+pub(crate) fn entry<'s, 'v>(value: Value<'s, 'v>, now: i64, ttl: Option<u64>) -> Value<'s, 'v> {
+    let mut object = BTreeMap::new();
+    object.insert(Cow::Borrowed("value"), Cow::Owned(value));
+    object.insert(
+        Cow::Borrowed("deleted_at"),
+        Cow::Owned(Value::DateTime(now)),
+    );
+    if let Some(ttl) = ttl {
+        object.insert(
+            Cow::Borrowed("expires_at"),
+            Cow::Owned(Value::DateTime(now.saturating_add(ttl as i64 * 1000))),
+        );
+    }
+
+    Value::Object(object)
+}
+
+pub(crate) fn is_expired(entry: &Value, now: i64) -> bool {
+    let Value::Object(object) = entry else {
+        return false;
+    };
+
+    matches!(object.get("expires_at").map(|e| e.as_ref()), Some(Value::DateTime(t)) if *t <= now)
+}
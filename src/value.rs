@@ -1,20 +1,39 @@
 use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat};
+use num_bigint::BigInt;
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::expression::Expression;
+use crate::formatter;
+use crate::identifier::Identifier;
+use crate::pattern::Pattern;
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Value<'s, 'v> {
     Null,
     String(Cow<'s, str>),
     Integer(i64),
+    // Integers that do not fit into an i64. Results that fit are always
+    // stored as `Integer` so that equal numbers have the same representation.
+    BigInt(BigInt),
+    // Milliseconds since the unix epoch in UTC.
+    DateTime(i64),
     Boolean(bool),
     Array(Vec<Cow<'v, Value<'s, 'v>>>),
     Object(ValueObjectMap<'s, 'v>),
     Type(ValueType),
+    Closure(Box<Closure<'s, 'v>>),
+    Pattern(QuotedPattern<'s>),
+    Expression(QuotedExpression<'s>),
 }
 
 pub(crate) type ValueObjectMap<'s, 'v> = BTreeMap<Cow<'s, str>, Cow<'v, Value<'s, 'v>>>;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub enum ValueType {
     Null,
     String,
@@ -23,11 +42,177 @@ pub enum ValueType {
     Array,
     Object,
     Type,
+    Function,
+    Pattern,
+    Expression,
+    Datetime,
+}
+
+impl PartialOrd for Value<'_, '_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Integers are ordered by their numeric value, also inside of arrays and
+// objects.
+impl Ord for Value<'_, '_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::String(l), Value::String(r)) => l.cmp(r),
+            (Value::Integer(l), Value::Integer(r)) => l.cmp(r),
+            (Value::Integer(l), Value::BigInt(r)) => BigInt::from(*l).cmp(r),
+            (Value::BigInt(l), Value::Integer(r)) => l.cmp(&BigInt::from(*r)),
+            (Value::BigInt(l), Value::BigInt(r)) => l.cmp(r),
+            (Value::DateTime(l), Value::DateTime(r)) => l.cmp(r),
+            (Value::Boolean(l), Value::Boolean(r)) => l.cmp(r),
+            (Value::Array(l), Value::Array(r)) => l.cmp(r),
+            (Value::Object(l), Value::Object(r)) => l.cmp(r),
+            (Value::Type(l), Value::Type(r)) => l.cmp(r),
+            (Value::Closure(l), Value::Closure(r)) => l.cmp(r),
+            (Value::Pattern(l), Value::Pattern(r)) => l.cmp(r),
+            (Value::Expression(l), Value::Expression(r)) => l.cmp(r),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuotedPattern<'s>(pub Box<Pattern<'s>>);
+
+// Quoted patterns are compared by their source text.
+impl PartialEq for QuotedPattern<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_string() == other.0.to_string()
+    }
+}
+
+impl Eq for QuotedPattern<'_> {}
+
+impl PartialOrd for QuotedPattern<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QuotedPattern<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.to_string().cmp(&other.0.to_string())
+    }
+}
+
+impl std::hash::Hash for QuotedPattern<'_> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_string().hash(state)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuotedExpression<'s>(pub Box<Expression<'s>>);
+
+// Like quoted patterns, quoted expressions are compared by their source text.
+impl PartialEq for QuotedExpression<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_string() == other.0.to_string()
+    }
+}
+
+impl Eq for QuotedExpression<'_> {}
+
+impl PartialOrd for QuotedExpression<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QuotedExpression<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.to_string().cmp(&other.0.to_string())
+    }
+}
+
+impl std::hash::Hash for QuotedExpression<'_> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_string().hash(state)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Closure<'s, 'v> {
+    pub parameter: Pattern<'s>,
+    pub body: Expression<'s>,
+    pub captured: BTreeMap<Identifier<'s>, Value<'s, 'v>>,
+}
+
+impl<'s, 'v> Closure<'s, 'v> {
+    // Closures are compared by their source text and captured values.
+    fn key(&self) -> (String, &BTreeMap<Identifier<'s>, Value<'s, 'v>>) {
+        (format!("{} => {}", self.parameter, self.body), &self.captured)
+    }
+}
+
+impl PartialEq for Closure<'_, '_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Closure<'_, '_> {}
+
+impl PartialOrd for Closure<'_, '_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Closure<'_, '_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl std::hash::Hash for Closure<'_, '_> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+impl ValueType {
+    pub const ALL: [ValueType; 11] = [
+        ValueType::Null,
+        ValueType::String,
+        ValueType::Integer,
+        ValueType::Boolean,
+        ValueType::Array,
+        ValueType::Object,
+        ValueType::Type,
+        ValueType::Function,
+        ValueType::Pattern,
+        ValueType::Expression,
+        ValueType::Datetime,
+    ];
+
+    // The name of the type in the syntax, eg `x is Integer`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValueType::Null => "Null",
+            ValueType::String => "String",
+            ValueType::Integer => "Integer",
+            ValueType::Boolean => "Boolean",
+            ValueType::Array => "Array",
+            ValueType::Object => "Object",
+            ValueType::Type => "Type",
+            ValueType::Function => "Function",
+            ValueType::Pattern => "Pattern",
+            ValueType::Expression => "Expression",
+            ValueType::Datetime => "Datetime",
+        }
+    }
 }
 
 impl std::fmt::Display for ValueType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        write!(f, "{}", self.name())
     }
 }
 
@@ -36,11 +221,15 @@ impl<'s, 'v> Value<'s, 'v> {
         match self {
             Value::Null => ValueType::Null,
             Value::String(_) => ValueType::String,
-            Value::Integer(_) => ValueType::Integer,
+            Value::Integer(_) | Value::BigInt(_) => ValueType::Integer,
+            Value::DateTime(_) => ValueType::Datetime,
             Value::Boolean(_) => ValueType::Boolean,
             Value::Array(_) => ValueType::Array,
             Value::Object(_) => ValueType::Object,
             Value::Type(_) => ValueType::Type,
+            Value::Closure(_) => ValueType::Function,
+            Value::Pattern(_) => ValueType::Pattern,
+            Value::Expression(_) => ValueType::Expression,
         }
     }
 
@@ -62,13 +251,19 @@ impl<'s, 'v> Value<'s, 'v> {
             (Value::Array(a), ValueType::Boolean) => Value::Boolean(!a.is_empty()),
             (Value::String(s), ValueType::Boolean) => Value::Boolean(!s.is_empty()),
             (Value::String(s), ValueType::Array) => Value::Array(
-                s.chars()
+                s.graphemes(true)
                     .map(|c| Cow::Owned(Value::String(Cow::Owned(c.to_string()))))
                     .collect(),
             ),
             (Value::String(_), ValueType::Object) => todo!(),
             (Value::Integer(i), ValueType::String) => Value::String(Cow::Owned(i.to_string())),
             (Value::Integer(i), ValueType::Boolean) => Value::Boolean(i != &0),
+            (Value::BigInt(i), ValueType::String) => Value::String(Cow::Owned(i.to_string())),
+            (Value::BigInt(_), ValueType::Boolean) => Value::Boolean(true),
+            (Value::DateTime(t), ValueType::String) => Value::String(Cow::Owned(format_datetime(*t))),
+            (Value::DateTime(t), ValueType::Integer) => Value::Integer(*t),
+            (Value::String(s), ValueType::Datetime) => Value::parse_datetime(s)?,
+            (Value::Integer(i), ValueType::Datetime) => Value::from_timestamp_millis(*i)?,
             (Value::Boolean(b), ValueType::String) => Value::String(Cow::Owned(b.to_string())),
             (Value::Boolean(b), ValueType::Integer) => Value::Integer(if *b { 1 } else { 0 }),
             (Value::Array(a), ValueType::Integer) => Value::Integer(a.len() as i64),
@@ -76,6 +271,76 @@ impl<'s, 'v> Value<'s, 'v> {
             _ => return None,
         })
     }
+
+    pub(crate) fn parse_integer(s: &str) -> Option<Value<'s, 'v>> {
+        match str::parse::<i64>(s) {
+            Ok(i) => Some(Value::Integer(i)),
+            Err(_) => str::parse::<BigInt>(s).ok().map(Value::from_bigint),
+        }
+    }
+
+    // Accepts ISO-8601 dates like 2024-01-15, optionally followed by a time
+    // like T10:30, T10:30:00 or T10:30:00.250 and an offset like Z or +02:00.
+    // Without an offset the time is taken as UTC.
+    pub(crate) fn parse_datetime(s: &str) -> Option<Value<'s, 'v>> {
+        let (date, time) = s.split_once('T').unwrap_or((s, "00:00"));
+        let (time, offset) = match time.find(['Z', '+', '-']) {
+            Some(i) => time.split_at(i),
+            None => (time, "Z"),
+        };
+
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+        let time = NaiveTime::parse_from_str(time, "%H:%M:%S%.f")
+            .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+            .ok()?;
+        let offset = match offset {
+            "Z" => 0,
+            _ => offset.parse::<FixedOffset>().ok()?.local_minus_utc() as i64,
+        };
+
+        let local = NaiveDateTime::new(date, time).and_utc().timestamp_millis();
+        Value::from_timestamp_millis(local.checked_sub(offset * 1000)?)
+    }
+
+    // Datetimes are limited to the years 0 to 9999 so that they can always
+    // be written as literals.
+    pub(crate) fn from_timestamp_millis(millis: i64) -> Option<Value<'s, 'v>> {
+        let year = DateTime::from_timestamp_millis(millis)?.year();
+        (0..=9999).contains(&year).then_some(Value::DateTime(millis))
+    }
+
+    pub(crate) fn from_bigint(i: BigInt) -> Value<'s, 'v> {
+        match i64::try_from(&i) {
+            Ok(small) => Value::Integer(small),
+            Err(_) => Value::BigInt(i),
+        }
+    }
+
+    pub(crate) fn to_bigint(&self) -> Option<BigInt> {
+        match self {
+            Value::Integer(i) => Some(BigInt::from(*i)),
+            Value::BigInt(i) => Some(i.clone()),
+            _ => None,
+        }
+    }
+
+    // The position of the variant in the ordering of values, integers share
+    // one position no matter if they are stored as `Integer` or `BigInt`.
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::String(_) => 1,
+            Value::Integer(_) | Value::BigInt(_) => 2,
+            Value::DateTime(_) => 3,
+            Value::Boolean(_) => 4,
+            Value::Array(_) => 5,
+            Value::Object(_) => 6,
+            Value::Type(_) => 7,
+            Value::Closure(_) => 8,
+            Value::Pattern(_) => 9,
+            Value::Expression(_) => 10,
+        }
+    }
 }
 
 impl<'s, 'v> std::fmt::Display for Value<'s, 'v> {
@@ -84,6 +349,8 @@ impl<'s, 'v> std::fmt::Display for Value<'s, 'v> {
             Value::Null => write!(f, "null"),
             Value::String(s) => write!(f, "\"{s}\""),
             Value::Integer(i) => write!(f, "{i}"),
+            Value::BigInt(i) => write!(f, "{i}"),
+            Value::DateTime(t) => write!(f, "@{}", format_datetime(*t)),
             Value::Boolean(b) => write!(f, "{b}"),
             Value::Array(a) => {
                 let _ = write!(f, "[");
@@ -95,13 +362,26 @@ impl<'s, 'v> std::fmt::Display for Value<'s, 'v> {
             Value::Object(o) => {
                 let _ = write!(f, "{{");
                 for (k, v) in o {
-                    let _ = write!(f, "{k}: ",);
+                    let _ = if formatter::is_identifier_name(k) {
+                        write!(f, "{k}: ")
+                    } else {
+                        write!(f, "\"{k}\": ")
+                    };
                     let _ = write!(f, "{v}, ",);
                 }
                 write!(f, "}}")
             }
             Value::Type(t) => write!(f, "{t}"),
+            Value::Closure(_) => write!(f, "{}", self.to_expression()),
+            Value::Pattern(QuotedPattern(p)) => write!(f, "pattern({p})"),
+            Value::Expression(QuotedExpression(e)) => write!(f, "quote({e})"),
         };
         write!(f, "")
     }
 }
+
+pub(crate) fn format_datetime(millis: i64) -> String {
+    DateTime::from_timestamp_millis(millis)
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+        .unwrap_or_default()
+}
@@ -0,0 +1,198 @@
+// Values are written with the data model of serde so that embedders can
+// move them into and out of their own data structures without the string
+// parser. Null, booleans, integers, strings, arrays and objects map onto
+// their JSON counterparts. The other values are written as an object with a
+// single tag key, eg `{"$type": "Integer"}` or `{"$bigint": "10000000000000000000"}`, and
+// objects that would be taken for one are wrapped into `{"$object": ...}`.
+// Functions can not be written since they capture their environment.
+
+use std::borrow::Cow;
+use std::marker::PhantomData;
+
+use num_bigint::BigInt;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Unexpected, Visitor};
+use serde::ser::{self, SerializeMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::grammar;
+use crate::value::{
+    format_datetime, QuotedExpression, QuotedPattern, Value, ValueObjectMap, ValueType,
+};
+
+const TAGS: [&str; 6] = ["$object", "$bigint", "$datetime", "$type", "$pattern", "$expression"];
+
+impl Serialize for Value<'_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Null => serializer.serialize_unit(),
+            Value::String(s) => serializer.serialize_str(s),
+            Value::Integer(i) => serializer.serialize_i64(*i),
+            Value::Boolean(b) => serializer.serialize_bool(*b),
+            Value::Array(a) => serializer.collect_seq(a),
+            Value::Object(o) if o.len() == 1 && o.keys().all(|k| TAGS.contains(&k.as_ref())) => {
+                tagged(serializer, "$object", o)
+            }
+            Value::Object(o) => serializer.collect_map(o),
+            Value::BigInt(i) => tagged(serializer, "$bigint", &i.to_string()),
+            Value::DateTime(t) => tagged(serializer, "$datetime", &format_datetime(*t)),
+            Value::Type(t) => tagged(serializer, "$type", t),
+            Value::Pattern(QuotedPattern(p)) => tagged(serializer, "$pattern", &p.to_string()),
+            Value::Expression(QuotedExpression(e)) => {
+                tagged(serializer, "$expression", &e.to_string())
+            }
+            Value::Closure(_) => Err(ser::Error::custom("functions can not be serialized")),
+        }
+    }
+}
+
+fn tagged<S: Serializer, T: Serialize + ?Sized>(
+    serializer: S,
+    tag: &str,
+    value: &T,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(1))?;
+    map.serialize_entry(tag, value)?;
+    map.end()
+}
+
+impl<'de, 's, 'v> Deserialize<'de> for Value<'s, 'v> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(ValueVisitor(PhantomData).deserialize(deserializer)?.value)
+    }
+}
+
+// A decoded value along with the object it was decoded from if that was
+// taken for a tagged value, since the content of `$object` is used as is.
+struct Decoded<'s, 'v> {
+    value: Value<'s, 'v>,
+    object: Option<Value<'s, 'v>>,
+}
+
+impl<'s, 'v> From<Value<'s, 'v>> for Decoded<'s, 'v> {
+    fn from(value: Value<'s, 'v>) -> Self {
+        Self { value, object: None }
+    }
+}
+
+struct ValueVisitor<'s, 'v>(PhantomData<Value<'s, 'v>>);
+
+impl<'de, 's, 'v> DeserializeSeed<'de> for ValueVisitor<'s, 'v> {
+    type Value = Decoded<'s, 'v>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, 's, 'v> Visitor<'de> for ValueVisitor<'s, 'v> {
+    type Value = Decoded<'s, 'v>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a damasc value")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(Value::Null.into())
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(Value::Null.into())
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_bool<E: de::Error>(self, b: bool) -> Result<Self::Value, E> {
+        Ok(Value::Boolean(b).into())
+    }
+
+    fn visit_i64<E: de::Error>(self, i: i64) -> Result<Self::Value, E> {
+        Ok(Value::Integer(i).into())
+    }
+
+    fn visit_u64<E: de::Error>(self, i: u64) -> Result<Self::Value, E> {
+        Ok(Value::from_bigint(BigInt::from(i)).into())
+    }
+
+    fn visit_i128<E: de::Error>(self, i: i128) -> Result<Self::Value, E> {
+        Ok(Value::from_bigint(BigInt::from(i)).into())
+    }
+
+    fn visit_u128<E: de::Error>(self, i: u128) -> Result<Self::Value, E> {
+        Ok(Value::from_bigint(BigInt::from(i)).into())
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        Ok(Value::String(Cow::Owned(s.to_string())).into())
+    }
+
+    fn visit_string<E: de::Error>(self, s: String) -> Result<Self::Value, E> {
+        Ok(Value::String(Cow::Owned(s)).into())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+        while let Some(value) = seq.next_element::<Value>()? {
+            values.push(Cow::Owned(value));
+        }
+        Ok(Value::Array(values).into())
+    }
+
+    // A tag only counts as one if it is the single key of the object.
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut object = ValueObjectMap::new();
+        let mut wrapped = None;
+        while let Some(key) = map.next_key::<String>()? {
+            let decoded = map.next_value_seed(ValueVisitor(PhantomData))?;
+            if key == "$object" {
+                wrapped = decoded.object;
+            }
+            object.insert(Cow::Owned(key), Cow::Owned(decoded.value));
+        }
+        if object.len() != 1 || !object.keys().all(|k| TAGS.contains(&k.as_ref())) {
+            return Ok(Value::Object(object).into());
+        }
+
+        let (key, value) = object.first_key_value().unwrap();
+        let value = untag(key, wrapped.unwrap_or_else(|| value.as_ref().clone()))?;
+        Ok(Decoded {
+            value,
+            object: Some(Value::Object(object)),
+        })
+    }
+}
+
+fn untag<'s, 'v, E: de::Error>(tag: &str, value: Value<'s, 'v>) -> Result<Value<'s, 'v>, E> {
+    let text = match &value {
+        Value::String(s) => Some(s.as_ref()),
+        _ => None,
+    };
+    let found = value.get_type().to_string();
+    let invalid = |expected: &'static str| match text {
+        Some(s) => E::invalid_value(Unexpected::Str(s), &expected),
+        None => E::invalid_type(Unexpected::Other(&found), &expected),
+    };
+
+    match (tag, text) {
+        ("$object", _) => match value {
+            Value::Object(_) => Ok(value),
+            _ => Err(invalid("an object")),
+        },
+        ("$bigint", Some(s)) => s
+            .parse::<BigInt>()
+            .map(Value::from_bigint)
+            .map_err(|_| invalid("an integer")),
+        ("$datetime", Some(s)) => Value::parse_datetime(s).ok_or_else(|| invalid("a datetime")),
+        ("$type", Some(s)) => ValueType::deserialize(s.into_deserializer())
+            .map(Value::Type)
+            .map_err(|_: de::value::Error| invalid("a type name")),
+        ("$pattern", Some(s)) => grammar::parse(grammar::pattern, s)
+            .map(|p| Value::Pattern(QuotedPattern(Box::new(p))))
+            .map_err(|_| invalid("a pattern")),
+        ("$expression", Some(s)) => grammar::parse(grammar::expression, s)
+            .map(|e| Value::Expression(QuotedExpression(Box::new(e))))
+            .map_err(|_| invalid("an expression")),
+        _ => Err(invalid("a string")),
+    }
+}
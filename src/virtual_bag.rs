@@ -0,0 +1,82 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::identifier::Identifier;
+use crate::value::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IntegerRange {
+    pub start: i64,
+    pub end: Option<i64>,
+}
+
+impl std::fmt::Display for IntegerRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}..", self.start)?;
+        if let Some(end) = self.end {
+            write!(f, "{end}")?;
+        }
+        Ok(())
+    }
+}
+
+// How many values of an unbounded range are scanned at most for those a
+// statement is limited to, a range without enough matching values would
+// otherwise be scanned up to `i64::MAX`.
+pub(crate) const SCAN_BUDGET: usize = 1 << 20;
+
+pub type ValueGenerator<'s, 'v> =
+    Arc<dyn Fn() -> Box<dyn Iterator<Item = Value<'s, 'v>>> + Send + Sync>;
+
+// The values of a virtual bag are computed on each access instead of being
+// stored, so every call to `values` has to produce the same sequence.
+#[derive(Clone)]
+pub enum VirtualSource<'s, 'v> {
+    Range(IntegerRange),
+    External(ValueGenerator<'s, 'v>),
+    // The state of the engine held by a system bag, see `system_bag`.
+    System(Vec<Value<'s, 'v>>),
+    // A view over the union of the given bags. Its values are resolved by
+    // `BagBundle::bag` when it is read and kept until one of the bags
+    // changes.
+    Union(Vec<Identifier<'s>>, Arc<OnceLock<Vec<Value<'s, 'v>>>>),
+}
+
+impl<'s, 'v> VirtualSource<'s, 'v> {
+    pub(crate) fn union(bags: Vec<Identifier<'s>>) -> Self {
+        VirtualSource::Union(bags, Arc::default())
+    }
+
+    pub(crate) fn values(&self) -> Box<dyn Iterator<Item = Value<'s, 'v>> + '_> {
+        match self {
+            VirtualSource::Range(IntegerRange { start, end: Some(end) }) => {
+                Box::new((*start..*end).map(Value::Integer))
+            }
+            VirtualSource::Range(IntegerRange { start, end: None }) => {
+                Box::new((*start..=i64::MAX).take(SCAN_BUDGET).map(Value::Integer))
+            }
+            VirtualSource::External(generator) => generator(),
+            VirtualSource::System(values) => Box::new(values.iter().cloned()),
+            VirtualSource::Union(_, values) => Box::new(values.get().into_iter().flatten().cloned()),
+        }
+    }
+
+    pub(crate) fn is_bounded(&self) -> bool {
+        !matches!(self, VirtualSource::Range(IntegerRange { end: None, .. }))
+    }
+}
+
+// A value of a view tagged with the bag it originates from, eg
+// `{origin: "logs_a", value: ...}`.
+pub(crate) fn tagged<'s, 'v>(origin: &Identifier, value: Value<'s, 'v>) -> Value<'s, 'v> {
+    Value::Object(BTreeMap::from([
+        (
+            Cow::Borrowed("origin"),
+            Cow::Owned(Value::String(Cow::Owned(origin.name.to_string()))),
+        ),
+        (Cow::Borrowed("value"), Cow::Owned(value)),
+    ]))
+}
@@ -1,11 +1,21 @@
 #![cfg(target_arch = "wasm32")]
 
+use std::cell::RefCell;
+
 use wasm_bindgen;
 
+use crate::builtins::{BuiltinGroup, Builtins};
+use crate::capabilities::Capabilities;
+use crate::env::EvalError;
+use crate::error_catalog::ErrorCode;
+use crate::json;
+use crate::limits::Limits;
 use crate::repl::Repl;
 use crate::repl::ReplError;
+use crate::value::Value;
 
 use cfg_if::cfg_if;
+use serde::Serialize;
 use wasm_bindgen::prelude::*;
 
 cfg_if! {
@@ -24,6 +34,101 @@ extern "C" {
     fn show_result(stmt: &str, result: &str);
 }
 
+// Objects become plain JS objects instead of `Map`s, so the results can be
+// used like the parsed responses of the web server.
+fn to_js(json: &serde_json::Value) -> JsValue {
+    json.serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+        .unwrap_or(JsValue::NULL)
+}
+
+thread_local! {
+    // The JS functions registered as builtins, referred to by their index
+    // since they can not be shared between threads like the builtins.
+    static CALLBACKS: RefCell<Vec<js_sys::Function>> = RefCell::new(vec![]);
+}
+
+// The arguments and the result are converted like the results of `run`.
+fn call<'s, 'v>(callback: usize, arguments: &[Value<'s, 'v>]) -> Result<Value<'s, 'v>, EvalError> {
+    let arguments: js_sys::Array = arguments
+        .iter()
+        .map(|a| to_js(&json::to_json_lossy(a)))
+        .collect();
+    let result = CALLBACKS
+        .with(|c| c.borrow()[callback].apply(&JsValue::NULL, &arguments))
+        .map_err(|_| EvalError::FunctionFailed)?;
+    let result: serde_json::Value =
+        serde_wasm_bindgen::from_value(result).map_err(|_| EvalError::FunctionFailed)?;
+
+    json::from_json(&result).ok_or(EvalError::FunctionFailed)
+}
+
+// Configures the functions of a `WasmRepl` before it is created, eg
+// `new WasmReplBuilder().disable("reflection").function("random", Math.random).build()`.
+#[wasm_bindgen]
+pub struct WasmReplBuilder {
+    builtins: Builtins,
+}
+
+#[wasm_bindgen]
+impl WasmReplBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            builtins: Builtins::default(),
+        }
+    }
+
+    // Enables a group of builtins by its name, eg `higher_order`.
+    #[wasm_bindgen]
+    pub fn enable(mut self, group: &str) -> Result<WasmReplBuilder, JsValue> {
+        self.builtins.enable(builtin_group(group)?);
+        Ok(self)
+    }
+
+    #[wasm_bindgen]
+    pub fn disable(mut self, group: &str) -> Result<WasmReplBuilder, JsValue> {
+        self.builtins.disable(builtin_group(group)?);
+        Ok(self)
+    }
+
+    // Registers a JS function callable under the given name. Throwing or
+    // returning a value that can not be converted fails the call with
+    // `FunctionFailed`.
+    #[wasm_bindgen]
+    pub fn function(
+        mut self,
+        name: &str,
+        callback: js_sys::Function,
+    ) -> Result<WasmReplBuilder, JsValue> {
+        let index = CALLBACKS.with(|c| {
+            let mut callbacks = c.borrow_mut();
+            callbacks.push(callback);
+            callbacks.len() - 1
+        });
+        if !self.builtins.register(name, move |arguments| call(index, arguments)) {
+            return Err(JsValue::from_str(&format!("function {name} is already defined")));
+        }
+
+        Ok(self)
+    }
+
+    #[wasm_bindgen]
+    pub fn build(self) -> WasmRepl {
+        let mut state = Box::new(Repl::new("init"));
+        state.settings.limits = Limits::playground();
+        // There is no file system in the browser.
+        state.capabilities = Capabilities::ALL.without(Capabilities::IO);
+        state.builtins = self.builtins;
+
+        WasmRepl { state }
+    }
+}
+
+fn builtin_group(name: &str) -> Result<BuiltinGroup, JsValue> {
+    BuiltinGroup::from_name(name)
+        .ok_or_else(|| JsValue::from_str(&format!("unknown group of builtins {name}")))
+}
+
 #[wasm_bindgen]
 pub struct WasmRepl {
     state: Box<Repl<'static, 'static, 'static, 'static>>,
@@ -31,26 +136,80 @@ pub struct WasmRepl {
 
 #[wasm_bindgen]
 impl WasmRepl {
+    // A repl with all builtins, see `WasmReplBuilder` to change them.
     #[wasm_bindgen(constructor)]
     pub fn default() -> Self {
-        Self {
-            state: Box::new(Repl::new("init")),
-        }
+        WasmReplBuilder::new().build()
     }
 
     #[wasm_bindgen]
     pub fn eval(&mut self, input: &str) {
-        let stmt = match crate::parser::statement(input) {
-            Ok((_, s)) => s,
+        let stmt = match self.state.parse(input) {
+            Ok(s) => s,
             Err(e) => {
-                return show_error(input, &format!("read error: {e}"));
+                return show_error(input, &format!("read error[{}]: {e}", e.code()));
             }
         };
 
         match self.state.execute(stmt) {
             Ok(r) => return show_result(input, &format!("{r}")),
             Err(ReplError::Exit) => {}
-            Err(e) => return show_error(input, &format!("Error: {e:?}")),
+            Err(e) => return show_error(input, &format!("Error[{}]: {e:?}", e.code())),
         }
     }
+
+    // Like `eval`, but returns the result as object of the same shape as
+    // `ReplOutput::to_json` instead of showing it. Errors are thrown as
+    // `{code, message}`.
+    #[wasm_bindgen]
+    pub fn run(&mut self, input: &str) -> Result<JsValue, JsValue> {
+        let stmt = self.state.parse(input).map_err(|e| {
+            to_js(&serde_json::json!({
+                "code": e.code(),
+                "message": e.to_string(),
+            }))
+        })?;
+
+        match self.state.execute(stmt) {
+            Ok(r) => Ok(to_js(&r.to_json())),
+            Err(ReplError::Exit) => Ok(JsValue::UNDEFINED),
+            Err(e) => Err(to_js(&serde_json::json!({
+                "code": e.code(),
+                "message": format!("{e:?}"),
+            }))),
+        }
+    }
+
+    // The variables as object from their names to their values.
+    #[wasm_bindgen]
+    pub fn bindings(&self) -> JsValue {
+        to_js(&self.state.bindings_to_json())
+    }
+
+    // The bags as array of `{name, count, current}`.
+    #[wasm_bindgen]
+    pub fn bags(&self) -> JsValue {
+        to_js(&self.state.bags_to_json())
+    }
+
+    // Like `complete`, but returns the completions as object.
+    #[wasm_bindgen]
+    pub fn completions(&self, input: &str, cursor: usize) -> JsValue {
+        to_js(
+            &self
+                .state
+                .completion_context()
+                .complete(input, cursor)
+                .to_json(),
+        )
+    }
+
+    #[wasm_bindgen]
+    pub fn complete(&self, input: &str, cursor: usize) -> String {
+        self.state
+            .completion_context()
+            .complete(input, cursor)
+            .to_json()
+            .to_string()
+    }
 }
@@ -0,0 +1,369 @@
+// Generators of syntax trees and values for the property tests, limited to
+// what the grammar can express: strings can not contain `"`, numbers are
+// written without sign and identifiers are picked from a few names that do
+// not start like a literal.
+
+use std::borrow::Cow;
+
+use damasc::{
+    collation::Collation,
+    env::Environment,
+    expression::{
+        ArrayItem, BinaryExpression, BinaryOperator, CallExpression, Expression, LambdaExpression,
+        LetExpression, LogicalExpression, LogicalOperator, MemberExpression, ObjectProperty,
+        Property, PropertyKey, StringTemplate, StringTemplatePart, UnaryExpression, UnaryOperator,
+    },
+    identifier::Identifier,
+    literal::Literal,
+    parser::{full_expression, pattern as parse_pattern},
+    pattern::{ArrayPatternItem, ObjectPropertyPattern, Pattern, PropertyPattern, Rest},
+    value::{QuotedExpression, QuotedPattern, Value, ValueType},
+};
+use num_bigint::BigInt;
+use proptest::collection::{btree_map, vec};
+use proptest::prelude::*;
+use proptest::sample::select;
+
+// `where` is a keyword and has to be written as `#where` in some places.
+const NAMES: &[&str] = &["a", "b", "x", "name", "_rest", "where"];
+
+const TYPES: &[ValueType] = &[
+    ValueType::Null,
+    ValueType::String,
+    ValueType::Integer,
+    ValueType::Boolean,
+    ValueType::Array,
+    ValueType::Object,
+    ValueType::Type,
+    ValueType::Function,
+    ValueType::Pattern,
+    ValueType::Expression,
+    ValueType::Datetime,
+];
+
+const REGEXES: &[&str] = &["a+", "[0-9]*", "^x$", "a\\/b"];
+
+const DATETIMES: &[&str] = &["2024-02-29", "2024-02-29T13:45:00Z", "2024-02-29T13:45:00.25+01:00"];
+
+const BINARY_OPERATORS: &[BinaryOperator] = &[
+    BinaryOperator::StrictEqual,
+    BinaryOperator::StrictNotEqual,
+    BinaryOperator::LessThan,
+    BinaryOperator::GreaterThan,
+    BinaryOperator::LessThanEqual,
+    BinaryOperator::GreaterThanEqual,
+    BinaryOperator::Plus,
+    BinaryOperator::Minus,
+    BinaryOperator::Times,
+    BinaryOperator::Over,
+    BinaryOperator::Mod,
+    BinaryOperator::In,
+    BinaryOperator::PowerOf,
+    BinaryOperator::Is,
+    BinaryOperator::Cast,
+    BinaryOperator::Matches,
+];
+
+fn text() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9 _.,:;$`{}()\\[\\]\\\\/-]{0,8}"
+}
+
+fn identifier() -> impl Strategy<Value = Identifier<'static>> {
+    select(NAMES).prop_map(|name| Identifier {
+        name: Cow::Borrowed(name),
+    })
+}
+
+fn value_type() -> impl Strategy<Value = ValueType> {
+    select(TYPES)
+}
+
+// The name of a property, written as string if it is no identifier.
+fn property_name() -> impl Strategy<Value = Identifier<'static>> {
+    prop_oneof![identifier(), text().prop_map(|name| Identifier { name: Cow::Owned(name) })]
+}
+
+// The literals patterns can match, regular expressions are not literals
+// there but `Pattern::Regex`.
+fn pattern_literal() -> impl Strategy<Value = Literal<'static>> {
+    prop_oneof![
+        Just(Literal::Null),
+        text().prop_map(|s| Literal::String(Cow::Owned(s))),
+        any::<u32>().prop_map(|n| Literal::Number(Cow::Owned(n.to_string()))),
+        any::<bool>().prop_map(Literal::Boolean),
+        value_type().prop_map(Literal::Type),
+        select(DATETIMES).prop_map(|t| Literal::DateTime(Cow::Borrowed(t))),
+    ]
+}
+
+fn literal() -> impl Strategy<Value = Literal<'static>> {
+    prop_oneof![
+        6 => pattern_literal(),
+        1 => select(REGEXES).prop_map(|r| Literal::Regex(Cow::Borrowed(r))),
+    ]
+}
+
+pub fn expression() -> impl Strategy<Value = Expression<'static>> {
+    let leaf = prop_oneof![
+        literal().prop_map(Expression::Literal),
+        identifier().prop_map(Expression::Identifier),
+    ];
+
+    leaf.prop_recursive(4, 48, 3, |inner| {
+        let pattern = pattern_with(inner.clone().boxed());
+        let boxed = || inner.clone().prop_map(Box::new);
+
+        prop_oneof![
+            vec(array_item(inner.clone()), 0..4).prop_map(Expression::Array),
+            vec(object_property(inner.clone()), 0..4).prop_map(Expression::Object),
+            (select(BINARY_OPERATORS), boxed(), boxed()).prop_map(|(operator, left, right)| {
+                Expression::Binary(BinaryExpression {
+                    operator,
+                    left,
+                    right,
+                })
+            }),
+            (any::<bool>(), boxed(), boxed()).prop_map(|(or, left, right)| {
+                Expression::Logical(LogicalExpression {
+                    operator: if or { LogicalOperator::Or } else { LogicalOperator::And },
+                    left,
+                    right,
+                })
+            }),
+            (
+                select(vec![UnaryOperator::Minus, UnaryOperator::Plus, UnaryOperator::Not]),
+                boxed()
+            )
+                .prop_map(|(operator, argument)| {
+                    Expression::Unary(UnaryExpression { operator, argument })
+                }),
+            (boxed(), prop_oneof![
+                property_name().prop_map(|p| Box::new(Expression::Literal(Literal::String(p.name)))),
+                boxed(),
+            ])
+                .prop_map(|(object, property)| {
+                    Expression::Member(MemberExpression { object, property })
+                }),
+            (identifier(), vec(inner.clone(), 0..3)).prop_map(|(function, arguments)| {
+                Expression::Call(CallExpression {
+                    function,
+                    arguments,
+                })
+            }),
+            (vec(("[a-z ]{0,4}", boxed()), 0..3), "[a-z ]{0,4}").prop_map(|(parts, suffix)| {
+                Expression::Template(StringTemplate {
+                    parts: parts
+                        .into_iter()
+                        .map(|(fixed_start, dynamic_end)| StringTemplatePart {
+                            fixed_start: Cow::Owned(fixed_start),
+                            dynamic_end,
+                        })
+                        .collect(),
+                    suffix: Cow::Owned(suffix),
+                })
+            }),
+            (pattern.clone(), boxed()).prop_map(|(parameter, body)| {
+                Expression::Lambda(LambdaExpression { parameter, body })
+            }),
+            (pattern.clone(), boxed(), boxed()).prop_map(|(pattern, value, body)| {
+                Expression::Let(LetExpression {
+                    pattern,
+                    value,
+                    body,
+                })
+            }),
+            pattern.prop_map(|p| Expression::Pattern(Box::new(p))),
+            boxed().prop_map(Expression::Quote),
+            (
+                select(&BINARY_OPERATORS[..6]),
+                boxed(),
+                boxed(),
+                select(vec![Collation::Binary, Collation::NoCase, Collation::Locale("de".into())])
+            )
+                .prop_map(|(operator, left, right, collation)| {
+                    let comparison = Expression::Binary(BinaryExpression {
+                        operator,
+                        left,
+                        right,
+                    });
+                    Expression::Collate(Box::new(comparison), collation)
+                }),
+        ]
+    })
+}
+
+fn array_item(
+    expression: impl Strategy<Value = Expression<'static>> + Clone,
+) -> impl Strategy<Value = ArrayItem<'static>> {
+    prop_oneof![
+        3 => expression.clone().prop_map(ArrayItem::Single),
+        1 => expression.prop_map(ArrayItem::Spread),
+    ]
+}
+
+fn object_property(
+    expression: impl Strategy<Value = Expression<'static>> + Clone,
+) -> impl Strategy<Value = ObjectProperty<'static>> {
+    prop_oneof![
+        identifier().prop_map(ObjectProperty::Single),
+        (property_name(), expression.clone()).prop_map(|(key, value)| {
+            ObjectProperty::Property(Property {
+                key: PropertyKey::Identifier(key),
+                value,
+            })
+        }),
+        (expression.clone(), expression.clone()).prop_map(|(key, value)| {
+            ObjectProperty::Property(Property {
+                key: PropertyKey::Expression(key),
+                value,
+            })
+        }),
+        expression.prop_map(ObjectProperty::Spread),
+    ]
+}
+
+pub fn pattern() -> impl Strategy<Value = Pattern<'static>> {
+    pattern_with(expression().boxed())
+}
+
+// Patterns with the given expressions as guards and computed keys. Guards
+// are only allowed on the items of arrays and the values of objects.
+fn pattern_with(expression: BoxedStrategy<Expression<'static>>) -> BoxedStrategy<Pattern<'static>> {
+    let leaf = prop_oneof![
+        Just(Pattern::Discard),
+        identifier().prop_map(Pattern::Identifier),
+        value_type().prop_map(Pattern::TypedDiscard),
+        (identifier(), value_type()).prop_map(|(id, t)| Pattern::TypedIdentifier(id, t)),
+        pattern_literal().prop_map(Pattern::Literal),
+        select(REGEXES).prop_map(|r| Pattern::Regex(Cow::Borrowed(r))),
+    ];
+
+    leaf.prop_recursive(3, 24, 3, move |inner| {
+        let item = prop_oneof![
+            3 => inner.clone(),
+            1 => (inner.clone(), expression.clone())
+                .prop_map(|(p, guard)| Pattern::Guarded(Box::new(p), Box::new(guard))),
+        ];
+        let rest = prop_oneof![
+            Just(Rest::Exact),
+            Just(Rest::Discard),
+            inner.clone().prop_map(|p| Rest::Collect(Box::new(p))),
+        ];
+        let property = prop_oneof![
+            identifier().prop_map(ObjectPropertyPattern::Single),
+            (property_name(), item.clone()).prop_map(|(key, value)| {
+                ObjectPropertyPattern::Match(PropertyPattern {
+                    key: PropertyKey::Identifier(key),
+                    value,
+                })
+            }),
+            (expression.clone(), item.clone()).prop_map(|(key, value)| {
+                ObjectPropertyPattern::Match(PropertyPattern {
+                    key: PropertyKey::Expression(key),
+                    value,
+                })
+            }),
+        ];
+
+        prop_oneof![
+            (identifier(), inner.clone()).prop_map(|(id, p)| Pattern::Capture(id, Box::new(p))),
+            (vec(item, 0..3), rest.clone()).prop_map(|(items, rest)| {
+                Pattern::Array(items.into_iter().map(ArrayPatternItem::Pattern).collect(), rest)
+            }),
+            (vec(property, 0..3), rest).prop_map(|(properties, rest)| {
+                Pattern::Object(properties, rest)
+            }),
+            vec(inner.clone(), 2..4).prop_map(Pattern::Or),
+            inner.prop_map(|p| Pattern::Descendant(Box::new(p))),
+        ]
+    })
+    .boxed()
+}
+
+// Values as produced by evaluating expressions, without closures.
+pub fn value() -> impl Strategy<Value = Value<'static, 'static>> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        text().prop_map(|s| Value::String(Cow::Owned(s))),
+        any::<i64>().prop_map(Value::Integer),
+        (any::<bool>(), 1..i64::MAX).prop_map(|(negative, n)| {
+            let big: BigInt = BigInt::from(n) << 64;
+            Value::BigInt(if negative { -big } else { big })
+        }),
+        // Up to the end of the year 9999, which still has four digits.
+        (0..253_402_300_800_000i64).prop_map(Value::DateTime),
+        any::<bool>().prop_map(Value::Boolean),
+        value_type().prop_map(Value::Type),
+    ];
+
+    leaf.prop_recursive(3, 24, 4, |inner| {
+        prop_oneof![
+            vec(inner.clone(), 0..4)
+                .prop_map(|items| Value::Array(items.into_iter().map(Cow::Owned).collect())),
+            btree_map(property_name(), inner, 0..4).prop_map(|properties| {
+                Value::Object(
+                    properties
+                        .into_iter()
+                        .map(|(k, v)| (k.name, Cow::Owned(v)))
+                        .collect(),
+                )
+            }),
+            pattern().prop_map(|p| Value::Pattern(QuotedPattern(Box::new(p)))),
+            expression().prop_map(|e| Value::Expression(QuotedExpression(Box::new(e)))),
+        ]
+    })
+}
+
+// The invariants: printing a syntax tree and parsing the result gives the
+// same tree back, and values evaluate to themselves when printed.
+
+pub fn expression_round_trip(expression: &Expression) -> Result<(), TestCaseError> {
+    let source = expression.to_string();
+    let Ok((_, parsed)) = full_expression(&source) else {
+        return Err(TestCaseError::fail(format!("{source} can not be parsed")));
+    };
+    prop_assert_eq!(
+        serde_json::to_value(&parsed).unwrap(),
+        serde_json::to_value(expression).unwrap(),
+        "{}",
+        source
+    );
+
+    Ok(())
+}
+
+pub fn pattern_round_trip(pattern: &Pattern) -> Result<(), TestCaseError> {
+    let source = pattern.to_string();
+    let Ok(("", parsed)) = parse_pattern(&source) else {
+        return Err(TestCaseError::fail(format!("{source} can not be parsed")));
+    };
+    prop_assert_eq!(
+        serde_json::to_value(&parsed).unwrap(),
+        serde_json::to_value(pattern).unwrap(),
+        "{}",
+        source
+    );
+
+    Ok(())
+}
+
+pub fn value_round_trip(value: &Value) -> Result<(), TestCaseError> {
+    let source = value.to_string();
+    let Ok((_, parsed)) = full_expression(&source) else {
+        return Err(TestCaseError::fail(format!("{source} can not be parsed")));
+    };
+    let evaluated = Environment::new().eval_expr(&parsed).ok();
+    prop_assert_eq!(evaluated.as_ref(), Some(value), "{}", source);
+
+    Ok(())
+}
+
+pub fn value_serde_round_trip(value: &Value) -> Result<(), TestCaseError> {
+    let json = serde_json::to_string(value).unwrap();
+    let Ok(parsed) = serde_json::from_str::<Value>(&json) else {
+        return Err(TestCaseError::fail(format!("{json} can not be deserialized")));
+    };
+    prop_assert_eq!(&parsed, value, "{}", json);
+
+    Ok(())
+}
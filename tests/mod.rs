@@ -2,20 +2,45 @@
 #![feature(assert_matches)]
 
 use damasc::{
+    backend::{BackendError, BagBackend, ScanFilter},
     assignment::{Assignment},
-    env::Environment,
+    env::{Environment, EvalError},
+    error_catalog::{lookup, ErrorCode},
+    json::{from_json, to_json},
+    limits::{LimitError, Limits},
     matcher::Matcher,
-    parser::{expression_multi, try_match_multi},
+    repl::{Repl, ReplOutput},
+    parser::{
+        checked_statement, explicit_statement, expression_multi, try_match_multi, Expected,
+        StatementError,
+    },
+    query::QueryEngine,
     statement::Statement, topology::TopologyError,
+    identifier::Identifier,
+    value::{Value, ValueType},
+    virtual_bag::ValueGenerator,
 };
-use std::{assert_matches::assert_matches, collections::BTreeMap};
+use std::assert_matches::assert_matches;
+use proptest::prelude::*;
+
+mod arbitrary;
+
+// Parses and executes a statement, returning its output or the debug form of
+// the error it failed with.
+fn run_statement<'s>(repl: &mut Repl<'_, '_, 's, '_>, input: &'s str) -> String {
+    match checked_statement(input, &Limits::default()) {
+        Ok(statement) => match repl.execute(statement) {
+            Ok(output) => output.to_string(),
+            Err(e) => format!("{e:?}"),
+        },
+        Err(e) => format!("{e:?}"),
+    }
+}
 
 #[test]
 fn test_expressions() {
     let mut tests = include_str!("test_expressions.txt").lines().array_chunks();
-    let env = damasc::env::Environment {
-        bindings: BTreeMap::new(),
-    };
+    let env = damasc::env::Environment::new();
 
     for [expr, result, sep] in &mut tests {
         assert_eq!("---", sep, "Expression pairs are separated by --- line");
@@ -54,9 +79,7 @@ fn test_expressions() {
 #[test]
 fn test_patterns() {
     let tests = include_str!("test_patterns.txt").lines();
-    let env = Environment {
-        bindings: BTreeMap::new(),
-    };
+    let env = Environment::new();
 
     for case in tests {
         let mut matcher = Matcher::new(&env);
@@ -90,9 +113,7 @@ fn test_patterns() {
 #[test]
 fn test_negative_patterns() {
     let tests = include_str!("test_negative_patterns.txt").lines();
-    let env = Environment {
-        bindings: BTreeMap::new(),
-    };
+    let env = Environment::new();
 
     for case in tests {
         let mut matcher = Matcher::new(&env);
@@ -123,12 +144,54 @@ fn test_negative_patterns() {
     }
 }
 
+#[test]
+fn test_match_all() {
+    let env = Environment::new();
+    let matcher = Matcher::new(&env);
+    let solutions = |pattern: &'static str, value: &'static str| {
+        let (_, pattern) = damasc::parser::pattern(pattern).unwrap();
+        let (_, expression) = damasc::parser::full_expression(value).unwrap();
+        let value = env.eval_expr(&expression).unwrap();
+        matcher
+            .match_all(&pattern, std::borrow::Cow::Owned(value))
+            .map(|m| m.bindings.iter().map(|(k, v)| format!("{}={v}", k.name)).collect::<Vec<_>>().join(","))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(solutions("**(x is Integer)", "[1, [2, {a: 3}], 4]"), ["x=1", "x=2", "x=3", "x=4"]);
+    assert_eq!(solutions("[x, _] | [_, x] | {a: x}", "[1, 2]"), ["x=1", "x=2"]);
+    assert_eq!(solutions("[**(x is Integer), y | \"b\"]", "[[1, 2], \"b\"]"), ["x=1,y=\"b\"", "x=1", "x=2,y=\"b\"", "x=2"]);
+    assert_eq!(solutions("[z @ **(x is Integer) if x > 1]", "[[1, 2]]"), ["x=2,z=[1, 2, ]"]);
+    assert!(solutions("**(x is Boolean)", "[1, [2]]").is_empty());
+}
+
+#[test]
+fn test_matcher_checkpoint() {
+    let env = Environment::new();
+    let mut matcher = Matcher::new(&env);
+    let (_, expression) = damasc::parser::full_expression("{a: 1, b: 2}").unwrap();
+    let value = env.eval_expr(&expression).unwrap();
+    let pattern = |p: &'static str| damasc::parser::pattern(p).unwrap().1;
+
+    let (first, conflicting, second) = (pattern("{a, ...}"), pattern("{a: b, b}"), pattern("{b, ...}"));
+    matcher.match_pattern(&first, &value).unwrap();
+    let checkpoint = matcher.checkpoint();
+    assert!(matcher.match_pattern(&conflicting, &value).is_err());
+    matcher.rollback(checkpoint);
+    assert_eq!(matcher.bindings.len(), 1);
+    assert_eq!(matcher.bindings.get("b"), None);
+
+    matcher.match_pattern(&second, &value).unwrap();
+    let bound = matcher.bindings.iter().map(|(k, v)| format!("{}={v}", k.name)).collect::<Vec<_>>();
+    assert_eq!(bound, ["a=1", "b=2"]);
+    matcher.rollback(0);
+    assert!(matcher.bindings.is_empty());
+}
+
 #[test]
 fn test_topological_assignments() {
     let tests = include_str!("test_topological.txt").lines();
-    let env = Environment {
-        bindings: BTreeMap::new(),
-    };
+    let env = Environment::new();
 
     for case in tests {
         let mut tmp_env = env.clone();
@@ -157,16 +220,14 @@ fn test_topological_assignments() {
                 "Test Expression Value matches the test pattern: {case}"
             );
 
-            matcher.local_env.clone().merge(&mut tmp_env);
+            matcher.merge_into(&mut tmp_env);
         }
     }
 }
 #[test]
 fn test_topological_fail() {
     let tests = include_str!("test_topological_fail.txt").lines();
-    let env = Environment {
-        bindings: BTreeMap::new(),
-    };
+    let env = Environment::new();
 
     for case in tests {
         let Ok((_, Statement::MatchSet(assignment_set))) = try_match_multi(case) else {
@@ -179,3 +240,3051 @@ fn test_topological_fail() {
         )
     }
 }
+
+#[test]
+fn test_limits() {
+    let limits = Limits::default();
+    let deep = |open: &str, close: &str, n: usize| format!("{}1{}", open.repeat(n), close.repeat(n));
+    let check = |input: &str, limits: &Limits| checked_statement(input, limits).err();
+
+    assert_matches!(check(&deep("[", "]", 8), &limits), None);
+    assert_matches!(
+        check(&deep("[", "]", 100), &limits),
+        Some(StatementError::Limit(LimitError::NestingTooDeep { .. }))
+    );
+    assert_matches!(
+        check(&deep("!", "", 100), &limits),
+        Some(StatementError::Limit(LimitError::NestingTooDeep { .. }))
+    );
+    assert_matches!(
+        check(&deep("`${", "}`", 10), &limits),
+        Some(StatementError::Limit(LimitError::TemplateTooDeep { .. }))
+    );
+    assert_matches!(
+        check(&vec!["1"; 200].join("+"), &limits),
+        Some(StatementError::Limit(LimitError::ExpressionTooDeep { .. }))
+    );
+    assert_matches!(
+        check(&format!("{} = 1", deep("[", "]", 40).replace('1', "x")), &limits),
+        Some(StatementError::Limit(LimitError::PatternTooDeep { .. }))
+    );
+    assert_matches!(
+        check("\"(((((\"", &Limits { max_nesting: 2, ..limits }),
+        None
+    );
+}
+
+#[test]
+fn test_budget() {
+    let limits = Limits::playground();
+    let check = |input: &str| checked_statement(input, &limits).err();
+
+    assert_matches!(check(".query a; b into [a, b] where a < b"), None);
+    assert_matches!(
+        check(&format!("\"{}\"", "a".repeat(3000))),
+        Some(StatementError::Limit(LimitError::InputTooLong { .. }))
+    );
+    assert_matches!(
+        check(&format!("[{}]", vec!["1"; 100].join(","))),
+        Some(StatementError::Limit(LimitError::ArrayTooLarge { .. }))
+    );
+    assert_matches!(
+        check(&vec!["[1,2,3]"; 200].join(";")),
+        Some(StatementError::Limit(LimitError::TooManyNodes { .. }))
+    );
+    assert_matches!(
+        check(".query a; b; c; d; e"),
+        Some(StatementError::Limit(LimitError::JoinTooWide { .. }))
+    );
+    assert_matches!(
+        checked_statement(&format!("\"{}\"", "a".repeat(3000)), &Limits::default()).err(),
+        None
+    );
+}
+
+#[test]
+fn test_json() {
+    let env = Environment::new();
+    let Ok((_, values)) = expression_multi(r#"null; "foo"; -42; true; [1, [2, {}]]; {a: {b: "c"}, d: []}; 18446744073709551615; [@2024-01-15, Integer]"#) else {
+        unreachable!("Expressions can be parsed");
+    };
+
+    for expr in values.expressions {
+        let value = env.eval_expr(&expr).unwrap();
+        let json = to_json(&value).unwrap();
+
+        assert_eq!(from_json(&json), Some(value));
+    }
+
+    let json = serde_json::json!({"x": [1, "two", null], "y": {"z": false}});
+    let Ok((_, expected)) = expression_multi(r#"{x: [1, "two", null], y: {z: false}}"#) else {
+        unreachable!("Expression can be parsed");
+    };
+    assert_eq!(from_json(&json), env.eval_expr(&expected.expressions[0]).ok());
+    assert_eq!(from_json(&serde_json::json!([1.5])), None);
+
+    let Ok((_, big)) = expression_multi("[18446744073709551616, -18446744073709551616]") else {
+        unreachable!("Expression can be parsed");
+    };
+    let value = env.eval_expr(&big.expressions[0]).unwrap();
+    assert_eq!(
+        to_json(&value),
+        Some(serde_json::json!([{"$bigint": "18446744073709551616"}, {"$bigint": "-18446744073709551616"}]))
+    );
+}
+
+#[test]
+fn test_explicit_bindings() {
+    let limits = Limits::default();
+    let check = |input: &str| explicit_statement(input, &limits).err();
+
+    assert_matches!(check(".let x = 5"), None);
+    assert_matches!(check(".match [x, 2] = [1, 2]"), None);
+    assert_matches!(check("x == 5"), None);
+    assert_matches!(check("x = 5"), Some(StatementError::Ambiguous(_)));
+    assert_matches!(check("let x = 5"), Some(StatementError::Ambiguous(_)));
+    assert_matches!(checked_statement("x = 5", &limits).err(), None);
+    assert_matches!(check("let x = 2 in x * x; 3"), None);
+    assert!(matches!(
+        checked_statement("let x = 2 in [1, 2] && true; y = 1", &limits),
+        Ok(Statement::AssignSet(set)) if set.assignments.len() == 2
+    ));
+    assert!(matches!(
+        checked_statement("let x = 2 in x * x; 3", &limits),
+        Ok(Statement::Eval(set)) if set.expressions.len() == 2
+    ));
+}
+
+#[test]
+fn test_aggregation() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| {
+        let statement = checked_statement(input, &limits).unwrap();
+        match repl.execute(statement) {
+            Ok(ReplOutput::Values(values)) => values
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(";"),
+            Ok(_) => String::new(),
+            Err(_) => "error".to_string(),
+        }
+    };
+
+    run(r#".insert {n: "a", age: 30}; {n: "b", age: 40}; {n: "c", age: 30}; {n: "d"}"#);
+
+    assert_eq!(run(".query {age, ...} into [age, count(), sum(age)] group by age"), "[30, 2, 60, ];[40, 1, 40, ]");
+    assert_eq!(run(".query {age, ...} into [min(age), max(age), avg(age)]"), "[30, 40, 33, ]");
+    assert_eq!(run(".query {age, ...} into [count(), sum(age), min(age)] where age > 50"), "[0, 0, null, ]");
+    assert_eq!(run(".query x into count() group by type(x)"), "4");
+    assert_eq!(run(".query {n, ...} into sum(n)"), "error");
+}
+
+#[test]
+fn test_memoize() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".set memoize true");
+    run(".insert [1, 2]; [2, 1]; [1, 2]");
+    assert_eq!(run(".query x into [sort(x), length(x)]"), "[[1, 2, ], 2, ];\n".repeat(3));
+    // Only calls depending on nothing but their arguments are memoized.
+    assert_eq!(run(".query x into eval(quote(x[0]))"), "1;\n2;\n1;\n");
+    assert_eq!(run(".query x into sort(x, fn(y) => eval(quote(-y)))"), "[2, 1, ];\n".repeat(3));
+    // Templates are told apart by their source, not by where they are kept.
+    assert_eq!(
+        run(".query x into [`a${x[0]}`, `b${x[0]}`, `a${x[0]}`]"),
+        "[\"a1\", \"b1\", \"a1\", ];\n[\"a2\", \"b2\", \"a2\", ];\n[\"a1\", \"b1\", \"a1\", ];\n"
+    );
+}
+
+#[test]
+fn test_macros() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| {
+        let statement = checked_statement(input, &limits).unwrap();
+        match repl.execute(statement) {
+            Ok(output) => output.to_string(),
+            Err(_) => "error".to_string(),
+        }
+    };
+
+    run(".insert {age: 120}; {age: 30}; {age: 101}");
+    run(".macro cleanup := .delete {age, ...} where age > 100");
+    run(".macro older(n) := .query {age, ...} into age where age > n");
+
+    assert_eq!(run("!older(100)"), "120;\n101;\n");
+    assert_eq!(run("!cleanup"), "DELETED 2 items.\n");
+    assert_eq!(run("!older(0)"), "30;\n");
+    assert_eq!(run("!older(1, 2)"), "error");
+    assert_eq!(run(".macro again := !cleanup"), "error");
+    assert_eq!(run("!true"), "false;\n");
+}
+
+#[test]
+fn test_indexes() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| {
+        match repl.execute_measured(checked_statement(input, &limits).unwrap()) {
+            Ok((output, metrics)) => (output.to_string(), metrics.scanned),
+            Err(_) => ("error".to_string(), 0),
+        }
+    };
+
+    run(".insert {id: 1, v: 10}; {id: 2, v: 20}; {id: 2, v: 21}; 3");
+    assert_eq!(run(".query x where x.id == 2").1, 4);
+
+    assert_eq!(run(".index init on .id").0, "INDEXED 4 items.\n");
+    assert_eq!(run(".index missing on .id").0, "error");
+    assert_eq!(run(".query x into x.v where x.id == 2"), ("20;\n21;\n".to_string(), 2));
+    assert_eq!(run(".query {id: 1, v}"), ("{id: 1, v: 10, };\n".to_string(), 1));
+    assert_eq!(run(".query {id, v} into v where 1 + 1 == id && v > 20"), ("21;\n".to_string(), 2));
+    assert_eq!(run(".query x where x.id == 3"), ("".to_string(), 0));
+    assert_eq!(run(".query x where x.v == 10").1, 4);
+    assert_eq!(run(".change {id, v} into {id, v: 0} where id == 1"), ("CHANGED 1 items.\n".to_string(), 1));
+    assert_eq!(run(".delete {id, ...} where id == 2"), ("DELETED 2 items.\n".to_string(), 2));
+    run(".insert {id: 2, v: 22}");
+    assert_eq!(run(".query x where x.id == 2"), ("{id: 2, v: 22, };\n".to_string(), 1));
+    assert_eq!(run(".query x where x.id == 1"), ("{id: 1, v: 0, };\n".to_string(), 1));
+
+    // Popping a value moves the last one in its place.
+    run(".insert {id: 3, v: 30}; {id: 4, v: 40}");
+    run(".pop {id: 1, v: 0}");
+    assert_eq!(run(".query x where x.id == 1"), ("".to_string(), 0));
+    assert_eq!(run(".query x where x.id == 4"), ("{id: 4, v: 40, };\n".to_string(), 1));
+    run(".pop {id: 3, v: 30}");
+    assert_eq!(run(".query x where x.id == 3"), ("".to_string(), 0));
+    assert_eq!(run(".query x where x.id == 2"), ("{id: 2, v: 22, };\n".to_string(), 1));
+    assert_eq!(run(".query x where x.id == 4"), ("{id: 4, v: 40, };\n".to_string(), 1));
+
+    run(".bag tags as {id, ...} distinct");
+    run(".insert {id: 1}; {id: 2}; {id: 3}");
+    run(".index tags on .id");
+    run(".pop {id: 1}");
+    assert_eq!(run(".insert {id: 3}; {id: 1}").0, "INSERTED 1 items.\n");
+    assert_eq!(run(".query x where x.id == 3"), ("{id: 3, };\n".to_string(), 1));
+    assert_eq!(run(".query x where x.id == 1"), ("{id: 1, };\n".to_string(), 1));
+}
+
+#[test]
+fn test_completion() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    for input in [
+        ".bag people",
+        ".let limit_age = 5; life = 42",
+        ".macro cleanup := .query x",
+        ".env save large",
+    ] {
+        repl.execute(checked_statement(input, &limits).unwrap()).unwrap();
+    }
+    let context = repl.completion_context();
+    let texts = |input: &str, cursor: usize| {
+        let completions = context.complete(input, cursor);
+        (
+            completions.start,
+            completions
+                .candidates
+                .into_iter()
+                .map(|c| format!("{}:{}", c.text, c.kind))
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    assert_eq!(
+        texts(".qu", 3),
+        (1, vec!["query:command".into(), "queryx:command".into(), "quit:command".into()])
+    );
+    assert_eq!(texts(".rol", 4), (1, vec!["rollback:command".into()]));
+    assert_eq!(texts(".drop pe", 8), (6, vec!["people:bag".into()]));
+    assert_eq!(texts("!cl", 3), (1, vec!["cleanup:macro".into()]));
+    assert_eq!(
+        texts("1 + li", 6),
+        (4, vec!["life:variable".into(), "limit:keyword".into(), "limit_age:variable".into()])
+    );
+    assert_eq!(texts("keys(x) + le", 12), (10, vec!["length:function".into()]));
+    assert_eq!(texts("Int", 99), (0, vec!["Integer:type".into()]));
+    assert_eq!(texts(".stats p", 8), (7, vec!["people:bag".into()]));
+    assert_eq!(texts(".unset li", 9), (7, vec!["life:variable".into(), "limit_age:variable".into()]));
+    assert_eq!(texts(".env l", 6), (5, vec!["load:keyword".into()]));
+    assert_eq!(texts(".env load l", 11), (10, vec!["large:environment".into()]));
+}
+
+#[test]
+fn test_error_codes() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| {
+        let statement = checked_statement(input, &limits).unwrap();
+        match repl.execute(statement) {
+            Ok(output) => output.to_string(),
+            Err(e) => e.code().to_string(),
+        }
+    };
+
+    assert_eq!(run("1 / 0"), "E0005");
+    assert_eq!(run(".insert x"), "E0003");
+    assert!(run(".explain e0005").starts_with("E0005: DivisionByZero\n"));
+    assert_eq!(run(".explain E9999"), "Unknown error code E9999.\n");
+    assert_eq!(
+        run(".explain 1 / 0"),
+        "Only queries, insertions, deletions, changes and moves can be explained.\n"
+    );
+
+    let env = Environment::new();
+    let Ok((_, expression)) = expression_multi("1 / 0") else {
+        unreachable!()
+    };
+    let error = env.eval_expr(&expression.expressions[0]).unwrap_err();
+    assert_eq!(lookup(error.code()).map(|e| e.name), Some("DivisionByZero"));
+
+    let error = explicit_statement("x = 5", &limits).err().unwrap();
+    assert_eq!(error.code(), "E0101");
+}
+
+#[test]
+fn test_ast_export() {
+    let limits = Limits::default();
+    let statement = checked_statement("x + 1", &limits).unwrap();
+    assert_eq!(
+        statement.to_json(),
+        serde_json::json!({"Eval": {"expressions": [{"Binary": {
+            "operator": "Plus",
+            "left": {"Identifier": {"name": "x"}},
+            "right": {"Literal": {"Number": "1"}},
+        }}]}})
+    );
+
+    let mut repl = Repl::open("init", None).unwrap();
+    let statement = checked_statement(".ast .drop people", &limits).unwrap();
+    let Ok(ReplOutput::Notice(output)) = repl.execute(statement) else {
+        unreachable!(".ast always succeeds");
+    };
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&output).unwrap(),
+        serde_json::json!({"DropBag": {"name": "people"}})
+    );
+}
+
+#[test]
+fn test_query_engine() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    repl.execute(checked_statement(".insert 5; 0; 2", &limits).unwrap()).unwrap();
+    repl.execute(checked_statement(".let n = 10", &limits).unwrap()).unwrap();
+
+    let Ok(Statement::Query(query)) = checked_statement(".query x into n / x", &limits) else {
+        unreachable!("query can be parsed");
+    };
+    let engine = QueryEngine::new(&repl.bag_bundle, repl.env.clone());
+
+    let mut results = engine.query(&repl.current_bag, &query).unwrap();
+    assert_matches!(results.next(), Some(Ok(Value::Integer(2))));
+    assert_matches!(results.next(), Some(Err(EvalError::MathDivision)));
+    assert_matches!(results.next(), Some(Ok(Value::Integer(5))));
+    assert_matches!(results.next(), None);
+
+    let missing = Identifier {
+        name: "missing".into(),
+    };
+    assert!(engine.query(&missing, &query).is_none());
+}
+
+#[test]
+fn test_ast_import() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    repl.execute(checked_statement(".insert {a: 1}; {a: 2}; {a: 3}", &limits).unwrap()).unwrap();
+
+    let parsed = checked_statement(".query {a: x} into x * 10 where x > 1", &limits).unwrap();
+    let imported = Statement::from_json(parsed.to_json(), &limits).unwrap();
+    let expected = repl.execute(parsed).unwrap().to_string();
+    assert_eq!(repl.execute(imported).unwrap().to_string(), expected);
+    assert!(expected.contains("20;") && expected.contains("30;"));
+
+    let invalid = serde_json::json!({"Eval": {"expressions": [{"Binary": {
+        "operator": "PowerOf",
+        "left": {"Literal": {"Number": "1"}},
+        "right": {"Literal": {"Numbr": "2"}},
+    }}]}});
+    assert_matches!(
+        Statement::from_json(invalid, &limits).err(),
+        Some(StatementError::InvalidNode { path, .. }) if path == "Eval.expressions[0].Binary.right.Literal"
+    );
+
+    let nested = (0..200).fold(serde_json::json!({"Literal": "Null"}), |e, _| {
+        serde_json::json!({"Unary": {"operator": "Not", "argument": e}})
+    });
+    assert_matches!(
+        Statement::from_json(serde_json::json!({"Eval": {"expressions": [nested]}}), &limits).err(),
+        Some(StatementError::Limit(_))
+    );
+}
+
+#[test]
+fn test_prepared_statements() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".insert {id: 1, v: 10}; {id: 2, v: 20}; {id: 3, v: 30}");
+    assert_eq!(run(".prepare find := .query {id, v} into v where id == $id"), "OK.\n");
+    assert_eq!(run(".prepare between := .query {id, v} into v where v > $min && v < $max"), "OK.\n");
+    assert_eq!(run(".prepare total := .query {v, ...} into sum(v)"), "OK.\n");
+    assert_eq!(run(".prepare square := .let y = x * x; x = $n"), "OK.\n");
+
+    assert_eq!(run(".execute find {id: 2}"), "20;\n");
+    assert_eq!(run(".execute find {id: 1 + 2}"), "30;\n");
+    assert_eq!(run(".execute between {min: 15, max: 100}"), run(".query {v, ...} into v where v > 15"));
+    assert_eq!(run(".execute total"), "60;\n");
+    assert_eq!(run(".execute square {n: 7}"), "YES.\nx := 7;\ny := 49;\n");
+    assert_eq!(run(".prepare pick := .query x where x.id == $id"), "OK.\n");
+    assert_eq!(run(".execute pick {id: 3}"), run(".query x where x.id == 3"));
+    assert_eq!(run(".execute find"), "PreparedError");
+    assert_eq!(run(".execute find {id: 2, other: 3}"), "PreparedError");
+    assert_eq!(run(".execute between {min: 1, mx: 2}"), "PreparedError");
+    assert_eq!(run(".execute unknown"), "PreparedError");
+    assert_eq!(run(".prepare nested := !cleanup"), "PreparedError");
+    assert_eq!(run("$id"), "EvalError(UnknownIdentifier)");
+}
+
+
+#[test]
+fn test_virtual_bags() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let generator: ValueGenerator =
+        std::sync::Arc::new(|| Box::new(["a", "b"].into_iter().map(|s| Value::String(s.into()))));
+    assert!(repl.bag_bundle.register_virtual_bag(Identifier { name: "letters".into() }, generator));
+
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    assert_eq!(run(".bag digits as virtual 0..5"), "BAG CREATED\n");
+    assert_eq!(run(".query x where x > 2"), "3;\n4;\n");
+    assert_eq!(run(".query a;b into a * b where a + b == 7"), "12;\n12;\n");
+    assert_eq!(run(".query x into count()"), "5;\n");
+    assert_eq!(run(".insert 7"), "TranscationAborted");
+    assert_eq!(run(".delete x"), "TranscationAborted");
+    assert_eq!(run(".bag digits as virtual 0..3"), "BagError");
+
+    assert_eq!(run(".bag naturals as virtual 1.."), "BAG CREATED\n");
+    assert_eq!(run(".query x where x % 3 == 0 limit 3"), "3;\n6;\n9;\n");
+    assert_eq!(run(".query x where x > 3"), "EvalError(OutOfFuel)");
+    assert_eq!(run(".query x into count() limit 1"), "EvalError(OutOfFuel)");
+    assert_eq!(run(".query x where x < 0 limit 1"), "EvalError(OutOfFuel)");
+    assert_eq!(run(".query a; b limit 1"), "EvalError(OutOfFuel)");
+    assert_eq!(run(".delta naturals naturals"), "EvalError(OutOfFuel)");
+
+    assert_eq!(run(".bag letters"), "SWITCHED BAG\n");
+    assert_eq!(run(".query x"), "\"a\";\n\"b\";\n");
+    assert_eq!(run(".pop \"a\""), "TranscationAborted");
+}
+
+#[test]
+fn test_parse_errors() {
+    let limits = Limits::default();
+    let error = |input: &str| match checked_statement(input, &limits) {
+        Err(StatementError::Syntax(e)) => e,
+        other => unreachable!("Statement can not be parsed, {input}: {:?}", other.err()),
+    };
+
+    let e = error(".query x wher x > 1");
+    assert_eq!((e.offset, e.line, e.column), (9, 1, 10));
+    assert_eq!(e.expected, vec![Expected::EndOfInput]);
+    assert_eq!(e.render(), ".query x wher x > 1\n         ^");
+
+    let e = error(".insert {a: 1,, b: 2}");
+    assert_eq!(e.offset, 14);
+    assert_eq!(e.expected, vec![Expected::Token("}")]);
+
+    let e = error(".connection foo(x){\n&b.test x; where (x != y);\n&a.consume [x,];y; wher ((2 * x) > y);\n}");
+    assert_eq!((e.line, e.column), (3, 25));
+    assert_eq!(e.source_line, "&a.consume [x,];y; wher ((2 * x) > y);");
+    assert_eq!(
+        e.to_string(),
+        "Syntax error at line 3, column 25, expected `}`\n&a.consume [x,];y; wher ((2 * x) > y);\n                        ^"
+    );
+
+    let e = error("[1, \"€\", 3");
+    assert_eq!((e.offset, e.column), (12, 11));
+    assert!(e.expected.contains(&Expected::Token("]")));
+}
+
+#[test]
+fn test_big_integers() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".insert 9223372036854775807; 9223372036854775807; 18446744073709551616; 5");
+    assert_eq!(run(".query x into sum(x)"), "36893488147419103235;\n");
+    assert_eq!(run(".query x into avg(x)"), "9223372036854775808;\n");
+    assert_eq!(run(".query x into max(x)"), "18446744073709551616;\n");
+    assert_eq!(run(".query x into min(x)"), "5;\n");
+    assert_eq!(run(".query x where x > 9223372036854775807"), "18446744073709551616;\n");
+    assert_eq!(run(".query 18446744073709551616 into 1"), "1;\n");
+    assert_eq!(run("2 ^ 65535 > 0"), "true;\n");
+    assert_eq!(run("2 ^ 65536"), "EvalError(Overflow)");
+    assert_eq!(run("2 ^ 4294967296"), "EvalError(Overflow)");
+    assert_eq!(
+        run("sort([18446744073709551616, -18446744073709551616, 1])"),
+        "[-18446744073709551616, 1, 18446744073709551616, ];\n"
+    );
+    assert_eq!(
+        run("sort([[18446744073709551616], [-18446744073709551616], [1]])"),
+        "[[-18446744073709551616, ], [1, ], [18446744073709551616, ], ];\n"
+    );
+}
+
+
+struct MemoryBackend {
+    rows: std::sync::Mutex<Vec<serde_json::Value>>,
+    filters: std::sync::Mutex<Vec<serde_json::Value>>,
+    bounds: std::sync::Mutex<Vec<Vec<String>>>,
+}
+
+impl<'s, 'v> BagBackend<'s, 'v> for MemoryBackend {
+    fn filter_keys(&self) -> Vec<String> {
+        vec!["id".to_string()]
+    }
+
+    fn scan(&self, filter: &ScanFilter<'s, 'v>) -> Result<Vec<Value<'s, 'v>>, BackendError> {
+        self.bounds
+            .lock()
+            .unwrap()
+            .push(filter.bounds.iter().map(|b| b.to_string()).collect());
+        let filter = serde_json::Value::Object(
+            filter.equals.iter().map(|(k, v)| (k.clone(), to_json(v).unwrap())).collect(),
+        );
+        self.filters.lock().unwrap().push(filter.clone());
+
+        Ok(self
+            .rows
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|row| filter.as_object().unwrap().iter().all(|(k, v)| &row[k] == v))
+            .map(|row| from_json(row).unwrap())
+            .collect())
+    }
+
+    fn insert(&self, values: &[Value<'s, 'v>]) -> Result<(), BackendError> {
+        let mut rows = self.rows.lock().unwrap();
+        for v in values {
+            let Value::Object(_) = v else {
+                return Err(BackendError::Unsupported);
+            };
+            rows.push(to_json(v).unwrap());
+        }
+        Ok(())
+    }
+
+    fn delete(&self, values: &[Value<'s, 'v>]) -> Result<(), BackendError> {
+        let mut rows = self.rows.lock().unwrap();
+        for v in values {
+            let json = to_json(v).unwrap();
+            let position = rows.iter().position(|r| r == &json).unwrap();
+            rows.remove(position);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backend_bags() {
+    let backend = std::sync::Arc::new(MemoryBackend {
+        rows: std::sync::Mutex::new(vec![serde_json::json!({"id": 1, "name": "a"})]),
+        filters: std::sync::Mutex::new(vec![]),
+        bounds: std::sync::Mutex::new(vec![]),
+    });
+    let mut repl = Repl::open("init", None).unwrap();
+    assert!(repl.bag_bundle.register_backend_bag(Identifier { name: "people".into() }, backend.clone()));
+    assert!(!repl.bag_bundle.register_backend_bag(Identifier { name: "people".into() }, backend.clone()));
+
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+    let last_filter = || backend.filters.lock().unwrap().last().cloned().unwrap();
+    let last_bounds = || backend.bounds.lock().unwrap().last().cloned().unwrap();
+    let rows = || backend.rows.lock().unwrap().clone();
+
+    assert_eq!(run(".bag people"), "SWITCHED BAG\n");
+    run(".insert {id: 2, name: \"b\"}; {id: 3, name: \"c\"}");
+    assert_eq!(rows().len(), 3);
+
+    assert_eq!(run(".query {id: 2, name} into name"), "\"b\";\n");
+    assert_eq!(last_filter(), serde_json::json!({"id": 2}));
+    assert_eq!(run(".query p into p.name where p.id == 3"), "\"c\";\n");
+    assert_eq!(last_filter(), serde_json::json!({"id": 3}));
+    assert_eq!(run(".query {name, ...} into count()"), "3;\n");
+    assert_eq!(last_filter(), serde_json::json!({}));
+    assert_eq!(run(".query {id, name} into name where id > 2 && 1 + 1 >= id && name != \"x\""), "");
+    assert_eq!(last_bounds(), vec!["id <= 2", "id > 2"]);
+    assert_eq!(run(".query p into p.name where 3 <= p.id || p.id < 1"), "\"c\";\n");
+    assert_eq!(last_bounds(), Vec::<String>::new());
+    assert_eq!(run(".query {id, name} into name where 2 < id"), "\"c\";\n");
+    assert_eq!(last_bounds(), vec!["id > 2"]);
+
+    run(".change {id: 1, name} into {id: 1, name: \"z\"}");
+    run(".delete {id: 2, ...}");
+    let mut names = rows().iter().map(|r| r["name"].clone()).collect::<Vec<_>>();
+    names.sort_by_key(|n| n.to_string());
+    assert_eq!(names, vec![serde_json::json!("c"), serde_json::json!("z")]);
+
+    assert_eq!(run(".insert 5"), "TranscationAborted");
+    assert_eq!(rows().len(), 2);
+    assert_eq!(run(".query x into count()"), "2;\n");
+}
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_sqlite_backend() {
+    use damasc::sqlite_backend::SqliteBackend;
+
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    connection
+        .execute_batch("CREATE TABLE people (id INTEGER, name TEXT); INSERT INTO people VALUES (1, 'ada');")
+        .unwrap();
+    let backend = SqliteBackend::new(connection, "people").unwrap();
+    assert!(SqliteBackend::new(rusqlite::Connection::open_in_memory().unwrap(), "missing").is_err());
+
+    let mut repl = Repl::open("init", None).unwrap();
+    repl.bag_bundle.register_backend_bag(Identifier { name: "people".into() }, std::sync::Arc::new(backend));
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".bag people");
+    run(".insert {id: 2, name: \"bob\"}; {id: 3}");
+    assert_eq!(run(".query {id: 3, name} into name"), "null;\n");
+    assert_eq!(run(".query {id, name} into id where id >= 2 && id < 3"), "2;\n");
+    assert_eq!(run(".query {id, name: null} into id where id > 0"), "3;\n");
+    assert_eq!(run(".query p into p.id where p.name == \"bob\""), "2;\n");
+    run(".delete {id: 1, ...}");
+    assert_eq!(run(".query {id, ...} into sum(id)"), "5;\n");
+    assert_eq!(run(".insert {id: [1]}"), "TranscationAborted");
+    assert_eq!(run(".insert {age: 5}"), "TranscationAborted");
+    assert_eq!(run(".query x into count()"), "2;\n");
+
+    // Nothing is deleted if an insertion of the same commit fails.
+    run(".begin");
+    run(".delete {id: 2, ...}");
+    run(".insert {id: 4, name: [1]}");
+    assert_eq!(run(".commit"), "TranscationAborted");
+    run(".rollback");
+    assert_eq!(run(".query {id, ...} into sum(id)"), "5;\n");
+
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    connection
+        .execute_batch("CREATE TABLE prices (amount REAL); INSERT INTO prices VALUES (1.5);")
+        .unwrap();
+    let prices = SqliteBackend::new(connection, "prices").unwrap();
+    assert_matches!(
+        damasc::backend::BagBackend::scan(&prices, &Default::default()),
+        Err(damasc::backend::BackendError::Unsupported)
+    );
+}
+
+#[test]
+fn test_datetimes() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".insert {t: @2024-02-01T08:00Z}; {t: @2023-12-24}; {t: @2024-01-15T12:30:00.500Z}");
+    assert_eq!(run(".query {t} into max(t)"), "@2024-02-01T08:00:00Z;\n");
+    assert_eq!(run(".query {t} into min(t)"), "@2023-12-24T00:00:00Z;\n");
+    assert_eq!(run(".query {t} into t as Integer where t >= @2024-01-01 && t < @2024-02-01"), "1705321800500;\n");
+    assert_eq!(run(".query {t: @2023-12-24} into 1"), "1;\n");
+    assert_eq!(run("@2023-02-30"), "EvalError(InvalidDatetime)");
+    assert_eq!(run("@9999-12-31T23:59:59Z + 1000"), "EvalError(Overflow)");
+    assert_eq!(run("@2024-01-15 + @2024-01-15"), "EvalError(TypeError)");
+    assert_eq!(run("\"yesterday\" as Datetime"), "EvalError(TypeError)");
+    assert!(checked_statement("@2024-1-15", &limits).is_err());
+}
+
+#[test]
+fn test_change_data_capture() {
+    use damasc::cdc::change_record;
+    use damasc::observer::Mutation;
+
+    let limits = Limits::default();
+    let records = std::sync::Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+    let mut repl = Repl::open("init", None).unwrap();
+    let observed = records.clone();
+    assert!(repl.bag_bundle.observe(
+        Identifier { name: "init".into() },
+        std::sync::Arc::new(move |bag: &Identifier, mutations: &[Mutation]| {
+            let mut records = observed.lock().unwrap();
+            records.extend(mutations.iter().map(|m| change_record(bag, m).unwrap()));
+        }),
+    ));
+    assert!(!repl.bag_bundle.observe(Identifier { name: "missing".into() }, std::sync::Arc::new(|_: &Identifier, _: &[Mutation]| {})));
+
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+    let taken = || std::mem::take(&mut *records.lock().unwrap());
+
+    run(".insert {id: 1}; {id: 2}");
+    assert_eq!(
+        taken(),
+        vec![
+            serde_json::json!({"bag": "init", "op": "insert", "after": {"id": 1}}),
+            serde_json::json!({"bag": "init", "op": "insert", "after": {"id": 2}}),
+        ]
+    );
+    run(".change {id: 2} into {id: 3}");
+    assert_eq!(
+        taken(),
+        vec![serde_json::json!({"bag": "init", "op": "update", "before": {"id": 2}, "after": {"id": 3}})]
+    );
+    run(".delete {id: 1}");
+    assert_eq!(taken(), vec![serde_json::json!({"bag": "init", "op": "delete", "before": {"id": 1}})]);
+    run(".bag other as _");
+    run(".bag init");
+    run(".move(other) {id}");
+    assert_eq!(taken(), vec![serde_json::json!({"bag": "init", "op": "delete", "before": {"id": 3}})]);
+    run(".query x");
+    run(".insert 5 + true");
+    assert_eq!(taken(), Vec::<serde_json::Value>::new());
+    assert_eq!(run(".cdc missing to log"), "BagError");
+
+    // Observers are only told about changes once they are stored.
+    let directory = std::env::temp_dir().join(format!("damasc_observed_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    let storage = damasc::storage::Storage::new(&directory).unwrap();
+    let mut repl = Repl::open("init", Some(storage)).unwrap();
+    let stored = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let seen = stored.clone();
+    let file = directory.join("init.bag");
+    assert!(repl.bag_bundle.observe(
+        Identifier { name: "init".into() },
+        std::sync::Arc::new(move |_: &Identifier, _: &[Mutation]| {
+            seen.lock().unwrap().push(std::fs::read_to_string(&file).unwrap_or_default());
+        }),
+    ));
+    let statement = checked_statement(".insert 42", &limits).unwrap();
+    repl.execute(statement).unwrap();
+    assert_matches!(stored.lock().unwrap().as_slice(), [contents] if contents.contains("42;"));
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn test_replication() {
+    let limits = Limits::default();
+    let log = std::env::temp_dir().join(format!("damasc_replication_{}", std::process::id()));
+    let _ = std::fs::remove_file(&log);
+    let path = || std::borrow::Cow::Owned(log.to_string_lossy().into_owned());
+    let execute = |repl: &mut Repl, input: &'static str| {
+        let statement = checked_statement(input, &limits).unwrap();
+        match repl.execute(statement) {
+            Ok(output) => output.to_string(),
+            Err(e) => format!("{e:?}"),
+        }
+    };
+
+    let mut primary = Repl::open("init", None).unwrap();
+    execute(&mut primary, ".insert {id: 1}; {id: 2}");
+    primary
+        .execute(Statement::Cdc(Identifier { name: "init".into() }, path()))
+        .unwrap();
+    execute(&mut primary, ".insert {id: 3}");
+    execute(&mut primary, ".change {id: 2} into {id: 4}");
+    execute(&mut primary, ".delete {id: 1}");
+    execute(&mut primary, ".insert {id: 6, at: @2024-01-15, n: 18446744073709551616, t: Integer}");
+    assert_eq!(execute(&mut primary, ".insert fn(x) => x"), "TranscationAborted");
+
+    let mut replica = Repl::open("init", None).unwrap();
+    execute(&mut replica, ".insert {id: 1}; {id: 5}");
+    for _ in 0..2 {
+        replica.execute(Statement::Replicate(path())).unwrap();
+        assert_eq!(
+            execute(&mut replica, ".query x order by x"),
+            execute(&mut primary, ".query x order by x")
+        );
+    }
+    assert_eq!(
+        execute(&mut replica, ".query x order by x"),
+        "{at: @2024-01-15T00:00:00Z, id: 6, n: 18446744073709551616, t: Integer, };\n{id: 3, };\n{id: 4, };\n"
+    );
+
+    let mut other = Repl::open("other", None).unwrap();
+    other.execute(Statement::Replicate(path())).unwrap();
+    assert_eq!(execute(&mut other, ".bag init"), "SWITCHED BAG\n");
+    assert_eq!(execute(&mut other, ".query {id, ...} into id order by id"), "3;\n4;\n6;\n");
+
+    std::fs::write(&log, "{\"op\": \"insert\"}\n").unwrap();
+    assert_matches!(replica.execute(Statement::Replicate(path())), Err(damasc::repl::ReplError::ParseError));
+    let _ = std::fs::remove_file(&log);
+}
+
+#[test]
+fn test_order_by() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".insert {n: \"c\", a: 3}; {n: \"a\", a: 1}; {n: \"b\", a: 2}; {n: \"d\", a: 2}; 18446744073709551616");
+    assert_eq!(run(".query {n, a} into n order by [a, n]"), "\"a\";\n\"b\";\n\"d\";\n\"c\";\n");
+    assert_eq!(run(".query {n, a} into n order by [a, n] desc limit 2"), "\"c\";\n\"d\";\n");
+    assert_eq!(run(".query {n, a} into n where a < 3 order by n asc limit 1"), "\"a\";\n");
+    assert_eq!(run(".query {a, ...} into [a, count()] group by a order by count() desc limit 1"), "[2, 2, ];\n");
+    assert_eq!(run(".query x is Integer order by x limit 1"), "18446744073709551616;\n");
+    assert_eq!(run(".query {n, a} into n order by a + n"), "EvalError(TypeError)");
+    run(".bag numbers as virtual 0..");
+    assert_eq!(run(".query x order by x limit 1"), "EvalError(OutOfFuel)");
+}
+
+#[test]
+fn test_collate() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".insert \"bob\"; \"Ann\"; \"alice\"; \"Bea\"");
+    assert_eq!(run(".query x order by x"), "\"Ann\";\n\"Bea\";\n\"alice\";\n\"bob\";\n");
+    assert_eq!(run(".query x order by x collate nocase"), "\"alice\";\n\"Ann\";\n\"Bea\";\n\"bob\";\n");
+    assert_eq!(run(".query x order by x collate nocase desc limit 2"), "\"bob\";\n\"Bea\";\n");
+    assert_eq!(run(".query x where x == \"ann\" collate nocase"), "\"Ann\";\n");
+    assert_eq!(run(".query x where x == \"ann\""), "");
+}
+
+#[test]
+fn test_offset() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".insert 1; 2; 3; 4; 5");
+    assert_eq!(run(".query x limit 2 offset 1"), "2;\n3;\n");
+    assert_eq!(run(".query x where x > 1 offset 3"), "5;\n");
+    assert_eq!(run(".query x order by x desc limit 2 offset 2"), "3;\n2;\n");
+    assert_eq!(run(".query x into x % 2 group by x % 2 offset 1"), "0;\n");
+    assert_eq!(run(".query x offset 9"), "");
+    assert_eq!(run(".query limit 1 offset 4"), "5;\n");
+    run(".change x into x * 10 where x > 2 limit 1 offset 1");
+    assert_eq!(run(".query x"), "1;\n2;\n3;\n40;\n5;\n");
+    run(".delete x offset 3");
+    assert_eq!(run(".query x"), "1;\n2;\n3;\n");
+    run(".bag numbers as virtual 0..");
+    assert_eq!(run(".query x limit 2 offset 10"), "10;\n11;\n");
+}
+
+#[test]
+fn test_server_protocol() {
+    let repl = std::sync::Mutex::new(Repl::new("init"));
+    let serve = |input: &str| {
+        let mut output = vec![];
+        damasc::server::serve(&repl, "secret", input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let responses = serve("secret\n.insert 1; 2\n\n.query x where x > 1\n.let f = fn(y) => y\n.delete\n.exit\n.query x\n");
+    assert_eq!(responses.len(), 5);
+    assert_eq!(responses[0]["status"], "ok");
+    assert_eq!(responses[1]["result"], serde_json::json!({"kind": "inserted", "count": 2}));
+    assert_eq!(responses[1]["metrics"]["affected"], 2);
+    assert_eq!(responses[2]["result"], serde_json::json!({"kind": "values", "values": [2]}));
+    assert_eq!(responses[2]["metrics"]["scanned"], 2);
+    assert!(responses[3]["result"]["bindings"]["f"].is_string());
+    assert_eq!(responses[4]["status"], "error");
+    assert_eq!(responses[4]["code"], "E0100");
+
+    let responses = serve("wrong\n.query x\n");
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0]["status"], "error");
+
+    // Bindings stay with their session, an open transaction is rolled back
+    // once the client disconnects.
+    let responses = serve("secret\n.let y = f\n.begin\n.insert 3\n.query x\n");
+    assert_eq!(responses[1]["code"], "E0204");
+    assert_eq!(responses[4]["result"]["values"], serde_json::json!([1, 2, 3]));
+    let responses = serve("secret\n.query x\n.begin\n");
+    assert_eq!(responses[1]["result"]["values"], serde_json::json!([1, 2]));
+    assert_eq!(responses[2]["status"], "ok");
+    // So does the history.
+    let responses = serve("secret\n.bag $history\n.query {input, ...} into input\n");
+    assert_eq!(responses[2]["result"]["values"][0], ".bag $history");
+
+    // Lines longer than the limit are rejected, before and after the token.
+    let long = "x".repeat(damasc::server::MAX_LINE as usize + 1);
+    let responses = serve(&format!("{long}\n.query x\n"));
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0]["message"], "Authentication required");
+    let responses = serve(&format!("secret\r\n.query x\n{long}\n.query x\n"));
+    assert_eq!(responses.len(), 3);
+    assert_eq!(responses[1]["result"]["values"], serde_json::json!([1, 2]));
+    assert_eq!(responses[2]["message"], "Line too long");
+}
+
+#[test]
+fn test_statement_metrics() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let run = |repl: &mut Repl, input: &'static str| {
+        repl.execute_measured(checked_statement(input, &limits).unwrap()).unwrap().1
+    };
+
+    assert_eq!(run(&mut repl, ".insert 1; 2; 3; 4").affected, 4);
+    let metrics = run(&mut repl, ".query x where x > 2");
+    assert_eq!((metrics.scanned, metrics.affected), (4, 0));
+    assert_eq!(run(&mut repl, ".query x limit 1").scanned, 1);
+    assert_eq!(run(&mut repl, ".query x; y").scanned, 16);
+    let metrics = run(&mut repl, ".change x into x * 10 where x > 2");
+    assert_eq!((metrics.scanned, metrics.affected), (4, 2));
+    assert_eq!(run(&mut repl, ".delete x where x > 30").affected, 1);
+
+    run(&mut repl, ".set verbose true");
+    let output = repl.execute(checked_statement(".delete 30", &limits).unwrap()).unwrap();
+    assert_eq!(output.affected(), 1);
+    assert_eq!(output.to_json()["kind"], "deleted");
+    assert_eq!(output.to_json()["metrics"]["affected"], 1);
+    assert!(output.to_string().starts_with("DELETED 1 items.\n-- "));
+    assert!(output.to_string().ends_with("ms, 3 scanned, 1 affected\n"));
+}
+
+#[test]
+fn test_triggers() {
+    let mut repl = Repl::open("a", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".bag b");
+    run(".bag c");
+    run(".bag a");
+    run(".connection double() { &a.consume x where true; &b.produce x * 2 }");
+    run(".connection again() { &b.test x where x < 10; &c.produce x }");
+    assert_eq!(run(".trigger missing on insert into a"), "ConnectionError");
+    assert_eq!(run(".trigger double on insert into nowhere"), "BagError");
+    run(".trigger double on insert into a");
+    run(".trigger again on insert into b");
+
+    run(".insert 1; 2; 30");
+    assert_eq!(run(".query x"), "");
+    run(".bag b");
+    assert_eq!(run(".query x order by x"), "2;\n4;\n60;\n");
+    run(".bag c");
+    assert_eq!(run(".query x order by x"), "2;\n4;\n");
+
+    // A connection feeding its own bag never settles down.
+    run(".connection echo() { &c.test x where true; &c.produce x }");
+    run(".trigger echo on insert into c");
+    assert_eq!(run(".insert 5"), "ConnectionError");
+    assert_eq!(run(".query x order by x"), "2;\n4;\n");
+    run(".disconnect echo");
+    assert_eq!(run(".insert 5"), "INSERTED 1 items.\n");
+}
+
+#[test]
+fn test_trigger_network() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("totals", None).unwrap();
+    let mut run = |input: &'static str| {
+        let (output, metrics) = repl.execute_measured(checked_statement(input, &limits).unwrap()).unwrap();
+        (output.to_string(), metrics.scanned)
+    };
+
+    run(".bag orders");
+    run(".connection total() { &orders.consume {kind: \"order\", n} where true; &totals.produce n }");
+    run(".trigger total on insert into orders");
+
+    // The network rules out the connection without scanning the bag.
+    assert_eq!(run(".insert {kind: \"note\", n: 1}").1, 0);
+    assert_ne!(run(".insert {kind: \"order\", n: 2}").1, 0);
+
+    // Literals the network has no key for are considered for every value.
+    run(".connection big() { &orders.consume 18446744073709551616 where true; &totals.produce 0 }");
+    run(".trigger big on insert into orders");
+    run(".insert 18446744073709551616");
+    run(".bag totals");
+    assert_eq!(run(".query x order by x").0, "0;\n2;\n");
+}
+
+#[test]
+fn test_solve() {
+    let mut repl = Repl::open("a", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".bag b");
+    run(".bag a");
+    run(".insert 1; 2; 3; 4; 10");
+    run(".connection take(n) { &a.consume x; y where x + y == n; &b.produce x * y }");
+    assert_eq!(
+        run(".solve take 5"),
+        "SOLVED.\na: 4 deleted, 0 inserted\nb: 0 deleted, 2 inserted\n"
+    );
+    assert_eq!(run(".query x"), "10;\n");
+    assert_eq!(run(".solve take 5"), "SOLVED.\n");
+    assert_eq!(run(".solve missing 5"), "connection not defined\n");
+    run(".bag b");
+    assert_eq!(run(".query x order by x"), "4;\n6;\n");
+}
+
+
+#[test]
+fn test_incremental_solve() {
+    let solve = |incremental: &'static str| {
+        let mut repl = Repl::open("src", None).unwrap();
+        let mut run = |input: &'static str| run_statement(&mut repl, input);
+        run(".bag dst");
+        run(".bag src");
+        run(".connection take(n) { &src.consume x; y where x + y == n; &dst.produce [x, y] }");
+        run(incremental);
+        run(".insert 1; 2");
+        run(".insert 3");
+        run(".insert 4");
+        let solved = run(".solve take 5");
+        (solved, run(".query x"))
+    };
+
+    // Only the combinations with a value of the last commit are explored.
+    assert_eq!(
+        solve(".set incremental true"),
+        ("SOLVED.\ndst: 0 deleted, 1 inserted\nsrc: 2 deleted, 0 inserted\n".to_string(), "2;\n3;\n".to_string())
+    );
+    assert_eq!(
+        solve(".set incremental false"),
+        ("SOLVED.\ndst: 0 deleted, 2 inserted\nsrc: 4 deleted, 0 inserted\n".to_string(), "".to_string())
+    );
+}
+
+#[test]
+fn test_step_debugger() {
+    let mut repl = Repl::open("a", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".bag b");
+    run(".bag a");
+    run(".insert 1; 2; 3; 4");
+    run(".connection take(n) { &a.consume x; y where x + y == n; &b.produce x * y }");
+    assert_eq!(run(".continue"), "SteppingState");
+
+    let first = run(".solve take 5 step");
+    assert!(first.starts_with("CANDIDATE 1 of 4 for take.\nn := 5; -- parameter\n"));
+    assert!(first.contains("x := 1; -- &a.consume\ny := 4; -- &a.consume\n"));
+    assert!(first.contains("&a.consume 1;\n&a.consume 4;\n&b.produce 4;\n"));
+    assert_eq!(run(".solve take 5 step"), "SteppingState");
+
+    // Changes of the bags wait for the stepping, queries do not.
+    assert_eq!(run(".insert 10"), "QUEUED until the stepping ends.\n");
+    assert_eq!(run(".query x where x > 3"), "4;\n");
+
+    assert!(run(".skip").starts_with("CANDIDATE 2 of 4"));
+    let third = run(".continue");
+    assert!(third.starts_with("CANDIDATE 3 of 4"));
+    assert!(third.contains("-- conflicts with an accepted candidate\n"));
+    assert!(run(".continue").starts_with("CANDIDATE 4 of 4"));
+    assert_eq!(
+        run(".skip"),
+        "SOLVED.\na: 2 deleted, 0 inserted\nb: 0 deleted, 1 inserted\nINSERTED 1 items.\n"
+    );
+    assert_eq!(run(".query x order by x"), "1;\n4;\n10;\n");
+    assert_eq!(run(".abort"), "SteppingState");
+
+    run(".solve take 5 step");
+    assert_eq!(run(".abort"), "ABORTED.\n");
+    assert_eq!(run(".solve take 50 step"), "SOLVED.\n");
+    run(".bag b");
+    assert_eq!(run(".query x"), "6;\n");
+}
+
+#[test]
+fn test_breakpoints() {
+    let file = std::env::temp_dir().join(format!("damasc_breakpoints_{}", std::process::id()));
+    let run_file = Statement::Run(std::borrow::Cow::Owned(file.to_string_lossy().into_owned()));
+    let mut repl = Repl::open("ordered", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".break on insert into ordered where total > 1000");
+    run(".break on insert into big {n, ...} where n > 5");
+    assert_eq!(
+        run(".break"),
+        ".break on insert into ordered where total > 1000\n.break on insert into big {n, ...} where n > 5\n"
+    );
+    // Only interactive frontends are paused.
+    assert_eq!(run(".insert {total: 2000}"), "INSERTED 1 items.\n");
+    repl.interactive = true;
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    assert_eq!(run(".insert {total: 5}; [1200]"), "INSERTED 2 items.\n");
+    assert_eq!(
+        run(".insert {total: 1500, id: 2}"),
+        "INSERTED 1 items.\nBREAK on insert into ordered.\n{id: 2, total: 1500, };\n\
+         -- from .insert {total: 1500, id: 2}\n-- .continue or .abort\n"
+    );
+    assert_eq!(run(".skip"), "SteppingState");
+    assert_eq!(run(".insert 1"), "QUEUED until .continue.\n");
+    assert_eq!(run(".query {id, ...} into id"), "2;\n");
+    assert_eq!(run(".continue"), "RESUMED.\nINSERTED 1 items.\n");
+    assert_eq!(run(".continue"), "SteppingState");
+
+    // The rest of a script waits, values produced by connections are
+    // attributed to them.
+    run(".bag big");
+    run(".bag ordered");
+    run(".connection grow(n) { &ordered.test _ where true; &big.produce {n} }");
+    std::fs::write(&file, ".insert 2\n.solve grow 9\n.insert 3\n.insert 4\n").unwrap();
+    let output = repl.execute(run_file.clone()).unwrap().to_string();
+    assert!(output.starts_with("Ran 2 statements from file"));
+    assert!(output.contains("BREAK on insert into big.\n{n: 9, };\n-- from connection grow\n"));
+    assert!(output.ends_with("-- 2 statements queued, .continue or .abort\n"));
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+    assert_eq!(run(".abort"), "ABORTED, 2 queued statements dropped.\n");
+    assert_eq!(run(".query x where x == 3 || x == 4"), "");
+
+    run(".break off");
+    assert_eq!(run(".insert {total: 3000}"), "INSERTED 1 items.\n");
+    let _ = std::fs::remove_file(&file);
+}
+
+#[test]
+fn test_presentation() {
+    use damasc::presentation::Theme;
+
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| {
+        repl.execute(checked_statement(input, &limits).unwrap()).unwrap()
+    };
+
+    run(".insert {name: \"a\", count: 12, done: true}");
+    for input in [".query x", ".insert 1", "x = 5", ".bags", ".match 1 = 2"] {
+        let output = run(input);
+        assert_eq!(Theme::PLAIN.render(&output), output.to_string());
+    }
+
+    let output = run(".query x where x.count == 12");
+    assert_eq!(
+        Theme::COLORED.render(&output),
+        "{count: \x1b[36m12\x1b[0m, done: \x1b[35mtrue\x1b[0m, name: \x1b[32m\"a\"\x1b[0m, };\n"
+    );
+    assert_eq!(Theme::COLORED.error("Error"), "\x1b[31mError\x1b[0m");
+
+    let input = ".query [x, \"(\"] where x > 1)";
+    assert_eq!(Theme::PLAIN.input(input), input);
+    assert_eq!(
+        Theme::COLORED.input(input),
+        "\x1b[35m.query\x1b[0m [\x1b[33mx\x1b[0m, \x1b[32m\"(\"\x1b[0m] \x1b[35mwhere\x1b[0m \x1b[33mx\x1b[0m > \x1b[36m1\x1b[0m\x1b[31m)\x1b[0m"
+    );
+    assert_eq!(
+        Theme::COLORED.input("(1 /* ) */ // x\n)"),
+        "(\x1b[36m1\x1b[0m \x1b[2m/* ) */ // x\x1b[0m\n)"
+    );
+    assert_eq!(Theme::COLORED.input("{a: [1}"), "\x1b[31m{\x1b[0m\x1b[33ma\x1b[0m: \x1b[31m[\x1b[0m\x1b[36m1\x1b[0m\x1b[31m}\x1b[0m");
+
+    run(".set color true");
+    assert!(run(".set").to_string().contains("color = true"));
+}
+
+#[test]
+fn test_ndjson() {
+    use damasc::repl::ReplError;
+    use damasc::statement::DataFormat;
+
+    let limits = Limits::default();
+    let file = std::env::temp_dir().join(format!("damasc_ndjson_{}", std::process::id()));
+    let path = || std::borrow::Cow::Owned(file.to_string_lossy().into_owned());
+    let mut repl = Repl::open("init", None).unwrap();
+
+    repl.execute(checked_statement(".insert {id: 1, tags: [\"a\"]}; 2", &limits).unwrap())
+        .unwrap();
+    repl.execute(Statement::Export(path(), DataFormat::Ndjson, false)).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&file).unwrap(),
+        "{\"id\":1,\"tags\":[\"a\"]}\n2\n"
+    );
+
+    let lines = ["{\"id\": 3}", "{broken", "", "1.5", "\"text\""];
+    std::fs::write(&file, lines.join("\n")).unwrap();
+    let output = repl.execute(Statement::Import(path(), DataFormat::Ndjson, false)).unwrap();
+    assert!(output
+        .to_string()
+        .ends_with("into current bag(init)\nSkipped 2 malformed lines: 2, 4\n"));
+    let output = repl.execute(checked_statement(".query x", &limits).unwrap()).unwrap();
+    assert_eq!(output.to_string(), "{id: 1, tags: [\"a\", ], };\n2;\n{id: 3, };\n\"text\";\n");
+
+    // A value that can not be exported keeps the previous file untouched.
+    let previous = std::fs::read_to_string(&file).unwrap();
+    repl.execute(checked_statement(".insert fn(y) => y", &limits).unwrap()).unwrap();
+    assert_matches!(
+        repl.execute(Statement::Export(path(), DataFormat::Ndjson, false)),
+        Err(ReplError::EvalError(EvalError::TypeError))
+    );
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), previous);
+    assert!(!std::path::Path::new(&format!("{}.partial", file.display())).exists());
+    let _ = std::fs::remove_file(&file);
+}
+
+#[test]
+fn test_profile() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".insert 1; 2; 3; 4; 5; 6");
+    run(".query x where x > 1 && x == 6");
+    assert!(run(".profile").starts_with("No guards evaluated."));
+
+    run(".set profile true");
+    assert_eq!(run(".query x where x > 1 && x == 6"), "6;\n");
+    assert_eq!(
+        run(".profile"),
+        "x > 1 && x == 6\n  x > 1: 6 evaluated, 1 rejected (17%)\n  \
+         x == 6: 5 evaluated, 4 rejected (80%)\n  hint: check x == 6 first\n"
+    );
+    run(".profile reset");
+    run(".set profile false");
+    run(".query x where x > 1");
+    assert!(run(".profile").starts_with("No guards evaluated."));
+}
+
+#[test]
+fn test_lenient() {
+    use damasc::capabilities::Capabilities;
+    use damasc::repl::ReplError;
+
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    assert_eq!(run("[rate, 1]"), "EvalError(UnknownIdentifier)");
+    assert_eq!(run(".let x = rate"), "AssignmentError");
+
+    run(".set lenient true");
+    assert_eq!(
+        run("[rate, 1]"),
+        "[null, 1, ];\n-- warning: unknown identifier rate evaluated to null\n"
+    );
+    assert_eq!(
+        run(".let y = x; x = rate"),
+        "YES.\nx := null;\ny := null;\n-- warning: unknown identifier rate evaluated to null\n"
+    );
+    assert_eq!(run("y"), "null;\n");
+    run(".insert {v: 1}");
+    assert_eq!(
+        run(".query {v} where v == missing"),
+        "-- warning: unknown identifier missing evaluated to null\n"
+    );
+
+    // A visitor of a shared repl may not change how it evaluates for others.
+    repl.capabilities = Capabilities::ALL.without(Capabilities::SESSION);
+    let statement = checked_statement(".set lenient false", &limits).unwrap();
+    assert_matches!(repl.execute(statement), Err(ReplError::Denied));
+    assert!(repl.settings.lenient);
+}
+
+#[test]
+fn test_transaction() {
+    use damasc::observer::Mutation;
+
+    let notified = std::sync::Arc::new(std::sync::Mutex::new(0));
+    let mut repl = Repl::open("init", None).unwrap();
+    let counter = notified.clone();
+    assert!(repl.bag_bundle.observe(
+        Identifier { name: "init".into() },
+        std::sync::Arc::new(move |_: &Identifier, mutations: &[Mutation]| {
+            *counter.lock().unwrap() += mutations.len();
+        }),
+    ));
+
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+    let notifications = || std::mem::take(&mut *notified.lock().unwrap());
+
+    assert_eq!(run(".commit"), "TransactionState");
+    run(".insert {id: 1}; {id: 2}");
+    assert_eq!(notifications(), 2);
+
+    assert_eq!(run(".begin"), "BEGIN.\n");
+    assert_eq!(run(".begin"), "TransactionState");
+    run(".delete {id: 1}");
+    run(".bag archive as _");
+    run(".insert {id: 1}");
+    run(".bag init");
+    run(".let y = 5");
+    run(".macro grow := .insert {id: 9}");
+    assert_eq!(run(".query x"), "{id: 2, };\n");
+    assert_eq!(notifications(), 0);
+    assert_eq!(run(".rollback"), "ROLLBACK.\n");
+    assert_eq!(run(".query x order by x"), "{id: 1, };\n{id: 2, };\n");
+    assert!(!run(".bags").contains("archive"));
+    assert_eq!(run("y"), "EvalError(UnknownIdentifier)");
+    assert_eq!(run("!grow"), "EvalError(UnknownIdentifier)");
+    assert_eq!(notifications(), 0);
+
+    run(".begin");
+    run(".delete {id: 1}");
+    run(".insert {id: 3}");
+    assert_eq!(notifications(), 0);
+    assert_eq!(run(".commit"), "COMMIT.\n");
+    assert_eq!(notifications(), 2);
+    assert_eq!(run(".query x order by x"), "{id: 2, };\n{id: 3, };\n");
+    assert_eq!(run(".rollback"), "TransactionState");
+
+    // A commit whose observers failed can be tried again.
+    struct FailOnce(std::sync::atomic::AtomicBool);
+    impl<'s, 'v> damasc::observer::MutationObserver<'s, 'v> for FailOnce {
+        fn notify(
+            &self,
+            _: &Identifier<'s>,
+            _: &[Mutation<'s, 'v>],
+        ) -> Result<(), damasc::observer::ObserverError> {
+            match self.0.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                true => Err(damasc::observer::ObserverError),
+                false => Ok(()),
+            }
+        }
+    }
+    let flaky = FailOnce(std::sync::atomic::AtomicBool::new(true));
+    repl.bag_bundle.observe(Identifier { name: "init".into() }, std::sync::Arc::new(flaky));
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+    run(".begin");
+    run(".insert {id: 4}");
+    assert_eq!(run(".commit"), "TranscationAborted");
+    assert_eq!(run(".commit"), "COMMIT.\n");
+    assert_eq!(notifications(), 2);
+}
+
+#[test]
+fn test_transaction_conflict() {
+    let mut first = Repl::open("init", None).unwrap();
+    let mut second = Repl::open("init", None).unwrap();
+    second.bag_bundle = first.bag_bundle.clone();
+    let version = first.bag_bundle.version();
+
+
+    assert_eq!(run_statement(&mut first, ".insert 1"), "INSERTED 1 items.\n");
+    assert_eq!(first.bag_bundle.version(), version + 1);
+    assert_eq!(run_statement(&mut second, ".query x"), "");
+    assert_eq!(run_statement(&mut second, ".insert 2"), "TransactionConflict");
+    assert_eq!(second.bag_bundle.version(), version);
+
+    second.bag_bundle = first.bag_bundle.clone();
+    assert_eq!(run_statement(&mut second, ".insert 2"), "INSERTED 1 items.\n");
+    assert_eq!(run_statement(&mut second, ".query x order by x"), "1;\n2;\n");
+    assert_eq!(run_statement(&mut first, ".delete x"), "TransactionConflict");
+    assert_eq!(run_statement(&mut first, ".query x"), "1;\n");
+
+    // Rolling back does not discard what the others committed meanwhile.
+    first.bag_bundle = second.bag_bundle.clone();
+    assert_eq!(run_statement(&mut first, ".begin"), "BEGIN.\n");
+    assert_eq!(run_statement(&mut second, ".insert 3"), "INSERTED 1 items.\n");
+    assert_eq!(run_statement(&mut first, ".rollback"), "TransactionConflict");
+    assert_eq!(run_statement(&mut first, ".rollback"), "TransactionState");
+}
+
+#[test]
+fn test_assert() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    assert_eq!(run("assert(1 < 2, \"ordered\")"), "true;\n");
+    assert_eq!(
+        run("assert(1 > 2, \"not ordered\")"),
+        "AssertionError(\"not ordered\")"
+    );
+    assert_eq!(run("assert(1, \"not a boolean\")"), "EvalError(TypeError)");
+    assert_eq!(run("assert(true)"), "EvalError(ArgumentMismatch)");
+
+    run(".insert {age: 20}; {age: -1}");
+    assert_eq!(
+        run(".query {age} into age where assert(age >= 0, \"age is negative\") && age > 18"),
+        "20;\n-- warning: assertion failed: age is negative\n"
+    );
+    run(".bag adults as {age} where assert(age >= 18, \"too young\")");
+    assert_eq!(run(".insert {age: 30}"), "INSERTED 1 items.\n");
+    // A guard only rejects the value, the message of its assertion is no error.
+    assert_eq!(run(".insert {age: 3}"), "GuardError");
+    run(".bag init");
+    assert_eq!(
+        run(".query {age} into age.missing where assert(age >= 0, \"age is negative\")"),
+        "EvalError(TypeError)"
+    );
+}
+
+#[test]
+fn test_bag_statistics() {
+    let mut repl = Repl::open("init", None).unwrap();
+
+    run_statement(&mut repl, ".insert {id: 1, name: \"a\"}; {id: 2}; {id: 2}; 7; \"text\"");
+    let init = Identifier { name: "init".into() };
+    let statistics = repl.bag_bundle.bags[&init].statistics().unwrap();
+    assert_eq!(statistics.count, 5);
+    assert_eq!(statistics.distinct, 4);
+    assert_eq!(
+        statistics.types.into_iter().collect::<Vec<_>>(),
+        vec![(ValueType::String, 1), (ValueType::Integer, 1), (ValueType::Object, 3)]
+    );
+    assert_eq!(statistics.keys, vec![("id".to_string(), 3), ("name".to_string(), 1)]);
+    assert!(statistics.bytes > 0);
+
+    let output = run_statement(&mut repl, ".stats init");
+    assert!(output.starts_with("count: 5\ndistinct: 4\nmemory: ~"));
+    assert!(output.ends_with("types:\n  String: 1\n  Integer: 1\n  Object: 3\nkeys:\n  id: 3\n  name: 1\n"));
+    assert_eq!(run_statement(&mut repl, ".stats missing"), "BagError");
+    run_statement(&mut repl, ".bag digits as virtual 0..10");
+    assert_eq!(run_statement(&mut repl, ".stats digits"), "BagError");
+}
+
+#[test]
+fn test_log() {
+    let mut repl = Repl::open("init", None).unwrap();
+
+    run_statement(&mut repl, ".log 42");
+    run_statement(&mut repl, ".log warn {attempt: 3}");
+    run_statement(&mut repl, ".let error = \"bare\"");
+    run_statement(&mut repl, ".log error");
+    assert_eq!(run_statement(&mut repl, ".log missing"), "EvalError(UnknownIdentifier)");
+
+    run_statement(&mut repl, ".bag log");
+    assert_eq!(
+        run_statement(&mut repl, ".query {level, value, time, ...} into [level, value] where time is Datetime"),
+        "[\"info\", 42, ];\n[\"warn\", {attempt: 3, }, ];\n[\"info\", \"bare\", ];\n"
+    );
+    assert_eq!(
+        run_statement(&mut repl, ".query {level: \"warn\", value, ...} into value"),
+        "{attempt: 3, };\n"
+    );
+
+    run_statement(&mut repl, ".set log_bag \"audit\"");
+    run_statement(&mut repl, ".log debug 1");
+    run_statement(&mut repl, ".bag audit");
+    assert_eq!(run_statement(&mut repl, ".query {level, ...} into level"), "\"debug\";\n");
+    assert_eq!(run_statement(&mut repl, ".set log_bag \"\""), "SettingError");
+}
+
+#[test]
+fn test_distinct_bag() {
+    use damasc::bag::StorageMode;
+    use damasc::snapshot::Snapshot;
+
+    let mut repl = Repl::open("init", None).unwrap();
+
+    run_statement(&mut repl, ".bag tags as _ is String distinct");
+    assert_eq!(run_statement(&mut repl, ".insert \"a\"; \"b\"; \"a\""), "INSERTED 2 items.\n");
+    assert_eq!(run_statement(&mut repl, ".insert \"b\""), "INSERTED 0 items.\n");
+    assert_eq!(run_statement(&mut repl, ".insert 1"), "GuardError");
+    run_statement(&mut repl, ".change x into \"b\" where x == \"a\"");
+    assert_eq!(run_statement(&mut repl, ".query x"), "\"b\";\n");
+    run_statement(&mut repl, ".pop \"b\"");
+    assert_eq!(run_statement(&mut repl, ".insert \"b\""), "INSERTED 1 items.\n");
+
+    run_statement(&mut repl, ".bag init");
+    assert_eq!(run_statement(&mut repl, ".insert 1; 1"), "INSERTED 2 items.\n");
+
+    let snapshot = Snapshot::of(&repl.bag_bundle);
+    let modes = snapshot.bags.iter().map(|b| (b.name.as_str(), b.mode)).collect::<Vec<_>>();
+    assert_eq!(modes, vec![("init", StorageMode::Multiset), ("tags", StorageMode::Distinct)]);
+
+    let mut bundle = vec![];
+    repl.write_bundle(&mut bundle).unwrap();
+    assert!(String::from_utf8(bundle)
+        .unwrap()
+        .contains(".bag tags as _ is String where true distinct\n\"b\";\n"));
+}
+
+#[test]
+fn test_counted_bag() {
+    let mut repl = Repl::open("init", None).unwrap();
+
+    run_statement(&mut repl, ".bag words as _ is String counted");
+    assert_eq!(run_statement(&mut repl, ".insert \"a\"; \"b\"; \"a\"; \"a\""), "INSERTED 4 items.\n");
+    assert_eq!(
+        run_statement(&mut repl, ".query x into [x, count_of(x)]"),
+        "[\"a\", 3, ];\n[\"b\", 1, ];\n"
+    );
+    assert_eq!(run_statement(&mut repl, ".query x into sum(count_of(x))"), "4;\n");
+    assert_eq!(run_statement(&mut repl, ".query x into count_of(\"c\")"), "0;\n0;\n");
+
+    run_statement(&mut repl, ".pop \"a\"");
+    assert_eq!(run_statement(&mut repl, ".query x into count_of(x) where x == \"a\""), "2;\n");
+    assert_eq!(run_statement(&mut repl, ".delete x where x == \"a\""), "DELETED 2 items.\n");
+    run_statement(&mut repl, ".insert \"c\"; \"c\"");
+    run_statement(&mut repl, ".change x into \"b\" where x == \"c\"");
+    assert_eq!(run_statement(&mut repl, ".query x into [x, count_of(x)]"), "[\"b\", 3, ];\n");
+    assert_eq!(run_statement(&mut repl, ".delete x limit 1"), "DELETED 1 items.\n");
+    assert_eq!(run_statement(&mut repl, ".query x into count_of(x)"), "2;\n");
+    run_statement(&mut repl, ".insert \"b\"");
+
+    let words = Identifier { name: "words".into() };
+    assert_eq!(repl.bag_bundle.bags[&words].statistics().unwrap().count, 3);
+    let mut bundle = vec![];
+    repl.write_bundle(&mut bundle).unwrap();
+    assert!(String::from_utf8(bundle)
+        .unwrap()
+        .contains(" counted\n\"b\";\n\"b\";\n\"b\";\n"));
+
+    run_statement(&mut repl, ".bag init");
+    run_statement(&mut repl, ".insert 1; 1");
+    assert_eq!(run_statement(&mut repl, ".query x into count_of(x)"), "1;\n1;\n");
+}
+
+#[test]
+fn test_system_bags() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let run = |repl: &mut Repl, input: &'static str| {
+        let statement = repl.parse(input).unwrap();
+        match repl.execute(statement) {
+            Ok(output) => output.to_string(),
+            Err(e) => format!("{e:?}"),
+        }
+    };
+
+    run(&mut repl, ".insert 1; 2");
+    run(&mut repl, ".bag digits as virtual 0..3");
+    run(&mut repl, ".connection double() { &init.consume x where true; &init.produce x * 2 }");
+    run(&mut repl, ".bag $bags");
+    assert_eq!(
+        run(&mut repl, ".query {name, size, virtual, ...} into [name, size, virtual]"),
+        "[\"digits\", null, true, ];\n[\"init\", 2, false, ];\n"
+    );
+    assert_eq!(run(&mut repl, ".insert 3"), "TranscationAborted");
+
+    run(&mut repl, ".bag $settings");
+    assert_eq!(run(&mut repl, ".query {name: \"lenient\", value, ...} into value"), "false;\n");
+    run(&mut repl, ".set lenient true");
+    assert_eq!(run(&mut repl, ".query {name: \"lenient\", value, ...} into value"), "true;\n");
+
+    run(&mut repl, ".bag $connections");
+    assert_eq!(run(&mut repl, ".query {name, ...} into name"), "\"double\";\n");
+
+    run(&mut repl, ".bag $history");
+    assert_eq!(
+        run(&mut repl, ".query {index, input, ...} into input where index < 2"),
+        "\".insert 1; 2\";\n\".bag digits as virtual 0..3\";\n"
+    );
+
+    assert_eq!(run(&mut repl, ".bag $unknown"), "BagError");
+    assert!(repl.parse(".bag $bags as _").is_err());
+    run(&mut repl, ".bag init");
+    assert_eq!(run(&mut repl, ".bags"), "Bags: digits, init\n");
+}
+
+#[test]
+fn test_rename_and_copy_bag() {
+    let mut repl = Repl::open("init", None).unwrap();
+
+    run_statement(&mut repl, ".bag people as {age, ...} where age >= 0");
+    run_statement(&mut repl, ".insert {age: 12}; {age: 30}; {age: 45}");
+
+    assert_eq!(run_statement(&mut repl, ".copy people backup"), "INSERTED 3 items.\n");
+    assert_eq!(
+        run_statement(&mut repl, ".copy people adults {age, ...} where age >= 18 limit 1"),
+        "INSERTED 1 items.\n"
+    );
+    assert_eq!(run_statement(&mut repl, ".copy people adults"), "BagError");
+    assert_eq!(run_statement(&mut repl, ".copy missing other"), "BagError");
+
+    assert_eq!(run_statement(&mut repl, ".rename backup archive"), "BAG RENAMED\n");
+    assert_eq!(run_statement(&mut repl, ".rename backup other"), "BagError");
+    assert_eq!(run_statement(&mut repl, ".rename archive adults"), "BagError");
+    run_statement(&mut repl, ".bag archive");
+    assert_eq!(run_statement(&mut repl, ".query {age} into age"), "12;\n30;\n45;\n");
+    // The copy has the guard of the copied bag.
+    assert_eq!(run_statement(&mut repl, ".insert {age: -1}"), "GuardError");
+
+    assert_eq!(run_statement(&mut repl, ".rename archive current"), "BAG RENAMED\n");
+    assert_eq!(
+        run_statement(&mut repl, ".bag"),
+        "Current Bag: current, size: 3, constraint: {age, ...} where age >= 0\n"
+    );
+    let bags = repl.bags().into_iter().map(|b| b.name.to_string()).collect::<Vec<_>>();
+    assert_eq!(bags, ["adults", "current", "init", "people"]);
+
+    run_statement(&mut repl, ".connection c() { &people.consume {age, ...}; }");
+    assert_eq!(run_statement(&mut repl, ".rename people persons"), "BagError");
+}
+
+#[test]
+fn test_merge() {
+    let mut repl = Repl::open("target", None).unwrap();
+
+    run_statement(&mut repl, ".insert {id: 1, v: 1, t: 5}; {id: 2, v: 2, t: 5}");
+    run_statement(&mut repl, ".bag source");
+    run_statement(&mut repl, ".insert {id: 1, v: 9, t: 1}; {id: 2, v: 8, t: 9}; {id: 3, v: 3, t: 0}");
+    run_statement(&mut repl, ".bag target");
+
+    assert_eq!(
+        run_statement(&mut repl, ".merge from source into target on fn({id, ...}) => id prefer target"),
+        "MERGED: 1 inserted, 0 updated, 2 skipped.\n"
+    );
+    assert_eq!(
+        run_statement(&mut repl, ".merge from source into target on fn({id, ...}) => id prefer newest by fn({t, ...}) => t"),
+        "MERGED: 0 inserted, 1 updated, 2 skipped.\n"
+    );
+    assert_eq!(
+        run_statement(&mut repl, ".query {id, v, ...} into [id, v] order by id"),
+        "[1, 1, ];\n[2, 8, ];\n[3, 3, ];\n"
+    );
+    assert_eq!(
+        run_statement(&mut repl, ".merge from source into target on fn({id, ...}) => id"),
+        "MERGED: 0 inserted, 1 updated, 2 skipped.\n"
+    );
+    assert_eq!(
+        run_statement(&mut repl, ".query {id, v, ...} into [id, v] order by id"),
+        "[1, 9, ];\n[2, 8, ];\n[3, 3, ];\n"
+    );
+
+    run_statement(&mut repl, ".bag twice");
+    run_statement(&mut repl, ".insert {id: 1, v: 1}; {id: 1, v: 2}");
+    assert_eq!(
+        run_statement(&mut repl, ".merge from twice into target on fn({id, ...}) => id"),
+        "MERGED: 0 inserted, 2 updated, 0 skipped.\n"
+    );
+    run_statement(&mut repl, ".bag target");
+    assert_eq!(run_statement(&mut repl, ".query {id: 1, v, ...} into v"), "2;\n");
+
+    assert_eq!(run_statement(&mut repl, ".merge from missing into target on fn(x) => x"), "BagError");
+    assert_eq!(run_statement(&mut repl, ".merge from source into target on 5"), "EvalError(TypeError)");
+    run_statement(&mut repl, ".bag guarded as {id, ...} where id < 3");
+    assert_eq!(
+        run_statement(&mut repl, ".merge from source into guarded on fn({id, ...}) => id"),
+        "GuardError"
+    );
+}
+
+#[test]
+fn test_union_view() {
+    use damasc::snapshot::Snapshot;
+
+    let mut repl = Repl::open("init", None).unwrap();
+
+    run_statement(&mut repl, ".bag logs_a");
+    run_statement(&mut repl, ".insert {level: \"warn\"}; {level: \"info\"}");
+    run_statement(&mut repl, ".bag logs_b");
+    run_statement(&mut repl, ".insert {level: \"error\"}");
+
+    assert_eq!(run_statement(&mut repl, ".view all_logs := union of logs_a, logs_b"), "VIEW CREATED\n");
+    assert_eq!(
+        run_statement(&mut repl, ".query {origin, value: {level}} into [origin, level]"),
+        "[\"logs_a\", \"warn\", ];\n[\"logs_a\", \"info\", ];\n[\"logs_b\", \"error\", ];\n"
+    );
+    assert_eq!(run_statement(&mut repl, ".insert 1"), "TranscationAborted");
+
+    // The view follows the changes of its bags.
+    run_statement(&mut repl, ".bag logs_b");
+    run_statement(&mut repl, ".insert {level: \"debug\"}");
+    run_statement(&mut repl, ".bag all_logs");
+    assert_eq!(
+        run_statement(&mut repl, ".query {origin: \"logs_b\", value: {level}} into level"),
+        "\"error\";\n\"debug\";\n"
+    );
+    run_statement(&mut repl, ".bag logs_b");
+    run_statement(&mut repl, ".begin");
+    run_statement(&mut repl, ".delete {level: \"error\"}");
+    run_statement(&mut repl, ".bag all_logs");
+    assert_eq!(run_statement(&mut repl, ".query {origin: \"logs_b\", value: {level}} into level"), "\"debug\";\n");
+    run_statement(&mut repl, ".rollback");
+    run_statement(&mut repl, ".bag all_logs");
+    assert_eq!(
+        run_statement(&mut repl, ".query {origin: \"logs_b\", value: {level}} into level"),
+        "\"error\";\n\"debug\";\n"
+    );
+
+    // Views defined alike share their values.
+    assert_eq!(run_statement(&mut repl, ".view shared_logs := union of logs_a, logs_b"), "VIEW CREATED\n");
+    run_statement(&mut repl, ".bag logs_a");
+    run_statement(&mut repl, ".insert {level: \"trace\"}");
+    for view in [".bag all_logs", ".bag shared_logs"] {
+        run_statement(&mut repl, view);
+        assert_eq!(
+            run_statement(&mut repl, ".query {origin: \"logs_a\", value: {level}} into level"),
+            "\"warn\";\n\"info\";\n\"trace\";\n"
+        );
+    }
+
+    assert_eq!(run_statement(&mut repl, ".view all_logs := union of logs_a"), "BagError");
+    assert_eq!(run_statement(&mut repl, ".view nested := union of all_logs"), "BagError");
+    assert_eq!(run_statement(&mut repl, ".view missing := union of nope"), "BagError");
+    assert_eq!(run_statement(&mut repl, ".rename logs_a logs_c"), "BagError");
+
+    let mut bundle = vec![];
+    repl.write_bundle(&mut bundle).unwrap();
+    assert!(String::from_utf8(bundle)
+        .unwrap()
+        .contains(".view all_logs := union of logs_a, logs_b\n"));
+
+    let snapshot = Snapshot::of(&repl.bag_bundle);
+    let view = snapshot.bags.iter().find(|b| b.name == "all_logs").unwrap();
+    assert_eq!(view.union, ["logs_a", "logs_b"]);
+    assert!(view.values.is_empty());
+}
+
+#[test]
+fn test_vars_and_unset() {
+    let mut repl = Repl::open("init", None).unwrap();
+
+    assert_eq!(run_statement(&mut repl, ".vars"), "No variables.\n");
+    run_statement(&mut repl, ".let x = 5");
+    run_statement(&mut repl, ".let name = \"a\"");
+    assert_eq!(run_statement(&mut repl, ".vars"), "name: String\nx: Integer\n");
+
+    assert_eq!(run_statement(&mut repl, ".unset x"), "OK.\n");
+    assert_eq!(run_statement(&mut repl, ".unset x"), "NO.\n");
+    assert_eq!(run_statement(&mut repl, ".vars"), "name: String\n");
+
+    assert_eq!(run_statement(&mut repl, ".unset *"), "OK.\n");
+    assert_eq!(run_statement(&mut repl, ".vars"), "No variables.\n");
+}
+
+#[test]
+fn test_query_hints() {
+    let mut repl = Repl::open("init", None).unwrap();
+
+    run_statement(&mut repl, ".insert {a: 1}; {a: 2}");
+    run_statement(&mut repl, ".index init on .a");
+
+    assert_eq!(run_statement(&mut repl, ".query /*+ no_index */ {a} where a == 1"), "{a: 1, };\n");
+    assert_eq!(
+        run_statement(&mut repl, ".query /*+ no_index, join_order(y, x) */ x; y into [x.a, y.a] where x.a > y.a"),
+        "[2, 1, ];\n"
+    );
+    assert_eq!(
+        run_statement(&mut repl, ".query /*+ fast, no_index(a) */ {a} where a == 2"),
+        "{a: 2, };\n-- warning: unknown query hint fast ignored\n-- warning: unknown query hint no_index(a) ignored\n"
+    );
+    assert!(run_statement(&mut repl, ".explain .query /*+ no_index */ {a}").contains("hint: no_index\n"));
+}
+
+#[test]
+fn test_fingerprint() {
+    let limits = Limits::default();
+    let fingerprint = |input: &str| damasc::fingerprint::statement(&checked_statement(input, &limits).unwrap());
+
+    let query = fingerprint(".query x where x == 1 + y");
+    assert_eq!(query, fingerprint(".query   x where (y+1) == x"));
+    assert_eq!(query, 0x2ac06c4dbbfa8190);
+    assert_ne!(query, fingerprint(".query x where x == 1 - y"));
+    assert_ne!(query, fingerprint(".query x where x == 1 + z"));
+    assert_ne!(fingerprint(".query x where x > y"), fingerprint(".query x where y > x"));
+
+    let view = |names: [&'static str; 2]| {
+        damasc::fingerprint::view(&names.map(|name| Identifier { name: name.into() }))
+    };
+    assert_eq!(view(["a", "b"]), view(["a", "b"]));
+    assert_ne!(view(["a", "b"]), view(["b", "a"]));
+}
+
+#[test]
+fn test_run_script() {
+    let limits = Limits::default();
+    let file = std::env::temp_dir().join(format!("damasc_script_{}", std::process::id()));
+    let path = || std::borrow::Cow::Owned(file.to_string_lossy().into_owned());
+    let mut repl = Repl::open("init", None).unwrap();
+
+    std::fs::write(&file, ".bag people\n\n.insert {name: \"a\"}; {name: \"b\"}\n.let n = 2\n").unwrap();
+    let output = repl.execute(Statement::Run(path())).unwrap();
+    assert!(output.to_string().starts_with("Ran 3 statements from file"));
+    assert_eq!(repl.current_bag.name, "people");
+    let output = repl.execute(checked_statement(".query {name} into name", &limits).unwrap()).unwrap();
+    assert_eq!(output.to_string(), "\"a\";\n\"b\";\n");
+
+    std::fs::write(&file, ".insert {name: \"c\"}\n.insert n / 0\n.insert {name: \"d\"}").unwrap();
+    assert_matches!(
+        repl.execute(Statement::Run(path())),
+        Err(damasc::repl::ReplError::ScriptFailed(2, message)) if message == "Error[E0005]: EvalError(MathDivision)"
+    );
+    std::fs::write(&file, ".query x x").unwrap();
+    assert_matches!(repl.execute(Statement::Run(path())), Err(damasc::repl::ReplError::ScriptFailed(1, _)));
+    let output = repl.execute(checked_statement(".query {name} into name", &limits).unwrap()).unwrap();
+    assert_eq!(output.to_string(), "\"a\";\n\"b\";\n\"c\";\n");
+    let _ = std::fs::remove_file(&file);
+
+    // Neither directly nor through a macro can a script run another one,
+    // and its lines are not added to the history.
+    let file = "damasc_recursive_script";
+    std::fs::write(file, ".insert {name: \"e\"}\n!again").unwrap();
+    repl.execute(checked_statement(".macro again := .run damasc_recursive_script", &limits).unwrap()).unwrap();
+    repl.history.clear();
+    assert_matches!(
+        repl.execute(checked_statement("!again", &limits).unwrap()),
+        Err(damasc::repl::ReplError::ScriptFailed(2, message)) if message == "Scripts can not run other scripts"
+    );
+    assert!(repl.history.is_empty());
+    let _ = std::fs::remove_file(file);
+}
+
+#[test]
+fn test_execute_many() {
+    use damasc::repl::ReplError;
+
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let query = |repl: &mut Repl| {
+        let statement = checked_statement(".query x", &limits).unwrap();
+        repl.execute(statement).unwrap().to_string()
+    };
+
+    let report = repl.execute_script(".insert 1; 2\n\n.query x where x > 1\n").unwrap();
+    assert_eq!(report.outputs.len(), 2);
+    assert!(report.failed.is_empty());
+    assert_eq!(report.outputs[0].to_string(), "INSERTED 2 items.\n");
+    assert_eq!(report.outputs[1].to_string(), "2;\n");
+
+    // A failing statement rolls back the whole script.
+    assert_matches!(
+        repl.execute_script(".insert 3\n.insert 1 / 0\n.insert 4"),
+        Err(ReplError::ScriptFailed(2, message)) if message == "Error[E0005]: EvalError(MathDivision)"
+    );
+    assert_eq!(query(&mut repl), "1;\n2;\n");
+
+    // Nothing is executed if a line can not be parsed.
+    assert_matches!(repl.execute_script(".insert 3\n.insert"), Err(ReplError::ScriptFailed(2, _)));
+    assert_eq!(query(&mut repl), "1;\n2;\n");
+
+    let statements = vec![
+        checked_statement(".insert 3", &limits).unwrap(),
+        checked_statement(".commit", &limits).unwrap(),
+    ];
+    assert_matches!(repl.execute_many(statements), Err((1, ReplError::TransactionState)));
+    assert_eq!(query(&mut repl), "1;\n2;\n");
+
+    // Rolling back also undoes the variables, macros and settings changed by
+    // the statements before the failing one.
+    let statements = [".let n = 1", ".macro m := .query x", ".set confirm_threshold 5", ".drop missing"]
+        .map(|input| checked_statement(input, &limits).unwrap());
+    assert_matches!(repl.execute_many(statements.into()), Err((3, ReplError::BagError)));
+    assert!(repl.vars().is_empty());
+    assert_eq!(repl.settings.confirm_threshold, None);
+    assert_matches!(
+        repl.execute(checked_statement("!m", &limits).unwrap()),
+        Err(ReplError::EvalError(EvalError::UnknownIdentifier))
+    );
+
+    // Inside of an explicit transaction nothing is rolled back.
+    repl.execute(Statement::Begin).unwrap();
+    assert!(repl.execute_script(".insert 3\n.insert 1 / 0").is_err());
+    repl.execute(Statement::Commit).unwrap();
+    assert_eq!(query(&mut repl), "1;\n2;\n3;\n");
+}
+
+#[test]
+fn test_script_error_policy() {
+    use damasc::repl::ReplError;
+    use damasc::statement::ErrorPolicy;
+
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let query = |repl: &mut Repl| {
+        let statement = checked_statement(".query x", &limits).unwrap();
+        repl.execute(statement).unwrap().to_string()
+    };
+
+    let report = repl
+        .execute_script(".on error continue\n.insert 1\n.insert 1 / 0\n.insert 2")
+        .unwrap();
+    assert_eq!(report.outputs.len(), 2);
+    assert_matches!(report.failed.as_slice(), [(3, ReplError::EvalError(EvalError::MathDivision))]);
+    assert_eq!(
+        report.to_string(),
+        "2 statements succeeded, 1 failed.\nline 3: Error[E0005]: EvalError(MathDivision)\n"
+    );
+    assert_eq!(query(&mut repl), "1;\n2;\n");
+
+    // `continue` keeps what a `.run` did before its failing line,
+    // `rollback_statement` undoes it. File names can not contain paths.
+    let file = "damasc_policy_script";
+    std::fs::write(file, ".bag other\n.let n = 1\n.set confirm_threshold 5\n.insert 1 / 0").unwrap();
+    let run = format!(".run {file}");
+    let report = repl.execute_script(&format!(".on error rollback_statement\n{run}")).unwrap();
+    assert_matches!(report.failed.as_slice(), [(2, ReplError::ScriptFailed(4, _))]);
+    assert_eq!(repl.current_bag.name, "init");
+    assert_eq!(repl.bags().len(), 1);
+    assert!(repl.vars().is_empty());
+    assert_eq!(repl.settings.confirm_threshold, None);
+    let report = repl.execute_script(&format!(".on error continue\n{run}")).unwrap();
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(repl.current_bag.name, "other");
+    assert_eq!(repl.bags().len(), 2);
+    let _ = std::fs::remove_file(file);
+    repl.execute(checked_statement(".bag init", &limits).unwrap()).unwrap();
+
+    // The policy applies to the statements below it.
+    assert_matches!(
+        repl.execute_script(".on error continue\n.insert 3\n.on error abort\n.insert 1 / 0"),
+        Err(ReplError::ScriptFailed(4, _))
+    );
+    assert_eq!(query(&mut repl), "1;\n2;\n");
+
+    assert_matches!(
+        repl.execute_script(".on error ignore\n.insert 3"),
+        Err(ReplError::ScriptFailed(1, _))
+    );
+
+    // `.run` files and bundles set their own policy, which ends with them.
+    std::fs::write(file, ".on error continue\n.insert 1 / 0\n.insert 3").unwrap();
+    let output = repl.execute(checked_statement(&run, &limits).unwrap()).unwrap();
+    assert!(output.to_string().contains("line 2: Error[E0005]: EvalError(MathDivision)"));
+    assert_eq!(repl.error_policy(), ErrorPolicy::Abort);
+    assert_eq!(query(&mut repl), "1;\n2;\n3;\n");
+    std::fs::write(file, ".bag numbers\n.on error rollback_statement\n4; 5 / 0\n6").unwrap();
+    let bundle = format!(".load_bundle {file}");
+    let output = repl.execute(checked_statement(&bundle, &limits).unwrap()).unwrap();
+    assert!(output.to_string().contains("line 3: Error[E0005]: EvalError(MathDivision)"));
+    assert_eq!(query(&mut repl), "6;\n");
+    let _ = std::fs::remove_file(file);
+}
+
+#[test]
+fn test_env_snapshots() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &str| {
+        let statement = checked_statement(input, &limits).unwrap();
+        repl.execute(statement).map(|o| o.to_string()).map_err(|e| format!("{e:?}"))
+    };
+
+    run(".let a = 1; b = 2").unwrap();
+    assert_eq!(run(".env save small"), Ok("OK.\n".into()));
+    run(".let a = 10").unwrap();
+    run(".env save large").unwrap();
+    run(".unset *").unwrap();
+
+    assert_eq!(run(".env load small"), Ok("OK.\n".into()));
+    assert_eq!(run("a + b"), Ok("3;\n".into()));
+    run(".env load large").unwrap();
+    assert_eq!(run("a + b"), Ok("12;\n".into()));
+
+    // Changing the loaded variables does not change the saved copy.
+    run(".let a = 100").unwrap();
+    run(".env load small").unwrap();
+    assert_eq!(run("a"), Ok("1;\n".into()));
+
+    assert_eq!(run(".env load unknown"), Ok("NO.\n".into()));
+    assert_eq!(run("a"), Ok("1;\n".into()));
+}
+
+#[test]
+fn test_custom_statement() {
+    use damasc::extension::CustomStatement;
+    use damasc::repl::{ReplError, ReplOutput};
+
+    // `.double <bag>` inserts the doubled values of the current bag into the bag.
+    struct Double;
+
+    impl<'b, 'i, 's, 'v> CustomStatement<'b, 'i, 's, 'v> for Double {
+        fn name(&self) -> &str {
+            "double"
+        }
+
+        fn capabilities(&self) -> damasc::capabilities::Capabilities {
+            damasc::capabilities::Capabilities::BAG_CREATE
+        }
+
+        fn parse(&self, arguments: &str) -> Result<(), String> {
+            match arguments.chars().all(|c| c.is_alphabetic()) && !arguments.is_empty() {
+                true => Ok(()),
+                false => Err(format!("'{arguments}' is not a bag name")),
+            }
+        }
+
+        fn execute(
+            &self,
+            repl: &mut Repl<'b, 'i, 's, 'v>,
+            arguments: &str,
+        ) -> Result<ReplOutput<'i, 's, 'v>, ReplError> {
+            let source = repl.current_bag.name.to_string();
+            let query = repl.parse(".query x into x * 2").map_err(|_| ReplError::ParseError)?;
+            let doubled = repl.execute(query)?.to_string();
+            let values = doubled.trim_end().trim_end_matches(';').replace(";\n", "; ");
+            for input in [format!(".bag {arguments}"), format!(".insert {values}"), format!(".bag {source}")] {
+                let statement = repl.parse(&input).map_err(|_| ReplError::ParseError)?;
+                repl.execute(statement)?;
+            }
+
+            Ok(ReplOutput::Notice(format!("Doubled into {arguments}")))
+        }
+    }
+
+    let mut repl = Repl::open("init", None).unwrap();
+    assert!(repl.register(Double));
+    assert!(!repl.register(Double));
+
+    let mut run = |input: &str| {
+        let statement = repl.parse(input).map_err(|e| e.to_string())?;
+        repl.execute(statement).map(|o| o.to_string()).map_err(|e| format!("{e:?}"))
+    };
+
+    run(".insert 1; 2").unwrap();
+    assert_eq!(run(".double twice"), Ok("Doubled into twice\n".into()));
+    run(".bag twice").unwrap();
+    assert_eq!(run(".query x"), Ok("2;\n4;\n".into()));
+    assert_eq!(
+        run(".double 42"),
+        Err("Invalid arguments for .double: '42' is not a bag name".into())
+    );
+
+    repl.capabilities = damasc::capabilities::Capabilities::ALL.without(damasc::capabilities::Capabilities::BAG_CREATE);
+    let statement = repl.parse(".double again").unwrap();
+    assert_matches!(repl.execute(statement), Err(ReplError::Denied));
+
+    // Aliases of builtin commands are taken as well.
+    struct Quit;
+
+    impl<'b, 'i, 's, 'v> CustomStatement<'b, 'i, 's, 'v> for Quit {
+        fn name(&self) -> &str {
+            "quit"
+        }
+
+        fn execute(&self, _: &mut Repl<'b, 'i, 's, 'v>, _: &str) -> Result<ReplOutput<'i, 's, 'v>, ReplError> {
+            Ok(ReplOutput::Ack)
+        }
+    }
+
+    assert!(!repl.register(Quit));
+}
+
+#[test]
+fn test_capabilities() {
+    use damasc::capabilities::Capabilities;
+
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    for input in [".insert 1", ".macro grow := .insert 2", ".prepare add := .insert $x"] {
+        repl.execute(checked_statement(input, &limits).unwrap()).unwrap();
+    }
+    repl.capabilities = Capabilities::ALL.without(Capabilities::MUTATION | Capabilities::IO);
+    let mut run = |input: &str| {
+        let statement = checked_statement(input, &limits).unwrap();
+        repl.execute(statement).map(|o| o.to_string()).map_err(|e| format!("{e:?}"))
+    };
+
+    assert_eq!(run(".query x"), Ok("1;\n".into()));
+    assert_eq!(run(".insert 2"), Err("Denied".into()));
+    assert_eq!(run(".dump values"), Err("Denied".into()));
+    // Macros need the capabilities of their body.
+    assert_eq!(run(".macro list := .query x"), Err("Denied".into()));
+    // So do those defined before the capabilities were restricted.
+    assert_eq!(run("!grow"), Err("Denied".into()));
+    assert_eq!(run(".execute add {x: 2}"), Err("Denied".into()));
+    assert_eq!(run(".query x"), Ok("1;\n".into()));
+
+    let bags = repl.bags();
+    let required = |input: &str| Capabilities::required(&checked_statement(input, &limits).unwrap(), &bags);
+    assert_eq!(required(".bag init"), Capabilities::MUTATION);
+    assert_eq!(required(".bag other"), Capabilities::MUTATION | Capabilities::BAG_CREATE);
+    assert_eq!(required(".run setup"), Capabilities::MUTATION | Capabilities::IO);
+    assert_eq!(required(".set max_depth 1"), Capabilities::MUTATION | Capabilities::SESSION);
+    assert_eq!(required(".set color true"), Capabilities::MUTATION | Capabilities::SESSION);
+    assert_eq!(required(".bag $history"), Capabilities::MUTATION | Capabilities::SESSION);
+    assert_eq!(required(".solve take 5"), Capabilities::MUTATION | Capabilities::CONNECTIONS);
+    assert_eq!(
+        required(".solve take 5 step"),
+        Capabilities::MUTATION | Capabilities::CONNECTIONS | Capabilities::SESSION
+    );
+    assert_eq!(required(".query x"), Capabilities::NONE);
+}
+
+#[test]
+fn test_incomplete_input() {
+    use damasc::parser::is_incomplete;
+
+    assert!(is_incomplete(".connection double() {"));
+    assert!(is_incomplete(".connection double() {\n  &a.consume x where true;"));
+    assert!(is_incomplete(".insert [1, 2,\n"));
+    assert!(is_incomplete(".insert 1;"));
+    assert!(!is_incomplete(".insert 1; 2"));
+    assert!(!is_incomplete(".insert \"{[(\""));
+    assert!(is_incomplete(".insert \"a\\\"; ["));
+    assert!(!is_incomplete(".insert \"a\\\"; `{`"));
+    assert!(!is_incomplete(".insert x matches /[(/"));
+    assert!(!is_incomplete(".insert (6 / 2) * [3 / 1]"));
+    assert!(!is_incomplete(".insert 1)"));
+
+    let limits = Limits::default();
+    let input = ".connection double() {\n  &a.consume x where true;\n  &b.produce x * 2\n}";
+    assert!(!is_incomplete(input));
+    assert!(matches!(checked_statement(input, &limits), Ok(Statement::Connect(..))));
+}
+
+#[test]
+fn test_comments() {
+    use damasc::parser::{is_blank, strip_comments};
+
+    assert_eq!(strip_comments(".insert 1 // one"), ".insert 1       ");
+    assert_eq!(strip_comments("1 /* a\nb */ + 2"), "1     \n     + 2");
+    assert_eq!(strip_comments(".insert \"//\"; `/*`"), ".insert \"//\"; `/*`");
+    assert_eq!(strip_comments(".query /*+ no_index */ x"), ".query /*+ no_index */ x");
+    assert_eq!(strip_comments(r#".query x where x matches /a\//"#), r#".query x where x matches /a\//"#);
+    assert_eq!(strip_comments(r#"[/\/*"/, 1] // one"#), r#"[/\/*"/, 1]       "#);
+    assert_eq!(strip_comments("6 / 2 // three"), "6 / 2         ");
+    assert!(is_blank("  // only a comment"));
+    assert!(!is_blank("1 // one"));
+
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &str| {
+        let statement = checked_statement(input, &limits).map_err(|e| e.to_string())?;
+        repl.execute(statement).map(|o| o.to_string()).map_err(|e| format!("{e:?}"))
+    };
+
+    assert_eq!(run(".insert 1; /* two */ 2 // numbers"), Ok("INSERTED 2 items.\n".into()));
+    assert_eq!(run(".query /*+ no_index */ x where x > 1 // big ones"), Ok("2;\n".into()));
+    assert!(run(".connection double() { // doubles\n  &init.consume x where true;\n  &init.produce x * 2 /* twice */\n}").is_ok());
+    // Errors point to the position in the input with its comments.
+    assert_eq!(
+        run("/* a */ 1 +"),
+        Err("Syntax error at line 1, column 11, expected end of input\n/* a */ 1 +\n          ^".into())
+    );
+
+    let file = "damasc_commented_bundle";
+    std::fs::write(file, "// exported for the tests\n\n.bag numbers /* all of them */\n1; 2 // small\n").unwrap();
+    let output = run(&format!(".load_bundle {file}"));
+    let _ = std::fs::remove_file(file);
+    assert!(output.is_ok());
+    run(".bag numbers").unwrap();
+    assert_eq!(run(".query x"), Ok("1;\n2;\n".into()));
+}
+
+#[test]
+fn test_jails() {
+    use damasc::jail::{JailSettings, Jails};
+
+    fn run<'s>(
+        repl: &mut Repl<'_, '_, 's, 's>,
+        jails: &mut Jails<'s, 's>,
+        session: &str,
+        input: &str,
+    ) -> Result<String, String> {
+        let statement = checked_statement(input, &Limits::default()).unwrap();
+        jails
+            .run(repl, session, |repl| repl.execute(statement))
+            .unwrap()
+            .map(|o| o.to_string())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut jails = Jails::new(JailSettings {
+        item_ttl: None,
+        max_items: 2,
+        session_ttl: 3600,
+        max_sessions: 2,
+    });
+    let (a, b, c) = (jails.issue(), jails.issue(), jails.issue());
+    assert!(jails.is_issued(&a));
+    assert!(!jails.is_issued("made.up"));
+
+    assert_eq!(run(&mut repl, &mut jails, &a, ".insert 1; 2"), Ok("INSERTED 2 items.\n".into()));
+    assert_eq!(run(&mut repl, &mut jails, &a, ".insert 3"), Err("GuardError".into()));
+    assert_eq!(run(&mut repl, &mut jails, &b, ".query x"), Ok("".into()));
+    assert_eq!(run(&mut repl, &mut jails, &a, ".query x"), Ok("1;\n2;\n".into()));
+    // Sessions stay in their jail.
+    assert_eq!(run(&mut repl, &mut jails, &b, ".bag init"), Err("Denied".into()));
+    assert_eq!(run(&mut repl, &mut jails, &b, ".bag jail_1"), Err("Denied".into()));
+    assert_eq!(run(&mut repl, &mut jails, &b, ".delta jail_1 jail_2"), Err("Denied".into()));
+    assert_eq!(run(&mut repl, &mut jails, &b, ".bag jail_2"), Ok("SWITCHED BAG\n".into()));
+    assert_eq!(run(&mut repl, &mut jails, &b, ".insert 4"), Ok("INSERTED 1 items.\n".into()));
+    assert_eq!(run(&mut repl, &mut jails, &a, ".query x"), Ok("1;\n2;\n".into()));
+    assert_eq!(jails.bag(&a).map(|b| b.name.to_string()), Some("jail_1".into()));
+    assert_eq!(jails.bag(&c), None);
+
+    let statement = checked_statement(".query x", &Limits::default()).unwrap();
+    let denied = jails.run(&mut repl, "made.up", |repl| repl.execute(statement.clone()));
+    assert!(matches!(denied, Err(damasc::repl::ReplError::Denied)));
+    let full = jails.run(&mut repl, &c, |repl| repl.execute(statement));
+    assert!(matches!(full, Err(damasc::repl::ReplError::TooManySessions)));
+
+    assert_eq!(run(&mut repl, &mut jails, &b, ".change x into 40 where x == 4"), Ok("CHANGED 1 items.\n".into()));
+
+    jails.settings.item_ttl = Some(0);
+    assert_eq!(run(&mut repl, &mut jails, &a, ".insert 5"), Ok("INSERTED 1 items.\n".into()));
+    assert_eq!(run(&mut repl, &mut jails, &a, ".query x"), Ok("".into()));
+    // Changed values expire as well.
+    assert_eq!(run(&mut repl, &mut jails, &b, ".query x"), Ok("".into()));
+
+    jails.settings.session_ttl = 0;
+    assert_eq!(run(&mut repl, &mut jails, &c, ".query x"), Ok("".into()));
+    assert_eq!(jails.bag(&a), None);
+    assert_eq!(jails.bag(&c).map(|b| b.name.to_string()), Some("jail_3".into()));
+}
+
+#[test]
+fn test_formatter() {
+    use damasc::formatter;
+
+    let statements = [
+        ".query {x, y} where x > y && !(y < 0) group by x order by sum(y) collate nocase desc limit 3 offset 1",
+        ".queryx /*+ no_index, join_order(b, a) */ a; b into {n: count(), s: avg(a.size)} where a.id == b.id",
+        ".query",
+        ".query limit 2",
+        ".insert [1, ...xs, -(-1), - x[0], (a + b) * c ^ 2 ^ 3, a - (b - c), `x=${x + 1}!`]; {a, \"b c\": 1, [k]: 2, ...o}",
+        ".insert (a[0]).b.c; a.b[\"c d\"]; (-a).b; (fn(x) => x * 2); let [a, b] = f(x) + 1 in a * b",
+        ".insert x is Integer == true; (x as String) as Integer; a == b collate \"de\"; pattern({x if x > 1, ...}); quote(x + 1)",
+        ".match {a: x @ [_, ...rest], [\"b c\"]: y @ (1 | 2), where: **{z}, [k]: _ is String} = v",
+        ".insert a == b collate nocase == c; a == (b < c collate binary); x > 1 && y != z collate \"de\"",
+        ".let [x, y if y > x] = [1, 2]; z = {x, y}",
+        ".delete {age} where age < 0 limit 1 offset 2",
+        ".preview .change {n} into {n: n + 1} where n > 1",
+        ".move(archive) {done: true} into {id} where id > 3 limit 5",
+        ".move(archive) limit 1",
+        ".bag people as {age, ...} where age >= 0 limit 10 distinct",
+        ".bag numbers as virtual 1..",
+        ".copy people adults {age, ...} where age >= 18",
+        ".restore {x} from trash where x > 1",
+        ".connection pairs(n) { &a.consume x; y where x + y == n; &b.test z where true; &c.absent w where w == z; let s = x + y; guard s > 0; &d.produce [x, y, s]; s }",
+        ".macro twice(x) := .insert x; x",
+        "!twice(1 + 2)",
+        ".prepare p := .query {a} where a == $a",
+        ".execute p {a: 1}",
+        ".merge from a into b on id prefer newest by updated",
+        ".explain .query x where x > 1",
+        ".format 1 + 2",
+        ".log warn {x: 1}",
+        ".load_json data verify",
+        ".trash on ttl 60",
+    ];
+    let cases = statements
+        .into_iter()
+        .chain(include_str!("test_expressions.txt").lines().filter(|l| *l != "---"))
+        .chain(include_str!("test_patterns.txt").lines())
+        .chain(include_str!("test_negative_patterns.txt").lines());
+
+    for case in cases {
+        let Ok((_, statement)) = damasc::parser::statement(case) else {
+            unreachable!("Statement can be parsed: {case}");
+        };
+        let formatted = formatter::statement(&statement);
+        let Ok((_, reparsed)) = damasc::parser::statement(&formatted) else {
+            unreachable!("Formatted statement can be parsed: {case} => {formatted}");
+        };
+        // The guards of the consumers of a connection are moved into its
+        // guard, so only its formatting stays the same.
+        if !matches!(statement, Statement::Connect(..)) {
+            assert_eq!(statement.to_json(), reparsed.to_json(), "{case} => {formatted}");
+        }
+        assert_eq!(formatter::statement(&reparsed), formatted);
+
+        let pretty = formatter::pretty(&statement, 20);
+        let Ok((_, reparsed)) = damasc::parser::statement(&pretty) else {
+            unreachable!("Pretty statement can be parsed: {case} => {pretty}");
+        };
+        assert_eq!(formatter::statement(&reparsed), formatted, "{case} => {pretty}");
+    }
+
+    let mut repl = Repl::new("init");
+    let mut run = |input: &str| {
+        let statement = repl.parse(input).unwrap();
+        match repl.execute(statement) {
+            Ok(ReplOutput::Notice(n)) => n,
+            _ => unreachable!("{input} is formatted"),
+        }
+    };
+    assert_eq!(run(".format 1+2*3"), "1 + 2 * 3");
+    assert_eq!(run(".format (1+2)*3"), "(1 + 2) * 3");
+    assert_eq!(run(".format .query {x,y}where (x>y) limit 3"), ".query {x, y} where x > y limit 3");
+    assert_eq!(
+        run(".format .insert {name: \"a long name\", tags: [\"first\", \"second\", \"third\"], size: 1234567, owner: \"someone\"}"),
+        ".insert {\n  name: \"a long name\",\n  tags: [\"first\", \"second\", \"third\"],\n  size: 1234567,\n  owner: \"someone\"\n}"
+    );
+    assert_eq!(
+        run(".format .connection c() { &a.consume x where x > 0; &b.produce x }"),
+        ".connection c() {\n  &a.consume x where true;\n  guard x > 0;\n  &b.produce x\n}"
+    );
+}
+
+proptest! {
+    #[test]
+    fn test_expression_round_trip(expression in arbitrary::expression()) {
+        arbitrary::expression_round_trip(&expression)?;
+    }
+
+    #[test]
+    fn test_pattern_round_trip(pattern in arbitrary::pattern()) {
+        arbitrary::pattern_round_trip(&pattern)?;
+    }
+
+    #[test]
+    fn test_value_round_trip(value in arbitrary::value()) {
+        arbitrary::value_round_trip(&value)?;
+    }
+
+    #[test]
+    fn test_value_serde_round_trip(value in arbitrary::value()) {
+        arbitrary::value_serde_round_trip(&value)?;
+    }
+}
+
+#[test]
+fn test_builtins() {
+    use damasc::builtins::BuiltinGroup;
+
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    repl.builtins.disable(BuiltinGroup::Reflection);
+    assert!(repl.builtins.register("double", |args| match args {
+        [Value::Integer(i)] => Ok(Value::Integer(i * 2)),
+        _ => Err(EvalError::FunctionFailed),
+    }));
+    assert!(!repl.builtins.register("double", |_| Ok(Value::Null)));
+    assert!(!repl.builtins.register("length", |_| Ok(Value::Null)));
+    assert!(!repl.builtins.register("type", |_| Ok(Value::Null)));
+
+    let context = repl.completion_context();
+    assert!(context.functions.contains("double"));
+    assert!(context.functions.contains("length"));
+    assert!(!context.functions.contains("type"));
+
+    let mut run = |input: &'static str| match repl.execute(checked_statement(input, &limits).unwrap()) {
+        Ok(ReplOutput::Values(values)) => values
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(";"),
+        Ok(_) => String::new(),
+        Err(_) => "error".to_string(),
+    };
+    assert_eq!(run(".insert 1; 2"), "");
+    assert_eq!(run(".query x into double(x) + length([x])"), "3;5");
+    assert_eq!(run(".query x into double(\"x\")"), "error");
+    assert_eq!(run(".query x into type(x)"), "error");
+    assert_eq!(run(".query x into map([x], fn(y) => double(y))"), "[2, ];[4, ]");
+}
+
+#[test]
+fn test_delta() {
+    let mut repl = Repl::open("before", None).unwrap();
+
+    run_statement(&mut repl, ".insert {id: 1, v: 1}; {id: 2, v: 2}; {id: 4, v: 4}");
+    run_statement(&mut repl, ".bag after");
+    run_statement(&mut repl, ".insert {id: 1, v: 1}; {id: 2, v: 3}; {id: 3, v: 0}");
+
+    assert_eq!(
+        run_statement(&mut repl, ".delta before after"),
+        "{added: [{id: 2, v: 3, }, {id: 3, v: 0, }, ], changed: [], removed: [{id: 2, v: 2, }, {id: 4, v: 4, }, ], };\n"
+    );
+    assert_eq!(
+        run_statement(&mut repl, ".delta before after on fn({id, ...}) => id"),
+        "{added: [{id: 3, v: 0, }, ], changed: [{after: {id: 2, v: 3, }, before: {id: 2, v: 2, }, key: 2, }, ], removed: [{id: 4, v: 4, }, ], };\n"
+    );
+    assert_eq!(
+        run_statement(&mut repl, ".delta after after"),
+        "{added: [], changed: [], removed: [], };\n"
+    );
+
+    run_statement(&mut repl, ".bag twice");
+    run_statement(&mut repl, ".insert 1; 1; 2");
+    run_statement(&mut repl, ".bag once");
+    run_statement(&mut repl, ".insert 1; 3");
+    assert_eq!(
+        run_statement(&mut repl, ".delta twice once"),
+        "{added: [3, ], changed: [], removed: [1, 2, ], };\n"
+    );
+
+    assert_eq!(run_statement(&mut repl, ".delta before missing"), "BagError");
+    assert_eq!(run_statement(&mut repl, ".delta before after on 5"), "EvalError(TypeError)");
+}
+
+#[test]
+fn test_help() {
+    use damasc::help::HELP;
+
+    let limits = Limits::default();
+    for entry in HELP {
+        for example in entry.examples {
+            assert!(
+                checked_statement(example, &limits).is_ok(),
+                "example of .{} does not parse: {example}",
+                entry.command
+            );
+        }
+    }
+
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| {
+        repl.execute(checked_statement(input, &limits).unwrap()).unwrap().to_string()
+    };
+
+    let overview = run(".help");
+    assert!(HELP.iter().all(|e| overview.contains(&format!(".{} ", e.command))));
+    let query = run(".help query");
+    assert!(query.starts_with(".query [<pattern>; ...]"));
+    assert!(query.contains("Examples:\n  .query {age, name}"));
+    assert_eq!(run(".h .quit"), run(".help exit"));
+    assert_eq!(
+        run(".help nope"),
+        "Unknown command nope. Type .help for a list of commands.\n"
+    );
+}
+
+#[test]
+fn test_connection_absence() {
+    let mut repl = Repl::open("readings", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".bag alerts");
+    run(".insert {sensor: 2, level: 1}");
+    run(".bag readings");
+    run(".insert {sensor: 1, value: 20}; {sensor: 2, value: 50}; {sensor: 3, value: 5}");
+    run(
+        ".connection alert() { &readings.test {sensor: s, value: v} where v > 10; \
+        &alerts.produce {sensor: s, level: v / 10}; &alerts.absent {sensor: s, level: l} where l > 1 }",
+    );
+    assert_eq!(run(".solve alert null"), "SOLVED.\nalerts: 0 deleted, 2 inserted\n");
+    assert_eq!(run(".solve alert null"), "SOLVED.\n");
+    run(".bag alerts");
+    assert_eq!(
+        run(".query x order by x.level"),
+        "{level: 1, sensor: 2, };\n{level: 2, sensor: 1, };\n{level: 5, sensor: 2, };\n"
+    );
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn test_parquet_export() {
+    use damasc::{columnar, statement::DataFormat};
+
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| repl.execute(checked_statement(input, &limits).unwrap());
+
+    run(".insert {name: \"a\", count: 1, done: true}; {name: \"b\", count: null}").unwrap();
+    let Ok(ReplOutput::Values(values)) = run(".query x") else {
+        unreachable!()
+    };
+    let batch = columnar::record_batch(&values.iter().collect::<Vec<_>>()).unwrap();
+    assert_eq!(batch.num_rows(), 2);
+    let columns = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| format!("{}: {}", f.name(), f.data_type()))
+        .collect::<Vec<_>>();
+    assert_eq!(columns, ["count: Int64", "done: Boolean", "name: Utf8"]);
+
+    let mut repl = Repl::open("other", None).unwrap();
+    let file = std::env::temp_dir().join(format!("damasc_parquet_{}", std::process::id()));
+    let export = || Statement::Export(file.to_string_lossy().into_owned().into(), DataFormat::Parquet, false);
+    repl.execute(checked_statement(".insert {x: 1}; {x: 2}", &limits).unwrap()).unwrap();
+    repl.execute(export()).unwrap();
+    assert!(std::fs::read(&file).unwrap().starts_with(b"PAR1"));
+
+    repl.execute(checked_statement(".insert {x: \"3\"}", &limits).unwrap()).unwrap();
+    assert_matches!(repl.execute(export()), Err(damasc::repl::ReplError::EvalError(EvalError::TypeError)));
+    let _ = std::fs::remove_file(&file);
+}
+
+#[test]
+fn test_save_restore() {
+    use damasc::repl::ReplError;
+
+    let limits = Limits::default();
+    let file = std::env::temp_dir().join(format!("damasc_snapshot_{}", std::process::id()));
+    let path = || std::borrow::Cow::Owned(file.to_string_lossy().into_owned());
+    let mut repl = Repl::open("init", None).unwrap();
+    let run = |repl: &mut Repl, input: &'static str| {
+        repl.execute(checked_statement(input, &limits).unwrap())
+            .map(|o| o.to_string())
+    };
+
+    run(&mut repl, ".insert 1; [2, 3]").unwrap();
+    run(&mut repl, ".bag digits as virtual 0..3").unwrap();
+    run(&mut repl, ".bag adults as {age, ...} where age >= 18 limit 2").unwrap();
+    run(&mut repl, ".insert {age: 20, id: 1}").unwrap();
+    run(&mut repl, ".index adults on .id").unwrap();
+    repl.execute(Statement::Save(path())).unwrap();
+
+    run(&mut repl, ".drop digits").unwrap();
+    run(&mut repl, ".insert {age: 30, id: 2}").unwrap();
+    let output = repl.execute(Statement::Restore(path())).unwrap();
+    assert_eq!(
+        output.to_string(),
+        format!(
+            "Restored 3 bags with 3 values in total from file '{}'\n",
+            file.display()
+        )
+    );
+
+    assert_eq!(run(&mut repl, ".query x").unwrap(), "{age: 20, id: 1, };\n");
+    assert_matches!(run(&mut repl, ".insert {age: 3}"), Err(ReplError::GuardError));
+    run(&mut repl, ".insert {age: 40, id: 3}").unwrap();
+    assert_matches!(run(&mut repl, ".insert {age: 50}"), Err(ReplError::GuardError));
+    run(&mut repl, ".bag digits").unwrap();
+    assert_eq!(run(&mut repl, ".query x").unwrap(), "0;\n1;\n2;\n");
+    run(&mut repl, ".bag init").unwrap();
+    assert_eq!(run(&mut repl, ".query x").unwrap(), "1;\n[2, 3, ];\n");
+
+    std::fs::write(&file, "{\"version\": 99, \"bags\": []}").unwrap();
+    assert_matches!(repl.execute(Statement::Restore(path())), Err(ReplError::ParseError));
+    let _ = std::fs::remove_file(&file);
+}
+
+#[test]
+#[cfg(all(feature = "yaml", feature = "toml", feature = "xml"))]
+fn test_document_import() {
+    use damasc::statement::DataFormat;
+
+    let limits = Limits::default();
+    let file = std::env::temp_dir().join(format!("damasc_documents_{}", std::process::id()));
+    let mut import = |content: &str, format| {
+        std::fs::write(&file, content).unwrap();
+        let mut repl = Repl::open("init", None).unwrap();
+        repl.execute(Statement::Import(
+            file.to_string_lossy().into_owned().into(),
+            format,
+            false,
+        ))
+        .and_then(|_| repl.execute(checked_statement(".query x", &limits).unwrap()))
+        .map(|o| o.to_string())
+    };
+
+    assert_eq!(
+        import("- name: a\n  tags: [x]\n- 2\n---\nnull\n---\ntrue\n", DataFormat::Yaml).unwrap(),
+        "{name: \"a\", tags: [\"x\", ], };\n2;\ntrue;\n"
+    );
+    assert_eq!(
+        import("name = \"a\"\nborn = 2024-01-15T10:30:00Z\n[server]\nport = 80\n", DataFormat::Toml).unwrap(),
+        "{born: @2024-01-15T10:30:00Z, name: \"a\", server: {port: 80, }, };\n"
+    );
+    assert_eq!(
+        import(
+            "<people><person id=\"1\"><name>a</name><tag>x</tag><tag>y</tag></person><person>b</person></people>",
+            DataFormat::Xml
+        )
+        .unwrap(),
+        "{id: \"1\", name: \"a\", tag: [\"x\", \"y\", ], };\n\"b\";\n"
+    );
+    assert_matches!(import("x = 1.5", DataFormat::Toml), Err(damasc::repl::ReplError::ParseError));
+    assert_matches!(import("<open>", DataFormat::Xml), Err(damasc::repl::ReplError::ParseError));
+    let _ = std::fs::remove_file(&file);
+}
+
+#[test]
+fn test_verified_transfer() {
+    use damasc::repl::ReplError;
+    use damasc::statement::DataFormat;
+
+    let limits = Limits::default();
+    let file = std::env::temp_dir().join(format!("damasc_verified_{}", std::process::id()));
+    let path = || std::borrow::Cow::Owned(file.to_string_lossy().into_owned());
+    let manifest = damasc::manifest::path(&path());
+    let mut repl = Repl::open("init", None).unwrap();
+
+    let run = |repl: &mut Repl, input: &'static str| {
+        repl.execute(checked_statement(input, &limits).unwrap()).unwrap().to_string()
+    };
+    run(&mut repl, ".insert 1; [2]; {x: 3}");
+    for format in [DataFormat::Lines, DataFormat::Json, DataFormat::Ndjson] {
+        repl.execute(Statement::Export(path(), format, true)).unwrap();
+        assert!(std::fs::read_to_string(&manifest).unwrap().contains("\"count\": 3"));
+
+        run(&mut repl, ".bag target");
+        let output = repl.execute(Statement::Import(path(), format, true)).unwrap();
+        assert!(output.to_string().ends_with(", verified by manifest\n"));
+
+        let content = std::fs::read_to_string(&file).unwrap();
+        std::fs::write(&file, &content[..content.len() - 2]).unwrap();
+        assert_matches!(
+            repl.execute(Statement::Import(path(), format, true)),
+            Err(ReplError::VerificationError)
+        );
+        assert_eq!(run(&mut repl, ".query x").lines().count(), 3);
+        run(&mut repl, ".bag init");
+        run(&mut repl, ".drop target");
+    }
+
+    // A manifest whose checksum matches but whose count does not.
+    std::fs::write(&file, "1\n2\n").unwrap();
+    let checksum = damasc::manifest::checksum(std::fs::File::open(&file).unwrap()).unwrap();
+    std::fs::write(&manifest, format!("{{\"count\": 3, \"checksum\": \"{checksum}\"}}")).unwrap();
+    assert_matches!(
+        repl.execute(Statement::Import(path(), DataFormat::Lines, true)),
+        Err(ReplError::VerificationError)
+    );
+    std::fs::remove_file(&manifest).unwrap();
+    assert_matches!(
+        repl.execute(Statement::Import(path(), DataFormat::Lines, true)),
+        Err(ReplError::VerificationError)
+    );
+    repl.execute(Statement::Import(path(), DataFormat::Lines, false)).unwrap();
+    assert!(matches!(
+        checked_statement(".load_json values verify", &limits),
+        Ok(Statement::Import(_, DataFormat::Json, true))
+    ));
+    let _ = std::fs::remove_file(&file);
+}
+
+#[test]
+fn test_preview() {
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let run = |repl: &mut Repl, input: &'static str| {
+        let output = repl.execute(checked_statement(input, &limits).unwrap()).unwrap();
+        (output.to_string(), output.affected())
+    };
+
+    run(&mut repl, ".insert {age: 20, tags: [1]}; {age: 30, tags: []}; 5");
+    let output = run(&mut repl, ".preview .change {age, tags} into {age: age + 1, tags: [2]}");
+    assert_eq!(output.1, 0);
+    assert_eq!(
+        output.0,
+        "WOULD CHANGE 2 items.\n\
+        @@ item 1 @@\n- .age: 20\n+ .age: 21\n- .tags[0]: 1\n+ .tags[0]: 2\n\
+        @@ item 2 @@\n- .age: 30\n+ .age: 31\n+ .tags[0]: 2\n"
+    );
+    assert_eq!(
+        run(&mut repl, ".query x").0,
+        "{age: 20, tags: [1, ], };\n{age: 30, tags: [], };\n5;\n"
+    );
+
+    let output = run(&mut repl, ".preview .change x into x where x == 5");
+    assert_eq!(output.0, "WOULD CHANGE 1 items.\n");
+
+    run(&mut repl, ".insert 1; 2; 3; 4; 6; 7");
+    let output = run(&mut repl, ".preview .change x is Integer into x * 10");
+    assert!(output.0.starts_with("WOULD CHANGE 7 items.\n@@ item 1 @@\n- 5\n+ 50\n"));
+    assert!(output.0.ends_with("@@ item 5 @@\n- 4\n+ 40\n... and 2 more.\n"));
+}
+
+#[test]
+fn test_trash() {
+    use damasc::repl::ReplError;
+
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let run = |repl: &mut Repl, input: &'static str| {
+        repl.execute(checked_statement(input, &limits).unwrap())
+            .map(|o| o.to_string())
+    };
+
+    assert_matches!(run(&mut repl, ".trash"), Err(ReplError::BagError));
+    run(&mut repl, ".trash on").unwrap();
+    run(&mut repl, ".insert {id: 1}; {id: 2}; {id: 3}; 4").unwrap();
+    assert_eq!(run(&mut repl, ".delete {id} where id > 1").unwrap(), "DELETED 2 items.\n");
+    run(&mut repl, ".pop 4").unwrap();
+    assert_eq!(run(&mut repl, ".query x").unwrap(), "{id: 1, };\n");
+
+    let trashed = run(&mut repl, ".trash").unwrap();
+    assert_eq!(trashed.lines().count(), 3);
+    assert!(trashed.lines().all(|l| l.contains("deleted_at: @")));
+    assert!(repl.bags().iter().any(|b| b.name == "init::trash"));
+
+    assert_eq!(
+        run(&mut repl, ".restore {id} from trash where id == 3").unwrap(),
+        "MOVED 1 items.\n"
+    );
+    assert_eq!(run(&mut repl, ".query x").unwrap(), "{id: 1, };\n{id: 3, };\n");
+    assert_eq!(run(&mut repl, ".trash").unwrap().lines().count(), 2);
+    assert_eq!(
+        run(&mut repl, ".purge").unwrap(),
+        "Purged 2 values from the trash of bag init\n"
+    );
+    assert_eq!(run(&mut repl, ".trash").unwrap(), "");
+
+    // Values whose time to live is over are no longer listed or restored.
+    run(&mut repl, ".trash on ttl 0").unwrap();
+    run(&mut repl, ".delete x").unwrap();
+    assert_eq!(run(&mut repl, ".trash").unwrap(), "");
+    assert_eq!(run(&mut repl, ".restore from trash").unwrap(), "MOVED 0 items.\n");
+
+    run(&mut repl, ".trash off").unwrap();
+    assert!(!repl.bags().iter().any(|b| b.name == "init::trash"));
+
+    // Dropping a bag keeps its trash for a bag created again under its name.
+    run(&mut repl, ".bag other").unwrap();
+    run(&mut repl, ".trash on").unwrap();
+    run(&mut repl, ".insert 5; 6").unwrap();
+    run(&mut repl, ".delete x where x == 5").unwrap();
+    run(&mut repl, ".bag init").unwrap();
+    run(&mut repl, ".drop other").unwrap();
+    run(&mut repl, ".bag other").unwrap();
+    assert_eq!(run(&mut repl, ".restore from trash").unwrap(), "MOVED 1 items.\n");
+    assert_eq!(run(&mut repl, ".query x").unwrap(), "5;\n");
+    assert!(matches!(
+        checked_statement(".restore backup", &limits),
+        Ok(Statement::Restore(_))
+    ));
+}
+
+#[test]
+fn test_confirmation_threshold() {
+    use damasc::repl::ReplError;
+
+    let limits = Limits::default();
+    let mut repl = Repl::open("init", None).unwrap();
+    let parse = |input: &'static str| checked_statement(input, &limits).unwrap();
+    let query = |repl: &mut Repl| repl.execute(parse(".query x")).unwrap().to_string();
+
+    repl.execute(parse(".insert 1; 2; 3")).unwrap();
+    repl.execute(parse(".set confirm_threshold 2")).unwrap();
+    assert_matches!(repl.execute(parse(".delete x")), Err(ReplError::Unconfirmed(3)));
+    assert_matches!(repl.execute(parse(".change x into x + 1")), Err(ReplError::Unconfirmed(3)));
+    assert_eq!(query(&mut repl), "1;\n2;\n3;\n");
+    repl.execute(parse(".delete x where x > 2")).unwrap();
+    assert_eq!(query(&mut repl), "1;\n2;\n");
+
+    // Macros and batches are checked by the statements they run.
+    repl.execute(parse(".set confirm_threshold 1")).unwrap();
+    repl.execute(parse(".macro wipe := .delete x")).unwrap();
+    assert_matches!(repl.execute(parse("!wipe")), Err(ReplError::Unconfirmed(2)));
+    assert_matches!(
+        repl.execute_many(vec![parse(".insert 7"), parse(".delete x")]),
+        Err((1, ReplError::Unconfirmed(3)))
+    );
+    assert_eq!(query(&mut repl), "1;\n2;\n");
+    assert_eq!(
+        repl.confirming(true, |repl| repl.execute(parse("!wipe"))).unwrap().to_string(),
+        "DELETED 2 items.\n"
+    );
+
+    repl.execute(parse(".bag other")).unwrap();
+    repl.execute(parse(".insert 4; 5; 6")).unwrap();
+    repl.execute(parse(".bag init")).unwrap();
+    assert_matches!(repl.execute(parse(".drop other")), Err(ReplError::Unconfirmed(3)));
+
+    repl.execute(parse(".set confirm_threshold null")).unwrap();
+    repl.execute(parse(".drop other")).unwrap();
+}
+
+#[test]
+fn test_plan_cache() {
+    let mut repl = Repl::open("init", None).unwrap();
+
+    let statement = repl.parse(".insert 1; 2").unwrap();
+    repl.execute(statement).unwrap();
+    assert!(matches!(repl.parse("  .insert 1; 2\n"), Ok(Statement::Insert(_))));
+    assert!(repl.parse(".query x where").is_err());
+    assert_eq!((repl.plan_cache.hits, repl.plan_cache.misses), (1, 2));
+    assert_eq!(repl.plan_cache.len(), 1);
+
+    let stats = repl.parse(".stats").unwrap();
+    assert_eq!(
+        repl.execute(stats).unwrap().to_string(),
+        "Plan cache: 2 of 256 statements cached, 1 hits, 3 misses, 0 evictions\n"
+    );
+
+    // Settings may change the limits statements are checked against.
+    let set = repl.parse(".set max_nesting 1").unwrap();
+    repl.execute(set).unwrap();
+    assert!(repl.plan_cache.is_empty());
+    assert!(repl.parse(".insert [[1]]").is_err());
+
+    let mut cache = damasc::plan_cache::PlanCache::new(2);
+    cache.insert("a", Statement::Noop);
+    cache.insert("b", Statement::Noop);
+    cache.get("a");
+    cache.insert("c", Statement::Noop);
+    assert!(cache.get("a").is_some());
+    assert!(cache.get("b").is_none());
+    assert_eq!(cache.evictions, 1);
+}
+
+#[test]
+fn test_grammar() {
+    use damasc::grammar::{self, parse};
+    use nom::sequence::tuple;
+
+    assert_eq!(grammar::VERSION, 3);
+    assert_eq!(parse(grammar::expression, " 1 + 2 ").unwrap().to_string(), "1 + 2");
+    assert_eq!(parse(grammar::pattern, "{x, ...}").unwrap().to_string(), "{x, ...}");
+    assert_eq!(parse(grammar::limit_clause, "limit 10"), Ok(10));
+    assert!(parse(grammar::order_clause, "order by x desc").unwrap().descending);
+    assert!(!parse(grammar::order_clause, "order by x").unwrap().descending);
+    assert_eq!(parse(grammar::assignments, "[x, y] = [1, 2]; z = 3").unwrap().assignments.len(), 2);
+    assert!(matches!(parse(grammar::statement, ".bags"), Ok(Statement::ListBags)));
+
+    let (pattern, guard) = parse(tuple((grammar::pattern, grammar::where_clause)), "x where x > 1").unwrap();
+    assert_eq!(format!("{pattern} {guard}"), "x x > 1");
+
+    let error = parse(grammar::expression, "1 + 2 3").unwrap_err();
+    assert_eq!((error.line, error.column), (1, 7));
+    assert!(parse(grammar::where_clause, "where").is_err());
+}
+
+#[test]
+fn test_keywords() {
+    use damasc::grammar::{self, parse};
+
+    for input in [
+        "{where: 1, into: 2, limit: 3}.limit",
+        "#where + order * group",
+        "{#offset, #limit: 1}",
+        "x.where.#order",
+    ] {
+        let expression = parse(grammar::expression, input).unwrap();
+        let printed = expression.to_string();
+        assert_eq!(parse(grammar::expression, &printed).unwrap().to_string(), printed, "{input}");
+    }
+
+    for input in ["{where: w, #limit}", "[#into, order]"] {
+        let pattern = parse(grammar::pattern, input).unwrap();
+        let printed = pattern.to_string();
+        assert_eq!(parse(grammar::pattern, &printed).unwrap().to_string(), printed, "{input}");
+    }
+
+    assert_eq!(parse(grammar::expression, "#where").unwrap().to_string(), "#where");
+    assert_eq!(
+        parse(grammar::expression, "#where + #order * group").unwrap().to_string(),
+        "#where + order * group"
+    );
+    assert_eq!(parse(grammar::pattern, "{#limit, order}").unwrap().to_string(), "{#limit, order}");
+    assert!(parse(grammar::expression, "where").is_err());
+    assert!(parse(grammar::pattern, "{where}").is_err());
+
+    let mut repl = Repl::open("init", None).unwrap();
+    for input in [".insert {order: 2, where: 1}; {order: 1, where: 2}", ".let group = 10"] {
+        let statement = repl.parse(input).unwrap();
+        repl.execute(statement).unwrap();
+    }
+    let query = repl.parse(".query {order, where: w} into order + group order by w desc").unwrap();
+    assert_eq!(repl.execute(query).unwrap().to_string(), "11;\n12;\n");
+}
+
+#[test]
+fn test_bags_to_json() {
+    let mut repl = Repl::open("init", None).unwrap();
+    for input in [".insert 1; 2", ".bag other as _"] {
+        let statement = repl.parse(input).unwrap();
+        repl.execute(statement).unwrap();
+    }
+
+    assert_eq!(
+        repl.bags_to_json().to_string(),
+        r#"[{"count":2,"current":false,"name":"init"},{"count":0,"current":true,"name":"other"}]"#
+    );
+
+    // The values of virtual bags are counted as well.
+    for input in [
+        ".insert 3",
+        ".bag digits as virtual 0..10",
+        ".bag naturals as virtual 1..",
+        ".view both := union of init, other",
+        ".let x = {a: [1, \"b\"]}",
+        ".let #where = null",
+    ] {
+        let statement = repl.parse(input).unwrap();
+        repl.execute(statement).unwrap();
+    }
+    let counts = repl.bags_to_json()
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|bag| format!("{}={}", bag["name"].as_str().unwrap(), bag["count"]))
+        .collect::<Vec<_>>();
+    assert_eq!(counts, ["both=3", "digits=10", "init=2", "naturals=null", "other=1"]);
+
+    assert_eq!(repl.bindings_to_json().to_string(), r#"{"where":null,"x":{"a":[1,"b"]}}"#);
+}
+
+#[test]
+fn test_value_serde() {
+    let env = Environment::new();
+    let eval = |source: &str| {
+        let expression = damasc::grammar::parse(damasc::grammar::expression, source).unwrap();
+        env.eval_expr(&expression).unwrap()
+    };
+    let to_json = |source: &str| serde_json::to_string(&eval(source)).unwrap();
+
+    assert_eq!(to_json(r#"{a: [1, "x", null, true]}"#), r#"{"a":[1,"x",null,true]}"#);
+    assert_eq!(to_json("Integer"), r#"{"$type":"Integer"}"#);
+    assert_eq!(
+        to_json("10000000000000000000"),
+        r#"{"$bigint":"10000000000000000000"}"#
+    );
+    assert_eq!(
+        to_json("@2024-01-15T10:30:00Z"),
+        r#"{"$datetime":"2024-01-15T10:30:00Z"}"#
+    );
+    assert_eq!(to_json("quote(x + 1)"), r#"{"$expression":"x + 1"}"#);
+    assert_eq!(
+        to_json(r#"{"$type": "Integer"}"#),
+        r#"{"$object":{"$type":"Integer"}}"#
+    );
+    assert!(serde_json::to_string(&eval("fn(x) => x")).is_err());
+
+    for source in [
+        r#"{"$type": "Integer"}"#,
+        r#"{"$object": {"$type": "Integer"}}"#,
+        r#"{"$object": {"$object": Integer}, x: 1}"#,
+        "{a: pattern({x, ...}), b: [Boolean, @2024-01-15]}",
+    ] {
+        let value = eval(source);
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), value, "{json}");
+    }
+
+    let from_json = |json: &str| serde_json::from_str::<Value>(json).map(|v| v.to_string());
+    assert_eq!(from_json("18446744073709551615").unwrap(), "18446744073709551615");
+    assert_eq!(from_json(r#"{"$foo":1}"#).unwrap(), r#"{"$foo": 1, }"#);
+    assert_eq!(from_json(r#"{"$type":"Integer","x":1}"#).unwrap(), r#"{"$type": "Integer", x: 1, }"#);
+    assert!(from_json("1.5").is_err());
+    assert!(from_json(r#"{"$type":"Number"}"#).is_err());
+    assert!(from_json(r#"{"$bigint":12}"#).is_err());
+}
+
+#[test]
+fn test_specialize_guards() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".insert {a: 1, b: 2}; {a: 2, b: 3}; {a: 1, b: 5}");
+    run(".let m = 2");
+    run(".let a = 100");
+    // Bound values are substituted, names the pattern binds are not.
+    let explained = run(".explain .query {a, b} where b > m && a < 2");
+    assert!(explained.contains("guard: b > 2 && a < 2\n"), "{explained}");
+    assert_eq!(run(".query {a, b} where b > m && a < 2"), "{a: 1, b: 5, };\n");
+    assert!(run(".explain .delete {a, b} where b > m").contains("predicate: {a, b} where b > 2\n"));
+    // Calls depending on the query are kept.
+    let explained = run(".explain .query x where count_of(m) == 0 && assert(m > 5, \"small\")");
+    assert!(explained.contains("guard: count_of(2) == 0 && assert(false, \"small\")\n"), "{explained}");
+
+    run(".set specialize false");
+    assert!(run(".explain .query {a, b} where b > m && a < 2").contains("guard: b > m && a < 2\n"));
+    assert_eq!(run(".query {a, b} where b > m && a < 2"), "{a: 1, b: 5, };\n");
+}
+
+#[test]
+fn test_literal_plan() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".insert {a: 1, b: 2}; {a: \"1\", b: 2}; {a: 1}; 1; \"1\"");
+    assert!(run(".explain .query {a: 1, ...}").contains(": literal comparison\n"));
+    assert!(run(".explain .query {a: 1, b: 2}").contains(": literal comparison\n"));
+    assert!(run(".explain .query {a: 1, b}").contains(": pattern matching\n"));
+
+    assert_eq!(run(".query {a: 1, ...}"), "{a: 1, b: 2, };\n{a: 1, };\n");
+    assert_eq!(run(".query {a: 1, b: 2}"), "{a: 1, b: 2, };\n");
+    assert_eq!(run(".query {a: 1}"), "{a: 1, };\n");
+    assert_eq!(run(".query 1"), "1;\n");
+    assert_eq!(run(".delete {a: \"1\", ...}"), "DELETED 1 items.\n");
+    assert_eq!(run(".query {b: 2, ...}"), "{a: 1, b: 2, };\n");
+}
+
+#[test]
+fn test_pattern_values() {
+    let mut repl = Repl::open("init", None).unwrap();
+    let mut run = |input: &'static str| run_statement(&mut repl, input);
+
+    run(".insert {a: 1, b: 2}; {a: 2, b: 3}; [2]");
+    assert_eq!(run(".let p = pattern({a: 2, b})"), "YES.\np := pattern({a: 2, b});\n");
+    assert_eq!(run(".query x where matches(x, p)"), "{a: 2, b: 3, };\n");
+    assert_eq!(run(".query x where matches(x, pattern([_]))"), "[2, ];\n");
+    assert_eq!(run(".query x into type(p) limit 1"), "Pattern;\n");
+    assert_eq!(run(".query x into p == pattern({a: 2, b}) limit 1"), "true;\n");
+    assert_eq!(run(".let r = matches(1, 5)"), "EvalError(TypeError)");
+}
+
+#[test]
+fn test_storage() {
+    let directory = std::env::temp_dir().join(format!("damasc_storage_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    let open = || {
+        let storage = damasc::storage::Storage::new(&directory).unwrap();
+        Repl::open("init", Some(storage))
+    };
+
+    let mut repl = open().unwrap();
+    run_statement(&mut repl, ".insert 1; 2; 3");
+    run_statement(&mut repl, ".bag tags as _ is String");
+    run_statement(&mut repl, ".insert \"a\"; \"b\"");
+    run_statement(&mut repl, ".delete x where x == \"a\"");
+    run_statement(&mut repl, ".bag init");
+    run_statement(&mut repl, ".insert 4");
+    drop(repl);
+
+    // The bags, their values and their guards are loaded again.
+    let mut repl = open().unwrap();
+    assert_eq!(run_statement(&mut repl, ".query x"), "1;\n2;\n3;\n4;\n");
+    run_statement(&mut repl, ".bag tags");
+    assert_eq!(run_statement(&mut repl, ".query x"), "\"b\";\n");
+    assert_eq!(run_statement(&mut repl, ".insert 5"), "GuardError");
+    drop(repl);
+
+    std::fs::write(directory.join("broken.bag"), "{{{\n").unwrap();
+    assert!(open().is_err());
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn test_restore_saved_state() {
+    let file = std::env::temp_dir().join(format!("damasc_state_{}.bundle", std::process::id()));
+    let mut repl = Repl::open("init", None).unwrap();
+    run_statement(&mut repl, ".insert {id: 1}; {id: 2}");
+    run_statement(&mut repl, ".bag tags as _ is String");
+    run_statement(&mut repl, ".insert \"a\"");
+
+    let mut writer = std::fs::File::create(&file).unwrap();
+    repl.write_bundle(&mut writer).unwrap();
+    drop(writer);
+
+    // Like the web server does on startup, the bags are replaced by the
+    // saved ones.
+    let mut restored = Repl::new("init");
+    restored.bag_bundle.bags.clear();
+    let statement = Statement::LoadBundle(std::borrow::Cow::Owned(file.to_string_lossy().into_owned()));
+    assert_matches!(restored.execute(statement), Ok(_));
+    run_statement(&mut restored, ".bag init");
+    assert_eq!(run_statement(&mut restored, ".query x"), "{id: 1, };\n{id: 2, };\n");
+    run_statement(&mut restored, ".bag tags");
+    assert_eq!(run_statement(&mut restored, ".query x"), "\"a\";\n");
+    assert_eq!(run_statement(&mut restored, ".insert 1"), "GuardError");
+    let _ = std::fs::remove_file(&file);
+}
+
+#[test]
+fn test_audit_log() {
+    use damasc::audit::{AuditLog, AUDIT_HISTORY};
+
+    let path = std::env::temp_dir().join(format!("damasc_audit_{}.log", std::process::id()));
+    let rotated = path.with_extension("log.1");
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&rotated);
+
+    let mut audit = AuditLog::new(&path, 150);
+    let started = std::time::Instant::now();
+    audit.record("0123456789abcdef.signature", ".insert 1", started, "ok", "INSERTED 1 items.\nmore");
+    let written = std::fs::read_to_string(&path).unwrap();
+    assert!(written.contains(" session=01234567 "), "{written}");
+    assert!(written.contains("outcome=ok statement=\".insert 1\" detail=\"INSERTED 1 items.\"\n"), "{written}");
+
+    // The log is moved aside once it reached the maximum size.
+    audit.record("s2", ".query x", started, "unauthorized", "");
+    assert!(!rotated.exists());
+    audit.record("s3", ".query y", started, "error", "");
+    assert!(std::fs::read_to_string(&rotated).unwrap().contains("session=s2"));
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+    for _ in 0..AUDIT_HISTORY {
+        audit.record("s4", ".query z", started, "ok", "");
+    }
+    assert_eq!(audit.recent().count(), AUDIT_HISTORY);
+    assert!(audit.recent().all(|e| e.session == "s4"));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&rotated);
+}
+
+#[test]
+fn test_auth() {
+    use damasc::auth::{base64, Auth, AuthScope};
+    use damasc::server::{constant_time_eq, random_token};
+
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foo"), "Zm9v");
+    assert_eq!(base64(b"user:secret"), "dXNlcjpzZWNyZXQ=");
+
+    assert!(constant_time_eq("secret", "secret"));
+    assert!(!constant_time_eq("secret", "secreT"));
+    assert!(!constant_time_eq("secret", "secret2"));
+    let token = random_token();
+    assert_eq!(token.len(), 32);
+    assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_ne!(token, random_token());
+
+    assert!(Auth::new(None, None, AuthScope::Everything).is_none());
+    let auth = Auth::new(Some("t0k3n".into()), Some(("user", "secret")), AuthScope::Mutations).unwrap();
+    assert!(auth.accepts("Bearer t0k3n"));
+    assert!(auth.accepts("Basic dXNlcjpzZWNyZXQ="));
+    assert!(!auth.accepts("Bearer wrong"));
+    assert!(!auth.accepts("Basic t0k3n"));
+    assert!(!auth.accepts("t0k3n"));
+    assert!(auth.has_basic());
+
+    let token_only = Auth::new(Some("t0k3n".into()), None, AuthScope::Everything).unwrap();
+    assert!(!token_only.accepts("Basic dXNlcjpzZWNyZXQ="));
+    assert!(!token_only.has_basic());
+
+    let limits = Limits::default();
+    let insert = checked_statement(".insert 1", &limits).unwrap();
+    let query = checked_statement(".query x", &limits).unwrap();
+    assert!(auth.is_required(Some(&insert)));
+    assert!(!auth.is_required(Some(&query)));
+    assert!(!auth.is_required(None));
+    assert!(token_only.is_required(Some(&query)));
+    assert!(token_only.is_required(None));
+}